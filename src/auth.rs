@@ -0,0 +1,700 @@
+//! A ready-made ZAP handler (RFC 27), in the style of CZMQ's zauth.
+//!
+//! An `Authenticator` binds inproc://zeromq.zap.01 on a context and answers
+//! the requests of the NULL, PLAIN and CURVE servers of that context from a
+//! background thread. Clients can be filtered by address with allow and
+//! deny lists, PLAIN credentials are checked against a password file and
+//! CURVE clients against a directory of public key certificates. Both files
+//! are read again whenever they change, so access can be granted or revoked
+//! without restarting.
+//!
+//! NULL servers only consult the handler if they have a ZAP domain set.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::SystemTime;
+
+use crate::command::Command;
+use crate::err::ZmqError;
+use crate::i_mailbox::IMailbox;
+use crate::mechanism::add_property;
+use crate::rust_zmq::{Context, Result, Socket, SocketType, SNDMORE};
+use crate::sockopt::Linger;
+use crate::tcp_address::TcpAddressMask;
+use crate::utils::{z85_decode, z85_encode};
+use crate::zap_client::ZAP_ENDPOINT;
+
+/// Passed to `Authenticator::configure_curve` to let in any client with a
+/// valid CURVE handshake, whatever its public key.
+pub const CURVE_ALLOW_ANY: &str = "*";
+
+const ZAP_VERSION: &[u8] = b"1.0";
+const CURVE_KEY_LEN: usize = 32;
+
+/// Answers the ZAP requests of a context until it is dropped or the
+/// context is terminated.
+///
+/// Without any configuration every client is let in. Once an address is
+/// allowed, only allowed addresses get in and the deny list is ignored;
+/// otherwise denied addresses are turned away. PLAIN and CURVE clients
+/// must also present credentials found in the configured password file or
+/// certificate directory, even when their address is allowed.
+pub struct Authenticator {
+    config: Arc<Mutex<Config>>,
+    // Mailbox of the handler socket, to interrupt the thread blocked on it.
+    mailbox: Arc<dyn IMailbox>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Authenticator {
+    /// Starts answering ZAP requests for the sockets of `ctx`. Fails with
+    /// `AddrInUse` if the context has a ZAP handler already.
+    pub fn new(ctx: &Context) -> Result<Self> {
+        let handler = ctx.socket(SocketType::Rep)?;
+        handler.set(Linger(0))?;
+        handler.bind(ZAP_ENDPOINT)?;
+
+        let config = Arc::new(Mutex::new(Config::default()));
+        let mailbox = handler.get_mailbox();
+        let worker = {
+            let config = Arc::clone(&config);
+            thread::Builder::new()
+                .name("ZMQbg/ZAP".to_string())
+                .spawn(move || run(handler, &config))
+                .map_err(ZmqError::SystemError)?
+        };
+
+        Ok(Authenticator {
+            config,
+            mailbox,
+            worker: Some(worker),
+        })
+    }
+
+    /// Lets in clients from `address`, an IP address with an optional
+    /// prefix length such as `192.168.1.0/24`.
+    pub fn allow(&self, address: &str) -> Result<()> {
+        let mask = TcpAddressMask::resolve(address, true)?;
+        lock(&self.config).allow.push(mask);
+        Ok(())
+    }
+
+    /// Turns away clients from `address`, in the same format as `allow`.
+    pub fn deny(&self, address: &str) -> Result<()> {
+        let mask = TcpAddressMask::resolve(address, true)?;
+        lock(&self.config).deny.push(mask);
+        Ok(())
+    }
+
+    /// Checks PLAIN clients against a password file with one
+    /// `username=password` line per user. Lines starting with `#` are
+    /// comments.
+    pub fn configure_plain<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let passwords = Passwords::load(path.as_ref()).map_err(ZmqError::SystemError)?;
+        lock(&self.config).plain = Some(passwords);
+        Ok(())
+    }
+
+    /// Checks CURVE clients against the public key certificates in the
+    /// `location` directory, or lets in any of them if `location` is
+    /// `CURVE_ALLOW_ANY`.
+    ///
+    /// Certificates are in the format CZMQ's zcert saves: the Z85 encoded
+    /// key is the `public-key` of the `curve` section, and the entries of
+    /// the `metadata` section are passed on as metadata of the connection.
+    pub fn configure_curve(&self, location: &str) -> Result<()> {
+        let curve = if location == CURVE_ALLOW_ANY {
+            Curve::AllowAny
+        } else {
+            Curve::Certificates(
+                Certificates::load(Path::new(location)).map_err(ZmqError::SystemError)?,
+            )
+        };
+        lock(&self.config).curve = Some(curve);
+        Ok(())
+    }
+}
+
+impl Drop for Authenticator {
+    fn drop(&mut self) {
+        // Makes the blocking receive of the handler return ETERM, as
+        // terminating the context would.
+        self.mailbox.send(Command::Stop);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn lock(config: &Mutex<Config>) -> MutexGuard<'_, Config> {
+    config.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn run(handler: Socket, config: &Mutex<Config>) {
+    loop {
+        let request = match recv_request(&handler) {
+            Ok(request) => request,
+            Err(ZmqError::Term) => break,
+            Err(_) => continue,
+        };
+        let reply = lock(config).authenticate(&request);
+        if let Err(ZmqError::Term) = send_reply(&handler, &request, &reply) {
+            break;
+        }
+    }
+    let _ = handler.close();
+}
+
+fn recv_request(handler: &Socket) -> Result<Vec<Vec<u8>>> {
+    let mut request = Vec::new();
+    loop {
+        let msg = handler.recv_msg(0)?;
+        request.push(msg.to_vec());
+        if !msg.get_more() {
+            return Ok(request);
+        }
+    }
+}
+
+fn send_reply(handler: &Socket, request: &[Vec<u8>], reply: &Reply) -> Result<()> {
+    let request_id = request.get(1).map_or(&[][..], |id| &id[..]);
+    let frames: [&[u8]; 6] = [
+        ZAP_VERSION,
+        request_id,
+        reply.status_code.as_bytes(),
+        reply.status_text.as_bytes(),
+        reply.user_id.as_bytes(),
+        &reply.metadata,
+    ];
+    let last = frames.len() - 1;
+    for (i, frame) in frames.iter().enumerate() {
+        handler.send(*frame, if i < last { SNDMORE } else { 0 })?;
+    }
+    Ok(())
+}
+
+struct Reply {
+    status_code: &'static str,
+    status_text: &'static str,
+    user_id: String,
+    metadata: Vec<u8>,
+}
+
+impl Reply {
+    fn ok(user_id: String, metadata: Vec<u8>) -> Self {
+        Reply {
+            status_code: "200",
+            status_text: "OK",
+            user_id,
+            metadata,
+        }
+    }
+
+    fn denied() -> Self {
+        Reply {
+            status_code: "400",
+            status_text: "No access",
+            user_id: String::new(),
+            metadata: Vec::new(),
+        }
+    }
+
+    fn internal_error() -> Self {
+        Reply {
+            status_code: "500",
+            status_text: "Internal error",
+            user_id: String::new(),
+            metadata: Vec::new(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Config {
+    allow: Vec<TcpAddressMask>,
+    deny: Vec<TcpAddressMask>,
+    plain: Option<Passwords>,
+    curve: Option<Curve>,
+}
+
+impl Config {
+    fn authenticate(&mut self, request: &[Vec<u8>]) -> Reply {
+        // Version, request id, domain, address, routing id and mechanism,
+        // then the credentials.
+        if request.len() < 6 || request[0] != ZAP_VERSION {
+            return Reply::internal_error();
+        }
+        let address = std::str::from_utf8(&request[3])
+            .ok()
+            .and_then(|address| address.parse::<IpAddr>().ok());
+        let mechanism = &request[5][..];
+        let credentials = &request[6..];
+
+        let listed = |masks: &[TcpAddressMask]| {
+            address.is_some_and(|address| masks.iter().any(|mask| mask.match_address(&address)))
+        };
+        let denied = if self.allow.is_empty() {
+            listed(&self.deny)
+        } else {
+            !listed(&self.allow)
+        };
+        if denied {
+            return Reply::denied();
+        }
+
+        let authenticated = match mechanism {
+            // A NULL client gets in unless its address was denied.
+            b"NULL" => Some((String::new(), Vec::new())),
+            // Even an allowed PLAIN or CURVE client must authenticate.
+            b"PLAIN" => self.authenticate_plain(credentials),
+            b"CURVE" => self.authenticate_curve(credentials),
+            _ => None,
+        };
+        match authenticated {
+            Some((user_id, metadata)) => Reply::ok(user_id, metadata),
+            None => Reply::denied(),
+        }
+    }
+
+    fn authenticate_plain(&mut self, credentials: &[Vec<u8>]) -> Option<(String, Vec<u8>)> {
+        let passwords = self.plain.as_mut()?;
+        let [username, password] = credentials else {
+            return None;
+        };
+        let username = std::str::from_utf8(username).ok()?;
+        passwords.refresh();
+        match passwords.passwords.get(username) {
+            Some(expected) if expected.as_bytes() == password.as_slice() => {
+                Some((username.to_string(), Vec::new()))
+            }
+            _ => None,
+        }
+    }
+
+    fn authenticate_curve(&mut self, credentials: &[Vec<u8>]) -> Option<(String, Vec<u8>)> {
+        let [client_key] = credentials else {
+            return None;
+        };
+        if client_key.len() != CURVE_KEY_LEN {
+            return None;
+        }
+        let user_id = z85_encode(client_key).ok()?;
+        match self.curve.as_mut()? {
+            Curve::AllowAny => Some((user_id, Vec::new())),
+            Curve::Certificates(certificates) => {
+                certificates.refresh();
+                let certificate = certificates.certificates.get(&client_key[..])?;
+                let mut metadata = Vec::new();
+                for (name, value) in &certificate.metadata {
+                    add_property(&mut metadata, name, value.as_bytes());
+                }
+                Some((user_id, metadata))
+            }
+        }
+    }
+}
+
+// Modification time and size of a file, or of each file in a directory,
+// used to tell whether it has to be loaded again.
+type Stamp = Vec<(PathBuf, Option<SystemTime>, u64)>;
+
+fn stamp(path: &Path) -> Stamp {
+    let entry_stamp = |path: PathBuf| {
+        let metadata = fs::metadata(&path).ok()?;
+        Some((path, metadata.modified().ok(), metadata.len()))
+    };
+    if path.is_dir() {
+        let mut stamp: Stamp = fs::read_dir(path)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry_stamp(entry.ok()?.path()))
+            .collect();
+        stamp.sort();
+        stamp
+    } else {
+        entry_stamp(path.to_path_buf()).into_iter().collect()
+    }
+}
+
+struct Passwords {
+    path: PathBuf,
+    stamp: Stamp,
+    passwords: HashMap<String, String>,
+}
+
+impl Passwords {
+    fn load(path: &Path) -> io::Result<Self> {
+        let stamp = stamp(path);
+        let passwords = parse_passwords(&fs::read_to_string(path)?);
+        Ok(Passwords {
+            path: path.to_path_buf(),
+            stamp,
+            passwords,
+        })
+    }
+
+    // A file that can no longer be read lets nobody in.
+    fn refresh(&mut self) {
+        if stamp(&self.path) != self.stamp {
+            *self = Passwords::load(&self.path).unwrap_or_else(|_| Passwords {
+                path: self.path.clone(),
+                stamp: stamp(&self.path),
+                passwords: HashMap::new(),
+            });
+        }
+    }
+}
+
+fn parse_passwords(text: &str) -> HashMap<String, String> {
+    text.lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(username, password)| (username.trim().to_string(), password.to_string()))
+        .collect()
+}
+
+enum Curve {
+    AllowAny,
+    Certificates(Certificates),
+}
+
+struct Certificate {
+    metadata: Vec<(String, String)>,
+}
+
+struct Certificates {
+    path: PathBuf,
+    stamp: Stamp,
+    // By binary public key, as the CURVE server sends it.
+    certificates: HashMap<Vec<u8>, Certificate>,
+}
+
+impl Certificates {
+    fn load(path: &Path) -> io::Result<Self> {
+        let stamp = stamp(path);
+        let mut certificates = HashMap::new();
+        for entry in fs::read_dir(path)? {
+            let path = entry?.path();
+            // Files that aren't certificates are skipped.
+            let parsed = fs::read_to_string(&path)
+                .ok()
+                .and_then(|text| parse_certificate(&text));
+            if let Some((public_key, certificate)) = parsed {
+                certificates.insert(public_key, certificate);
+            }
+        }
+        Ok(Certificates {
+            path: path.to_path_buf(),
+            stamp,
+            certificates,
+        })
+    }
+
+    fn refresh(&mut self) {
+        if stamp(&self.path) != self.stamp {
+            *self = Certificates::load(&self.path).unwrap_or_else(|_| Certificates {
+                path: self.path.clone(),
+                stamp: stamp(&self.path),
+                certificates: HashMap::new(),
+            });
+        }
+    }
+}
+
+/// Reads the public key and metadata of a certificate, which is a ZPL
+/// (RFC 4) document such as:
+///
+/// ```text
+/// metadata
+///     name = "client"
+/// curve
+///     public-key = "Yne@$w-vo<fVvi]a<NY6T1ed:M$fCG*[IaLV{hID"
+/// ```
+fn parse_certificate(text: &str) -> Option<(Vec<u8>, Certificate)> {
+    let mut section = "";
+    let mut public_key = None;
+    let mut metadata = Vec::new();
+    for line in text.lines() {
+        let content = line.trim();
+        if content.is_empty() || content.starts_with('#') {
+            continue;
+        }
+        if !line.starts_with(char::is_whitespace) {
+            section = content;
+            continue;
+        }
+        let Some((name, value)) = content.split_once('=') else {
+            continue;
+        };
+        let name = name.trim();
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+        match section {
+            "curve" if name == "public-key" => public_key = Some(value),
+            // Longer names can't be sent as ZAP metadata.
+            "metadata" if name.len() <= u8::MAX as usize => {
+                metadata.push((name.to_string(), value.to_string()))
+            }
+            _ => {}
+        }
+    }
+
+    let public_key = z85_decode(public_key?).ok()?;
+    if public_key.len() != CURVE_KEY_LEN {
+        return None;
+    }
+    Some((public_key, Certificate { metadata }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rust_zmq::DONTWAIT;
+    use crate::sockopt::{
+        LastEndpoint, PlainPassword, PlainServer, PlainUsername, RcvTimeo, ZapDomain,
+    };
+
+    // A directory of its own for each test, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("zauth-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // Connects a PUSH to a PULL over TCP and tells whether a message got
+    // through, i.e. whether the authenticator let the PUSH in. A rejected
+    // PUSH may lose its pipe before it gets to send.
+    fn admitted(ctx: &Context, server: impl Fn(&Socket), client: impl Fn(&Socket)) -> bool {
+        let pull = ctx.socket(SocketType::Pull).unwrap();
+        pull.set(Linger(0)).unwrap();
+        pull.set(RcvTimeo(500)).unwrap();
+        server(&pull);
+        pull.bind("tcp://127.0.0.1:*").unwrap();
+        let endpoint = pull.get::<LastEndpoint>().unwrap();
+
+        let push = ctx.socket(SocketType::Push).unwrap();
+        push.set(Linger(0)).unwrap();
+        client(&push);
+        push.connect(&endpoint).unwrap();
+        match push.send("hello", DONTWAIT) {
+            Ok(()) => {}
+            Err(ZmqError::Again) => return false,
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+
+        match pull.recv_bytes(0) {
+            Ok(msg) => {
+                assert_eq!(msg, b"hello");
+                true
+            }
+            Err(ZmqError::Again) => false,
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    fn plain_server(socket: &Socket) {
+        socket.set(PlainServer(true)).unwrap();
+    }
+
+    fn plain_client(username: &str, password: &str) -> impl Fn(&Socket) {
+        let username = username.to_string();
+        let password = password.to_string();
+        move |socket| {
+            socket.set(PlainUsername(username.clone())).unwrap();
+            socket.set(PlainPassword(password.clone())).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_parse_passwords() {
+        let passwords = parse_passwords("# users\nadmin=secret\n\nno password\nuser=a=b\n");
+        assert_eq!(passwords.len(), 2);
+        assert_eq!(passwords["admin"], "secret");
+        assert_eq!(passwords["user"], "a=b");
+    }
+
+    #[test]
+    fn test_parse_certificate() {
+        let text = "#   ZeroMQ CURVE Public Certificate\n\
+                    \n\
+                    metadata\n    name = \"client\"\n    email = 'not quoted'\n\
+                    curve\n    public-key = \"Yne@$w-vo<fVvi]a<NY6T1ed:M$fCG*[IaLV{hID\"\n";
+        let (public_key, certificate) = parse_certificate(text).unwrap();
+        assert_eq!(
+            z85_encode(&public_key).unwrap(),
+            "Yne@$w-vo<fVvi]a<NY6T1ed:M$fCG*[IaLV{hID"
+        );
+        assert_eq!(
+            certificate.metadata,
+            [
+                ("name".to_string(), "client".to_string()),
+                ("email".to_string(), "'not quoted'".to_string()),
+            ]
+        );
+
+        assert!(parse_certificate("curve\n    public-key = \"short\"\n").is_none());
+        assert!(parse_certificate("just some notes\n").is_none());
+    }
+
+    #[test]
+    fn test_one_authenticator_per_context() {
+        let ctx = Context::new();
+        let auth = Authenticator::new(&ctx).unwrap();
+        assert!(matches!(Authenticator::new(&ctx), Err(ZmqError::AddrInUse)));
+        assert!(matches!(
+            auth.allow("not an address"),
+            Err(ZmqError::InvalidInput)
+        ));
+        assert!(matches!(
+            auth.deny("10.0.0.0/33"),
+            Err(ZmqError::InvalidInput)
+        ));
+        assert!(matches!(
+            auth.configure_plain("/nonexistent/passwords"),
+            Err(ZmqError::SystemError(_))
+        ));
+
+        // Dropping it stops the handler and frees the endpoint.
+        drop(auth);
+        let _auth = Authenticator::new(&ctx).unwrap();
+    }
+
+    #[test]
+    fn test_allow_and_deny() {
+        let ctx = Context::new();
+        let auth = Authenticator::new(&ctx).unwrap();
+        let null_server = |socket: &Socket| socket.set(ZapDomain("global".to_string())).unwrap();
+        assert!(admitted(&ctx, null_server, |_| {}));
+
+        auth.deny("127.0.0.0/8").unwrap();
+        assert!(!admitted(&ctx, null_server, |_| {}));
+
+        // Allowing overrides the deny list, and turns away every address
+        // that isn't allowed.
+        auth.allow("10.0.0.0/8").unwrap();
+        assert!(!admitted(&ctx, null_server, |_| {}));
+        auth.allow("127.0.0.1").unwrap();
+        assert!(admitted(&ctx, null_server, |_| {}));
+
+        // PLAIN clients must authenticate even from an allowed address.
+        assert!(!admitted(
+            &ctx,
+            plain_server,
+            plain_client("admin", "secret")
+        ));
+    }
+
+    #[test]
+    fn test_plain_password_file() {
+        let ctx = Context::new();
+        let auth = Authenticator::new(&ctx).unwrap();
+        let dir = TempDir::new("plain");
+        let path = dir.0.join("passwords");
+        fs::write(&path, "admin=secret\n").unwrap();
+        auth.configure_plain(&path).unwrap();
+
+        assert!(admitted(
+            &ctx,
+            plain_server,
+            plain_client("admin", "secret")
+        ));
+        assert!(!admitted(
+            &ctx,
+            plain_server,
+            plain_client("admin", "wrong")
+        ));
+        assert!(!admitted(
+            &ctx,
+            plain_server,
+            plain_client("guest", "guest")
+        ));
+
+        // Changes to the file apply to the next clients.
+        fs::write(&path, "admin=changed\nguest=guest\n").unwrap();
+        assert!(!admitted(
+            &ctx,
+            plain_server,
+            plain_client("admin", "secret")
+        ));
+        assert!(admitted(
+            &ctx,
+            plain_server,
+            plain_client("admin", "changed")
+        ));
+        assert!(admitted(&ctx, plain_server, plain_client("guest", "guest")));
+
+        fs::remove_file(&path).unwrap();
+        assert!(!admitted(
+            &ctx,
+            plain_server,
+            plain_client("admin", "changed")
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "curve")]
+    fn test_curve_certificates() {
+        use crate::rust_zmq::curve_keypair;
+        use crate::sockopt::{CurvePublicKey, CurveSecretKey, CurveServer, CurveServerKey};
+
+        let ctx = Context::new();
+        let auth = Authenticator::new(&ctx).unwrap();
+        let dir = TempDir::new("curve");
+        auth.configure_curve(dir.0.to_str().unwrap()).unwrap();
+
+        let (server_public, server_secret) = curve_keypair().unwrap();
+        let curve_server = |socket: &Socket| {
+            socket.set(CurveServer(true)).unwrap();
+            socket
+                .set(CurveSecretKey(server_secret.clone().into_bytes()))
+                .unwrap();
+        };
+        let (client_public, client_secret) = curve_keypair().unwrap();
+        let curve_client = |socket: &Socket| {
+            socket
+                .set(CurveServerKey(server_public.clone().into_bytes()))
+                .unwrap();
+            socket
+                .set(CurvePublicKey(client_public.clone().into_bytes()))
+                .unwrap();
+            socket
+                .set(CurveSecretKey(client_secret.clone().into_bytes()))
+                .unwrap();
+        };
+
+        assert!(!admitted(&ctx, curve_server, curve_client));
+
+        // A certificate added to the directory is picked up right away.
+        let certificate = format!(
+            "metadata\n    name = \"client\"\ncurve\n    public-key = \"{}\"\n",
+            client_public
+        );
+        fs::write(dir.0.join("client.key"), certificate).unwrap();
+        assert!(admitted(&ctx, curve_server, curve_client));
+
+        fs::remove_file(dir.0.join("client.key")).unwrap();
+        assert!(!admitted(&ctx, curve_server, curve_client));
+
+        auth.configure_curve(CURVE_ALLOW_ANY).unwrap();
+        assert!(admitted(&ctx, curve_server, curve_client));
+    }
+}
@@ -0,0 +1,30 @@
+//! Generates a CurveZMQ keypair and prints both keys, Z85 encoded.
+
+use std::process;
+
+use libzmq_rs::{curve_keypair, ZmqError};
+
+fn main() {
+    println!("This tool generates a CurveZMQ keypair, as two printable strings you can");
+    println!("use in configuration files or source code. The encoding uses Z85, which");
+    println!("is a base-85 format that is described in 0MQ RFC 32. The keypair always");
+    println!("works with the secret key held by one party and the public key");
+    println!("distributed (securely!) to peers wishing to connect to it.");
+
+    let (public_key, secret_key) = match curve_keypair() {
+        Ok(keypair) => keypair,
+        Err(ZmqError::NotSupported) => {
+            println!("To use curve_keygen, please rebuild with the `curve` feature enabled.");
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("curve_keygen: {}", e);
+            process::exit(1);
+        }
+    };
+
+    println!("\n== CURVE PUBLIC KEY ==");
+    println!("{}", public_key);
+    println!("\n== CURVE SECRET KEY ==");
+    println!("{}", secret_key);
+}
@@ -1,36 +1,49 @@
 // SPDX-License-Identifier: MPL-2.0
 
+use crate::constants::ZMQ_EAGAIN;
 use crate::context::Context;
 use crate::message::Message;
-use crate::pipe::MsgFlags::MORE;
 use crate::pipe::Pipe;
-use crate::session_base::{SessionBase, ZMQ_CHANNEL};
-use crate::socket_base::SocketBase;
+use crate::socket_base::{SocketBase, SocketBehavior};
+use crate::zmq_draft::ZMQ_CHANNEL;
 use std::sync::Arc;
 
+/// CHANNEL socket: thread-safe, single-part counterpart of PAIR.
 pub struct Channel {
+    base: SocketBase,
     pipe: Option<Pipe>,
-    // other fields from SocketBase
-    socket_base: SocketBase,
 }
 
 impl Channel {
-    pub fn new(parent: &Arc<Context>, tid: u32, sid: i32) -> Self {
-        let mut channel = Channel {
-            pipe: None,
-            // initialize other fields from SocketBase
-            socket_base: SocketBase::new(parent, tid, sid, false),
-        };
-        channel.socket_base.options.type_ = ZMQ_CHANNEL;
-        channel
+    pub fn new(ctx: &Arc<Context>, tid: u32, sid: i32) -> Self {
+        let mut base = SocketBase::new(ctx, tid, sid, true);
+        base.options.socket_type = ZMQ_CHANNEL;
+
+        Channel { base, pipe: None }
+    }
+
+    fn read(&self, msg: &mut Message) -> Result<(), i32> {
+        match &self.pipe {
+            Some(pipe) if pipe.read(msg) => Ok(()),
+            _ => {
+                // Initialise the output parameter to be a 0-byte message.
+                *msg = Message::new();
+                Err(ZMQ_EAGAIN)
+            }
+        }
+    }
+}
+
+impl SocketBehavior for Channel {
+    fn base(&self) -> &SocketBase {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut SocketBase {
+        &mut self.base
     }
 
-    pub fn xattach_pipe(
-        &mut self,
-        pipe: Arc<Pipe>,
-        subscribe_to_all: bool,
-        locally_initiated: bool,
-    ) {
+    fn xattach_pipe(&mut self, pipe: Pipe, _subscribe_to_all: bool, _locally_initiated: bool) {
         // ZMQ_PAIR socket can only be connected to a single peer.
         // The socket rejects any further connection requests.
         if self.pipe.is_none() {
@@ -40,79 +53,50 @@ impl Channel {
         }
     }
 
-    pub fn xpipe_terminated(&mut self, pipe: &Arc<Pipe>) {
+    fn xpipe_terminated(&mut self, pipe: &Pipe) {
         if self.pipe.as_ref() == Some(pipe) {
             self.pipe = None;
         }
     }
 
-    pub fn xread_activated(&self, _pipe: &Arc<Pipe>) {
-        // There's just one pipe. No lists of active and inactive pipes.
-        // There's nothing to do here.
-    }
-
-    pub fn xwrite_activated(&self, _pipe: &Arc<Pipe>) {
-        // There's just one pipe. No lists of active and inactive pipes.
-        // There's nothing to do here.
-    }
-
-    pub fn xsend(&mut self, msg: &mut Message) -> Result<(), i32> {
+    fn xsend(&mut self, msg: &mut Message) -> Result<(), i32> {
         // CHANNEL sockets do not allow multipart data (ZMQ_SNDMORE)
-        if msg.flags() & MORE != 0 {
+        if msg.has_more() {
             return Err(libc::EINVAL);
         }
 
-        if let Some(pipe) = &self.pipe {
-            if !pipe.write(msg) {
-                return Err(libc::EAGAIN);
+        match &self.pipe {
+            Some(pipe) if pipe.write(msg) => {
+                pipe.flush();
+                Ok(())
             }
-            pipe.flush();
-            msg.init().map_err(|_| libc::EINVAL)?;
-            Ok(())
-        } else {
-            Err(libc::EAGAIN)
+            _ => Err(ZMQ_EAGAIN),
         }
     }
 
-    pub fn xrecv(&mut self, msg: &mut Message) -> Result<(), i32> {
-        msg.close().map_err(|_| libc::EINVAL)?;
-
-        if let Some(pipe) = &self.pipe {
-            let mut read = pipe.read(msg);
-            while read && msg.flags() & MORE != 0 {
-                read = pipe.read(msg);
-                while read && msg.flags() & MORE != 0 {
-                    read = pipe.read(msg);
-                }
-                if read {
-                    read = pipe.read(msg);
-                }
-            }
+    fn xrecv(&mut self, msg: &mut Message) -> Result<(), i32> {
+        self.read(msg)?;
 
-            if !read {
-                msg.init().map_err(|_| libc::EINVAL)?;
-                return Err(libc::EAGAIN);
+        // Drop any messages with more flag
+        while msg.has_more() {
+            // drop all frames of the current multi-frame message
+            self.read(msg)?;
+            while msg.has_more() {
+                self.read(msg)?;
             }
-            Ok(())
-        } else {
-            msg.init().map_err(|_| libc::EINVAL)?;
-            Err(libc::EAGAIN)
+
+            // get the new message
+            self.read(msg)?;
         }
+
+        Ok(())
     }
 
-    pub fn xhas_in(&self) -> bool {
-        if let Some(pipe) = &self.pipe {
-            pipe.check_read()
-        } else {
-            false
-        }
+    fn xhas_in(&mut self) -> bool {
+        self.pipe.as_ref().map_or(false, |pipe| pipe.check_read())
     }
 
-    pub fn xhas_out(&self) -> bool {
-        if let Some(pipe) = &self.pipe {
-            pipe.check_write()
-        } else {
-            false
-        }
+    fn xhas_out(&mut self) -> bool {
+        self.pipe.as_ref().map_or(false, |pipe| pipe.check_write())
     }
 }
@@ -2,93 +2,80 @@
 use crate::context::Context;
 use crate::fair_queue::FairQueue;
 use crate::load_balancer::LoadBalancer;
-use crate::message::*;
-use crate::options::Options;
+use crate::message::Message;
 use crate::pipe::Pipe;
-use crate::session_base::ZMQ_CLIENT;
-use crate::socket_base::SocketBase;
+use crate::socket_base::{SocketBase, SocketBehavior};
+use crate::zmq_draft::ZMQ_CLIENT;
 use libc::EINVAL;
+use std::sync::Arc;
 
+/// CLIENT socket: single-part counterpart of DEALER talking to SERVER.
 pub struct Client {
-    options: Options,
+    base: SocketBase,
+    // Messages are fair-queued from inbound pipes. And load-balanced to
+    // the outbound pipes.
     fq: FairQueue,
     lb: LoadBalancer,
-    socket_base: SocketBase,
-    pipe: Pipe,
 }
 
 impl Client {
-    pub fn new(parent: &Context, tid: u32, sid: i32) -> Self {
-        let mut options = Options::default();
-        options.can_send_hello_msg = true;
-        options.can_recv_hiccup_msg = true;
-
-        let mut c = Client {
-            options,
+    pub fn new(ctx: &Arc<Context>, tid: u32, sid: i32) -> Self {
+        let mut base = SocketBase::new(ctx, tid, sid, true);
+        base.options.socket_type = ZMQ_CLIENT;
+        base.options.can_send_hello_msg = true;
+        base.options.can_recv_hiccup_msg = true;
+
+        Client {
+            base,
             fq: FairQueue::new(),
             lb: LoadBalancer::new(),
-            socket_base: SocketBase::new(parent, tid, sid, false),
-            pipe: Pipe::new(),
-        };
-        c.socket_base.options.type_ = ZMQ_CLIENT;
+        }
     }
+}
 
-    pub fn attach_pipe(&mut self, pipe: &Pipe, subscribe_to_all: bool, locally_initiated: bool) {
-        // LIBZMQ_UNUSED!(subscribe_to_all);
-        // LIBZMQ_UNUSED!(locally_initiated);
+impl SocketBehavior for Client {
+    fn base(&self) -> &SocketBase {
+        &self.base
+    }
 
-        // zmq_assert!(pipe);
+    fn base_mut(&mut self) -> &mut SocketBase {
+        &mut self.base
+    }
 
-        self.fq.attach(pipe);
+    fn xattach_pipe(&mut self, pipe: Pipe, _subscribe_to_all: bool, _locally_initiated: bool) {
+        self.fq.attach(pipe.clone());
         self.lb.attach(pipe);
     }
 
-    pub fn send(&mut self, msg: &mut Message) -> Result<(), i32> {
+    fn xsend(&mut self, msg: &mut Message) -> Result<(), i32> {
         // CLIENT sockets do not allow multipart data (ZMQ_SNDMORE)
-        if msg.flags() & MsgFlags::More != 0 {
+        if msg.has_more() {
             return Err(EINVAL);
         }
-        self.lb.sendpipe(msg, None)
+        self.lb.sendpipe(msg).map(|_| ())
     }
 
-    pub fn recv(&mut self, msg: &mut Message) -> Result<(), i32> {
-        let mut rc = self.fq.recvpipe(msg, None);
-
-        // Drop any messages with more flag
-        while rc.is_ok() && msg.flags() & MsgFlags::More != 0 {
-            // drop all frames of the current multi-frame message
-            rc = self.fq.recvpipe(msg, None);
-
-            while rc.is_ok() && msg.flags() & MsgFlags::More != 0 {
-                rc = self.fq.recvpipe(msg, None);
-            }
-
-            // get the new message
-            if rc.is_ok() {
-                rc = self.fq.recvpipe(msg, None);
-            }
-        }
-
-        rc
+    fn xrecv(&mut self, msg: &mut Message) -> Result<(), i32> {
+        self.fq.recvpipe_single_part(msg).map(|_| ())
     }
 
-    pub fn has_in(&mut self) -> bool {
+    fn xhas_in(&mut self) -> bool {
         self.fq.has_in()
     }
 
-    pub fn has_out(&mut self) -> bool {
+    fn xhas_out(&mut self) -> bool {
         self.lb.has_out()
     }
 
-    pub fn read_activated(&mut self, pipe: &Pipe) {
+    fn xread_activated(&mut self, pipe: &Pipe) {
         self.fq.activated(pipe);
     }
 
-    pub fn write_activated(&mut self, pipe: &Pipe) {
+    fn xwrite_activated(&mut self, pipe: &Pipe) {
         self.lb.activated(pipe);
     }
 
-    pub fn pipe_terminated(&mut self, pipe: &Pipe) {
+    fn xpipe_terminated(&mut self, pipe: &Pipe) {
         self.fq.pipe_terminated(pipe);
         self.lb.pipe_terminated(pipe);
     }
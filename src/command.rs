@@ -1,104 +1,44 @@
-use crate::object::{Object, Own};
+//! Commands exchanged between the context, sockets and I/O objects.
+//!
+//! In libzmq a command carries a raw destination pointer. Here every
+//! mailbox belongs to exactly one object, so the destination is implicit in
+//! the mailbox the command is posted to.
+
+use crate::io_object::IoObject;
 use crate::pipe::Pipe;
-use crate::session_base::{Engine, SocketBase};
-use std::ffi::c_int;
-// Forward declarations for external types
-// pub trait Object {}
-// pub trait Own: Object {}
-// pub trait Engine {}
-// pub trait Pipe {}
-// pub trait SocketBase {}
 
-#[repr(C)]
 #[derive(Debug)]
-pub enum CommandType {
+pub enum Command {
+    /// Sent to a socket by the context on termination; blocking calls
+    /// return ETERM from then on.
     Stop,
-    Plug,
-    Own,
-    Attach,
-    Bind,
-    ActivateRead,
-    ActivateWrite,
-    Hiccup,
-    PipeTerm,
-    PipeTermAck,
-    PipeHwm,
-    TermReq,
-    Term,
-    TermAck,
-    TermEndpoint,
-    Reap,
-    Reaped,
-    InprocConnected,
-    ConnFailed,
-    PipePeerStats,
-    PipeStatsPublish,
+
+    /// Wakes a thread blocked on the mailbox without carrying any work.
     Done,
-}
 
-#[derive(Debug)]
-pub struct EndpointUriPair {
-    // Implementation details to be added based on the original C++ endpoint_uri_pair_t
-}
+    /// Sent by the writing end of a pipe to the owner of the reading end
+    /// once messages are available again.
+    ActivateRead(Pipe),
 
-#[repr(C)]
-#[derive(Debug)]
-pub enum CommandArgs {
-    Stop,
-    Plug,
-    Own {
-        object: Box<dyn Own>,
-    },
-    Attach {
-        engine: Option<Box<dyn Engine>>,
-    },
-    Bind {
-        pipe: Box<dyn Pipe>,
-    },
-    ActivateRead,
-    ActivateWrite {
-        msgs_read: u64,
-    },
-    Hiccup {
-        pipe: Box<dyn Pipe>,
-    },
-    PipeTerm,
-    PipeTermAck,
-    PipeHwm {
-        inhwm: c_int,
-        outhwm: c_int,
-    },
-    TermReq {
-        object: Box<dyn Own>,
-    },
-    Term {
-        linger: c_int,
-    },
-    TermAck,
-    TermEndpoint {
-        endpoint: String,
-    },
-    Reap {
-        socket: Box<dyn SocketBase>,
-    },
-    Reaped,
-    PipePeerStats {
-        queue_count: u64,
-        socket_base: Box<dyn Own>,
-        endpoint_pair: Box<EndpointUriPair>,
-    },
-    PipeStatsPublish {
-        outbound_queue_count: u64,
-        inbound_queue_count: u64,
-        endpoint_pair: Box<EndpointUriPair>,
-    },
-    Done,
-}
+    /// Sent by the reading end of a pipe to the owner of the writing end
+    /// once the queue drained below the low water mark.
+    ActivateWrite(Pipe),
+
+    /// Hands a new pipe to a socket bound to an inproc endpoint.
+    Bind(Pipe),
+
+    /// Tells the owner of the pipe that it is being terminated.
+    PipeTerm(Pipe),
+
+    /// Sent by the session to the socket when the connection was
+    /// re-established and messages queued in the meantime were dropped.
+    Hiccup(Pipe),
+
+    /// Hands a new object over to an I/O thread, which plugs it in.
+    Plug(Box<dyn IoObject>),
 
-#[repr(C)]
-#[cfg_attr(target_os = "linux", repr(align(64)))] // ZMQ_CACHELINE_SIZE is typically 64
-pub struct Command {
-    pub destination: Box<dyn Object>,
-    pub typ: CommandType,
-    pub args: CommandArgs,
+    /// Asks an I/O object to shut down, waiting up to the given linger
+    /// period (in milliseconds, -1 = forever) for outbound messages to be
+    /// sent.
+    Term(i32),
 }
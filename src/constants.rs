@@ -23,16 +23,31 @@ pub const ZMQ_EINTR: c_int = libc::EINTR;
 #[cfg(windows)]
 pub const ZMQ_EINTR: c_int = winapi::um::winsock2::WSAEINTR;
 
-#[cfg(unix)]
-pub const ZMQ_EFSM: c_int = libc::EFSM;
-#[cfg(windows)]
-pub const ZMQ_EFSM: c_int = 10052;
+/*  A number random enough not to collide with different errno ranges on      */
+/*  different OSes. The assumption is that error_t is at least 32-bit type.   */
+// #define ZMQ_HAUSNUMERO 156384712
+pub const ZMQ_HAUSNUMERO: c_int = 156384712;
+
+/*  Native 0MQ error codes.                                                   */
+// #define EFSM (ZMQ_HAUSNUMERO + 51)
+pub const ZMQ_EFSM: c_int = ZMQ_HAUSNUMERO + 51;
+// #define ENOCOMPATPROTO (ZMQ_HAUSNUMERO + 52)
+pub const ZMQ_ENOCOMPATPROTO: c_int = ZMQ_HAUSNUMERO + 52;
+// #define ETERM (ZMQ_HAUSNUMERO + 53)
+pub const ZMQ_ETERM: c_int = ZMQ_HAUSNUMERO + 53;
+// #define EMTHREAD (ZMQ_HAUSNUMERO + 54)
+pub const ZMQ_EMTHREAD: c_int = ZMQ_HAUSNUMERO + 54;
 
 #[cfg(unix)]
 pub const ZMQ_EPROTO: c_int = libc::EPROTO;
 #[cfg(windows)]
 pub const ZMQ_EPROTO: c_int = 10053;
 
+#[cfg(unix)]
+pub const ZMQ_ENOTSOCK: c_int = libc::ENOTSOCK;
+#[cfg(windows)]
+pub const ZMQ_ENOTSOCK: c_int = winapi::um::winsock2::WSAENOTSOCK;
+
 #[cfg(unix)]
 pub const ZMQ_EFAULT: c_int = libc::EFAULT;
 #[cfg(windows)]
@@ -83,6 +98,41 @@ pub const ZMQ_PROTOCOL_ERROR_ZAP_INVALID_METADATA: i32 = 0x20000005;
 // #define ZMQ_PROTOCOL_ERROR_WS_UNSPECIFIED 0x30000000
 pub const ZMQ_PROTOCOL_ERROR_WS_UNSPECIFIED: i32 = 0x30000000;
 
+/*  Context options                                                           */
+// #define ZMQ_IO_THREADS 1
+pub const ZMQ_IO_THREADS: i32 = 1;
+// #define ZMQ_MAX_SOCKETS 2
+pub const ZMQ_MAX_SOCKETS: i32 = 2;
+// #define ZMQ_SOCKET_LIMIT 3
+pub const ZMQ_SOCKET_LIMIT: i32 = 3;
+// #define ZMQ_THREAD_PRIORITY 3
+pub const ZMQ_THREAD_PRIORITY: i32 = 3;
+// #define ZMQ_THREAD_SCHED_POLICY 4
+pub const ZMQ_THREAD_SCHED_POLICY: i32 = 4;
+// #define ZMQ_MAX_MSGSZ 5
+pub const ZMQ_MAX_MSGSZ: i32 = 5;
+// #define ZMQ_MSG_T_SIZE 6
+pub const ZMQ_MSG_T_SIZE: i32 = 6;
+
+// #define ZMQ_IO_THREADS_DFLT 1
+pub const ZMQ_IO_THREADS_DFLT: i32 = 1;
+// #define ZMQ_MAX_SOCKETS_DFLT 1023
+pub const ZMQ_MAX_SOCKETS_DFLT: i32 = 1023;
+
+/*  Message options                                                           */
+// #define ZMQ_MORE 1
+pub const ZMQ_MORE: i32 = 1;
+// #define ZMQ_SHARED 3
+pub const ZMQ_SHARED: i32 = 3;
+// #define ZMQ_SRCFD 2
+pub const ZMQ_SRCFD: i32 = 2;
+
+/*  Send/recv options.                                                        */
+// #define ZMQ_DONTWAIT 1
+pub const ZMQ_DONTWAIT: i32 = 1;
+// #define ZMQ_SNDMORE 2
+pub const ZMQ_SNDMORE: i32 = 2;
+
 // Constants from zmq.h would go here
 pub const ZMQ_VERSION_MAJOR: i32 = 4;
 pub const ZMQ_VERSION_MINOR: i32 = 3;
@@ -174,7 +224,7 @@ pub const ZMQ_SNDBUF: i32 = 11;
 // #define ZMQ_RCVBUF 12
 pub const ZMQ_RCVBUF: i32 = 12;
 // #define ZMQ_RCVMORE 13
-pub const ZMQ_RECV_MORE: i32 = 13;
+pub const ZMQ_RCVMORE: i32 = 13;
 // #define ZMQ_FD 14
 pub const ZMQ_FD: i32 = 14;
 // #define ZMQ_EVENTS 15
@@ -201,6 +251,8 @@ pub const ZMQ_MULTICAST_HOPS: i32 = 25;
 pub const ZMQ_RCVTIMEO: i32 = 27;
 // #define ZMQ_SNDTIMEO 28
 pub const ZMQ_SNDTIMEO: i32 = 28;
+// #define ZMQ_IPV4ONLY 31
+pub const ZMQ_IPV4ONLY: i32 = 31;
 // #define ZMQ_LAST_ENDPOINT 32
 pub const ZMQ_LAST_ENDPOINT: i32 = 32;
 // #define ZMQ_ROUTER_MANDATORY 33
@@ -310,10 +362,25 @@ pub const ZMQ_GSSAPI_SERVICE_PRINCIPAL_NAMETYPE: i32 = 91;
 // #define ZMQ_BINDTODEVICE 92
 pub const ZMQ_BINDTODEVICE: i32 = 92;
 
-#[cfg(unix)]
-pub const ZMQ_ETERM: c_int = libc::ETERM;
-#[cfg(windows)]
-pub const ZMQ_ETERM: c_int = winapi::um::winsock2::WSAESHUTDOWN;
+/*  Security mechanisms                                                       */
+// #define ZMQ_NULL 0
+pub const ZMQ_NULL: i32 = 0;
+// #define ZMQ_PLAIN 1
+pub const ZMQ_PLAIN: i32 = 1;
+// #define ZMQ_CURVE 2
+pub const ZMQ_CURVE: i32 = 2;
+// #define ZMQ_GSSAPI 3
+pub const ZMQ_GSSAPI: i32 = 3;
+
+/*  I/O multiplexing.                                                         */
+// #define ZMQ_POLLIN 1
+pub const ZMQ_POLLIN: i16 = 1;
+// #define ZMQ_POLLOUT 2
+pub const ZMQ_POLLOUT: i16 = 2;
+// #define ZMQ_POLLERR 4
+pub const ZMQ_POLLERR: i16 = 4;
+// #define ZMQ_POLLPRI 8
+pub const ZMQ_POLLPRI: i16 = 8;
 
 #[cfg(unix)]
 pub const ZMQ_EPROTONOSUPPORT: c_int = libc::EPROTONOSUPPORT;
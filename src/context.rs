@@ -1,26 +1,38 @@
 use std::collections::{HashMap, HashSet};
-use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
-use std::sync::Mutex;
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::command::Command;
+use crate::constants::{
+    ZMQ_BLOCKY, ZMQ_ETERM, ZMQ_IO_THREADS, ZMQ_IO_THREADS_DFLT, ZMQ_IPV6, ZMQ_MAX_MSGSZ,
+    ZMQ_EMTHREAD, ZMQ_MAX_SOCKETS, ZMQ_MAX_SOCKETS_DFLT, ZMQ_MSG_T_SIZE, ZMQ_SOCKET_LIMIT,
+    ZMQ_THREAD_PRIORITY, ZMQ_THREAD_SCHED_POLICY,
+};
+use crate::i_mailbox::IMailbox;
+use crate::io_thread::IoThread;
+use crate::message::{zmq_msg_t, Message};
+use crate::options::{get_effective_conflate_option, Options};
+use crate::pipe::Pipe;
+use crate::socket_base::{send_routing_id, SocketBase, SocketBehavior};
+use crate::zmq_draft::ZMQ_ZERO_COPY_RECV;
 
 // Constants
 const ZMQ_CTX_TAG_VALUE_GOOD: u32 = 0xabadcafe;
 const ZMQ_CTX_TAG_VALUE_BAD: u32 = 0xdeadbeef;
-const ZMQ_MAX_SOCKETS_DFLT: i32 = 1024;
-const ZMQ_IO_THREADS_DFLT: i32 = 1;
 
-// Types from other ZMQ modules that we'll need to interact with
-type Socket = Box<dyn SocketTrait>;
-type IoThread = Box<dyn IoThreadTrait>;
-type Pipe = Box<dyn PipeTrait>;
-type Mailbox = Box<dyn MailboxTrait>;
-
-// Endpoint information
+// A socket bound to an inproc endpoint, as seen by connecting peers.
+#[derive(Clone)]
 pub struct Endpoint {
-    socket: Socket,
-    options: Options,
+    // Thread ID of the socket, identifying it while it is alive.
+    pub tid: u32,
+    // Where pipes for new connections are sent to.
+    pub mailbox: Arc<dyn IMailbox>,
+    // Snapshot of the socket's options at bind time.
+    pub options: Options,
 }
 
-// Pending connection information
+// An inproc connection made before anybody bound to its endpoint.
 pub struct PendingConnection {
     endpoint: Endpoint,
     connect_pipe: Pipe,
@@ -54,14 +66,12 @@ impl ThreadContext {
             let val = i32::from_ne_bytes(value.try_into().unwrap());
 
             match option {
-                // ZMQ_THREAD_SCHED_POLICY
-                1 if val >= 0 => {
+                ZMQ_THREAD_SCHED_POLICY if val >= 0 => {
                     self.thread_sched_policy = val;
                     return Ok(());
                 }
 
-                // ZMQ_THREAD_PRIORITY
-                2 if val >= 0 => {
+                ZMQ_THREAD_PRIORITY if val >= 0 => {
                     self.thread_priority = val;
                     return Ok(());
                 }
@@ -78,12 +88,16 @@ impl ThreadContext {
 
         if value.len() == std::mem::size_of::<i32>() {
             match option {
-                // ZMQ_THREAD_SCHED_POLICY
-                1 => {
+                ZMQ_THREAD_SCHED_POLICY => {
                     value.copy_from_slice(&self.thread_sched_policy.to_ne_bytes());
                     return Ok(());
                 }
 
+                ZMQ_THREAD_PRIORITY => {
+                    value.copy_from_slice(&self.thread_priority.to_ne_bytes());
+                    return Ok(());
+                }
+
                 _ => {}
             }
         }
@@ -94,13 +108,14 @@ impl ThreadContext {
 
 // Main context
 pub struct Context {
-    tag: u32,
+    tag: AtomicU32,
     starting: AtomicBool,
     terminating: AtomicBool,
 
     // Synchronization
-    slot_sync: Mutex<()>,
-    endpoints_sync: Mutex<()>,
+    slot_sync: Mutex<Slots>,
+    slot_cond: Condvar,
+    endpoints_sync: Mutex<Endpoints>,
     opt_sync: Mutex<()>,
 
     // Configuration
@@ -111,26 +126,59 @@ pub struct Context {
     max_msgsz: AtomicI32,
     zero_copy: AtomicBool,
 
-    // Storage
-    sockets: Vec<Socket>,
-    io_threads: Vec<IoThread>,
-    slots: Vec<Option<Mailbox>>,
-    empty_slots: Vec<u32>,
+    // Used to hand out unique socket IDs
+    max_socket_id: AtomicI32,
+
+    thread_ctx: Mutex<ThreadContext>,
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        // Every socket holds a reference, so none is left when the context
+        // is dropped without being terminated. Only the I/O threads remain;
+        // they finish the lingering sessions, then exit.
+        let slots = self.slot_sync.get_mut().unwrap_or_else(|e| e.into_inner());
+        let io_threads = mem::take(&mut slots.io_threads);
+        Self::stop_io_threads(&io_threads);
+    }
+}
+
+// Inproc endpoints, guarded together by `endpoints_sync`.
+#[derive(Default)]
+struct Endpoints {
+    // List of inproc endpoints within this context.
     endpoints: HashMap<String, Endpoint>,
+    // Connections to endpoints nobody has bound to yet.
     pending_connections: HashMap<String, Vec<PendingConnection>>,
+}
 
-    thread_ctx: ThreadContext,
+// Mailbox slots, guarded together by `slot_sync`.
+struct Slots {
+    // Mailboxes of all objects (sockets, I/O threads) indexed by thread ID
+    slots: Vec<Option<Arc<dyn IMailbox>>>,
+    // Unused slot indices, popped from the back
+    empty_slots: Vec<u32>,
+    // Thread IDs of the sockets that are currently open
+    sockets: Vec<u32>,
+    // I/O threads, started along with the slot table
+    io_threads: Vec<Arc<IoThread>>,
 }
 
 impl Context {
     pub fn new() -> Self {
         Context {
-            tag: ZMQ_CTX_TAG_VALUE_GOOD,
+            tag: AtomicU32::new(ZMQ_CTX_TAG_VALUE_GOOD),
             starting: AtomicBool::new(true),
             terminating: AtomicBool::new(false),
 
-            slot_sync: Mutex::new(()),
-            endpoints_sync: Mutex::new(()),
+            slot_sync: Mutex::new(Slots {
+                slots: Vec::new(),
+                empty_slots: Vec::new(),
+                sockets: Vec::new(),
+                io_threads: Vec::new(),
+            }),
+            slot_cond: Condvar::new(),
+            endpoints_sync: Mutex::new(Endpoints::default()),
             opt_sync: Mutex::new(()),
 
             max_sockets: AtomicI32::new(ZMQ_MAX_SOCKETS_DFLT),
@@ -140,106 +188,362 @@ impl Context {
             max_msgsz: AtomicI32::new(i32::MAX),
             zero_copy: AtomicBool::new(true),
 
-            sockets: Vec::new(),
-            io_threads: Vec::new(),
-            slots: Vec::new(),
-            empty_slots: Vec::new(),
-            endpoints: HashMap::new(),
-            pending_connections: HashMap::new(),
+            max_socket_id: AtomicI32::new(0),
 
-            thread_ctx: ThreadContext::new(),
+            thread_ctx: Mutex::new(ThreadContext::new()),
         }
     }
 
-    pub fn get_socket_type() -> i32 {
-        // if socket_id < 0 || socket_id >= self.sockets.len() as i32 {
-        //     return -1;
-        // }
+    pub fn check_tag(&self) -> bool {
+        self.tag.load(Ordering::SeqCst) == ZMQ_CTX_TAG_VALUE_GOOD
+    }
 
-        // self.sockets[socket_id as usize].options.type_
-        todo!()
+    pub fn set(&self, option: i32, value: i32) -> Result<(), i32> {
+        let _lock = self.opt_sync.lock().unwrap();
+
+        match option {
+            ZMQ_MAX_SOCKETS if value >= 1 && value <= clipped_maxsocket(i32::MAX) => {
+                self.max_sockets.store(value, Ordering::SeqCst)
+            }
+            ZMQ_IO_THREADS if value >= 0 => self.io_thread_count.store(value, Ordering::SeqCst),
+            ZMQ_IPV6 if value >= 0 => self.ipv6.store(value != 0, Ordering::SeqCst),
+            ZMQ_BLOCKY if value >= 0 => self.blocky.store(value != 0, Ordering::SeqCst),
+            ZMQ_MAX_MSGSZ if value >= 0 => self.max_msgsz.store(value, Ordering::SeqCst),
+            ZMQ_ZERO_COPY_RECV if value >= 0 => self.zero_copy.store(value != 0, Ordering::SeqCst),
+            _ => {
+                return self
+                    .thread_ctx
+                    .lock()
+                    .unwrap()
+                    .set(option, &value.to_ne_bytes())
+            }
+        }
+
+        Ok(())
     }
 
-    pub fn check_tag(&self) -> bool {
-        self.tag == ZMQ_CTX_TAG_VALUE_GOOD
+    pub fn get(&self, option: i32) -> Result<i32, i32> {
+        let _lock = self.opt_sync.lock().unwrap();
+
+        match option {
+            ZMQ_MAX_SOCKETS => Ok(self.max_sockets.load(Ordering::SeqCst)),
+            ZMQ_SOCKET_LIMIT => Ok(clipped_maxsocket(i32::MAX)),
+            ZMQ_IO_THREADS => Ok(self.io_thread_count.load(Ordering::SeqCst)),
+            ZMQ_IPV6 => Ok(self.ipv6.load(Ordering::SeqCst) as i32),
+            ZMQ_BLOCKY => Ok(self.blocky.load(Ordering::SeqCst) as i32),
+            ZMQ_MAX_MSGSZ => Ok(self.max_msgsz.load(Ordering::SeqCst)),
+            ZMQ_MSG_T_SIZE => Ok(std::mem::size_of::<zmq_msg_t>() as i32),
+            ZMQ_ZERO_COPY_RECV => Ok(self.zero_copy.load(Ordering::SeqCst) as i32),
+            _ => {
+                let mut value = [0u8; 4];
+                self.thread_ctx.lock().unwrap().get(option, &mut value)?;
+                Ok(i32::from_ne_bytes(value))
+            }
+        }
     }
 
-    pub fn terminate(&mut self) -> Result<(), i32> {
-        let _slot_lock = self.slot_sync.lock().unwrap();
+    // Lazily sets up the slot table the first time a socket is created, so
+    // that ZMQ_MAX_SOCKETS can still be changed right after zmq_ctx_new.
+    fn start(&self, slots: &mut Slots) -> Result<(), i32> {
+        let mazmq = self.max_sockets.load(Ordering::SeqCst) as u32;
+        let ios = self.io_thread_count.load(Ordering::SeqCst) as u32;
+
+        // Slot 0 is the termination mailbox, slot 1 the reaper; the I/O
+        // threads follow and the sockets take the rest.
+        let slot_count = mazmq + ios + 2;
+
+        // Create I/O thread objects and launch them.
+        let mut io_threads = Vec::with_capacity(ios as usize);
+        for tid in 2..ios + 2 {
+            let io_thread = IoThread::new(tid).map_err(|_| libc::EMFILE)?;
+            if io_thread.start().is_err() {
+                Self::stop_io_threads(&io_threads);
+                return Err(libc::EMFILE);
+            }
+            io_threads.push(io_thread);
+        }
+        slots.io_threads = io_threads;
+
+        slots.slots = (0..slot_count).map(|_| None).collect();
+        slots.empty_slots = (ios + 2..slot_count).rev().collect();
 
-        // Handle pending connections
-        let pending = self.pending_connections.clone();
-        for (addr, conns) in pending {
-            // Create socket and bind...
+        self.starting.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn stop_io_threads(io_threads: &[Arc<IoThread>]) {
+        for io_thread in io_threads {
+            io_thread.stop();
+        }
+        for io_thread in io_threads {
+            io_thread.join();
         }
+    }
 
-        if !self.starting.load(Ordering::SeqCst) {
-            self.terminating.store(true, Ordering::SeqCst);
+    /// Returns the least loaded I/O thread among those allowed by the
+    /// affinity bitmask (0 allows any).
+    pub fn choose_io_thread(&self, affinity: u64) -> Result<Arc<IoThread>, i32> {
+        let slots = self.slot_sync.lock().unwrap();
+
+        // Find the I/O thread with minimum load.
+        slots
+            .io_threads
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| affinity == 0 || (*i < 64 && affinity & (1 << i) != 0))
+            .min_by_key(|(_, io_thread)| io_thread.get_load())
+            .map(|(_, io_thread)| Arc::clone(io_thread))
+            .ok_or(ZMQ_EMTHREAD)
+    }
+
+    pub fn create_socket(self: &Arc<Self>, socket_type: i32) -> Result<Box<dyn SocketBehavior>, i32> {
+        let mut slots = self.slot_sync.lock().unwrap();
+
+        // Once zmq_ctx_term() or zmq_ctx_shutdown() was called, we can't
+        // create new sockets.
+        if self.terminating.load(Ordering::SeqCst) {
+            return Err(ZMQ_ETERM);
+        }
+
+        if self.starting.load(Ordering::SeqCst) {
+            self.start(&mut slots)?;
+        }
+
+        // If max_sockets limit was reached, return error.
+        let slot = match slots.empty_slots.pop() {
+            Some(slot) => slot,
+            None => return Err(libc::EMFILE),
+        };
+
+        // Generate new unique socket ID.
+        let sid = self.max_socket_id.fetch_add(1, Ordering::SeqCst) + 1;
+
+        let socket = match SocketBase::create(socket_type, self, slot, sid) {
+            Ok(socket) => socket,
+            Err(e) => {
+                slots.empty_slots.push(slot);
+                return Err(e);
+            }
+        };
+
+        slots.slots[slot as usize] = Some(socket.get_mailbox());
+        slots.sockets.push(slot);
+
+        Ok(socket)
+    }
 
-            // Stop all sockets
-            for socket in &self.sockets {
-                socket.stop();
+    /// Releases the slot of a closed socket. Wakes up zmq_ctx_term() once
+    /// the last socket is gone.
+    pub fn destroy_socket(&self, tid: u32) {
+        let mut slots = self.slot_sync.lock().unwrap();
+
+        slots.slots[tid as usize] = None;
+        slots.empty_slots.push(tid);
+        slots.sockets.retain(|&t| t != tid);
+
+        if self.terminating.load(Ordering::SeqCst) && slots.sockets.is_empty() {
+            self.slot_cond.notify_all();
+        }
+    }
+
+    /// Sends a command to the object owning the given thread ID.
+    pub fn send_command(&self, tid: u32, cmd: Command) {
+        let slots = self.slot_sync.lock().unwrap();
+        if let Some(Some(mailbox)) = slots.slots.get(tid as usize) {
+            mailbox.send(cmd);
+        }
+    }
+
+    /// Blocks until every socket created in this context has been closed.
+    pub fn terminate(&self) -> Result<(), i32> {
+        let mut slots = self.slot_sync.lock().unwrap();
+
+        if !self.starting.load(Ordering::SeqCst) {
+            // First send stop command to sockets so that any blocking calls
+            // can be interrupted. If there are no sockets we can skip it.
+            if !self.terminating.swap(true, Ordering::SeqCst) {
+                Self::stop_sockets(&slots);
             }
 
-            if self.sockets.is_empty() {
-                // Stop reaper if no sockets
+            while !slots.sockets.is_empty() {
+                slots = self.slot_cond.wait(slots).unwrap();
             }
+
+            // The I/O threads exit once the sessions of the closed sockets
+            // are done sending their pending messages.
+            let io_threads = mem::take(&mut slots.io_threads);
+            drop(slots);
+            Self::stop_io_threads(&io_threads);
         }
+        self.terminating.store(true, Ordering::SeqCst);
 
-        // Cleanup and drop context
-        self.tag = ZMQ_CTX_TAG_VALUE_BAD;
+        // Deallocate the resources.
+        self.tag.store(ZMQ_CTX_TAG_VALUE_BAD, Ordering::SeqCst);
 
         Ok(())
     }
 
-    pub fn shutdown(&mut self) -> Result<(), i32> {
-        let _lock = self.slot_sync.lock().unwrap();
+    /// Interrupts blocking calls on all sockets without waiting for them
+    /// to be closed.
+    pub fn shutdown(&self) -> Result<(), i32> {
+        let slots = self.slot_sync.lock().unwrap();
 
-        if !self.terminating.load(Ordering::SeqCst) {
-            self.terminating.store(true, Ordering::SeqCst);
+        if !self.terminating.swap(true, Ordering::SeqCst) && !self.starting.load(Ordering::SeqCst)
+        {
+            Self::stop_sockets(&slots);
+        }
 
-            if !self.starting.load(Ordering::SeqCst) {
-                // Stop all sockets
-                for socket in &self.sockets {
-                    socket.stop();
-                }
+        Ok(())
+    }
 
-                if self.sockets.is_empty() {
-                    // Stop reaper
-                }
+    fn stop_sockets(slots: &Slots) {
+        for &tid in &slots.sockets {
+            if let Some(mailbox) = &slots.slots[tid as usize] {
+                mailbox.send(Command::Stop);
             }
         }
+    }
+
+    /// Makes an inproc endpoint known to the peers connecting to it.
+    pub fn register_endpoint(&self, addr: &str, endpoint: Endpoint) -> Result<(), i32> {
+        let mut endpoints = self.endpoints_sync.lock().unwrap();
 
+        if endpoints.endpoints.contains_key(addr) {
+            return Err(libc::EADDRINUSE);
+        }
+        endpoints.endpoints.insert(addr.to_string(), endpoint);
         Ok(())
     }
 
-    // Additional methods would go here...
-    #[cfg(feature = "vmci")]
-    pub fn get_vmci_socket_family(&self) -> c_int {
-        todo!()
+    /// Removes the endpoint, provided it was registered by the socket with
+    /// the given thread ID.
+    pub fn unregister_endpoint(&self, addr: &str, tid: u32) -> Result<(), i32> {
+        let mut endpoints = self.endpoints_sync.lock().unwrap();
+
+        match endpoints.endpoints.get(addr) {
+            Some(endpoint) if endpoint.tid == tid => {
+                endpoints.endpoints.remove(addr);
+                Ok(())
+            }
+            _ => Err(libc::ENOENT),
+        }
     }
-}
 
-// Required traits for Socket, IoThread, etc would be defined here
+    /// Removes all endpoints registered by the socket.
+    pub fn unregister_endpoints(&self, tid: u32) {
+        let mut endpoints = self.endpoints_sync.lock().unwrap();
+        endpoints.endpoints.retain(|_, endpoint| endpoint.tid != tid);
+    }
 
-pub trait SocketTrait {
-    fn stop(&self);
-    // Other socket methods...
-}
+    pub fn find_endpoint(&self, addr: &str) -> Result<Endpoint, i32> {
+        let endpoints = self.endpoints_sync.lock().unwrap();
+        endpoints
+            .endpoints
+            .get(addr)
+            .cloned()
+            .ok_or(libc::ECONNREFUSED)
+    }
 
-pub trait IoThreadTrait {
-    // IoThread methods...
-}
+    /// Queues a connection to an endpoint nobody is bound to yet. If the
+    /// bind happened in the meantime, the sockets are connected right away.
+    pub fn pend_connection(
+        &self,
+        addr: &str,
+        endpoint: Endpoint,
+        connect_pipe: Pipe,
+        bind_pipe: Pipe,
+    ) {
+        let pending = PendingConnection {
+            endpoint,
+            connect_pipe,
+            bind_pipe,
+        };
+
+        let mut endpoints = self.endpoints_sync.lock().unwrap();
+        match endpoints.endpoints.get(addr) {
+            // Still no bind.
+            None => endpoints
+                .pending_connections
+                .entry(addr.to_string())
+                .or_default()
+                .push(pending),
+            // Bind has happened in the mean time, connect directly.
+            Some(bind) => Self::connect_inproc_sockets(bind, &pending),
+        }
+    }
 
-pub trait PipeTrait {
-    // Pipe methods...
-}
+    /// Hands the connections waiting for `addr` over to the socket that
+    /// just bound to it.
+    pub fn connect_pending(&self, addr: &str) {
+        let mut endpoints = self.endpoints_sync.lock().unwrap();
+
+        let pending = endpoints
+            .pending_connections
+            .remove(addr)
+            .unwrap_or_default();
+        if let Some(bind) = endpoints.endpoints.get(addr) {
+            for connection in &pending {
+                Self::connect_inproc_sockets(bind, connection);
+            }
+        }
+    }
+
+    fn connect_inproc_sockets(bind: &Endpoint, pending: &PendingConnection) {
+        // The connecting socket always sent its routing id; drop it if the
+        // bound socket doesn't want it.
+        if !bind.options.recv_routing_id {
+            let mut msg = Message::new();
+            pending.bind_pipe.read(&mut msg);
+        }
 
-pub trait MailboxTrait {
-    // Mailbox methods...
+        // The bound socket keeps only the latest message even if the
+        // connecting one doesn't conflate.
+        if get_effective_conflate_option(&bind.options) {
+            pending.bind_pipe.set_conflate();
+        }
+
+        let connect_options = &pending.endpoint.options;
+        if !get_effective_conflate_option(connect_options) {
+            pending.connect_pipe.set_hwms_boost(
+                bind.options.send_high_water_mark,
+                bind.options.recv_high_water_mark,
+            );
+            pending.bind_pipe.set_hwms_boost(
+                connect_options.send_high_water_mark,
+                connect_options.recv_high_water_mark,
+            );
+
+            pending.connect_pipe.set_hwms(
+                connect_options.recv_high_water_mark,
+                connect_options.send_high_water_mark,
+            );
+            pending.bind_pipe.set_hwms(
+                bind.options.recv_high_water_mark,
+                bind.options.send_high_water_mark,
+            );
+        } else {
+            pending.connect_pipe.set_hwms(-1, -1);
+            pending.bind_pipe.set_hwms(-1, -1);
+        }
+
+        bind.mailbox.send(Command::Bind(pending.bind_pipe.clone()));
+
+        // If the connecting socket was closed in the meantime the write
+        // fails quietly.
+        if connect_options.recv_routing_id {
+            send_routing_id(&pending.bind_pipe, &bind.options);
+        }
+    }
+
+    #[cfg(feature = "vmci")]
+    pub fn get_vmci_socket_family(&self) -> c_int {
+        todo!()
+    }
 }
 
-// Options struct would be defined here
-pub struct Options {
-    // Option fields...
+// Highest number of sockets the slot table is allowed to hold.
+fn clipped_maxsocket(max_requested: i32) -> i32 {
+    // Leave room for the termination mailbox and the reaper.
+    let max = i32::from(u16::MAX) - 2;
+    max_requested.min(max)
 }
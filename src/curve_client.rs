@@ -1,14 +1,18 @@
+use sodiumoxide::crypto::box_;
 
-#[cfg(feature = "curve")]
-pub struct CurveClient {
-    state: State,
-    tools: CurveClientTools,
-    session: Session,
-    options: Options,
-}
+use crate::constants::ZMQ_EPROTO;
+use crate::curve_client_tools::CurveClientTools;
+use crate::curve_mechanism_base::{get_short_nonce, make_nonce, CurveEncoding, SHORT_NONCE_LEN};
+use crate::mechanism::{Mechanism, MechanismOps, Status};
+use crate::message::Message;
+use crate::options::Options;
+use crate::session_base::SessionBase;
+
+const READY_COMMAND_NAME_LEN: usize = 6;
+const ERROR_COMMAND_NAME_LEN: usize = 6;
+const ERROR_REASON_LEN_SIZE: usize = 1;
 
-#[cfg(feature = "curve")]
-#[derive(PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum State {
     SendHello,
     ExpectWelcome,
@@ -18,109 +22,101 @@ enum State {
     Connected,
 }
 
-#[cfg(feature = "curve")]
+/// The client side of the CURVE security mechanism (RFC 26): HELLO,
+/// INITIATE with our vouch and properties, then boxed messages.
+pub struct CurveClient {
+    base: Mechanism,
+    encoding: CurveEncoding,
+    tools: CurveClientTools,
+    state: State,
+}
+
 impl CurveClient {
-    pub fn new(session: Session, options: Options, downgrade_sub: bool) -> Self {
+    pub fn new(options: &Options, downgrade_sub: bool) -> Self {
         CurveClient {
-            state: State::SendHello,
+            base: Mechanism::new(options),
+            encoding: CurveEncoding::new(b"CurveZMQMESSAGEC", b"CurveZMQMESSAGES", downgrade_sub),
             tools: CurveClientTools::new(
-                options.curve_public_key,
-                options.curve_secret_key,
-                options.curve_server_key,
+                &options.curve_public_key,
+                &options.curve_secret_key,
+                &options.curve_server_key,
             ),
-            session,
-            options,
+            state: State::SendHello,
         }
     }
 
-    fn produce_hello(&mut self, msg: &mut Message) -> Result<(), Box<dyn std::error::Error>> {
-        msg.init_size(200)?;
-        self.tools.produce_hello(msg.data_mut(), self.get_and_inc_nonce())?;
+    fn produce_hello(&mut self, msg: &mut Message) -> Result<(), i32> {
+        let hello = self.tools.produce_hello(self.encoding.get_and_inc_nonce());
+        *msg = Message::with_data(&hello).map_err(|_| libc::ENOMEM)?;
         Ok(())
     }
 
-    fn process_welcome(&mut self, msg_data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
-        self.tools.process_welcome(msg_data, self.get_writable_precom_buffer())?;
+    fn process_welcome(&mut self, msg_data: &[u8]) -> Result<(), i32> {
+        let precom = self.tools.process_welcome(msg_data)?;
+        self.encoding.set_precom(precom);
         self.state = State::SendInitiate;
         Ok(())
     }
 
-    fn produce_initiate(&mut self, msg: &mut Message) -> Result<(), Box<dyn std::error::Error>> {
-        let metadata_length = self.basic_properties_len();
-        let mut metadata_plaintext = vec![0u8; metadata_length];
-        
-        self.add_basic_properties(&mut metadata_plaintext);
-        
-        let msg_size = 113 + 128 + CRYPTO_BOX_BOXZEROBYTES + metadata_length;
-        msg.init_size(msg_size)?;
-        
-        self.tools.produce_initiate(
-            msg.data_mut(),
-            msg_size,
-            self.get_and_inc_nonce(),
-            &metadata_plaintext,
-            metadata_length,
-        )?;
-        
+    fn produce_initiate(&mut self, msg: &mut Message) -> Result<(), i32> {
+        let mut metadata_plaintext = Vec::new();
+        self.base.add_basic_properties(&mut metadata_plaintext);
+
+        let initiate = self
+            .tools
+            .produce_initiate(self.encoding.get_and_inc_nonce(), &metadata_plaintext);
+        *msg = Message::with_data(&initiate).map_err(|_| libc::ENOMEM)?;
         Ok(())
     }
 
-    fn process_ready(&mut self, msg_data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
-        if msg_data.len() < 30 {
-            return Err("Malformed READY command".into());
+    fn process_ready(&mut self, msg_data: &[u8]) -> Result<(), i32> {
+        if msg_data.len() < READY_COMMAND_NAME_LEN + SHORT_NONCE_LEN + box_::MACBYTES {
+            return Err(ZMQ_EPROTO);
         }
 
-        let clen = (msg_data.len() - 14) + CRYPTO_BOX_BOXZEROBYTES;
-        let mut ready_nonce = [0u8; CRYPTO_BOX_NONCEBYTES];
-        let mut ready_plaintext = vec![0u8; CRYPTO_BOX_ZEROBYTES + clen];
-        let mut ready_box = vec![0u8; CRYPTO_BOX_BOXZEROBYTES + 16 + clen];
-
-        // Copy nonce data
-        ready_nonce[..16].copy_from_slice(b"CurveZMQREADY---");
-        ready_nonce[16..24].copy_from_slice(&msg_data[6..14]);
-        
-        self.set_peer_nonce(u64::from_le_bytes(msg_data[6..14].try_into()?));
-
-        // Decrypt the message
-        crypto_box_open_afternm(
-            &mut ready_plaintext,
-            &ready_box,
-            clen,
+        let short_nonce = &msg_data[READY_COMMAND_NAME_LEN..][..SHORT_NONCE_LEN];
+        let ready_nonce = make_nonce(b"CurveZMQREADY---", short_nonce);
+        let ready_plaintext = box_::open_precomputed(
+            &msg_data[READY_COMMAND_NAME_LEN + SHORT_NONCE_LEN..],
             &ready_nonce,
-            self.get_precom_buffer(),
-        )?;
+            self.encoding.precom(),
+        )
+        .map_err(|_| ZMQ_EPROTO)?;
+        self.encoding.set_peer_nonce(get_short_nonce(short_nonce));
 
-        self.parse_metadata(&ready_plaintext[CRYPTO_BOX_ZEROBYTES..])?;
+        self.base.parse_metadata(&ready_plaintext, false)?;
         self.state = State::Connected;
-        
         Ok(())
     }
 
-    fn process_error(&mut self, msg_data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
-        if self.state != State::ExpectWelcome && self.state != State::ExpectReady {
-            return Err("Unexpected command".into());
-        }
-        
-        if msg_data.len() < 7 {
-            return Err("Malformed ERROR command".into());
+    fn process_error(&mut self, msg_data: &[u8]) -> Result<(), i32> {
+        let fixed_prefix_size = ERROR_COMMAND_NAME_LEN + ERROR_REASON_LEN_SIZE;
+        if msg_data.len() < fixed_prefix_size {
+            return Err(ZMQ_EPROTO);
         }
-
-        let error_reason_len = msg_data[6] as usize;
-        if error_reason_len > msg_data.len() - 7 {
-            return Err("Malformed ERROR command".into());
+        let error_reason_len = msg_data[ERROR_COMMAND_NAME_LEN] as usize;
+        if error_reason_len > msg_data.len() - fixed_prefix_size {
+            return Err(ZMQ_EPROTO);
         }
-
-        let error_reason = std::str::from_utf8(&msg_data[7..7 + error_reason_len])?;
-        self.handle_error_reason(error_reason);
         self.state = State::ErrorReceived;
-        
         Ok(())
     }
 }
 
-#[cfg(feature = "curve")]
-impl Mechanism for CurveClient {
-    fn next_handshake_command(&mut self, msg: &mut Message) -> Result<(), Box<dyn std::error::Error>> {
+impl MechanismOps for CurveClient {
+    fn base(&self) -> &Mechanism {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut Mechanism {
+        &mut self.base
+    }
+
+    fn next_handshake_command(
+        &mut self,
+        _session: &mut SessionBase,
+        msg: &mut Message,
+    ) -> Result<(), i32> {
         match self.state {
             State::SendHello => {
                 self.produce_hello(msg)?;
@@ -130,38 +126,42 @@ impl Mechanism for CurveClient {
                 self.produce_initiate(msg)?;
                 self.state = State::ExpectReady;
             }
-            _ => return Err("Invalid state".into()),
+            _ => return Err(libc::EAGAIN),
         }
         Ok(())
     }
 
-    fn process_handshake_command(&mut self, msg: &mut Message) -> Result<(), Box<dyn std::error::Error>> {
+    fn process_handshake_command(
+        &mut self,
+        _session: &mut SessionBase,
+        msg: &mut Message,
+    ) -> Result<(), i32> {
         let msg_data = msg.data();
-        
-        if CurveClientTools::is_handshake_command_welcome(msg_data) {
-            self.process_welcome(msg_data)?;
-        } else if CurveClientTools::is_handshake_command_ready(msg_data) {
-            self.process_ready(msg_data)?;
-        } else if CurveClientTools::is_handshake_command_error(msg_data) {
-            self.process_error(msg_data)?;
+        if CurveClientTools::is_handshake_command_welcome(msg_data)
+            && self.state == State::ExpectWelcome
+        {
+            self.process_welcome(msg_data)
+        } else if CurveClientTools::is_handshake_command_ready(msg_data)
+            && self.state == State::ExpectReady
+        {
+            self.process_ready(msg_data)
+        } else if CurveClientTools::is_handshake_command_error(msg_data)
+            && matches!(self.state, State::ExpectWelcome | State::ExpectReady)
+        {
+            self.process_error(msg_data)
         } else {
-            return Err("Unexpected command".into());
+            Err(ZMQ_EPROTO)
         }
-
-        msg.close()?;
-        msg.init()?;
-        
-        Ok(())
     }
 
-    fn encode(&mut self, msg: &mut Message) -> Result<(), Box<dyn std::error::Error>> {
-        assert_eq!(self.state, State::Connected);
-        self.encode_message(msg)
+    fn encode(&mut self, msg: &mut Message) -> Result<(), i32> {
+        debug_assert_eq!(self.state, State::Connected);
+        self.encoding.encode(msg)
     }
 
-    fn decode(&mut self, msg: &mut Message) -> Result<(), Box<dyn std::error::Error>> {
-        assert_eq!(self.state, State::Connected);
-        self.decode_message(msg)
+    fn decode(&mut self, msg: &mut Message) -> Result<(), i32> {
+        debug_assert_eq!(self.state, State::Connected);
+        self.encoding.decode(msg)
     }
 
     fn status(&self) -> Status {
@@ -171,4 +171,4 @@ impl Mechanism for CurveClient {
             _ => Status::Handshaking,
         }
     }
-}
\ No newline at end of file
+}
@@ -1,170 +1,154 @@
-#![allow(non_snake_case)]
+//! The client half of the CurveZMQ handshake, apart from the mechanism
+//! state so that it can be reused outside of an engine.
 
 use sodiumoxide::crypto::box_;
+use sodiumoxide::randombytes::randombytes_into;
 
-const CRYPTO_BOX_NONCEBYTES: usize = 24;
-const CRYPTO_BOX_PUBLICKEYBYTES: usize = 32;
-const CRYPTO_BOX_SECRETKEYBYTES: usize = 32;
-const CRYPTO_BOX_ZEROBYTES: usize = 32;
-const CRYPTO_BOX_BOXZEROBYTES: usize = 16;
+use crate::constants::ZMQ_EPROTO;
+use crate::curve_mechanism_base::{make_nonce, SHORT_NONCE_LEN};
 
+pub const HELLO_SIZE: usize = 200;
+pub const WELCOME_SIZE: usize = 168;
+pub const COOKIE_SIZE: usize = 96;
+
+const HELLO_PREFIX: &[u8] = b"\x05HELLO\x01\x00";
+const HELLO_PADDING_SIZE: usize = 72;
+const HELLO_PLAINTEXT_SIZE: usize = 64;
+const WELCOME_COMMAND: &[u8] = b"\x07WELCOME";
+const INITIATE_COMMAND: &[u8] = b"\x08INITIATE";
+const READY_COMMAND: &[u8] = b"\x05READY";
+const ERROR_COMMAND: &[u8] = b"\x05ERROR";
+const LONG_NONCE_LEN: usize = 16;
+
+/// Keys of the client side of a CurveZMQ handshake.
 pub struct CurveClientTools {
     // Our public key (C)
-    public_key: [u8; CRYPTO_BOX_PUBLICKEYBYTES],
+    public_key: box_::PublicKey,
     // Our secret key (c)
-    secret_key: [u8; CRYPTO_BOX_SECRETKEYBYTES],
+    secret_key: box_::SecretKey,
     // Our short-term public key (C')
-    cn_public: [u8; CRYPTO_BOX_PUBLICKEYBYTES],
+    cn_public: box_::PublicKey,
     // Our short-term secret key (c')
-    cn_secret: [u8; CRYPTO_BOX_SECRETKEYBYTES],
+    cn_secret: box_::SecretKey,
     // Server's public key (S)
-    server_key: [u8; CRYPTO_BOX_PUBLICKEYBYTES],
+    server_key: box_::PublicKey,
     // Server's short-term public key (S')
-    cn_server: [u8; CRYPTO_BOX_PUBLICKEYBYTES],
+    cn_server: box_::PublicKey,
     // Cookie received from server
-    cn_cookie: [u8; 96], // 16 + 80
+    cn_cookie: [u8; COOKIE_SIZE],
 }
 
 impl CurveClientTools {
-    pub fn new(
-        curve_public_key: &[u8; CRYPTO_BOX_PUBLICKEYBYTES],
-        curve_secret_key: &[u8; CRYPTO_BOX_SECRETKEYBYTES],
-        curve_server_key: &[u8; CRYPTO_BOX_PUBLICKEYBYTES],
-    ) -> Self {
+    pub fn new(public_key: &[u8; 32], secret_key: &[u8; 32], server_key: &[u8; 32]) -> Self {
         let (cn_public, cn_secret) = box_::gen_keypair();
-
-        Self {
-            public_key: *curve_public_key,
-            secret_key: *curve_secret_key,
-            cn_public: cn_public.0,
-            cn_secret: cn_secret.0,
-            server_key: *curve_server_key,
-            cn_server: [0; CRYPTO_BOX_PUBLICKEYBYTES],
-            cn_cookie: [0; 96],
+        CurveClientTools {
+            public_key: box_::PublicKey(*public_key),
+            secret_key: box_::SecretKey(*secret_key),
+            cn_public,
+            cn_secret,
+            server_key: box_::PublicKey(*server_key),
+            cn_server: box_::PublicKey([0; box_::PUBLICKEYBYTES]),
+            cn_cookie: [0; COOKIE_SIZE],
         }
     }
 
-    pub fn produce_hello(&self, cn_nonce: u64) -> Result<Vec<u8>, &'static str> {
-        let mut hello_nonce = [0u8; CRYPTO_BOX_NONCEBYTES];
-        hello_nonce[..16].copy_from_slice(b"CurveZMQHELLO---");
-        hello_nonce[16..24].copy_from_slice(&cn_nonce.to_le_bytes());
-
-        let hello_plaintext = vec![0u8; CRYPTO_BOX_ZEROBYTES + 64];
-
-        let server_pk = box_::PublicKey(self.server_key);
-        let cn_sk = box_::SecretKey(self.cn_secret);
-
+    /// HELLO: version, padding, C' and Box[64 * %x0](C'->S).
+    pub fn produce_hello(&self, cn_nonce: u64) -> Vec<u8> {
+        let short_nonce = cn_nonce.to_be_bytes();
+        let hello_nonce = make_nonce(b"CurveZMQHELLO---", &short_nonce);
         let hello_box = box_::seal(
-            &hello_plaintext,
-            &box_::Nonce(hello_nonce),
-            &server_pk,
-            &cn_sk,
+            &[0; HELLO_PLAINTEXT_SIZE],
+            &hello_nonce,
+            &self.server_key,
+            &self.cn_secret,
         );
 
-        let mut hello = Vec::with_capacity(200);
-        hello.extend_from_slice(b"\x05HELLO\x01\x00");
-        hello.extend_from_slice(&vec![0; 72]); // Anti-amplification padding
-        hello.extend_from_slice(&self.cn_public);
-        hello.extend_from_slice(&cn_nonce.to_le_bytes());
-        hello.extend_from_slice(&hello_box[CRYPTO_BOX_BOXZEROBYTES..]);
-
-        Ok(hello)
+        let mut hello = Vec::with_capacity(HELLO_SIZE);
+        hello.extend_from_slice(HELLO_PREFIX);
+        // Anti-amplification padding
+        hello.resize(HELLO_PREFIX.len() + HELLO_PADDING_SIZE, 0);
+        hello.extend_from_slice(&self.cn_public.0);
+        hello.extend_from_slice(&short_nonce);
+        hello.extend_from_slice(&hello_box);
+        debug_assert_eq!(hello.len(), HELLO_SIZE);
+        hello
     }
 
-    pub fn process_welcome(
-        &mut self,
-        msg_data: &[u8],
-        cn_precom: &mut [u8],
-    ) -> Result<(), &'static str> {
-        if msg_data.len() != 168 {
-            return Err("Invalid welcome message size");
+    /// WELCOME: Box[S' + cookie](S->C'). Returns the key to box messages
+    /// exchanged with the server's short-term key.
+    pub fn process_welcome(&mut self, msg_data: &[u8]) -> Result<box_::PrecomputedKey, i32> {
+        if msg_data.len() != WELCOME_SIZE {
+            return Err(ZMQ_EPROTO);
         }
 
-        let mut welcome_nonce = [0u8; CRYPTO_BOX_NONCEBYTES];
-        welcome_nonce[..8].copy_from_slice(b"WELCOME-");
-        welcome_nonce[8..24].copy_from_slice(&msg_data[8..24]);
-
-        let server_pk = box_::PublicKey(self.server_key);
-        let cn_sk = box_::SecretKey(self.cn_secret);
-
-        let welcome_box = &msg_data[24..168];
-        let welcome_plaintext =
-            box_::open(welcome_box, &box_::Nonce(welcome_nonce), &server_pk, &cn_sk)
-                .map_err(|_| "Failed to open welcome box")?;
-
-        self.cn_server.copy_from_slice(&welcome_plaintext[..32]);
-        self.cn_cookie.copy_from_slice(&welcome_plaintext[32..128]);
-
-        // Precompute the shared secret
-        let cn_server_pk = box_::PublicKey(self.cn_server);
-        let precom = box_::precompute(&cn_server_pk, &box_::SecretKey(self.cn_secret));
-        cn_precom.copy_from_slice(&precom.0);
-
-        Ok(())
+        let welcome_nonce = make_nonce(
+            b"WELCOME-",
+            &msg_data[WELCOME_COMMAND.len()..WELCOME_COMMAND.len() + LONG_NONCE_LEN],
+        );
+        let welcome_plaintext = box_::open(
+            &msg_data[WELCOME_COMMAND.len() + LONG_NONCE_LEN..],
+            &welcome_nonce,
+            &self.server_key,
+            &self.cn_secret,
+        )
+        .map_err(|_| ZMQ_EPROTO)?;
+
+        self.cn_server.0.copy_from_slice(&welcome_plaintext[..32]);
+        self.cn_cookie.copy_from_slice(&welcome_plaintext[32..]);
+
+        // Message independent precomputation
+        Ok(box_::precompute(&self.cn_server, &self.cn_secret))
     }
 
-    pub fn produce_initiate(
-        &self,
-        cn_nonce: u64,
-        metadata_plaintext: &[u8],
-    ) -> Result<Vec<u8>, &'static str> {
-        let mut vouch_nonce = [0u8; CRYPTO_BOX_NONCEBYTES];
-        vouch_nonce[..8].copy_from_slice(b"VOUCH---");
-        sodiumoxide::randombytes::randombytes_into(&mut vouch_nonce[8..24]);
-
-        let mut vouch_plaintext = vec![0u8; CRYPTO_BOX_ZEROBYTES + 64];
-        vouch_plaintext[CRYPTO_BOX_ZEROBYTES..][..32].copy_from_slice(&self.cn_public);
-        vouch_plaintext[CRYPTO_BOX_ZEROBYTES + 32..][..32].copy_from_slice(&self.server_key);
-
-        let cn_server_pk = box_::PublicKey(self.cn_server);
-        let secret_sk = box_::SecretKey(self.secret_key);
+    /// INITIATE: the cookie, then Box[C + vouch + metadata](C'->S'), the
+    /// vouch being Box[C' + S](C->S') to prove we own C.
+    pub fn produce_initiate(&self, cn_nonce: u64, metadata_plaintext: &[u8]) -> Vec<u8> {
+        let mut vouch_nonce = [0u8; LONG_NONCE_LEN];
+        randombytes_into(&mut vouch_nonce);
 
+        let mut vouch_plaintext = Vec::with_capacity(64);
+        vouch_plaintext.extend_from_slice(&self.cn_public.0);
+        vouch_plaintext.extend_from_slice(&self.server_key.0);
         let vouch_box = box_::seal(
             &vouch_plaintext,
-            &box_::Nonce(vouch_nonce),
-            &cn_server_pk,
-            &secret_sk,
+            &make_nonce(b"VOUCH---", &vouch_nonce),
+            &self.cn_server,
+            &self.secret_key,
         );
 
-        let mut initiate_nonce = [0u8; CRYPTO_BOX_NONCEBYTES];
-        initiate_nonce[..16].copy_from_slice(b"CurveZMQINITIATE");
-        initiate_nonce[16..24].copy_from_slice(&cn_nonce.to_le_bytes());
-
-        let mut initiate_plaintext =
-            vec![0u8; CRYPTO_BOX_ZEROBYTES + 128 + metadata_plaintext.len()];
-        let offset = CRYPTO_BOX_ZEROBYTES;
-        initiate_plaintext[offset..offset + 32].copy_from_slice(&self.public_key);
-        initiate_plaintext[offset + 32..offset + 48].copy_from_slice(&vouch_nonce[8..24]);
-        initiate_plaintext[offset + 48..offset + 128]
-            .copy_from_slice(&vouch_box[CRYPTO_BOX_BOXZEROBYTES..]);
-        initiate_plaintext[offset + 128..].copy_from_slice(metadata_plaintext);
+        let mut initiate_plaintext = Vec::with_capacity(128 + metadata_plaintext.len());
+        initiate_plaintext.extend_from_slice(&self.public_key.0);
+        initiate_plaintext.extend_from_slice(&vouch_nonce);
+        initiate_plaintext.extend_from_slice(&vouch_box);
+        initiate_plaintext.extend_from_slice(metadata_plaintext);
 
+        let short_nonce = cn_nonce.to_be_bytes();
         let initiate_box = box_::seal(
             &initiate_plaintext,
-            &box_::Nonce(initiate_nonce),
-            &cn_server_pk,
-            &box_::SecretKey(self.cn_secret),
+            &make_nonce(b"CurveZMQINITIATE", &short_nonce),
+            &self.cn_server,
+            &self.cn_secret,
         );
 
-        let mut initiate =
-            Vec::with_capacity(113 + 128 + CRYPTO_BOX_BOXZEROBYTES + metadata_plaintext.len());
-        initiate.extend_from_slice(b"\x08INITIATE");
+        let mut initiate = Vec::with_capacity(
+            INITIATE_COMMAND.len() + COOKIE_SIZE + SHORT_NONCE_LEN + initiate_box.len(),
+        );
+        initiate.extend_from_slice(INITIATE_COMMAND);
         initiate.extend_from_slice(&self.cn_cookie);
-        initiate.extend_from_slice(&cn_nonce.to_le_bytes());
-        initiate.extend_from_slice(&initiate_box[CRYPTO_BOX_BOXZEROBYTES..]);
-
-        Ok(initiate)
+        initiate.extend_from_slice(&short_nonce);
+        initiate.extend_from_slice(&initiate_box);
+        initiate
     }
 
     pub fn is_handshake_command_welcome(msg_data: &[u8]) -> bool {
-        msg_data.starts_with(b"\x07WELCOME")
+        msg_data.starts_with(WELCOME_COMMAND)
     }
 
     pub fn is_handshake_command_ready(msg_data: &[u8]) -> bool {
-        msg_data.starts_with(b"\x05READY")
+        msg_data.starts_with(READY_COMMAND)
     }
 
     pub fn is_handshake_command_error(msg_data: &[u8]) -> bool {
-        msg_data.starts_with(b"\x05ERROR")
+        msg_data.starts_with(ERROR_COMMAND)
     }
 }
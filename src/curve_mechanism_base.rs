@@ -1,120 +1,228 @@
-#[cfg(feature = "curve")]
-use std::convert::TryInto;
-use std::mem;
+//! Encryption of the MESSAGE commands exchanged once a CurveZMQ handshake
+//! is over, shared by the client and server mechanisms.
+
 use sodiumoxide::crypto::box_;
 
-const FLAG_MASK: u8 = 0x03; // msg_t::more | msg_t::command
+use crate::constants::ZMQ_EPROTO;
+use crate::message::{Message, MsgFlags, CANCEL_CMD_NAME, SUB_CMD_NAME};
+
+const FLAG_MASK: u8 = MsgFlags::More as u8 | MsgFlags::Command as u8;
 const FLAGS_LEN: usize = 1;
 const NONCE_PREFIX_LEN: usize = 16;
 const MESSAGE_COMMAND: &[u8] = b"\x07MESSAGE";
 const MESSAGE_COMMAND_LEN: usize = 8;
-const MESSAGE_HEADER_LEN: usize = MESSAGE_COMMAND_LEN + mem::size_of::<u64>();
+const MESSAGE_HEADER_LEN: usize = MESSAGE_COMMAND_LEN + SHORT_NONCE_LEN;
+
+/// Length of the nonce counter sent along with a box.
+pub const SHORT_NONCE_LEN: usize = 8;
+
+/// Builds a full nonce from a prefix and the bytes that follow it.
+pub fn make_nonce(prefix: &[u8], suffix: &[u8]) -> box_::Nonce {
+    let mut nonce = [0u8; box_::NONCEBYTES];
+    nonce[..prefix.len()].copy_from_slice(prefix);
+    nonce[prefix.len()..].copy_from_slice(suffix);
+    box_::Nonce(nonce)
+}
+
+/// Reads a short nonce, sent in network byte order.
+pub fn get_short_nonce(data: &[u8]) -> u64 {
+    let mut nonce = [0u8; SHORT_NONCE_LEN];
+    nonce.copy_from_slice(&data[..SHORT_NONCE_LEN]);
+    u64::from_be_bytes(nonce)
+}
 
-#[derive(Debug)]
+/// Boxes and unboxes messages with the key both peers derived from their
+/// short-term keys. Each side counts its own nonces up from 1 and rejects
+/// any nonce from the peer that isn't greater than the last one it saw.
 pub struct CurveEncoding {
-    encode_nonce_prefix: [u8; NONCE_PREFIX_LEN],
-    decode_nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    encode_nonce_prefix: &'static [u8],
+    decode_nonce_prefix: &'static [u8],
     cn_nonce: u64,
     cn_peer_nonce: u64,
+    // Intermediary buffer used to speed up boxing and unboxing.
     cn_precom: box_::PrecomputedKey,
     downgrade_sub: bool,
 }
 
 impl CurveEncoding {
     pub fn new(
-        encode_nonce_prefix: &[u8],
-        decode_nonce_prefix: &[u8],
-        precomputed_key: box_::PrecomputedKey,
+        encode_nonce_prefix: &'static [u8],
+        decode_nonce_prefix: &'static [u8],
         downgrade_sub: bool,
     ) -> Self {
-        let mut enc_prefix = [0u8; NONCE_PREFIX_LEN];
-        let mut dec_prefix = [0u8; NONCE_PREFIX_LEN];
-        enc_prefix.copy_from_slice(&encode_nonce_prefix[..NONCE_PREFIX_LEN]);
-        dec_prefix.copy_from_slice(&decode_nonce_prefix[..NONCE_PREFIX_LEN]);
-        
-        Self {
-            encode_nonce_prefix: enc_prefix,
-            decode_nonce_prefix: dec_prefix,
+        debug_assert_eq!(encode_nonce_prefix.len(), NONCE_PREFIX_LEN);
+        debug_assert_eq!(decode_nonce_prefix.len(), NONCE_PREFIX_LEN);
+        CurveEncoding {
+            encode_nonce_prefix,
+            decode_nonce_prefix,
             cn_nonce: 1,
             cn_peer_nonce: 1,
-            cn_precom: precomputed_key,
+            cn_precom: box_::PrecomputedKey([0; box_::PRECOMPUTEDKEYBYTES]),
             downgrade_sub,
         }
     }
 
-    fn get_and_inc_nonce(&mut self) -> u64 {
+    pub fn get_and_inc_nonce(&mut self) -> u64 {
         let nonce = self.cn_nonce;
         self.cn_nonce += 1;
         nonce
     }
 
-    fn set_peer_nonce(&mut self, nonce: u64) {
+    pub fn set_peer_nonce(&mut self, nonce: u64) {
         self.cn_peer_nonce = nonce;
     }
 
-    fn check_validity(&self, msg: &[u8]) -> Result<(), i32> {
-        if msg.len() < MESSAGE_COMMAND_LEN || 
-           !msg.starts_with(MESSAGE_COMMAND) {
-            return Err(ZMQ_PROTOCOL_ERROR_ZMTP_UNEXPECTED_COMMAND);
-        }
-
-        if msg.len() < MESSAGE_HEADER_LEN + box_::MACBYTES + FLAGS_LEN {
-            return Err(ZMQ_PROTOCOL_ERROR_ZMTP_MALFORMED_COMMAND_MESSAGE);
-        }
-
-        let nonce = u64::from_le_bytes(msg[MESSAGE_COMMAND_LEN..][..8].try_into().unwrap());
-        if nonce <= self.cn_peer_nonce {
-            return Err(ZMQ_PROTOCOL_ERROR_ZMTP_INVALID_SEQUENCE);
-        }
-        
-        Ok(())
+    pub fn precom(&self) -> &box_::PrecomputedKey {
+        &self.cn_precom
     }
 
-    pub fn encode(&mut self, msg: &[u8], flags: u8) -> Result<Vec<u8>, i32> {
-        let mut nonce = vec![0u8; box_::NONCEBYTES];
-        nonce[..NONCE_PREFIX_LEN].copy_from_slice(&self.encode_nonce_prefix);
-        nonce[NONCE_PREFIX_LEN..NONCE_PREFIX_LEN+8]
-            .copy_from_slice(&self.get_and_inc_nonce().to_le_bytes());
+    pub fn set_precom(&mut self, precom: box_::PrecomputedKey) {
+        self.cn_precom = precom;
+    }
 
-        let mut plaintext = Vec::with_capacity(FLAGS_LEN + msg.len());
-        plaintext.push(flags & FLAG_MASK);
-        plaintext.extend_from_slice(msg);
+    /// Replaces `msg` with a MESSAGE command carrying it.
+    pub fn encode(&mut self, msg: &mut Message) -> Result<(), i32> {
+        let short_nonce = self.get_and_inc_nonce().to_be_bytes();
+        let message_nonce = make_nonce(self.encode_nonce_prefix, &short_nonce);
+
+        let mut flags = msg.flags() as u8 & FLAG_MASK;
+        let mut plaintext = Vec::with_capacity(FLAGS_LEN + SUB_CMD_NAME.len() + msg.size());
+        plaintext.push(0);
+
+        // Subscriptions aren't stored as commands so that they can be sent
+        // the pre-3.1 way, as a message starting with 1 or 0.
+        if msg.is_subscribe() || msg.is_cancel() {
+            if self.downgrade_sub {
+                plaintext.push(u8::from(msg.is_subscribe()));
+            } else {
+                flags |= MsgFlags::Command as u8;
+                if msg.is_subscribe() {
+                    plaintext.extend_from_slice(SUB_CMD_NAME);
+                } else {
+                    plaintext.extend_from_slice(CANCEL_CMD_NAME);
+                }
+            }
+        }
+        plaintext[0] = flags;
+        plaintext.extend_from_slice(msg.data());
 
-        let ciphertext = box_::seal_precomputed(&plaintext, 
-                                              &box_::Nonce::from_slice(&nonce).unwrap(),
-                                              &self.cn_precom);
+        let message_box = box_::seal_precomputed(&plaintext, &message_nonce, &self.cn_precom);
 
-        let mut result = Vec::with_capacity(MESSAGE_HEADER_LEN + ciphertext.len());
-        result.extend_from_slice(MESSAGE_COMMAND);
-        result.extend_from_slice(&nonce[NONCE_PREFIX_LEN..NONCE_PREFIX_LEN+8]);
-        result.extend_from_slice(&ciphertext);
+        let mut message = Vec::with_capacity(MESSAGE_HEADER_LEN + message_box.len());
+        message.extend_from_slice(MESSAGE_COMMAND);
+        message.extend_from_slice(&short_nonce);
+        message.extend_from_slice(&message_box);
 
-        Ok(result)
+        *msg = Message::with_data(&message).map_err(|_| libc::ENOMEM)?;
+        Ok(())
     }
 
-    pub fn decode(&mut self, msg: &[u8]) -> Result<(Vec<u8>, u8), i32> {
-        self.check_validity(msg)?;
+    /// Replaces the MESSAGE command in `msg` with the message it carries.
+    pub fn decode(&mut self, msg: &mut Message) -> Result<(), i32> {
+        let message = msg.data();
+        if !message.starts_with(MESSAGE_COMMAND) {
+            return Err(ZMQ_EPROTO);
+        }
+        if message.len() < MESSAGE_HEADER_LEN + box_::MACBYTES + FLAGS_LEN {
+            return Err(ZMQ_EPROTO);
+        }
 
-        let mut nonce = vec![0u8; box_::NONCEBYTES];
-        nonce[..NONCE_PREFIX_LEN].copy_from_slice(&self.decode_nonce_prefix);
-        nonce[NONCE_PREFIX_LEN..NONCE_PREFIX_LEN+8]
-            .copy_from_slice(&msg[MESSAGE_COMMAND_LEN..MESSAGE_COMMAND_LEN+8]);
+        let nonce = get_short_nonce(&message[MESSAGE_COMMAND_LEN..]);
+        if nonce <= self.cn_peer_nonce {
+            return Err(ZMQ_EPROTO);
+        }
+        self.set_peer_nonce(nonce);
 
-        let ciphertext = &msg[MESSAGE_HEADER_LEN..];
-        
+        let message_nonce = make_nonce(
+            self.decode_nonce_prefix,
+            &message[MESSAGE_COMMAND_LEN..MESSAGE_HEADER_LEN],
+        );
         let plaintext = box_::open_precomputed(
-            ciphertext,
-            &box_::Nonce::from_slice(&nonce).unwrap(),
-            &self.cn_precom
-        ).map_err(|_| ZMQ_PROTOCOL_ERROR_ZMTP_CRYPTOGRAPHIC)?;
+            &message[MESSAGE_HEADER_LEN..],
+            &message_nonce,
+            &self.cn_precom,
+        )
+        .map_err(|_| ZMQ_EPROTO)?;
 
         let flags = plaintext[0];
-        Ok((plaintext[FLAGS_LEN..].to_vec(), flags))
+        *msg = Message::with_data(&plaintext[FLAGS_LEN..]).map_err(|_| libc::ENOMEM)?;
+        if flags & MsgFlags::More as u8 != 0 {
+            msg.set_flags(MsgFlags::More);
+        }
+        if flags & MsgFlags::Command as u8 != 0 {
+            msg.set_flags(MsgFlags::Command);
+        }
+        Ok(())
     }
 }
 
-// Error codes
-const ZMQ_PROTOCOL_ERROR_ZMTP_UNEXPECTED_COMMAND: i32 = -1;
-const ZMQ_PROTOCOL_ERROR_ZMTP_MALFORMED_COMMAND_MESSAGE: i32 = -2;  
-const ZMQ_PROTOCOL_ERROR_ZMTP_INVALID_SEQUENCE: i32 = -3;
-const ZMQ_PROTOCOL_ERROR_ZMTP_CRYPTOGRAPHIC: i32 = -4;
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoding_pair() -> (CurveEncoding, CurveEncoding) {
+        let (client_public, client_secret) = box_::gen_keypair();
+        let (server_public, server_secret) = box_::gen_keypair();
+        let mut client = CurveEncoding::new(b"CurveZMQMESSAGEC", b"CurveZMQMESSAGES", false);
+        client.set_precom(box_::precompute(&server_public, &client_secret));
+        let mut server = CurveEncoding::new(b"CurveZMQMESSAGES", b"CurveZMQMESSAGEC", false);
+        server.set_precom(box_::precompute(&client_public, &server_secret));
+
+        // The handshake used up HELLO and INITIATE nonces on the client
+        // side and a READY nonce on the server side.
+        client.get_and_inc_nonce();
+        server.set_peer_nonce(client.get_and_inc_nonce());
+        client.set_peer_nonce(server.get_and_inc_nonce());
+        (client, server)
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let (mut client, mut server) = encoding_pair();
+
+        let mut msg = Message::with_data(b"hello").unwrap();
+        msg.set_flags(MsgFlags::More);
+        client.encode(&mut msg).unwrap();
+        assert!(msg.data().starts_with(MESSAGE_COMMAND));
+        assert_eq!(get_short_nonce(&msg.data()[MESSAGE_COMMAND_LEN..]), 3);
+        assert_eq!(
+            msg.size(),
+            MESSAGE_HEADER_LEN + box_::MACBYTES + FLAGS_LEN + 5
+        );
+        assert!(!msg.has_more());
+
+        server.decode(&mut msg).unwrap();
+        assert_eq!(msg.data(), b"hello");
+        assert!(msg.has_more());
+
+        // Subscriptions travel as commands.
+        let mut msg = Message::with_data(b"topic").unwrap();
+        msg.set_flags(MsgFlags::Subscribe);
+        server.encode(&mut msg).unwrap();
+        client.decode(&mut msg).unwrap();
+        assert!(msg.is_command());
+        assert_eq!(msg.data(), b"\x09SUBSCRIBEtopic");
+    }
+
+    #[test]
+    fn test_decode_rejects_replay_and_tampering() {
+        let (mut client, mut server) = encoding_pair();
+
+        let mut first = Message::with_data(b"one").unwrap();
+        client.encode(&mut first).unwrap();
+        let mut replayed = Message::with_data(first.data()).unwrap();
+        server.decode(&mut first).unwrap();
+        assert_eq!(server.decode(&mut replayed), Err(ZMQ_EPROTO));
+
+        let mut tampered = Message::with_data(b"two").unwrap();
+        client.encode(&mut tampered).unwrap();
+        let last = tampered.size() - 1;
+        tampered.data_mut()[last] ^= 1;
+        assert_eq!(server.decode(&mut tampered), Err(ZMQ_EPROTO));
+
+        // Our own messages don't open with the prefixes swapped.
+        let mut msg = Message::with_data(b"three").unwrap();
+        client.encode(&mut msg).unwrap();
+        assert_eq!(client.decode(&mut msg), Err(ZMQ_EPROTO));
+    }
+}
@@ -1,95 +1,108 @@
 use crate::constants::{ZMQ_DEALER, ZMQ_PROBE_ROUTER};
+use crate::context::Context;
 use crate::fair_queue::FairQueue;
 use crate::load_balancer::LoadBalancer;
 use crate::message::Message;
-use crate::socket_base::{SocketBase, SocketOptions};
-use crate::{context::Context, pipe::Pipe};
+use crate::options::int_at_least;
+use crate::pipe::Pipe;
+use crate::socket_base::{SocketBase, SocketBehavior};
+use std::sync::Arc;
 
-pub struct Dealer<T: Pipe> {
-    socket_base: SocketBase,
-    fq: FairQueue<T>,
+/// DEALER socket: fair-queues incoming and load-balances outgoing
+/// messages.
+pub struct Dealer {
+    base: SocketBase,
+    // Messages are fair-queued from inbound pipes. And load-balanced to
+    // the outbound pipes.
+    fq: FairQueue,
     lb: LoadBalancer,
+    // If true, send an empty message to every connected router peer
     probe_router: bool,
 }
 
-impl<T> Dealer<T> {
-    pub fn new(parent: &Context, tid: u32, sid: i32) -> Self {
-        let mut options = SocketOptions::default();
-        options.socket_type = ZMQ_DEALER;
-        options.can_send_hello_msg = true;
-        options.can_recv_hiccup_msg = true;
+impl Dealer {
+    pub fn new(ctx: &Arc<Context>, tid: u32, sid: i32) -> Self {
+        let mut base = SocketBase::new(ctx, tid, sid, false);
+        base.options.socket_type = ZMQ_DEALER;
+        base.options.can_send_hello_msg = true;
+        base.options.can_recv_hiccup_msg = true;
 
         Dealer {
-            socket_base: SocketBase::new(parent, tid, sid, options),
+            base,
             fq: FairQueue::new(),
             lb: LoadBalancer::new(),
             probe_router: false,
         }
     }
 
-    pub fn attach_pipe(
-        &mut self,
-        pipe: &mut Pipe,
-        _subscribe_to_all: bool,
-        _locally_initiated: bool,
-    ) {
+    /// Sends a message, returning the pipe it went to.
+    pub fn sendpipe(&mut self, msg: &mut Message) -> Result<Option<Pipe>, i32> {
+        self.lb.sendpipe(msg)
+    }
+
+    /// Receives a message, returning the pipe it came from.
+    pub fn recvpipe(&mut self, msg: &mut Message) -> Result<Pipe, i32> {
+        self.fq.recvpipe(msg)
+    }
+}
+
+impl SocketBehavior for Dealer {
+    fn base(&self) -> &SocketBase {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut SocketBase {
+        &mut self.base
+    }
+
+    fn xattach_pipe(&mut self, pipe: Pipe, _subscribe_to_all: bool, _locally_initiated: bool) {
         if self.probe_router {
-            let probe_msg = Message::new();
-            if let Ok(msg) = probe_msg {
-                let _ = pipe.write(&msg);
-                pipe.flush();
-            }
+            let mut probe_msg = Message::new();
+            // Pipe is fresh, there is room for the probe.
+            pipe.write(&mut probe_msg);
+            pipe.flush();
         }
 
-        self.fq.attach(pipe);
+        self.fq.attach(pipe.clone());
         self.lb.attach(pipe);
     }
 
-    pub fn set_sock_opt(&mut self, option: i32, optval: &[u8]) -> Result<(), i32> {
-        if option == ZMQ_PROBE_ROUTER && optval.len() == std::mem::size_of::<i32>() {
-            let value = i32::from_ne_bytes(optval.try_into().unwrap());
-            if value >= 0 {
-                self.probe_router = value != 0;
-                return Ok(());
+    fn xsetsockopt(&mut self, option: i32, optval: &[u8]) -> Result<(), i32> {
+        match option {
+            ZMQ_PROBE_ROUTER => {
+                self.probe_router = int_at_least(optval, 0)? != 0;
+                Ok(())
             }
+            _ => Err(libc::EINVAL),
         }
-        Err(libc::EINVAL)
     }
 
-    pub fn send(&mut self, msg: &mut Message) -> Result<(), i32> {
-        self.sendpipe(msg, None)
+    fn xsend(&mut self, msg: &mut Message) -> Result<(), i32> {
+        self.sendpipe(msg).map(|_| ())
     }
 
-    pub fn recv(&mut self, msg: &mut Message) -> Result<(), i32> {
-        self.recvpipe(msg, None)
+    fn xrecv(&mut self, msg: &mut Message) -> Result<(), i32> {
+        self.recvpipe(msg).map(|_| ())
     }
 
-    pub fn has_in(&self) -> bool {
+    fn xhas_in(&mut self) -> bool {
         self.fq.has_in()
     }
 
-    pub fn has_out(&self) -> bool {
+    fn xhas_out(&mut self) -> bool {
         self.lb.has_out()
     }
 
-    pub fn read_activated(&mut self, pipe: &mut Pipe) {
+    fn xread_activated(&mut self, pipe: &Pipe) {
         self.fq.activated(pipe);
     }
 
-    pub fn write_activated(&mut self, pipe: &mut Pipe) {
+    fn xwrite_activated(&mut self, pipe: &Pipe) {
         self.lb.activated(pipe);
     }
 
-    pub fn pipe_terminated(&mut self, pipe: &mut Pipe) {
+    fn xpipe_terminated(&mut self, pipe: &Pipe) {
         self.fq.pipe_terminated(pipe);
         self.lb.pipe_terminated(pipe);
     }
-
-    pub fn sendpipe(&mut self, msg: &mut Message, pipe: Option<&mut Pipe>) -> Result<(), i32> {
-        self.lb.sendpipe(msg, pipe)
-    }
-
-    pub fn recvpipe(&mut self, msg: &mut Message, pipe: Option<&mut Pipe>) -> Result<(), i32> {
-        self.fq.recvpipe(msg, pipe)
-    }
 }
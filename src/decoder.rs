@@ -1,123 +1,90 @@
+//! Common part of the message decoders.
+//!
+//! As in libzmq's decoder_base_t, decoding is a sequence of steps, each of
+//! which waits for a known number of bytes: either a few header bytes,
+//! collected in a small buffer, or the body of the message being decoded.
+//! The decoder implementations drive the steps; `DecoderBase` does the
+//! copying.
+
 use std::cmp;
 
-// Trait for allocator implementations
-pub trait DecoderAllocator {
-    fn allocate(&mut self) -> Vec<u8>;
-    fn deallocate(&mut self);
-    fn size(&self) -> usize;
-    fn resize(&mut self, new_size: usize);
-}
+use crate::message::Message;
 
-// Trait for decoder implementations
-pub trait Decoder {
-    fn get_buffer(&mut self) -> (&mut [u8], usize);
-    fn decode(&mut self, data: &[u8], size: usize) -> Result<(usize, bool), std::io::Error>;
-    fn resize_buffer(&mut self, new_size: usize);
+// What the bytes of the current step are read into.
+#[derive(Clone, Copy, PartialEq)]
+enum Target {
+    Header,
+    Body,
 }
 
-// Base decoder struct
-pub struct DecoderBase<T, A>
-where
-    A: DecoderAllocator,
-{
-    next_step: Option<fn(&mut T, &[u8]) -> Result<(), std::io::Error>>,
+pub struct DecoderBase {
+    tmpbuf: [u8; 8],
+    target: Target,
+    // Bytes read so far and bytes the current step waits for.
     read_pos: usize,
     to_read: usize,
-    allocator: A,
-    buffer: Vec<u8>,
+    // The message being decoded.
+    pub in_progress: Message,
 }
 
-impl<T, A> DecoderBase<T, A>
-where
-    A: DecoderAllocator,
-{
-    pub fn new(buf_size: usize, allocator: A) -> Self {
-        let mut allocator = allocator;
-        let buffer = allocator.allocate();
-
+impl DecoderBase {
+    pub fn new() -> Self {
         DecoderBase {
-            next_step: None,
+            tmpbuf: [0; 8],
+            target: Target::Header,
             read_pos: 0,
             to_read: 0,
-            allocator,
-            buffer,
+            in_progress: Message::new(),
         }
     }
 
-    // Protected method for derived implementations
-    fn next_step(
-        &mut self,
-        read_pos: usize,
-        to_read: usize,
-        next: fn(&mut T, &[u8]) -> Result<(), std::io::Error>,
-    ) {
-        self.read_pos = read_pos;
-        self.to_read = to_read;
-        self.next_step = Some(next);
+    /// Makes the next step wait for `size` (at most 8) header bytes.
+    pub fn next_header_step(&mut self, size: usize) {
+        assert!(size <= self.tmpbuf.len());
+        self.target = Target::Header;
+        self.read_pos = 0;
+        self.to_read = size;
     }
 
-    pub fn get_allocator(&mut self) -> &mut A {
-        &mut self.allocator
+    /// Makes the next step wait for the body of the message in progress.
+    pub fn next_body_step(&mut self) {
+        self.target = Target::Body;
+        self.read_pos = 0;
+        self.to_read = self.in_progress.size();
     }
-}
-
-impl<T, A> Decoder for DecoderBase<T, A>
-where
-    A: DecoderAllocator,
-{
-    fn get_buffer(&mut self) -> (&mut [u8], usize) {
-        self.buffer = self.allocator.allocate();
 
-        // Zero-copy optimization for large messages
-        if self.to_read >= self.allocator.size() {
-            return (&mut self.buffer[self.read_pos..], self.to_read);
-        }
-
-        (&mut self.buffer[..], self.allocator.size())
+    /// The header bytes read by the step that just completed.
+    pub fn header(&self) -> &[u8] {
+        &self.tmpbuf[..self.read_pos]
     }
 
-    fn decode(&mut self, data: &[u8], size: usize) -> Result<(usize, bool), std::io::Error> {
-        let mut bytes_used = 0;
-
-        // Handle zero-copy case
-        if data.as_ptr() == &self.buffer[self.read_pos] as *const u8 {
-            debug_assert!(size <= self.to_read);
-            self.read_pos += size;
-            self.to_read -= size;
-            bytes_used = size;
-
-            while self.to_read == 0 {
-                if let Some(next) = self.next_step {
-                    next(data[bytes_used..])?;
-                }
-            }
-            return Ok((bytes_used, false));
-        }
-
-        // Normal copy case
-        while bytes_used < size {
-            let to_copy = cmp::min(self.to_read, size - bytes_used);
-
-            if self.read_pos as *const u8 != data[bytes_used..].as_ptr() {
-                self.buffer[self.read_pos..self.read_pos + to_copy]
-                    .copy_from_slice(&data[bytes_used..bytes_used + to_copy]);
-            }
-
-            self.read_pos += to_copy;
-            self.to_read -= to_copy;
-            bytes_used += to_copy;
-
-            while self.to_read == 0 {
-                if let Some(next) = self.next_step {
-                    next(data[bytes_used..])?;
-                }
-            }
-        }
+    /// Feeds data to the current step. Returns the number of bytes taken
+    /// and whether the step has all the bytes it waited for.
+    pub fn feed(&mut self, data: &[u8]) -> (usize, bool) {
+        let n = cmp::min(self.to_read, data.len());
+        let dest = match self.target {
+            Target::Header => &mut self.tmpbuf[self.read_pos..self.read_pos + n],
+            Target::Body => &mut self.in_progress.data_mut()[self.read_pos..self.read_pos + n],
+        };
+        dest.copy_from_slice(&data[..n]);
+        self.read_pos += n;
+        self.to_read -= n;
+        (n, self.to_read == 0)
+    }
+}
 
-        Ok((bytes_used, false))
+impl Default for DecoderBase {
+    fn default() -> Self {
+        DecoderBase::new()
     }
+}
 
-    fn resize_buffer(&mut self, new_size: usize) {
-        self.allocator.resize(new_size);
+/// Allocates the message for a frame of `size` bytes announced on the
+/// wire, enforcing ZMQ_MAXMSGSIZE (-1 = unlimited).
+pub fn alloc_msg(size: u64, max_msg_size: i64) -> Result<Message, i32> {
+    if max_msg_size >= 0 && size > max_msg_size as u64 {
+        return Err(libc::EMSGSIZE);
     }
+    let size = usize::try_from(size).map_err(|_| libc::EMSGSIZE)?;
+    Message::with_size(size).map_err(|_| libc::ENOMEM)
 }
@@ -1,79 +1,46 @@
-use std::io::{Error, ErrorKind};
-
-#[derive(Debug)]
-pub struct DgramSocket {
-    pipe: Option<Box<Pipe>>,
+use crate::constants::ZMQ_EAGAIN;
+use crate::context::Context;
+use crate::message::Message;
+use crate::pipe::Pipe;
+use crate::socket_base::{SocketBase, SocketBehavior};
+use crate::zmq_draft::ZMQ_DGRAM;
+use std::sync::Arc;
+
+/// DGRAM socket: sends and receives two-part (address, body) datagrams
+/// over UDP.
+pub struct Dgram {
+    base: SocketBase,
+    pipe: Option<Pipe>,
+    // If true, more outgoing message parts are expected.
     more_out: bool,
-    options: SocketOptions,
-}
-
-#[derive(Debug)]
-struct SocketOptions {
-    socket_type: SocketType,
-    raw_socket: bool,
-}
-
-#[derive(Debug)]
-enum SocketType {
-    Dgram,
-    // ... other socket types ...
-}
-
-// Mock Pipe type - you'll need to implement the actual pipe functionality
-#[derive(Debug)]
-struct Pipe {
-    // pipe implementation details
 }
 
-impl Pipe {
-    fn write(&mut self, msg: &[u8]) -> bool {
-        // implement pipe write
-        true
-    }
-
-    fn read(&mut self, msg: &mut Vec<u8>) -> bool {
-        // implement pipe read
-        true
-    }
-
-    fn check_read(&self) -> bool {
-        // implement read check
-        true
-    }
-
-    fn check_write(&self) -> bool {
-        // implement write check
-        true
-    }
+impl Dgram {
+    pub fn new(ctx: &Arc<Context>, tid: u32, sid: i32) -> Self {
+        let mut base = SocketBase::new(ctx, tid, sid, false);
+        base.options.socket_type = ZMQ_DGRAM;
+        base.options.raw_socket = true;
 
-    fn flush(&mut self) {
-        // implement flush
+        Dgram {
+            base,
+            pipe: None,
+            more_out: false,
+        }
     }
+}
 
-    fn terminate(&mut self, _force: bool) {
-        // implement terminate
+impl SocketBehavior for Dgram {
+    fn base(&self) -> &SocketBase {
+        &self.base
     }
-}
 
-impl DgramSocket {
-    pub fn new() -> Self {
-        DgramSocket {
-            pipe: None,
-            more_out: false,
-            options: SocketOptions {
-                socket_type: SocketType::Dgram,
-                raw_socket: true,
-            },
-        }
+    fn base_mut(&mut self) -> &mut SocketBase {
+        &mut self.base
     }
 
-    pub fn attach_pipe(
-        &mut self,
-        pipe: &mut Pipe,
-        _subscribe_to_all: bool,
-        _locally_initiated: bool,
-    ) {
-        // DGRAM socket can only be connected to a single peer
+    fn xattach_pipe(&mut self, pipe: Pipe, _subscribe_to_all: bool, _locally_initiated: bool) {
+        // ZMQ_DGRAM socket can only be connected to a single peer.
+        // The socket rejects any further connection requests.
         if self.pipe.is_none() {
             self.pipe = Some(pipe);
         } else {
@@ -81,44 +48,40 @@ impl DgramSocket {
         }
     }
 
-    pub fn pipe_terminated(&mut self, pipe: &Pipe) {
-        if let Some(ref p) = self.pipe {
-            // Compare pipe addresses or use some form of identification
+    fn xpipe_terminated(&mut self, pipe: &Pipe) {
+        if self.pipe.as_ref() == Some(pipe) {
             self.pipe = None;
         }
     }
 
-    pub fn send(&mut self, msg: &[u8], has_more: bool) -> Result<(), Error> {
-        // If there's no out pipe, just drop it
-        let pipe = self
-            .pipe
-            .as_mut()
-            .ok_or_else(|| Error::new(ErrorKind::NotConnected, "No pipe connected"))?;
+    fn xsend(&mut self, msg: &mut Message) -> Result<(), i32> {
+        // If there's no out pipe, just drop it.
+        let pipe = match &self.pipe {
+            Some(pipe) => pipe,
+            None => {
+                *msg = Message::new();
+                return Err(ZMQ_EAGAIN);
+            }
+        };
 
-        // If this is the first part of the message
+        let more = msg.has_more();
         if !self.more_out {
-            if !has_more {
-                return Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    "Expected multipart message",
-                ));
-            }
-        } else {
-            // dgram messages are two part only, reject if more is set
-            if has_more {
-                return Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    "Unexpected message part",
-                ));
+            // If this is the first part of the message it's the ID of the
+            // peer to send the message to.
+            if !more {
+                return Err(libc::EINVAL);
             }
+        } else if more {
+            // dgram messages are two part only, reject part if more is set
+            return Err(libc::EINVAL);
         }
 
-        // Push the message into the pipe
+        // Push the message into the pipe.
         if !pipe.write(msg) {
-            return Err(Error::new(ErrorKind::WouldBlock, "Would block"));
+            return Err(ZMQ_EAGAIN);
         }
 
-        if !has_more {
+        if !more {
             pipe.flush();
         }
 
@@ -128,24 +91,22 @@ impl DgramSocket {
         Ok(())
     }
 
-    pub fn recv(&mut self, msg: &mut Vec<u8>) -> Result<(), Error> {
-        if let Some(ref mut pipe) = self.pipe {
-            if pipe.read(msg) {
-                Ok(())
-            } else {
-                Err(Error::new(ErrorKind::WouldBlock, "Would block"))
-            }
-        } else {
-            Err(Error::new(ErrorKind::NotConnected, "No pipe connected"))
+    fn xrecv(&mut self, msg: &mut Message) -> Result<(), i32> {
+        // Deallocate old content of the message.
+        *msg = Message::new();
+
+        match &self.pipe {
+            Some(pipe) if pipe.read(msg) => Ok(()),
+            _ => Err(ZMQ_EAGAIN),
         }
     }
 
-    pub fn has_in(&self) -> bool {
-        self.pipe.as_ref().map_or(false, |p| p.check_read())
+    fn xhas_in(&mut self) -> bool {
+        self.pipe.as_ref().map_or(false, |pipe| pipe.check_read())
     }
 
-    pub fn has_out(&self) -> bool {
-        self.pipe.as_ref().map_or(false, |p| p.check_write())
+    fn xhas_out(&mut self) -> bool {
+        self.pipe.as_ref().map_or(false, |pipe| pipe.check_write())
     }
 }
 
@@ -155,8 +116,10 @@ mod tests {
 
     #[test]
     fn test_dgram_socket_creation() {
-        let socket = DgramSocket::new();
+        let ctx = Arc::new(Context::new());
+        let socket = Dgram::new(&ctx, 0, 1);
         assert!(socket.pipe.is_none());
         assert!(!socket.more_out);
+        assert_eq!(socket.base().options.socket_type, ZMQ_DGRAM);
     }
 }
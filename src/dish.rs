@@ -1,144 +1,257 @@
-use crate::message::Message;
+use crate::constants::ZMQ_EAGAIN;
+use crate::context::Context;
+use crate::dist::Dist;
+use crate::fair_queue::FairQueue;
+use crate::message::{Message, MsgFlags, ZMQ_GROUP_MAX_LENGTH};
+use crate::pipe::Pipe;
+use crate::socket_base::{SocketBase, SocketBehavior};
+use crate::zmq_draft::ZMQ_DISH;
 use std::collections::HashSet;
-use std::error::Error;
-
-const ZMQ_GROUP_MAX_LENGTH: usize = 255; // Assumed max length
-
-// #[derive(Debug)]
-// struct Msg {
-//     data: Vec<u8>,
-//     group: String,
-//     flags: u32,
-// }
-//
-// impl Msg {
-//     fn new() -> Self {
-//         Self {
-//             data: Vec::new(),
-//             group: String::new(),
-//             flags: 0,
-//         }
-//     }
-//
-//     fn init_join(&mut self) -> Result<(), Box<dyn Error>> {
-//         self.flags |= 1; // JOIN flag
-//         Ok(())
-//     }
-//
-//     fn init_leave(&mut self) -> Result<(), Box<dyn Error>> {
-//         self.flags |= 2; // LEAVE flag
-//         Ok(())
-//     }
-//
-//     fn set_group(&mut self, group: &str) -> Result<(), Box<dyn Error>> {
-//         self.group = group.to_string();
-//         Ok(())
-//     }
-//
-//     fn is_join(&self) -> bool {
-//         (self.flags & 1) != 0
-//     }
-//
-//     fn is_leave(&self) -> bool {
-//         (self.flags & 2) != 0
-//     }
-// }
-
-struct Dish {
+use std::sync::Arc;
+
+/// DISH socket: receives the messages of the groups it joined from RADIO
+/// peers.
+pub struct Dish {
+    base: SocketBase,
+
+    // Fair queueing object for inbound pipes.
+    fq: FairQueue,
+
+    // Object for distributing the subscriptions upstream.
+    dist: Dist,
+
+    // The repository of subscriptions.
     subscriptions: HashSet<String>,
+
+    // If true, 'message' contains a matching message to return on the
+    // next recv call.
     has_message: bool,
     message: Message,
-    fq: FairQueue,
-    dist: Distributor,
 }
 
 impl Dish {
-    fn new(parent: &Context, tid: u32, sid: i32) -> Self {
-        Self {
+    pub fn new(ctx: &Arc<Context>, tid: u32, sid: i32) -> Self {
+        let mut base = SocketBase::new(ctx, tid, sid, true);
+        base.options.socket_type = ZMQ_DISH;
+
+        // When socket is being closed down we don't want to wait till pending
+        // subscription commands are sent to the wire.
+        base.options.linger.store(0, std::sync::atomic::Ordering::SeqCst);
+
+        Dish {
+            base,
+            fq: FairQueue::new(),
+            dist: Dist::new(),
             subscriptions: HashSet::new(),
             has_message: false,
             message: Message::new(),
-            fq: FairQueue::new(),
-            dist: Distributor::new(),
         }
     }
 
-    fn join(&mut self, group: &str) -> Result<(), Box<dyn Error>> {
+    // Sends all the current subscriptions down the pipe.
+    fn send_subscriptions(&self, pipe: &Pipe) {
+        for group in &self.subscriptions {
+            let mut msg = Message::new();
+            if msg.init_join().is_err() || msg.set_group(group).is_err() {
+                continue;
+            }
+
+            // Send it to the pipe.
+            pipe.write(&mut msg);
+        }
+
+        pipe.flush();
+    }
+}
+
+impl SocketBehavior for Dish {
+    fn base(&self) -> &SocketBase {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut SocketBase {
+        &mut self.base
+    }
+
+    fn xattach_pipe(&mut self, pipe: Pipe, _subscribe_to_all: bool, _locally_initiated: bool) {
+        self.fq.attach(pipe.clone());
+        self.dist.attach(pipe.clone());
+
+        // Send all the cached subscriptions to the new upstream peer.
+        self.send_subscriptions(&pipe);
+    }
+
+    fn xread_activated(&mut self, pipe: &Pipe) {
+        self.fq.activated(pipe);
+    }
+
+    fn xwrite_activated(&mut self, pipe: &Pipe) {
+        self.dist.activated(pipe);
+    }
+
+    fn xpipe_terminated(&mut self, pipe: &Pipe) {
+        self.fq.pipe_terminated(pipe);
+        self.dist.pipe_terminated(pipe);
+    }
+
+    /// Joins the group, telling all connected radios about it.
+    fn xjoin(&mut self, group: &str) -> Result<(), i32> {
         if group.len() > ZMQ_GROUP_MAX_LENGTH {
-            return Err("Group name too long".into());
+            return Err(libc::EINVAL);
         }
 
+        // User cannot join same group twice
         if !self.subscriptions.insert(group.to_string()) {
-            return Err("Already subscribed to group".into());
+            return Err(libc::EINVAL);
         }
 
         let mut msg = Message::new();
         msg.init_join()?;
-        msg.set_group(group)?;
+        msg.set_group(group).map_err(|_| libc::EINVAL)?;
 
-        self.dist.send_to_all(&msg)?;
-        Ok(())
+        self.dist.send_to_all(&mut msg)
     }
 
-    fn leave(&mut self, group: &str) -> Result<(), Box<dyn Error>> {
+    /// Leaves the group, telling all connected radios about it.
+    fn xleave(&mut self, group: &str) -> Result<(), i32> {
         if group.len() > ZMQ_GROUP_MAX_LENGTH {
-            return Err("Group name too long".into());
+            return Err(libc::EINVAL);
         }
 
         if !self.subscriptions.remove(group) {
-            return Err("Not subscribed to group".into());
+            return Err(libc::EINVAL);
         }
 
         let mut msg = Message::new();
         msg.init_leave()?;
-        msg.set_group(group)?;
+        msg.set_group(group).map_err(|_| libc::EINVAL)?;
 
-        self.dist.send_to_all(&msg)?;
-        Ok(())
+        self.dist.send_to_all(&mut msg)
+    }
+
+    fn xhiccuped(&mut self, pipe: &Pipe) {
+        // Send all the cached subscriptions to the hiccuped pipe.
+        self.send_subscriptions(pipe);
+    }
+
+    fn xsend(&mut self, _msg: &mut Message) -> Result<(), i32> {
+        Err(libc::ENOTSUP)
+    }
+
+    fn xhas_out(&mut self) -> bool {
+        // Subscription can be added/removed anytime.
+        true
     }
 
-    fn recv(&mut self, msg: &mut Message) -> Result<(), Box<dyn Error>> {
+    fn xrecv(&mut self, msg: &mut Message) -> Result<(), i32> {
+        // If there's already a message prepared by a previous call to zmq_poll,
+        // return it straight ahead.
         if self.has_message {
-            *msg = std::mem::replace(&mut self.message, Message::new());
+            *msg = std::mem::take(&mut self.message);
             self.has_message = false;
             return Ok(());
         }
 
-        self.recv_internal(msg)
-    }
-
-    fn recv_internal(&mut self, msg: &mut Message) -> Result<(), Box<dyn Error>> {
         loop {
+            // Get a message using fair queueing algorithm.
             self.fq.recv(msg)?;
 
-            if self.subscriptions.contains(&msg.group) {
+            // Skip non matching messages
+            if self.subscriptions.contains(msg.get_group()) {
                 return Ok(());
             }
         }
     }
+
+    fn xhas_in(&mut self) -> bool {
+        // If there's already a message prepared by a previous call to zmq_poll,
+        // return straight ahead.
+        if self.has_message {
+            return true;
+        }
+
+        loop {
+            // Get a message using fair queueing algorithm.
+            let mut message = Message::new();
+            if let Err(e) = self.fq.recv(&mut message) {
+                debug_assert_eq!(e, ZMQ_EAGAIN);
+                return false;
+            }
+
+            // Skip non matching messages
+            if self.subscriptions.contains(message.get_group()) {
+                self.message = message;
+                self.has_message = true;
+                return true;
+            }
+        }
+    }
+}
+
+/// Session hook of a DISH socket. Messages arrive as a group frame
+/// followed by the body and go up as one message with its group set;
+/// joins and leaves go out as JOIN and LEAVE commands.
+pub(crate) struct DishSession {
+    // Group frame of the message being received, once it arrived.
+    group: Option<String>,
 }
 
-// Placeholder implementations
-struct Context;
-struct FairQueue;
-struct Distributor;
+impl DishSession {
+    pub(crate) fn new() -> Self {
+        DishSession { group: None }
+    }
+
+    /// Checks a message coming from the peer. Returns false for a group
+    /// frame, which is kept until its body arrives; the body gets the
+    /// group set. Returns EFAULT for anything out of place.
+    pub(crate) fn check_msg(&mut self, msg: &mut Message) -> Result<bool, i32> {
+        if self.group.is_none() {
+            if !msg.has_more() || msg.size() > ZMQ_GROUP_MAX_LENGTH {
+                return Err(libc::EFAULT);
+            }
+            let group = std::str::from_utf8(msg.data()).map_err(|_| libc::EFAULT)?;
+            self.group = Some(group.to_string());
+            return Ok(false);
+        }
+        let group = self.group.as_deref().unwrap_or_default();
+
+        // Set the message group
+        if msg.get_group().is_empty() {
+            msg.set_group(group).map_err(|_| libc::EFAULT)?;
+        }
 
-impl FairQueue {
-    fn new() -> Self {
-        Self
+        // Thread safe socket doesn't support multipart messages
+        if msg.has_more() {
+            return Err(libc::EFAULT);
+        }
+        Ok(true)
     }
 
-    fn recv(&mut self, msg: &mut Message) -> Result<(), Box<dyn Error>> {
-        Ok(()) // Placeholder
+    /// The body was delivered to the socket, a group frame comes next.
+    pub(crate) fn msg_pushed(&mut self) {
+        self.group = None;
     }
-}
 
-impl Distributor {
-    fn new() -> Self {
-        Self
+    /// Replaces a join or leave message by the JOIN or LEAVE command sent
+    /// to the peer. Other messages are left alone.
+    pub(crate) fn convert_join_leave(&self, msg: &mut Message) -> Result<(), i32> {
+        let name: &[u8] = if msg.is_join() {
+            b"\x04JOIN"
+        } else if msg.is_leave() {
+            b"\x05LEAVE"
+        } else {
+            return Ok(());
+        };
+
+        let mut data = name.to_vec();
+        data.extend_from_slice(msg.get_group().as_bytes());
+        let mut command = Message::with_data(&data).map_err(|_| libc::ENOMEM)?;
+        command.set_flags(MsgFlags::Command);
+        *msg = command;
+        Ok(())
     }
 
-    fn send_to_all(&mut self, msg: &Message) -> Result<(), Box<dyn Error>> {
-        Ok(()) // Placeholder
+    pub(crate) fn reset(&mut self) {
+        self.group = None;
     }
 }
@@ -1,68 +1,28 @@
 use crate::message::Message;
+use crate::pipe::Pipe;
 
-// Forward declarations for external types we depend on
-pub trait Pipe {
-    fn write(&mut self, msg: &mut Message) -> bool;
-    fn flush(&mut self);
-    fn check_hwm(&self) -> bool;
-}
-
-// #[derive(Clone)]
-// pub struct Message {
-//     flags: u32,
-//     refs: i32,
-// }
-
-// impl Message {
-//     const MORE: u32 = 0x1;
-//
-//     pub fn new() -> Self {
-//         Message {
-//             flags: 0,
-//             refs: 0,
-//         }
-//     }
-//
-//     pub fn flags(&self) -> u32 {
-//         self.flags
-//     }
-//
-//     pub fn is_vsm(&self) -> bool {
-//         // Simplified VSM check
-//         false
-//     }
-//
-//     pub fn add_refs(&mut self, refs: i32) {
-//         self.refs += refs;
-//     }
-//
-//     pub fn rm_refs(&mut self, refs: i32) {
-//         self.refs -= refs;
-//     }
-//
-//     pub fn close(&mut self) -> bool {
-//         true
-//     }
-//
-//     pub fn init(&mut self) -> bool {
-//         true
-//     }
-// }
-
+/// Class manages a set of outbound pipes. It sends each messages to
+/// each of them.
 pub struct Dist {
-    // List of outbound pipes
-    pipes: Vec<Box<dyn Pipe>>,
+    // List of outbound pipes.
+    pipes: Vec<Pipe>,
 
-    // Number of all the pipes to send the next message to
+    // Number of all the pipes to send the next message to.
     matching: usize,
 
-    // Number of active pipes at the beginning of the pipes array
+    // Number of active pipes. All the active pipes are located at the
+    // beginning of the pipes array. These are the pipes the messages
+    // can be sent to at the moment.
     active: usize,
 
-    // Number of pipes eligible for sending messages
+    // Number of pipes eligible for sending messages to. This includes all
+    // the active pipes plus all the pipes that we can in theory send
+    // messages to (the HWM is not yet reached), but sending a message
+    // to them would result in partial message being delivered, ie. message
+    // with initial parts missing.
     eligible: usize,
 
-    // True if we are in the middle of a multipart message
+    // True if last we are in the middle of a multipart message.
     more: bool,
 }
 
@@ -77,179 +37,241 @@ impl Dist {
         }
     }
 
-    pub fn attach(&mut self, pipe: Box<dyn Pipe>) {
+    /// Adds the pipe to the distributor object.
+    pub fn attach(&mut self, pipe: Pipe) {
+        // If we are in the middle of sending a message, we'll add new pipe
+        // into the list of eligible pipes. Otherwise we add it to the list
+        // of active pipes.
+        self.pipes.push(pipe);
+        let last = self.pipes.len() - 1;
         if self.more {
-            self.pipes.push(pipe);
-            if self.eligible < self.pipes.len() {
-                self.pipes.swap(self.eligible, self.pipes.len() - 1);
-                self.eligible += 1;
-            }
+            self.pipes.swap(self.eligible, last);
+            self.eligible += 1;
         } else {
-            self.pipes.push(pipe);
-            if self.active < self.pipes.len() {
-                self.pipes.swap(self.active, self.pipes.len() - 1);
-                self.active += 1;
-                self.eligible += 1;
-            }
+            self.pipes.swap(self.active, last);
+            self.active += 1;
+            self.eligible += 1;
         }
     }
 
-    pub fn has_pipe(&self, pipe: &Box<dyn Pipe>) -> bool {
-        // In Rust we'll need to implement proper comparison for pipes
-        // This is a simplified version
-        false
+    /// Checks if this pipe is present in the distributor.
+    pub fn has_pipe(&self, pipe: &Pipe) -> bool {
+        self.pipes.contains(pipe)
     }
 
-    pub fn match_pipe(&mut self, index: usize) {
-        if index < self.matching || index >= self.eligible {
+    /// Mark the pipe as matching. Subsequent call to send_to_matching
+    /// will send message also to this pipe.
+    pub fn match_pipe(&mut self, pipe: &Pipe) {
+        let index = match self.pipes.iter().position(|p| p == pipe) {
+            Some(index) => index,
+            None => return,
+        };
+
+        // If pipe is already matching do nothing.
+        if index < self.matching {
+            return;
+        }
+
+        // If the pipe isn't eligible, ignore it.
+        if index >= self.eligible {
             return;
         }
+
+        // Mark the pipe as matching.
         self.pipes.swap(index, self.matching);
         self.matching += 1;
     }
 
+    /// Marks all pipes that are not matched as matched and vice-versa.
     pub fn reverse_match(&mut self) {
         let prev_matching = self.matching;
+
+        // Reset matching to 0
         self.unmatch();
 
+        // Mark all matching pipes as not matching and vice-versa.
+        // To do this, push all pipes that are eligible but not
+        // matched - i.e. between "matching" and "eligible" -
+        // to the beginning of the queue.
         for i in prev_matching..self.eligible {
             self.pipes.swap(i, self.matching);
             self.matching += 1;
         }
     }
 
+    /// Mark all pipes as non-matching.
     pub fn unmatch(&mut self) {
         self.matching = 0;
     }
 
-    pub fn pipe_terminated(&mut self, index: usize) {
+    /// Removes the pipe from the distributor object.
+    pub fn pipe_terminated(&mut self, pipe: &Pipe) {
+        let mut index = match self.pipes.iter().position(|p| p == pipe) {
+            Some(index) => index,
+            None => return,
+        };
+
+        // Remove the pipe from the list; adjust number of matching, active
+        // and/or eligible pipes accordingly.
         if index < self.matching {
             self.pipes.swap(index, self.matching - 1);
             self.matching -= 1;
+            index = self.matching;
         }
         if index < self.active {
             self.pipes.swap(index, self.active - 1);
             self.active -= 1;
+            index = self.active;
         }
         if index < self.eligible {
             self.pipes.swap(index, self.eligible - 1);
             self.eligible -= 1;
+            index = self.eligible;
         }
-        self.pipes.remove(index);
+
+        self.pipes.swap_remove(index);
     }
 
-    pub fn activated(&mut self, index: usize) {
-        if self.eligible < self.pipes.len() {
-            self.pipes.swap(index, self.eligible);
-            self.eligible += 1;
+    /// Activates pipe that have previously reached high watermark.
+    pub fn activated(&mut self, pipe: &Pipe) {
+        let index = match self.pipes.iter().position(|p| p == pipe) {
+            Some(index) => index,
+            None => return,
+        };
+        if index < self.eligible {
+            return;
         }
 
-        if !self.more && self.active < self.pipes.len() {
+        // Move the pipe from passive to eligible state.
+        self.pipes.swap(index, self.eligible);
+        self.eligible += 1;
+
+        // If there's no message being sent at the moment, move it to
+        // the active state.
+        if !self.more {
             self.pipes.swap(self.eligible - 1, self.active);
             self.active += 1;
         }
     }
 
-    pub fn send_to_all(&mut self, msg: &mut Message) -> Result<(), &'static str> {
+    /// Send the message to all the outbound pipes.
+    pub fn send_to_all(&mut self, msg: &mut Message) -> Result<(), i32> {
         self.matching = self.active;
         self.send_to_matching(msg)
     }
 
-    pub fn send_to_matching(&mut self, msg: &mut Message) -> Result<(), &'static str> {
-        let msg_more = (msg.flags() & Message::MORE) != 0;
+    /// Send the message to the matching outbound pipes.
+    pub fn send_to_matching(&mut self, msg: &mut Message) -> Result<(), i32> {
+        // Is this end of a multipart message?
+        let msg_more = msg.has_more();
 
-        self.distribute(msg)?;
+        // Push the message to matching pipes.
+        self.distribute(msg);
 
+        // If multipart message is fully sent, activate all the eligible pipes.
         if !msg_more {
             self.active = self.eligible;
         }
 
         self.more = msg_more;
+
         Ok(())
     }
 
-    fn distribute(&mut self, msg: &mut Message) -> Result<(), &'static str> {
+    // Write the message to all the matching pipes. The message is
+    // consumed: on return `msg` is an empty message.
+    fn distribute(&mut self, msg: &mut Message) {
+        // If there are no matching pipes available, simply drop the message.
         if self.matching == 0 {
-            msg.close();
-            msg.init();
-            return Ok(());
-        }
-
-        if msg.is_vsm() {
-            let mut i = 0;
-            while i < self.matching {
-                if !self.write(i, msg) {
-                    // Index stays the same as the pipe was removed
-                } else {
-                    i += 1;
-                }
-            }
-            msg.init();
-            return Ok(());
+            *msg = Message::new();
+            return;
         }
 
-        msg.add_refs(self.matching as i32 - 1);
-
-        let mut failed = 0;
+        // Push copy of the message to each matching pipe. Large messages
+        // share their content rather than being copied byte by byte.
         let mut i = 0;
         while i < self.matching {
-            if !self.write(i, msg) {
-                failed += 1;
-                // Index stays the same as the pipe was removed
-            } else {
+            let mut copy = msg.copy();
+            if self.write(i, &mut copy) {
                 i += 1;
             }
+            // Otherwise the index stays the same as the pipe was moved out
+            // of the matching range.
         }
 
-        if failed > 0 {
-            msg.rm_refs(failed);
-        }
-
-        msg.init();
-        Ok(())
+        *msg = Message::new();
     }
 
     pub fn has_out(&self) -> bool {
         true
     }
 
-    fn write(&mut self, index: usize, msg: &mut Message) -> bool {
+    // Write the message to the pipe. Make the pipe inactive if writing
+    // fails. In such a case false is returned.
+    fn write(&mut self, mut index: usize, msg: &mut Message) -> bool {
+        let more = msg.has_more();
         if !self.pipes[index].write(msg) {
-            // Handle pipe failure
-            if index < self.matching {
-                self.pipes.swap(index, self.matching - 1);
-                self.matching -= 1;
-            }
-            if index < self.active {
-                self.pipes.swap(index, self.active - 1);
-                self.active -= 1;
-            }
-            if self.active < self.eligible {
-                self.pipes.swap(self.active, self.eligible - 1);
-                self.eligible -= 1;
-            }
+            self.pipes.swap(index, self.matching - 1);
+            self.matching -= 1;
+            index = self.matching;
+            self.pipes.swap(index, self.active - 1);
+            self.active -= 1;
+            index = self.active;
+            self.pipes.swap(index, self.eligible - 1);
+            self.eligible -= 1;
             return false;
         }
-
-        if (msg.flags() & Message::MORE) == 0 {
+        if !more {
             self.pipes[index].flush();
         }
         true
     }
 
+    /// Returns true if all matching pipes can take one more message.
     pub fn check_hwm(&self) -> bool {
-        for i in 0..self.matching {
-            if !self.pipes[i].check_hwm() {
-                return false;
-            }
-        }
-        true
+        self.pipes[..self.matching].iter().all(|pipe| pipe.check_hwm())
+    }
+}
+
+impl Default for Dist {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::pipe::create_pipe_pair;
+
+    #[test]
+    fn test_send_to_matching() {
+        let mut dist = Dist::new();
+        let mut readers = Vec::new();
+        let mut writers = Vec::new();
+        for _ in 0..3 {
+            let (writer, reader) = create_pipe_pair([false, false], [0, 0]);
+            dist.attach(writer.clone());
+            writers.push(writer);
+            readers.push(reader);
+        }
 
-    // Add tests here
+        dist.unmatch();
+        dist.match_pipe(&writers[1]);
+        let mut msg = Message::with_data(b"topic").unwrap();
+        dist.send_to_matching(&mut msg).unwrap();
+
+        let mut out = Message::new();
+        assert!(!readers[0].read(&mut out));
+        assert!(readers[1].read(&mut out));
+        assert_eq!(out.data(), b"topic");
+        assert!(!readers[2].read(&mut out));
+
+        let mut msg = Message::with_data(b"all").unwrap();
+        dist.send_to_all(&mut msg).unwrap();
+        for reader in &readers {
+            assert!(reader.read(&mut out));
+            assert_eq!(out.data(), b"all");
+        }
+    }
 }
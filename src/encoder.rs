@@ -1,123 +1,79 @@
-use std::cmp;
-
-pub trait EncoderStep {
-    fn execute(&mut self, encoder: &mut Encoder<Self>)
-    where
-        Self: Sized;
-}
+//! Common part of the message encoders.
+//!
+//! libzmq's encoder_base_t runs a state machine of encoding steps over a
+//! raw buffer. Every wire format in use boils down to a header followed by
+//! the message body, so the encoders here only produce the header and let
+//! `EncoderBase` stream header and body out in chunks of any size.
 
-pub trait IEncoder {
-    fn encode(&mut self, data: &mut Option<&mut [u8]>, size: usize) -> usize;
-    fn load_msg(&mut self, msg: Box<Message>);
-}
-
-pub struct Message {
-    // Message implementation details...
-}
+use std::cmp;
+use std::mem;
 
-impl Message {
-    pub fn close(&mut self) -> i32 {
-        0
-    }
-    pub fn init(&mut self) -> i32 {
-        0
-    }
-}
+use crate::message::Message;
 
-pub struct Encoder<T: EncoderStep> {
-    write_pos: Option<*mut u8>,
-    to_write: usize,
-    next_step: Option<T>,
-    new_msg_flag: bool,
-    buf_size: usize,
-    buf: Vec<u8>,
-    in_progress: Option<Box<Message>>,
+pub struct EncoderBase {
+    // Header of the message being encoded.
+    header: Vec<u8>,
+    header_pos: usize,
+    // The message being encoded, if any, and how much of its body was
+    // written out.
+    in_progress: Option<Message>,
+    body_pos: usize,
 }
 
-impl<T: EncoderStep> Encoder<T> {
-    pub fn new(bufsize: usize) -> Self {
-        Encoder {
-            write_pos: None,
-            to_write: 0,
-            next_step: None,
-            new_msg_flag: false,
-            buf_size: bufsize,
-            buf: vec![0; bufsize],
+impl EncoderBase {
+    pub fn new() -> Self {
+        EncoderBase {
+            header: Vec::new(),
+            header_pos: 0,
             in_progress: None,
+            body_pos: 0,
         }
     }
 
-    pub fn next_step(&mut self, write_pos: *mut u8, to_write: usize, next: T, new_msg_flag: bool) {
-        self.write_pos = Some(write_pos);
-        self.to_write = to_write;
-        self.next_step = Some(next);
-        self.new_msg_flag = new_msg_flag;
-    }
-
-    pub fn in_progress(&self) -> Option<&Box<Message>> {
-        self.in_progress.as_ref()
+    /// Starts encoding `msg`, preceded by `header`. The message is taken
+    /// over, leaving an empty one behind.
+    pub fn load(&mut self, msg: &mut Message, header: Vec<u8>) {
+        assert!(self.in_progress.is_none());
+        self.in_progress = Some(mem::take(msg));
+        self.header = header;
+        self.header_pos = 0;
+        self.body_pos = 0;
     }
-}
 
-impl<T: EncoderStep> IEncoder for Encoder<T> {
-    fn encode(&mut self, data: &mut Option<&mut [u8]>, size: usize) -> usize {
-        if self.in_progress.is_none() {
-            return 0;
-        }
-
-        let buffer = match data {
-            None => self.buf.as_mut_slice(),
-            Some(b) => *b,
+    /// Appends up to `size` bytes of the message being encoded to
+    /// `buffer`. Returns 0 once the message was fully written out.
+    pub fn encode(&mut self, buffer: &mut Vec<u8>, size: usize) -> usize {
+        let msg = match &self.in_progress {
+            Some(msg) => msg,
+            None => return 0,
         };
-        let buffersize = if data.is_none() { self.buf_size } else { size };
-
-        let mut pos = 0;
-        while pos < buffersize {
-            if self.to_write == 0 {
-                if self.new_msg_flag {
-                    if let Some(msg) = &mut self.in_progress {
-                        msg.close();
-                        msg.init();
-                    }
-                    self.in_progress = None;
-                    break;
-                }
-                if let Some(step) = &mut self.next_step {
-                    step.execute(self);
-                }
-            }
 
-            if pos == 0 && data.is_none() && self.to_write >= buffersize {
-                if let Some(write_pos) = self.write_pos {
-                    *data =
-                        Some(unsafe { std::slice::from_raw_parts_mut(write_pos, self.to_write) });
-                    pos = self.to_write;
-                    self.write_pos = None;
-                    self.to_write = 0;
-                    return pos;
-                }
-            }
+        let mut written = 0;
+        if self.header_pos < self.header.len() {
+            let n = cmp::min(self.header.len() - self.header_pos, size);
+            buffer.extend_from_slice(&self.header[self.header_pos..self.header_pos + n]);
+            self.header_pos += n;
+            written += n;
+        }
 
-            let to_copy = cmp::min(self.to_write, buffersize - pos);
-            if let Some(write_pos) = self.write_pos {
-                unsafe {
-                    std::ptr::copy_nonoverlapping(write_pos, buffer[pos..].as_mut_ptr(), to_copy);
-                }
-            }
-            pos += to_copy;
-            self.write_pos = self.write_pos.map(|p| unsafe { p.add(to_copy) });
-            self.to_write -= to_copy;
+        let body = msg.data();
+        if self.header_pos == self.header.len() && written < size {
+            let n = cmp::min(body.len() - self.body_pos, size - written);
+            buffer.extend_from_slice(&body[self.body_pos..self.body_pos + n]);
+            self.body_pos += n;
+            written += n;
         }
 
-        *data = Some(buffer);
-        pos
+        // The message is done; release it.
+        if self.header_pos == self.header.len() && self.body_pos == body.len() {
+            self.in_progress = None;
+        }
+        written
     }
+}
 
-    fn load_msg(&mut self, msg: Box<Message>) {
-        assert!(self.in_progress.is_none());
-        self.in_progress = Some(msg);
-        if let Some(step) = &mut self.next_step {
-            step.execute(self);
-        }
+impl Default for EncoderBase {
+    fn default() -> Self {
+        EncoderBase::new()
     }
 }
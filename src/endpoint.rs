@@ -1,10 +1,12 @@
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub enum EndpointType {
+    #[default]
     None,    // a connection-less endpoint
     Bind,    // a connection-oriented bind endpoint
     Connect, // a connection-oriented connect endpoint
 }
 
+#[derive(Clone, Debug, Default)]
 pub struct EndpointUriPair {
     local: String,
     remote: String,
@@ -1,5 +1,13 @@
 use core::fmt;
+use std::cell::Cell;
 use std::error::Error;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+
+use crate::constants::{
+    ZMQ_EAGAIN, ZMQ_EFAULT, ZMQ_EFSM, ZMQ_EMTHREAD, ZMQ_ENOCOMPATPROTO, ZMQ_ENOTSOCK,
+    ZMQ_EPROTONOSUPPORT, ZMQ_ETERM,
+};
 
 #[cfg(windows)]
 use winapi::shared::winerror::*;
@@ -13,22 +21,17 @@ pub enum ZmqError {
     Term,
     MThread,
     HostUnreach,
-    #[cfg(windows)]
     NotSupported,
-    #[cfg(windows)]
     ProtoNotSupported,
-    #[cfg(windows)]
     NoBuffers,
-    #[cfg(windows)]
     NetDown,
-    #[cfg(windows)]
     AddrInUse,
-    #[cfg(windows)]
     AddrNotAvail,
-    #[cfg(windows)]
     ConnRefused,
-    #[cfg(windows)]
     InProgress,
+    Again,
+    NotSocket,
+    Fault,
     SystemError(std::io::Error),
     InvalidInput,
     ParsingError(String),
@@ -44,25 +47,20 @@ impl fmt::Display for ZmqError {
             ZmqError::Term => write!(f, "Context was terminated"),
             ZmqError::MThread => write!(f, "No thread available"),
             ZmqError::HostUnreach => write!(f, "Host unreachable"),
-            #[cfg(windows)]
             ZmqError::NotSupported => write!(f, "Not supported"),
-            #[cfg(windows)]
             ZmqError::ProtoNotSupported => write!(f, "Protocol not supported"),
-            #[cfg(windows)]
             ZmqError::NoBuffers => write!(f, "No buffer space available"),
-            #[cfg(windows)]
             ZmqError::NetDown => write!(f, "Network is down"),
-            #[cfg(windows)]
             ZmqError::AddrInUse => write!(f, "Address in use"),
-            #[cfg(windows)]
             ZmqError::AddrNotAvail => write!(f, "Address not available"),
-            #[cfg(windows)]
             ZmqError::ConnRefused => write!(f, "Connection refused"),
-            #[cfg(windows)]
             ZmqError::InProgress => write!(f, "Operation in progress"),
+            ZmqError::Again => write!(f, "Resource temporarily unavailable"),
+            ZmqError::NotSocket => write!(f, "Socket operation on non-socket"),
+            ZmqError::Fault => write!(f, "Bad address"),
             ZmqError::SystemError(e) => write!(f, "{}", e),
-            ZmqError::InvalidInput => todo!(),
-            ZmqError::ParsingError(_) => todo!(),
+            ZmqError::InvalidInput => write!(f, "Invalid argument"),
+            ZmqError::ParsingError(s) => write!(f, "Parsing error: {}", s),
         }
     }
 }
@@ -75,29 +73,176 @@ impl Error for ZmqError {
             ZmqError::Term => "Context was terminated",
             ZmqError::MThread => "No thread available",
             ZmqError::HostUnreach => "Host unreachable",
-            #[cfg(windows)]
             ZmqError::NotSupported => "Not supported",
-            #[cfg(windows)]
             ZmqError::ProtoNotSupported => "Protocol not supported",
-            #[cfg(windows)]
             ZmqError::NoBuffers => "No buffer space available",
-            #[cfg(windows)]
             ZmqError::NetDown => "Network is down",
-            #[cfg(windows)]
             ZmqError::AddrInUse => "Address in use",
-            #[cfg(windows)]
             ZmqError::AddrNotAvail => "Address not available",
-            #[cfg(windows)]
             ZmqError::ConnRefused => "Connection refused",
-            #[cfg(windows)]
             ZmqError::InProgress => "Operation in progress",
+            ZmqError::Again => "Resource temporarily unavailable",
+            ZmqError::NotSocket => "Socket operation on non-socket",
+            ZmqError::Fault => "Bad address",
+            #[allow(deprecated)]
             ZmqError::SystemError(e) => e.description(),
-            ZmqError::InvalidInput => todo!(),
-            ZmqError::ParsingError(_) => todo!(),
+            ZmqError::InvalidInput => "Invalid argument",
+            ZmqError::ParsingError(_) => "Parsing error",
         }
     }
 }
 
+impl ZmqError {
+    /// The errno value reported to C callers for this error.
+    pub fn errno(&self) -> i32 {
+        match self {
+            ZmqError::Fsm => ZMQ_EFSM,
+            ZmqError::NoCompatProto => ZMQ_ENOCOMPATPROTO,
+            ZmqError::Term => ZMQ_ETERM,
+            ZmqError::MThread => ZMQ_EMTHREAD,
+            #[cfg(unix)]
+            ZmqError::HostUnreach => libc::EHOSTUNREACH,
+            #[cfg(unix)]
+            ZmqError::NotSupported => libc::ENOTSUP,
+            #[cfg(unix)]
+            ZmqError::ProtoNotSupported => libc::EPROTONOSUPPORT,
+            #[cfg(unix)]
+            ZmqError::NoBuffers => libc::ENOBUFS,
+            #[cfg(unix)]
+            ZmqError::NetDown => libc::ENETDOWN,
+            #[cfg(unix)]
+            ZmqError::AddrInUse => libc::EADDRINUSE,
+            #[cfg(unix)]
+            ZmqError::AddrNotAvail => libc::EADDRNOTAVAIL,
+            #[cfg(unix)]
+            ZmqError::ConnRefused => libc::ECONNREFUSED,
+            #[cfg(unix)]
+            ZmqError::InProgress => libc::EINPROGRESS,
+            #[cfg(windows)]
+            ZmqError::HostUnreach => WSAEHOSTUNREACH as i32,
+            #[cfg(windows)]
+            ZmqError::NotSupported => WSAEOPNOTSUPP as i32,
+            #[cfg(windows)]
+            ZmqError::ProtoNotSupported => WSAEPROTONOSUPPORT as i32,
+            #[cfg(windows)]
+            ZmqError::NoBuffers => WSAENOBUFS as i32,
+            #[cfg(windows)]
+            ZmqError::NetDown => WSAENETDOWN as i32,
+            #[cfg(windows)]
+            ZmqError::AddrInUse => WSAEADDRINUSE as i32,
+            #[cfg(windows)]
+            ZmqError::AddrNotAvail => WSAEADDRNOTAVAIL as i32,
+            #[cfg(windows)]
+            ZmqError::ConnRefused => WSAECONNREFUSED as i32,
+            #[cfg(windows)]
+            ZmqError::InProgress => WSAEINPROGRESS as i32,
+            ZmqError::Again => ZMQ_EAGAIN,
+            ZmqError::NotSocket => ZMQ_ENOTSOCK,
+            ZmqError::Fault => ZMQ_EFAULT,
+            ZmqError::SystemError(e) => e.raw_os_error().unwrap_or(libc::EIO),
+            ZmqError::InvalidInput | ZmqError::ParsingError(_) => libc::EINVAL,
+        }
+    }
+}
+
+impl From<i32> for ZmqError {
+    /// Maps an errno value, as returned by the internals, onto the error
+    /// type of the public API. Codes without a dedicated variant are kept
+    /// as system errors so that `errno()` gives them back unchanged.
+    fn from(errnum: i32) -> Self {
+        match errnum {
+            ZMQ_EFSM => ZmqError::Fsm,
+            ZMQ_ENOCOMPATPROTO => ZmqError::NoCompatProto,
+            ZMQ_ETERM => ZmqError::Term,
+            ZMQ_EMTHREAD => ZmqError::MThread,
+            ZMQ_EAGAIN => ZmqError::Again,
+            ZMQ_ENOTSOCK => ZmqError::NotSocket,
+            ZMQ_EFAULT => ZmqError::Fault,
+            ZMQ_EPROTONOSUPPORT => ZmqError::ProtoNotSupported,
+            #[cfg(unix)]
+            libc::EINVAL => ZmqError::InvalidInput,
+            #[cfg(unix)]
+            libc::EHOSTUNREACH => ZmqError::HostUnreach,
+            #[cfg(unix)]
+            libc::ENOTSUP => ZmqError::NotSupported,
+            #[cfg(unix)]
+            libc::ENOBUFS => ZmqError::NoBuffers,
+            #[cfg(unix)]
+            libc::ENETDOWN => ZmqError::NetDown,
+            #[cfg(unix)]
+            libc::EADDRINUSE => ZmqError::AddrInUse,
+            #[cfg(unix)]
+            libc::EADDRNOTAVAIL => ZmqError::AddrNotAvail,
+            #[cfg(unix)]
+            libc::ECONNREFUSED => ZmqError::ConnRefused,
+            #[cfg(unix)]
+            libc::EINPROGRESS => ZmqError::InProgress,
+            _ => ZmqError::SystemError(std::io::Error::from_raw_os_error(errnum)),
+        }
+    }
+}
+
+thread_local! {
+    // Per-thread error slot, the equivalent of errno for the C API.
+    static ERRNO: Cell<i32> = const { Cell::new(0) };
+}
+
+/// Records the error of the last failed API call on the calling thread.
+pub fn set_errno(errnum: i32) {
+    ERRNO.with(|e| e.set(errnum));
+
+    // Keep the C runtime's errno in sync, as C callers commonly inspect it
+    // directly instead of calling zmq_errno().
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    unsafe {
+        *libc::__errno_location() = errnum;
+    }
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+    unsafe {
+        *libc::__error() = errnum;
+    }
+}
+
+/// Returns the error of the last failed API call on the calling thread.
+pub fn errno() -> i32 {
+    ERRNO.with(|e| e.get())
+}
+
+/// Returns a static, NUL-terminated description of the error code. 0MQ
+/// specific codes get libzmq's wording, everything else is delegated to
+/// the C runtime.
+pub fn strerror(errnum: i32) -> &'static CStr {
+    let text: &'static [u8] = match errnum {
+        ZMQ_EFSM => b"Operation cannot be accomplished in current state\0",
+        ZMQ_ENOCOMPATPROTO => b"The protocol is not compatible with the socket type\0",
+        ZMQ_ETERM => b"Context was terminated\0",
+        ZMQ_EMTHREAD => b"No thread available\0",
+        #[cfg(windows)]
+        e if e == WSAEOPNOTSUPP as i32 => b"Not supported\0",
+        #[cfg(windows)]
+        e if e == WSAEPROTONOSUPPORT as i32 => b"Protocol not supported\0",
+        #[cfg(windows)]
+        e if e == WSAENOBUFS as i32 => b"No buffer space available\0",
+        #[cfg(windows)]
+        e if e == WSAENETDOWN as i32 => b"Network is down\0",
+        #[cfg(windows)]
+        e if e == WSAEADDRINUSE as i32 => b"Address in use\0",
+        #[cfg(windows)]
+        e if e == WSAEADDRNOTAVAIL as i32 => b"Address not available\0",
+        #[cfg(windows)]
+        e if e == WSAECONNREFUSED as i32 => b"Connection refused\0",
+        #[cfg(windows)]
+        e if e == WSAEINPROGRESS as i32 => b"Operation in progress\0",
+        _ => {
+            // strerror() returns a pointer into static storage.
+            let text = unsafe { libc::strerror(errnum) };
+            return unsafe { CStr::from_ptr(text as *const c_char) };
+        }
+    };
+
+    CStr::from_bytes_with_nul(text).unwrap()
+}
+
 #[cfg(windows)]
 pub fn wsa_error_to_errno(errcode: i32) -> std::io::Error {
     use std::io::ErrorKind;
@@ -174,3 +319,60 @@ pub fn zmq_abort(msg: &str) -> ! {
         std::process::abort();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strerror_zmq_codes() {
+        assert_eq!(strerror(ZMQ_ETERM).to_str().unwrap(), "Context was terminated");
+        assert_eq!(
+            strerror(ZMQ_EFSM).to_str().unwrap(),
+            "Operation cannot be accomplished in current state"
+        );
+        assert_eq!(
+            strerror(ZMQ_ENOCOMPATPROTO).to_str().unwrap(),
+            "The protocol is not compatible with the socket type"
+        );
+        assert_eq!(strerror(ZMQ_EMTHREAD).to_str().unwrap(), "No thread available");
+    }
+
+    #[test]
+    fn test_errno_is_per_thread() {
+        set_errno(ZMQ_ETERM);
+        let other = std::thread::spawn(|| {
+            set_errno(libc::EAGAIN);
+            errno()
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(other, libc::EAGAIN);
+        assert_eq!(errno(), ZMQ_ETERM);
+    }
+
+    #[test]
+    fn test_zmq_error_errno() {
+        assert_eq!(ZmqError::Term.errno(), ZMQ_ETERM);
+        assert_eq!(ZmqError::Fsm.errno(), ZMQ_EFSM);
+        assert_eq!(ZmqError::InvalidInput.errno(), libc::EINVAL);
+    }
+
+    #[test]
+    fn test_zmq_error_from_errno_round_trips() {
+        for errnum in [
+            ZMQ_ETERM,
+            ZMQ_EFSM,
+            ZMQ_EAGAIN,
+            ZMQ_ENOTSOCK,
+            ZMQ_EPROTONOSUPPORT,
+            libc::EINVAL,
+            libc::EADDRINUSE,
+            libc::ENOENT,
+        ] {
+            assert_eq!(ZmqError::from(errnum).errno(), errnum);
+        }
+        assert!(matches!(ZmqError::from(ZMQ_EAGAIN), ZmqError::Again));
+    }
+}
@@ -1,37 +1,27 @@
+use crate::constants::ZMQ_EAGAIN;
 use crate::message::Message;
-use std::error::Error;
-// #[derive(Debug)]
-// pub struct Message {
-//     flags: u32,
-//     data: Vec<u8>,
-// }
-//
-// impl Message {
-//     pub fn new() -> Self {
-//         Message {
-//             flags: 0,
-//             data: Vec::new(),
-//         }
-//     }
-//
-//     pub fn has_more(&self) -> bool {
-//         (self.flags & 1) != 0
-//     }
-// }
-
-pub trait Pipe {
-    fn read(&mut self, msg: &mut Message) -> bool;
-    fn check_read(&self) -> bool;
-}
+use crate::pipe::Pipe;
 
+/// Class manages a set of inbound pipes. On receive it performs fair
+/// queueing so that senders gone berserk won't cause denial of
+/// service for decent senders.
 pub struct FairQueue {
-    pipes: Vec<T>,
+    // Inbound pipes.
+    pipes: Vec<Pipe>,
+
+    // Number of active pipes. All the active pipes are located at the
+    // beginning of the pipes array.
     active: usize,
+
+    // Index of the next bound pipe to read a message from.
     current: usize,
+
+    // If true, part of a multipart message was already received, but
+    // there are following parts still waiting in the current pipe.
     more: bool,
 }
 
-impl<T: Pipe> FairQueue<T> {
+impl FairQueue {
     pub fn new() -> Self {
         FairQueue {
             pipes: Vec::new(),
@@ -41,13 +31,21 @@ impl<T: Pipe> FairQueue<T> {
         }
     }
 
-    pub fn attach(&mut self, pipe: T) {
+    pub fn attach(&mut self, pipe: Pipe) {
         self.pipes.push(pipe);
-        self.pipes.swap(self.active, self.pipes.len() - 1);
+        let last = self.pipes.len() - 1;
+        self.pipes.swap(self.active, last);
         self.active += 1;
     }
 
-    pub fn pipe_terminated(&mut self, index: usize) {
+    pub fn pipe_terminated(&mut self, pipe: &Pipe) {
+        let index = match self.pipes.iter().position(|p| p == pipe) {
+            Some(index) => index,
+            None => return,
+        };
+
+        // Remove the pipe from the list; adjust number of active pipes
+        // accordingly.
         if index < self.active {
             self.active -= 1;
             self.pipes.swap(index, self.active);
@@ -55,33 +53,46 @@ impl<T: Pipe> FairQueue<T> {
                 self.current = 0;
             }
         }
-        self.pipes.remove(index);
+        self.pipes.swap_remove(self.active.max(index));
     }
 
-    pub fn activated(&mut self, index: usize) {
-        self.pipes.swap(index, self.active);
-        self.active += 1;
+    pub fn activated(&mut self, pipe: &Pipe) {
+        // Move the pipe to the list of active pipes.
+        if let Some(index) = self.pipes.iter().position(|p| p == pipe) {
+            if index >= self.active {
+                self.pipes.swap(index, self.active);
+                self.active += 1;
+            }
+        }
     }
 
-    pub fn recv(&mut self) -> Result<Message, Box<dyn Error>> {
-        self.recv_pipe().map(|(msg, _)| msg)
+    pub fn recv(&mut self, msg: &mut Message) -> Result<(), i32> {
+        self.recvpipe(msg).map(|_| ())
     }
 
-    pub fn recv_pipe(&mut self) -> Result<(Message, usize), Box<dyn Error>> {
-        let mut msg = Message::new();
-
+    /// Receives a message, returning the pipe it was read from.
+    pub fn recvpipe(&mut self, msg: &mut Message) -> Result<Pipe, i32> {
+        // Round-robin over the pipes to get the next message.
         while self.active > 0 {
-            if self.pipes[self.current].read(&mut msg) {
-                let current_pipe = self.current;
+            // Try to fetch new message. If we've already read part of the
+            // message subsequent part should be immediately available.
+            let fetched = self.pipes[self.current].read(msg);
+
+            // Note that when message is not fetched, current pipe is
+            // deactivated and replaced by another active pipe. Thus we don't
+            // have to increase the 'current' pointer.
+            if fetched {
+                let pipe = self.pipes[self.current].clone();
                 self.more = msg.has_more();
-
                 if !self.more {
                     self.current = (self.current + 1) % self.active;
                 }
-
-                return Ok((msg, current_pipe));
+                return Ok(pipe);
             }
 
+            // Check the atomicity of the message.
+            // If we've already received the first part of the message
+            // we should get the remaining parts without blocking.
             debug_assert!(!self.more);
 
             self.active -= 1;
@@ -91,19 +102,48 @@ impl<T: Pipe> FairQueue<T> {
             }
         }
 
-        Err("No messages available".into())
+        // No message is available. Initialise the output parameter
+        // to be a 0-byte message.
+        *msg = Message::new();
+        Err(ZMQ_EAGAIN)
+    }
+
+    /// Receives the next single-part message, dropping any multipart
+    /// messages on the way, as used by the thread-safe socket types.
+    pub fn recvpipe_single_part(&mut self, msg: &mut Message) -> Result<Pipe, i32> {
+        let mut pipe = self.recvpipe(msg)?;
+
+        // Drop any messages with more flag
+        while msg.has_more() {
+            // drop all frames of the current multi-frame message
+            self.recvpipe(msg)?;
+            while msg.has_more() {
+                self.recvpipe(msg)?;
+            }
+
+            // get the new message
+            pipe = self.recvpipe(msg)?;
+        }
+
+        Ok(pipe)
     }
 
     pub fn has_in(&mut self) -> bool {
+        // There are subsequent parts of the partly-read message available.
         if self.more {
             return true;
         }
 
+        // Note that messing with current doesn't break the fairness of fair
+        // queueing algorithm. If there are no messages available current will
+        // get back to its original value. Otherwise it'll point to the first
+        // pipe holding messages, skipping only pipes with no messages available.
         while self.active > 0 {
             if self.pipes[self.current].check_read() {
                 return true;
             }
 
+            // Deactivate the pipe.
             self.active -= 1;
             self.pipes.swap(self.current, self.active);
             if self.current == self.active {
@@ -115,30 +155,57 @@ impl<T: Pipe> FairQueue<T> {
     }
 }
 
+impl Default for FairQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::pipe::create_pipe_pair;
 
-    struct MockPipe {
-        has_message: bool,
-    }
+    #[test]
+    fn test_fair_queue_basic() {
+        let mut fq = FairQueue::new();
+        assert!(!fq.has_in());
 
-    impl Pipe for MockPipe {
-        fn read(&mut self, _msg: &mut Message) -> bool {
-            self.has_message
-        }
+        let (writer, reader) = create_pipe_pair([false, false], [0, 0]);
+        fq.attach(reader);
+        assert!(!fq.has_in());
 
-        fn check_read(&self) -> bool {
-            self.has_message
-        }
+        let mut msg = Message::with_data(b"x").unwrap();
+        assert!(writer.write(&mut msg));
+        writer.flush();
+        fq.activated(&fq.pipes[0].clone());
+        assert!(fq.has_in());
     }
 
     #[test]
-    fn test_fair_queue_basic() {
+    fn test_fair_queue_round_robin() {
         let mut fq = FairQueue::new();
-        assert!(!fq.has_in());
+        let mut writers = Vec::new();
+        for _ in 0..2 {
+            let (writer, reader) = create_pipe_pair([false, false], [0, 0]);
+            fq.attach(reader);
+            writers.push(writer);
+        }
+        for (i, writer) in writers.iter().enumerate() {
+            for _ in 0..2 {
+                assert!(writer.write(&mut Message::with_data(&[i as u8]).unwrap()));
+            }
+            writer.flush();
+        }
 
-        fq.attach(MockPipe { has_message: true });
-        assert!(fq.has_in());
+        let mut msg = Message::new();
+        let mut seen = Vec::new();
+        while fq.recv(&mut msg).is_ok() {
+            seen.push(msg.data()[0]);
+        }
+        // Messages alternate between the pipes.
+        assert_eq!(seen.len(), 4);
+        assert_ne!(seen[0], seen[1]);
+        assert_eq!(fq.recv(&mut msg), Err(ZMQ_EAGAIN));
     }
 }
@@ -1,108 +1,56 @@
-use std::rc::Rc;
-
-// Forward declarations
-pub struct Context;
-pub struct Pipe;
-pub struct Message {
-    flags: u32,
-}
-
-// Constants
-const MSG_MORE: u32 = 1;
-
-// Message implementation
-impl Message {
-    fn flags(&self) -> u32 {
-        self.flags
-    }
-}
-
-// Fair queuing implementation
-struct FairQueue {
-    pipes: Vec<Rc<Pipe>>,
-}
-
-impl FairQueue {
-    fn new() -> Self {
-        FairQueue { pipes: Vec::new() }
-    }
-
-    fn attach(&mut self, pipe: Rc<Pipe>) {
-        self.pipes.push(pipe);
-    }
-
-    fn activated(&mut self, _pipe: &Pipe) {
-        // Implementation for pipe activation
-    }
-
-    fn pipe_terminated(&mut self, pipe: &Pipe) {
-        self.pipes
-            .retain(|p| !Rc::ptr_eq(p, &Rc::new(pipe.clone())));
-    }
-
-    fn has_in(&self) -> bool {
-        !self.pipes.is_empty()
-    }
-
-    fn recvpipe(&mut self, msg: &mut Message) -> i32 {
-        // Simplified receive implementation
-        if self.pipes.is_empty() {
-            return -1;
-        }
-        0
-    }
-}
-
-// Main Gather implementation
+use crate::context::Context;
+use crate::fair_queue::FairQueue;
+use crate::message::Message;
+use crate::pipe::Pipe;
+use crate::socket_base::{SocketBase, SocketBehavior};
+use crate::zmq_draft::ZMQ_GATHER;
+use std::sync::Arc;
+
+/// GATHER socket: thread-safe, single-part counterpart of PULL.
 pub struct Gather {
+    base: SocketBase,
+    // Fair queueing object for inbound pipes.
     fq: FairQueue,
-    socket_id: i32,
-    thread_id: u32,
 }
 
 impl Gather {
-    pub fn new(parent: &Context, thread_id: u32, socket_id: i32) -> Self {
+    pub fn new(ctx: &Arc<Context>, tid: u32, sid: i32) -> Self {
+        let mut base = SocketBase::new(ctx, tid, sid, true);
+        base.options.socket_type = ZMQ_GATHER;
+
         Gather {
+            base,
             fq: FairQueue::new(),
-            socket_id,
-            thread_id,
         }
     }
+}
 
-    pub fn attach_pipe(&mut self, pipe: Rc<Pipe>) {
+impl SocketBehavior for Gather {
+    fn base(&self) -> &SocketBase {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut SocketBase {
+        &mut self.base
+    }
+
+    fn xattach_pipe(&mut self, pipe: Pipe, _subscribe_to_all: bool, _locally_initiated: bool) {
         self.fq.attach(pipe);
     }
 
-    pub fn read_activated(&mut self, pipe: &Pipe) {
+    fn xread_activated(&mut self, pipe: &Pipe) {
         self.fq.activated(pipe);
     }
 
-    pub fn pipe_terminated(&mut self, pipe: &Pipe) {
+    fn xpipe_terminated(&mut self, pipe: &Pipe) {
         self.fq.pipe_terminated(pipe);
     }
 
-    pub fn recv(&mut self, msg: &mut Message) -> i32 {
-        let mut rc = self.fq.recvpipe(msg);
-
-        // Drop any messages with more flag
-        while rc == 0 && (msg.flags() & MSG_MORE) != 0 {
-            // drop all frames of the current multi-frame message
-            rc = self.fq.recvpipe(msg);
-
-            while rc == 0 && (msg.flags() & MSG_MORE) != 0 {
-                rc = self.fq.recvpipe(msg);
-            }
-
-            // get the new message
-            if rc == 0 {
-                rc = self.fq.recvpipe(msg);
-            }
-        }
-
-        rc
+    fn xrecv(&mut self, msg: &mut Message) -> Result<(), i32> {
+        self.fq.recvpipe_single_part(msg).map(|_| ())
     }
 
-    pub fn has_in(&self) -> bool {
+    fn xhas_in(&mut self) -> bool {
         self.fq.has_in()
     }
 }
@@ -1,233 +1,185 @@
-use std::collections::HashSet;
+use std::collections::BTreeMap;
 use std::sync::atomic::{AtomicU32, Ordering};
 
+/// Multi-trie (prefix tree). Each node in the trie is a set of values,
+/// typically the pipes subscribed to the prefix leading to the node.
 #[derive(Debug)]
 pub struct GenericMtrie<T> {
-    pipes: Option<HashSet<T>>,
+    root: Node<T>,
     num_prefixes: AtomicU32,
-    min: u8,
-    count: u16,
-    live_nodes: u16,
-    next: NextNode<T>,
 }
 
 #[derive(Debug)]
-enum NextNode<T> {
-    Empty,
-    Single(Box<GenericMtrie<T>>),
-    Table(Vec<Option<Box<GenericMtrie<T>>>>),
+struct Node<T> {
+    values: Vec<T>,
+    next: BTreeMap<u8, Box<Node<T>>>,
 }
 
-#[derive(PartialEq)]
+#[derive(Debug, PartialEq)]
 pub enum RemoveResult {
     NotFound,
     LastValueRemoved,
     ValuesRemain,
 }
 
-impl<T: Eq + std::hash::Hash + Clone> GenericMtrie<T> {
+impl<T> Node<T> {
+    fn new() -> Self {
+        Node {
+            values: Vec::new(),
+            next: BTreeMap::new(),
+        }
+    }
+
+    fn is_redundant(&self) -> bool {
+        self.values.is_empty() && self.next.is_empty()
+    }
+}
+
+impl<T: PartialEq + Clone> Node<T> {
+    fn remove(&mut self, prefix: &[u8], value: &T) -> RemoveResult {
+        let (c, rest) = match prefix.split_first() {
+            None => {
+                return match self.values.iter().position(|v| v == value) {
+                    None => RemoveResult::NotFound,
+                    Some(pos) => {
+                        self.values.remove(pos);
+                        if self.values.is_empty() {
+                            RemoveResult::LastValueRemoved
+                        } else {
+                            RemoveResult::ValuesRemain
+                        }
+                    }
+                };
+            }
+            Some(split) => split,
+        };
+
+        let next = match self.next.get_mut(c) {
+            Some(next) => next,
+            None => return RemoveResult::NotFound,
+        };
+        let result = next.remove(rest, value);
+        if next.is_redundant() {
+            self.next.remove(c);
+        }
+        result
+    }
+
+    fn rm_value<F>(
+        &mut self,
+        value: &T,
+        buf: &mut Vec<u8>,
+        removed: &mut u32,
+        call_on_uniq: bool,
+        func: &mut F,
+    ) where
+        F: FnMut(&[u8]),
+    {
+        if let Some(pos) = self.values.iter().position(|v| v == value) {
+            self.values.remove(pos);
+            if self.values.is_empty() {
+                *removed += 1;
+            }
+            if !call_on_uniq || self.values.is_empty() {
+                func(buf);
+            }
+        }
+
+        self.next.retain(|c, next| {
+            buf.push(*c);
+            next.rm_value(value, buf, removed, call_on_uniq, func);
+            buf.pop();
+            !next.is_redundant()
+        });
+    }
+}
+
+impl<T: PartialEq + Clone> GenericMtrie<T> {
     pub fn new() -> Self {
         GenericMtrie {
-            pipes: None,
+            root: Node::new(),
             num_prefixes: AtomicU32::new(0),
-            min: 0,
-            count: 0,
-            live_nodes: 0,
-            next: NextNode::Empty,
         }
     }
 
+    /// Add key to the trie. Returns true if it's a new subscription
+    /// rather than a duplicate.
     pub fn add(&mut self, prefix: &[u8], value: T) -> bool {
-        let mut current = self;
-        let mut prefix_iter = prefix.iter();
-
-        while let Some(&c) = prefix_iter.next() {
-            if c < current.min || c >= current.min + current.count as u8 {
-                // Extend the table
-                if current.count == 0 {
-                    current.min = c;
-                    current.count = 1;
-                    current.next = NextNode::Empty;
-                } else {
-                    current.extend_table(c);
-                }
-            }
-
-            current = match &mut current.next {
-                NextNode::Empty => {
-                    let new_node = Box::new(GenericMtrie::new());
-                    current.next = NextNode::Single(new_node);
-                    current.live_nodes += 1;
-                    match &mut current.next {
-                        NextNode::Single(node) => node,
-                        _ => unreachable!(),
-                    }
-                }
-                NextNode::Single(node) if current.count == 1 => node,
-                NextNode::Table(table) => {
-                    let idx = (c - current.min) as usize;
-                    if table[idx].is_none() {
-                        table[idx] = Some(Box::new(GenericMtrie::new()));
-                        current.live_nodes += 1;
-                    }
-                    table[idx].as_mut().unwrap()
-                }
-                _ => unreachable!(),
-            };
+        let mut current = &mut self.root;
+        for c in prefix {
+            current = current.next.entry(*c).or_insert_with(|| Box::new(Node::new()));
         }
 
-        let is_new = current.pipes.is_none();
+        let is_new = current.values.is_empty();
         if is_new {
-            current.pipes = Some(HashSet::new());
             self.num_prefixes.fetch_add(1, Ordering::SeqCst);
         }
-        current.pipes.as_mut().unwrap().insert(value);
+        if !current.values.contains(&value) {
+            current.values.push(value);
+        }
         is_new
     }
 
+    /// Remove specific subscription from the trie.
     pub fn remove(&mut self, prefix: &[u8], value: &T) -> RemoveResult {
-        if prefix.is_empty() {
-            if let Some(pipes) = &mut self.pipes {
-                if pipes.remove(value) {
-                    if pipes.is_empty() {
-                        self.pipes = None;
-                        self.num_prefixes.fetch_sub(1, Ordering::SeqCst);
-                        return RemoveResult::LastValueRemoved;
-                    }
-                    return RemoveResult::ValuesRemain;
-                }
-            }
-            return RemoveResult::NotFound;
+        let result = self.root.remove(prefix, value);
+        if result == RemoveResult::LastValueRemoved {
+            self.num_prefixes.fetch_sub(1, Ordering::SeqCst);
         }
-
-        // Handle non-empty prefix traversal and removal
-        let mut result = RemoveResult::NotFound;
-        let c = prefix[0];
-
-        if let Some(next) = self.get_next_node(c) {
-            result = next.remove(&prefix[1..], value);
-            if next.is_redundant() {
-                self.remove_child_node(c);
-            }
-        }
-
         result
     }
 
-    pub fn match_prefix<F>(&self, data: &[u8], mut callback: F)
+    /// Remove all subscriptions for a specific value from the trie.
+    /// The `func` callback is invoked with the prefixes removed; when
+    /// `call_on_uniq` is true only for prefixes no other value holds.
+    pub fn rm_value<F>(&mut self, value: &T, mut func: F, call_on_uniq: bool)
     where
-        F: FnMut(&T),
+        F: FnMut(&[u8]),
     {
-        let mut current = self;
-        let mut data_iter = data.iter();
+        let mut buf = Vec::new();
+        let mut removed = 0;
+        self.root
+            .rm_value(value, &mut buf, &mut removed, call_on_uniq, &mut func);
+        self.num_prefixes.fetch_sub(removed, Ordering::SeqCst);
+    }
 
+    /// Signal all the matching values.
+    pub fn match_prefix<F>(&self, data: &[u8], mut func: F)
+    where
+        F: FnMut(&T),
+    {
+        let mut current = &self.root;
+        let mut data = data.iter();
         loop {
-            if let Some(pipes) = &current.pipes {
-                for pipe in pipes {
-                    callback(pipe);
-                }
+            // Signal the values attached to this node.
+            for value in &current.values {
+                func(value);
             }
 
-            match data_iter.next() {
+            // If we are at the end of the message, there's nothing more to
+            // match.
+            let c = match data.next() {
+                Some(c) => c,
                 None => break,
-                Some(&c) if current.count == 0 => break,
-                Some(&c) => match &current.next {
-                    NextNode::Single(node) if current.count == 1 && c == current.min => {
-                        current = node;
-                    }
-                    NextNode::Table(table)
-                        if c >= current.min && c < current.min + current.count as u8 =>
-                    {
-                        if let Some(Some(node)) = table.get((c - current.min) as usize) {
-                            current = node;
-                        } else {
-                            break;
-                        }
-                    }
-                    _ => break,
-                },
-            }
+            };
+
+            // If there's no corresponding slot for the first character
+            // of the prefix, the message does not match.
+            current = match current.next.get(c) {
+                Some(next) => next,
+                None => break,
+            };
         }
     }
 
     pub fn num_prefixes(&self) -> u32 {
         self.num_prefixes.load(Ordering::SeqCst)
     }
+}
 
-    fn is_redundant(&self) -> bool {
-        self.pipes.is_none() && self.live_nodes == 0
-    }
-
-    fn extend_table(&mut self, c: u8) {
-        let new_min = std::cmp::min(self.min, c);
-        let new_max = std::cmp::max(self.min + self.count as u8 - 1, c);
-        let new_count = (new_max - new_min + 1) as u16;
-
-        let mut new_table = vec![None; new_count as usize];
-
-        match std::mem::replace(&mut self.next, NextNode::Empty) {
-            NextNode::Single(node) if self.count == 1 => {
-                let idx = (self.min - new_min) as usize;
-                new_table[idx] = Some(node);
-            }
-            NextNode::Table(old_table) => {
-                for (i, node) in old_table.into_iter().enumerate() {
-                    if let Some(node) = node {
-                        new_table[(self.min - new_min) as usize + i] = Some(node);
-                    }
-                }
-            }
-            _ => {}
-        }
-
-        self.min = new_min;
-        self.count = new_count;
-        self.next = NextNode::Table(new_table);
-    }
-
-    fn get_next_node(&mut self, c: u8) -> Option<&mut GenericMtrie<T>> {
-        if c < self.min || c >= self.min + self.count as u8 {
-            return None;
-        }
-
-        match &mut self.next {
-            NextNode::Single(node) if self.count == 1 => Some(node),
-            NextNode::Table(table) => {
-                let idx = (c - self.min) as usize;
-                table.get_mut(idx)?.as_mut()
-            }
-            _ => None,
-        }
-    }
-
-    fn remove_child_node(&mut self, c: u8) {
-        self.live_nodes -= 1;
-        match &mut self.next {
-            NextNode::Single(_) if self.count == 1 => {
-                self.next = NextNode::Empty;
-                self.count = 0;
-            }
-            NextNode::Table(table) => {
-                let idx = (c - self.min) as usize;
-                table[idx] = None;
-
-                if self.live_nodes == 1 {
-                    // Compact to single node
-                    let mut single_node = None;
-                    for node in table.iter_mut() {
-                        if let Some(n) = node.take() {
-                            single_node = Some(n);
-                            break;
-                        }
-                    }
-                    if let Some(node) = single_node {
-                        self.next = NextNode::Single(node);
-                        self.count = 1;
-                    }
-                }
-            }
-            _ => {}
-        }
+impl<T: PartialEq + Clone> Default for GenericMtrie<T> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -252,4 +204,22 @@ mod tests {
         assert_eq!(mtrie.remove(b"test", &1), RemoveResult::ValuesRemain);
         assert_eq!(mtrie.remove(b"test", &2), RemoveResult::LastValueRemoved);
     }
+
+    #[test]
+    fn test_prefix_match_and_rm_value() {
+        let mut mtrie = GenericMtrie::new();
+        assert!(mtrie.add(b"", 1));
+        assert!(mtrie.add(b"ab", 1));
+        assert!(mtrie.add(b"abc", 2));
+        assert_eq!(mtrie.num_prefixes(), 3);
+
+        let mut matches = Vec::new();
+        mtrie.match_prefix(b"abcd", |&v| matches.push(v));
+        assert_eq!(matches, vec![1, 1, 2]);
+
+        let mut removed = Vec::new();
+        mtrie.rm_value(&1, |prefix| removed.push(prefix.to_vec()), true);
+        assert_eq!(removed, vec![b"".to_vec(), b"ab".to_vec()]);
+        assert_eq!(mtrie.num_prefixes(), 1);
+    }
 }
@@ -1,13 +1,13 @@
 use crate::message::Message;
 
 /// Interface to be implemented by message encoder.
-pub trait IEncoder {
-    /// The function returns a batch of binary data. The data
-    /// are filled to a supplied buffer. If no buffer is supplied
-    /// encoder will provide buffer of its own.
+pub trait IEncoder: Send {
+    /// Appends up to `size` bytes of the encoded message to `buffer`.
+    /// A message that doesn't fit is continued on the next call.
     /// Function returns 0 when a new message is required.
-    fn encode(&mut self, buffer: Option<&mut [u8]>, size: usize) -> usize;
+    fn encode(&mut self, buffer: &mut Vec<u8>, size: usize) -> usize;
 
-    /// Load a new message into encoder.
+    /// Load a new message into encoder. The encoder takes the message
+    /// over, leaving an empty one behind.
     fn load_msg(&mut self, msg: &mut Message);
 }
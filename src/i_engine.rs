@@ -1,36 +1,50 @@
-// Assuming these types exist in other modules
+use crate::endpoint::EndpointUriPair;
+use crate::poller_base::IoContext;
+use crate::session_base::SessionBase;
 
-use crate::{endpoint::EndpointUriPair, io_thread::IoThread, mechanism_base::SessionBase};
-
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ErrorReason {
     ProtocolError,
     ConnectionError,
     TimeoutError,
 }
 
-pub trait IEngine {
+/// Abstract interface to be implemented by the various engines.
+///
+/// Engines live inside their session; every call gets the session state
+/// the engine reads messages from and writes them to, along with the I/O
+/// context of the session to register file descriptors and timers with.
+/// An engine that fails reports it through `SessionBase::engine_error`,
+/// after which the session drops it.
+pub trait IEngine: Send {
     /// Indicate if the engine has a handshake stage.
-    /// If engine has handshake stage, engine must call session.engine_ready when the handshake is complete.
+    /// If engine has handshake stage, engine must call session.engine_ready
+    /// when the handshake is complete.
     fn has_handshake_stage(&self) -> bool;
 
     /// Plug the engine to the session.
-    fn plug(&mut self, io_thread: &mut IoThread, session: &mut SessionBase);
+    fn plug(&mut self, session: &mut SessionBase, io: &mut IoContext);
 
-    /// Terminate and deallocate the engine.
+    /// Unplug the engine before it is dropped.
     /// Note that 'detached' events are not fired on termination.
-    fn terminate(&mut self);
+    fn terminate(&mut self, io: &mut IoContext);
 
     /// This method is called by the session to signalize that more
     /// messages can be written to the pipe.
-    /// Returns false if the engine was deleted due to an error.
-    fn restart_input(&mut self) -> bool;
+    /// Returns false if the engine failed.
+    fn restart_input(&mut self, session: &mut SessionBase, io: &mut IoContext) -> bool;
 
     /// This method is called by the session to signalize that there
     /// are messages to send available.
-    fn restart_output(&mut self);
+    fn restart_output(&mut self, session: &mut SessionBase, io: &mut IoContext);
 
-    fn zap_msg_available(&mut self);
+    fn zap_msg_available(&mut self, session: &mut SessionBase, io: &mut IoContext);
 
     fn get_endpoint(&self) -> &EndpointUriPair;
+
+    /// Poll events on the engine's file descriptor and its timers, as
+    /// routed by the session.
+    fn in_event(&mut self, session: &mut SessionBase, io: &mut IoContext);
+    fn out_event(&mut self, session: &mut SessionBase, io: &mut IoContext);
+    fn timer_event(&mut self, id: i32, session: &mut SessionBase, io: &mut IoContext);
 }
@@ -1,15 +1,15 @@
 use crate::command::Command;
 
 /// Interface to be implemented by mailbox.
-pub trait IMailbox {
+pub trait IMailbox: Send + Sync {
     /// Send a command to the mailbox
-    fn send(&mut self, cmd: &Command);
+    fn send(&self, cmd: Command);
 
-    /// Receive a command from the mailbox with timeout
-    /// Returns Ok(command) on success, or an error
-    fn recv(&mut self, timeout: i32) -> Result<Command, Box<dyn std::error::Error>>;
+    /// Receive a command from the mailbox with timeout in milliseconds
+    /// (-1 waits forever). Returns Err(EAGAIN) if the timeout expired.
+    fn recv(&self, timeout: i32) -> Result<Command, i32>;
 
     /// Close file descriptors in the signaller when forked
     #[cfg(target_family = "unix")]
-    fn forked(&mut self);
+    fn forked(&self);
 }
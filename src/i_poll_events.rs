@@ -1,15 +1,20 @@
 //! Poll events interface for ZMQ implementation
 //! Converted from original C++ code
 
+use crate::poller_base::IoContext;
+
 /// Trait to be implemented by objects that want to be notified
 /// about events on file descriptors.
+///
+/// Every callback gets the context of the I/O thread the object lives in,
+/// through which it can register file descriptors and timers.
 pub trait IPollEvents {
     /// Called by I/O thread when file descriptor is ready for reading.
-    fn in_event(&mut self);
+    fn in_event(&mut self, io: &mut IoContext);
 
     /// Called by I/O thread when file descriptor is ready for writing.
-    fn out_event(&mut self);
+    fn out_event(&mut self, io: &mut IoContext);
 
     /// Called when timer expires.
-    fn timer_event(&mut self, id: i32);
+    fn timer_event(&mut self, id: i32, io: &mut IoContext);
 }
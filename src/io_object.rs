@@ -1,87 +1,77 @@
 // SPDX-License-Identifier: MPL-2.0
 
-use std::ptr::null_mut;
-
-pub struct IoObject<'a> {
-    poller: *mut Poller,
-    io_thread: Option<&'a IoThread>,
+//! Objects living in an I/O thread.
+//!
+//! In libzmq io_object_t keeps a pointer to the poller of its thread and
+//! objects send commands to each other by pointer. Here the I/O thread owns
+//! its objects outright: they are addressed by an `ObjectId`, commands for
+//! them are queued in the thread's mailbox, and every callback gets an
+//! `IoContext` to register file descriptors and timers with.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::command::Command;
+use crate::constants::ZMQ_EAGAIN;
+use crate::i_mailbox::IMailbox;
+use crate::i_poll_events::IPollEvents;
+use crate::io_thread::IoThread;
+use crate::poller_base::IoContext;
+
+/// Identifies an object within the whole process. 0 is reserved for the
+/// I/O thread itself.
+pub type ObjectId = u64;
+
+static NEXT_OBJECT_ID: AtomicU64 = AtomicU64::new(1);
+
+pub(crate) fn next_object_id() -> ObjectId {
+    NEXT_OBJECT_ID.fetch_add(1, Ordering::Relaxed)
 }
 
-impl<'a> IoObject<'a> {
-    pub fn new(io_thread: Option<&'a IoThread>) -> IoObject<'a> {
-        let mut obj = IoObject {
-            poller: null_mut(),
-            io_thread,
-        };
-        if let Some(thread) = io_thread {
-            obj.plug(thread);
-        }
-        obj
-    }
-
-    pub fn plug(&mut self, io_thread: &'a IoThread) {
-        assert!(!self.poller.is_null());
-        self.poller = io_thread.get_poller();
-    }
-
-    pub fn unplug(&mut self) {
-        assert!(!self.poller.is_null());
-        self.poller = null_mut();
-    }
-
-    pub fn add_fd(&mut self, fd: Fd) -> Handle {
-        unsafe { (*self.poller).add_fd(fd, self) }
-    }
-
-    pub fn rm_fd(&mut self, handle: Handle) {
-        unsafe { (*self.poller).rm_fd(handle) }
-    }
-
-    pub fn set_pollin(&mut self, handle: Handle) {
-        unsafe { (*self.poller).set_pollin(handle) }
-    }
+/// An object plugged into an I/O thread, such as a session or a listener.
+pub trait IoObject: IPollEvents + Send {
+    /// Called once the object was handed over to its I/O thread.
+    fn process_plug(&mut self, io: &mut IoContext);
 
-    pub fn reset_pollin(&mut self, handle: Handle) {
-        unsafe { (*self.poller).reset_pollin(handle) }
-    }
-
-    pub fn set_pollout(&mut self, handle: Handle) {
-        unsafe { (*self.poller).set_pollout(handle) }
-    }
+    /// Handles a command posted to the object's mailbox.
+    fn process_command(&mut self, cmd: Command, io: &mut IoContext);
+}
 
-    pub fn reset_pollout(&mut self, handle: Handle) {
-        unsafe { (*self.poller).reset_pollout(handle) }
+impl fmt::Debug for dyn IoObject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("IoObject")
     }
+}
 
-    pub fn add_timer(&mut self, timeout: i32, id: i32) {
-        unsafe { (*self.poller).add_timer(timeout, self, id) }
-    }
+/// Mailbox of a single object in an I/O thread. Commands are queued in the
+/// thread's mailbox, tagged with the object they are meant for.
+pub struct ObjectMailbox {
+    id: ObjectId,
+    thread: Arc<IoThread>,
+}
 
-    pub fn cancel_timer(&mut self, id: i32) {
-        unsafe { (*self.poller).cancel_timer(self, id) }
+impl ObjectMailbox {
+    pub(crate) fn new(id: ObjectId, thread: Arc<IoThread>) -> Self {
+        ObjectMailbox { id, thread }
     }
 
-    pub fn in_event(&self) {
-        assert!(false);
+    pub fn id(&self) -> ObjectId {
+        self.id
     }
+}
 
-    pub fn out_event(&self) {
-        assert!(false);
+impl IMailbox for ObjectMailbox {
+    fn send(&self, cmd: Command) {
+        self.thread.send_to(self.id, cmd);
     }
 
-    pub fn timer_event(&self, _: i32) {
-        assert!(false);
+    // The I/O thread dispatches the commands itself; nobody waits on an
+    // object's mailbox.
+    fn recv(&self, _timeout: i32) -> Result<Command, i32> {
+        Err(ZMQ_EAGAIN)
     }
-}
 
-impl<'a> Drop for IoObject<'a> {
-    fn drop(&mut self) {
-        // Destructor logic if needed
-    }
+    #[cfg(target_family = "unix")]
+    fn forked(&self) {}
 }
-
-// Placeholder types for the example
-type Poller = ();
-type IoThread = ();
-type Fd = i32;
-type Handle = i32;
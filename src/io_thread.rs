@@ -1,112 +1,238 @@
-// Forward declarations
-pub struct Context;
-pub struct Poller;
-pub struct Mailbox;
-pub struct Command;
-
-// Equivalent to i_poll_events
-pub trait PollEvents {
-    fn in_event(&mut self);
-    fn out_event(&mut self);
-    fn timer_event(&mut self, id: i32);
+//! Background thread running the network I/O of a context.
+//!
+//! Each I/O thread owns a poller and the objects (sessions, listeners)
+//! plugged into it. Other threads talk to those objects through the
+//! thread's mailbox, whose signaler is polled alongside the sockets.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::command::Command;
+use crate::io_object::{next_object_id, IoObject, ObjectId, ObjectMailbox};
+use crate::poller_base::{IoContext, PollerBase};
+use crate::signaler::Signaler;
+
+// Commands addressed to this id are for the thread itself.
+const THREAD_ID: ObjectId = 0;
+
+struct Commands {
+    queue: VecDeque<(ObjectId, Command)>,
+    // True while the reader was signalled and hasn't drained the queue
+    // yet; the signaler is only used on the transition.
+    active: bool,
 }
 
 pub struct IoThread {
-    ctx: *mut Context,
     tid: u32,
-    mailbox: Mailbox,
-    mailbox_handle: Option<i32>, // Equivalent to poller_t::handle_t
-    poller: *mut Poller,
+    commands: Mutex<Commands>,
+    signaler: Signaler,
+    // Objects launched and not yet terminated.
+    live: Mutex<HashSet<ObjectId>>,
+    load: AtomicI32,
+    worker: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl IoThread {
-    pub fn new(ctx: *mut Context, tid: u32) -> Self {
-        let mut io_thread = IoThread {
-            ctx,
+    pub fn new(tid: u32) -> io::Result<Arc<Self>> {
+        Ok(Arc::new(IoThread {
             tid,
-            mailbox: Mailbox,
-            mailbox_handle: None,
-            poller: Box::into_raw(Box::new(Poller)),
-        };
+            commands: Mutex::new(Commands {
+                queue: VecDeque::new(),
+                active: false,
+            }),
+            signaler: Signaler::new()?,
+            live: Mutex::new(HashSet::new()),
+            load: AtomicI32::new(0),
+            worker: Mutex::new(None),
+        }))
+    }
 
-        if io_thread.mailbox.get_fd() != -1 {
-            io_thread.mailbox_handle =
-                Some(unsafe { (*io_thread.poller).add_fd(io_thread.mailbox.get_fd(), &io_thread) });
-            if let Some(handle) = io_thread.mailbox_handle {
-                unsafe {
-                    (*io_thread.poller).set_pollin(handle);
-                }
-            }
-        }
+    /// Launches the worker thread.
+    pub fn start(self: &Arc<Self>) -> io::Result<()> {
+        let thread = Arc::clone(self);
+        let worker = thread::Builder::new()
+            .name(format!("ZMQbg/IO/{}", self.tid - 2))
+            .spawn(move || thread.run())?;
+        *self.worker.lock().unwrap() = Some(worker);
+        Ok(())
+    }
 
-        io_thread
+    /// Asks the thread to exit once all of its objects terminated.
+    pub fn stop(&self) {
+        self.send_to(THREAD_ID, Command::Stop);
     }
 
-    pub fn start(&mut self) {
-        let name = format!("IO/{}", self.tid - 1); // Adjusted for Rust string formatting
-        unsafe {
-            (*self.poller).start(&name);
+    /// Waits for the worker thread to exit. Called from the worker itself,
+    /// e.g. when one of its objects drops the last reference to the context,
+    /// it returns right away; the thread exits on its own after `stop`.
+    pub fn join(&self) {
+        let worker = self.worker.lock().unwrap().take();
+        if let Some(worker) = worker {
+            if worker.thread().id() != thread::current().id() {
+                let _ = worker.join();
+            }
         }
     }
 
-    pub fn stop(&mut self) {
-        self.send_stop();
+    /// Returns the load of the thread, i.e. the number of file descriptors
+    /// it polls.
+    pub fn get_load(&self) -> i32 {
+        self.load.load(Ordering::Relaxed)
     }
 
-    pub fn get_mailbox(&mut self) -> &mut Mailbox {
-        &mut self.mailbox
+    /// Hands an object over to the thread. It is plugged asynchronously;
+    /// the returned mailbox can be used right away.
+    pub fn launch(self: &Arc<Self>, object: Box<dyn IoObject>) -> Arc<ObjectMailbox> {
+        let id = next_object_id();
+        self.live.lock().unwrap().insert(id);
+        self.send_to(id, Command::Plug(object));
+        Arc::new(ObjectMailbox::new(id, Arc::clone(self)))
     }
 
-    pub fn get_load(&self) -> i32 {
-        unsafe { (*self.poller).get_load() }
+    pub(crate) fn is_alive(&self, id: ObjectId) -> bool {
+        self.live.lock().unwrap().contains(&id)
     }
 
-    pub fn get_poller(&self) -> *mut Poller {
-        self.poller
+    pub(crate) fn send_to(&self, id: ObjectId, cmd: Command) {
+        let mut commands = self.commands.lock().unwrap();
+        commands.queue.push_back((id, cmd));
+        if !commands.active {
+            commands.active = true;
+            drop(commands);
+            self.signaler.send().expect("failed to signal I/O thread");
+        }
     }
 
-    fn process_stop(&mut self) {
-        if let Some(handle) = self.mailbox_handle {
-            unsafe {
-                (*self.poller).rm_fd(handle);
-                (*self.poller).stop();
+    // Takes the next command off the queue. Once the queue is empty the
+    // pending signal is consumed, so the thread sleeps until the next send.
+    fn next_command(&self) -> Option<(ObjectId, Command)> {
+        let mut commands = self.commands.lock().unwrap();
+        match commands.queue.pop_front() {
+            Some(cmd) => Some(cmd),
+            None => {
+                if commands.active {
+                    commands.active = false;
+                    drop(commands);
+                    let _ = self.signaler.recv();
+                }
+                None
             }
         }
     }
 
-    fn send_stop(&mut self) {
-        // Implementation details would go here
-    }
-}
+    fn run(self: Arc<Self>) {
+        let mut poller = PollerBase::new();
+        let mut objects: HashMap<ObjectId, Box<dyn IoObject>> = HashMap::new();
+        let mailbox_handle = poller.add_fd(self.signaler.get_fd(), THREAD_ID);
+        poller.set_pollin(mailbox_handle);
+        let mut stopping = false;
 
-impl PollEvents for IoThread {
-    fn in_event(&mut self) {
-        let mut cmd = Command;
         loop {
-            match self.mailbox.recv(&mut cmd, 0) {
-                Ok(_) => {
-                    // Process command
+            // Execute any timers that are due. The handlers may add new
+            // timers, so the wait is only known once none is left to run.
+            let timeout = loop {
+                let (expired, timeout) = poller.expired_timers();
+                if expired.is_empty() {
+                    break timeout;
+                }
+                for (owner, id) in expired {
+                    self.dispatch(&mut poller, &mut objects, owner, |object, io| {
+                        object.timer_event(id, io)
+                    });
+                }
+            };
+
+            // Wait for the objects to finish their business before
+            // exiting.
+            if stopping && objects.is_empty() {
+                break;
+            }
+
+            self.load.store(poller.get_load() - 1, Ordering::Relaxed);
+            for (handle, revents) in poller.poll(timeout) {
+                // The owner may have removed the descriptor while handling
+                // an earlier event of this round.
+                let owner = match poller.owner(handle) {
+                    Some(owner) => owner,
+                    None => continue,
+                };
+
+                if owner == THREAD_ID {
+                    stopping |= self.process_commands(&mut poller, &mut objects);
+                    continue;
+                }
+
+                if revents & libc::POLLOUT != 0 {
+                    self.dispatch(&mut poller, &mut objects, owner, |object, io| {
+                        object.out_event(io)
+                    });
+                }
+                if poller.owner(handle) != Some(owner) {
+                    continue;
+                }
+                // Errors and hang-ups are reported as input; the read
+                // then fails and tells the owner what happened.
+                if revents & (libc::POLLIN | libc::POLLERR | libc::POLLHUP) != 0 {
+                    self.dispatch(&mut poller, &mut objects, owner, |object, io| {
+                        object.in_event(io)
+                    });
                 }
-                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
-                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
-                Err(_) => break,
             }
         }
-    }
 
-    fn out_event(&mut self) {
-        panic!("out_event should never be called");
+        poller.rm_fd(mailbox_handle);
     }
 
-    fn timer_event(&mut self, _id: i32) {
-        panic!("timer_event should never be called");
+    // Processes the commands waiting in the mailbox. Returns true if the
+    // thread was asked to stop.
+    fn process_commands(
+        self: &Arc<Self>,
+        poller: &mut PollerBase,
+        objects: &mut HashMap<ObjectId, Box<dyn IoObject>>,
+    ) -> bool {
+        let mut stop = false;
+        while let Some((id, cmd)) = self.next_command() {
+            match cmd {
+                Command::Stop if id == THREAD_ID => stop = true,
+                Command::Plug(object) => {
+                    objects.insert(id, object);
+                    self.dispatch(poller, objects, id, |object, io| object.process_plug(io));
+                }
+                cmd => self.dispatch(poller, objects, id, |object, io| {
+                    object.process_command(cmd, io)
+                }),
+            }
+        }
+        stop
     }
-}
 
-impl Drop for IoThread {
-    fn drop(&mut self) {
-        unsafe {
-            Box::from_raw(self.poller);
+    // Runs a callback of the object with the given id. Commands for objects
+    // that are gone already are dropped.
+    fn dispatch<F>(
+        self: &Arc<Self>,
+        poller: &mut PollerBase,
+        objects: &mut HashMap<ObjectId, Box<dyn IoObject>>,
+        id: ObjectId,
+        f: F,
+    ) where
+        F: FnOnce(&mut dyn IoObject, &mut IoContext),
+    {
+        let mut object = match objects.remove(&id) {
+            Some(object) => object,
+            None => return,
+        };
+
+        let mut io = IoContext::new(poller, self, id);
+        f(object.as_mut(), &mut io);
+
+        if io.is_terminated() {
+            poller.forget(id);
+            self.live.lock().unwrap().remove(&id);
+        } else {
+            objects.insert(id, object);
         }
     }
 }
@@ -1,20 +1,33 @@
 //!
 //! This is the main file for the ZeroMQ FFI bindings.
 //! All functions in this file should allow for calling by external apps, and conform to C/C++ calling conventions.
+//!
+//! Rust code uses the safe API re-exported below instead; the FFI is built
+//! on top of it.
 
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 
-use crate::context::Context;
+use crate::message::{zmq_msg_t, MsgFlags, MsgFreeFn};
 use constants::{EFAULT, ZMQ_VERSION_MAJOR, ZMQ_VERSION_MINOR, ZMQ_VERSION_PATCH};
-use std::ffi::c_void;
-use std::os::raw::c_int;
+use std::convert::TryFrom;
+use std::ffi::{c_void, CStr};
+use std::os::raw::{c_char, c_int};
 use std::ptr;
+use std::slice;
+
+pub use crate::auth::{Authenticator, CURVE_ALLOW_ANY};
+pub use crate::err::ZmqError;
+pub use crate::rust_zmq::{
+    curve_keypair, curve_public, Context, Message, Result, Socket, SocketType, DONTWAIT, SNDMORE,
+};
+pub use crate::utils::{z85_decode, z85_encode, Z85Error};
 
 mod address;
 mod array;
 mod atomic_counter;
 mod atomic_ptr;
+mod auth;
 mod blob;
 mod channel;
 mod client;
@@ -30,8 +43,6 @@ mod curve_client;
 #[cfg(feature = "curve")]
 mod curve_client_tools;
 #[cfg(feature = "curve")]
-mod curve_keygen;
-#[cfg(feature = "curve")]
 mod curve_mechanism_base;
 #[cfg(feature = "curve")]
 mod curve_server;
@@ -81,6 +92,7 @@ mod message;
 mod metadata;
 mod mtrie;
 mod mutex;
+#[cfg(feature = "norm")]
 mod norm_engine;
 mod null_mechanism;
 mod object;
@@ -115,6 +127,7 @@ mod reaper;
 mod rep;
 mod req;
 mod router;
+mod rust_zmq;
 mod scatter;
 mod secure_allocator;
 mod select;
@@ -126,6 +139,7 @@ mod sockaddr_storage;
 mod socket;
 mod socket_base;
 mod socket_poller;
+pub mod sockopt;
 mod socks;
 mod socks_connecter;
 mod stream;
@@ -172,10 +186,7 @@ mod ws_protocol;
 mod wss_engine;
 mod xpub;
 mod xsub;
-mod ypipe;
-mod ypipe_base;
 mod ypipe_conflate;
-mod yqueue;
 mod zap_client;
 mod zmq_draft;
 mod zmq_pub;
@@ -191,6 +202,21 @@ pub extern "C" fn zmq_version(major: *mut c_int, minor: *mut c_int, patch: *mut
     }
 }
 
+/*  This function retrieves the errno as it is known to 0MQ library. The goal */
+/*  of this function is to make the code 100% portable, including where 0MQ   */
+/*  compiled with certain CRT library (on Windows) is linked to an            */
+/*  application that uses different CRT library.                              */
+#[no_mangle]
+pub extern "C" fn zmq_errno() -> c_int {
+    err::errno()
+}
+
+/*  Resolves system errors and 0MQ errors to human-readable string.           */
+#[no_mangle]
+pub extern "C" fn zmq_strerror(errnum: c_int) -> *const c_char {
+    err::strerror(errnum).as_ptr()
+}
+
 #[no_mangle]
 pub extern "C" fn zmq_ctx_new() -> *mut c_void {
     // Initialize network if needed
@@ -198,28 +224,820 @@ pub extern "C" fn zmq_ctx_new() -> *mut c_void {
         return ptr::null_mut();
     }
 
-    // Create new context
+    // Create new context. Sockets keep their own reference to the shared
+    // state, so it stays valid until the last socket is closed.
     Box::into_raw(Box::new(Context::new())) as *mut c_void
 }
 
 #[no_mangle]
 pub extern "C" fn zmq_ctx_term(context: *mut c_void) -> c_int {
-    if context.is_null() {
+    if unsafe { context_from_raw(context) }.is_none() {
         set_errno(EFAULT);
         return -1;
     }
 
-    unsafe {
-        let mut ctx = Box::from_raw(context as *mut Context);
-        match ctx.terminate() {
-            Ok(_) => {
-                shutdown_network();
-                0
-            }
+    // Take over the handle returned by zmq_ctx_new.
+    let ctx = unsafe { Box::from_raw(context as *mut Context) };
+    match ctx.terminate() {
+        Ok(_) => {
+            shutdown_network();
+            0
+        }
+        Err(e) => {
+            // Give the handle back so the caller can retry.
+            let _ = Box::into_raw(ctx);
+            set_errno(e.errno());
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_ctx_shutdown(context: *mut c_void) -> c_int {
+    match unsafe { context_from_raw(context) } {
+        Some(ctx) => result_to_rc(ctx.shutdown()),
+        None => {
+            set_errno(EFAULT);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_ctx_set(context: *mut c_void, option: c_int, optval: c_int) -> c_int {
+    match unsafe { context_from_raw(context) } {
+        Some(ctx) => result_to_rc(ctx.set(option, optval)),
+        None => {
+            set_errno(EFAULT);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_ctx_get(context: *mut c_void, option: c_int) -> c_int {
+    match unsafe { context_from_raw(context) } {
+        Some(ctx) => match ctx.get(option) {
+            Ok(value) => value,
             Err(e) => {
-                set_errno(e);
+                set_errno(e.errno());
                 -1
             }
+        },
+        None => {
+            set_errno(EFAULT);
+            -1
+        }
+    }
+}
+
+// Stable/legacy context API
+#[no_mangle]
+pub extern "C" fn zmq_init(io_threads: c_int) -> *mut c_void {
+    if io_threads < 0 {
+        set_errno(libc::EINVAL);
+        return ptr::null_mut();
+    }
+
+    let ctx = zmq_ctx_new();
+    if !ctx.is_null() {
+        zmq_ctx_set(ctx, constants::ZMQ_IO_THREADS, io_threads);
+    }
+    ctx
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_term(context: *mut c_void) -> c_int {
+    zmq_ctx_term(context)
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_ctx_destroy(context: *mut c_void) -> c_int {
+    zmq_ctx_term(context)
+}
+
+// Sockets
+#[no_mangle]
+pub extern "C" fn zmq_socket(context: *mut c_void, type_: c_int) -> *mut c_void {
+    let ctx = match unsafe { context_from_raw(context) } {
+        Some(ctx) => ctx,
+        None => {
+            set_errno(EFAULT);
+            return ptr::null_mut();
+        }
+    };
+
+    match SocketType::try_from(type_).and_then(|socket_type| ctx.socket(socket_type)) {
+        Ok(socket) => Box::into_raw(Box::new(socket)) as *mut c_void,
+        Err(e) => {
+            set_errno(e.errno());
+            ptr::null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_close(s: *mut c_void) -> c_int {
+    if unsafe { socket_from_raw(s) }.is_none() {
+        set_errno(libc::ENOTSOCK);
+        return -1;
+    }
+
+    let socket = unsafe { Box::from_raw(s as *mut Socket) };
+    result_to_rc(socket.close())
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_bind(s: *mut c_void, addr: *const c_char) -> c_int {
+    with_endpoint(s, addr, |socket, endpoint| socket.bind(endpoint))
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_connect(s: *mut c_void, addr: *const c_char) -> c_int {
+    with_endpoint(s, addr, |socket, endpoint| socket.connect(endpoint))
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_unbind(s: *mut c_void, addr: *const c_char) -> c_int {
+    with_endpoint(s, addr, |socket, endpoint| socket.unbind(endpoint))
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_disconnect(s: *mut c_void, addr: *const c_char) -> c_int {
+    with_endpoint(s, addr, |socket, endpoint| socket.disconnect(endpoint))
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_connect_peer(s: *mut c_void, addr: *const c_char) -> u32 {
+    // 0 is never a valid routing id, so it reports the failure; errno
+    // tells why.
+    let mut routing_id = 0;
+    let rc = with_endpoint(s, addr, |socket, endpoint| {
+        routing_id = socket.connect_peer(endpoint)?;
+        Ok(())
+    });
+    if rc == -1 {
+        return 0;
+    }
+    routing_id
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_join(s: *mut c_void, group: *const c_char) -> c_int {
+    with_endpoint(s, group, |socket, group| socket.join(group))
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_leave(s: *mut c_void, group: *const c_char) -> c_int {
+    with_endpoint(s, group, |socket, group| socket.leave(group))
+}
+
+// Socket options
+#[no_mangle]
+pub extern "C" fn zmq_setsockopt(
+    s: *mut c_void,
+    option: c_int,
+    optval: *const c_void,
+    optvallen: usize,
+) -> c_int {
+    let socket = match unsafe { socket_from_raw(s) } {
+        Some(socket) => socket,
+        None => {
+            set_errno(libc::ENOTSOCK);
+            return -1;
+        }
+    };
+    if optval.is_null() && optvallen != 0 {
+        set_errno(EFAULT);
+        return -1;
+    }
+
+    let optval = if optvallen == 0 {
+        &[][..]
+    } else {
+        unsafe { slice::from_raw_parts(optval as *const u8, optvallen) }
+    };
+    result_to_rc(socket.setsockopt(option, optval))
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_getsockopt(
+    s: *mut c_void,
+    option: c_int,
+    optval: *mut c_void,
+    optvallen: *mut usize,
+) -> c_int {
+    let socket = match unsafe { socket_from_raw(s) } {
+        Some(socket) => socket,
+        None => {
+            set_errno(libc::ENOTSOCK);
+            return -1;
+        }
+    };
+    let optvallen = match unsafe { optvallen.as_mut() } {
+        Some(optvallen) => optvallen,
+        None => {
+            set_errno(EFAULT);
+            return -1;
+        }
+    };
+    if optval.is_null() && *optvallen != 0 {
+        set_errno(EFAULT);
+        return -1;
+    }
+
+    let buf = if *optvallen == 0 {
+        &mut [][..]
+    } else {
+        unsafe { slice::from_raw_parts_mut(optval as *mut u8, *optvallen) }
+    };
+    match socket.getsockopt(option, buf) {
+        Ok(len) => {
+            *optvallen = len;
+            0
+        }
+        Err(e) => {
+            set_errno(e.errno());
+            -1
+        }
+    }
+}
+
+// Sending functions
+#[no_mangle]
+pub extern "C" fn zmq_send(s: *mut c_void, buf: *const c_void, len: usize, flags: c_int) -> c_int {
+    let socket = match unsafe { socket_from_raw(s) } {
+        Some(socket) => socket,
+        None => {
+            set_errno(libc::ENOTSOCK);
+            return -1;
+        }
+    };
+    if buf.is_null() && len != 0 {
+        set_errno(EFAULT);
+        return -1;
+    }
+
+    let data = if len == 0 {
+        &[][..]
+    } else {
+        unsafe { slice::from_raw_parts(buf as *const u8, len) }
+    };
+    let mut msg = match message::Message::with_data(data) {
+        Ok(msg) => msg,
+        Err(_) => {
+            set_errno(libc::ENOMEM);
+            return -1;
+        }
+    };
+
+    match socket.send_raw(&mut msg, flags) {
+        // Truncate returned size to INT_MAX to avoid overflow to negative
+        Ok(()) => len.min(c_int::MAX as usize) as c_int,
+        Err(e) => {
+            set_errno(e.errno());
+            -1
+        }
+    }
+}
+
+// Receiving functions
+#[no_mangle]
+pub extern "C" fn zmq_recv(s: *mut c_void, buf: *mut c_void, len: usize, flags: c_int) -> c_int {
+    let socket = match unsafe { socket_from_raw(s) } {
+        Some(socket) => socket,
+        None => {
+            set_errno(libc::ENOTSOCK);
+            return -1;
+        }
+    };
+    if buf.is_null() && len != 0 {
+        set_errno(EFAULT);
+        return -1;
+    }
+
+    let msg = match socket.recv_msg(flags) {
+        Ok(msg) => msg,
+        Err(e) => {
+            set_errno(e.errno());
+            return -1;
+        }
+    };
+
+    // An oversized message is silently truncated.
+    let size = msg.len();
+    let to_copy = size.min(len);
+    if to_copy > 0 {
+        unsafe {
+            ptr::copy_nonoverlapping(msg.as_ptr(), buf as *mut u8, to_copy);
+        }
+    }
+
+    // Return the original message size, which may be larger than len.
+    size.min(c_int::MAX as usize) as c_int
+}
+
+// Send a message that is never freed or modified (e.g. a string literal).
+#[no_mangle]
+pub extern "C" fn zmq_send_const(
+    s: *mut c_void,
+    buf: *const c_void,
+    len: usize,
+    flags: c_int,
+) -> c_int {
+    let socket = match unsafe { socket_from_raw(s) } {
+        Some(socket) => socket,
+        None => {
+            set_errno(libc::ENOTSOCK);
+            return -1;
+        }
+    };
+    if buf.is_null() && len != 0 {
+        set_errno(EFAULT);
+        return -1;
+    }
+
+    let mut msg = unsafe { message::Message::with_const(buf as *const u8, len) };
+    match socket.send_raw(&mut msg, flags) {
+        Ok(()) => len.min(c_int::MAX as usize) as c_int,
+        Err(e) => {
+            set_errno(e.errno());
+            -1
+        }
+    }
+}
+
+// Message functions
+//
+// A zmq_msg_t only points at the message, see its definition: copy it with
+// zmq_msg_copy or zmq_msg_move, never with memcpy, and release every
+// initialised message with zmq_msg_close or zmq_msg_send.
+#[no_mangle]
+pub extern "C" fn zmq_msg_init(msg: *mut zmq_msg_t) -> c_int {
+    match unsafe { msg.as_mut() } {
+        Some(msg) => {
+            msg.init(message::Message::new());
+            0
+        }
+        None => {
+            set_errno(EFAULT);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_msg_init_size(msg: *mut zmq_msg_t, size: usize) -> c_int {
+    let msg = match unsafe { msg.as_mut() } {
+        Some(msg) => msg,
+        None => {
+            set_errno(EFAULT);
+            return -1;
+        }
+    };
+
+    match message::Message::with_size(size) {
+        Ok(m) => {
+            msg.init(m);
+            0
+        }
+        Err(_) => {
+            msg.clear();
+            set_errno(libc::ENOMEM);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_msg_init_buffer(msg: *mut zmq_msg_t, buf: *const c_void, size: usize) -> c_int {
+    let msg = match unsafe { msg.as_mut() } {
+        Some(msg) => msg,
+        None => {
+            set_errno(EFAULT);
+            return -1;
+        }
+    };
+    if buf.is_null() && size != 0 {
+        set_errno(EFAULT);
+        return -1;
+    }
+
+    let data = if size == 0 {
+        &[][..]
+    } else {
+        unsafe { slice::from_raw_parts(buf as *const u8, size) }
+    };
+    match message::Message::with_data(data) {
+        Ok(m) => {
+            msg.init(m);
+            0
+        }
+        Err(_) => {
+            msg.clear();
+            set_errno(libc::ENOMEM);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_msg_init_data(
+    msg: *mut zmq_msg_t,
+    data: *mut c_void,
+    size: usize,
+    ffn: Option<MsgFreeFn>,
+    hint: *mut c_void,
+) -> c_int {
+    let msg = match unsafe { msg.as_mut() } {
+        Some(msg) => msg,
+        None => {
+            set_errno(EFAULT);
+            return -1;
+        }
+    };
+
+    match unsafe { message::Message::with_ffn(data as *mut u8, size, ffn, hint) } {
+        Ok(m) => {
+            msg.init(m);
+            0
+        }
+        Err(_) => {
+            msg.clear();
+            set_errno(EFAULT);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_msg_close(msg: *mut zmq_msg_t) -> c_int {
+    match unsafe { msg.as_mut() }.and_then(|msg| msg.take()) {
+        Some(_) => 0,
+        None => {
+            set_errno(EFAULT);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_msg_move(dest: *mut zmq_msg_t, src: *mut zmq_msg_t) -> c_int {
+    let (dest, src) = match unsafe { (dest.as_mut(), src.as_mut()) } {
+        (Some(dest), Some(src)) => (dest, src),
+        _ => {
+            set_errno(EFAULT);
+            return -1;
+        }
+    };
+
+    let moved = match src.get_mut() {
+        Some(m) => std::mem::take(m),
+        None => {
+            set_errno(EFAULT);
+            return -1;
+        }
+    };
+    // The destination must be initialised; its old content is released.
+    match dest.get_mut() {
+        Some(d) => *d = moved,
+        None => {
+            set_errno(EFAULT);
+            return -1;
+        }
+    }
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_msg_copy(dest: *mut zmq_msg_t, src: *mut zmq_msg_t) -> c_int {
+    let (dest, src) = match unsafe { (dest.as_mut(), src.as_mut()) } {
+        (Some(dest), Some(src)) => (dest, src),
+        _ => {
+            set_errno(EFAULT);
+            return -1;
+        }
+    };
+
+    let copy = match src.get_mut() {
+        Some(m) => m.copy(),
+        None => {
+            set_errno(EFAULT);
+            return -1;
+        }
+    };
+    match dest.get_mut() {
+        Some(d) => *d = copy,
+        None => {
+            set_errno(EFAULT);
+            return -1;
+        }
+    }
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_msg_data(msg: *mut zmq_msg_t) -> *mut c_void {
+    match unsafe { msg.as_mut() }.and_then(|msg| msg.get_mut()) {
+        Some(m) => m.data_ptr() as *mut c_void,
+        None => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_msg_size(msg: *const zmq_msg_t) -> usize {
+    match unsafe { msg.as_ref() }.and_then(|msg| msg.get()) {
+        Some(m) => m.size(),
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_msg_more(msg: *const zmq_msg_t) -> c_int {
+    zmq_msg_get(msg, constants::ZMQ_MORE)
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_msg_get(msg: *const zmq_msg_t, property: c_int) -> c_int {
+    let m = match unsafe { msg.as_ref() }.and_then(|msg| msg.get()) {
+        Some(m) => m,
+        None => {
+            set_errno(EFAULT);
+            return -1;
+        }
+    };
+
+    match property {
+        constants::ZMQ_MORE => m.has_more() as c_int,
+        // The originating file descriptor is not tracked.
+        constants::ZMQ_SRCFD => -1,
+        constants::ZMQ_SHARED => m.has_flag(MsgFlags::Shared) as c_int,
+        _ => {
+            set_errno(libc::EINVAL);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_msg_set(_msg: *mut zmq_msg_t, _property: c_int, _optval: c_int) -> c_int {
+    //  No properties supported at present
+    set_errno(libc::EINVAL);
+    -1
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_msg_gets(msg: *const zmq_msg_t, property: *const c_char) -> *const c_char {
+    let m = match unsafe { msg.as_ref() }.and_then(|msg| msg.get()) {
+        Some(m) => m,
+        None => {
+            set_errno(EFAULT);
+            return ptr::null();
+        }
+    };
+    if property.is_null() {
+        set_errno(libc::EINVAL);
+        return ptr::null();
+    }
+    let property = match unsafe { CStr::from_ptr(property) }.to_str() {
+        Ok(property) => property,
+        Err(_) => {
+            set_errno(libc::EINVAL);
+            return ptr::null();
+        }
+    };
+
+    match m.metadata().and_then(|metadata| metadata.get_cstr(property)) {
+        Some(value) => value.as_ptr(),
+        None => {
+            set_errno(libc::EINVAL);
+            ptr::null()
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_msg_set_routing_id(msg: *mut zmq_msg_t, routing_id: u32) -> c_int {
+    match unsafe { msg.as_mut() }.and_then(|msg| msg.get_mut()) {
+        Some(m) => match m.set_routing_id(routing_id) {
+            Ok(()) => 0,
+            Err(_) => {
+                set_errno(libc::EINVAL);
+                -1
+            }
+        },
+        None => {
+            set_errno(EFAULT);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_msg_routing_id(msg: *mut zmq_msg_t) -> u32 {
+    match unsafe { msg.as_ref() }.and_then(|msg| msg.get()) {
+        Some(m) => m.get_routing_id(),
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_msg_set_group(msg: *mut zmq_msg_t, group: *const c_char) -> c_int {
+    let m = match unsafe { msg.as_mut() }.and_then(|msg| msg.get_mut()) {
+        Some(m) => m,
+        None => {
+            set_errno(EFAULT);
+            return -1;
+        }
+    };
+    if group.is_null() {
+        set_errno(libc::EINVAL);
+        return -1;
+    }
+
+    match unsafe { CStr::from_ptr(group) }.to_str() {
+        Ok(group) if m.set_group(group).is_ok() => 0,
+        _ => {
+            set_errno(libc::EINVAL);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_msg_group(msg: *mut zmq_msg_t) -> *const c_char {
+    match unsafe { msg.as_ref() }.and_then(|msg| msg.get()) {
+        Some(m) => m.group_cstr().as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_msg_send(msg: *mut zmq_msg_t, s: *mut c_void, flags: c_int) -> c_int {
+    let socket = match unsafe { socket_from_raw(s) } {
+        Some(socket) => socket,
+        None => {
+            set_errno(libc::ENOTSOCK);
+            return -1;
+        }
+    };
+    let m = match unsafe { msg.as_mut() }.and_then(|msg| msg.get_mut()) {
+        Some(m) => m,
+        None => {
+            set_errno(EFAULT);
+            return -1;
+        }
+    };
+
+    let size = m.size();
+    match socket.send_raw(m, flags) {
+        // On success the message is left empty, as with libzmq.
+        Ok(()) => size.min(c_int::MAX as usize) as c_int,
+        Err(e) => {
+            set_errno(e.errno());
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_msg_recv(msg: *mut zmq_msg_t, s: *mut c_void, flags: c_int) -> c_int {
+    let socket = match unsafe { socket_from_raw(s) } {
+        Some(socket) => socket,
+        None => {
+            set_errno(libc::ENOTSOCK);
+            return -1;
+        }
+    };
+    let m = match unsafe { msg.as_mut() }.and_then(|msg| msg.get_mut()) {
+        Some(m) => m,
+        None => {
+            set_errno(EFAULT);
+            return -1;
+        }
+    };
+
+    match socket.recv_raw(m, flags) {
+        Ok(()) => m.size().min(c_int::MAX as usize) as c_int,
+        Err(e) => {
+            set_errno(e.errno());
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_sendmsg(s: *mut c_void, msg: *mut zmq_msg_t, flags: c_int) -> c_int {
+    zmq_msg_send(msg, s, flags)
+}
+
+#[no_mangle]
+pub extern "C" fn zmq_recvmsg(s: *mut c_void, msg: *mut zmq_msg_t, flags: c_int) -> c_int {
+    zmq_msg_recv(msg, s, flags)
+}
+
+// Encryption functions
+
+/*  Encodes a binary block as Z85 into dest, which must hold size * 5 / 4     */
+/*  characters and a terminating zero. The size must be a multiple of 4.      */
+#[no_mangle]
+pub extern "C" fn zmq_z85_encode(dest: *mut c_char, data: *const u8, size: usize) -> *mut c_char {
+    if dest.is_null() || (data.is_null() && size > 0) {
+        set_errno(libc::EINVAL);
+        return ptr::null_mut();
+    }
+    let data = if size == 0 {
+        &[][..]
+    } else {
+        unsafe { slice::from_raw_parts(data, size) }
+    };
+
+    match z85_encode(data) {
+        Ok(encoded) => {
+            unsafe { put_c_string(dest, &encoded) };
+            dest
+        }
+        Err(e) => {
+            set_errno(ZmqError::from(e).errno());
+            ptr::null_mut()
+        }
+    }
+}
+
+/*  Decodes a Z85 string into dest, which must hold strlen (string) * 4 / 5   */
+/*  bytes. Returns NULL with EINVAL if the string isn't valid Z85.            */
+#[no_mangle]
+pub extern "C" fn zmq_z85_decode(dest: *mut u8, string: *const c_char) -> *mut u8 {
+    if dest.is_null() || string.is_null() {
+        set_errno(libc::EINVAL);
+        return ptr::null_mut();
+    }
+
+    match z85_decode(unsafe { CStr::from_ptr(string) }.to_bytes()) {
+        Ok(decoded) => {
+            unsafe { ptr::copy_nonoverlapping(decoded.as_ptr(), dest, decoded.len()) };
+            dest
+        }
+        Err(e) => {
+            set_errno(ZmqError::from(e).errno());
+            ptr::null_mut()
+        }
+    }
+}
+
+/*  Generates a CURVE keypair, writing each key as 40 Z85 characters and a    */
+/*  terminating zero into the 41-byte buffers.                                */
+#[no_mangle]
+pub extern "C" fn zmq_curve_keypair(
+    z85_public_key: *mut c_char,
+    z85_secret_key: *mut c_char,
+) -> c_int {
+    if z85_public_key.is_null() || z85_secret_key.is_null() {
+        set_errno(libc::EINVAL);
+        return -1;
+    }
+
+    match curve_keypair() {
+        Ok((public_key, secret_key)) => {
+            unsafe {
+                put_c_string(z85_public_key, &public_key);
+                put_c_string(z85_secret_key, &secret_key);
+            }
+            0
+        }
+        Err(e) => {
+            set_errno(e.errno());
+            -1
+        }
+    }
+}
+
+/*  Derives the public key from a Z85 secret key, into a 41-byte buffer.      */
+#[no_mangle]
+pub extern "C" fn zmq_curve_public(
+    z85_public_key: *mut c_char,
+    z85_secret_key: *const c_char,
+) -> c_int {
+    if z85_public_key.is_null() || z85_secret_key.is_null() {
+        set_errno(libc::EINVAL);
+        return -1;
+    }
+    let secret_key = match unsafe { CStr::from_ptr(z85_secret_key) }.to_str() {
+        Ok(secret_key) => secret_key,
+        Err(_) => {
+            set_errno(libc::EINVAL);
+            return -1;
+        }
+    };
+
+    match curve_public(secret_key) {
+        Ok(public_key) => {
+            unsafe { put_c_string(z85_public_key, &public_key) };
+            0
+        }
+        Err(e) => {
+            set_errno(e.errno());
+            -1
         }
     }
 }
@@ -235,12 +1053,253 @@ fn shutdown_network() {
 }
 
 fn set_errno(err: i32) {
-    // Set errno
+    err::set_errno(err);
+}
+
+fn result_to_rc(rc: Result<()>) -> c_int {
+    match rc {
+        Ok(()) => 0,
+        Err(e) => {
+            set_errno(e.errno());
+            -1
+        }
+    }
+}
+
+// Borrows a context handed out by zmq_ctx_new.
+unsafe fn context_from_raw<'a>(context: *mut c_void) -> Option<&'a Context> {
+    if context.is_null() {
+        return None;
+    }
+    let ctx = &*(context as *const Context);
+    if !ctx.check_tag() {
+        return None;
+    }
+    Some(ctx)
+}
+
+unsafe fn socket_from_raw<'a>(s: *mut c_void) -> Option<&'a Socket> {
+    if s.is_null() {
+        return None;
+    }
+    let socket = &*(s as *const Socket);
+    if !socket.check_tag() {
+        return None;
+    }
+    Some(socket)
+}
+
+// Copies `value` and a terminating zero to a caller-provided buffer.
+unsafe fn put_c_string(dest: *mut c_char, value: &str) {
+    ptr::copy_nonoverlapping(value.as_ptr() as *const c_char, dest, value.len());
+    *dest.add(value.len()) = 0;
+}
+
+fn with_endpoint<F>(s: *mut c_void, addr: *const c_char, f: F) -> c_int
+where
+    F: FnOnce(&Socket, &str) -> Result<()>,
+{
+    let socket = match unsafe { socket_from_raw(s) } {
+        Some(socket) => socket,
+        None => {
+            set_errno(libc::ENOTSOCK);
+            return -1;
+        }
+    };
+    if addr.is_null() {
+        set_errno(libc::EINVAL);
+        return -1;
+    }
+    let endpoint = match unsafe { CStr::from_ptr(addr) }.to_str() {
+        Ok(endpoint) => endpoint,
+        Err(_) => {
+            set_errno(libc::EINVAL);
+            return -1;
+        }
+    };
+
+    result_to_rc(f(socket, endpoint))
 }
 
-// Many more functions would need to be implemented...
-// This is just a basic skeleton showing the structure
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{
+        ZMQ_DONTWAIT, ZMQ_ETERM, ZMQ_LAST_ENDPOINT, ZMQ_LINGER, ZMQ_PAIR, ZMQ_PULL, ZMQ_PUSH,
+        ZMQ_RCVTIMEO,
+    };
+    use crate::zmq_draft::ZMQ_PEER;
+    use std::ffi::CString;
+
+    fn addr(endpoint: &str) -> CString {
+        CString::new(endpoint).unwrap()
+    }
+
+    fn setsockopt_int(s: *mut c_void, option: c_int, value: c_int) {
+        let rc = zmq_setsockopt(
+            s,
+            option,
+            &value as *const c_int as *const c_void,
+            std::mem::size_of::<c_int>(),
+        );
+        assert_eq!(rc, 0);
+    }
+
+    // Sends `data` from `from` and receives it on `to`.
+    fn round_trip(from: *mut c_void, to: *mut c_void, data: &[u8]) {
+        let rc = zmq_send(from, data.as_ptr() as *const c_void, data.len(), 0);
+        assert_eq!(rc, data.len() as c_int);
+
+        let mut buf = [0u8; 64];
+        let rc = zmq_recv(to, buf.as_mut_ptr() as *mut c_void, buf.len(), 0);
+        assert_eq!(rc, data.len() as c_int);
+        assert_eq!(&buf[..data.len()], data);
+    }
+
+    #[test]
+    fn test_inproc_round_trip() {
+        let ctx = zmq_ctx_new();
+        let server = zmq_socket(ctx, ZMQ_PAIR);
+        let client = zmq_socket(ctx, ZMQ_PAIR);
+        assert!(!server.is_null() && !client.is_null());
+
+        assert_eq!(zmq_bind(server, addr("inproc://ffi").as_ptr()), 0);
+        assert_eq!(zmq_connect(client, addr("inproc://ffi").as_ptr()), 0);
+        round_trip(client, server, b"hello");
+        round_trip(server, client, b"world");
+
+        // An oversized message is truncated, but its full size is returned.
+        assert_eq!(zmq_send(client, b"truncated".as_ptr() as *const c_void, 9, 0), 9);
+        let mut buf = [0u8; 5];
+        let rc = zmq_recv(server, buf.as_mut_ptr() as *mut c_void, buf.len(), 0);
+        assert_eq!(rc, 9);
+        assert_eq!(&buf, b"trunc");
+
+        assert_eq!(zmq_close(client), 0);
+        assert_eq!(zmq_close(server), 0);
+        assert_eq!(zmq_ctx_term(ctx), 0);
+    }
+
+    #[test]
+    fn test_tcp_round_trip() {
+        let ctx = zmq_ctx_new();
+        let pull = zmq_socket(ctx, ZMQ_PULL);
+        let push = zmq_socket(ctx, ZMQ_PUSH);
+        setsockopt_int(pull, ZMQ_RCVTIMEO, 5000);
+        setsockopt_int(push, ZMQ_LINGER, 0);
+
+        assert_eq!(zmq_bind(pull, addr("tcp://127.0.0.1:*").as_ptr()), 0);
+        let mut endpoint = [0u8; 256];
+        let mut len = endpoint.len();
+        let rc = zmq_getsockopt(
+            pull,
+            ZMQ_LAST_ENDPOINT,
+            endpoint.as_mut_ptr() as *mut c_void,
+            &mut len,
+        );
+        assert_eq!(rc, 0);
+        let endpoint = CStr::from_bytes_until_nul(&endpoint).unwrap();
+        assert!(endpoint.to_str().unwrap().starts_with("tcp://127.0.0.1:"));
+
+        assert_eq!(zmq_connect(push, endpoint.as_ptr()), 0);
+        round_trip(push, pull, b"over tcp");
+
+        assert_eq!(zmq_close(push), 0);
+        assert_eq!(zmq_close(pull), 0);
+        assert_eq!(zmq_ctx_term(ctx), 0);
+    }
+
+    #[test]
+    fn test_msg_accessors() {
+        let mut msg = std::mem::MaybeUninit::<zmq_msg_t>::uninit();
+        let msg = msg.as_mut_ptr();
+        assert_eq!(zmq_msg_init_buffer(msg, b"hello".as_ptr() as *const c_void, 5), 0);
+
+        // The getters only need a shared reference.
+        let view = msg as *const zmq_msg_t;
+        assert_eq!(zmq_msg_size(view), 5);
+        assert_eq!(zmq_msg_more(view), 0);
+        assert_eq!(zmq_msg_get(view, constants::ZMQ_SHARED), 0);
+
+        assert_eq!(zmq_msg_close(msg), 0);
+        assert_eq!(zmq_msg_size(view), 0);
+        assert_eq!(zmq_msg_more(view), -1);
+        assert_eq!(zmq_errno(), EFAULT);
+        assert_eq!(zmq_msg_close(msg), -1);
+        assert_eq!(zmq_errno(), EFAULT);
+    }
+
+    #[test]
+    fn test_socket_errors() {
+        let mut buf = [0u8; 8];
+        let data = buf.as_mut_ptr() as *mut c_void;
 
-// Export the C API
-pub use self::zmq_ctx_new as zmq_init;
-pub use self::zmq_ctx_term as zmq_term;
+        // Null sockets.
+        assert!(zmq_socket(ptr::null_mut(), ZMQ_PAIR).is_null());
+        assert_eq!(zmq_errno(), EFAULT);
+        assert_eq!(zmq_bind(ptr::null_mut(), addr("inproc://a").as_ptr()), -1);
+        assert_eq!(zmq_errno(), libc::ENOTSOCK);
+        assert_eq!(zmq_connect(ptr::null_mut(), addr("inproc://a").as_ptr()), -1);
+        assert_eq!(zmq_errno(), libc::ENOTSOCK);
+        assert_eq!(zmq_send(ptr::null_mut(), data, 1, 0), -1);
+        assert_eq!(zmq_errno(), libc::ENOTSOCK);
+        assert_eq!(zmq_recv(ptr::null_mut(), data, 1, 0), -1);
+        assert_eq!(zmq_errno(), libc::ENOTSOCK);
+        assert_eq!(zmq_close(ptr::null_mut()), -1);
+        assert_eq!(zmq_errno(), libc::ENOTSOCK);
+
+        let ctx = zmq_ctx_new();
+        assert!(zmq_socket(ctx, -1).is_null());
+        assert_eq!(zmq_errno(), libc::EINVAL);
+        let s = zmq_socket(ctx, ZMQ_PAIR);
+
+        // Bad endpoints.
+        assert_eq!(zmq_bind(s, ptr::null()), -1);
+        assert_eq!(zmq_errno(), libc::EINVAL);
+        assert_eq!(zmq_bind(s, addr("inproc").as_ptr()), -1);
+        assert_eq!(zmq_errno(), libc::EINVAL);
+        assert_eq!(zmq_connect(s, addr("bogus://a").as_ptr()), -1);
+        assert_eq!(zmq_errno(), libc::EPROTONOSUPPORT);
+
+        // Nothing to receive yet.
+        assert_eq!(zmq_recv(s, data, buf.len(), ZMQ_DONTWAIT), -1);
+        assert_eq!(zmq_errno(), libc::EAGAIN);
+
+        // Once the context shuts down, the socket only accepts zmq_close.
+        assert_eq!(zmq_ctx_shutdown(ctx), 0);
+        assert_eq!(zmq_bind(s, addr("inproc://a").as_ptr()), -1);
+        assert_eq!(zmq_errno(), ZMQ_ETERM);
+        assert_eq!(zmq_send(s, data, 1, 0), -1);
+        assert_eq!(zmq_errno(), ZMQ_ETERM);
+        assert_eq!(zmq_recv(s, data, buf.len(), 0), -1);
+        assert_eq!(zmq_errno(), ZMQ_ETERM);
+        assert_eq!(zmq_close(s), 0);
+        assert_eq!(zmq_ctx_term(ctx), 0);
+    }
+
+    #[test]
+    fn test_connect_peer_errors() {
+        assert_eq!(zmq_connect_peer(ptr::null_mut(), addr("inproc://a").as_ptr()), 0);
+        assert_eq!(zmq_errno(), libc::ENOTSOCK);
+
+        // Only PEER sockets can connect this way.
+        let ctx = zmq_ctx_new();
+        let pair = zmq_socket(ctx, ZMQ_PAIR);
+        assert_eq!(zmq_connect_peer(pair, addr("inproc://peer").as_ptr()), 0);
+        assert_eq!(zmq_errno(), libc::ENOTSUP);
+
+        let peer = zmq_socket(ctx, ZMQ_PEER);
+        assert_eq!(zmq_connect_peer(peer, ptr::null()), 0);
+        assert_eq!(zmq_errno(), libc::EINVAL);
+        assert_eq!(zmq_connect_peer(peer, addr("bogus://a").as_ptr()), 0);
+        assert_eq!(zmq_errno(), libc::EPROTONOSUPPORT);
+        assert_ne!(zmq_connect_peer(peer, addr("inproc://peer").as_ptr()), 0);
+
+        assert_eq!(zmq_ctx_shutdown(ctx), 0);
+        assert_eq!(zmq_connect_peer(peer, addr("inproc://peer").as_ptr()), 0);
+        assert_eq!(zmq_errno(), ZMQ_ETERM);
+        assert_eq!(zmq_close(pair), 0);
+        assert_eq!(zmq_close(peer), 0);
+        assert_eq!(zmq_ctx_term(ctx), 0);
+    }
+}
@@ -1,56 +1,24 @@
-use std::mem;
-
-use crate::{message::Message, pipe::Pipe};
-
-// pub struct Msg {
-//     flags: u32,
-// }
-
-// impl Msg {
-//     pub fn new() -> Self {
-//         Self { flags: 0 }
-//     }
-
-//     pub fn flags(&self) -> u32 {
-//         self.flags
-//     }
-
-//     pub fn has_more(&self) -> bool {
-//         (self.flags & Self::MORE) != 0
-//     }
-
-//     const MORE: u32 = 1;
-// }
-
-// pub struct Pipe {
-//     // Implementation details omitted for brevity
-// }
-
-// impl Pipe {
-//     pub fn write(&mut self, _msg: &mut Msg) -> bool {
-//         // Simplified implementation
-//         true
-//     }
-
-//     pub fn rollback(&mut self) {
-//         // Simplified implementation
-//     }
-
-//     pub fn flush(&mut self) {
-//         // Simplified implementation
-//     }
-
-//     pub fn check_write(&self) -> bool {
-//         // Simplified implementation
-//         true
-//     }
-// }
+use crate::constants::ZMQ_EAGAIN;
+use crate::message::Message;
+use crate::pipe::Pipe;
 
+/// This class manages a set of outbound pipes. On send it load balances
+/// messages fairly among the pipes.
 pub struct LoadBalancer {
+    // List of outbound pipes.
     pipes: Vec<Pipe>,
+
+    // Number of active pipes. All the active pipes are located at the
+    // beginning of the pipes array.
     active: usize,
+
+    // Points to the last pipe that the most recent message was sent to.
     current: usize,
+
+    // True if last we are in the middle of a multipart message.
     more: bool,
+
+    // True if we are dropping current message.
     dropping: bool,
 }
 
@@ -67,14 +35,25 @@ impl LoadBalancer {
 
     pub fn attach(&mut self, pipe: Pipe) {
         self.pipes.push(pipe);
-        self.activated(self.pipes.len() - 1);
+        let last = self.pipes.len() - 1;
+        self.pipes.swap(self.active, last);
+        self.active += 1;
     }
 
-    pub fn pipe_terminated(&mut self, index: usize) {
+    pub fn pipe_terminated(&mut self, pipe: &Pipe) {
+        let index = match self.pipes.iter().position(|p| p == pipe) {
+            Some(index) => index,
+            None => return,
+        };
+
+        // If we are in the middle of multipart message and current pipe
+        // have disconnected, we have to drop the remainder of the message.
         if index == self.current && self.more {
             self.dropping = true;
         }
 
+        // Remove the pipe from the list; adjust number of active pipes
+        // accordingly.
         if index < self.active {
             self.active -= 1;
             self.pipes.swap(index, self.active);
@@ -82,38 +61,50 @@ impl LoadBalancer {
                 self.current = 0;
             }
         }
-        self.pipes.remove(index);
+        self.pipes.swap_remove(self.active.max(index));
     }
 
-    pub fn activated(&mut self, index: usize) {
-        self.pipes.swap(index, self.active);
-        self.active += 1;
+    pub fn activated(&mut self, pipe: &Pipe) {
+        // Move the pipe to the list of active pipes.
+        if let Some(index) = self.pipes.iter().position(|p| p == pipe) {
+            if index >= self.active {
+                self.pipes.swap(index, self.active);
+                self.active += 1;
+            }
+        }
     }
 
     pub fn send(&mut self, msg: &mut Message) -> Result<(), i32> {
-        self.sendpipe(msg, None)
+        self.sendpipe(msg).map(|_| ())
     }
 
-    pub fn sendpipe(&mut self, msg: &mut Message, pipe: Option<&mut Pipe>) -> Result<(), i32> {
+    /// Sends a message, returning the pipe it was written to. Dropped
+    /// parts of a message whose pipe went away yield no pipe.
+    pub fn sendpipe(&mut self, msg: &mut Message) -> Result<Option<Pipe>, i32> {
+        // Drop the message if required. If we are at the end of the message
+        // switch back to non-dropping mode.
         if self.dropping {
             self.more = msg.has_more();
             self.dropping = self.more;
-            return Ok(());
+            *msg = Message::new();
+            return Ok(None);
         }
 
         while self.active > 0 {
             if self.pipes[self.current].write(msg) {
-                if let Some(p) = pipe {
-                    *p = mem::replace(&mut self.pipes[self.current], Pipe::new());
-                }
                 break;
             }
 
+            // If send fails for multi-part msg rollback other
+            // parts sent earlier and return EAGAIN.
+            // Application should handle this as suitable
             if self.more {
                 self.pipes[self.current].rollback();
+                // At this point the pipe is already being deleted, so we
+                // have to drop the rest of the message.
                 self.dropping = msg.has_more();
                 self.more = false;
-                return Err(-2); // EAGAIN equivalent
+                return Err(ZMQ_EAGAIN);
             }
 
             self.active -= 1;
@@ -124,10 +115,15 @@ impl LoadBalancer {
             }
         }
 
+        // If there are no pipes we cannot send the message.
         if self.active == 0 {
-            return Err(-1); // EAGAIN equivalent
+            return Err(ZMQ_EAGAIN);
         }
 
+        let pipe = self.pipes[self.current].clone();
+
+        // If it's final part of the message we can flush it downstream and
+        // continue round-robining (load balance).
         self.more = msg.has_more();
         if !self.more {
             self.pipes[self.current].flush();
@@ -138,19 +134,23 @@ impl LoadBalancer {
             }
         }
 
-        Ok(())
+        Ok(Some(pipe))
     }
 
     pub fn has_out(&mut self) -> bool {
+        // If one part of the message was already written we can definitely
+        // write the rest of the message.
         if self.more {
             return true;
         }
 
         while self.active > 0 {
+            // Check whether a pipe has room for another message.
             if self.pipes[self.current].check_write() {
                 return true;
             }
 
+            // Deactivate the pipe.
             self.active -= 1;
             self.pipes.swap(self.current, self.active);
             if self.current == self.active {
@@ -161,3 +161,9 @@ impl LoadBalancer {
         false
     }
 }
+
+impl Default for LoadBalancer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,154 +1,80 @@
-use std::sync::Mutex;
-
-// Command type placeholder - would need actual implementation
-pub struct Command {
-    // Command implementation details
-}
-
-// YPipe type placeholder - would need actual implementation
-struct YPipe<T> {
-    // YPipe implementation details
-    _phantom: std::marker::PhantomData<T>,
-}
-
-// Signaler type placeholder - would need actual implementation
-struct Signaler {
-    // Signaler implementation details
-}
-
-pub trait IMailbox {
-    fn get_fd(&self) -> i32;
-    fn send(&self, cmd: Command);
-    fn recv(&mut self, timeout: i32) -> Result<Command, std::io::Error>;
-    fn valid(&self) -> bool;
-    #[cfg(target_family = "unix")]
-    fn forked(&mut self);
-}
-
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::command::Command;
+use crate::constants::ZMQ_EAGAIN;
+use crate::i_mailbox::IMailbox;
+
+/// Mailbox of a single-threaded object.
+///
+/// libzmq wakes the owner through a signaler file descriptor; here the
+/// owner blocks on a condition variable instead, which works the same on
+/// every platform.
 pub struct Mailbox {
-    cpipe: YPipe<Command>,
-    signaler: Signaler,
-    sync: Mutex<()>,
-    active: bool,
+    cpipe: Mutex<VecDeque<Command>>,
+    cond_var: Condvar,
 }
 
 impl Mailbox {
     pub fn new() -> Self {
-        let mailbox = Mailbox {
-            cpipe: YPipe {
-                _phantom: std::marker::PhantomData,
-            },
-            signaler: Signaler {},
-            sync: Mutex::new(()),
-            active: false,
-        };
-
-        // Initialize in passive state
-        assert!(!mailbox.cpipe_check_read());
-        mailbox
+        Mailbox {
+            cpipe: Mutex::new(VecDeque::new()),
+            cond_var: Condvar::new(),
+        }
     }
+}
 
-    fn cpipe_check_read(&self) -> bool {
-        // Implementation for checking pipe read state
-        false
+impl Default for Mailbox {
+    fn default() -> Self {
+        Mailbox::new()
     }
 }
 
 impl IMailbox for Mailbox {
-    fn get_fd(&self) -> i32 {
-        self.signaler.get_fd()
-    }
-
     fn send(&self, cmd: Command) {
-        let _guard = self.sync.lock().unwrap();
-        let ok = {
-            self.cpipe.write(cmd, false);
-            self.cpipe.flush()
-        };
-        if !ok {
-            self.signaler.send();
-        }
+        let mut cpipe = self.cpipe.lock().unwrap();
+        cpipe.push_back(cmd);
+        self.cond_var.notify_one();
     }
 
-    fn recv(&mut self, timeout: i32) -> Result<Command, std::io::Error> {
-        if self.active {
-            if let Some(cmd) = self.cpipe.read() {
-                return Ok(cmd);
-            }
-            self.active = false;
-        }
+    fn recv(&self, timeout: i32) -> Result<Command, i32> {
+        let mut cpipe = self.cpipe.lock().unwrap();
 
-        match self.signaler.wait(timeout) {
-            Ok(_) => match self.signaler.recv() {
-                Ok(_) => {
-                    self.active = true;
-                    let cmd = self
-                        .cpipe
-                        .read()
-                        .expect("Command must be available after signal");
-                    Ok(cmd)
-                }
-                Err(e) => Err(e),
-            },
-            Err(e) => Err(e),
+        // Fast path: commands are already waiting.
+        if let Some(cmd) = cpipe.pop_front() {
+            return Ok(cmd);
+        }
+        if timeout == 0 {
+            return Err(ZMQ_EAGAIN);
         }
-    }
-
-    fn valid(&self) -> bool {
-        self.signaler.valid()
-    }
-
-    #[cfg(target_family = "unix")]
-    fn forked(&mut self) {
-        self.signaler.forked();
-    }
-}
-
-// Implementation traits for YPipe
-impl<T> YPipe<T> {
-    fn write(&self, _item: T, _incomplete: bool) {
-        // Implementation for writing to pipe
-    }
-
-    fn flush(&self) -> bool {
-        // Implementation for flushing pipe
-        true
-    }
-
-    fn read(&self) -> Option<T> {
-        // Implementation for reading from pipe
-        None
-    }
-}
-
-// Implementation for Signaler
-impl Signaler {
-    fn get_fd(&self) -> i32 {
-        // Implementation to get file descriptor
-        0
-    }
-
-    fn send(&self) {
-        // Implementation to send signal
-    }
 
-    fn wait(&self, _timeout: i32) -> std::io::Result<()> {
-        // Implementation to wait for signal
-        Ok(())
-    }
+        let deadline = if timeout > 0 {
+            Some(Instant::now() + Duration::from_millis(timeout as u64))
+        } else {
+            None
+        };
 
-    fn recv(&self) -> std::io::Result<()> {
-        // Implementation to receive signal
-        Ok(())
-    }
+        loop {
+            cpipe = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(ZMQ_EAGAIN);
+                    }
+                    self.cond_var.wait_timeout(cpipe, deadline - now).unwrap().0
+                }
+                None => self.cond_var.wait(cpipe).unwrap(),
+            };
 
-    fn valid(&self) -> bool {
-        // Implementation to check validity
-        true
+            if let Some(cmd) = cpipe.pop_front() {
+                return Ok(cmd);
+            }
+        }
     }
 
     #[cfg(target_family = "unix")]
-    fn forked(&mut self) {
-        // Implementation for fork handling
+    fn forked(&self) {
+        // Nothing to do: no file descriptors are shared with the parent.
     }
 }
@@ -1,129 +1,104 @@
+use std::collections::VecDeque;
 use std::sync::{Condvar, Mutex};
-use std::time::Duration;
-use std::vec::Vec;
+use std::time::{Duration, Instant};
 
-// Command type placeholder - implement based on your needs
-#[derive(Clone)]
-pub struct Command {
-    // Command fields go here
-}
-
-// Signaler type placeholder - implement based on your needs
-pub trait Signaler: Send {
-    fn send(&self);
-}
-
-// YPipe implementation placeholder
-struct YPipe<T> {
-    data: Vec<T>,
-}
-
-impl<T> YPipe<T> {
-    fn new() -> Self {
-        YPipe { data: Vec::new() }
-    }
-
-    fn write(&mut self, item: T, _flush: bool) {
-        self.data.push(item);
-    }
+use crate::command::Command;
+use crate::constants::ZMQ_EAGAIN;
+use crate::i_mailbox::IMailbox;
 
-    fn flush(&mut self) -> bool {
-        !self.data.is_empty()
-    }
-
-    fn read(&mut self) -> Option<T> {
-        if self.data.is_empty() {
-            None
-        } else {
-            Some(self.data.remove(0))
-        }
-    }
-
-    fn check_read(&self) -> bool {
-        !self.data.is_empty()
-    }
+struct State {
+    cpipe: VecDeque<Command>,
+    // Bumped on every command sent, so that waiters notice new commands
+    // even when another thread processed them first.
+    sent: u64,
 }
 
+/// Mailbox of a thread-safe socket.
+///
+/// Any number of threads may use the socket, so rather than a single
+/// owner blocking in `recv`, threads wait for commands with the socket
+/// unlocked and then race to process them. Every command wakes all the
+/// waiters, as libzmq's condition variable broadcast does.
 pub struct MailboxSafe {
-    cpipe: Mutex<YPipe<Command>>,
+    state: Mutex<State>,
     cond_var: Condvar,
-    signalers: Mutex<Vec<Box<dyn Signaler>>>,
 }
 
 impl MailboxSafe {
     pub fn new() -> Self {
-        let cpipe = YPipe::new();
-        assert!(!cpipe.check_read());
-
         MailboxSafe {
-            cpipe: Mutex::new(cpipe),
+            state: Mutex::new(State {
+                cpipe: VecDeque::new(),
+                sent: 0,
+            }),
             cond_var: Condvar::new(),
-            signalers: Mutex::new(Vec::new()),
         }
     }
 
-    pub fn send(&self, cmd: Command) {
-        let mut pipe = self.cpipe.lock().unwrap();
-        pipe.write(cmd, false);
-        let needs_signal = pipe.flush();
+    /// Blocks until a command is waiting or, when commands got processed
+    /// by another thread meanwhile, one more was sent. Returns Err(EAGAIN)
+    /// if the timeout (-1 = forever) expired first.
+    pub fn wait(&self, timeout: i32) -> Result<(), i32> {
+        let mut state = self.state.lock().unwrap();
+        let sent = state.sent;
 
-        if !needs_signal {
-            self.cond_var.notify_all();
-            let signalers = self.signalers.lock().unwrap();
-            for signaler in signalers.iter() {
-                signaler.send();
-            }
-        }
-    }
-
-    pub fn recv(&self, timeout: Option<Duration>) -> Result<Command, std::io::Error> {
-        let mut pipe = self.cpipe.lock().unwrap();
-
-        if let Some(cmd) = pipe.read() {
-            return Ok(cmd);
-        }
+        let deadline = if timeout > 0 {
+            Some(Instant::now() + Duration::from_millis(timeout as u64))
+        } else {
+            None
+        };
 
-        match timeout {
-            Some(timeout_duration) => {
-                let result = self.cond_var.wait_timeout(pipe, timeout_duration).unwrap();
-                pipe = result.0;
-                if result.1.timed_out() {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::WouldBlock,
-                        "timeout",
-                    ));
-                }
-            }
-            None => {
-                pipe = self.cond_var.wait(pipe).unwrap();
+        while state.cpipe.is_empty() && state.sent == sent {
+            if timeout == 0 {
+                return Err(ZMQ_EAGAIN);
             }
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(ZMQ_EAGAIN);
+                    }
+                    self.cond_var.wait_timeout(state, deadline - now).unwrap().0
+                }
+                None => self.cond_var.wait(state).unwrap(),
+            };
         }
 
-        pipe.read().ok_or_else(|| {
-            std::io::Error::new(std::io::ErrorKind::WouldBlock, "no command available")
-        })
+        Ok(())
     }
+}
 
-    pub fn add_signaler(&self, signaler: Box<dyn Signaler>) {
-        let mut signalers = self.signalers.lock().unwrap();
-        signalers.push(signaler);
+impl Default for MailboxSafe {
+    fn default() -> Self {
+        MailboxSafe::new()
     }
+}
 
-    pub fn remove_signaler(&self, signaler: *const dyn Signaler) {
-        let mut signalers = self.signalers.lock().unwrap();
-        signalers.retain(|s| std::ptr::addr_of!(**s) != signaler);
+impl IMailbox for MailboxSafe {
+    fn send(&self, cmd: Command) {
+        let mut state = self.state.lock().unwrap();
+        state.cpipe.push_back(cmd);
+        state.sent = state.sent.wrapping_add(1);
+        self.cond_var.notify_all();
     }
 
-    pub fn clear_signalers(&self) {
-        let mut signalers = self.signalers.lock().unwrap();
-        signalers.clear();
+    fn recv(&self, timeout: i32) -> Result<Command, i32> {
+        if let Some(cmd) = self.state.lock().unwrap().cpipe.pop_front() {
+            return Ok(cmd);
+        }
+        self.wait(timeout)?;
+
+        // Another thread may have taken the command we were woken for.
+        self.state
+            .lock()
+            .unwrap()
+            .cpipe
+            .pop_front()
+            .ok_or(ZMQ_EAGAIN)
     }
 
     #[cfg(target_family = "unix")]
-    pub fn forked(&self) {
-        // Implementation for fork support if needed
+    fn forked(&self) {
+        // Nothing to do: no file descriptors are shared with the parent.
     }
 }
-
-unsafe impl Send for MailboxSafe {}
-unsafe impl Sync for MailboxSafe {}
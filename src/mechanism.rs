@@ -1,5 +1,17 @@
 use std::collections::HashMap;
 
+use crate::constants::{
+    ZMQ_DEALER, ZMQ_PAIR, ZMQ_PUB, ZMQ_PULL, ZMQ_PUSH, ZMQ_REP, ZMQ_REQ, ZMQ_ROUTER, ZMQ_STREAM,
+    ZMQ_SUB, ZMQ_XPUB, ZMQ_XSUB,
+};
+use crate::message::{Message, MsgFlags};
+use crate::options::Options;
+use crate::session_base::SessionBase;
+use crate::zmq_draft::{
+    ZMQ_CHANNEL, ZMQ_CLIENT, ZMQ_DGRAM, ZMQ_DISH, ZMQ_GATHER, ZMQ_PEER, ZMQ_RADIO, ZMQ_SCATTER,
+    ZMQ_SERVER,
+};
+
 // Socket type constants
 const SOCKET_TYPE_PAIR: &str = "PAIR";
 const SOCKET_TYPE_PUB: &str = "PUB";
@@ -13,194 +25,317 @@ const SOCKET_TYPE_PUSH: &str = "PUSH";
 const SOCKET_TYPE_XPUB: &str = "XPUB";
 const SOCKET_TYPE_XSUB: &str = "XSUB";
 const SOCKET_TYPE_STREAM: &str = "STREAM";
-
-#[cfg(feature = "draft")]
 const SOCKET_TYPE_SERVER: &str = "SERVER";
-#[cfg(feature = "draft")]
 const SOCKET_TYPE_CLIENT: &str = "CLIENT";
-#[cfg(feature = "draft")]
 const SOCKET_TYPE_RADIO: &str = "RADIO";
-#[cfg(feature = "draft")]
 const SOCKET_TYPE_DISH: &str = "DISH";
-#[cfg(feature = "draft")]
 const SOCKET_TYPE_GATHER: &str = "GATHER";
-#[cfg(feature = "draft")]
 const SOCKET_TYPE_SCATTER: &str = "SCATTER";
-#[cfg(feature = "draft")]
 const SOCKET_TYPE_DGRAM: &str = "DGRAM";
-#[cfg(feature = "draft")]
 const SOCKET_TYPE_PEER: &str = "PEER";
-#[cfg(feature = "draft")]
 const SOCKET_TYPE_CHANNEL: &str = "CHANNEL";
 
-const ZMTP_PROPERTY_SOCKET_TYPE: &str = "Socket-Type";
-const ZMTP_PROPERTY_IDENTITY: &str = "Identity";
+pub const ZMTP_PROPERTY_SOCKET_TYPE: &str = "Socket-Type";
+pub const ZMTP_PROPERTY_IDENTITY: &str = "Identity";
 
-#[derive(Debug, PartialEq)]
+// Length of the name and value prefixes of a property on the wire.
+const NAME_LEN_SIZE: usize = 1;
+const VALUE_LEN_SIZE: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Status {
     Handshaking,
     Ready,
     Error,
 }
 
-#[derive(Clone)]
-pub struct Options {
-    socket_type: i32,
+/// State shared by all security mechanisms: the peer's routing id and
+/// user id, and the properties it announced during the handshake.
+pub struct Mechanism {
+    pub options: Options,
+    zmtp_properties: HashMap<String, String>,
+    zap_properties: HashMap<String, String>,
     routing_id: Vec<u8>,
-    routing_id_size: usize,
-    recv_routing_id: bool,
-    app_metadata: HashMap<String, String>,
+    user_id: Vec<u8>,
 }
 
-pub struct Blob {
-    data: Vec<u8>,
-}
+/// Abstract interface to be implemented by the various security
+/// mechanisms, driven by the engine during and after the handshake.
+pub trait MechanismOps: Send {
+    fn base(&self) -> &Mechanism;
+    fn base_mut(&mut self) -> &mut Mechanism;
 
-impl Blob {
-    fn new() -> Self {
-        Self { data: Vec::new() }
-    }
+    /// Prepare next handshake command that is to be sent to the peer.
+    /// EAGAIN means there is nothing to send at the moment.
+    fn next_handshake_command(
+        &mut self,
+        session: &mut SessionBase,
+        msg: &mut Message,
+    ) -> Result<(), i32>;
+
+    /// Process the handshake command received from the peer.
+    fn process_handshake_command(
+        &mut self,
+        session: &mut SessionBase,
+        msg: &mut Message,
+    ) -> Result<(), i32>;
 
-    fn set(&mut self, data: &[u8]) {
-        self.data = data.to_vec();
+    fn encode(&mut self, _msg: &mut Message) -> Result<(), i32> {
+        Ok(())
     }
 
-    fn size(&self) -> usize {
-        self.data.len()
+    fn decode(&mut self, _msg: &mut Message) -> Result<(), i32> {
+        Ok(())
     }
 
-    fn data(&self) -> &[u8] {
-        &self.data
+    /// Notifies mechanism about availability of ZAP message.
+    fn zap_msg_available(&mut self, _session: &mut SessionBase) -> Result<(), i32> {
+        Ok(())
     }
-}
 
-pub struct Mechanism {
-    options: Options,
-    zmtp_properties: HashMap<String, String>,
-    zap_properties: HashMap<String, String>,
-    routing_id: Blob,
-    user_id: Blob,
+    /// Returns the status of this mechanism.
+    fn status(&self) -> Status;
 }
 
 impl Mechanism {
-    pub fn new(options: Options) -> Self {
-        Self {
-            options,
+    pub fn new(options: &Options) -> Self {
+        Mechanism {
+            options: options.clone(),
             zmtp_properties: HashMap::new(),
             zap_properties: HashMap::new(),
-            routing_id: Blob::new(),
-            user_id: Blob::new(),
+            routing_id: Vec::new(),
+            user_id: Vec::new(),
         }
     }
 
     pub fn set_peer_routing_id(&mut self, id: &[u8]) {
-        self.routing_id.set(id);
+        self.routing_id = id.to_vec();
+    }
+
+    /// Returns the routing id announced by the peer, as the message the
+    /// engine pushes to the session first.
+    pub fn peer_routing_id(&self) -> Message {
+        let mut msg = Message::with_data(&self.routing_id).unwrap();
+        msg.set_flags(MsgFlags::RoutingId);
+        msg
     }
 
     pub fn set_user_id(&mut self, user_id: &[u8]) {
-        self.user_id.set(user_id);
+        self.user_id = user_id.to_vec();
         self.zap_properties.insert(
             String::from("User-Id"),
             String::from_utf8_lossy(user_id).into_owned(),
         );
     }
 
-    pub fn get_user_id(&self) -> &Blob {
+    pub fn get_user_id(&self) -> &[u8] {
         &self.user_id
     }
 
-    fn socket_type_string(socket_type: i32) -> &'static str {
-        match socket_type {
-            0 => SOCKET_TYPE_PAIR,
-            1 => SOCKET_TYPE_PUB,
-            2 => SOCKET_TYPE_SUB,
-            3 => SOCKET_TYPE_REQ,
-            4 => SOCKET_TYPE_REP,
-            5 => SOCKET_TYPE_DEALER,
-            6 => SOCKET_TYPE_ROUTER,
-            7 => SOCKET_TYPE_PULL,
-            8 => SOCKET_TYPE_PUSH,
-            9 => SOCKET_TYPE_XPUB,
-            10 => SOCKET_TYPE_XSUB,
-            11 => SOCKET_TYPE_STREAM,
-            #[cfg(feature = "draft")]
-            12 => SOCKET_TYPE_SERVER,
-            #[cfg(feature = "draft")]
-            13 => SOCKET_TYPE_CLIENT,
-            #[cfg(feature = "draft")]
-            14 => SOCKET_TYPE_RADIO,
-            #[cfg(feature = "draft")]
-            15 => SOCKET_TYPE_DISH,
-            #[cfg(feature = "draft")]
-            16 => SOCKET_TYPE_GATHER,
-            #[cfg(feature = "draft")]
-            17 => SOCKET_TYPE_SCATTER,
-            #[cfg(feature = "draft")]
-            18 => SOCKET_TYPE_DGRAM,
-            #[cfg(feature = "draft")]
-            19 => SOCKET_TYPE_PEER,
-            #[cfg(feature = "draft")]
-            20 => SOCKET_TYPE_CHANNEL,
-            _ => panic!("Invalid socket type"),
+    pub fn get_zmtp_properties(&self) -> &HashMap<String, String> {
+        &self.zmtp_properties
+    }
+
+    pub fn get_zap_properties(&self) -> &HashMap<String, String> {
+        &self.zap_properties
+    }
+
+    /// Appends the Socket-Type, Identity and application metadata
+    /// properties, as announced in READY and INITIATE commands.
+    pub fn add_basic_properties(&self, buf: &mut Vec<u8>) {
+        // Add socket type property.
+        let socket_type = socket_type_string(self.options.socket_type);
+        add_property(buf, ZMTP_PROPERTY_SOCKET_TYPE, socket_type.as_bytes());
+
+        // Add identity (aka routing id) property.
+        if matches!(
+            self.options.socket_type,
+            ZMQ_REQ | ZMQ_DEALER | ZMQ_ROUTER
+        ) {
+            let size = self.options.routing_id_size as usize;
+            add_property(buf, ZMTP_PROPERTY_IDENTITY, &self.options.routing_id[..size]);
+        }
+
+        for (name, value) in &self.options.app_metadata {
+            add_property(buf, name, value.as_bytes());
         }
     }
 
-    fn check_socket_type(&self, peer_type: &str) -> bool {
+    /// Builds a command message made of `prefix` followed by the basic
+    /// properties.
+    pub fn make_command_with_basic_properties(
+        &self,
+        msg: &mut Message,
+        prefix: &[u8],
+    ) -> Result<(), i32> {
+        let mut buf = prefix.to_vec();
+        self.add_basic_properties(&mut buf);
+
+        *msg = Message::with_data(&buf).map_err(|_| libc::ENOMEM)?;
+        msg.set_flags(MsgFlags::Command);
+        Ok(())
+    }
+
+    /// Parses a metadata. Metadata consists of a list of properties
+    /// consisting of name and value as size-specified strings. Returns
+    /// EPROTO if the metadata is malformed and EINVAL if the peer's socket
+    /// type isn't compatible with ours.
+    pub fn parse_metadata(&mut self, mut data: &[u8], zap_flag: bool) -> Result<(), i32> {
+        while !data.is_empty() {
+            // Get the property name.
+            let name_length = data[0] as usize;
+            data = &data[NAME_LEN_SIZE..];
+            if data.len() < name_length {
+                return Err(libc::EPROTO);
+            }
+            let name = String::from_utf8_lossy(&data[..name_length]).into_owned();
+            data = &data[name_length..];
+
+            // Get the property value.
+            if data.len() < VALUE_LEN_SIZE {
+                return Err(libc::EPROTO);
+            }
+            let mut value_length = [0u8; VALUE_LEN_SIZE];
+            value_length.copy_from_slice(&data[..VALUE_LEN_SIZE]);
+            let value_length = u32::from_be_bytes(value_length) as usize;
+            data = &data[VALUE_LEN_SIZE..];
+            if data.len() < value_length {
+                return Err(libc::EPROTO);
+            }
+            let value = &data[..value_length];
+            data = &data[value_length..];
+
+            if name == ZMTP_PROPERTY_IDENTITY && self.options.recv_routing_id {
+                self.set_peer_routing_id(value);
+            } else if name == ZMTP_PROPERTY_SOCKET_TYPE {
+                let peer_type = String::from_utf8_lossy(value);
+                if !self.check_socket_type(&peer_type) {
+                    return Err(libc::EINVAL);
+                }
+            }
+
+            let value = String::from_utf8_lossy(value).into_owned();
+            if zap_flag {
+                self.zap_properties.insert(name, value);
+            } else {
+                self.zmtp_properties.insert(name, value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true iff socket associated with the mechanism is compatible
+    /// with a given socket type 'type_'.
+    pub fn check_socket_type(&self, peer_type: &str) -> bool {
         match self.options.socket_type {
-            3 /* REQ */ => peer_type == SOCKET_TYPE_REP || peer_type == SOCKET_TYPE_ROUTER,
-            4 /* REP */ => peer_type == SOCKET_TYPE_REQ || peer_type == SOCKET_TYPE_DEALER,
-            5 /* DEALER */ => {
+            ZMQ_REQ => peer_type == SOCKET_TYPE_REP || peer_type == SOCKET_TYPE_ROUTER,
+            ZMQ_REP => peer_type == SOCKET_TYPE_REQ || peer_type == SOCKET_TYPE_DEALER,
+            ZMQ_DEALER => {
                 peer_type == SOCKET_TYPE_REP
                     || peer_type == SOCKET_TYPE_DEALER
                     || peer_type == SOCKET_TYPE_ROUTER
             }
-            6 /* ROUTER */ => {
+            ZMQ_ROUTER => {
                 peer_type == SOCKET_TYPE_REQ
                     || peer_type == SOCKET_TYPE_DEALER
                     || peer_type == SOCKET_TYPE_ROUTER
             }
-            8 /* PUSH */ => peer_type == SOCKET_TYPE_PULL,
-            7 /* PULL */ => peer_type == SOCKET_TYPE_PUSH,
-            1 /* PUB */ => peer_type == SOCKET_TYPE_SUB || peer_type == SOCKET_TYPE_XSUB,
-            2 /* SUB */ => peer_type == SOCKET_TYPE_PUB || peer_type == SOCKET_TYPE_XPUB,
-            9 /* XPUB */ => peer_type == SOCKET_TYPE_SUB || peer_type == SOCKET_TYPE_XSUB,
-            10 /* XSUB */ => peer_type == SOCKET_TYPE_PUB || peer_type == SOCKET_TYPE_XPUB,
-            0 /* PAIR */ => peer_type == SOCKET_TYPE_PAIR,
-            #[cfg(feature = "draft")]
-            12 /* SERVER */ => peer_type == SOCKET_TYPE_CLIENT,
-            #[cfg(feature = "draft")]
-            13 /* CLIENT */ => peer_type == SOCKET_TYPE_SERVER,
-            #[cfg(feature = "draft")]
-            14 /* RADIO */ => peer_type == SOCKET_TYPE_DISH,
-            #[cfg(feature = "draft")]
-            15 /* DISH */ => peer_type == SOCKET_TYPE_RADIO,
-            #[cfg(feature = "draft")]
-            16 /* GATHER */ => peer_type == SOCKET_TYPE_SCATTER,
-            #[cfg(feature = "draft")]
-            17 /* SCATTER */ => peer_type == SOCKET_TYPE_GATHER,
-            #[cfg(feature = "draft")]
-            18 /* DGRAM */ => peer_type == SOCKET_TYPE_DGRAM,
-            #[cfg(feature = "draft")]
-            19 /* PEER */ => peer_type == SOCKET_TYPE_PEER,
-            #[cfg(feature = "draft")]
-            20 /* CHANNEL */ => peer_type == SOCKET_TYPE_CHANNEL,
+            ZMQ_PUSH => peer_type == SOCKET_TYPE_PULL,
+            ZMQ_PULL => peer_type == SOCKET_TYPE_PUSH,
+            ZMQ_PUB => peer_type == SOCKET_TYPE_SUB || peer_type == SOCKET_TYPE_XSUB,
+            ZMQ_SUB => peer_type == SOCKET_TYPE_PUB || peer_type == SOCKET_TYPE_XPUB,
+            ZMQ_XPUB => peer_type == SOCKET_TYPE_SUB || peer_type == SOCKET_TYPE_XSUB,
+            ZMQ_XSUB => peer_type == SOCKET_TYPE_PUB || peer_type == SOCKET_TYPE_XPUB,
+            ZMQ_PAIR => peer_type == SOCKET_TYPE_PAIR,
+            ZMQ_SERVER => peer_type == SOCKET_TYPE_CLIENT,
+            ZMQ_CLIENT => peer_type == SOCKET_TYPE_SERVER,
+            ZMQ_RADIO => peer_type == SOCKET_TYPE_DISH,
+            ZMQ_DISH => peer_type == SOCKET_TYPE_RADIO,
+            ZMQ_GATHER => peer_type == SOCKET_TYPE_SCATTER,
+            ZMQ_SCATTER => peer_type == SOCKET_TYPE_GATHER,
+            ZMQ_DGRAM => peer_type == SOCKET_TYPE_DGRAM,
+            ZMQ_PEER => peer_type == SOCKET_TYPE_PEER,
+            ZMQ_CHANNEL => peer_type == SOCKET_TYPE_CHANNEL,
             _ => false,
         }
     }
 }
 
-// Trait for mechanism implementations
-pub trait MechanismOps {
-    fn next_handshake_command(&mut self) -> Vec<u8>;
-    fn process_handshake_command(&mut self, msg: &[u8]) -> i32;
-    fn encode(&mut self, msg: &mut Vec<u8>) -> i32 {
-        0
+pub fn socket_type_string(socket_type: i32) -> &'static str {
+    match socket_type {
+        ZMQ_PAIR => SOCKET_TYPE_PAIR,
+        ZMQ_PUB => SOCKET_TYPE_PUB,
+        ZMQ_SUB => SOCKET_TYPE_SUB,
+        ZMQ_REQ => SOCKET_TYPE_REQ,
+        ZMQ_REP => SOCKET_TYPE_REP,
+        ZMQ_DEALER => SOCKET_TYPE_DEALER,
+        ZMQ_ROUTER => SOCKET_TYPE_ROUTER,
+        ZMQ_PULL => SOCKET_TYPE_PULL,
+        ZMQ_PUSH => SOCKET_TYPE_PUSH,
+        ZMQ_XPUB => SOCKET_TYPE_XPUB,
+        ZMQ_XSUB => SOCKET_TYPE_XSUB,
+        ZMQ_STREAM => SOCKET_TYPE_STREAM,
+        ZMQ_SERVER => SOCKET_TYPE_SERVER,
+        ZMQ_CLIENT => SOCKET_TYPE_CLIENT,
+        ZMQ_RADIO => SOCKET_TYPE_RADIO,
+        ZMQ_DISH => SOCKET_TYPE_DISH,
+        ZMQ_GATHER => SOCKET_TYPE_GATHER,
+        ZMQ_SCATTER => SOCKET_TYPE_SCATTER,
+        ZMQ_DGRAM => SOCKET_TYPE_DGRAM,
+        ZMQ_PEER => SOCKET_TYPE_PEER,
+        ZMQ_CHANNEL => SOCKET_TYPE_CHANNEL,
+        _ => panic!("Invalid socket type"),
     }
-    fn decode(&mut self, msg: &mut Vec<u8>) -> i32 {
-        0
+}
+
+/// Appends a property to a command body: the name prefixed with its
+/// length as one byte, the value prefixed with its length as four bytes
+/// in network byte order.
+pub fn add_property(buf: &mut Vec<u8>, name: &str, value: &[u8]) {
+    assert!(name.len() <= u8::MAX as usize);
+    buf.push(name.len() as u8);
+    buf.extend_from_slice(name.as_bytes());
+    buf.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    buf.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mechanism(socket_type: i32) -> Mechanism {
+        let mut options = Options::new();
+        options.socket_type = socket_type;
+        Mechanism::new(&options)
+    }
+
+    #[test]
+    fn test_basic_properties_round_trip() {
+        let mut dealer = mechanism(ZMQ_DEALER);
+        dealer.options.routing_id[..3].copy_from_slice(b"abc");
+        dealer.options.routing_id_size = 3;
+        let mut buf = Vec::new();
+        dealer.add_basic_properties(&mut buf);
+
+        let mut router = mechanism(ZMQ_ROUTER);
+        router.options.recv_routing_id = true;
+        router.parse_metadata(&buf, false).unwrap();
+        assert_eq!(router.peer_routing_id().data(), b"abc");
+        assert_eq!(
+            router.get_zmtp_properties().get(ZMTP_PROPERTY_SOCKET_TYPE),
+            Some(&String::from("DEALER"))
+        );
     }
-    fn zap_msg_available(&mut self) -> i32 {
-        0
+
+    #[test]
+    fn test_parse_metadata_rejects_bad_input() {
+        let mut push = mechanism(ZMQ_PUSH);
+        let mut buf = Vec::new();
+        add_property(&mut buf, ZMTP_PROPERTY_SOCKET_TYPE, b"PUB");
+        assert_eq!(push.parse_metadata(&buf, false), Err(libc::EINVAL));
+
+        // Value length runs past the end of the command.
+        let buf = [4, b'N', b'a', b'm', b'e', 0, 0, 0, 9, b'x'];
+        assert_eq!(push.parse_metadata(&buf, false), Err(libc::EPROTO));
     }
-    fn status(&self) -> Status;
 }
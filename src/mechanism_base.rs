@@ -1,64 +1,34 @@
-use std::error::Error;
+//! Helpers shared by the security mechanisms, the counterpart of libzmq's
+//! mechanism_base_t.
 
-// Placeholder types that would need to be properly defined elsewhere
-pub struct SessionBase;
-pub struct Options {
-    pub zap_domain: String,
-}
-pub struct Message {
-    data: Vec<u8>,
-}
-pub struct Socket;
+use crate::mechanism::Mechanism;
+use crate::message::Message;
 
 const STATUS_CODE_LEN: usize = 3;
-const ZERO_DIGIT: char = '0';
-const FACTOR: i32 = 100;
-
-pub trait Mechanism {
-    // Add mechanism trait methods here
-}
-
-pub struct MechanismBase<'a> {
-    session: &'a SessionBase,
-    options: Options,
-}
-
-impl<'a> MechanismBase<'a> {
-    pub fn new(session: &'a SessionBase, options: Options) -> Self {
-        MechanismBase { session, options }
-    }
 
-    pub fn check_basic_command_structure(&self, msg: &Message) -> Result<(), Box<dyn Error>> {
-        if msg.data.is_empty() || msg.data.len() <= msg.data[0] as usize {
-            // TODO: Implement proper event handling
-            return Err("EPROTO: Malformed command".into());
-        }
-        Ok(())
+/// Checks that a command starts with a name length byte followed by at
+/// least that many bytes.
+pub fn check_basic_command_structure(msg: &Message) -> Result<(), i32> {
+    if msg.size() <= 1 || msg.size() <= msg.data()[0] as usize {
+        return Err(libc::EPROTO);
     }
+    Ok(())
+}
 
-    pub fn handle_error_reason(&self, error_reason: &[u8]) {
-        if error_reason.len() == STATUS_CODE_LEN {
-            let chars: Vec<char> = error_reason.iter().map(|&b| b as char).collect();
-
-            if chars[1] == ZERO_DIGIT
-                && chars[2] == ZERO_DIGIT
-                && chars[0] >= '3'
-                && chars[0] <= '5'
-            {
-                let status_code = (chars[0] as i32 - ZERO_DIGIT as i32) * FACTOR;
-                // TODO: Implement proper event handling for authentication failure
-                println!("Authentication failed with status code: {}", status_code);
-            } else {
-                // TODO: Handle ZAP protocol violation
-            }
+/// Returns the status code carried by an ERROR command's reason, if it is
+/// one a ZAP handler may send (300, 400 or 500).
+pub fn error_reason_status_code(error_reason: &[u8]) -> Option<i32> {
+    match error_reason {
+        [digit @ b'3'..=b'5', b'0', b'0'] if error_reason.len() == STATUS_CODE_LEN => {
+            Some(i32::from(digit - b'0') * 100)
         }
+        _ => None,
     }
+}
 
+impl Mechanism {
+    /// Returns true if a ZAP handler has to authenticate the peer.
     pub fn zap_required(&self) -> bool {
         !self.options.zap_domain.is_empty()
     }
 }
-
-impl<'a> Mechanism for MechanismBase<'a> {
-    // Implement mechanism trait methods
-}
@@ -1,78 +1,68 @@
-use std::error::Error;
+use std::alloc::{handle_alloc_error, Layout};
+use std::ffi::CStr;
 use std::mem;
+use std::os::raw::c_void;
 use std::ptr;
 use std::slice;
-use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::metadata::Metadata;
 
 const CMD_TYPE_MASK: u8 = 0x1c;
-const ZMQ_GROUP_MAX_LENGTH: usize = 255; // From zmq.h
+pub(crate) const ZMQ_GROUP_MAX_LENGTH: usize = 255; // From zmq.h
 const MSG_T_SIZE: usize = 64;
 const MAX_VSM_SIZE: usize =
     MSG_T_SIZE - (mem::size_of::<*mut Metadata>() + 3 + 16 + mem::size_of::<u32>());
 
 // Command names
-const CANCEL_CMD_NAME: &[u8] = b"\x06CANCEL";
-const SUB_CMD_NAME: &[u8] = b"\x09SUBSCRIBE";
-const PING_CMD_NAME_SIZE: usize = 5;
-const CANCEL_CMD_NAME_SIZE: usize = 7;
-const SUB_CMD_NAME_SIZE: usize = 10;
-
-#[derive(Debug)]
-pub struct Metadata {
-    ref_count: AtomicI32,
-    // Add other metadata fields as needed
-}
-
-impl Metadata {
-    fn new() -> Self {
-        Metadata {
-            ref_count: AtomicI32::new(1),
-        }
-    }
+pub(crate) const CANCEL_CMD_NAME: &[u8] = b"\x06CANCEL";
+pub(crate) const SUB_CMD_NAME: &[u8] = b"\x09SUBSCRIBE";
+pub(crate) const PING_CMD_NAME_SIZE: usize = 5;
+pub(crate) const CANCEL_CMD_NAME_SIZE: usize = 7;
+pub(crate) const SUB_CMD_NAME_SIZE: usize = 10;
 
-    fn add_ref(&self) {
-        self.ref_count.fetch_add(1, Ordering::SeqCst);
-    }
-
-    fn drop_ref(&self) -> bool {
-        self.ref_count.fetch_sub(1, Ordering::SeqCst) == 1
-    }
-}
-
-type MsgFreeFn = unsafe extern "C" fn(*mut u8, *mut u8);
+pub type MsgFreeFn = unsafe extern "C" fn(*mut c_void, *mut c_void);
 
+// Buffer shared by all copies of a large message. Released when the last
+// copy goes away.
 #[derive(Debug)]
 struct Content {
     data: *mut u8,
     size: usize,
     ffn: Option<MsgFreeFn>,
-    hint: *mut u8,
-    ref_count: AtomicUsize,
+    hint: *mut c_void,
 }
 
 impl Drop for Content {
     fn drop(&mut self) {
-        if let Some(ffn) = self.ffn {
-            unsafe { ffn(self.data as *mut u8, self.hint) };
+        match self.ffn {
+            // Buffer supplied by the user through zmq_msg_init_data.
+            Some(ffn) => unsafe { ffn(self.data as *mut c_void, self.hint) },
+            // Buffer allocated by the library itself.
+            None => unsafe { libc::free(self.data as *mut c_void) },
         }
     }
 }
 
-#[derive(Debug)]
-enum GroupStorage {
+// The buffer is never written through shared copies, only its owner thread
+// fills it in before the first copy is made.
+unsafe impl Send for Content {}
+unsafe impl Sync for Content {}
+
+#[derive(Debug, Clone)]
+pub(crate) enum GroupStorage {
     Short([u8; 15]),
-    Long(Box<LongGroup>),
+    Long(Arc<LongGroup>),
 }
 
 #[derive(Debug)]
-struct LongGroup {
+pub(crate) struct LongGroup {
     group: [u8; ZMQ_GROUP_MAX_LENGTH + 1],
-    ref_count: AtomicUsize,
 }
 
 #[derive(Debug)]
 pub struct Message {
-    metadata: Option<Box<Metadata>>,
+    metadata: Option<Arc<Metadata>>,
     flags: u8,
     routing_id: u32,
     pub(crate) group: GroupStorage,
@@ -82,14 +72,18 @@ pub struct Message {
 #[derive(Debug)]
 enum MessageContent {
     Vsm { data: [u8; MAX_VSM_SIZE], size: u8 },
-    Lmsg { content: Box<Content> },
+    Lmsg { content: Arc<Content> },
     Cmsg { data: *mut u8, size: usize },
-    Zclmsg { content: Box<Content> },
+    Zclmsg { content: Arc<Content> },
     Delimiter,
     Join,
     Leave,
 }
 
+// Constant messages point at caller-owned memory that outlives the message
+// by contract (zmq_send_const), everything else is owned.
+unsafe impl Send for Message {}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u8)]
 pub enum MsgFlags {
@@ -128,25 +122,22 @@ impl Message {
             };
             Ok(msg)
         } else {
-            let content = Box::new(Content {
-                data: unsafe { libc::malloc(size) as *mut u8 },
-                size,
-                ffn: None,
-                hint: ptr::null_mut(),
-                ref_count: AtomicUsize::new(1),
-            });
-
-            if content.data.is_null() {
+            // malloc(0) may legally return NULL, so always ask for a byte.
+            let data = unsafe { libc::malloc(size.max(1)) as *mut u8 };
+            if data.is_null() {
                 return Err("Memory allocation failed");
             }
 
-            Ok(Message {
-                metadata: None,
-                flags: 0,
-                routing_id: 0,
-                group: GroupStorage::Short([0; 15]),
-                content: MessageContent::Lmsg { content },
-            })
+            let mut msg = Self::new();
+            msg.content = MessageContent::Lmsg {
+                content: Arc::new(Content {
+                    data,
+                    size,
+                    ffn: None,
+                    hint: ptr::null_mut(),
+                }),
+            };
+            Ok(msg)
         }
     }
 
@@ -156,36 +147,130 @@ impl Message {
         Ok(msg)
     }
 
+    /// Takes ownership of a caller-supplied buffer. `ffn` is invoked with
+    /// `hint` once the last copy of the message is released; without it the
+    /// buffer is treated as constant and never freed.
+    ///
+    /// # Safety
+    /// `data` must be valid for `size` bytes until `ffn` is called.
+    pub unsafe fn with_ffn(
+        data: *mut u8,
+        size: usize,
+        ffn: Option<MsgFreeFn>,
+        hint: *mut c_void,
+    ) -> Result<Self, &'static str> {
+        // If data is NULL and size is not 0, a segfault
+        // would occur once the data is accessed
+        if data.is_null() && size != 0 {
+            return Err("Invalid buffer");
+        }
+
+        let mut msg = Self::new();
+        msg.content = match ffn {
+            None => MessageContent::Cmsg { data, size },
+            Some(ffn) => MessageContent::Lmsg {
+                content: Arc::new(Content {
+                    data,
+                    size,
+                    ffn: Some(ffn),
+                    hint,
+                }),
+            },
+        };
+        Ok(msg)
+    }
+
+    /// Wraps constant memory without copying it.
+    ///
+    /// # Safety
+    /// `data` must stay valid and unchanged for as long as the message or
+    /// any copy of it lives.
+    pub unsafe fn with_const(data: *const u8, size: usize) -> Self {
+        let mut msg = Self::new();
+        msg.content = MessageContent::Cmsg {
+            data: data as *mut u8,
+            size,
+        };
+        msg
+    }
+
+    pub fn delimiter() -> Self {
+        let mut msg = Self::new();
+        msg.content = MessageContent::Delimiter;
+        msg
+    }
+
     pub fn data(&self) -> &[u8] {
         match &self.content {
             MessageContent::Vsm { data, size } => &data[..*size as usize],
-            MessageContent::Lmsg { content } => unsafe {
-                slice::from_raw_parts(content.data, content.size)
-            },
-            MessageContent::Cmsg { data, size } => unsafe { slice::from_raw_parts(*data, *size) },
-            MessageContent::Zclmsg { content } => unsafe {
-                slice::from_raw_parts(content.data, content.size)
-            },
+            MessageContent::Lmsg { content } | MessageContent::Zclmsg { content } => {
+                if content.size == 0 {
+                    &[]
+                } else {
+                    unsafe { slice::from_raw_parts(content.data, content.size) }
+                }
+            }
+            MessageContent::Cmsg { data, size } => {
+                if *size == 0 {
+                    &[]
+                } else {
+                    unsafe { slice::from_raw_parts(*data, *size) }
+                }
+            }
             _ => &[],
         }
     }
 
+    /// The payload, for writing. Content shared with copies of the message,
+    /// or constant memory from zmq_send_const, is copied into a buffer of
+    /// this message's own first.
     pub fn data_mut(&mut self) -> &mut [u8] {
+        let private = match &mut self.content {
+            MessageContent::Lmsg { content } | MessageContent::Zclmsg { content } => {
+                Arc::get_mut(content).is_some()
+            }
+            MessageContent::Cmsg { .. } => false,
+            _ => true,
+        };
+        if !private {
+            self.unshare();
+        }
+
         match &mut self.content {
             MessageContent::Vsm { data, size } => &mut data[..*size as usize],
-            MessageContent::Lmsg { content } => unsafe {
-                slice::from_raw_parts_mut(content.data, content.size)
-            },
-            MessageContent::Cmsg { data, size } => unsafe {
-                slice::from_raw_parts_mut(*data, *size)
-            },
-            MessageContent::Zclmsg { content } => unsafe {
-                slice::from_raw_parts_mut(content.data, content.size)
-            },
+            MessageContent::Lmsg { content } | MessageContent::Zclmsg { content } => {
+                if content.size == 0 {
+                    &mut []
+                } else {
+                    unsafe { slice::from_raw_parts_mut(content.data, content.size) }
+                }
+            }
             _ => &mut [],
         }
     }
 
+    // Replaces shared or constant content with a private copy.
+    fn unshare(&mut self) {
+        let size = self.size();
+        let mut private = match Message::with_size(size) {
+            Ok(private) => private,
+            Err(_) => handle_alloc_error(Layout::array::<u8>(size).unwrap()),
+        };
+        private.data_mut().copy_from_slice(self.data());
+        self.content = private.content;
+        self.reset_flags(MsgFlags::Shared);
+    }
+
+    /// Raw pointer to the payload, as handed out by zmq_msg_data.
+    pub fn data_ptr(&mut self) -> *mut u8 {
+        match &mut self.content {
+            MessageContent::Vsm { data, .. } => data.as_mut_ptr(),
+            MessageContent::Lmsg { content } | MessageContent::Zclmsg { content } => content.data,
+            MessageContent::Cmsg { data, .. } => *data,
+            _ => ptr::null_mut(),
+        }
+    }
+
     pub fn size(&self) -> usize {
         match &self.content {
             MessageContent::Vsm { size, .. } => *size as usize,
@@ -201,6 +286,46 @@ impl Message {
         dst.copy_from_slice(data);
     }
 
+    /// Makes another message sharing this one's content. Large buffers are
+    /// reference counted rather than copied, and both messages are marked
+    /// shared from then on.
+    pub fn copy(&mut self) -> Message {
+        let content = match &self.content {
+            MessageContent::Vsm { data, size } => MessageContent::Vsm {
+                data: *data,
+                size: *size,
+            },
+            MessageContent::Lmsg { content } => MessageContent::Lmsg {
+                content: Arc::clone(content),
+            },
+            MessageContent::Zclmsg { content } => MessageContent::Zclmsg {
+                content: Arc::clone(content),
+            },
+            MessageContent::Cmsg { data, size } => MessageContent::Cmsg {
+                data: *data,
+                size: *size,
+            },
+            MessageContent::Delimiter => MessageContent::Delimiter,
+            MessageContent::Join => MessageContent::Join,
+            MessageContent::Leave => MessageContent::Leave,
+        };
+
+        if matches!(
+            self.content,
+            MessageContent::Lmsg { .. } | MessageContent::Zclmsg { .. }
+        ) {
+            self.set_flags(MsgFlags::Shared);
+        }
+
+        Message {
+            metadata: self.metadata.clone(),
+            flags: self.flags,
+            routing_id: self.routing_id,
+            group: self.group.clone(),
+            content,
+        }
+    }
+
     pub fn set_flags(&mut self, flags: MsgFlags) {
         self.flags |= flags as u8;
     }
@@ -221,6 +346,10 @@ impl Message {
         Ok(())
     }
 
+    pub fn reset_routing_id(&mut self) {
+        self.routing_id = 0;
+    }
+
     pub fn get_routing_id(&self) -> u32 {
         self.routing_id
     }
@@ -232,12 +361,11 @@ impl Message {
         }
 
         self.group = if bytes.len() > 14 {
-            let mut long_group = Box::new(LongGroup {
+            let mut long_group = LongGroup {
                 group: [0; ZMQ_GROUP_MAX_LENGTH + 1],
-                ref_count: AtomicUsize::new(1),
-            });
+            };
             long_group.group[..bytes.len()].copy_from_slice(bytes);
-            GroupStorage::Long(long_group)
+            GroupStorage::Long(Arc::new(long_group))
         } else {
             let mut short = [0; 15];
             short[..bytes.len()].copy_from_slice(bytes);
@@ -247,20 +375,31 @@ impl Message {
     }
 
     pub fn get_group(&self) -> &str {
-        match &self.group {
-            GroupStorage::Short(group) => {
-                let len = group.iter().position(|&x| x == 0).unwrap_or(14);
-                std::str::from_utf8(&group[..len]).unwrap_or("")
-            }
-            GroupStorage::Long(group) => {
-                let len = group
-                    .group
-                    .iter()
-                    .position(|&x| x == 0)
-                    .unwrap_or(ZMQ_GROUP_MAX_LENGTH);
-                std::str::from_utf8(&group.group[..len]).unwrap_or("")
-            }
-        }
+        self.group_cstr().to_str().unwrap_or("")
+    }
+
+    /// The group as a NUL-terminated string, for zmq_msg_group.
+    pub fn group_cstr(&self) -> &CStr {
+        let bytes: &[u8] = match &self.group {
+            GroupStorage::Short(group) => group,
+            GroupStorage::Long(group) => &group.group,
+        };
+        // Both buffers are one byte longer than the longest group allowed,
+        // so there is always a terminator.
+        let len = bytes.iter().position(|&x| x == 0).unwrap();
+        CStr::from_bytes_with_nul(&bytes[..=len]).unwrap()
+    }
+
+    pub fn metadata(&self) -> Option<&Arc<Metadata>> {
+        self.metadata.as_ref()
+    }
+
+    pub fn set_metadata(&mut self, metadata: Arc<Metadata>) {
+        self.metadata = Some(metadata);
+    }
+
+    pub fn reset_metadata(&mut self) {
+        self.metadata = None;
     }
 
     pub fn has_more(&self) -> bool {
@@ -271,27 +410,80 @@ impl Message {
         self.has_flag(MsgFlags::Command)
     }
 
-    pub(crate) fn init_join(&mut self) -> Result<(), Box<dyn Error>> {
-        self.flags |= 1; // JOIN flag
+    pub fn is_command(&self) -> bool {
+        self.has_flag(MsgFlags::Command)
+    }
+
+    pub fn is_routing_id(&self) -> bool {
+        self.has_flag(MsgFlags::RoutingId)
+    }
+
+    pub fn is_credential(&self) -> bool {
+        self.has_flag(MsgFlags::Credential)
+    }
+
+    fn cmd_type(&self) -> u8 {
+        self.flags & CMD_TYPE_MASK
+    }
+
+    pub fn is_ping(&self) -> bool {
+        self.cmd_type() == MsgFlags::Ping as u8
+    }
+
+    pub fn is_pong(&self) -> bool {
+        self.cmd_type() == MsgFlags::Pong as u8
+    }
+
+    pub fn is_subscribe(&self) -> bool {
+        self.cmd_type() == MsgFlags::Subscribe as u8
+    }
+
+    pub fn is_cancel(&self) -> bool {
+        self.cmd_type() == MsgFlags::Cancel as u8
+    }
+
+    pub fn is_close_cmd(&self) -> bool {
+        self.cmd_type() == MsgFlags::CloseCmd as u8
+    }
+
+    pub fn is_delimiter(&self) -> bool {
+        matches!(self.content, MessageContent::Delimiter)
+    }
+
+    /// Makes a subscription to `topic`. The wire format is left to the
+    /// encoder: a SUBSCRIBE command for ZMTP/3.1 peers, a message
+    /// prefixed with 1 for older ones.
+    pub(crate) fn init_subscribe(&mut self, topic: &[u8]) -> Result<(), i32> {
+        *self = Message::with_data(topic).map_err(|_| libc::ENOMEM)?;
+        self.set_flags(MsgFlags::Subscribe);
+        Ok(())
+    }
+
+    /// Makes a cancellation of the subscription to `topic`.
+    pub(crate) fn init_cancel(&mut self, topic: &[u8]) -> Result<(), i32> {
+        *self = Message::with_data(topic).map_err(|_| libc::ENOMEM)?;
+        self.set_flags(MsgFlags::Cancel);
         Ok(())
     }
 
-    pub(crate) fn init_leave(&mut self) -> Result<(), Box<dyn Error>> {
-        self.flags |= 2; // LEAVE flag
+    pub(crate) fn init_join(&mut self) -> Result<(), i32> {
+        *self = Message::new();
+        self.content = MessageContent::Join;
         Ok(())
     }
 
-    // fn set_group(&mut self, group: &str) -> Result<(), Box<dyn Error>> {
-    //     self.group = group.to_string();
-    //     Ok(())
-    // }
+    pub(crate) fn init_leave(&mut self) -> Result<(), i32> {
+        *self = Message::new();
+        self.content = MessageContent::Leave;
+        Ok(())
+    }
 
-    fn is_join(&self) -> bool {
-        (self.flags & 1) != 0
+    pub fn is_join(&self) -> bool {
+        matches!(self.content, MessageContent::Join)
     }
 
-    fn is_leave(&self) -> bool {
-        (self.flags & 2) != 0
+    pub fn is_leave(&self) -> bool {
+        matches!(self.content, MessageContent::Leave)
     }
 
     pub fn flags(&self) -> u32 {
@@ -299,68 +491,179 @@ impl Message {
     }
 
     pub fn is_vsm(&self) -> bool {
-        // Simplified VSM check
-        false
+        matches!(self.content, MessageContent::Vsm { .. })
     }
 
-    pub fn add_refs(&mut self, refs: i32) {
-        self.refs += refs;
+    /// Releases the content and leaves an empty message behind.
+    pub fn close(&mut self) -> Result<(), i32> {
+        *self = Message::new();
+        Ok(())
     }
 
-    pub fn rm_refs(&mut self, refs: i32) {
-        self.refs -= refs;
+    pub fn init(&mut self) -> Result<(), i32> {
+        *self = Message::new();
+        Ok(())
     }
+}
 
-    pub fn close(&mut self) -> bool {
-        true
+impl Default for Message {
+    fn default() -> Self {
+        Message::new()
     }
+}
 
-    pub fn init(&mut self) -> bool {
-        true
-    }
+// Basic types and structures
+//
+// Same size and alignment as libzmq's zmq_msg_t, but not the same layout:
+// libzmq keeps small messages inline, whereas this only holds a pointer to
+// a heap-allocated Message, tagged so that a structure which was never
+// initialised, or was closed already, is told apart from a live one. C code
+// must not look inside, as with libzmq.
+//
+// Because of the pointer, C callers have to stick to what libzmq documents
+// even where libzmq itself would get away with less:
+// - a zmq_msg_t must not be copied with memcpy or by assignment; both
+//   copies would own the same Message. Use zmq_msg_copy or zmq_msg_move.
+// - every initialised message has to be closed, or handed over with
+//   zmq_msg_send, even an empty or very small one; otherwise the Message
+//   leaks.
+#[repr(C)]
+pub struct zmq_msg_t {
+    msg: *mut Message,
+    tag: u32,
+    _reserved: [u8; MSG_T_SIZE - mem::size_of::<*mut Message>() - mem::size_of::<u32>()],
 }
 
-impl Drop for Message {
-    fn drop(&mut self) {
-        // Handle cleanup of content
-        match &mut self.content {
-            MessageContent::Lmsg { content } => {
-                if !self.has_flag(MsgFlags::Shared)
-                    || content.ref_count.fetch_sub(1, Ordering::SeqCst) == 1
-                {
-                    unsafe {
-                        libc::free(content.data as *mut libc::c_void);
-                    }
-                }
-            }
-            MessageContent::Zclmsg { content } => {
-                if !self.has_flag(MsgFlags::Shared)
-                    || content.ref_count.fetch_sub(1, Ordering::SeqCst) == 1
-                {
-                    if let Some(ffn) = content.ffn {
-                        unsafe {
-                            ffn(content.data, content.hint);
-                        }
-                    }
-                }
-            }
-            _ => {}
+const _: () = assert!(mem::size_of::<zmq_msg_t>() == MSG_T_SIZE);
+
+const MSG_T_TAG_VALUE_GOOD: u32 = 0x5a4d5347;
+const MSG_T_TAG_VALUE_BAD: u32 = 0xdeadbeef;
+
+impl zmq_msg_t {
+    /// Stores a freshly initialised message, overwriting whatever was there.
+    pub fn init(&mut self, msg: Message) {
+        self.msg = Box::into_raw(Box::new(msg));
+        self.tag = MSG_T_TAG_VALUE_GOOD;
+    }
+
+    /// Marks the structure as holding no message, e.g. after a failed
+    /// initialisation, so that closing it doesn't free garbage.
+    pub fn clear(&mut self) {
+        self.msg = ptr::null_mut();
+        self.tag = MSG_T_TAG_VALUE_BAD;
+    }
+
+    fn check(&self) -> bool {
+        self.tag == MSG_T_TAG_VALUE_GOOD && !self.msg.is_null()
+    }
+
+    /// Returns the message, or None if the structure was not initialised
+    /// or has already been closed.
+    pub fn get(&self) -> Option<&Message> {
+        if !self.check() {
+            return None;
         }
+        Some(unsafe { &*self.msg })
+    }
 
-        // Handle cleanup of group
-        if let GroupStorage::Long(group) = &self.group {
-            if group.ref_count.fetch_sub(1, Ordering::SeqCst) == 1 {
-                // Box will handle deallocation
-            }
+    /// Same as `get`, for changing the message.
+    pub fn get_mut(&mut self) -> Option<&mut Message> {
+        if !self.check() {
+            return None;
         }
+        Some(unsafe { &mut *self.msg })
+    }
+
+    /// Takes the message out, leaving the structure closed.
+    pub fn take(&mut self) -> Option<Message> {
+        if !self.check() {
+            return None;
+        }
+        let msg = unsafe { Box::from_raw(self.msg) };
+        self.clear();
+        Some(*msg)
     }
 }
 
-// Implementation of Clone would go here if needed
-// Implementation of Debug would go here if needed
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-// Basic types and structures
-#[repr(C)]
-pub struct zmq_msg_t {
-    field0: [u8; 64], // Internal implementation detail
+    #[test]
+    fn test_copy_shares_large_content() {
+        let payload = vec![7u8; MAX_VSM_SIZE + 1];
+        let mut msg = Message::with_data(&payload).unwrap();
+        let copy = msg.copy();
+
+        assert_eq!(copy.data(), &payload[..]);
+        assert_eq!(copy.data().as_ptr(), msg.data().as_ptr());
+        assert!(msg.has_flag(MsgFlags::Shared));
+        assert!(copy.has_flag(MsgFlags::Shared));
+    }
+
+    #[test]
+    fn test_writing_unshares_content() {
+        let payload = vec![7u8; MAX_VSM_SIZE + 1];
+        let mut msg = Message::with_data(&payload).unwrap();
+        let mut copy = msg.copy();
+
+        copy.data_mut()[0] = 1;
+        assert_eq!(msg.data(), &payload[..]);
+        assert_eq!(copy.data()[0], 1);
+        assert_ne!(copy.data().as_ptr(), msg.data().as_ptr());
+        assert!(!copy.has_flag(MsgFlags::Shared));
+
+        // Once alone, the original writes in place.
+        let ptr = msg.data().as_ptr();
+        msg.data_mut()[0] = 2;
+        assert_eq!(msg.data().as_ptr(), ptr);
+
+        static CONSTANT: [u8; 3] = *b"abc";
+        let mut constant = unsafe { Message::with_const(CONSTANT.as_ptr(), CONSTANT.len()) };
+        constant.data_mut()[0] = b'x';
+        assert_eq!(constant.data(), b"xbc");
+        assert_eq!(&CONSTANT, b"abc");
+    }
+
+    #[test]
+    fn test_group_round_trip() {
+        let mut msg = Message::new();
+        msg.set_group("short").unwrap();
+        assert_eq!(msg.get_group(), "short");
+
+        let long = "g".repeat(ZMQ_GROUP_MAX_LENGTH);
+        msg.set_group(&long).unwrap();
+        assert_eq!(msg.get_group(), long);
+
+        assert!(msg.set_group(&"g".repeat(ZMQ_GROUP_MAX_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn test_command_types() {
+        let mut msg = Message::new();
+        msg.set_flags(MsgFlags::Command);
+        msg.set_flags(MsgFlags::Subscribe);
+        assert!(msg.is_subscribe());
+        assert!(!msg.is_ping());
+        assert!(!msg.is_cancel());
+    }
+
+    #[test]
+    fn test_msg_t_rejects_uninitialised() {
+        // Whatever was on the stack isn't taken for a message.
+        let mut raw: zmq_msg_t = unsafe { mem::zeroed() };
+        raw.msg = 0x1000 as *mut Message;
+        assert!(raw.get_mut().is_none());
+        assert!(raw.take().is_none());
+
+        raw.init(Message::with_data(b"hello").unwrap());
+        assert_eq!(raw.get_mut().unwrap().data(), b"hello");
+        assert!(raw.take().is_some());
+        // Closing twice is an error, not a double free.
+        assert!(raw.take().is_none());
+
+        raw.init(Message::new());
+        raw.clear();
+        assert!(raw.get_mut().is_none());
+    }
 }
@@ -1,28 +1,41 @@
 use std::collections::HashMap;
+use std::ffi::{CStr, CString};
 use std::sync::atomic::{AtomicUsize, Ordering};
 
+use crate::zmq_draft::ZMQ_MSG_PROPERTY_ROUTING_ID;
+
+#[derive(Debug)]
 pub struct Metadata {
     // Reference counter using atomic operations
     ref_cnt: AtomicUsize,
-    // Dictionary holding metadata using Rust's HashMap
-    dict: HashMap<String, String>,
+    // Dictionary holding metadata using Rust's HashMap. Values are kept
+    // NUL-terminated so zmq_msg_gets can hand them out directly.
+    dict: HashMap<String, CString>,
 }
 
 impl Metadata {
     pub fn new(dict: HashMap<String, String>) -> Self {
         Metadata {
             ref_cnt: AtomicUsize::new(1),
-            dict,
+            // Values with embedded NULs can't be represented in C, drop them.
+            dict: dict
+                .into_iter()
+                .filter_map(|(k, v)| CString::new(v).ok().map(|v| (k, v)))
+                .collect(),
         }
     }
 
     // Returns Option<&str> instead of raw pointer
     pub fn get(&self, property: &str) -> Option<&str> {
+        self.get_cstr(property).and_then(|s| s.to_str().ok())
+    }
+
+    pub fn get_cstr(&self, property: &str) -> Option<&CStr> {
         if property == "Identity" {
             // Handle legacy "Identity" property
-            return self.get("ZMQ_MSG_PROPERTY_ROUTING_ID");
+            return self.get_cstr(ZMQ_MSG_PROPERTY_ROUTING_ID);
         }
-        self.dict.get(property).map(|s| s.as_str())
+        self.dict.get(property).map(|s| s.as_c_str())
     }
 
     pub fn add_ref(&self) {
@@ -1,21 +1,10 @@
 // Copyright MPL-2.0
 
-pub struct Pipe; // Placeholder for the original pipe_t class
+use crate::generic_mtrie::GenericMtrie;
+use crate::pipe::Pipe;
 
-pub struct Mtrie<T> {
-    _phantom: std::marker::PhantomData<T>,
-}
-
-impl<T> Mtrie<T> {
-    pub fn new() -> Self {
-        Mtrie {
-            _phantom: std::marker::PhantomData,
-        }
-    }
-}
-
-// Create concrete type alias for common usage
-pub type MtriePipe = Mtrie<Pipe>;
+/// Subscription trie keyed by the pipes subscribed to each prefix.
+pub type Mtrie = GenericMtrie<Pipe>;
 
 #[cfg(test)]
 mod tests {
@@ -23,6 +12,7 @@ mod tests {
 
     #[test]
     fn test_mtrie_creation() {
-        let _mtrie: MtriePipe = Mtrie::new();
+        let mtrie = Mtrie::new();
+        assert_eq!(mtrie.num_prefixes(), 0);
     }
 }
@@ -1,7 +1,7 @@
 use crate::context::{Context, Endpoint};
+use crate::i_engine::IEngine;
 use crate::io_thread::IoThread;
 use crate::pipe::Pipe;
-use crate::session_base::Engine;
 // Forward declarations/type aliases
 // type Endpoint = (); // Placeholder, implement actual type
 // type Context = (); // Placeholder for ctx_t
@@ -24,7 +24,7 @@ pub enum CommandType {
         object: Box<dyn Own>,
     },
     Attach {
-        engine: Box<dyn Engine>,
+        engine: Box<dyn IEngine>,
     },
     Bind {
         pipe: Pipe,
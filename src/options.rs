@@ -1,5 +1,11 @@
-use std::collections::HashMap;
-use std::sync::atomic::AtomicI32;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use crate::constants::*;
+#[cfg(feature = "curve")]
+use crate::utils::{z85_decode, z85_encode};
+use crate::zmq_draft::*;
 
 // Constants
 const CURVE_KEYSIZE: usize = 32;
@@ -11,59 +17,59 @@ type Uid = u32;
 type Gid = u32;
 type Pid = i32;
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct TcpAddressMask {
     // Implementation details omitted for brevity
 }
 
 pub struct Options {
     // High-water marks for message pipes
-    send_high_water_mark: i32,
-    recv_high_water_mark: i32,
+    pub send_high_water_mark: i32,
+    pub recv_high_water_mark: i32,
 
     // I/O thread affinity
-    affinity: u64,
+    pub affinity: u64,
 
     // Socket routing id
-    routing_id_size: u8,
-    routing_id: [u8; 256],
+    pub routing_id_size: u8,
+    pub routing_id: [u8; 256],
 
     // Maximum transfer rate [kb/s]. Default 100kb/s
-    rate: i32,
+    pub rate: i32,
 
     // Reliability time interval [ms]. Default 10 seconds
-    recovery_ivl: i32,
+    pub recovery_ivl: i32,
 
     // Sets the time-to-live field in every multicast packet sent
-    multicast_hops: i32,
+    pub multicast_hops: i32,
 
     // Sets the maximum transport data unit size in every multicast packet sent
-    multicast_max_trans_data_unit_szu: i32,
+    pub multicast_max_trans_data_unit_szu: i32,
 
     // SO_SNDBUF and SO_RCVBUF to be passed to underlying transport sockets
-    send_buf_opt: i32,
-    recv_buf_opt: i32,
+    pub send_buf_opt: i32,
+    pub recv_buf_opt: i32,
 
     // Type of service (containing DSCP and ECN socket options)
-    type_of_svc: i32,
+    pub type_of_svc: i32,
 
     // Protocol-defined priority
-    priority: i32,
+    pub priority: i32,
 
     // Socket type
-    socket_type: i8,
+    pub socket_type: i32,
 
     // Linger time, in milliseconds
-    linger: AtomicI32,
+    pub linger: AtomicI32,
 
     // Maximum interval in milliseconds beyond which userspace will timeout connect()
-    connect_timeout: i32,
+    pub connect_timeout: i32,
 
     // Maximum interval in milliseconds beyond which TCP will timeout retransmitted packets
-    tcp_max_retrans_intvl: i32,
+    pub tcp_max_retrans_intvl: i32,
 
     // Disable reconnect under certain conditions
-    reconnect_stop: i32,
+    pub reconnect_stop: i32,
 
     // Minimum interval between attempts to reconnect, in milliseconds
     pub reconnect_intvl: i32,
@@ -72,173 +78,173 @@ pub struct Options {
     pub reconnect_intvl_max: i32,
 
     // Maximum backlog for pending connections
-    backlog: i32,
+    pub backlog: i32,
 
     // Maximal size of message to handle
-    max_msg_sz: i64,
+    pub max_msg_sz: i64,
 
     // The timeout for send/recv operations for this socket, in milliseconds
-    recv_timeo: i32,
-    send_timeo: i32,
+    pub recv_timeo: i32,
+    pub send_timeo: i32,
 
     // If true, IPv6 is enabled (as well as IPv4)
-    ipv6: bool,
+    pub ipv6: bool,
 
     // If 1, connecting pipes are not attached immediately
-    immediate: i32,
+    pub immediate: i32,
 
     // If 1, (X)SUB socket should filter the messages
-    filter: bool,
+    pub filter: bool,
 
     // If true, the subscription matching is reversed
-    invert_matching: bool,
+    pub invert_matching: bool,
 
     // If true, the routing id message is forwarded to the socket
-    recv_routing_id: bool,
+    pub recv_routing_id: bool,
 
     // If true, router socket accepts non-zmq tcp connections
     pub raw_socket: bool,
-    raw_notify: bool,
+    pub raw_notify: bool,
 
     // Address of SOCKS proxy
-    socks_proxy_address: String,
-    socks_proxy_username: String,
-    socks_proxy_password: String,
+    pub socks_proxy_address: String,
+    pub socks_proxy_username: String,
+    pub socks_proxy_password: String,
 
     // TCP keep-alive settings
-    tcp_keepalive: i32,
-    tcp_keepalive_cnt: i32,
-    tcp_keepalive_idle: i32,
-    tcp_keepalive_intvl: i32,
+    pub tcp_keepalive: i32,
+    pub tcp_keepalive_cnt: i32,
+    pub tcp_keepalive_idle: i32,
+    pub tcp_keepalive_intvl: i32,
 
     // TCP accept() filters
-    tcp_accept_filters: Vec<TcpAddressMask>,
+    pub tcp_accept_filters: Vec<TcpAddressMask>,
 
     // IPC accept() filters
     #[cfg(any(target_os = "linux", target_os = "macos"))]
-    ipc_uid_accept_filters: HashSet<Uid>,
+    pub ipc_uid_accept_filters: HashSet<Uid>,
     #[cfg(any(target_os = "linux", target_os = "macos"))]
-    ipc_gid_accept_filters: HashSet<Gid>,
+    pub ipc_gid_accept_filters: HashSet<Gid>,
     #[cfg(target_os = "linux")]
-    ipc_pid_accept_filters: HashSet<Pid>,
+    pub ipc_pid_accept_filters: HashSet<Pid>,
 
     // Security mechanism
-    mechanism: i32,
-    as_server: i32,
-    zap_domain: String,
+    pub mechanism: i32,
+    pub as_server: i32,
+    pub zap_domain: String,
 
     // Security credentials for PLAIN mechanism
     pub plain_username: String,
     pub plain_password: String,
 
     // Security credentials for CURVE mechanism
-    curve_public_key: [u8; CURVE_KEYSIZE],
+    pub curve_public_key: [u8; CURVE_KEYSIZE],
     pub curve_secret_key: [u8; CURVE_KEYSIZE],
-    curve_server_key: [u8; CURVE_KEYSIZE],
+    pub curve_server_key: [u8; CURVE_KEYSIZE],
 
     // GSSAPI security configuration
     #[cfg(feature = "gssapi")]
-    gss_principal: String,
+    pub gss_principal: String,
     #[cfg(feature = "gssapi")]
-    gss_service_principal: String,
+    pub gss_service_principal: String,
     #[cfg(feature = "gssapi")]
-    gss_principal_nt: i32,
+    pub gss_principal_nt: i32,
     #[cfg(feature = "gssapi")]
-    gss_service_principal_nt: i32,
+    pub gss_service_principal_nt: i32,
     #[cfg(feature = "gssapi")]
-    gss_plaintext: bool,
+    pub gss_plaintext: bool,
 
     // Socket ID
-    socket_id: i32,
+    pub socket_id: i32,
 
     // If true, socket conflates outgoing/incoming messages
-    conflate: bool,
+    pub conflate: bool,
 
     // Connection handshake timeout
-    handshake_intvl: i32,
+    pub handshake_intvl: i32,
 
-    connected: bool,
+    pub connected: bool,
 
     // Heartbeat configuration
-    heartbeat_ttl: u16,
-    heartbeat_intvl: i32,
-    heartbeat_timeo: i32,
+    pub heartbeat_ttl: u16,
+    pub heartbeat_intvl: i32,
+    pub heartbeat_timeo: i32,
 
     // VMCI configuration
     #[cfg(feature = "vmci")]
-    vmci_buffer_size: u64,
+    pub vmci_buffer_size: u64,
     #[cfg(feature = "vmci")]
-    vmci_buffer_min_size: u64,
+    pub vmci_buffer_min_size: u64,
     #[cfg(feature = "vmci")]
-    vmci_buffer_max_size: u64,
+    pub vmci_buffer_max_size: u64,
     #[cfg(feature = "vmci")]
-    vmci_connect_timeout: i32,
+    pub vmci_connect_timeout: i32,
 
     // File descriptor to use
-    use_fd: i32,
+    pub use_fd: i32,
 
     // Device to bind to
-    bound_device: String,
+    pub bound_device: String,
 
     // ZAP configuration
-    pub(crate) zap_enforce_domain: bool,
+    pub zap_enforce_domain: bool,
 
     // Performance options
-    loopback_fastpath: bool,
-    multicast_loop: bool,
-    in_batch_size: i32,
-    out_batch_size: i32,
-    zero_copy: bool,
+    pub loopback_fastpath: bool,
+    pub multicast_loop: bool,
+    pub in_batch_size: i32,
+    pub out_batch_size: i32,
+    pub zero_copy: bool,
 
     // Router notifications
-    router_notify: i32,
+    pub router_notify: i32,
 
     // Application metadata
-    app_metadata: HashMap<String, String>,
+    pub app_metadata: HashMap<String, String>,
 
     // Monitor event version
-    monitor_event_version: i32,
+    pub monitor_event_version: i32,
 
     // WSS configuration
     #[cfg(feature = "wss")]
-    wss_key_pem: String,
+    pub wss_key_pem: String,
     #[cfg(feature = "wss")]
-    wss_cert_pem: String,
+    pub wss_cert_pem: String,
     #[cfg(feature = "wss")]
-    wss_trust_pem: String,
+    pub wss_trust_pem: String,
     #[cfg(feature = "wss")]
-    wss_hostname: String,
+    pub wss_hostname: String,
     #[cfg(feature = "wss")]
-    wss_trust_system: bool,
+    pub wss_trust_system: bool,
 
     // Protocol messages
-    hello_msg: Vec<u8>,
-    pub(crate) can_send_hello_msg: bool,
-    disconnect_msg: Vec<u8>,
-    can_recv_disconnect_msg: bool,
-    hiccup_msg: Vec<u8>,
-    pub(crate) can_recv_hiccup_msg: bool,
+    pub hello_msg: Vec<u8>,
+    pub can_send_hello_msg: bool,
+    pub disconnect_msg: Vec<u8>,
+    pub can_recv_disconnect_msg: bool,
+    pub hiccup_msg: Vec<u8>,
+    pub can_recv_hiccup_msg: bool,
 
     // NORM options
     #[cfg(feature = "norm")]
-    norm_mode: i32,
+    pub norm_mode: i32,
     #[cfg(feature = "norm")]
-    norm_unicast_nacks: bool,
+    pub norm_unicast_nacks: bool,
     #[cfg(feature = "norm")]
-    norm_buffer_size: i32,
+    pub norm_buffer_size: i32,
     #[cfg(feature = "norm")]
-    norm_segment_size: i32,
+    pub norm_segment_size: i32,
     #[cfg(feature = "norm")]
-    norm_block_size: i32,
+    pub norm_block_size: i32,
     #[cfg(feature = "norm")]
-    norm_num_parity: i32,
+    pub norm_num_parity: i32,
     #[cfg(feature = "norm")]
-    norm_num_autoparity: i32,
+    pub norm_num_autoparity: i32,
     #[cfg(feature = "norm")]
-    norm_push_enable: bool,
+    pub norm_push_enable: bool,
 
     // Busy polling configuration
-    busy_poll: i32,
+    pub busy_poll: i32,
 }
 
 impl Options {
@@ -322,13 +328,456 @@ impl Options {
             hiccup_msg: Vec::new(),
             can_recv_hiccup_msg: false,
             busy_poll: 0,
-            // Initialize feature-gated fields
-            ..Default::default()
+            #[cfg(feature = "gssapi")]
+            gss_principal: String::new(),
+            #[cfg(feature = "gssapi")]
+            gss_service_principal: String::new(),
+            #[cfg(feature = "gssapi")]
+            gss_principal_nt: 0, // ZMQ_GSSAPI_NT_HOSTBASED
+            #[cfg(feature = "gssapi")]
+            gss_service_principal_nt: 0, // ZMQ_GSSAPI_NT_HOSTBASED
+            #[cfg(feature = "gssapi")]
+            gss_plaintext: false,
+            #[cfg(feature = "vmci")]
+            vmci_buffer_size: 0,
+            #[cfg(feature = "vmci")]
+            vmci_buffer_min_size: 0,
+            #[cfg(feature = "vmci")]
+            vmci_buffer_max_size: 0,
+            #[cfg(feature = "vmci")]
+            vmci_connect_timeout: -1,
+            #[cfg(feature = "wss")]
+            wss_key_pem: String::new(),
+            #[cfg(feature = "wss")]
+            wss_cert_pem: String::new(),
+            #[cfg(feature = "wss")]
+            wss_trust_pem: String::new(),
+            #[cfg(feature = "wss")]
+            wss_hostname: String::new(),
+            #[cfg(feature = "wss")]
+            wss_trust_system: false,
+            #[cfg(feature = "norm")]
+            norm_mode: ZMQ_NORM_CC,
+            #[cfg(feature = "norm")]
+            norm_unicast_nacks: false,
+            #[cfg(feature = "norm")]
+            norm_buffer_size: 2048,
+            #[cfg(feature = "norm")]
+            norm_segment_size: 1400,
+            #[cfg(feature = "norm")]
+            norm_block_size: 16,
+            #[cfg(feature = "norm")]
+            norm_num_parity: 0,
+            #[cfg(feature = "norm")]
+            norm_num_autoparity: 0,
+            #[cfg(feature = "norm")]
+            norm_push_enable: false,
+        }
+    }
+
+    /// Parses a generic socket option. Options specific to a socket type
+    /// are handled by that type's `xsetsockopt` before this is reached.
+    pub fn setsockopt(&mut self, option: i32, optval: &[u8]) -> Result<(), i32> {
+        match option {
+            ZMQ_SNDHWM => self.send_high_water_mark = int_at_least(optval, 0)?,
+            ZMQ_RCVHWM => self.recv_high_water_mark = int_at_least(optval, 0)?,
+            ZMQ_AFFINITY => self.affinity = u64_value(optval)?,
+
+            ZMQ_ROUTING_ID => {
+                // Routing id is any binary string from 1 to 255 octets
+                if optval.is_empty() || optval.len() > u8::MAX as usize {
+                    return Err(libc::EINVAL);
+                }
+                self.routing_id_size = optval.len() as u8;
+                self.routing_id[..optval.len()].copy_from_slice(optval);
+            }
+
+            ZMQ_RATE => self.rate = int_at_least(optval, 1)?,
+            ZMQ_RECOVERY_IVL => self.recovery_ivl = int_at_least(optval, 0)?,
+            ZMQ_SNDBUF => self.send_buf_opt = int_at_least(optval, -1)?,
+            ZMQ_RCVBUF => self.recv_buf_opt = int_at_least(optval, -1)?,
+            ZMQ_TOS => self.type_of_svc = int_at_least(optval, 0)?,
+            ZMQ_PRIORITY => self.priority = int_at_least(optval, 0)?,
+
+            ZMQ_LINGER => {
+                let value = int_at_least(optval, -1)?;
+                self.linger.store(value, Ordering::SeqCst);
+            }
+
+            ZMQ_CONNECT_TIMEOUT => self.connect_timeout = int_at_least(optval, 0)?,
+            ZMQ_TCP_MAXRT => self.tcp_max_retrans_intvl = int_at_least(optval, 0)?,
+            ZMQ_RECONNECT_STOP => self.reconnect_stop = int_value(optval)?,
+            ZMQ_RECONNECT_IVL => self.reconnect_intvl = int_at_least(optval, -1)?,
+            ZMQ_RECONNECT_IVL_MAX => self.reconnect_intvl_max = int_at_least(optval, 0)?,
+            ZMQ_BACKLOG => self.backlog = int_at_least(optval, 0)?,
+            ZMQ_MAXMSGSIZE => self.max_msg_sz = i64_value(optval)?,
+            ZMQ_MULTICAST_HOPS => self.multicast_hops = int_at_least(optval, 1)?,
+            ZMQ_MULTICAST_MAXTPDU => {
+                self.multicast_max_trans_data_unit_szu = int_at_least(optval, 1)?
+            }
+            ZMQ_RCVTIMEO => self.recv_timeo = int_at_least(optval, -1)?,
+            ZMQ_SNDTIMEO => self.send_timeo = int_at_least(optval, -1)?,
+
+            //  Deprecated in favor of ZMQ_IPV6
+            ZMQ_IPV4ONLY => self.ipv6 = !bool_strict(optval)?,
+            //  To replace the somewhat surprising IPV4ONLY
+            ZMQ_IPV6 => self.ipv6 = bool_relaxed(optval)?,
+
+            ZMQ_SOCKS_PROXY => self.socks_proxy_address = string_value(optval, usize::MAX)?,
+            ZMQ_SOCKS_USERNAME => {
+                self.socks_proxy_username = string_value(optval, u8::MAX as usize)?
+            }
+            ZMQ_SOCKS_PASSWORD => {
+                self.socks_proxy_password = string_value(optval, u8::MAX as usize)?
+            }
+
+            ZMQ_TCP_KEEPALIVE => {
+                let value = int_value(optval)?;
+                if !(-1..=1).contains(&value) {
+                    return Err(libc::EINVAL);
+                }
+                self.tcp_keepalive = value;
+            }
+            ZMQ_TCP_KEEPALIVE_CNT => self.tcp_keepalive_cnt = int_at_least(optval, -1)?,
+            ZMQ_TCP_KEEPALIVE_IDLE => self.tcp_keepalive_idle = int_at_least(optval, -1)?,
+            ZMQ_TCP_KEEPALIVE_INTVL => self.tcp_keepalive_intvl = int_at_least(optval, -1)?,
+
+            ZMQ_IMMEDIATE => self.immediate = bool_strict(optval)? as i32,
+
+            ZMQ_BINDTODEVICE => self.bound_device = string_value(optval, BIND_DEV_SZ)?,
+
+            ZMQ_PLAIN_SERVER => {
+                let value = bool_strict(optval)?;
+                self.as_server = value as i32;
+                self.mechanism = if value { ZMQ_PLAIN } else { ZMQ_NULL };
+            }
+            ZMQ_PLAIN_USERNAME => {
+                if optval.is_empty() {
+                    self.mechanism = ZMQ_NULL;
+                } else {
+                    self.plain_username = string_value(optval, u8::MAX as usize)?;
+                    self.as_server = 0;
+                    self.mechanism = ZMQ_PLAIN;
+                }
+            }
+            ZMQ_PLAIN_PASSWORD => {
+                if optval.is_empty() {
+                    self.mechanism = ZMQ_NULL;
+                } else {
+                    self.plain_password = string_value(optval, u8::MAX as usize)?;
+                    self.as_server = 0;
+                    self.mechanism = ZMQ_PLAIN;
+                }
+            }
+
+            ZMQ_ZAP_DOMAIN => self.zap_domain = string_value(optval, u8::MAX as usize)?,
+
+            //  If curve encryption isn't built, these options provoke EINVAL
+            #[cfg(feature = "curve")]
+            ZMQ_CURVE_SERVER => {
+                let value = bool_strict(optval)?;
+                self.as_server = value as i32;
+                self.mechanism = if value { ZMQ_CURVE } else { ZMQ_NULL };
+            }
+            #[cfg(feature = "curve")]
+            ZMQ_CURVE_PUBLICKEY => {
+                self.curve_public_key = curve_key(optval)?;
+                self.mechanism = ZMQ_CURVE;
+            }
+            #[cfg(feature = "curve")]
+            ZMQ_CURVE_SECRETKEY => {
+                self.curve_secret_key = curve_key(optval)?;
+                self.mechanism = ZMQ_CURVE;
+            }
+            #[cfg(feature = "curve")]
+            ZMQ_CURVE_SERVERKEY => {
+                self.curve_server_key = curve_key(optval)?;
+                self.as_server = 0;
+                self.mechanism = ZMQ_CURVE;
+            }
+
+            ZMQ_CONFLATE => self.conflate = bool_strict(optval)?,
+            ZMQ_HANDSHAKE_IVL => self.handshake_intvl = int_at_least(optval, 0)?,
+            ZMQ_INVERT_MATCHING => self.invert_matching = bool_relaxed(optval)?,
+
+            ZMQ_HEARTBEAT_IVL => self.heartbeat_intvl = int_at_least(optval, 0)?,
+            ZMQ_HEARTBEAT_TTL => {
+                // Convert this to deciseconds from milliseconds
+                let value = int_value(optval)?;
+                if !(0..=(u16::MAX as i32) * 100).contains(&value) {
+                    return Err(libc::EINVAL);
+                }
+                self.heartbeat_ttl = (value / 100) as u16;
+            }
+            ZMQ_HEARTBEAT_TIMEOUT => self.heartbeat_timeo = int_at_least(optval, 0)?,
+
+            ZMQ_USE_FD => self.use_fd = int_at_least(optval, -1)?,
+            ZMQ_ZAP_ENFORCE_DOMAIN => self.zap_enforce_domain = bool_strict(optval)?,
+            ZMQ_LOOPBACK_FASTPATH => self.loopback_fastpath = bool_strict(optval)?,
+
+            ZMQ_METADATA => {
+                // "X-key:value", the key being at most 255 characters long
+                let s = std::str::from_utf8(optval).map_err(|_| libc::EINVAL)?;
+                match s.find(':') {
+                    Some(pos) if pos != 0 && pos != s.len() - 1 => {
+                        let key = &s[..pos];
+                        if !key.starts_with("X-") || key.len() > u8::MAX as usize {
+                            return Err(libc::EINVAL);
+                        }
+                        self.app_metadata
+                            .insert(key.to_string(), s[pos + 1..].to_string());
+                    }
+                    _ => return Err(libc::EINVAL),
+                }
+            }
+
+            ZMQ_MULTICAST_LOOP => self.multicast_loop = bool_strict(optval)?,
+            ZMQ_IN_BATCH_SIZE => self.in_batch_size = int_at_least(optval, 1)?,
+            ZMQ_OUT_BATCH_SIZE => self.out_batch_size = int_at_least(optval, 1)?,
+            ZMQ_BUSY_POLL => self.busy_poll = int_value(optval)?,
+
+            ZMQ_HELLO_MSG => {
+                self.hello_msg = optval.to_vec();
+                self.can_send_hello_msg = !optval.is_empty();
+            }
+            ZMQ_DISCONNECT_MSG => {
+                self.disconnect_msg = optval.to_vec();
+                self.can_recv_disconnect_msg = !optval.is_empty();
+            }
+            ZMQ_HICCUP_MSG => {
+                self.hiccup_msg = optval.to_vec();
+                self.can_recv_hiccup_msg = !optval.is_empty();
+            }
+
+            #[cfg(feature = "wss")]
+            ZMQ_WSS_KEY_PEM => self.wss_key_pem = string_value(optval, usize::MAX)?,
+            #[cfg(feature = "wss")]
+            ZMQ_WSS_CERT_PEM => self.wss_cert_pem = string_value(optval, usize::MAX)?,
+            #[cfg(feature = "wss")]
+            ZMQ_WSS_TRUST_PEM => self.wss_trust_pem = string_value(optval, usize::MAX)?,
+            #[cfg(feature = "wss")]
+            ZMQ_WSS_HOSTNAME => self.wss_hostname = string_value(optval, usize::MAX)?,
+            #[cfg(feature = "wss")]
+            ZMQ_WSS_TRUST_SYSTEM => self.wss_trust_system = bool_strict(optval)?,
+
+            _ => return Err(libc::EINVAL),
+        }
+
+        Ok(())
+    }
+
+    /// Copies the value of a generic socket option into `optval` and
+    /// returns the number of bytes written. Fails with EINVAL if the
+    /// buffer is too small or the option is unknown.
+    pub fn getsockopt(&self, option: i32, optval: &mut [u8]) -> Result<usize, i32> {
+        match option {
+            ZMQ_SNDHWM => put_int(optval, self.send_high_water_mark),
+            ZMQ_RCVHWM => put_int(optval, self.recv_high_water_mark),
+            ZMQ_AFFINITY => put_bytes(optval, &self.affinity.to_ne_bytes()),
+            ZMQ_ROUTING_ID => {
+                put_bytes(optval, &self.routing_id[..self.routing_id_size as usize])
+            }
+            ZMQ_RATE => put_int(optval, self.rate),
+            ZMQ_RECOVERY_IVL => put_int(optval, self.recovery_ivl),
+            ZMQ_SNDBUF => put_int(optval, self.send_buf_opt),
+            ZMQ_RCVBUF => put_int(optval, self.recv_buf_opt),
+            ZMQ_TOS => put_int(optval, self.type_of_svc),
+            ZMQ_PRIORITY => put_int(optval, self.priority),
+            ZMQ_TYPE => put_int(optval, self.socket_type),
+            ZMQ_LINGER => put_int(optval, self.linger.load(Ordering::SeqCst)),
+            ZMQ_CONNECT_TIMEOUT => put_int(optval, self.connect_timeout),
+            ZMQ_TCP_MAXRT => put_int(optval, self.tcp_max_retrans_intvl),
+            ZMQ_RECONNECT_STOP => put_int(optval, self.reconnect_stop),
+            ZMQ_RECONNECT_IVL => put_int(optval, self.reconnect_intvl),
+            ZMQ_RECONNECT_IVL_MAX => put_int(optval, self.reconnect_intvl_max),
+            ZMQ_BACKLOG => put_int(optval, self.backlog),
+            ZMQ_MAXMSGSIZE => put_bytes(optval, &self.max_msg_sz.to_ne_bytes()),
+            ZMQ_MULTICAST_HOPS => put_int(optval, self.multicast_hops),
+            ZMQ_MULTICAST_MAXTPDU => put_int(optval, self.multicast_max_trans_data_unit_szu),
+            ZMQ_RCVTIMEO => put_int(optval, self.recv_timeo),
+            ZMQ_SNDTIMEO => put_int(optval, self.send_timeo),
+            ZMQ_IPV4ONLY => put_int(optval, !self.ipv6 as i32),
+            ZMQ_IPV6 => put_int(optval, self.ipv6 as i32),
+            ZMQ_IMMEDIATE => put_int(optval, self.immediate),
+            ZMQ_SOCKS_PROXY => put_string(optval, &self.socks_proxy_address),
+            ZMQ_SOCKS_USERNAME => put_string(optval, &self.socks_proxy_username),
+            ZMQ_SOCKS_PASSWORD => put_string(optval, &self.socks_proxy_password),
+            ZMQ_TCP_KEEPALIVE => put_int(optval, self.tcp_keepalive),
+            ZMQ_TCP_KEEPALIVE_CNT => put_int(optval, self.tcp_keepalive_cnt),
+            ZMQ_TCP_KEEPALIVE_IDLE => put_int(optval, self.tcp_keepalive_idle),
+            ZMQ_TCP_KEEPALIVE_INTVL => put_int(optval, self.tcp_keepalive_intvl),
+            ZMQ_MECHANISM => put_int(optval, self.mechanism),
+            ZMQ_PLAIN_SERVER => {
+                put_int(optval, (self.as_server != 0 && self.mechanism == ZMQ_PLAIN) as i32)
+            }
+            ZMQ_PLAIN_USERNAME => put_string(optval, &self.plain_username),
+            ZMQ_PLAIN_PASSWORD => put_string(optval, &self.plain_password),
+            ZMQ_ZAP_DOMAIN => put_string(optval, &self.zap_domain),
+            ZMQ_BINDTODEVICE => put_string(optval, &self.bound_device),
+
+            #[cfg(feature = "curve")]
+            ZMQ_CURVE_SERVER => {
+                put_int(optval, (self.as_server != 0 && self.mechanism == ZMQ_CURVE) as i32)
+            }
+            #[cfg(feature = "curve")]
+            ZMQ_CURVE_PUBLICKEY => put_curve_key(optval, &self.curve_public_key),
+            #[cfg(feature = "curve")]
+            ZMQ_CURVE_SECRETKEY => put_curve_key(optval, &self.curve_secret_key),
+            #[cfg(feature = "curve")]
+            ZMQ_CURVE_SERVERKEY => put_curve_key(optval, &self.curve_server_key),
+
+            ZMQ_CONFLATE => put_int(optval, self.conflate as i32),
+            ZMQ_HANDSHAKE_IVL => put_int(optval, self.handshake_intvl),
+            ZMQ_INVERT_MATCHING => put_int(optval, self.invert_matching as i32),
+            ZMQ_HEARTBEAT_IVL => put_int(optval, self.heartbeat_intvl),
+            ZMQ_HEARTBEAT_TTL => put_int(optval, self.heartbeat_ttl as i32 * 100),
+            ZMQ_HEARTBEAT_TIMEOUT => put_int(optval, self.heartbeat_timeo),
+            ZMQ_USE_FD => put_int(optval, self.use_fd),
+            ZMQ_ZAP_ENFORCE_DOMAIN => put_int(optval, self.zap_enforce_domain as i32),
+            ZMQ_LOOPBACK_FASTPATH => put_int(optval, self.loopback_fastpath as i32),
+            ZMQ_MULTICAST_LOOP => put_int(optval, self.multicast_loop as i32),
+            ZMQ_IN_BATCH_SIZE => put_int(optval, self.in_batch_size),
+            ZMQ_OUT_BATCH_SIZE => put_int(optval, self.out_batch_size),
+            ZMQ_BUSY_POLL => put_int(optval, self.busy_poll),
+            ZMQ_ROUTER_NOTIFY => put_int(optval, self.router_notify),
+
+            #[cfg(feature = "wss")]
+            ZMQ_WSS_TRUST_SYSTEM => put_int(optval, self.wss_trust_system as i32),
+
+            _ => Err(libc::EINVAL),
         }
     }
+}
 
-    // Method implementations would go here
-    // The original C++ methods would need to be converted to Rust
+// Sessions and inproc peers take a snapshot of the socket's options, the
+// way options_t is copied by value in libzmq.
+impl Clone for Options {
+    fn clone(&self) -> Self {
+        Options {
+            send_high_water_mark: self.send_high_water_mark,
+            recv_high_water_mark: self.recv_high_water_mark,
+            affinity: self.affinity,
+            routing_id_size: self.routing_id_size,
+            routing_id: self.routing_id,
+            rate: self.rate,
+            recovery_ivl: self.recovery_ivl,
+            multicast_hops: self.multicast_hops,
+            multicast_max_trans_data_unit_szu: self.multicast_max_trans_data_unit_szu,
+            send_buf_opt: self.send_buf_opt,
+            recv_buf_opt: self.recv_buf_opt,
+            type_of_svc: self.type_of_svc,
+            priority: self.priority,
+            socket_type: self.socket_type,
+            linger: AtomicI32::new(self.linger.load(Ordering::SeqCst)),
+            connect_timeout: self.connect_timeout,
+            tcp_max_retrans_intvl: self.tcp_max_retrans_intvl,
+            reconnect_stop: self.reconnect_stop,
+            reconnect_intvl: self.reconnect_intvl,
+            reconnect_intvl_max: self.reconnect_intvl_max,
+            backlog: self.backlog,
+            max_msg_sz: self.max_msg_sz,
+            recv_timeo: self.recv_timeo,
+            send_timeo: self.send_timeo,
+            ipv6: self.ipv6,
+            immediate: self.immediate,
+            filter: self.filter,
+            invert_matching: self.invert_matching,
+            recv_routing_id: self.recv_routing_id,
+            raw_socket: self.raw_socket,
+            raw_notify: self.raw_notify,
+            socks_proxy_address: self.socks_proxy_address.clone(),
+            socks_proxy_username: self.socks_proxy_username.clone(),
+            socks_proxy_password: self.socks_proxy_password.clone(),
+            tcp_keepalive: self.tcp_keepalive,
+            tcp_keepalive_cnt: self.tcp_keepalive_cnt,
+            tcp_keepalive_idle: self.tcp_keepalive_idle,
+            tcp_keepalive_intvl: self.tcp_keepalive_intvl,
+            tcp_accept_filters: self.tcp_accept_filters.clone(),
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            ipc_uid_accept_filters: self.ipc_uid_accept_filters.clone(),
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            ipc_gid_accept_filters: self.ipc_gid_accept_filters.clone(),
+            #[cfg(target_os = "linux")]
+            ipc_pid_accept_filters: self.ipc_pid_accept_filters.clone(),
+            mechanism: self.mechanism,
+            as_server: self.as_server,
+            zap_domain: self.zap_domain.clone(),
+            plain_username: self.plain_username.clone(),
+            plain_password: self.plain_password.clone(),
+            curve_public_key: self.curve_public_key,
+            curve_secret_key: self.curve_secret_key,
+            curve_server_key: self.curve_server_key,
+            #[cfg(feature = "gssapi")]
+            gss_principal: self.gss_principal.clone(),
+            #[cfg(feature = "gssapi")]
+            gss_service_principal: self.gss_service_principal.clone(),
+            #[cfg(feature = "gssapi")]
+            gss_principal_nt: self.gss_principal_nt,
+            #[cfg(feature = "gssapi")]
+            gss_service_principal_nt: self.gss_service_principal_nt,
+            #[cfg(feature = "gssapi")]
+            gss_plaintext: self.gss_plaintext,
+            socket_id: self.socket_id,
+            conflate: self.conflate,
+            handshake_intvl: self.handshake_intvl,
+            connected: self.connected,
+            heartbeat_ttl: self.heartbeat_ttl,
+            heartbeat_intvl: self.heartbeat_intvl,
+            heartbeat_timeo: self.heartbeat_timeo,
+            #[cfg(feature = "vmci")]
+            vmci_buffer_size: self.vmci_buffer_size,
+            #[cfg(feature = "vmci")]
+            vmci_buffer_min_size: self.vmci_buffer_min_size,
+            #[cfg(feature = "vmci")]
+            vmci_buffer_max_size: self.vmci_buffer_max_size,
+            #[cfg(feature = "vmci")]
+            vmci_connect_timeout: self.vmci_connect_timeout,
+            use_fd: self.use_fd,
+            bound_device: self.bound_device.clone(),
+            zap_enforce_domain: self.zap_enforce_domain,
+            loopback_fastpath: self.loopback_fastpath,
+            multicast_loop: self.multicast_loop,
+            in_batch_size: self.in_batch_size,
+            out_batch_size: self.out_batch_size,
+            zero_copy: self.zero_copy,
+            router_notify: self.router_notify,
+            app_metadata: self.app_metadata.clone(),
+            monitor_event_version: self.monitor_event_version,
+            #[cfg(feature = "wss")]
+            wss_key_pem: self.wss_key_pem.clone(),
+            #[cfg(feature = "wss")]
+            wss_cert_pem: self.wss_cert_pem.clone(),
+            #[cfg(feature = "wss")]
+            wss_trust_pem: self.wss_trust_pem.clone(),
+            #[cfg(feature = "wss")]
+            wss_hostname: self.wss_hostname.clone(),
+            #[cfg(feature = "wss")]
+            wss_trust_system: self.wss_trust_system,
+            hello_msg: self.hello_msg.clone(),
+            can_send_hello_msg: self.can_send_hello_msg,
+            disconnect_msg: self.disconnect_msg.clone(),
+            can_recv_disconnect_msg: self.can_recv_disconnect_msg,
+            hiccup_msg: self.hiccup_msg.clone(),
+            can_recv_hiccup_msg: self.can_recv_hiccup_msg,
+            #[cfg(feature = "norm")]
+            norm_mode: self.norm_mode,
+            #[cfg(feature = "norm")]
+            norm_unicast_nacks: self.norm_unicast_nacks,
+            #[cfg(feature = "norm")]
+            norm_buffer_size: self.norm_buffer_size,
+            #[cfg(feature = "norm")]
+            norm_segment_size: self.norm_segment_size,
+            #[cfg(feature = "norm")]
+            norm_block_size: self.norm_block_size,
+            #[cfg(feature = "norm")]
+            norm_num_parity: self.norm_num_parity,
+            #[cfg(feature = "norm")]
+            norm_num_autoparity: self.norm_num_autoparity,
+            #[cfg(feature = "norm")]
+            norm_push_enable: self.norm_push_enable,
+            busy_poll: self.busy_poll,
+        }
+    }
 }
 
 impl Default for Options {
@@ -337,5 +786,227 @@ impl Default for Options {
     }
 }
 
-// Helper functions would go here
-// Convert the C++ free functions to Rust free functions or implement as associated functions
+/// Whether a socket option makes sense for the given socket type. Options
+/// not listed here apply to every socket type.
+pub fn applies_to(option: i32, socket_type: i32) -> bool {
+    let types: &[i32] = match option {
+        ZMQ_SUBSCRIBE | ZMQ_UNSUBSCRIBE => &[ZMQ_SUB, ZMQ_XSUB, ZMQ_XPUB],
+        ZMQ_XPUB_VERBOSE
+        | ZMQ_XPUB_VERBOSER
+        | ZMQ_XPUB_MANUAL
+        | ZMQ_XPUB_MANUAL_LAST_VALUE
+        | ZMQ_XPUB_WELCOME_MSG => &[ZMQ_XPUB, ZMQ_PUB],
+        ZMQ_XPUB_NODROP => &[ZMQ_XPUB, ZMQ_PUB, ZMQ_RADIO],
+        ZMQ_ONLY_FIRST_SUBSCRIBE | ZMQ_TOPICS_COUNT => &[ZMQ_XPUB, ZMQ_PUB, ZMQ_XSUB, ZMQ_SUB],
+        ZMQ_XSUB_VERBOSE_UNSUBSCRIBE => &[ZMQ_XSUB, ZMQ_SUB],
+        ZMQ_ROUTER_MANDATORY | ZMQ_ROUTER_RAW | ZMQ_ROUTER_HANDOVER | ZMQ_ROUTER_NOTIFY => {
+            &[ZMQ_ROUTER]
+        }
+        ZMQ_PROBE_ROUTER => &[ZMQ_ROUTER, ZMQ_DEALER, ZMQ_REQ],
+        ZMQ_CONNECT_ROUTING_ID => &[ZMQ_ROUTER, ZMQ_STREAM],
+        ZMQ_REQ_CORRELATE | ZMQ_REQ_RELAXED => &[ZMQ_REQ],
+        ZMQ_STREAM_NOTIFY => &[ZMQ_STREAM],
+        _ => return true,
+    };
+
+    types.contains(&socket_type)
+}
+
+/// ZMQ_CONFLATE only takes effect on the socket types it makes sense for.
+pub fn get_effective_conflate_option(options: &Options) -> bool {
+    options.conflate
+        && matches!(
+            options.socket_type,
+            ZMQ_DEALER | ZMQ_PULL | ZMQ_PUSH | ZMQ_PUB | ZMQ_SUB
+        )
+}
+
+// Helpers for decoding option values. Integer options must be passed
+// with exactly the size of an int, as in libzmq.
+
+pub(crate) fn int_value(optval: &[u8]) -> Result<i32, i32> {
+    match <[u8; mem::size_of::<i32>()]>::try_from(optval) {
+        Ok(bytes) => Ok(i32::from_ne_bytes(bytes)),
+        Err(_) => Err(libc::EINVAL),
+    }
+}
+
+pub(crate) fn int_at_least(optval: &[u8], min: i32) -> Result<i32, i32> {
+    let value = int_value(optval)?;
+    if value < min {
+        return Err(libc::EINVAL);
+    }
+    Ok(value)
+}
+
+fn i64_value(optval: &[u8]) -> Result<i64, i32> {
+    match <[u8; mem::size_of::<i64>()]>::try_from(optval) {
+        Ok(bytes) => Ok(i64::from_ne_bytes(bytes)),
+        Err(_) => Err(libc::EINVAL),
+    }
+}
+
+fn u64_value(optval: &[u8]) -> Result<u64, i32> {
+    match <[u8; mem::size_of::<u64>()]>::try_from(optval) {
+        Ok(bytes) => Ok(u64::from_ne_bytes(bytes)),
+        Err(_) => Err(libc::EINVAL),
+    }
+}
+
+// Only 0 and 1 are accepted.
+pub(crate) fn bool_strict(optval: &[u8]) -> Result<bool, i32> {
+    match int_value(optval)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(libc::EINVAL),
+    }
+}
+
+// Any non-zero value means true.
+pub(crate) fn bool_relaxed(optval: &[u8]) -> Result<bool, i32> {
+    Ok(int_value(optval)? != 0)
+}
+
+fn string_value(optval: &[u8], max_len: usize) -> Result<String, i32> {
+    if optval.len() > max_len {
+        return Err(libc::EINVAL);
+    }
+    String::from_utf8(optval.to_vec()).map_err(|_| libc::EINVAL)
+}
+
+// Keys are given either as 32 raw bytes or as 40 Z85 characters, the
+// latter optionally NUL-terminated.
+#[cfg(feature = "curve")]
+fn curve_key(optval: &[u8]) -> Result<[u8; CURVE_KEYSIZE], i32> {
+    let mut key = [0u8; CURVE_KEYSIZE];
+    match optval.len() {
+        CURVE_KEYSIZE => key.copy_from_slice(optval),
+        CURVE_KEYSIZE_Z85 | 41 => {
+            let decoded = z85_decode(&optval[..CURVE_KEYSIZE_Z85]).map_err(|_| libc::EINVAL)?;
+            key.copy_from_slice(&decoded);
+        }
+        _ => return Err(libc::EINVAL),
+    }
+    Ok(key)
+}
+
+pub(crate) fn put_int(optval: &mut [u8], value: i32) -> Result<usize, i32> {
+    if optval.len() != mem::size_of::<i32>() {
+        return Err(libc::EINVAL);
+    }
+    optval.copy_from_slice(&value.to_ne_bytes());
+    Ok(mem::size_of::<i32>())
+}
+
+pub(crate) fn put_bytes(optval: &mut [u8], value: &[u8]) -> Result<usize, i32> {
+    if optval.len() < value.len() {
+        return Err(libc::EINVAL);
+    }
+    optval[..value.len()].copy_from_slice(value);
+    Ok(value.len())
+}
+
+// Strings are returned NUL-terminated, the terminator being counted.
+pub(crate) fn put_string(optval: &mut [u8], value: &str) -> Result<usize, i32> {
+    if optval.len() < value.len() + 1 {
+        return Err(libc::EINVAL);
+    }
+    optval[..value.len()].copy_from_slice(value.as_bytes());
+    optval[value.len()] = 0;
+    Ok(value.len() + 1)
+}
+
+// The buffer size selects the encoding: 32 bytes for binary, 41 for Z85.
+#[cfg(feature = "curve")]
+fn put_curve_key(optval: &mut [u8], key: &[u8; CURVE_KEYSIZE]) -> Result<usize, i32> {
+    match optval.len() {
+        CURVE_KEYSIZE => put_bytes(optval, key),
+        len if len == CURVE_KEYSIZE_Z85 + 1 => {
+            let text = z85_encode(key).map_err(|_| libc::EINVAL)?;
+            put_string(optval, &text)
+        }
+        _ => Err(libc::EINVAL),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_int(options: &Options, option: i32) -> i32 {
+        let mut buf = [0u8; 4];
+        assert_eq!(options.getsockopt(option, &mut buf), Ok(4));
+        i32::from_ne_bytes(buf)
+    }
+
+    #[test]
+    fn test_int_options_round_trip() {
+        let mut options = Options::new();
+        options.setsockopt(ZMQ_SNDHWM, &42i32.to_ne_bytes()).unwrap();
+        options.setsockopt(ZMQ_LINGER, &(-1i32).to_ne_bytes()).unwrap();
+
+        assert_eq!(get_int(&options, ZMQ_SNDHWM), 42);
+        assert_eq!(get_int(&options, ZMQ_LINGER), -1);
+        assert_eq!(get_int(&options, ZMQ_RCVHWM), 1000);
+    }
+
+    #[test]
+    fn test_invalid_values_are_rejected() {
+        let mut options = Options::new();
+        assert_eq!(
+            options.setsockopt(ZMQ_SNDHWM, &(-1i32).to_ne_bytes()),
+            Err(libc::EINVAL)
+        );
+        // Wrong size for an integer option.
+        assert_eq!(options.setsockopt(ZMQ_RCVHWM, &[1u8]), Err(libc::EINVAL));
+        assert_eq!(
+            options.setsockopt(ZMQ_IMMEDIATE, &2i32.to_ne_bytes()),
+            Err(libc::EINVAL)
+        );
+        assert_eq!(options.setsockopt(ZMQ_ROUTING_ID, &[]), Err(libc::EINVAL));
+        assert_eq!(options.setsockopt(ZMQ_METADATA, b"Foo:bar"), Err(libc::EINVAL));
+        assert_eq!(get_int(&options, ZMQ_SNDHWM), 1000);
+    }
+
+    #[test]
+    fn test_heartbeat_ttl_is_kept_in_deciseconds() {
+        let mut options = Options::new();
+        options.setsockopt(ZMQ_HEARTBEAT_TTL, &1234i32.to_ne_bytes()).unwrap();
+        assert_eq!(get_int(&options, ZMQ_HEARTBEAT_TTL), 1200);
+    }
+
+    #[test]
+    fn test_string_options_are_nul_terminated() {
+        let mut options = Options::new();
+        options.setsockopt(ZMQ_ZAP_DOMAIN, b"global").unwrap();
+
+        let mut buf = [0xffu8; 16];
+        assert_eq!(options.getsockopt(ZMQ_ZAP_DOMAIN, &mut buf), Ok(7));
+        assert_eq!(&buf[..7], b"global\0");
+
+        let mut small = [0u8; 6];
+        assert_eq!(
+            options.getsockopt(ZMQ_ZAP_DOMAIN, &mut small),
+            Err(libc::EINVAL)
+        );
+    }
+
+    #[test]
+    fn test_plain_credentials_select_mechanism() {
+        let mut options = Options::new();
+        options.setsockopt(ZMQ_PLAIN_USERNAME, b"admin").unwrap();
+        assert_eq!(get_int(&options, ZMQ_MECHANISM), ZMQ_PLAIN);
+        assert_eq!(get_int(&options, ZMQ_PLAIN_SERVER), 0);
+
+        options.setsockopt(ZMQ_PLAIN_SERVER, &1i32.to_ne_bytes()).unwrap();
+        assert_eq!(get_int(&options, ZMQ_PLAIN_SERVER), 1);
+    }
+
+    #[test]
+    fn test_applicability() {
+        assert!(applies_to(ZMQ_SUBSCRIBE, ZMQ_SUB));
+        assert!(!applies_to(ZMQ_SUBSCRIBE, ZMQ_PUSH));
+        assert!(!applies_to(ZMQ_REQ_RELAXED, ZMQ_DEALER));
+        assert!(applies_to(ZMQ_SNDHWM, ZMQ_DEALER));
+    }
+}
@@ -1,26 +1,40 @@
-
-const ZMQ_PAIR: i32 = 0;
-const EAGAIN: i32 = 11;
-
-#[derive(Debug)]
+use crate::constants::{ZMQ_EAGAIN, ZMQ_PAIR};
+use crate::context::Context;
+use crate::message::Message;
+use crate::pipe::Pipe;
+use crate::socket_base::{SocketBase, SocketBehavior};
+use std::sync::Arc;
+
+/// PAIR socket: exclusive connection to a single peer.
 pub struct Pair {
+    base: SocketBase,
     pipe: Option<Pipe>,
-    ctx: *mut Context,
-    tid: u32,
-    sid: i32,
+    last_in: Option<Pipe>,
 }
 
 impl Pair {
-    pub fn new(parent: *mut Context, tid: u32, sid: i32) -> Self {
-        Self {
+    pub fn new(ctx: &Arc<Context>, tid: u32, sid: i32) -> Self {
+        let mut base = SocketBase::new(ctx, tid, sid, false);
+        base.options.socket_type = ZMQ_PAIR;
+
+        Pair {
+            base,
             pipe: None,
-            ctx: parent,
-            tid,
-            sid,
+            last_in: None,
         }
     }
+}
 
-    pub fn attach_pipe(&mut self, pipe: Pipe, _subscribe_to_all: bool, _locally_initiated: bool) {
+impl SocketBehavior for Pair {
+    fn base(&self) -> &SocketBase {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut SocketBase {
+        &mut self.base
+    }
+
+    fn xattach_pipe(&mut self, pipe: Pipe, _subscribe_to_all: bool, _locally_initiated: bool) {
         // ZMQ_PAIR socket can only be connected to a single peer.
         // The socket rejects any further connection requests.
         if self.pipe.is_none() {
@@ -30,110 +44,52 @@ impl Pair {
         }
     }
 
-    pub fn send(&mut self, msg: &mut Message) -> Result<(), i32> {
-        if let Some(pipe) = &mut self.pipe {
-            if !pipe.write(msg) {
-                return Err(EAGAIN);
+    fn xpipe_terminated(&mut self, pipe: &Pipe) {
+        if self.pipe.as_ref() == Some(pipe) {
+            if self.last_in.as_ref() == Some(pipe) {
+                self.last_in = None;
             }
-
-            if !msg.has_more() {
-                pipe.flush();
-            }
-
-            msg.init()?;
-            Ok(())
-        } else {
-            Err(EAGAIN)
+            self.pipe = None;
         }
     }
 
-    pub fn recv(&mut self, msg: &mut Message) -> Result<(), i32> {
-        msg.close()?;
+    fn xsend(&mut self, msg: &mut Message) -> Result<(), i32> {
+        let pipe = match &self.pipe {
+            Some(pipe) => pipe,
+            None => return Err(ZMQ_EAGAIN),
+        };
 
-        if let Some(pipe) = &mut self.pipe {
-            if !pipe.read(msg) {
-                msg.init()?;
-                return Err(EAGAIN);
-            }
-            Ok(())
-        } else {
-            msg.init()?;
-            Err(EAGAIN)
+        let more = msg.has_more();
+        if !pipe.write(msg) {
+            return Err(ZMQ_EAGAIN);
         }
-    }
-
-    pub fn has_in(&self) -> bool {
-        self.pipe.as_ref().map_or(false, |p| p.check_read())
-    }
 
-    pub fn has_out(&self) -> bool {
-        self.pipe.as_ref().map_or(false, |p| p.check_write())
-    }
-
-    pub fn read_activated(&mut self, _pipe: &Pipe) {
-        // There's just one pipe. No lists of active and inactive pipes.
-        // There's nothing to do here.
-    }
+        if !more {
+            pipe.flush();
+        }
 
-    pub fn write_activated(&mut self, _pipe: &Pipe) {
-        // There's just one pipe. No lists of active and inactive pipes.
-        // There's nothing to do here.
+        Ok(())
     }
 
-    pub fn pipe_terminated(&mut self, pipe: &Pipe) {
-        if let Some(ref p) = self.pipe {
-            if std::ptr::eq(p as *const Pipe, pipe as *const Pipe) {
-                self.pipe = None;
+    fn xrecv(&mut self, msg: &mut Message) -> Result<(), i32> {
+        match &self.pipe {
+            Some(pipe) if pipe.read(msg) => {
+                self.last_in = Some(pipe.clone());
+                Ok(())
+            }
+            _ => {
+                // Initialise the output parameter to be a 0-byte message.
+                *msg = Message::new();
+                Err(ZMQ_EAGAIN)
             }
         }
     }
-}
-
-// These are placeholder types that would need to be properly implemented
-pub struct Context;
-pub struct Pipe {
-    // pipe implementation details
-}
-pub struct Message {
-    flags: u32,
-}
 
-impl Pipe {
-    pub fn write(&mut self, _msg: &Message) -> bool {
-        unimplemented!()
+    fn xhas_in(&mut self) -> bool {
+        self.pipe.as_ref().map_or(false, |pipe| pipe.check_read())
     }
-    
-    pub fn read(&mut self, _msg: &mut Message) -> bool {
-        unimplemented!()
-    }
-    
-    pub fn flush(&mut self) {
-        unimplemented!()
-    }
-    
-    pub fn terminate(&self, _force: bool) {
-        unimplemented!()
-    }
-    
-    pub fn check_read(&self) -> bool {
-        unimplemented!()
-    }
-    
-    pub fn check_write(&self) -> bool {
-        unimplemented!()
-    }
-}
 
-impl Message {
-    pub fn init(&mut self) -> Result<(), i32> {
-        unimplemented!()
-    }
-    
-    pub fn close(&mut self) -> Result<(), i32> {
-        unimplemented!()
-    }
-    
-    pub fn has_more(&self) -> bool {
-        self.flags & 1 != 0
+    fn xhas_out(&mut self) -> bool {
+        self.pipe.as_ref().map_or(false, |pipe| pipe.check_write())
     }
 }
@@ -1,95 +1,84 @@
-use std::sync::Mutex;
-
-// Equivalent to the C++ options structure
-#[derive(Default)]
-struct Options {
-    type_: i32,
-    can_send_hello_msg: bool,
-    can_recv_disconnect_msg: bool,
-    can_recv_hiccup_msg: bool,
-    immediate: i32,
-}
-
-// Constants
-const ZMQ_PEER: i32 = 1; // Actual value may differ, check ZMQ documentation
+use crate::context::Context;
+use crate::message::Message;
+use crate::pipe::Pipe;
+use crate::server::Server;
+use crate::socket_base::{SocketBase, SocketBehavior};
+use crate::zmq_draft::ZMQ_PEER;
+use std::sync::Arc;
 
+/// PEER socket: a SERVER that can also connect out to other peers.
 pub struct Peer {
-    options: Options,
+    server: Server,
+    // Routing id of the most recently attached pipe.
     peer_last_routing_id: u32,
-    sync: Mutex<()>,
 }
 
 impl Peer {
-    pub fn new(parent: &mut Context, tid: u32, sid: i32) -> Self {
-        let mut peer = Peer {
-            options: Options {
-                type_: ZMQ_PEER,
-                can_send_hello_msg: true,
-                can_recv_disconnect_msg: true,
-                can_recv_hiccup_msg: true,
-                immediate: 0,
-                ..Default::default()
-            },
-            peer_last_routing_id: 0,
-            sync: Mutex::new(()),
-        };
+    pub fn new(ctx: &Arc<Context>, tid: u32, sid: i32) -> Self {
+        let mut server = Server::new(ctx, tid, sid);
+        let options = &mut server.base_mut().options;
+        options.socket_type = ZMQ_PEER;
+        options.can_send_hello_msg = true;
+        options.can_recv_disconnect_msg = true;
+        options.can_recv_hiccup_msg = true;
 
-        // Initialize base "server_t" equivalent here if needed
-        peer
+        Peer {
+            server,
+            peer_last_routing_id: 0,
+        }
     }
+}
 
-    pub fn connect_peer(&mut self, endpoint_uri: &str) -> u32 {
-        let _lock = self.sync.lock().unwrap();
+impl SocketBehavior for Peer {
+    fn base(&self) -> &SocketBase {
+        self.server.base()
+    }
 
-        // connect_peer cannot work with immediate enabled
-        if self.options.immediate == 1 {
-            // In Rust we would typically return a Result instead of setting errno
-            return 0;
-        }
+    fn base_mut(&mut self) -> &mut SocketBase {
+        self.server.base_mut()
+    }
 
-        if self.connect_internal(endpoint_uri).is_err() {
-            return 0;
+    fn connect_peer(&mut self, endpoint: &str) -> Result<u32, i32> {
+        // The routing id is only known once the pipe is attached, which
+        // ZMQ_IMMEDIATE would postpone until the connection is up.
+        if self.base().options.immediate == 1 {
+            return Err(libc::EFAULT);
         }
 
-        self.peer_last_routing_id
+        self.connect(endpoint)?;
+        Ok(self.peer_last_routing_id)
     }
 
-    pub fn attach_pipe(
-        &mut self,
-        pipe: &mut Pipe,
-        subscribe_to_all: bool,
-        locally_initiated: bool,
-    ) {
-        // Call equivalent of server_t::xattach_pipe first
-        self.server_attach_pipe(pipe, subscribe_to_all, locally_initiated);
+    fn xattach_pipe(&mut self, pipe: Pipe, subscribe_to_all: bool, locally_initiated: bool) {
+        self.server.xattach_pipe(pipe.clone(), subscribe_to_all, locally_initiated);
         self.peer_last_routing_id = pipe.get_server_socket_routing_id();
     }
 
-    // Helper functions that would need to be implemented
-    fn connect_internal(&self, _endpoint_uri: &str) -> Result<(), Box<dyn std::error::Error>> {
-        // Implementation needed
-        Ok(())
+    fn xpipe_terminated(&mut self, pipe: &Pipe) {
+        self.server.xpipe_terminated(pipe);
     }
 
-    fn server_attach_pipe(
-        &mut self,
-        _pipe: &mut Pipe,
-        _subscribe_to_all: bool,
-        _locally_initiated: bool,
-    ) {
-        // Implementation needed
+    fn xread_activated(&mut self, pipe: &Pipe) {
+        self.server.xread_activated(pipe);
     }
-}
 
-// These would need to be implemented
-struct Context;
-struct Pipe {
-    // Implementation needed
-}
+    fn xwrite_activated(&mut self, pipe: &Pipe) {
+        self.server.xwrite_activated(pipe);
+    }
+
+    fn xsend(&mut self, msg: &mut Message) -> Result<(), i32> {
+        self.server.xsend(msg)
+    }
+
+    fn xrecv(&mut self, msg: &mut Message) -> Result<(), i32> {
+        self.server.xrecv(msg)
+    }
+
+    fn xhas_in(&mut self) -> bool {
+        self.server.xhas_in()
+    }
 
-impl Pipe {
-    fn get_server_socket_routing_id(&self) -> u32 {
-        // Implementation needed
-        0
+    fn xhas_out(&mut self) -> bool {
+        self.server.xhas_out()
     }
 }
@@ -35,7 +35,7 @@ impl PgmSender {
             has_tx_timer: false,
             has_rx_timer: false,
             session: None,
-            encoder: V1Encoder::new(0),
+            encoder: V1Encoder::new(),
             more_flag: false,
             pgm_socket: PgmSocket::new(false, options),
             handle: Handle::null(),
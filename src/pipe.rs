@@ -1,360 +1,851 @@
 #![allow(dead_code)]
 
+//! Bidirectional pipe connecting a socket to its peer.
+//!
+//! A pipe pair consists of two `Pipe` ends sharing one queue per direction.
+//! Each end is owned by one object (a socket or a session) and reports
+//! state changes to it through its mailbox, the way pipe_t reports to its
+//! i_pipe_events sink in libzmq:
+//!
+//! * `ActivateRead` once a reader that found the pipe empty can read again,
+//! * `ActivateWrite` once a writer that hit the high water mark can write,
+//! * `PipeTerm` when the pipe is being shut down,
+//! * `Hiccup` when the connection behind the peer was re-established.
+//!
+//! Writes are staged locally and only become visible to the reader on
+//! `flush`, and only up to the last complete message, so a reader never
+//! sees half of a multipart message.
+//!
+//! Unlike libzmq, which links the ends with a lock-free ypipe, each
+//! direction is a `VecDeque` behind a mutex: the watermarks and the
+//! activation flags live next to the messages and change together with
+//! them, so both ends lock anyway.
+
+use crate::command::Command;
+use crate::i_mailbox::IMailbox;
+use crate::message::Message;
+use crate::ypipe_conflate::YPipeConflate;
 use std::collections::VecDeque;
-use std::sync::Arc;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+// Unique identity of each pipe end, used for equality and hashing.
+static NEXT_PIPE_ID: AtomicU64 = AtomicU64::new(1);
+
+// Messages waiting for the reader: all of them in order, or only the
+// latest one for conflating pipes.
+enum Msgs {
+    All(VecDeque<Message>),
+    Latest(YPipeConflate<Message>),
+}
 
-// Message type placeholder
-#[derive(Clone)]
-pub struct Msg {
-    data: Vec<u8>,
-    flags: u32,
+// Messages travelling in one direction of the pipe pair.
+struct Queue {
+    msgs: Msgs,
+    // Number of complete messages written to / read from the queue.
+    msgs_written: u64,
+    msgs_read: u64,
+    // High and low water marks in messages, 0 meaning unlimited.
+    hwm: i32,
+    lwm: i32,
+    // The reader found the queue empty and waits for activate_read.
+    reader_waiting: bool,
+    // The writer hit the high water mark and waits for activate_write.
+    writer_waiting: bool,
+    // The writing end was terminated; no more messages will arrive.
+    writer_closed: bool,
+    // The reading end was terminated; nobody will read anymore.
+    reader_closed: bool,
 }
 
-impl Msg {
-    pub fn new() -> Self {
-        Self {
-            data: Vec::new(),
-            flags: 0,
+impl Queue {
+    fn new(hwm: i32, conflate: bool) -> Self {
+        Queue {
+            msgs: if conflate {
+                Msgs::Latest(YPipeConflate::new())
+            } else {
+                Msgs::All(VecDeque::new())
+            },
+            msgs_written: 0,
+            msgs_read: 0,
+            hwm,
+            lwm: compute_lwm(hwm),
+            reader_waiting: false,
+            writer_waiting: false,
+            writer_closed: false,
+            reader_closed: false,
         }
     }
 
-    pub fn init_size(&mut self, size: usize) -> i32 {
-        self.data.resize(size, 0);
-        0
+    fn full(&self) -> bool {
+        !self.conflate() && self.hwm > 0 && self.msgs_written - self.msgs_read >= self.hwm as u64
     }
 
-    pub fn init_buffer(&mut self, data: &[u8], size: usize) -> i32 {
-        self.data = data[..size].to_vec();
-        0
+    // Keep only the last complete message (ZMQ_CONFLATE).
+    fn conflate(&self) -> bool {
+        matches!(self.msgs, Msgs::Latest(_))
     }
 
-    pub fn close(&mut self) {
-        self.data.clear();
-    }
-
-    pub fn is_delimiter(&self) -> bool {
-        (self.flags & MsgFlags::DELIMITER as u32) != 0
+    // Whether there's a message to read, and if so whether it's the
+    // delimiter.
+    fn peek(&self) -> Option<bool> {
+        match &self.msgs {
+            Msgs::All(msgs) => msgs.front().map(Message::is_delimiter),
+            Msgs::Latest(latest) => latest
+                .check_read()
+                .then(|| latest.probe(Message::is_delimiter)),
+        }
     }
 
-    pub fn is_routing_id(&self) -> bool {
-        (self.flags & MsgFlags::ROUTING_ID as u32) != 0
+    fn pop(&mut self) -> Option<Message> {
+        match &mut self.msgs {
+            Msgs::All(msgs) => msgs.pop_front(),
+            Msgs::Latest(latest) => latest.read(),
+        }
     }
 
-    pub fn is_credential(&self) -> bool {
-        (self.flags & MsgFlags::CREDENTIAL as u32) != 0
+    fn push(&mut self, msg: Message) {
+        match &mut self.msgs {
+            Msgs::All(msgs) => msgs.push_back(msg),
+            Msgs::Latest(latest) => latest.write(msg),
+        }
     }
 
-    pub fn flags(&self) -> u32 {
-        self.flags
+    fn clear(&mut self) {
+        match &mut self.msgs {
+            Msgs::All(msgs) => msgs.clear(),
+            Msgs::Latest(latest) => {
+                latest.read();
+            }
+        }
     }
 
-    pub fn set_flags(&mut self, flags: u32) {
-        self.flags = flags;
+    fn has_delimiter(&self) -> bool {
+        match &self.msgs {
+            Msgs::All(msgs) => msgs.iter().any(Message::is_delimiter),
+            Msgs::Latest(latest) => latest.probe(Message::is_delimiter),
+        }
     }
+}
 
-    pub fn init_delimiter(&mut self) {
-        self.flags = MsgFlags::DELIMITER as u32;
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // Pipe is operational.
+    Active,
+    // Peer asked for termination; we drain the inbound queue until the
+    // delimiter is read.
+    WaitingForDelimiter,
+    // Delimiter was read before the peer's termination request arrived.
+    DelimiterReceived,
+    // We asked for termination ourselves.
+    TermReqSent,
+    // Both sides are done with the pipe.
+    Terminated,
 }
 
-#[derive(Clone, Copy)]
-pub enum MsgFlags {
-    MORE = 1,
-    DELIMITER = 2,
-    ROUTING_ID = 4,
-    CREDENTIAL = 8,
+// State only ever touched by the end owning it.
+struct Local {
+    // Written but not yet flushed messages.
+    pending: Vec<Message>,
+    state: State,
+    // Whether the owner was already told the pipe is gone.
+    reported: bool,
+    // Drop the messages still in the inbound queue when the pipe is
+    // terminated instead of reading them up to the delimiter.
+    nodelay: bool,
+    // Routing id of the peer, used by ROUTER-like sockets.
+    routing_id: Vec<u8>,
+    // Integral routing id, used by SERVER-like sockets.
+    server_socket_routing_id: u32,
+    // Endpoint the pipe was created for.
+    endpoint: String,
+    // The peer's high water marks, added on top of our own for inproc
+    // pipes. -1 while unknown.
+    in_hwm_boost: i32,
+    out_hwm_boost: i32,
 }
 
-// Basic pipe traits and types
-pub trait PipeEvents {
-    fn read_activated(&mut self, pipe: &mut Pipe);
-    fn write_activated(&mut self, pipe: &mut Pipe);
-    fn hiccuped(&mut self, pipe: &mut Pipe);
-    fn pipe_terminated(&mut self, pipe: &mut Pipe);
+struct End {
+    id: u64,
+    inbound: Arc<Mutex<Queue>>,
+    outbound: Arc<Mutex<Queue>>,
+    peer: Mutex<Weak<End>>,
+    sink: Mutex<Option<Arc<dyn IMailbox>>>,
+    local: Mutex<Local>,
 }
 
+/// Handle to one end of a pipe pair. Clones refer to the same end.
 #[derive(Clone)]
-pub struct Blob {
-    data: Vec<u8>,
+pub struct Pipe {
+    end: Arc<End>,
 }
 
-impl Blob {
-    pub fn set_deep_copy(&mut self, other: &Blob) {
-        self.data = other.data.clone();
+/// Creates a pipe pair. `hwms[0]` limits the messages written by the first
+/// pipe, `hwms[1]` the ones written by the second; `conflate` works the
+/// same way.
+pub fn create_pipe_pair(conflate: [bool; 2], hwms: [i32; 2]) -> (Pipe, Pipe) {
+    let upstream = Arc::new(Mutex::new(Queue::new(hwms[0], conflate[0])));
+    let downstream = Arc::new(Mutex::new(Queue::new(hwms[1], conflate[1])));
+
+    let first = Pipe::new_end(Arc::clone(&downstream), Arc::clone(&upstream));
+    let second = Pipe::new_end(upstream, downstream);
+
+    *lock(&first.end.peer) = Arc::downgrade(&second.end);
+    *lock(&second.end.peer) = Arc::downgrade(&first.end);
+
+    (first, second)
+}
+
+fn compute_lwm(hwm: i32) -> i32 {
+    // Compute the low water mark. Following point should be taken
+    // into consideration:
+    //
+    // 1. LWM has to be less than HWM.
+    // 2. LWM cannot be set to very low value (such as zero) as after filling
+    //    the queue it would start to refill only after all the messages are
+    //    read from it and thus unnecessarily hold the progress back.
+    // 3. LWM cannot be set to very high value (such as HWM-1) as it would
+    //    result in lock-step filling of the queue - if a single message is
+    //    read from a full queue, writer thread is resumed to write exactly one
+    //    message to the queue and go back to sleep immediately. This would
+    //    result in low performance.
+    //
+    // Given the 3. it would be good to keep HWM and LWM as far apart as
+    // possible to reduce the thread switching overhead to almost zero.
+    // Let's make LWM 1/2 of HWM.
+    const MAX_WM_DELTA: i32 = 1024;
+    if hwm > MAX_WM_DELTA * 2 {
+        hwm - MAX_WM_DELTA
+    } else {
+        (hwm + 1) / 2
     }
 }
 
-#[derive(Clone)]
-pub struct EndpointUriPair {
-    local: String,
-    remote: String,
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
 }
 
-// Basic pipe implementation
-pub struct YPipe {
-    queue: VecDeque<Msg>,
-    conflate: bool,
+// Only the last single-part message survives conflation; multipart
+// messages can't be conflated and are dropped.
+fn last_single_part(msgs: impl IntoIterator<Item = Message>) -> Option<Message> {
+    let mut more = false;
+    let mut last = None;
+    for msg in msgs {
+        let has_more = msg.has_more();
+        if !more && !has_more {
+            last = Some(msg);
+        }
+        more = has_more;
+    }
+    last
 }
 
-impl YPipe {
-    pub fn new(conflate: bool) -> Self {
-        Self {
-            queue: VecDeque::new(),
-            conflate,
+impl Pipe {
+    fn new_end(inbound: Arc<Mutex<Queue>>, outbound: Arc<Mutex<Queue>>) -> Self {
+        Pipe {
+            end: Arc::new(End {
+                id: NEXT_PIPE_ID.fetch_add(1, Ordering::Relaxed),
+                inbound,
+                outbound,
+                peer: Mutex::new(Weak::new()),
+                sink: Mutex::new(None),
+                local: Mutex::new(Local {
+                    pending: Vec::new(),
+                    state: State::Active,
+                    reported: false,
+                    nodelay: false,
+                    routing_id: Vec::new(),
+                    server_socket_routing_id: 0,
+                    endpoint: String::new(),
+                    in_hwm_boost: -1,
+                    out_hwm_boost: -1,
+                }),
+            }),
         }
     }
 
-    pub fn write(&mut self, msg: Msg, more: bool) {
-        if self.conflate && !more {
-            self.queue.clear();
+    fn peer(&self) -> Option<Pipe> {
+        lock(&self.end.peer).upgrade().map(|end| Pipe { end })
+    }
+
+    // Posts a command to the object owning this end, if any.
+    fn notify(&self, cmd: Command) {
+        if let Some(sink) = lock(&self.end.sink).as_ref() {
+            sink.send(cmd);
         }
-        self.queue.push_back(msg);
     }
 
-    pub fn read(&mut self, msg: &mut Msg) -> bool {
-        if let Some(m) = self.queue.pop_front() {
-            *msg = m;
-            true
-        } else {
-            false
+    /// Specifies the mailbox of the object to send events to.
+    pub fn set_event_sink(&self, sink: Arc<dyn IMailbox>) {
+        *lock(&self.end.sink) = Some(sink);
+
+        // The peer may have gone away before we had anyone to tell.
+        if lock(&self.end.inbound).writer_closed && !lock(&self.end.local).reported {
+            self.notify(Command::PipeTerm(self.clone()));
         }
     }
 
+    /// Returns true if there is at least one message to read in the pipe.
     pub fn check_read(&self) -> bool {
-        !self.queue.is_empty()
-    }
+        let state = lock(&self.end.local).state;
+        if state != State::Active && state != State::WaitingForDelimiter {
+            return false;
+        }
 
-    pub fn probe<F>(&self, predicate: F) -> bool
-    where
-        F: Fn(&Msg) -> bool,
-    {
-        self.queue.front().map_or(false, predicate)
+        let mut queue = lock(&self.end.inbound);
+        match queue.peek() {
+            Some(true) => {
+                drop(queue);
+                self.process_delimiter();
+                false
+            }
+            Some(false) => true,
+            None => {
+                queue.reader_waiting = true;
+                false
+            }
+        }
     }
 
-    pub fn flush(&mut self) -> bool {
-        !self.queue.is_empty()
-    }
-}
+    /// Reads a message from the underlying pipe.
+    pub fn read(&self, msg: &mut Message) -> bool {
+        let state = lock(&self.end.local).state;
+        if state != State::Active && state != State::WaitingForDelimiter {
+            return false;
+        }
 
-pub struct Pipe {
-    in_pipe: Option<YPipe>,
-    out_pipe: Option<YPipe>,
-    in_active: bool,
-    out_active: bool,
-    hwm: i32,
-    lwm: i32,
-    msgs_read: u64,
-    msgs_written: u64,
-    peers_msgs_read: u64,
-    peer: Option<Arc<Pipe>>,
-    sink: Option<Box<dyn PipeEvents>>,
-    state: PipeState,
-    delay: bool,
-    router_socket_routing_id: Blob,
-    server_socket_routing_id: u32,
-    conflate: bool,
-    endpoint_pair: EndpointUriPair,
-    disconnect_msg: Msg,
-}
+        let mut queue = lock(&self.end.inbound);
+        let front = loop {
+            match queue.pop() {
+                // If this is a credential, ignore it and receive next message.
+                Some(front) if front.is_credential() => queue.msgs_read += 1,
+                Some(front) => break front,
+                None => {
+                    queue.reader_waiting = true;
+                    return false;
+                }
+            }
+        };
 
-impl Pipe {
-    pub fn new() {
-        Self {
-            in_pipe: None,
-            out_pipe: None,
-            in_active: true,
-            out_active: true,
-            hwm: 0,
-            lwm: 0,
-            msgs_read: 0,
-            msgs_written: 0,
-            peers_msgs_read: 0,
-            peer: None,
-            sink: None,
-            state: PipeState::Active,
-            delay: true,
-            router_socket_routing_id: Blob { data: Vec::new() },
-            server_socket_routing_id: 0,
-            conflate: false,
-            endpoint_pair: EndpointUriPair {
-                local: String::new(),
-                remote: String::new(),
-            },
-            disconnect_msg: Msg::new(),
+        // If delimiter was read, start termination process of the pipe.
+        if front.is_delimiter() {
+            drop(queue);
+            self.process_delimiter();
+            return false;
+        }
+
+        if !front.has_more() && !front.is_routing_id() {
+            queue.msgs_read += 1;
+        }
+
+        let activate_writer = queue.writer_waiting
+            && queue.msgs_written - queue.msgs_read <= queue.lwm as u64;
+        if activate_writer {
+            queue.writer_waiting = false;
         }
+        drop(queue);
+
+        *msg = front;
+
+        if activate_writer {
+            if let Some(peer) = self.peer() {
+                peer.notify(Command::ActivateWrite(peer.clone()));
+            }
+        }
+        true
     }
 
-    pub fn check_read(&mut self) -> bool {
-        if !self.in_active {
+    /// Checks whether messages can be written to the pipe. If the pipe is
+    /// closed or the message would cause high watermark to be exceeded the
+    /// function returns false.
+    pub fn check_write(&self) -> bool {
+        if lock(&self.end.local).state != State::Active {
+            return false;
+        }
+
+        let mut queue = lock(&self.end.outbound);
+        if queue.reader_closed {
             return false;
         }
-        if self.state != PipeState::Active && self.state != PipeState::WaitingForDelimiter {
+        if queue.full() {
+            queue.writer_waiting = true;
             return false;
         }
+        true
+    }
 
-        if let Some(in_pipe) = &mut self.in_pipe {
-            if !in_pipe.check_read() {
-                self.in_active = false;
-                return false;
-            }
+    /// Returns true if the high water mark would not be exceeded by one
+    /// more message, without arming the write activation.
+    pub fn check_hwm(&self) -> bool {
+        !lock(&self.end.outbound).full()
+    }
 
-            if in_pipe.probe(Msg::is_delimiter) {
-                let mut msg = Msg::new();
-                in_pipe.read(&mut msg);
-                self.process_delimiter();
-                return false;
-            }
+    /// Writes a message to the underlying pipe. Returns false if the message
+    /// does not pass check_write. On success the message is taken out of
+    /// `msg`, leaving an empty one behind.
+    pub fn write(&self, msg: &mut Message) -> bool {
+        if !self.check_write() {
+            return false;
         }
 
+        let more = msg.has_more();
+        let is_routing_id = msg.is_routing_id();
+        let msg = mem::take(msg);
+
+        lock(&self.end.local).pending.push(msg);
+        if !more && !is_routing_id {
+            lock(&self.end.outbound).msgs_written += 1;
+        }
         true
     }
 
-    pub fn compute_lwm(hwm: i32) -> i32 {
-        (hwm + 1) / 2
+    /// Remove unfinished parts of the outbound message from the pipe.
+    pub fn rollback(&self) {
+        let mut local = lock(&self.end.local);
+        let complete = local
+            .pending
+            .iter()
+            .rposition(|msg| !msg.has_more())
+            .map_or(0, |pos| pos + 1);
+        local.pending.truncate(complete);
     }
 
-    pub fn process_delimiter(&mut self) {
-        match self.state {
-            PipeState::Active => {
-                self.state = PipeState::DelimiterReceived;
+    /// Flush the messages downstream.
+    pub fn flush(&self) {
+        let mut local = lock(&self.end.local);
+
+        // The peer does not exist anymore at this point.
+        if local.state == State::Terminated {
+            local.pending.clear();
+            return;
+        }
+
+        let complete = local
+            .pending
+            .iter()
+            .rposition(|msg| !msg.has_more())
+            .map_or(0, |pos| pos + 1);
+        if complete == 0 {
+            return;
+        }
+        let flushed: Vec<Message> = local.pending.drain(..complete).collect();
+        drop(local);
+
+        let mut queue = lock(&self.end.outbound);
+        if queue.reader_closed {
+            return;
+        }
+        if queue.conflate() {
+            match last_single_part(flushed) {
+                Some(msg) => queue.push(msg),
+                None => return,
             }
-            PipeState::WaitingForDelimiter => {
-                self.rollback();
-                self.out_pipe = None;
-                // send_pipe_term_ack would go here
-                self.state = PipeState::TermAckSent;
+        } else {
+            for msg in flushed {
+                queue.push(msg);
             }
-            _ => {}
         }
+
+        let activate_reader = queue.reader_waiting;
+        queue.reader_waiting = false;
+        drop(queue);
+
+        if activate_reader {
+            if let Some(peer) = self.peer() {
+                peer.notify(Command::ActivateRead(peer.clone()));
+            }
+        }
+    }
+
+    /// Ask pipe to terminate. The termination will happen asynchronously
+    /// and the owner is notified about the fact through a PipeTerm command.
+    /// If `delay` is true, this end keeps reading the messages the peer
+    /// already sent before terminating; it overrides `set_nodelay`. The
+    /// peer gets the delimiter either way, and its own setting decides
+    /// whether it reads what we sent.
+    pub fn terminate(&self, delay: bool) {
+        let mut local = lock(&self.end.local);
+        local.nodelay = !delay;
+        let drain = match local.state {
+            // Termination is already under way.
+            State::TermReqSent | State::Terminated => return,
+            // There are still pending messages available; the owner reads
+            // them until the delimiter.
+            State::WaitingForDelimiter if delay => true,
+            // The peer asked first; we are acknowledging.
+            State::WaitingForDelimiter | State::DelimiterReceived => {
+                local.state = State::Terminated;
+                false
+            }
+            State::Active => {
+                local.state = State::TermReqSent;
+                false
+            }
+        };
+        local.pending.clear();
+        drop(local);
+
+        // Nobody reads from this end anymore.
+        if !drain {
+            let mut inbound = lock(&self.end.inbound);
+            inbound.reader_closed = true;
+            inbound.clear();
+        }
+
+        // Let the peer drain what was already sent, then see the delimiter.
+        // Watermarks are not checked, so it fits even into a full pipe.
+        // Like a flush, this wakes up a reader that found the pipe empty.
+        let activate_reader = {
+            let mut outbound = lock(&self.end.outbound);
+            let activate_reader = !outbound.reader_closed && outbound.reader_waiting;
+            if !outbound.reader_closed {
+                outbound.push(Message::delimiter());
+            }
+            outbound.reader_waiting = false;
+            outbound.writer_closed = true;
+            activate_reader
+        };
+
+        if let Some(peer) = self.peer() {
+            if activate_reader {
+                peer.notify(Command::ActivateRead(peer.clone()));
+            }
+            peer.notify(Command::PipeTerm(peer.clone()));
+        }
+        self.notify(Command::PipeTerm(self.clone()));
     }
 
-    pub fn rollback(&mut self) {
-        if let Some(out_pipe) = &mut self.out_pipe {
-            while let Some(msg) = out_pipe.queue.pop_back() {
-                if msg.flags() & MsgFlags::MORE as u32 != 0 {
-                    // Close message
+    /// Handles a PipeTerm command. Returns true exactly once, when the pipe
+    /// is gone and the owner has to forget about it.
+    pub fn process_pipe_term(&self) -> bool {
+        let mut local = lock(&self.end.local);
+        match local.state {
+            State::Active => {
+                // Unless this end was told not to wait, let the owner read
+                // the remaining messages first.
+                let delimited = lock(&self.end.inbound).has_delimiter();
+                if delimited && !local.nodelay {
+                    local.state = State::WaitingForDelimiter;
+                    return false;
                 }
+                local.state = State::Terminated;
+            }
+            State::WaitingForDelimiter => return false,
+            State::DelimiterReceived | State::TermReqSent | State::Terminated => {
+                local.state = State::Terminated;
+            }
+        }
+
+        if local.reported {
+            return false;
+        }
+        local.reported = true;
+        drop(local);
+
+        let mut inbound = lock(&self.end.inbound);
+        inbound.reader_closed = true;
+        inbound.clear();
+        drop(inbound);
+        lock(&self.end.outbound).writer_closed = true;
+        true
+    }
+
+    // Handler for delimiter read from the pipe.
+    fn process_delimiter(&self) {
+        let mut local = lock(&self.end.local);
+        match local.state {
+            State::Active => local.state = State::DelimiterReceived,
+            State::WaitingForDelimiter => {
+                local.state = State::Terminated;
+                drop(local);
+                self.notify(Command::PipeTerm(self.clone()));
             }
+            _ => {}
         }
     }
+
+    /// Drops the messages the peer wrote but this end hasn't read yet and
+    /// lets the peer know. The session calls this after reconnecting, so
+    /// that the socket doesn't wait for replies that will never come and
+    /// subscribers resend their subscriptions.
+    pub fn hiccup(&self) {
+        // If termination is already under way do nothing.
+        if lock(&self.end.local).state != State::Active {
+            return;
+        }
+
+        let mut inbound = lock(&self.end.inbound);
+        if inbound.writer_closed {
+            return;
+        }
+        inbound.clear();
+        inbound.msgs_read = inbound.msgs_written;
+        inbound.reader_waiting = true;
+        let activate_writer = inbound.writer_waiting;
+        inbound.writer_waiting = false;
+        drop(inbound);
+
+        if let Some(peer) = self.peer() {
+            if activate_writer {
+                peer.notify(Command::ActivateWrite(peer.clone()));
+            }
+            peer.notify(Command::Hiccup(peer.clone()));
+        }
+    }
+
+    /// Pipe endpoint can store an routing ID to be used by its clients.
+    pub fn set_router_socket_routing_id(&self, routing_id: &[u8]) {
+        lock(&self.end.local).routing_id = routing_id.to_vec();
+    }
+
+    pub fn get_routing_id(&self) -> Vec<u8> {
+        lock(&self.end.local).routing_id.clone()
+    }
+
+    /// Pipe endpoint can store an opaque ID to be used by its clients.
+    pub fn set_server_socket_routing_id(&self, routing_id: u32) {
+        lock(&self.end.local).server_socket_routing_id = routing_id;
+    }
+
+    pub fn get_server_socket_routing_id(&self) -> u32 {
+        lock(&self.end.local).server_socket_routing_id
+    }
+
+    /// Don't delay pipe termination to read the pending inbound messages,
+    /// as there is no one to receive them.
+    pub fn set_nodelay(&self) {
+        lock(&self.end.local).nodelay = true;
+    }
+
+    /// Set the high water marks, in messages, for this end's inbound and
+    /// outbound directions. The inbound mark only decides when the writer
+    /// gets reactivated; the outbound one limits our writes.
+    pub fn set_hwms(&self, inhwm: i32, outhwm: i32) {
+        let local = lock(&self.end.local);
+        let mut in_hwm = inhwm + local.in_hwm_boost.max(0);
+        let mut out_hwm = outhwm + local.out_hwm_boost.max(0);
+
+        // If either send or recv side has hwm <= 0 it means infinite so we
+        // should set hwms infinite.
+        if inhwm <= 0 || local.in_hwm_boost == 0 {
+            in_hwm = 0;
+        }
+        if outhwm <= 0 || local.out_hwm_boost == 0 {
+            out_hwm = 0;
+        }
+        drop(local);
+
+        lock(&self.end.inbound).lwm = compute_lwm(in_hwm);
+        lock(&self.end.outbound).hwm = out_hwm;
+    }
+
+    /// Set the boost to high water marks, used by inproc sockets so total
+    /// hwm are sum of connect and bind sockets watermarks.
+    pub fn set_hwms_boost(&self, inhwm: i32, outhwm: i32) {
+        let mut local = lock(&self.end.local);
+        local.in_hwm_boost = inhwm;
+        local.out_hwm_boost = outhwm;
+    }
+
+    /// Keep only the latest inbound message from now on. Used when the
+    /// reading socket conflates but the writing one doesn't, so the pipe
+    /// was created without conflation; what is queued already is reduced
+    /// the same way.
+    pub fn set_conflate(&self) {
+        let mut queue = lock(&self.end.inbound);
+        if queue.conflate() {
+            return;
+        }
+        let queued = match mem::replace(&mut queue.msgs, Msgs::Latest(YPipeConflate::new())) {
+            Msgs::All(msgs) => msgs,
+            Msgs::Latest(_) => unreachable!(),
+        };
+        if let Some(msg) = last_single_part(queued) {
+            queue.push(msg);
+        }
+
+        // Conflating pipes are never full.
+        let activate_writer = queue.writer_waiting;
+        queue.writer_waiting = false;
+        drop(queue);
+
+        if activate_writer {
+            if let Some(peer) = self.peer() {
+                peer.notify(Command::ActivateWrite(peer.clone()));
+            }
+        }
+    }
+
+    pub fn set_endpoint(&self, endpoint: &str) {
+        lock(&self.end.local).endpoint = endpoint.to_string();
+    }
+
+    pub fn get_endpoint(&self) -> String {
+        lock(&self.end.local).endpoint.clone()
+    }
+
+    /// Returns true once the pipe was terminated from either side.
+    pub fn is_terminating(&self) -> bool {
+        lock(&self.end.local).state != State::Active
+    }
 }
 
-#[derive(PartialEq)]
-enum PipeState {
-    Active,
-    DelimiterReceived,
-    WaitingForDelimiter,
-    TermAckSent,
-    TermReqSent1,
-    TermReqSent2,
+impl PartialEq for Pipe {
+    fn eq(&self, other: &Self) -> bool {
+        self.end.id == other.end.id
+    }
 }
 
-// impl Pipe {
-//     pub fn new(
-//         in_pipe: YPipe,
-//         out_pipe: YPipe,
-//         inhwm: i32,
-//         outhwm: i32,
-//         conflate: bool,
-//     ) -> Self {
-//         Self {
-//             in_pipe: Some(in_pipe),
-//             out_pipe: Some(out_pipe),
-//             in_active: true,
-//             out_active: true,
-//             hwm: outhwm,
-//             lwm: Self::compute_lwm(inhwm),
-//             msgs_read: 0,
-//             msgs_written: 0,
-//             peers_msgs_read: 0,
-//             peer: None,
-//             sink: None,
-//             state: PipeState::Active,
-//             delay: true,
-//             router_socket_routing_id: Blob { data: Vec::new() },
-//             server_socket_routing_id: 0,
-//             conflate,
-//             endpoint_pair: EndpointUriPair {
-//                 local: String::new(),
-//                 remote: String::new(),
-//             },
-//             disconnect_msg: Msg::new(),
-//         }
-//     }
-
-//     pub fn set_peer(&mut self, peer: Arc<Pipe>) {
-//         self.peer = Some(peer);
-//     }
-
-//     pub fn check_read(&mut self) -> bool {
-//         if !self.in_active {
-//             return false;
-//         }
-//         if self.state != PipeState::Active && self.state != PipeState::WaitingForDelimiter {
-//             return false;
-//         }
-
-//         if let Some(in_pipe) = &mut self.in_pipe {
-//             if !in_pipe.check_read() {
-//                 self.in_active = false;
-//                 return false;
-//             }
-
-//             if in_pipe.probe(Msg::is_delimiter) {
-//                 let mut msg = Msg::new();
-//                 in_pipe.read(&mut msg);
-//                 self.process_delimiter();
-//                 return false;
-//             }
-//         }
-
-//         true
-//     }
-
-//     fn compute_lwm(hwm: i32) -> i32 {
-//         (hwm + 1) / 2
-//     }
-
-//     fn process_delimiter(&mut self) {
-//         match self.state {
-//             PipeState::Active => {
-//                 self.state = PipeState::DelimiterReceived;
-//             }
-//             PipeState::WaitingForDelimiter => {
-//                 self.rollback();
-//                 self.out_pipe = None;
-//                 // send_pipe_term_ack would go here
-//                 self.state = PipeState::TermAckSent;
-//             }
-//             _ => {}
-//         }
-//     }
-
-//     fn rollback(&mut self) {
-//         if let Some(out_pipe) = &mut self.out_pipe {
-//             while let Some(msg) = out_pipe.queue.pop_back() {
-//                 if msg.flags() & MsgFlags::MORE as u32 != 0 {
-//                     // Close message
-//                 }
-//             }
-//         }
-//     }
-// }
-
-// Create a pair of pipes for bidirectional communication
-pub fn create_pipe_pair(conflate: [bool; 2], hwms: [i32; 2]) -> (Pipe, Pipe) {
-    let pipe1 = YPipe::new(conflate[0]);
-    let pipe2 = YPipe::new(conflate[1]);
+impl Eq for Pipe {}
+
+impl Hash for Pipe {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.end.id.hash(state);
+    }
+}
+
+impl fmt::Debug for Pipe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pipe").field("id", &self.end.id).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::MsgFlags;
+
+    fn msg(data: &[u8], more: bool) -> Message {
+        let mut msg = Message::with_data(data).unwrap();
+        if more {
+            msg.set_flags(MsgFlags::More);
+        }
+        msg
+    }
 
-    let mut pipe_a = Pipe::new(pipe1, pipe2.clone(), hwms[0], hwms[1], conflate[0]);
+    #[test]
+    fn test_write_flush_read() {
+        let (a, b) = create_pipe_pair([false, false], [0, 0]);
+        assert!(!b.check_read());
 
-    let mut pipe_b = Pipe::new(pipe2, pipe1.clone(), hwms[1], hwms[0], conflate[1]);
+        assert!(a.write(&mut msg(b"hello", false)));
+        // Not visible before flush.
+        assert!(!b.check_read());
+        a.flush();
 
-    let pipe_a_arc = Arc::new(pipe_a.clone());
-    let pipe_b_arc = Arc::new(pipe_b.clone());
+        let mut out = Message::new();
+        assert!(b.read(&mut out));
+        assert_eq!(out.data(), b"hello");
+        assert!(!b.read(&mut out));
+    }
+
+    #[test]
+    fn test_partial_multipart_is_not_flushed() {
+        let (a, b) = create_pipe_pair([false, false], [0, 0]);
+        assert!(a.write(&mut msg(b"part", true)));
+        a.flush();
+        assert!(!b.check_read());
+
+        a.rollback();
+        assert!(a.write(&mut msg(b"whole", false)));
+        a.flush();
 
-    pipe_a.set_peer(pipe_b_arc);
-    pipe_b.set_peer(pipe_a_arc);
+        let mut out = Message::new();
+        assert!(b.read(&mut out));
+        assert_eq!(out.data(), b"whole");
+    }
+
+    #[test]
+    fn test_hwm() {
+        let (a, b) = create_pipe_pair([false, false], [2, 2]);
+        assert!(a.write(&mut msg(b"1", false)));
+        assert!(a.write(&mut msg(b"2", false)));
+        assert!(!a.check_write());
+        a.flush();
+
+        let mut out = Message::new();
+        assert!(b.read(&mut out));
+        assert!(a.check_write());
+    }
 
-    (pipe_a, pipe_b)
+    #[test]
+    fn test_conflate_keeps_last() {
+        let (a, b) = create_pipe_pair([true, false], [0, 0]);
+        for i in 0..5u8 {
+            assert!(a.write(&mut msg(&[i], false)));
+            a.flush();
+        }
+
+        let mut out = Message::new();
+        assert!(b.read(&mut out));
+        assert_eq!(out.data(), &[4]);
+        assert!(!b.read(&mut out));
+    }
+
+    #[test]
+    fn test_conflate_drops_multipart() {
+        let (a, b) = create_pipe_pair([true, false], [0, 0]);
+        assert!(a.write(&mut msg(b"single", false)));
+        assert!(a.write(&mut msg(b"head", true)));
+        assert!(a.write(&mut msg(b"tail", false)));
+        a.flush();
+
+        let mut out = Message::new();
+        assert!(b.read(&mut out));
+        assert_eq!(out.data(), b"single");
+        assert!(!b.read(&mut out));
+    }
+
+    #[test]
+    fn test_terminate_delivers_pending_messages() {
+        let (a, b) = create_pipe_pair([false, false], [0, 0]);
+        assert!(a.write(&mut msg(b"last", false)));
+        a.flush();
+        a.terminate(true);
+        assert!(a.process_pipe_term());
+
+        // Peer still reads the message sent before termination.
+        assert!(!b.process_pipe_term());
+        let mut out = Message::new();
+        assert!(b.read(&mut out));
+        assert_eq!(out.data(), b"last");
+        assert!(!b.read(&mut out));
+        assert!(b.process_pipe_term());
+        assert!(!b.process_pipe_term());
+    }
+
+    #[test]
+    fn test_nodelay_writer_still_sends_delimiter() {
+        // PUSH, PUB and the like don't wait for their own inbound messages,
+        // but the peer still reads what they sent.
+        let (a, b) = create_pipe_pair([false, false], [0, 0]);
+        a.set_nodelay();
+        assert!(a.write(&mut msg(b"last", false)));
+        a.flush();
+        a.terminate(false);
+        assert!(a.process_pipe_term());
+
+        assert!(!b.process_pipe_term());
+        let mut out = Message::new();
+        assert!(b.read(&mut out));
+        assert_eq!(out.data(), b"last");
+        assert!(!b.read(&mut out));
+        assert!(b.process_pipe_term());
+    }
+
+    #[test]
+    fn test_nodelay_reader_drops_pending_messages() {
+        let (a, b) = create_pipe_pair([false, false], [0, 0]);
+        b.set_nodelay();
+        assert!(a.write(&mut msg(b"dropped", false)));
+        a.flush();
+        a.terminate(true);
+
+        assert!(b.process_pipe_term());
+        let mut out = Message::new();
+        assert!(!b.read(&mut out));
+    }
 }
@@ -1,29 +1,14 @@
-use crate::{
-    constants::{
-        ZMQ_PROTOCOL_ERROR_ZMTP_INVALID_METADATA, ZMQ_PROTOCOL_ERROR_ZMTP_MALFORMED_COMMAND_ERROR,
-        ZMQ_PROTOCOL_ERROR_ZMTP_MALFORMED_COMMAND_WELCOME,
-        ZMQ_PROTOCOL_ERROR_ZMTP_UNEXPECTED_COMMAND,
-    },
-    mechanism::Status,
-    message::Message,
-    options::Options,
-    session_base::SessionBase,
+use crate::constants::ZMQ_EPROTO;
+use crate::mechanism::{Mechanism, MechanismOps, Status};
+use crate::message::Message;
+use crate::options::Options;
+use crate::plain_common::plain::{
+    BRIEF_LEN_SIZE, ERROR_PREFIX, ERROR_PREFIX_LEN, HELLO_PREFIX, INITIATE_PREFIX, READY_PREFIX,
+    READY_PREFIX_LEN, WELCOME_PREFIX, WELCOME_PREFIX_LEN,
 };
+use crate::session_base::SessionBase;
 
-// Constants from plain_common
-const WELCOME_PREFIX: &[u8] = b"WELCOME";
-const WELCOME_PREFIX_LEN: usize = WELCOME_PREFIX.len();
-const READY_PREFIX: &[u8] = b"READY";
-const READY_PREFIX_LEN: usize = READY_PREFIX.len();
-const ERROR_PREFIX: &[u8] = b"ERROR";
-const ERROR_PREFIX_LEN: usize = ERROR_PREFIX.len();
-const HELLO_PREFIX: &[u8] = b"HELLO";
-const HELLO_PREFIX_LEN: usize = HELLO_PREFIX.len();
-const INITIATE_PREFIX: &[u8] = b"INITIATE";
-const INITIATE_PREFIX_LEN: usize = INITIATE_PREFIX.len();
-const BRIEF_LEN_SIZE: usize = 1;
-
-#[derive(PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum State {
     SendingHello,
     WaitingForWelcome,
@@ -33,172 +18,121 @@ enum State {
     Ready,
 }
 
+/// The client side of the PLAIN security mechanism (RFC 24): the username
+/// and password go to the server in clear text.
 pub struct PlainClient {
+    base: Mechanism,
     state: State,
-    options: Options,
-    session: Box<dyn SessionBase>,
 }
 
 impl PlainClient {
-    pub fn new(session: SessionBase, options: Options) -> Self {
+    pub fn new(options: &Options) -> Self {
         PlainClient {
+            base: Mechanism::new(options),
             state: State::SendingHello,
-            options,
-            session,
-        }
-    }
-
-    pub fn next_handshake_command(&mut self, msg: &mut Message) -> Result<(), i32> {
-        match self.state {
-            State::SendingHello => {
-                self.produce_hello(msg)?;
-                self.state = State::WaitingForWelcome;
-                Ok(())
-            }
-            State::SendingInitiate => {
-                self.produce_initiate(msg)?;
-                self.state = State::WaitingForReady;
-                Ok(())
-            }
-            _ => Err(libc::EAGAIN),
-        }
-    }
-
-    pub fn process_handshake_command(&mut self, msg: &mut Message) -> Result<(), i32> {
-        let data = msg.data();
-
-        let result = if data.len() >= WELCOME_PREFIX_LEN && data.starts_with(WELCOME_PREFIX) {
-            self.process_welcome(data)
-        } else if data.len() >= READY_PREFIX_LEN && data.starts_with(READY_PREFIX) {
-            self.process_ready(&data[READY_PREFIX_LEN..])
-        } else if data.len() >= ERROR_PREFIX_LEN && data.starts_with(ERROR_PREFIX) {
-            self.process_error(&data[ERROR_PREFIX_LEN..])
-        } else {
-            self.session.get_socket().event_handshake_failed_protocol(
-                self.session.get_endpoint(),
-                ZMQ_PROTOCOL_ERROR_ZMTP_UNEXPECTED_COMMAND,
-            );
-            Err(libc::EPROTO)
-        };
-
-        if result.is_ok() {
-            msg.close()?;
-            msg.init()?;
-        }
-
-        result
-    }
-
-    pub fn status(&self) -> Status {
-        match self.state {
-            State::Ready => Status::Ready,
-            State::ErrorCommandReceived => Status::Error,
-            _ => Status::Handshaking,
         }
     }
 
     fn produce_hello(&self, msg: &mut Message) -> Result<(), i32> {
-        let username = &self.options.plain_username;
-        let password = &self.options.plain_password;
-
-        assert!(username.len() <= u8::MAX as usize);
-        assert!(password.len() <= u8::MAX as usize);
-
-        let command_size =
-            HELLO_PREFIX_LEN + BRIEF_LEN_SIZE + username.len() + BRIEF_LEN_SIZE + password.len();
-
-        msg.init_size(command_size)?;
-        let mut data = Vec::with_capacity(command_size);
-
-        data.extend_from_slice(HELLO_PREFIX);
-        data.push(username.len() as u8);
-        data.extend_from_slice(username.as_bytes());
-        data.push(password.len() as u8);
-        data.extend_from_slice(password.as_bytes());
-
-        msg.set_data(data);
+        let username = self.base.options.plain_username.as_bytes();
+        let password = self.base.options.plain_password.as_bytes();
+        debug_assert!(username.len() <= u8::MAX as usize);
+        debug_assert!(password.len() <= u8::MAX as usize);
+
+        let mut hello = HELLO_PREFIX.to_vec();
+        hello.push(username.len() as u8);
+        hello.extend_from_slice(username);
+        hello.push(password.len() as u8);
+        hello.extend_from_slice(password);
+        *msg = Message::with_data(&hello).map_err(|_| libc::ENOMEM)?;
         Ok(())
     }
 
-    fn produce_initiate(&self, msg: &mut Message) -> Result<(), i32> {
-        self.make_command_with_basic_properties(msg, INITIATE_PREFIX)
-    }
-
     fn process_welcome(&mut self, data: &[u8]) -> Result<(), i32> {
-        if self.state != State::WaitingForWelcome {
-            self.session.get_socket().event_handshake_failed_protocol(
-                self.session.get_endpoint(),
-                ZMQ_PROTOCOL_ERROR_ZMTP_UNEXPECTED_COMMAND,
-            );
-            return Err(libc::EPROTO);
-        }
-
-        if data.len() != WELCOME_PREFIX_LEN {
-            self.session.get_socket().event_handshake_failed_protocol(
-                self.session.get_endpoint(),
-                ZMQ_PROTOCOL_ERROR_ZMTP_MALFORMED_COMMAND_WELCOME,
-            );
-            return Err(libc::EPROTO);
+        if self.state != State::WaitingForWelcome || data.len() != WELCOME_PREFIX_LEN {
+            return Err(ZMQ_EPROTO);
         }
-
         self.state = State::SendingInitiate;
         Ok(())
     }
 
     fn process_ready(&mut self, data: &[u8]) -> Result<(), i32> {
         if self.state != State::WaitingForReady {
-            self.session.get_socket().event_handshake_failed_protocol(
-                self.session.get_endpoint(),
-                ZMQ_PROTOCOL_ERROR_ZMTP_UNEXPECTED_COMMAND,
-            );
-            return Err(libc::EPROTO);
-        }
-
-        match self.parse_metadata(data) {
-            Ok(_) => {
-                self.state = State::Ready;
-                Ok(())
-            }
-            Err(e) => {
-                self.session.get_socket().event_handshake_failed_protocol(
-                    self.session.get_endpoint(),
-                    ZMQ_PROTOCOL_ERROR_ZMTP_INVALID_METADATA,
-                );
-                Err(e)
-            }
+            return Err(ZMQ_EPROTO);
         }
+        self.base.parse_metadata(&data[READY_PREFIX_LEN..], false)?;
+        self.state = State::Ready;
+        Ok(())
     }
 
     fn process_error(&mut self, data: &[u8]) -> Result<(), i32> {
         if self.state != State::WaitingForWelcome && self.state != State::WaitingForReady {
-            self.session.get_socket().event_handshake_failed_protocol(
-                self.session.get_endpoint(),
-                ZMQ_PROTOCOL_ERROR_ZMTP_UNEXPECTED_COMMAND,
-            );
-            return Err(libc::EPROTO);
+            return Err(ZMQ_EPROTO);
         }
-
-        if data.len() < BRIEF_LEN_SIZE {
-            self.session.get_socket().event_handshake_failed_protocol(
-                self.session.get_endpoint(),
-                ZMQ_PROTOCOL_ERROR_ZMTP_MALFORMED_COMMAND_ERROR,
-            );
-            return Err(libc::EPROTO);
+        let fixed_prefix_size = ERROR_PREFIX_LEN + BRIEF_LEN_SIZE;
+        if data.len() < fixed_prefix_size {
+            return Err(ZMQ_EPROTO);
         }
-
-        let error_reason_len = data[0] as usize;
-        if error_reason_len > data.len() - BRIEF_LEN_SIZE {
-            self.session.get_socket().event_handshake_failed_protocol(
-                self.session.get_endpoint(),
-                ZMQ_PROTOCOL_ERROR_ZMTP_MALFORMED_COMMAND_ERROR,
-            );
-            return Err(libc::EPROTO);
+        let error_reason_len = data[ERROR_PREFIX_LEN] as usize;
+        if error_reason_len > data.len() - fixed_prefix_size {
+            return Err(ZMQ_EPROTO);
         }
+        self.state = State::ErrorCommandReceived;
+        Ok(())
+    }
+}
 
-        let error_reason = &data[BRIEF_LEN_SIZE..BRIEF_LEN_SIZE + error_reason_len];
-        self.handle_error_reason(error_reason)?;
+impl MechanismOps for PlainClient {
+    fn base(&self) -> &Mechanism {
+        &self.base
+    }
 
-        self.state = State::ErrorCommandReceived;
+    fn base_mut(&mut self) -> &mut Mechanism {
+        &mut self.base
+    }
+
+    fn next_handshake_command(
+        &mut self,
+        _session: &mut SessionBase,
+        msg: &mut Message,
+    ) -> Result<(), i32> {
+        match self.state {
+            State::SendingHello => {
+                self.produce_hello(msg)?;
+                self.state = State::WaitingForWelcome;
+            }
+            State::SendingInitiate => {
+                self.base
+                    .make_command_with_basic_properties(msg, INITIATE_PREFIX)?;
+                self.state = State::WaitingForReady;
+            }
+            _ => return Err(libc::EAGAIN),
+        }
         Ok(())
     }
+
+    fn process_handshake_command(
+        &mut self,
+        _session: &mut SessionBase,
+        msg: &mut Message,
+    ) -> Result<(), i32> {
+        let data = msg.data();
+        if data.starts_with(WELCOME_PREFIX) {
+            self.process_welcome(data)
+        } else if data.starts_with(READY_PREFIX) {
+            self.process_ready(data)
+        } else if data.starts_with(ERROR_PREFIX) {
+            self.process_error(data)
+        } else {
+            Err(ZMQ_EPROTO)
+        }
+    }
+
+    fn status(&self) -> Status {
+        match self.state {
+            State::Ready => Status::Ready,
+            State::ErrorCommandReceived => Status::Error,
+            _ => Status::Handshaking,
+        }
+    }
 }
@@ -1,197 +1,144 @@
-use std::string::String;
-
-use crate::{
-    constants::{
-        ZMQ_PROTOCOL_ERROR_ZMTP_MALFORMED_COMMAND_HELLO,
-        ZMQ_PROTOCOL_ERROR_ZMTP_UNEXPECTED_COMMAND, ZMQ_PROTOCOL_ERROR_ZMTP_UNSPECIFIED,
-    },
-    err::ZmqError,
-    message::Message,
-    options::Options,
-    session_base::SessionBase,
+use crate::constants::ZMQ_EPROTO;
+use crate::mechanism::{Mechanism, MechanismOps, Status};
+use crate::mechanism_base::check_basic_command_structure;
+use crate::message::Message;
+use crate::options::Options;
+use crate::plain_common::plain::{
+    BRIEF_LEN_SIZE, HELLO_PREFIX, HELLO_PREFIX_LEN, INITIATE_PREFIX, INITIATE_PREFIX_LEN,
+    READY_PREFIX, WELCOME_PREFIX,
 };
+use crate::session_base::SessionBase;
+use crate::zap_client::{State, ZapClientCommonHandshake};
 
-// State enum to replace the implicit state machine
-#[derive(PartialEq)]
-enum State {
-    SendingWelcome,
-    WaitingForHello,
-    WaitingForInitiate,
-    SendingReady,
-    SendingError,
-    Ready,
-    ErrorSent,
-    WaitingForZapReply,
-}
-
-// Constants for message prefixes
-const HELLO_PREFIX: &[u8] = b"HELLO";
-const WELCOME_PREFIX: &[u8] = b"WELCOME";
-const INITIATE_PREFIX: &[u8] = b"INITIATE";
-const READY_PREFIX: &[u8] = b"READY";
-const ERROR_PREFIX: &[u8] = b"ERROR";
-
+/// The server side of the PLAIN security mechanism (RFC 24). There is no
+/// point to PLAIN without a ZAP handler checking the username and
+/// password, so a client is only let in if the handler approves it.
 pub struct PlainServer {
-    state: State,
-    session: Box<dyn SessionBase>,
-    peer_address: String,
-    options: Options,
-    status_code: String,
+    base: Mechanism,
+    handshake: ZapClientCommonHandshake,
 }
 
 impl PlainServer {
-    pub fn new(session: SessionBase, peer_address: String, options: Options) -> Self {
-        // Assert ZAP requirements if enforce_domain is set
-        if options.zap_enforce_domain {
-            assert!(Self::zap_required(&options));
-        }
-
+    pub fn new(options: &Options, peer_address: &str) -> Self {
         PlainServer {
-            state: State::SendingWelcome,
-            session,
-            peer_address,
-            options,
-            status_code: String::new(),
+            base: Mechanism::new(options),
+            handshake: ZapClientCommonHandshake::new(peer_address, State::SendingWelcome),
         }
     }
 
-    pub fn next_handshake_command(&mut self, msg: &mut Message) -> Result<(), ZmqError> {
-        match self.state {
-            State::SendingWelcome => {
-                self.produce_welcome(msg);
-                self.state = State::WaitingForInitiate;
-                Ok(())
-            }
-            State::SendingReady => {
-                self.produce_ready(msg);
-                self.state = State::Ready;
-                Ok(())
-            }
-            State::SendingError => {
-                self.produce_error(msg);
-                self.state = State::ErrorSent;
-                Ok(())
-            }
-            _ => Err(ZmqError::Again),
-        }
-    }
-
-    pub fn process_handshake_command(&mut self, msg: &mut Message) -> Result<(), ZmqError> {
-        match self.state {
-            State::WaitingForHello => self.process_hello(msg),
-            State::WaitingForInitiate => self.process_initiate(msg),
-            _ => {
-                self.session.get_socket().event_handshake_failed_protocol(
-                    &self.session.get_endpoint(),
-                    ZMQ_PROTOCOL_ERROR_ZMTP_UNSPECIFIED,
-                );
-                Err(ZmqError::Protocol)
-            }
-        }?;
-
-        msg.close()?;
-        msg.init()?;
-        Ok(())
-    }
-
-    fn process_hello(&mut self, msg: &Message) -> Result<(), ZmqError> {
-        self.check_basic_command_structure(msg)?;
+    fn process_hello(&mut self, session: &mut SessionBase, msg: &Message) -> Result<(), i32> {
+        check_basic_command_structure(msg)?;
 
         let data = msg.data();
-        if data.len() < HELLO_PREFIX.len() || !data.starts_with(HELLO_PREFIX) {
-            self.session.get_socket().event_handshake_failed_protocol(
-                &self.session.get_endpoint(),
-                ZMQ_PROTOCOL_ERROR_ZMTP_UNEXPECTED_COMMAND,
-            );
-            return Err(ZmqError::Protocol);
+        if !data.starts_with(HELLO_PREFIX) {
+            return Err(ZMQ_EPROTO);
         }
+        let mut ptr = &data[HELLO_PREFIX_LEN..];
 
-        let mut pos = HELLO_PREFIX.len();
-
-        // Extract username
-        if pos >= data.len() {
-            self.session.get_socket().event_handshake_failed_protocol(
-                &self.session.get_endpoint(),
-                ZMQ_PROTOCOL_ERROR_ZMTP_MALFORMED_COMMAND_HELLO,
-            );
-            return Err(ZmqError::Protocol);
+        if ptr.len() < BRIEF_LEN_SIZE {
+            return Err(ZMQ_EPROTO);
         }
-
-        let username_len = data[pos] as usize;
-        pos += 1;
-
-        if pos + username_len > data.len() {
-            self.session.get_socket().event_handshake_failed_protocol(
-                &self.session.get_endpoint(),
-                ZMQ_PROTOCOL_ERROR_ZMTP_MALFORMED_COMMAND_HELLO,
-            );
-            return Err(ZmqError::Protocol);
+        let username_length = ptr[0] as usize;
+        ptr = &ptr[BRIEF_LEN_SIZE..];
+        if ptr.len() < username_length {
+            return Err(ZMQ_EPROTO);
         }
+        let username = &ptr[..username_length];
+        ptr = &ptr[username_length..];
 
-        let username = String::from_utf8_lossy(&data[pos..pos + username_len]).to_string();
-        pos += username_len;
-
-        // Extract password
-        if pos >= data.len() {
-            self.session.get_socket().event_handshake_failed_protocol(
-                &self.session.get_endpoint(),
-                ZMQ_PROTOCOL_ERROR_ZMTP_MALFORMED_COMMAND_HELLO,
-            );
-            return Err(ZmqError::Protocol);
+        if ptr.len() < BRIEF_LEN_SIZE {
+            return Err(ZMQ_EPROTO);
         }
-
-        let password_len = data[pos] as usize;
-        pos += 1;
-
-        if pos + password_len != data.len() {
-            self.session.get_socket().event_handshake_failed_protocol(
-                &self.session.get_endpoint(),
-                ZMQ_PROTOCOL_ERROR_ZMTP_MALFORMED_COMMAND_HELLO,
-            );
-            return Err(ZmqError::Protocol);
+        let password_length = ptr[0] as usize;
+        ptr = &ptr[BRIEF_LEN_SIZE..];
+        if ptr.len() != password_length {
+            return Err(ZMQ_EPROTO);
         }
+        let password = ptr;
 
-        let password = String::from_utf8_lossy(&data[pos..pos + password_len]).to_string();
-
-        self.session.zap_connect()?;
-        self.send_zap_request(&username, &password);
-        self.state = State::WaitingForZapReply;
-
-        self.receive_and_process_zap_reply()
+        // Use ZAP protocol (RFC 27) to authenticate the user.
+        session.zap_connect()?;
+        self.handshake.zap_client.send_zap_request(
+            &self.base,
+            session,
+            b"PLAIN",
+            &[username, password],
+        )?;
+        self.handshake.state = State::WaitingForZapReply;
+
+        // The reply is hardly there yet, but trying to read it makes the
+        // pipe tell us when it arrives.
+        self.handshake
+            .receive_and_process_zap_reply(&mut self.base, session)
     }
 
-    fn produce_welcome(&self, msg: &mut Message) {
-        msg.init_size(WELCOME_PREFIX.len())
-            .expect("Failed to initialize welcome message");
-        msg.copy_from_slice(WELCOME_PREFIX);
+    fn produce_welcome(&self, msg: &mut Message) -> Result<(), i32> {
+        *msg = Message::with_data(WELCOME_PREFIX).map_err(|_| libc::ENOMEM)?;
+        Ok(())
     }
 
-    fn produce_ready(&self, msg: &mut Message) {
-        self.make_command_with_basic_properties(msg, READY_PREFIX);
+    fn process_initiate(&mut self, msg: &Message) -> Result<(), i32> {
+        let data = msg.data();
+        if !data.starts_with(INITIATE_PREFIX) {
+            return Err(ZMQ_EPROTO);
+        }
+        self.base
+            .parse_metadata(&data[INITIATE_PREFIX_LEN..], false)?;
+        self.handshake.state = State::SendingReady;
+        Ok(())
     }
+}
 
-    fn produce_error(&self, msg: &mut Message) {
-        const EXPECTED_STATUS_CODE_LEN: u8 = 3;
-        assert_eq!(self.status_code.len(), EXPECTED_STATUS_CODE_LEN as usize);
-
-        msg.init_size(ERROR_PREFIX.len() + 1 + EXPECTED_STATUS_CODE_LEN as usize)
-            .expect("Failed to initialize error message");
+impl MechanismOps for PlainServer {
+    fn base(&self) -> &Mechanism {
+        &self.base
+    }
 
-        let mut data =
-            Vec::with_capacity(ERROR_PREFIX.len() + 1 + EXPECTED_STATUS_CODE_LEN as usize);
-        data.extend_from_slice(ERROR_PREFIX);
-        data.push(EXPECTED_STATUS_CODE_LEN);
-        data.extend_from_slice(self.status_code.as_bytes());
+    fn base_mut(&mut self) -> &mut Mechanism {
+        &mut self.base
+    }
 
-        msg.copy_from_slice(&data);
+    fn next_handshake_command(
+        &mut self,
+        _session: &mut SessionBase,
+        msg: &mut Message,
+    ) -> Result<(), i32> {
+        match self.handshake.state {
+            State::SendingWelcome => {
+                self.produce_welcome(msg)?;
+                self.handshake.state = State::WaitingForInitiate;
+            }
+            State::SendingReady => {
+                self.base
+                    .make_command_with_basic_properties(msg, READY_PREFIX)?;
+                self.handshake.state = State::Ready;
+            }
+            State::SendingError => {
+                self.handshake.produce_error(msg)?;
+                self.handshake.state = State::ErrorSent;
+            }
+            _ => return Err(libc::EAGAIN),
+        }
+        Ok(())
     }
 
-    fn send_zap_request(&self, username: &str, password: &str) {
-        let credentials = [username.as_bytes(), password.as_bytes()];
-        let credentials_sizes = [username.len(), password.len()];
+    fn process_handshake_command(
+        &mut self,
+        session: &mut SessionBase,
+        msg: &mut Message,
+    ) -> Result<(), i32> {
+        match self.handshake.state {
+            State::WaitingForHello => self.process_hello(session, msg),
+            State::WaitingForInitiate => self.process_initiate(msg),
+            _ => Err(ZMQ_EPROTO),
+        }
+    }
 
-        self.zap_client_send_request("PLAIN", &credentials, &credentials_sizes);
+    fn zap_msg_available(&mut self, session: &mut SessionBase) -> Result<(), i32> {
+        self.handshake.zap_msg_available(&mut self.base, session)
     }
 
-    // Helper functions would be implemented here...
+    fn status(&self) -> Status {
+        self.handshake.status()
+    }
 }
@@ -1,155 +1,296 @@
+//! File descriptor and timer bookkeeping of an I/O thread.
+//!
+//! libzmq's poller_base_t keeps the timers and leaves polling to the
+//! platform specific subclasses. Here a single poll(2) based poller is
+//! used everywhere, and the objects registering file descriptors and
+//! timers are referred to by their `ObjectId` rather than by pointer.
+
 use std::collections::BTreeMap;
-use std::sync::atomic::{AtomicI32, Ordering};
-use std::thread::{self, JoinHandle};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-// Forward declaration of trait that must be implemented elsewhere
-pub trait IPollEvents {
-    fn timer_event(&mut self, id: i32);
-}
+use crate::i_mailbox::IMailbox;
+use crate::io_object::{IoObject, ObjectId, ObjectMailbox};
+use crate::io_thread::IoThread;
 
-struct TimerInfo {
-    sink: Box<dyn IPollEvents>,
-    id: i32,
-}
+/// Identifies a file descriptor registered with the poller.
+pub type Handle = usize;
 
-pub struct Clock {
-    // Implementation details would go here
+struct FdEntry {
+    fd: RawFd,
+    owner: ObjectId,
+    events: libc::c_short,
 }
 
-impl Clock {
-    pub fn now_ms(&self) -> u64 {
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as u64
-    }
+struct TimerInfo {
+    owner: ObjectId,
+    id: i32,
 }
 
 pub struct PollerBase {
-    clock: Clock,
-    timers: BTreeMap<u64, TimerInfo>,
-    load: AtomicI32,
+    // Registered file descriptors, indexed by handle.
+    fds: Vec<Option<FdEntry>>,
+    // Handles removed during the current iteration. They are only reused
+    // once the events collected for them were dispatched.
+    retired: Vec<Handle>,
+    free: Vec<Handle>,
+    // Timers ordered by expiration; the sequence number keeps timers
+    // expiring at the same instant apart.
+    timers: BTreeMap<(Instant, u64), TimerInfo>,
+    timer_seq: u64,
 }
 
 impl PollerBase {
     pub fn new() -> Self {
         PollerBase {
-            clock: Clock {},
+            fds: Vec::new(),
+            retired: Vec::new(),
+            free: Vec::new(),
             timers: BTreeMap::new(),
-            load: AtomicI32::new(0),
+            timer_seq: 0,
         }
     }
 
+    /// Number of file descriptors registered, used to balance the load
+    /// between I/O threads.
     pub fn get_load(&self) -> i32 {
-        self.load.load(Ordering::Relaxed)
+        self.fds.iter().filter(|entry| entry.is_some()).count() as i32
     }
 
-    pub fn add_timer(&mut self, timeout: i32, sink: Box<dyn IPollEvents>, id: i32) {
-        let expiration = self.clock.now_ms() + timeout as u64;
-        let info = TimerInfo { sink, id };
-        self.timers.insert(expiration, info);
+    pub fn add_fd(&mut self, fd: RawFd, owner: ObjectId) -> Handle {
+        let entry = FdEntry {
+            fd,
+            owner,
+            events: 0,
+        };
+        match self.free.pop() {
+            Some(handle) => {
+                self.fds[handle] = Some(entry);
+                handle
+            }
+            None => {
+                self.fds.push(Some(entry));
+                self.fds.len() - 1
+            }
+        }
     }
 
-    pub fn cancel_timer(&mut self, sink_ptr: *const dyn IPollEvents, id: i32) {
-        let to_remove: Vec<_> = self
-            .timers
-            .iter()
-            .filter(|(_, info)| {
-                std::ptr::eq(sink_ptr, &*info.sink as *const dyn IPollEvents) && info.id == id
-            })
-            .map(|(&k, _)| k)
-            .collect();
+    pub fn rm_fd(&mut self, handle: Handle) {
+        let entry = self.fds[handle].take();
+        assert!(entry.is_some(), "handle not registered");
+        self.retired.push(handle);
+    }
 
-        for key in to_remove {
-            self.timers.remove(&key);
-        }
+    pub fn set_pollin(&mut self, handle: Handle) {
+        self.entry(handle).events |= libc::POLLIN;
     }
 
-    fn adjust_load(&self, amount: i32) {
-        if amount > 0 {
-            self.load.fetch_add(amount, Ordering::Relaxed);
-        } else if amount < 0 {
-            self.load.fetch_sub(-amount, Ordering::Relaxed);
-        }
+    pub fn reset_pollin(&mut self, handle: Handle) {
+        self.entry(handle).events &= !libc::POLLIN;
     }
 
-    fn execute_timers(&mut self) -> u64 {
-        if self.timers.is_empty() {
-            return 0;
+    pub fn set_pollout(&mut self, handle: Handle) {
+        self.entry(handle).events |= libc::POLLOUT;
+    }
+
+    pub fn reset_pollout(&mut self, handle: Handle) {
+        self.entry(handle).events &= !libc::POLLOUT;
+    }
+
+    fn entry(&mut self, handle: Handle) -> &mut FdEntry {
+        self.fds[handle].as_mut().expect("handle not registered")
+    }
+
+    /// Returns the object owning the handle, if it is still registered.
+    pub fn owner(&self, handle: Handle) -> Option<ObjectId> {
+        self.fds
+            .get(handle)
+            .and_then(|entry| entry.as_ref())
+            .map(|entry| entry.owner)
+    }
+
+    /// Add a timeout to expire in `timeout` milliseconds. After the
+    /// expiration, `timer_event` will be called on the owner with the
+    /// `id` supplied.
+    pub fn add_timer(&mut self, timeout: i32, owner: ObjectId, id: i32) {
+        let expiration = Instant::now() + Duration::from_millis(timeout.max(0) as u64);
+        self.timer_seq += 1;
+        self.timers
+            .insert((expiration, self.timer_seq), TimerInfo { owner, id });
+    }
+
+    /// Cancel the timer created by `owner` with the given `id`.
+    pub fn cancel_timer(&mut self, owner: ObjectId, id: i32) {
+        self.timers
+            .retain(|_, timer| !(timer.owner == owner && timer.id == id));
+    }
+
+    /// Drops everything an object registered, once it's gone.
+    pub fn forget(&mut self, owner: ObjectId) {
+        for handle in 0..self.fds.len() {
+            if self.owner(handle) == Some(owner) {
+                self.rm_fd(handle);
+            }
         }
+        self.timers.retain(|_, timer| timer.owner != owner);
+    }
 
-        let current = self.clock.now_ms();
+    /// Removes the timers that expired and returns them as (owner, id)
+    /// pairs, together with the time until the next timer expires
+    /// (None if there are no timers left).
+    pub fn expired_timers(&mut self) -> (Vec<(ObjectId, i32)>, Option<Duration>) {
+        let now = Instant::now();
         let mut expired = Vec::new();
 
-        while let Some((&time, _)) = self.timers.first_key_value() {
-            if time > current {
-                return time - current;
+        while let Some(entry) = self.timers.first_entry() {
+            let (expiration, _) = *entry.key();
+            if expiration > now {
+                return (expired, Some(expiration - now));
             }
+            let timer = entry.remove();
+            expired.push((timer.owner, timer.id));
+        }
+        (expired, None)
+    }
+
+    /// Waits for events on the registered file descriptors. Returns the
+    /// handles which are ready along with their poll(2) revents.
+    pub fn poll(&mut self, timeout: Option<Duration>) -> Vec<(Handle, libc::c_short)> {
+        // Handles retired in the previous iteration are safe to reuse now.
+        self.free.append(&mut self.retired);
 
-            if let Some((_, timer)) = self.timers.remove_entry(&time) {
-                expired.push(timer);
+        let mut handles = Vec::new();
+        let mut pollset = Vec::new();
+        for (handle, entry) in self.fds.iter().enumerate() {
+            if let Some(entry) = entry {
+                handles.push(handle);
+                pollset.push(libc::pollfd {
+                    fd: entry.fd,
+                    events: entry.events,
+                    revents: 0,
+                });
             }
         }
 
-        for timer in expired {
-            timer.sink.timer_event(timer.id);
+        // Round up so that timers don't get busy-polled for the last
+        // fraction of a millisecond.
+        let timeout = match timeout {
+            None => -1,
+            Some(timeout) => {
+                let ms = timeout.as_micros().div_ceil(1000);
+                ms.min(i32::MAX as u128) as i32
+            }
+        };
+
+        let rc = unsafe {
+            libc::poll(
+                pollset.as_mut_ptr(),
+                pollset.len() as libc::nfds_t,
+                timeout,
+            )
+        };
+        if rc == -1 {
+            let errno = std::io::Error::last_os_error().raw_os_error();
+            assert_eq!(errno, Some(libc::EINTR), "poll failed");
+            return Vec::new();
         }
 
-        self.timers
-            .first_key_value()
-            .map(|(&time, _)| time - current)
-            .unwrap_or(0)
+        handles
+            .into_iter()
+            .zip(pollset)
+            .filter(|(_, pfd)| pfd.revents != 0)
+            .map(|(handle, pfd)| (handle, pfd.revents))
+            .collect()
     }
 }
 
-pub struct ThreadCtx {
-    // Implementation details would go here
+impl Default for PollerBase {
+    fn default() -> Self {
+        PollerBase::new()
+    }
 }
 
-pub struct WorkerPollerBase {
-    base: PollerBase,
-    ctx: ThreadCtx,
-    worker: Option<JoinHandle<()>>,
+/// What an I/O object gets to see of its thread while one of its
+/// callbacks runs. This is the counterpart of the io_object_t base class:
+/// file descriptors and timers registered here belong to the object.
+pub struct IoContext<'a> {
+    poller: &'a mut PollerBase,
+    thread: &'a Arc<IoThread>,
+    id: ObjectId,
+    terminated: bool,
 }
 
-impl WorkerPollerBase {
-    pub fn new(ctx: ThreadCtx) -> Self {
-        WorkerPollerBase {
-            base: PollerBase::new(),
-            ctx,
-            worker: None,
+impl<'a> IoContext<'a> {
+    pub(crate) fn new(
+        poller: &'a mut PollerBase,
+        thread: &'a Arc<IoThread>,
+        id: ObjectId,
+    ) -> Self {
+        IoContext {
+            poller,
+            thread,
+            id,
+            terminated: false,
         }
     }
 
-    pub fn start(&mut self, name: Option<&str>) {
-        assert!(self.base.get_load() > 0);
-        let worker = thread::Builder::new()
-            .name(name.unwrap_or("worker").to_string())
-            .spawn(move || {
-                // Worker loop implementation would go here
-            })
-            .expect("Failed to spawn worker thread");
+    pub fn add_fd(&mut self, fd: RawFd) -> Handle {
+        self.poller.add_fd(fd, self.id)
+    }
 
-        self.worker = Some(worker);
+    pub fn rm_fd(&mut self, handle: Handle) {
+        self.poller.rm_fd(handle)
     }
 
-    pub fn stop_worker(&mut self) {
-        if let Some(worker) = self.worker.take() {
-            worker.join().expect("Worker thread panicked");
-        }
+    pub fn set_pollin(&mut self, handle: Handle) {
+        self.poller.set_pollin(handle)
     }
 
-    #[cfg(debug_assertions)]
-    fn check_thread(&self) {
-        if let Some(worker) = &self.worker {
-            assert!(thread::current().id() == worker.thread().id());
-        }
+    pub fn reset_pollin(&mut self, handle: Handle) {
+        self.poller.reset_pollin(handle)
+    }
+
+    pub fn set_pollout(&mut self, handle: Handle) {
+        self.poller.set_pollout(handle)
+    }
+
+    pub fn reset_pollout(&mut self, handle: Handle) {
+        self.poller.reset_pollout(handle)
+    }
+
+    pub fn add_timer(&mut self, timeout: i32, id: i32) {
+        self.poller.add_timer(timeout, self.id, id)
+    }
+
+    pub fn cancel_timer(&mut self, id: i32) {
+        self.poller.cancel_timer(self.id, id)
+    }
+
+    /// The mailbox of the object being called, to be used as the event
+    /// sink of its pipes.
+    pub fn mailbox(&self) -> Arc<dyn IMailbox> {
+        Arc::new(ObjectMailbox::new(self.id, Arc::clone(self.thread)))
+    }
+
+    /// Starts a new object in this I/O thread. It is plugged once the
+    /// current callback returns.
+    pub fn launch(&mut self, object: Box<dyn IoObject>) -> Arc<ObjectMailbox> {
+        self.thread.launch(object)
+    }
+
+    /// Returns true until the object behind the mailbox terminated.
+    pub fn is_alive(&self, object: &ObjectMailbox) -> bool {
+        self.thread.is_alive(object.id())
+    }
+
+    /// Asks the I/O thread to drop the object once the current callback
+    /// returns. Its file descriptors and timers are dropped with it.
+    pub fn terminate(&mut self) {
+        self.terminated = true;
     }
-}
 
-impl Drop for WorkerPollerBase {
-    fn drop(&mut self) {
-        assert_eq!(self.base.get_load(), 0);
+    pub(crate) fn is_terminated(&self) -> bool {
+        self.terminated
     }
 }
@@ -1,83 +1,57 @@
 use crate::constants::ZMQ_PULL;
-use crate::mailbox::Mailbox;
-use crate::socket_base::SocketBehavior;
-use crate::{
-    context::Context, fair_queue::FairQueue, message::Message, pipe::Pipe, socket_base::SocketBase,
-};
+use crate::context::Context;
+use crate::fair_queue::FairQueue;
+use crate::message::Message;
+use crate::pipe::Pipe;
+use crate::socket_base::{SocketBase, SocketBehavior};
+use std::sync::Arc;
 
 /// PULL socket implementation
 pub struct Pull {
-    /// Fair queuing object for inbound pipes
-    fair_queue: FairQueue,
     /// Base socket functionality
-    socket: SocketBase,
-
-    pipe: Pipe,
+    base: SocketBase,
+    /// Fair queuing object for inbound pipes
+    fq: FairQueue,
 }
 
 impl Pull {
-    pub fn new(parent: &Context, tid: u32, sid: i32) -> Self {
+    pub fn new(ctx: &Arc<Context>, tid: u32, sid: i32) -> Self {
+        let mut base = SocketBase::new(ctx, tid, sid, false);
+        base.options.socket_type = ZMQ_PULL;
+
         Self {
-            fair_queue: FairQueue::new(),
-            socket: SocketBase::new(parent, tid, sid, ZMQ_PULL),
-            pipe: Pipe::new(),
+            base,
+            fq: FairQueue::new(),
         }
     }
 }
 
 impl SocketBehavior for Pull {
-    fn attach_pipe(&mut self, pipe: &mut Pipe, _subscribe_to_all: bool, _locally_initiated: bool) {
-        debug_assert!(pipe.is_some());
-        self.fair_queue.attach(pipe);
-    }
-
-    fn recv(&mut self, msg: &mut Message) -> Result<(), i32> {
-        *msg = self.fair_queue.recv();
-        Ok(())
-    }
-
-    fn has_in(&self) -> bool {
-        self.fair_queue.has_in()
+    fn base(&self) -> &SocketBase {
+        &self.base
     }
 
-    fn read_activated(&mut self, pipe: &mut Pipe) {
-        self.fair_queue.activated(pipe);
+    fn base_mut(&mut self) -> &mut SocketBase {
+        &mut self.base
     }
 
-    fn pipe_terminated(&mut self, pipe: &mut Pipe) {
-        self.fair_queue.pipe_terminated(pipe);
+    fn xattach_pipe(&mut self, pipe: Pipe, _subscribe_to_all: bool, _locally_initiated: bool) {
+        self.fq.attach(pipe);
     }
 
-    fn check_tag(&self) -> bool {
-        todo!()
+    fn xpipe_terminated(&mut self, pipe: &Pipe) {
+        self.fq.pipe_terminated(pipe);
     }
 
-    fn is_thread_safe(&self) -> bool {
-        todo!()
+    fn xread_activated(&mut self, pipe: &Pipe) {
+        self.fq.activated(pipe);
     }
 
-    fn get_mailbox(&self) -> Option<&Mailbox> {
-        todo!()
+    fn xrecv(&mut self, msg: &mut Message) -> Result<(), i32> {
+        self.fq.recv(msg)
     }
 
-    fn bind(&mut self, endpoint: &str) -> Result<()> {
-        todo!()
+    fn xhas_in(&mut self) -> bool {
+        self.fq.has_in()
     }
-
-    fn connect(&mut self, endpoint: &str) -> Result<()> {
-        todo!()
-    }
-
-    fn send(&mut self, msg: Message, flags: i32) -> Result<()> {
-        todo!()
-    }
-
-    fn close(&mut self) -> Result<()> {
-        todo!()
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    // Add tests here
 }
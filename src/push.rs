@@ -1,54 +1,59 @@
-use crate::{
-    constants::ZMQ_PUSH, context::Context, load_balancer::LoadBalancer, message::Message, pipe::Pipe, socket::SocketBase, utils::NonCopyable
-};
+use crate::constants::ZMQ_PUSH;
+use crate::context::Context;
+use crate::load_balancer::LoadBalancer;
+use crate::message::Message;
+use crate::pipe::Pipe;
+use crate::socket_base::{SocketBase, SocketBehavior};
+use std::sync::Arc;
 
 /// Push socket type (ZMQ_PUSH)
 pub struct Push {
-    socket: SocketBase,
+    base: SocketBase,
+    // Load balancer managing the outbound pipes.
     lb: LoadBalancer,
 }
 
 impl Push {
-    pub fn new(parent: &mut Context, tid: u32, sid: i32) -> Self {
-        let mut socket = SocketBase::new(parent, tid, sid);
-        socket.set_socket_type(ZMQ_PUSH);
+    pub fn new(ctx: &Arc<Context>, tid: u32, sid: i32) -> Self {
+        let mut base = SocketBase::new(ctx, tid, sid, false);
+        base.options.socket_type = ZMQ_PUSH;
 
         Self {
-            socket,
+            base,
             lb: LoadBalancer::new(),
         }
     }
+}
+
+impl SocketBehavior for Push {
+    fn base(&self) -> &SocketBase {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut SocketBase {
+        &mut self.base
+    }
 
-    pub fn attach_pipe(
-        &mut self,
-        pipe: &mut Pipe,
-        _subscribe_to_all: bool,
-        _locally_initiated: bool,
-    ) {
+    fn xattach_pipe(&mut self, pipe: Pipe, _subscribe_to_all: bool, _locally_initiated: bool) {
         // Don't delay pipe termination as there is no one
-        // to receive the delimiter
+        // to receive the delimiter.
         pipe.set_nodelay();
-
-        debug_assert!(pipe.is_some());
         self.lb.attach(pipe);
     }
 
-    pub fn send(&mut self, msg: &mut Message) -> i32 {
-        self.lb.send(msg)
+    fn xpipe_terminated(&mut self, pipe: &Pipe) {
+        self.lb.pipe_terminated(pipe);
     }
 
-    pub fn has_out(&self) -> bool {
-        self.lb.has_out()
+    fn xwrite_activated(&mut self, pipe: &Pipe) {
+        self.lb.activated(pipe);
     }
 
-    pub fn write_activated(&mut self, pipe: &mut Pipe) {
-        self.lb.activated(pipe);
+    fn xsend(&mut self, msg: &mut Message) -> Result<(), i32> {
+        self.lb.send(msg)
     }
 
-    pub fn pipe_terminated(&mut self, pipe: &mut Pipe) {
-        self.lb.pipe_terminated(pipe);
+    fn xhas_out(&mut self) -> bool {
+        self.lb.has_out()
     }
 }
-
-// Implement NonCopyable trait
-impl NonCopyable for Push {}
@@ -1,100 +1,129 @@
+use crate::constants::{ZMQ_EAGAIN, ZMQ_XPUB_NODROP};
+use crate::context::Context;
+use crate::dist::Dist;
+use crate::message::{Message, MsgFlags};
+use crate::options::bool_relaxed;
+use crate::pipe::Pipe;
+use crate::socket_base::{SocketBase, SocketBehavior};
+use crate::zmq_draft::ZMQ_RADIO;
 use std::collections::HashMap;
-use std::collections::hash_map::Entry;
-
-// Forward declarations for external types we'd need to implement/import
-struct Ctx;
-struct Pipe;
-struct IoThread;
-struct SocketBase;
-struct Msg;
-struct Options;
-struct Address;
-struct Dist;
+use std::sync::Arc;
 
+/// RADIO socket: distributes single-part messages to the DISH peers that
+/// joined the message's group.
 pub struct Radio {
+    base: SocketBase,
+
+    // List of all subscriptions mapped to corresponding pipes.
     subscriptions: HashMap<String, Vec<Pipe>>,
+
+    // List of udp pipes
     udp_pipes: Vec<Pipe>,
+
+    // Distributor of messages holding the list of outbound pipes.
     dist: Dist,
+
+    // Drop messages if HWM reached, otherwise return with EAGAIN
     lossy: bool,
 }
 
 impl Radio {
-    pub fn new(parent: &mut Ctx, tid: u32, sid: i32) -> Self {
+    pub fn new(ctx: &Arc<Context>, tid: u32, sid: i32) -> Self {
+        let mut base = SocketBase::new(ctx, tid, sid, true);
+        base.options.socket_type = ZMQ_RADIO;
+
         Radio {
+            base,
             subscriptions: HashMap::new(),
             udp_pipes: Vec::new(),
             dist: Dist::new(),
             lossy: true,
         }
     }
+}
+
+impl SocketBehavior for Radio {
+    fn base(&self) -> &SocketBase {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut SocketBase {
+        &mut self.base
+    }
 
-    pub fn attach_pipe(&mut self, pipe: Pipe, subscribe_to_all: bool, locally_initiated: bool) {
+    fn xattach_pipe(&mut self, pipe: Pipe, subscribe_to_all: bool, _locally_initiated: bool) {
+        // Don't delay pipe termination as there is no one
+        // to receive the delimiter.
         pipe.set_nodelay();
-        self.dist.attach(pipe);
+
+        self.dist.attach(pipe.clone());
 
         if subscribe_to_all {
             self.udp_pipes.push(pipe);
         } else {
-            self.read_activated(&pipe);
+            // The pipe is active when attached. Let's read the subscriptions
+            // from it, if any.
+            self.xread_activated(&pipe);
         }
     }
 
-    pub fn read_activated(&mut self, pipe: &Pipe) {
-        while let Some(msg) = pipe.read() {
-            if msg.is_join() || msg.is_leave() {
-                let group = msg.group().to_string();
-                
-                if msg.is_join() {
-                    match self.subscriptions.entry(group) {
-                        Entry::Vacant(e) => { e.insert(vec![pipe.clone()]); }
-                        Entry::Occupied(mut e) => { e.get_mut().push(pipe.clone()); }
+    fn xread_activated(&mut self, pipe: &Pipe) {
+        // There are some subscriptions waiting. Let's process them.
+        let mut msg = Message::new();
+        while pipe.read(&mut msg) {
+            // Apply the subscription to the trie
+            if msg.is_join() {
+                self.subscriptions
+                    .entry(msg.get_group().to_string())
+                    .or_default()
+                    .push(pipe.clone());
+            } else if msg.is_leave() {
+                if let Some(pipes) = self.subscriptions.get_mut(msg.get_group()) {
+                    if let Some(pos) = pipes.iter().position(|p| p == pipe) {
+                        pipes.remove(pos);
                     }
-                } else {
-                    if let Entry::Occupied(mut entry) = self.subscriptions.entry(group) {
-                        if let Some(pos) = entry.get().iter().position(|x| x == pipe) {
-                            entry.get_mut().remove(pos);
-                        }
+                    if pipes.is_empty() {
+                        self.subscriptions.remove(msg.get_group());
                     }
                 }
             }
         }
     }
 
-    pub fn write_activated(&mut self, pipe: &Pipe) {
+    fn xwrite_activated(&mut self, pipe: &Pipe) {
         self.dist.activated(pipe);
     }
 
-    pub fn set_sockopt(&mut self, option: i32, value: i32) -> Result<(), i32> {
+    fn xsetsockopt(&mut self, option: i32, optval: &[u8]) -> Result<(), i32> {
         match option {
             ZMQ_XPUB_NODROP => {
-                self.lossy = value == 0;
+                self.lossy = !bool_relaxed(optval)?;
                 Ok(())
             }
-            _ => Err(EINVAL)
+            _ => Err(libc::EINVAL),
         }
     }
 
-    pub fn pipe_terminated(&mut self, pipe: &Pipe) {
+    fn xpipe_terminated(&mut self, pipe: &Pipe) {
         self.subscriptions.retain(|_, pipes| {
             pipes.retain(|p| p != pipe);
             !pipes.is_empty()
         });
 
-        if let Some(pos) = self.udp_pipes.iter().position(|x| x == pipe) {
-            self.udp_pipes.remove(pos);
-        }
+        self.udp_pipes.retain(|p| p != pipe);
 
         self.dist.pipe_terminated(pipe);
     }
 
-    pub fn send(&mut self, msg: &mut Msg) -> Result<(), i32> {
-        if msg.flags().contains(MsgFlags::MORE) {
-            return Err(EINVAL);
+    fn xsend(&mut self, msg: &mut Message) -> Result<(), i32> {
+        // Radio sockets do not allow multipart data (ZMQ_SNDMORE)
+        if msg.has_more() {
+            return Err(libc::EINVAL);
         }
 
         self.dist.unmatch();
 
-        if let Some(pipes) = self.subscriptions.get(msg.group()) {
+        if let Some(pipes) = self.subscriptions.get(msg.get_group()) {
             for pipe in pipes {
                 self.dist.match_pipe(pipe);
             }
@@ -105,87 +134,85 @@ impl Radio {
         }
 
         if self.lossy || self.dist.check_hwm() {
-            if self.dist.send_to_matching(msg).is_ok() {
-                return Ok(());
-            }
+            self.dist.send_to_matching(msg)
+        } else {
+            Err(ZMQ_EAGAIN)
         }
-        
-        Err(EAGAIN)
     }
 
-    pub fn has_out(&self) -> bool {
+    fn xhas_out(&mut self) -> bool {
         self.dist.has_out()
     }
 
-    pub fn recv(&mut self, _msg: &mut Msg) -> Result<(), i32> {
-        Err(ENOTSUP)
+    fn xrecv(&mut self, _msg: &mut Message) -> Result<(), i32> {
+        // Messages cannot be received from RADIO socket.
+        Err(libc::ENOTSUP)
     }
 
-    pub fn has_in(&self) -> bool {
+    fn xhas_in(&mut self) -> bool {
         false
     }
 }
 
-pub struct RadioSession {
-    state: SessionState,
-    pending_msg: Option<Msg>,
-}
-
-#[derive(PartialEq)]
-enum SessionState {
-    Group,
-    Body,
+/// Session hook of a RADIO socket. Messages go to the peer as a group
+/// frame followed by the body, and the JOIN and LEAVE commands coming back
+/// turn into the join and leave messages the socket expects.
+pub(crate) struct RadioSession {
+    // Body of the message whose group frame was pulled last.
+    pending_msg: Option<Message>,
 }
 
 impl RadioSession {
-    pub fn new(
-        io_thread: &IoThread,
-        connect: bool,
-        socket: &SocketBase,
-        options: &Options,
-        addr: &Address,
-    ) -> Self {
-        RadioSession {
-            state: SessionState::Group,
-            pending_msg: None,
+    pub(crate) fn new() -> Self {
+        RadioSession { pending_msg: None }
+    }
+
+    /// Replaces a JOIN or LEAVE command by the corresponding message.
+    /// Other messages are left alone.
+    pub(crate) fn convert_command(&self, msg: &mut Message) -> Result<(), i32> {
+        if !msg.is_command() {
+            return Ok(());
         }
+
+        // Set the msg type to either JOIN or LEAVE
+        let data = msg.data();
+        let mut join_leave_msg = Message::new();
+        let group = if let Some(group) = data.strip_prefix(b"\x04JOIN") {
+            join_leave_msg.init_join()?;
+            group
+        } else if let Some(group) = data.strip_prefix(b"\x05LEAVE") {
+            join_leave_msg.init_leave()?;
+            group
+        } else {
+            // If it is not a JOIN or LEAVE just push the message
+            return Ok(());
+        };
+
+        // Set the group
+        let group = std::str::from_utf8(group).map_err(|_| libc::EPROTO)?;
+        join_leave_msg.set_group(group).map_err(|_| libc::EPROTO)?;
+        *msg = join_leave_msg;
+        Ok(())
     }
 
-    pub fn push_msg(&mut self, msg: &mut Msg) -> Result<(), i32> {
-        // Command message handling would go here
-        // For brevity, only basic implementation shown
+    /// Splits a message pulled from the socket: `msg` becomes the group
+    /// frame and the body is kept for the next pull.
+    pub(crate) fn split_msg(&mut self, msg: &mut Message) -> Result<(), i32> {
+        // First frame is the group
+        let mut group = Message::with_data(msg.get_group().as_bytes()).map_err(|_| libc::ENOMEM)?;
+        group.set_flags(MsgFlags::More);
+
+        // Next status is the body
+        self.pending_msg = Some(std::mem::replace(msg, group));
         Ok(())
     }
 
-    pub fn pull_msg(&mut self, msg: &mut Msg) -> Result<(), i32> {
-        match self.state {
-            SessionState::Group => {
-                if let Some(pending) = self.pending_msg.take() {
-                    let group = pending.group();
-                    msg.init_size(group.len())?;
-                    msg.set_flags(MsgFlags::MORE);
-                    msg.copy_from_slice(group.as_bytes());
-                    self.pending_msg = Some(pending);
-                    self.state = SessionState::Body;
-                    Ok(())
-                } else {
-                    Err(EAGAIN)
-                }
-            }
-            SessionState::Body => {
-                if let Some(pending) = self.pending_msg.take() {
-                    *msg = pending;
-                    self.state = SessionState::Group;
-                    Ok(())
-                } else {
-                    Err(EAGAIN)
-                }
-            }
-        }
+    /// The body of the last message split, if not pulled yet.
+    pub(crate) fn take_body(&mut self) -> Option<Message> {
+        self.pending_msg.take()
     }
 
-    pub fn reset(&mut self) {
-        self.state = SessionState::Group;
+    pub(crate) fn reset(&mut self) {
         self.pending_msg = None;
     }
 }
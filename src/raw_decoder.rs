@@ -1,125 +1,33 @@
-use std::mem;
-
-// Message type placeholder - would need to be properly implemented
-pub struct Msg {
-    data: Vec<u8>,
-    is_zcmsg: bool,
-}
-
-impl Msg {
-    fn init(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        Ok(())
-    }
-
-    fn init_with_data(&mut self, 
-                      data: &[u8], 
-                      dealloc: fn(*mut u8),
-                      buffer: *mut u8,
-                      provide_content: bool) -> Result<(), Box<dyn std::error::Error>> {
-        self.data = data.to_vec();
-        self.is_zcmsg = provide_content;
-        Ok(())
-    }
-
-    fn close(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        self.data.clear();
-        Ok(())
-    }
-
-    fn is_zcmsg(&self) -> bool {
-        self.is_zcmsg
-    }
-}
-
-// Allocator for shared message memory
-struct SharedMessageMemoryAllocator {
-    buffer: Vec<u8>,
-    size: usize,
-    provide_content: bool,
-}
-
-impl SharedMessageMemoryAllocator {
-    fn new(bufsize: usize, _unused: usize) -> Self {
-        Self {
-            buffer: vec![0; bufsize],
-            size: bufsize,
-            provide_content: false,
-        }
-    }
-
-    fn allocate(&mut self) -> *mut u8 {
-        self.buffer.as_mut_ptr()
-    }
-
-    fn size(&self) -> usize {
-        self.size
-    }
-
-    fn buffer(&self) -> *mut u8 {
-        self.buffer.as_ptr() as *mut u8
-    }
-
-    fn provide_content(&self) -> bool {
-        self.provide_content
-    }
-
-    fn advance_content(&mut self) {
-        self.provide_content = true;
-    }
-
-    fn release(&mut self) {
-        self.buffer = vec![0; self.size];
-        self.provide_content = false;
-    }
-}
+use crate::i_decoder::IDecoder;
+use crate::message::Message;
 
+/// Decoder for raw sockets: there is no framing, whatever was read from
+/// the connection in one go becomes a message.
 pub struct RawDecoder {
-    in_progress: Msg,
-    allocator: SharedMessageMemoryAllocator,
+    in_progress: Message,
 }
 
 impl RawDecoder {
-    pub fn new(bufsize: usize) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut decoder = Self {
-            in_progress: Msg { 
-                data: Vec::new(),
-                is_zcmsg: false,
-            },
-            allocator: SharedMessageMemoryAllocator::new(bufsize, 1),
-        };
-        decoder.in_progress.init()?;
-        Ok(decoder)
-    }
-
-    pub fn get_buffer(&mut self) -> (*mut u8, usize) {
-        let data = self.allocator.allocate();
-        let size = self.allocator.size();
-        (data, size)
-    }
-
-    pub fn decode(&mut self, data: &[u8]) -> Result<usize, Box<dyn std::error::Error>> {
-        self.in_progress.init_with_data(
-            data,
-            Self::call_dec_ref,
-            self.allocator.buffer(),
-            self.allocator.provide_content()
-        )?;
-
-        if self.in_progress.is_zcmsg() {
-            self.allocator.advance_content();
-            self.allocator.release();
+    pub fn new() -> Self {
+        RawDecoder {
+            in_progress: Message::new(),
         }
-
-        Ok(data.len())
     }
+}
 
-    fn call_dec_ref(_ptr: *mut u8) {
-        // Placeholder for reference counting cleanup
+impl Default for RawDecoder {
+    fn default() -> Self {
+        RawDecoder::new()
     }
 }
 
-impl Drop for RawDecoder {
-    fn drop(&mut self) {
-        let _ = self.in_progress.close();
+impl IDecoder for RawDecoder {
+    fn decode(&mut self, data: &[u8]) -> Result<(usize, bool), i32> {
+        self.in_progress = Message::with_data(data).map_err(|_| libc::ENOMEM)?;
+        Ok((data.len(), true))
+    }
+
+    fn msg(&mut self) -> &mut Message {
+        &mut self.in_progress
     }
 }
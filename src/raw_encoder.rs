@@ -1,42 +1,33 @@
-#![allow(dead_code)]
-
-// Equivalent to encoder_base_t from C++
-pub trait EncoderBase {
-    fn next_step(&mut self, data: Option<&[u8]>, size: usize, ready: bool);
-    fn in_progress(&mut self) -> &mut Vec<u8>;
-}
+use crate::encoder::EncoderBase;
+use crate::i_encoder::IEncoder;
+use crate::message::Message;
 
+/// Encoder for raw sockets: message bodies are written out as they are,
+/// without any framing.
 pub struct RawEncoder {
-    buffer: Vec<u8>,
-    buffer_size: usize,
+    base: EncoderBase,
 }
 
 impl RawEncoder {
-    pub fn new(bufsize: usize) -> Self {
-        let mut encoder = RawEncoder {
-            buffer: Vec::with_capacity(bufsize),
-            buffer_size: bufsize,
-        };
-        // Initialize with empty data and ready state
-        encoder.next_step(None, 0, true);
-        encoder
+    pub fn new() -> Self {
+        RawEncoder {
+            base: EncoderBase::new(),
+        }
     }
+}
 
-    fn raw_message_ready(&mut self) {
-        let data = self.in_progress();
-        self.next_step(Some(data), data.len(), true);
+impl Default for RawEncoder {
+    fn default() -> Self {
+        RawEncoder::new()
     }
 }
 
-impl EncoderBase for RawEncoder {
-    fn next_step(&mut self, data: Option<&[u8]>, size: usize, ready: bool) {
-        if let Some(data) = data {
-            self.buffer.clear();
-            self.buffer.extend_from_slice(data);
-        }
+impl IEncoder for RawEncoder {
+    fn encode(&mut self, buffer: &mut Vec<u8>, size: usize) -> usize {
+        self.base.encode(buffer, size)
     }
 
-    fn in_progress(&mut self) -> &mut Vec<u8> {
-        &mut self.buffer
+    fn load_msg(&mut self, msg: &mut Message) {
+        self.base.load(msg, Vec::new());
     }
 }
@@ -1,160 +1,70 @@
-use std::any::Any;
+//! Engine of the raw sockets, STREAM and ROUTER with ZMQ_ROUTER_RAW,
+//! which exchange plain bytes with peers that don't speak ZMTP.
+
+use std::collections::HashMap;
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+
+use crate::endpoint::EndpointUriPair;
+use crate::message::Message;
+use crate::metadata::Metadata;
+use crate::options::Options;
+use crate::poller_base::IoContext;
+use crate::raw_decoder::RawDecoder;
+use crate::raw_encoder::RawEncoder;
+use crate::session_base::SessionBase;
+use crate::stream_engine_base::{NextMsg, ProcessMsg, StreamEngine, StreamEngineBase};
 
-// Forward declarations
-type Fd = i32;
-type ErrorReason = i32;
-type Properties = Vec<(String, String)>;
-
-// Traits (interfaces)
-pub trait IEngine {
-    fn error(&mut self, reason: ErrorReason);
-    fn plug_internal(&mut self);
-    fn handshake(&mut self) -> bool;
-}
-
-pub trait IoObject {
-    fn set_pollin(&mut self);
-    fn set_pollout(&mut self);
-    fn in_event(&mut self);
-}
-
-// Options struct to replace C++ options_t
-#[derive(Clone)]
-pub struct Options {
-    pub out_batch_size: usize,
-    pub in_batch_size: usize,
-    pub raw_socket: bool,
-    pub raw_notify: bool,
-}
-
-// Message type to replace C++ msg_t
-pub struct Msg {
-    pub data: Vec<u8>,
-    pub metadata: Option<Box<Metadata>>,
-}
-
-impl Msg {
-    pub fn new() -> Self {
-        Self {
-            data: Vec::new(),
-            metadata: None,
-        }
-    }
-
-    pub fn set_metadata(&mut self, metadata: Box<Metadata>) {
-        self.metadata = Some(metadata);
-    }
-}
-
-// Metadata type
-pub struct Metadata {
-    properties: Properties,
-}
-
-impl Metadata {
-    pub fn new(properties: Properties) -> Self {
-        Self { properties }
-    }
-}
-
-// Endpoint URI pair struct
-pub struct EndpointUriPair {
-    local: String,
-    remote: String,
-}
-
-// Base stream engine struct
-pub struct StreamEngineBase {
-    fd: Fd,
-    options: Options,
-    endpoint_pair: EndpointUriPair,
-    metadata: Option<Box<Metadata>>,
-}
-
-// Raw engine implementation
 pub struct RawEngine {
     base: StreamEngineBase,
-    encoder: Option<Box<dyn Any>>, // Placeholder for encoder
-    decoder: Option<Box<dyn Any>>, // Placeholder for decoder
 }
 
 impl RawEngine {
-    pub fn new(fd: Fd, options: Options, endpoint_pair: EndpointUriPair) -> Self {
-        Self {
-            base: StreamEngineBase {
-                fd,
-                options,
-                endpoint_pair,
-                metadata: None,
-            },
-            encoder: None,
-            decoder: None,
-        }
-    }
-
-    fn push_raw_msg_to_session(&mut self, msg: &mut Msg) -> i32 {
-        if let Some(ref metadata) = self.base.metadata {
-            if msg.metadata.is_none() {
-                msg.set_metadata(metadata.clone());
-            }
-        }
-        self.push_msg_to_session(msg)
+    pub fn new(fd: RawFd, options: &Options, endpoint_uri_pair: EndpointUriPair) -> Self {
+        let mut base = StreamEngineBase::new(fd, options, endpoint_uri_pair, false);
+        base.next_msg = NextMsg::PullMsgFromSession;
+        base.process_msg = ProcessMsg::PushRawMsgToSession;
+        RawEngine { base }
     }
+}
 
-    fn push_msg_to_session(&mut self, _msg: &mut Msg) -> i32 {
-        // Placeholder for actual implementation
-        0
+impl StreamEngine for RawEngine {
+    fn base(&self) -> &StreamEngineBase {
+        &self.base
     }
 
-    fn init_properties(&mut self, _properties: &mut Properties) -> bool {
-        // Placeholder for actual implementation
-        true
+    fn base_mut(&mut self) -> &mut StreamEngineBase {
+        &mut self.base
     }
-}
 
-impl IEngine for RawEngine {
-    fn error(&mut self, reason: ErrorReason) {
-        if self.base.options.raw_socket && self.base.options.raw_notify {
-            let mut terminator = Msg::new();
-            self.push_raw_msg_to_session(&mut terminator);
-        }
-        // Call base error handling
-    }
+    fn plug_internal(&mut self, session: &mut SessionBase, io: &mut IoContext) {
+        // No handshaking for raw sock, instantiate raw encoder and decoders.
+        self.base.encoder = Some(Box::new(RawEncoder::new()));
+        self.base.decoder = Some(Box::new(RawDecoder::new()));
+        self.base.handshaking = false;
 
-    fn plug_internal(&mut self) {
-        // Create encoder and decoder
-        // Setup message handling
-        
-        let mut properties = Properties::new();
-        if self.init_properties(&mut properties) {
-            self.base.metadata = Some(Box::new(Metadata::new(properties)));
+        let mut properties = HashMap::new();
+        if self.base.init_properties(&mut properties) {
+            debug_assert!(self.base.metadata.is_none());
+            self.base.metadata = Some(Arc::new(Metadata::new(properties)));
         }
 
         if self.base.options.raw_notify {
-            let mut connector = Msg::new();
-            self.push_raw_msg_to_session(&mut connector);
+            // For raw sockets, send an initial 0-length message to the
+            // application so that it knows a peer has connected.
+            let mut connector = Message::new();
+            let _ = self.base.push_raw_msg_to_session(&mut connector, session);
+            session.flush();
         }
 
-        self.set_pollin();
-        self.set_pollout();
-        self.in_event();
-    }
-
-    fn handshake(&mut self) -> bool {
-        true
-    }
-}
-
-impl IoObject for RawEngine {
-    fn set_pollin(&mut self) {
-        // Implement polling setup
-    }
+        self.base.set_pollin(io);
+        self.base.set_pollout(io);
 
-    fn set_pollout(&mut self) {
-        // Implement polling setup
+        // Flush all the data that may have been already received downstream.
+        self.in_event_internal(session, io);
     }
 
-    fn in_event(&mut self) {
-        // Handle incoming events
+    fn handshake(&mut self, _session: &mut SessionBase, _io: &mut IoContext) -> bool {
+        true
     }
 }
@@ -1,88 +1,75 @@
-use std::error::Error;
-
+use crate::constants::{ZMQ_EFSM, ZMQ_REP};
 use crate::context::Context;
+use crate::message::Message;
+use crate::pipe::Pipe;
+use crate::router::Router;
+use crate::socket_base::{SocketBase, SocketBehavior};
+use std::sync::Arc;
+
+/// REP socket: a ROUTER that echoes the request envelope back with the
+/// reply and enforces request/reply alternation.
+pub struct Rep {
+    router: Router,
+
+    // If true, we are in process of sending the reply. If false we are
+    // in process of receiving a request.
+    sending_reply: bool,
 
-// Equivalent to C++ msg_t
-struct Msg {
-    flags: u32,
-    data: Vec<u8>,
+    // If true, we are starting to receive a request. The beginning
+    // of the request is the backtrace stack.
+    request_begins: bool,
 }
 
-impl Msg {
-    const MORE: u32 = 1;
+impl Rep {
+    pub fn new(ctx: &Arc<Context>, tid: u32, sid: i32) -> Self {
+        let mut router = Router::new(ctx, tid, sid);
+        router.base_mut().options.socket_type = ZMQ_REP;
 
-    fn new() -> Self {
-        Msg {
-            flags: 0,
-            data: Vec::new(),
+        Rep {
+            router,
+            sending_reply: false,
+            request_begins: true,
         }
     }
-
-    fn flags(&self) -> u32 {
-        self.flags
-    }
-
-    fn size(&self) -> usize {
-        self.data.len()
-    }
 }
 
-// Router functionality (simplified)
-struct Router {
-    // Router implementation details
-}
-
-impl Router {
-    fn new(parent: *mut Context, tid: u32, sid: i32) -> Self {
-        Router {}
-    }
-
-    fn xsend(&mut self, msg: &mut Msg) -> Result<(), Box<dyn Error>> {
-        Ok(())
+impl SocketBehavior for Rep {
+    fn base(&self) -> &SocketBase {
+        self.router.base()
     }
 
-    fn xrecv(&mut self, msg: &mut Msg) -> Result<(), Box<dyn Error>> {
-        Ok(())
+    fn base_mut(&mut self) -> &mut SocketBase {
+        self.router.base_mut()
     }
 
-    fn rollback(&mut self) -> Result<(), Box<dyn Error>> {
-        Ok(())
+    fn xattach_pipe(&mut self, pipe: Pipe, subscribe_to_all: bool, locally_initiated: bool) {
+        self.router.xattach_pipe(pipe, subscribe_to_all, locally_initiated);
     }
 
-    fn xhas_in(&self) -> bool {
-        false
+    fn xpipe_terminated(&mut self, pipe: &Pipe) {
+        self.router.xpipe_terminated(pipe);
     }
 
-    fn xhas_out(&self) -> bool {
-        false
+    fn xread_activated(&mut self, pipe: &Pipe) {
+        self.router.xread_activated(pipe);
     }
-}
-
-// Main REP implementation
-pub struct Rep {
-    router: Router,
-    sending_reply: bool,
-    request_begins: bool,
-}
 
-impl Rep {
-    pub fn new(parent: *mut Context, tid: u32, sid: i32) -> Self {
-        Rep {
-            router: Router::new(parent, tid, sid),
-            sending_reply: false,
-            request_begins: true,
-        }
+    fn xwrite_activated(&mut self, pipe: &Pipe) {
+        self.router.xwrite_activated(pipe);
     }
 
-    pub fn xsend(&mut self, msg: &mut Msg) -> Result<(), Box<dyn Error>> {
+    fn xsend(&mut self, msg: &mut Message) -> Result<(), i32> {
+        // If we are in the middle of receiving a request, we cannot send reply.
         if !self.sending_reply {
-            return Err("EFSM".into());
+            return Err(ZMQ_EFSM);
         }
 
-        let more = (msg.flags() & Msg::MORE) != 0;
+        let more = msg.has_more();
 
+        // Push message to the reply pipe.
         self.router.xsend(msg)?;
 
+        // If the reply is complete flip the FSM back to request receiving state.
         if !more {
             self.sending_reply = false;
         }
@@ -90,32 +77,42 @@ impl Rep {
         Ok(())
     }
 
-    pub fn xrecv(&mut self, msg: &mut Msg) -> Result<(), Box<dyn Error>> {
+    fn xrecv(&mut self, msg: &mut Message) -> Result<(), i32> {
+        // If we are in middle of sending a reply, we cannot receive next request.
         if self.sending_reply {
-            return Err("EFSM".into());
+            return Err(ZMQ_EFSM);
         }
 
+        // First thing to do when receiving a request is to copy all the labels
+        // to the reply pipe.
         if self.request_begins {
             loop {
                 self.router.xrecv(msg)?;
 
-                if (msg.flags() & Msg::MORE) != 0 {
+                if msg.has_more() {
+                    // Empty message part delimits the traceback stack.
                     let bottom = msg.size() == 0;
+
+                    // Push it to the reply pipe.
                     self.router.xsend(msg)?;
 
                     if bottom {
                         break;
                     }
                 } else {
-                    self.router.rollback()?;
+                    // If the traceback stack is malformed, discard anything
+                    // already sent to pipe (we're at end of invalid message).
+                    self.router.rollback();
                 }
             }
             self.request_begins = false;
         }
 
+        // Get next message part to return to the user.
         self.router.xrecv(msg)?;
 
-        if (msg.flags() & Msg::MORE) == 0 {
+        // If whole request is read, flip the FSM to reply-sending state.
+        if !msg.has_more() {
             self.sending_reply = true;
             self.request_begins = true;
         }
@@ -123,17 +120,19 @@ impl Rep {
         Ok(())
     }
 
-    pub fn xhas_in(&self) -> bool {
+    fn xhas_in(&mut self) -> bool {
         if self.sending_reply {
             return false;
         }
+
         self.router.xhas_in()
     }
 
-    pub fn xhas_out(&self) -> bool {
+    fn xhas_out(&mut self) -> bool {
         if !self.sending_reply {
             return false;
         }
+
         self.router.xhas_out()
     }
 }
@@ -1,94 +1,148 @@
-use std::mem;
-use std::os::raw::c_void;
-
-use crate::constants::ZMQ_EFSM;
+use crate::constants::{ZMQ_EFSM, ZMQ_REQ, ZMQ_REQ_CORRELATE, ZMQ_REQ_RELAXED};
 use crate::context::Context;
+use crate::dealer::Dealer;
+use crate::message::{Message, MsgFlags};
+use crate::options::int_at_least;
+use crate::pipe::Pipe;
+use crate::random::generate_random;
+use crate::socket_base::{SocketBase, SocketBehavior};
+use std::mem;
+use std::sync::Arc;
 
-// Constants from ZMQ
-const ZMQ_REQ: i32 = 3;
-const ZMQ_REQ_CORRELATE: i32 = 1;
-const ZMQ_REQ_RELAXED: i32 = 2;
-
-// Forward declarations
-pub struct Dealer;
-pub struct Pipe;
-pub struct Msg;
-pub struct IoThread;
-pub struct SocketBase;
-pub struct Options {
-    type_: i32,
-}
-
-#[derive(Debug)]
-pub enum ReqSessionState {
-    Bottom,
-    RequestId,
-    Body,
-}
-
-pub struct ReqT {
+/// REQ socket: a DEALER enforcing strict request/reply alternation and
+/// prefixing each request with an empty delimiter frame.
+pub struct Req {
     dealer: Dealer,
+
+    // If true, request was already sent and reply wasn't received yet or
+    // was received partially.
     receiving_reply: bool,
+
+    // If true, we are starting to send/recv a message. The first part
+    // of the message must be empty message part (backtrace stack bottom).
     message_begins: bool,
-    reply_pipe: Option<Box<Pipe>>,
+
+    // The pipe the request was sent to and where the reply is expected.
+    reply_pipe: Option<Pipe>,
+
+    // Whether request id frames shall be sent and expected.
     request_id_frames_enabled: bool,
+
+    // The current request id. It is incremented every time before a new
+    // request is sent.
     request_id: u32,
+
+    // If false, send() will reset its internal state and terminate the
+    // reply_pipe's connection instead of failing if a previous request is
+    // still pending.
     strict: bool,
 }
 
-pub struct ReqSession {
-    state: ReqSessionState,
-    // Other session fields would go here
-}
+impl Req {
+    pub fn new(ctx: &Arc<Context>, tid: u32, sid: i32) -> Self {
+        let mut dealer = Dealer::new(ctx, tid, sid);
+        dealer.base_mut().options.socket_type = ZMQ_REQ;
 
-impl ReqT {
-    pub fn new(parent: &mut Context, tid: u32, sid: i32) -> Self {
-        let mut req = ReqT {
-            dealer: Dealer,
+        Req {
+            dealer,
             receiving_reply: false,
             message_begins: true,
             reply_pipe: None,
             request_id_frames_enabled: false,
             request_id: generate_random(),
             strict: true,
-        };
-        // Set socket type
-        // options.type_ = ZMQ_REQ;
-        req
+        }
     }
 
-    pub fn xsend(&mut self, msg: &mut Msg) -> Result<(), i32> {
+    // Receive only from the pipe the request was sent to, discarding
+    // frames from other pipes.
+    fn recv_reply_pipe(&mut self, msg: &mut Message) -> Result<(), i32> {
+        loop {
+            let pipe = self.dealer.recvpipe(msg)?;
+            if self.reply_pipe.is_none() || self.reply_pipe.as_ref() == Some(&pipe) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl SocketBehavior for Req {
+    fn base(&self) -> &SocketBase {
+        self.dealer.base()
+    }
+
+    fn base_mut(&mut self) -> &mut SocketBase {
+        self.dealer.base_mut()
+    }
+
+    fn xattach_pipe(&mut self, pipe: Pipe, subscribe_to_all: bool, locally_initiated: bool) {
+        self.dealer.xattach_pipe(pipe, subscribe_to_all, locally_initiated);
+    }
+
+    fn xsetsockopt(&mut self, option: i32, optval: &[u8]) -> Result<(), i32> {
+        match option {
+            ZMQ_REQ_CORRELATE => {
+                self.request_id_frames_enabled = int_at_least(optval, 0)? != 0;
+                Ok(())
+            }
+            ZMQ_REQ_RELAXED => {
+                self.strict = int_at_least(optval, 0)? == 0;
+                Ok(())
+            }
+            _ => self.dealer.xsetsockopt(option, optval),
+        }
+    }
+
+    fn xsend(&mut self, msg: &mut Message) -> Result<(), i32> {
+        // If we've sent a request and we still haven't got the reply,
+        // we can't send another request unless the strict option is disabled.
         if self.receiving_reply {
             if self.strict {
                 return Err(ZMQ_EFSM);
             }
+
             self.receiving_reply = false;
             self.message_begins = true;
         }
 
+        // First part of the request is the request routing id.
         if self.message_begins {
             self.reply_pipe = None;
 
             if self.request_id_frames_enabled {
-                self.request_id += 1;
-                // Create and send request ID frame
-                // ... implementation details ...
+                self.request_id = self.request_id.wrapping_add(1);
+
+                let mut id = Message::with_data(&self.request_id.to_ne_bytes())
+                    .map_err(|_| libc::ENOMEM)?;
+                id.set_flags(MsgFlags::More);
+
+                self.reply_pipe = self.dealer.sendpipe(&mut id)?;
             }
 
-            // Send bottom frame
-            // ... implementation details ...
+            let mut bottom = Message::new();
+            bottom.set_flags(MsgFlags::More);
 
+            let pipe = self.dealer.sendpipe(&mut bottom)?;
+            if self.reply_pipe.is_none() {
+                self.reply_pipe = pipe;
+            }
+            debug_assert!(self.reply_pipe.is_some());
             self.message_begins = false;
 
-            // Clear any pending messages
-            // ... implementation details ...
+            // Eat all currently available messages before the request is fully
+            // sent. This is done to avoid:
+            //   REQ sends request to A, A replies, B replies too.
+            //   A's reply was first and matches, that is used.
+            //   An hour later REQ sends a request to B. B's old reply is used.
+            let mut drop = Message::new();
+            while self.dealer.xrecv(&mut drop).is_ok() {}
         }
 
         let more = msg.has_more();
 
-        // Send the actual message
-        // ... implementation details ...
+        self.dealer.xsend(msg)?;
 
+        // If the request was fully sent, flip the FSM into reply-receiving state.
         if !more {
             self.receiving_reply = true;
             self.message_begins = true;
@@ -97,26 +151,45 @@ impl ReqT {
         Ok(())
     }
 
-    pub fn xrecv(&mut self, msg: &mut Msg) -> Result<(), i32> {
+    fn xrecv(&mut self, msg: &mut Message) -> Result<(), i32> {
+        // If request wasn't send, we can't wait for reply.
         if !self.receiving_reply {
             return Err(ZMQ_EFSM);
         }
 
+        // Skip messages until one with the right first frames is found.
         while self.message_begins {
+            // If enabled, the first frame must have the correct request_id.
             if self.request_id_frames_enabled {
-                // Handle request ID frame
-                // ... implementation details ...
+                self.recv_reply_pipe(msg)?;
+
+                if !msg.has_more() || msg.data() != self.request_id.to_ne_bytes() {
+                    // Skip the remaining frames and try the next message
+                    while msg.has_more() {
+                        self.recv_reply_pipe(msg)?;
+                    }
+                    continue;
+                }
             }
 
-            // Handle bottom frame
-            // ... implementation details ...
+            // The next frame must be 0.
+            // TODO: Failing this check should also close the connection with the peer!
+            self.recv_reply_pipe(msg)?;
+
+            if !msg.has_more() || msg.size() != 0 {
+                // Skip remaining frames and try the next message
+                while msg.has_more() {
+                    self.recv_reply_pipe(msg)?;
+                }
+                continue;
+            }
 
             self.message_begins = false;
         }
 
-        // Receive message
-        // ... implementation details ...
+        self.recv_reply_pipe(msg)?;
 
+        // If the reply is fully received, flip the FSM into request-sending state.
         if !msg.has_more() {
             self.receiving_reply = false;
             self.message_begins = true;
@@ -125,111 +198,98 @@ impl ReqT {
         Ok(())
     }
 
-    pub fn xhas_in(&self) -> bool {
+    fn xhas_in(&mut self) -> bool {
+        // TODO: Duplicates should be removed here.
         if !self.receiving_reply {
             return false;
         }
-        // Return dealer has_in
-        true
+
+        self.dealer.xhas_in()
     }
 
-    pub fn xhas_out(&self) -> bool {
+    fn xhas_out(&mut self) -> bool {
         if self.receiving_reply && self.strict {
             return false;
         }
-        // Return dealer has_out
-        true
-    }
 
-    pub fn xsetsockopt(
-        &mut self,
-        option: i32,
-        optval: *const c_void,
-        optvallen: usize,
-    ) -> Result<(), i32> {
-        let is_int = optvallen == std::mem::size_of::<i32>();
-        let mut value = 0;
-
-        if is_int {
-            unsafe {
-                value = *(optval as *const i32);
-            }
-        }
+        self.dealer.xhas_out()
+    }
 
-        match option {
-            ZMQ_REQ_CORRELATE => {
-                if is_int && value >= 0 {
-                    self.request_id_frames_enabled = value != 0;
-                    return Ok(());
-                }
-            }
-            ZMQ_REQ_RELAXED => {
-                if is_int && value >= 0 {
-                    self.strict = value == 0;
-                    return Ok(());
-                }
-            }
-            _ => {}
-        }
+    fn xread_activated(&mut self, pipe: &Pipe) {
+        self.dealer.xread_activated(pipe);
+    }
 
-        // Forward to dealer implementation
-        Ok(())
+    fn xwrite_activated(&mut self, pipe: &Pipe) {
+        self.dealer.xwrite_activated(pipe);
     }
 
-    pub fn xpipe_terminated(&mut self, pipe: &Pipe) {
-        if let Some(reply_pipe) = &self.reply_pipe {
-            // if reply_pipe matches pipe, set to None
+    fn xpipe_terminated(&mut self, pipe: &Pipe) {
+        if self.reply_pipe.as_ref() == Some(pipe) {
             self.reply_pipe = None;
         }
-        // Forward to dealer implementation
+        self.dealer.xpipe_terminated(pipe);
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ReqSessionState {
+    Bottom,
+    RequestId,
+    Body,
+}
+
+/// Checks the replies arriving at the session of a REQ socket: an
+/// optional request id, the empty delimiter, then the body. Anything else
+/// is a protocol error and costs the peer its connection.
+pub(crate) struct ReqSession {
+    state: ReqSessionState,
+}
+
 impl ReqSession {
-    pub fn new(
-        io_thread: &mut IoThread,
-        connect: bool,
-        socket: &mut SocketBase,
-        options: &Options,
-        addr: &str,
-    ) -> Self {
+    pub(crate) fn new() -> Self {
         ReqSession {
             state: ReqSessionState::Bottom,
         }
     }
 
-    pub fn push_msg(&mut self, msg: &Msg) -> Result<(), i32> {
-        // Ignore commands
+    /// Returns EFAULT if the message can't come next in a reply.
+    pub(crate) fn check_msg(&mut self, msg: &Message) -> Result<(), i32> {
+        // Ignore commands, they are processed by the engine and should not
+        // affect the state machine.
         if msg.is_command() {
             return Ok(());
         }
 
+        let more_only = msg.flags() == MsgFlags::More as u32;
         match self.state {
             ReqSessionState::Bottom => {
-                if msg.has_more() {
+                if more_only {
+                    // In case option ZMQ_CORRELATE is on, allow request_id to be
+                    // transferred as first frame (would be too cumbersome to check
+                    // whether the option is actually on or not).
                     if msg.size() == mem::size_of::<u32>() {
                         self.state = ReqSessionState::RequestId;
-                        return self.session_push_msg(msg);
+                        return Ok(());
                     }
                     if msg.size() == 0 {
                         self.state = ReqSessionState::Body;
-                        return self.session_push_msg(msg);
+                        return Ok(());
                     }
                 }
             }
             ReqSessionState::RequestId => {
-                if msg.has_more() && msg.size() == 0 {
+                if more_only && msg.size() == 0 {
                     self.state = ReqSessionState::Body;
-                    return self.session_push_msg(msg);
+                    return Ok(());
                 }
             }
             ReqSessionState::Body => {
-                if msg.has_more() {
-                    return self.session_push_msg(msg);
+                if more_only {
+                    return Ok(());
                 }
-                if !msg.has_more() {
+                if msg.flags() == 0 {
                     self.state = ReqSessionState::Bottom;
-                    return self.session_push_msg(msg);
+                    return Ok(());
                 }
             }
         }
@@ -237,42 +297,7 @@ impl ReqSession {
         Err(libc::EFAULT)
     }
 
-    pub fn reset(&mut self) {
-        // Reset session
+    pub(crate) fn reset(&mut self) {
         self.state = ReqSessionState::Bottom;
     }
-
-    fn session_push_msg(&self, msg: &Msg) -> Result<(), i32> {
-        // Implementation would go here
-        Ok(())
-    }
-}
-
-// Helper functions and traits
-trait MsgExt {
-    fn has_more(&self) -> bool;
-    fn is_command(&self) -> bool;
-    fn size(&self) -> usize;
-}
-
-impl MsgExt for Msg {
-    fn has_more(&self) -> bool {
-        // Implementation would go here
-        false
-    }
-
-    fn is_command(&self) -> bool {
-        // Implementation would go here
-        false
-    }
-
-    fn size(&self) -> usize {
-        // Implementation would go here
-        0
-    }
-}
-
-fn generate_random() -> u32 {
-    // Implementation would go here
-    0
 }
@@ -1,234 +1,487 @@
+use crate::constants::{
+    ZMQ_CONNECT_ROUTING_ID, ZMQ_EAGAIN, ZMQ_PROBE_ROUTER, ZMQ_ROUTER, ZMQ_ROUTER_HANDOVER,
+    ZMQ_ROUTER_MANDATORY, ZMQ_ROUTER_RAW,
+};
+use crate::context::Context;
+use crate::fair_queue::FairQueue;
+use crate::message::{Message, MsgFlags};
+use crate::options::int_at_least;
+use crate::pipe::Pipe;
+use crate::random::generate_random;
+use crate::socket_base::{SocketBase, SocketBehavior};
+use crate::zmq_draft::{ZMQ_NOTIFY_CONNECT, ZMQ_NOTIFY_DISCONNECT, ZMQ_ROUTER_NOTIFY};
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
-// Constants
-const ZMQ_ROUTER: i32 = 3;
-const ZMQ_ROUTER_MANDATORY: i32 = 54;
-const ZMQ_ROUTER_RAW: i32 = 41; 
-const ZMQ_ROUTER_HANDOVER: i32 = 55;
-const ZMQ_PROBE_ROUTER: i32 = 51;
-
-// Message flags
-const MSG_MORE: u8 = 1;
-
-#[derive(Clone)]
-pub struct Blob {
-    data: Vec<u8>
+// Outbound pipe along with whether it can be written to.
+struct OutPipe {
+    pipe: Pipe,
+    active: bool,
 }
 
-impl Blob {
-    fn new() -> Self {
-        Self { data: Vec::new() }
-    }
+/// ROUTER socket: prefixes every incoming message with the routing id of
+/// the peer it came from and routes outgoing messages by their first frame.
+pub struct Router {
+    base: SocketBase,
 
-    fn from_slice(data: &[u8]) -> Self {
-        Self { data: data.to_vec() }
-    }
+    // Fair queueing object for inbound pipes.
+    fq: FairQueue,
 
-    fn len(&self) -> usize {
-        self.data.len() 
-    }
-}
+    // True iff there is a message held in the pre-fetch buffer.
+    prefetched: bool,
 
-pub struct Msg {
-    data: Vec<u8>,
-    flags: u8,
-    metadata: Option<Arc<Metadata>>,
-}
+    // If true, the receiver got the message part with
+    // the peer's routing id.
+    routing_id_sent: bool,
 
-impl Msg {
-    fn new() -> Self {
-        Self {
-            data: Vec::new(),
-            flags: 0,
-            metadata: None,
-        }
-    }
+    // Holds the prefetched routing id.
+    prefetched_id: Message,
 
-    fn set_flags(&mut self, flags: u8) {
-        self.flags = flags;
-    }
+    // Holds the prefetched message.
+    prefetched_msg: Message,
 
-    fn has_more(&self) -> bool {
-        self.flags & MSG_MORE != 0
-    }
-}
+    // The pipe we are currently reading from
+    current_in: Option<Pipe>,
 
-pub struct Metadata {
-    // Metadata fields
-}
+    // Should current_in should be terminate after all parts received?
+    terminate_current_in: bool,
 
-pub struct Pipe {
-    routing_id: Blob,
-    active: bool,
-}
+    // If true, more incoming message parts are expected.
+    more_in: bool,
 
-impl Pipe {
-    fn new() -> Self {
-        Self {
-            routing_id: Blob::new(),
-            active: true,
-        }
-    }
+    // We keep a set of pipes that have not been identified yet.
+    anonymous_pipes: HashSet<Pipe>,
 
-    fn set_routing_id(&mut self, id: Blob) {
-        self.routing_id = id;
-    }
+    // Outbound pipes indexed by the peer IDs.
+    out_pipes: HashMap<Vec<u8>, OutPipe>,
 
-    fn check_write(&self) -> bool {
-        self.active
-    }
+    // The pipe we are currently writing to.
+    current_out: Option<Pipe>,
 
-    fn check_hwm(&self) -> bool {
-        true // Simplified
-    }
-}
+    // If true, more outgoing message parts are expected.
+    more_out: bool,
 
-pub struct Router {
-    // Fair queuing for inbound pipes
-    fq: FairQueue,
+    // Routing IDs are generated. It's a simple increment and wrap-over
+    // algorithm. This value is the next ID to use (if not used already).
+    next_integral_routing_id: u32,
 
-    // Pre-fetch buffer fields
-    prefetched: bool,
-    routing_id_sent: bool, 
-    prefetched_id: Msg,
-    prefetched_msg: Msg,
+    // Routing id to give the next pipe we connect, set by
+    // ZMQ_CONNECT_ROUTING_ID.
+    connect_routing_id: Vec<u8>,
 
-    // Current pipe tracking
-    current_in: Option<Pipe>,
-    terminate_current_in: bool,
-    current_out: Option<Pipe>, 
-    
-    // Pipe state
-    more_in: bool,
-    more_out: bool,
-    
-    // Collections
-    anonymous_pipes: HashSet<Pipe>,
-    out_pipes: HashMap<Blob, Pipe>,
-
-    // Configuration
+    // If true, report EAGAIN to the caller instead of silently dropping
+    // the message targeting an unknown peer.
     mandatory: bool,
     raw_socket: bool,
+
+    // if true, send an empty message to every connected router peer
     probe_router: bool,
+
+    // If true, the router will reassign an identity upon encountering a
+    // name collision. The new pipe will take the identity, the old pipe
+    // will be terminated.
     handover: bool,
-    
-    next_routing_id: u32,
 }
 
 impl Router {
-    pub fn new() -> Self {
-        Self {
+    pub fn new(ctx: &Arc<Context>, tid: u32, sid: i32) -> Self {
+        let mut base = SocketBase::new(ctx, tid, sid, false);
+        base.options.socket_type = ZMQ_ROUTER;
+        base.options.recv_routing_id = true;
+        base.options.raw_socket = false;
+        base.options.can_send_hello_msg = true;
+        base.options.can_recv_disconnect_msg = true;
+
+        Router {
+            base,
             fq: FairQueue::new(),
             prefetched: false,
             routing_id_sent: false,
-            prefetched_id: Msg::new(),
-            prefetched_msg: Msg::new(),
+            prefetched_id: Message::new(),
+            prefetched_msg: Message::new(),
             current_in: None,
             terminate_current_in: false,
-            current_out: None,
-            more_in: false, 
-            more_out: false,
+            more_in: false,
             anonymous_pipes: HashSet::new(),
             out_pipes: HashMap::new(),
+            current_out: None,
+            more_out: false,
+            next_integral_routing_id: generate_random(),
+            connect_routing_id: Vec::new(),
             mandatory: false,
             raw_socket: false,
             probe_router: false,
             handover: false,
-            next_routing_id: 0,
         }
     }
 
-    pub fn attach_pipe(&mut self, pipe: Pipe, locally_initiated: bool) {
-        if self.probe_router {
-            // Send empty probe message
-            let mut msg = Msg::new();
-            pipe.write(&msg);
+    /// Drops the unfinished outbound message, used by REP when the
+    /// traceback stack of a request is malformed.
+    pub fn rollback(&mut self) {
+        if let Some(pipe) = self.current_out.take() {
+            pipe.rollback();
+            self.more_out = false;
         }
+    }
 
-        if self.identify_peer(&pipe, locally_initiated) {
-            self.fq.attach(pipe);
-        } else {
-            self.anonymous_pipes.insert(pipe);
-        }
+    // Generates the next integral routing id, a zero byte followed by a
+    // 32-bit counter.
+    fn next_integral_routing_id(&mut self) -> Vec<u8> {
+        let mut buf = vec![0u8; 5];
+        buf[1..].copy_from_slice(&self.next_integral_routing_id.to_be_bytes());
+        self.next_integral_routing_id = self.next_integral_routing_id.wrapping_add(1);
+        buf
     }
 
     fn identify_peer(&mut self, pipe: &Pipe, locally_initiated: bool) -> bool {
-        let mut routing_id = Blob::new();
-
-        if locally_initiated && self.has_connect_routing_id() {
-            routing_id = self.get_connect_routing_id();
-            
-            // Don't allow duplicate IDs
-            if self.out_pipes.contains_key(&routing_id) {
-                return false;
-            }
-        } else if self.raw_socket {
-            // Assign integral routing ID for raw sockets
-            let mut buf = vec![0; 5];
-            buf[0] = 0;
-            buf[1..5].copy_from_slice(&self.next_routing_id.to_be_bytes());
-            self.next_routing_id += 1;
-            routing_id = Blob::from_slice(&buf);
+        let routing_id = if locally_initiated && !self.connect_routing_id.is_empty() {
+            let routing_id = std::mem::take(&mut self.connect_routing_id);
+
+            // Not allowed to duplicate an existing rid
+            debug_assert!(!self.out_pipes.contains_key(&routing_id));
+            routing_id
+        } else if self.base.options.raw_socket {
+            // Always assign an integral routing id for raw-socket
+            self.next_integral_routing_id()
         } else {
-            // Handle normal case
-            let mut msg = Msg::new();
+            // Pick up handshake cases and also case where next integral
+            // routing id is set.
+            let mut msg = Message::new();
             if !pipe.read(&mut msg) {
-                return false; 
+                return false;
             }
 
-            if msg.data.is_empty() {
-                // Auto-generate ID
-                let mut buf = vec![0; 5];
-                buf[0] = 0;
-                buf[1..5].copy_from_slice(&self.next_routing_id.to_be_bytes());
-                self.next_routing_id += 1;
-                routing_id = Blob::from_slice(&buf);
+            if msg.size() == 0 {
+                // Fall back on the auto-generation
+                self.next_integral_routing_id()
             } else {
-                routing_id = Blob::from_slice(&msg.data);
+                let routing_id = msg.data().to_vec();
 
-                // Handle duplicate IDs
-                if let Some(existing) = self.out_pipes.get(&routing_id) {
+                // Try to remove an existing routing id entry to allow the new
+                // connection to take the routing id.
+                if self.out_pipes.contains_key(&routing_id) {
                     if !self.handover {
+                        // Ignore peers with duplicate ID
                         return false;
                     }
 
-                    // Handover the routing ID
-                    let mut buf = vec![0; 5];
-                    buf[0] = 0;
-                    buf[1..5].copy_from_slice(&self.next_routing_id.to_be_bytes());
-                    self.next_routing_id += 1;
-                    let new_id = Blob::from_slice(&buf);
-
-                    self.out_pipes.remove(&routing_id);
-                    self.out_pipes.insert(new_id.clone(), existing.clone());
+                    // We will allow the new connection to take over this
+                    // routing id. Temporarily assign a new routing id to the
+                    // existing pipe so we can terminate it asynchronously.
+                    let new_routing_id = self.next_integral_routing_id();
+                    let old = self.out_pipes.remove(&routing_id).expect("pipe exists");
+                    old.pipe.set_router_socket_routing_id(&new_routing_id);
+                    let old_pipe = old.pipe.clone();
+                    self.out_pipes.insert(new_routing_id, old);
 
-                    if Some(existing) == self.current_in {
+                    if self.current_in.as_ref() == Some(&old_pipe) {
                         self.terminate_current_in = true;
+                    } else {
+                        old_pipe.terminate(true);
                     }
                 }
+                routing_id
             }
-        }
+        };
 
-        pipe.set_routing_id(routing_id.clone());
-        self.out_pipes.insert(routing_id, pipe.clone());
+        pipe.set_router_socket_routing_id(&routing_id);
+        self.out_pipes.insert(
+            routing_id,
+            OutPipe {
+                pipe: pipe.clone(),
+                active: true,
+            },
+        );
 
         true
     }
 
-    // Other router methods...
-}
+    // Reads the next message part, skipping the peer's routing id
+    // re-sent after a reconnection.
+    fn recv_skipping_routing_ids(&mut self, msg: &mut Message) -> Result<Pipe, i32> {
+        let mut pipe = self.fq.recvpipe(msg)?;
 
-struct FairQueue {
-    pipes: Vec<Pipe>,
+        // It's possible that we receive peer's routing id. That happens
+        // after reconnection. The current implementation assumes that
+        // the peer always uses the same routing id.
+        while msg.is_routing_id() {
+            pipe = self.fq.recvpipe(msg)?;
+        }
+        Ok(pipe)
+    }
+
+    fn routing_id_frame(pipe: &Pipe) -> Result<Message, i32> {
+        let mut msg = Message::with_data(&pipe.get_routing_id()).map_err(|_| libc::ENOMEM)?;
+        msg.set_flags(MsgFlags::More);
+        Ok(msg)
+    }
+
+    fn finish_current_in(&mut self) {
+        if let Some(pipe) = self.current_in.take() {
+            if self.terminate_current_in {
+                pipe.terminate(true);
+                self.terminate_current_in = false;
+            }
+        }
+    }
 }
 
-impl FairQueue {
-    fn new() -> Self {
-        Self { pipes: Vec::new() }
+impl SocketBehavior for Router {
+    fn base(&self) -> &SocketBase {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut SocketBase {
+        &mut self.base
+    }
+
+    fn xattach_pipe(&mut self, pipe: Pipe, _subscribe_to_all: bool, locally_initiated: bool) {
+        if self.probe_router {
+            let mut probe_msg = Message::new();
+            // Pipe is fresh, there is room for the probe.
+            pipe.write(&mut probe_msg);
+            pipe.flush();
+        }
+
+        if self.identify_peer(&pipe, locally_initiated) {
+            self.fq.attach(pipe);
+        } else {
+            self.anonymous_pipes.insert(pipe);
+        }
+    }
+
+    fn xpipe_terminated(&mut self, pipe: &Pipe) {
+        if !self.anonymous_pipes.remove(pipe) {
+            self.out_pipes.remove(&pipe.get_routing_id());
+            self.fq.pipe_terminated(pipe);
+            pipe.rollback();
+            if self.current_out.as_ref() == Some(pipe) {
+                self.current_out = None;
+            }
+        }
+    }
+
+    fn xread_activated(&mut self, pipe: &Pipe) {
+        if !self.anonymous_pipes.contains(pipe) {
+            self.fq.activated(pipe);
+        } else if self.identify_peer(pipe, false) {
+            self.anonymous_pipes.remove(pipe);
+            self.fq.attach(pipe.clone());
+        }
+    }
+
+    fn xwrite_activated(&mut self, pipe: &Pipe) {
+        if let Some(out) = self.out_pipes.values_mut().find(|out| out.pipe == *pipe) {
+            out.active = true;
+        }
     }
 
-    fn attach(&mut self, pipe: Pipe) {
-        self.pipes.push(pipe);
+    fn xsetsockopt(&mut self, option: i32, optval: &[u8]) -> Result<(), i32> {
+        match option {
+            ZMQ_CONNECT_ROUTING_ID => {
+                // TODO why isn't it possible to set an empty connect_routing_id
+                //   (which is the default value)
+                if optval.is_empty() {
+                    return Err(libc::EINVAL);
+                }
+                self.connect_routing_id = optval.to_vec();
+            }
+            ZMQ_ROUTER_RAW => {
+                self.raw_socket = int_at_least(optval, 0)? != 0;
+                if self.raw_socket {
+                    self.base.options.recv_routing_id = false;
+                    self.base.options.raw_socket = true;
+                }
+            }
+            ZMQ_ROUTER_MANDATORY => self.mandatory = int_at_least(optval, 0)? != 0,
+            ZMQ_PROBE_ROUTER => self.probe_router = int_at_least(optval, 0)? != 0,
+            ZMQ_ROUTER_HANDOVER => self.handover = int_at_least(optval, 0)? != 0,
+            ZMQ_ROUTER_NOTIFY => {
+                let value = int_at_least(optval, 0)?;
+                if value > ZMQ_NOTIFY_CONNECT | ZMQ_NOTIFY_DISCONNECT {
+                    return Err(libc::EINVAL);
+                }
+                self.base.options.router_notify = value;
+            }
+            _ => return Err(libc::EINVAL),
+        }
+        Ok(())
+    }
+
+    fn xsend(&mut self, msg: &mut Message) -> Result<(), i32> {
+        // If this is the first part of the message it's the ID of the
+        // peer to send the message to.
+        if !self.more_out {
+            debug_assert!(self.current_out.is_none());
+
+            // If we have malformed message (prefix with no subsequent message)
+            // then just silently ignore it.
+            if msg.has_more() {
+                self.more_out = true;
+
+                // Find the pipe associated with the routing id stored in the prefix.
+                // If there's no such pipe just silently ignore the message, unless
+                // router_mandatory is set.
+                match self.out_pipes.get_mut(msg.data()) {
+                    Some(out) => {
+                        // Check whether pipe is closed or not
+                        if out.pipe.check_write() {
+                            self.current_out = Some(out.pipe.clone());
+                        } else {
+                            // Check whether pipe is full or not
+                            let pipe_full = !out.pipe.check_hwm();
+                            out.active = false;
+
+                            if self.mandatory {
+                                self.more_out = false;
+                                return Err(if pipe_full {
+                                    ZMQ_EAGAIN
+                                } else {
+                                    libc::EHOSTUNREACH
+                                });
+                            }
+                        }
+                    }
+                    None if self.mandatory => {
+                        self.more_out = false;
+                        return Err(libc::EHOSTUNREACH);
+                    }
+                    None => {}
+                }
+            }
+
+            *msg = Message::new();
+            return Ok(());
+        }
+
+        // Ignore the MORE flag for raw-sock or assert?
+        if self.base.options.raw_socket {
+            msg.reset_flags(MsgFlags::More);
+        }
+
+        // Check whether this is the last part of the message.
+        self.more_out = msg.has_more();
+
+        // Push the message into the pipe. If there's no out pipe, just drop it.
+        match self.current_out.clone() {
+            // Close the remote connection if user has asked to do so
+            // by sending zero length message.
+            // Pending messages in the pipe will be dropped (on receiving term- ack)
+            Some(pipe) if self.raw_socket && msg.size() == 0 => {
+                pipe.terminate(false);
+                *msg = Message::new();
+                self.current_out = None;
+            }
+            Some(pipe) => {
+                if pipe.write(msg) {
+                    if !self.more_out {
+                        pipe.flush();
+                        self.current_out = None;
+                    }
+                } else {
+                    // HWM was checked before, so the pipe must be gone. Roll back
+                    // messages that were piped, for example REP labels.
+                    pipe.rollback();
+                    self.current_out = None;
+                    *msg = Message::new();
+                }
+            }
+            None => *msg = Message::new(),
+        }
+
+        Ok(())
+    }
+
+    fn xrecv(&mut self, msg: &mut Message) -> Result<(), i32> {
+        if self.prefetched {
+            if !self.routing_id_sent {
+                *msg = std::mem::take(&mut self.prefetched_id);
+                self.routing_id_sent = true;
+            } else {
+                *msg = std::mem::take(&mut self.prefetched_msg);
+                self.prefetched = false;
+            }
+            self.more_in = msg.has_more();
+
+            if !self.more_in {
+                self.finish_current_in();
+            }
+            return Ok(());
+        }
+
+        let pipe = self.recv_skipping_routing_ids(msg)?;
+
+        // If we are in the middle of reading a message, just return the next part.
+        if self.more_in {
+            self.more_in = msg.has_more();
+
+            if !self.more_in {
+                self.finish_current_in();
+            }
+        } else {
+            // We are at the beginning of a message.
+            // Keep the message part we have in the prefetch buffer
+            // and return the ID of the peer instead.
+            self.prefetched_msg = std::mem::take(msg);
+            self.prefetched = true;
+
+            *msg = Self::routing_id_frame(&pipe)?;
+            if let Some(metadata) = self.prefetched_msg.metadata() {
+                msg.set_metadata(Arc::clone(metadata));
+            }
+            self.routing_id_sent = true;
+            self.more_in = true;
+            self.current_in = Some(pipe);
+        }
+
+        Ok(())
+    }
+
+    fn xhas_in(&mut self) -> bool {
+        // If we are in the middle of reading the messages, there are
+        // definitely more parts available.
+        if self.more_in {
+            return true;
+        }
+
+        // We may already have a message pre-fetched.
+        if self.prefetched {
+            return true;
+        }
+
+        // Try to read the next message.
+        // The message, if read, is kept in the pre-fetch buffer.
+        let mut prefetched_msg = Message::new();
+        let pipe = match self.recv_skipping_routing_ids(&mut prefetched_msg) {
+            Ok(pipe) => pipe,
+            Err(e) => {
+                debug_assert_eq!(e, ZMQ_EAGAIN);
+                return false;
+            }
+        };
+
+        self.prefetched_id = match Self::routing_id_frame(&pipe) {
+            Ok(msg) => msg,
+            Err(_) => return false,
+        };
+        if let Some(metadata) = prefetched_msg.metadata() {
+            self.prefetched_id.set_metadata(Arc::clone(metadata));
+        }
+        self.prefetched_msg = prefetched_msg;
+        self.prefetched = true;
+        self.routing_id_sent = false;
+        self.current_in = Some(pipe);
+
+        true
+    }
+
+    fn xhas_out(&mut self) -> bool {
+        // In theory, ROUTER socket is always ready for writing (except when
+        // MANDATORY is set). Whether actual attempt to write succeeds depends
+        // on which pipe the message is going to be routed to.
+        if !self.mandatory {
+            return true;
+        }
+        self.out_pipes.values().any(|out| out.pipe.check_hwm())
     }
 }
@@ -0,0 +1,694 @@
+//! Safe Rust front-end.
+//!
+//! The C API in `lib.rs` is a thin layer over the types defined here: a
+//! `Context` owns the shared state, `Socket`s are created from it and
+//! exchange `Message`s. Every fallible call returns a `ZmqError`, which
+//! carries the same errno the C API would report.
+
+use std::alloc::{handle_alloc_error, Layout};
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+use crate::constants::{
+    ZMQ_DEALER, ZMQ_PAIR, ZMQ_PUB, ZMQ_PULL, ZMQ_PUSH, ZMQ_REP, ZMQ_REQ, ZMQ_ROUTER,
+    ZMQ_STREAM, ZMQ_SUB, ZMQ_XPUB, ZMQ_XSUB,
+};
+use crate::context;
+use crate::err::ZmqError;
+use crate::i_mailbox::IMailbox;
+use crate::message;
+use crate::socket_base::{self, SocketBehavior};
+use crate::sockopt::{GetOption, OptionValue, SetOption};
+use crate::utils;
+use crate::zmq_draft::{
+    ZMQ_CHANNEL, ZMQ_CLIENT, ZMQ_DGRAM, ZMQ_DISH, ZMQ_GATHER, ZMQ_PEER, ZMQ_RADIO,
+    ZMQ_SCATTER, ZMQ_SERVER,
+};
+
+pub use crate::constants::{ZMQ_DONTWAIT as DONTWAIT, ZMQ_SNDMORE as SNDMORE};
+
+pub type Result<T> = std::result::Result<T, ZmqError>;
+
+/// The messaging pattern a socket takes part in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SocketType {
+    Pair,
+    Pub,
+    Sub,
+    Req,
+    Rep,
+    Dealer,
+    Router,
+    Pull,
+    Push,
+    XPub,
+    XSub,
+    Stream,
+    Server,
+    Client,
+    Radio,
+    Dish,
+    Gather,
+    Scatter,
+    Dgram,
+    Peer,
+    Channel,
+}
+
+impl SocketType {
+    /// The `ZMQ_*` value of the socket type.
+    pub fn raw(self) -> i32 {
+        match self {
+            SocketType::Pair => ZMQ_PAIR,
+            SocketType::Pub => ZMQ_PUB,
+            SocketType::Sub => ZMQ_SUB,
+            SocketType::Req => ZMQ_REQ,
+            SocketType::Rep => ZMQ_REP,
+            SocketType::Dealer => ZMQ_DEALER,
+            SocketType::Router => ZMQ_ROUTER,
+            SocketType::Pull => ZMQ_PULL,
+            SocketType::Push => ZMQ_PUSH,
+            SocketType::XPub => ZMQ_XPUB,
+            SocketType::XSub => ZMQ_XSUB,
+            SocketType::Stream => ZMQ_STREAM,
+            SocketType::Server => ZMQ_SERVER,
+            SocketType::Client => ZMQ_CLIENT,
+            SocketType::Radio => ZMQ_RADIO,
+            SocketType::Dish => ZMQ_DISH,
+            SocketType::Gather => ZMQ_GATHER,
+            SocketType::Scatter => ZMQ_SCATTER,
+            SocketType::Dgram => ZMQ_DGRAM,
+            SocketType::Peer => ZMQ_PEER,
+            SocketType::Channel => ZMQ_CHANNEL,
+        }
+    }
+}
+
+impl TryFrom<i32> for SocketType {
+    type Error = ZmqError;
+
+    fn try_from(raw: i32) -> Result<Self> {
+        let socket_type = match raw {
+            ZMQ_PAIR => SocketType::Pair,
+            ZMQ_PUB => SocketType::Pub,
+            ZMQ_SUB => SocketType::Sub,
+            ZMQ_REQ => SocketType::Req,
+            ZMQ_REP => SocketType::Rep,
+            ZMQ_DEALER => SocketType::Dealer,
+            ZMQ_ROUTER => SocketType::Router,
+            ZMQ_PULL => SocketType::Pull,
+            ZMQ_PUSH => SocketType::Push,
+            ZMQ_XPUB => SocketType::XPub,
+            ZMQ_XSUB => SocketType::XSub,
+            ZMQ_STREAM => SocketType::Stream,
+            ZMQ_SERVER => SocketType::Server,
+            ZMQ_CLIENT => SocketType::Client,
+            ZMQ_RADIO => SocketType::Radio,
+            ZMQ_DISH => SocketType::Dish,
+            ZMQ_GATHER => SocketType::Gather,
+            ZMQ_SCATTER => SocketType::Scatter,
+            ZMQ_DGRAM => SocketType::Dgram,
+            ZMQ_PEER => SocketType::Peer,
+            ZMQ_CHANNEL => SocketType::Channel,
+            _ => return Err(ZmqError::InvalidInput),
+        };
+        Ok(socket_type)
+    }
+}
+
+/// Container for all sockets of a process, the equivalent of
+/// `zmq_ctx_new()`. Cloning yields another handle to the same context.
+#[derive(Clone)]
+pub struct Context {
+    inner: Arc<context::Context>,
+}
+
+impl Context {
+    pub fn new() -> Self {
+        Context {
+            inner: Arc::new(context::Context::new()),
+        }
+    }
+
+    /// Creates a socket of the given type in this context.
+    pub fn socket(&self, socket_type: SocketType) -> Result<Socket> {
+        let socket = self.inner.create_socket(socket_type.raw())?;
+        Ok(Socket {
+            socket: Mutex::new(socket),
+            socket_type,
+        })
+    }
+
+    /// Sets a `ZMQ_*` context option, as `zmq_ctx_set()` does.
+    pub fn set(&self, option: i32, value: i32) -> Result<()> {
+        Ok(self.inner.set(option, value)?)
+    }
+
+    /// Reads a `ZMQ_*` context option, as `zmq_ctx_get()` does.
+    pub fn get(&self, option: i32) -> Result<i32> {
+        Ok(self.inner.get(option)?)
+    }
+
+    /// Makes blocking calls on every socket of the context return
+    /// `ZmqError::Term`, without waiting for the sockets to be closed.
+    pub fn shutdown(&self) -> Result<()> {
+        Ok(self.inner.shutdown()?)
+    }
+
+    /// Shuts the context down and blocks until every socket created in it
+    /// has been closed.
+    pub fn terminate(&self) -> Result<()> {
+        Ok(self.inner.terminate()?)
+    }
+
+    pub(crate) fn check_tag(&self) -> bool {
+        self.inner.check_tag()
+    }
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Context::new()
+    }
+}
+
+impl fmt::Debug for Context {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Context").finish_non_exhaustive()
+    }
+}
+
+/// A socket created by `Context::socket`. The socket is closed when it is
+/// dropped.
+///
+/// Calls lock the socket, so a `Socket` can be shared between threads. Only
+/// the types reporting `ZMQ_THREAD_SAFE`, such as CLIENT and SERVER, are
+/// meant to be used by several threads at once: a thread blocked on any
+/// other type holds the lock until it returns.
+pub struct Socket {
+    socket: Mutex<Box<dyn SocketBehavior>>,
+    socket_type: SocketType,
+}
+
+impl Socket {
+    pub fn socket_type(&self) -> SocketType {
+        self.socket_type
+    }
+
+    /// Sets a typed option, e.g. `socket.set(SndHwm(1000))`.
+    pub fn set<O: SetOption>(&self, option: O) -> Result<()> {
+        self.setsockopt(O::ID, &option.into_value().to_bytes())
+    }
+
+    /// Reads a typed option, e.g. `socket.get::<LastEndpoint>()`.
+    pub fn get<O: GetOption>(&self) -> Result<O::Value> {
+        let mut optval = vec![0u8; <O::Value as OptionValue>::MAX_SIZE];
+        let len = self.getsockopt(O::ID, &mut optval)?;
+        O::Value::from_bytes(&optval[..len])
+    }
+
+    /// Sets an option from its C representation, as `zmq_setsockopt()`.
+    pub fn setsockopt(&self, option: i32, optval: &[u8]) -> Result<()> {
+        Ok(socket_base::lock(&self.socket).setsockopt(option, optval)?)
+    }
+
+    /// Copies an option into `optval` in its C representation, as
+    /// `zmq_getsockopt()`, returning the number of bytes written.
+    pub fn getsockopt(&self, option: i32, optval: &mut [u8]) -> Result<usize> {
+        Ok(socket_base::lock(&self.socket).getsockopt(option, optval)?)
+    }
+
+    /// Accepts incoming connections on the endpoint.
+    pub fn bind(&self, endpoint: &str) -> Result<()> {
+        Ok(socket_base::lock(&self.socket).bind(endpoint)?)
+    }
+
+    /// Stops accepting connections on an endpoint previously bound.
+    pub fn unbind(&self, endpoint: &str) -> Result<()> {
+        Ok(socket_base::lock(&self.socket).unbind(endpoint)?)
+    }
+
+    /// Creates an outgoing connection to the endpoint.
+    pub fn connect(&self, endpoint: &str) -> Result<()> {
+        Ok(socket_base::lock(&self.socket).connect(endpoint)?)
+    }
+
+    /// Connects a PEER socket and returns the routing id that messages to
+    /// the new peer are addressed with.
+    pub fn connect_peer(&self, endpoint: &str) -> Result<u32> {
+        Ok(socket_base::lock(&self.socket).connect_peer(endpoint)?)
+    }
+
+    /// Drops a connection previously made with `connect`.
+    pub fn disconnect(&self, endpoint: &str) -> Result<()> {
+        Ok(socket_base::lock(&self.socket).disconnect(endpoint)?)
+    }
+
+    /// Joins a group, for DISH sockets.
+    pub fn join(&self, group: &str) -> Result<()> {
+        Ok(socket_base::lock(&self.socket).join(group)?)
+    }
+
+    /// Leaves a group joined with `join`.
+    pub fn leave(&self, group: &str) -> Result<()> {
+        Ok(socket_base::lock(&self.socket).leave(group)?)
+    }
+
+    /// Queues a message part for sending. `flags` is a combination of
+    /// `DONTWAIT` and `SNDMORE`.
+    pub fn send<M: Into<Message>>(&self, msg: M, flags: i32) -> Result<()> {
+        let mut msg = msg.into();
+        self.send_raw(&mut msg.msg, flags)
+    }
+
+    /// Receives a message part into `msg`, replacing its content.
+    pub fn recv(&self, msg: &mut Message, flags: i32) -> Result<()> {
+        self.recv_raw(&mut msg.msg, flags)
+    }
+
+    /// Receives a message part into a new message.
+    pub fn recv_msg(&self, flags: i32) -> Result<Message> {
+        let mut msg = Message::new();
+        self.recv(&mut msg, flags)?;
+        Ok(msg)
+    }
+
+    /// Receives a message part and copies its content out.
+    pub fn recv_bytes(&self, flags: i32) -> Result<Vec<u8>> {
+        self.recv_msg(flags).map(|msg| msg.to_vec())
+    }
+
+    /// Closes the socket, reporting any error that `drop` would swallow.
+    pub fn close(self) -> Result<()> {
+        Ok(socket_base::lock(&self.socket).close()?)
+    }
+
+    pub(crate) fn send_raw(&self, msg: &mut message::Message, flags: i32) -> Result<()> {
+        Ok(socket_base::send_locked(&self.socket, msg, flags)?)
+    }
+
+    pub(crate) fn recv_raw(&self, msg: &mut message::Message, flags: i32) -> Result<()> {
+        Ok(socket_base::recv_locked(&self.socket, msg, flags)?)
+    }
+
+    pub(crate) fn check_tag(&self) -> bool {
+        socket_base::lock(&self.socket).check_tag()
+    }
+
+    /// The socket's command mailbox, which can be written to while another
+    /// thread is blocked on the socket.
+    pub(crate) fn get_mailbox(&self) -> Arc<dyn IMailbox> {
+        socket_base::lock(&self.socket).get_mailbox()
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let socket = self.socket.get_mut().unwrap_or_else(|e| e.into_inner());
+        // Already closed explicitly.
+        if socket.check_tag() {
+            let _ = socket.close();
+        }
+    }
+}
+
+impl fmt::Debug for Socket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Socket")
+            .field("socket_type", &self.socket_type)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A single message part.
+#[derive(Debug, Default)]
+pub struct Message {
+    msg: message::Message,
+}
+
+impl Message {
+    /// Creates an empty message.
+    pub fn new() -> Self {
+        Message {
+            msg: message::Message::new(),
+        }
+    }
+
+    /// Creates a zero-filled message of the given size.
+    pub fn with_size(size: usize) -> Self {
+        match message::Message::with_size(size) {
+            Ok(msg) => Message { msg },
+            Err(_) => handle_alloc_error(Layout::array::<u8>(size).unwrap()),
+        }
+    }
+
+    /// Whether more parts of the same multipart message follow.
+    pub fn get_more(&self) -> bool {
+        self.msg.has_more()
+    }
+
+    /// The content, if it is valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(self.msg.data()).ok()
+    }
+
+    /// The peer a SERVER received the message from, 0 if none.
+    pub fn routing_id(&self) -> u32 {
+        self.msg.get_routing_id()
+    }
+
+    /// Addresses the message to a peer of a SERVER socket. Zero is not a
+    /// valid routing id.
+    pub fn set_routing_id(&mut self, routing_id: u32) -> Result<()> {
+        self.msg
+            .set_routing_id(routing_id)
+            .map_err(|_| ZmqError::InvalidInput)
+    }
+}
+
+impl Deref for Message {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.msg.data()
+    }
+}
+
+impl DerefMut for Message {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.msg.data_mut()
+    }
+}
+
+impl AsRef<[u8]> for Message {
+    fn as_ref(&self) -> &[u8] {
+        self.msg.data()
+    }
+}
+
+impl AsMut<[u8]> for Message {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.msg.data_mut()
+    }
+}
+
+impl From<&[u8]> for Message {
+    fn from(data: &[u8]) -> Self {
+        let mut msg = Message::with_size(data.len());
+        msg.copy_from_slice(data);
+        msg
+    }
+}
+
+impl<const N: usize> From<&[u8; N]> for Message {
+    fn from(data: &[u8; N]) -> Self {
+        Message::from(&data[..])
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(data: Vec<u8>) -> Self {
+        Message::from(&data[..])
+    }
+}
+
+impl From<&str> for Message {
+    fn from(data: &str) -> Self {
+        Message::from(data.as_bytes())
+    }
+}
+
+impl From<String> for Message {
+    fn from(data: String) -> Self {
+        Message::from(data.as_bytes())
+    }
+}
+
+impl From<message::Message> for Message {
+    fn from(msg: message::Message) -> Self {
+        Message { msg }
+    }
+}
+
+/// Generates a new CURVE keypair, returned as the Z85 encoded public and
+/// secret keys. Fails with `NotSupported` unless built with CURVE support.
+pub fn curve_keypair() -> Result<(String, String)> {
+    Ok(utils::curve_keypair()?)
+}
+
+/// Derives the Z85 encoded public key of a Z85 encoded secret key.
+pub fn curve_public(secret_key: &str) -> Result<String> {
+    Ok(utils::curve_public(secret_key)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_socket_type_round_trips() {
+        for raw in 0..=20 {
+            let socket_type = SocketType::try_from(raw).unwrap();
+            assert_eq!(socket_type.raw(), raw);
+        }
+        assert!(matches!(
+            SocketType::try_from(21),
+            Err(ZmqError::InvalidInput)
+        ));
+    }
+
+    #[test]
+    fn test_message_conversions() {
+        let msg = Message::from(vec![1u8, 2, 3]);
+        assert_eq!(msg.as_ref(), &[1, 2, 3]);
+        assert!(!msg.get_more());
+
+        let msg = Message::from("hello");
+        assert_eq!(msg.as_str(), Some("hello"));
+        assert_eq!(msg.len(), 5);
+
+        // Large enough to live outside the message itself.
+        let payload = vec![9u8; 1024];
+        let mut msg = Message::from(payload.clone());
+        assert_eq!(&msg[..], &payload[..]);
+        msg[0] = 1;
+        assert_eq!(msg[0], 1);
+    }
+
+    #[test]
+    fn test_socket_lifecycle() {
+        let ctx = Context::new();
+        let socket = ctx.socket(SocketType::Pair).unwrap();
+        assert_eq!(socket.socket_type(), SocketType::Pair);
+        socket.close().unwrap();
+
+        ctx.terminate().unwrap();
+        assert!(matches!(
+            ctx.socket(SocketType::Pair),
+            Err(ZmqError::Term)
+        ));
+    }
+
+    #[test]
+    fn test_typed_options() {
+        use crate::sockopt::{LastEndpoint, Linger, RcvMore, ReqRelaxed, SndHwm, Subscribe, Type};
+
+        let ctx = Context::new();
+        let socket = ctx.socket(SocketType::Sub).unwrap();
+
+        socket.set(SndHwm(42)).unwrap();
+        assert_eq!(socket.get::<SndHwm>().unwrap(), 42);
+        assert!(matches!(socket.set(Linger(-2)), Err(ZmqError::InvalidInput)));
+        assert_eq!(socket.get::<Type>().unwrap(), ZMQ_SUB);
+        assert!(!socket.get::<RcvMore>().unwrap());
+        assert_eq!(socket.get::<LastEndpoint>().unwrap(), "");
+
+        // Not applicable to SUB sockets.
+        assert!(matches!(socket.set(ReqRelaxed(true)), Err(ZmqError::InvalidInput)));
+
+        let socket = ctx.socket(SocketType::Pair).unwrap();
+        assert!(matches!(
+            socket.set(Subscribe(b"topic".to_vec())),
+            Err(ZmqError::InvalidInput)
+        ));
+    }
+
+    #[test]
+    fn test_client_server_across_threads() {
+        let ctx = Context::new();
+        let server = ctx.socket(SocketType::Server).unwrap();
+        let client = ctx.socket(SocketType::Client).unwrap();
+        server.bind("inproc://client-server").unwrap();
+        client.connect("inproc://client-server").unwrap();
+
+        // Multipart messages are not supported.
+        assert!(matches!(
+            client.send("part", SNDMORE),
+            Err(ZmqError::InvalidInput)
+        ));
+        assert!(matches!(
+            server.send("part", SNDMORE),
+            Err(ZmqError::InvalidInput)
+        ));
+        // Replies need the routing id of a connected peer.
+        assert!(matches!(
+            server.send("reply", 0),
+            Err(ZmqError::HostUnreach)
+        ));
+
+        const THREADS: usize = 4;
+        const REQUESTS: usize = 50;
+        std::thread::scope(|scope| {
+            // A thread blocked receiving must not keep the others from
+            // sending on the same socket.
+            let receiver = scope.spawn(|| {
+                (0..THREADS * REQUESTS)
+                    .map(|_| client.recv_bytes(0).unwrap())
+                    .count()
+            });
+            for _ in 0..THREADS {
+                scope.spawn(|| {
+                    for _ in 0..REQUESTS {
+                        client.send("request", 0).unwrap();
+                    }
+                });
+            }
+
+            for _ in 0..THREADS * REQUESTS {
+                let request = server.recv_msg(0).unwrap();
+                assert_eq!(request.as_str(), Some("request"));
+                assert_ne!(request.routing_id(), 0);
+
+                let mut reply = Message::from("reply");
+                reply.set_routing_id(request.routing_id()).unwrap();
+                server.send(reply, 0).unwrap();
+            }
+            assert_eq!(receiver.join().unwrap(), THREADS * REQUESTS);
+        });
+    }
+
+    #[test]
+    fn test_gather_shared_by_workers() {
+        let ctx = Context::new();
+        let scatter = ctx.socket(SocketType::Scatter).unwrap();
+        let gather = ctx.socket(SocketType::Gather).unwrap();
+        gather.bind("inproc://workers").unwrap();
+        scatter.connect("inproc://workers").unwrap();
+
+        const WORKERS: usize = 4;
+        const JOBS: usize = 100;
+        let done = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..WORKERS)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut jobs = 0;
+                        while gather.recv_bytes(0).unwrap() != b"stop" {
+                            jobs += 1;
+                        }
+                        jobs
+                    })
+                })
+                .collect();
+
+            for _ in 0..JOBS {
+                scatter.send("job", 0).unwrap();
+            }
+            for _ in 0..WORKERS {
+                scatter.send("stop", 0).unwrap();
+            }
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap())
+                .sum::<usize>()
+        });
+        assert_eq!(done, JOBS);
+    }
+
+    #[test]
+    fn test_peer_connect_peer() {
+        let ctx = Context::new();
+        let bound = ctx.socket(SocketType::Peer).unwrap();
+        let connecting = ctx.socket(SocketType::Peer).unwrap();
+        bound.bind("inproc://peer").unwrap();
+
+        // The routing id can be used straight away.
+        let routing_id = connecting.connect_peer("inproc://peer").unwrap();
+        assert_ne!(routing_id, 0);
+        let mut msg = Message::from("hello");
+        msg.set_routing_id(routing_id).unwrap();
+        connecting.send(msg, 0).unwrap();
+
+        // The bound side addresses the connection like a SERVER does.
+        let request = bound.recv_msg(0).unwrap();
+        assert_eq!(request.as_str(), Some("hello"));
+        let mut reply = Message::from("world");
+        reply.set_routing_id(request.routing_id()).unwrap();
+        bound.send(reply, 0).unwrap();
+
+        let reply = connecting.recv_msg(0).unwrap();
+        assert_eq!(reply.as_str(), Some("world"));
+        assert_eq!(reply.routing_id(), routing_id);
+
+        let server = ctx.socket(SocketType::Server).unwrap();
+        assert!(matches!(
+            server.connect_peer("inproc://peer"),
+            Err(ZmqError::NotSupported)
+        ));
+    }
+
+    #[test]
+    fn test_dropping_context_first_stops_io_threads() {
+        let ctx = Context::new();
+        let socket = ctx.socket(SocketType::Pair).unwrap();
+        let io_thread = ctx.inner.choose_io_thread(0).unwrap();
+        drop(ctx);
+        drop(socket);
+        assert_eq!(Arc::strong_count(&io_thread), 1);
+
+        // A listener can outlive its socket for a moment, in which case the
+        // I/O thread itself lets go of the context.
+        let ctx = Context::new();
+        let socket = ctx.socket(SocketType::Pull).unwrap();
+        socket.bind("tcp://127.0.0.1:*").unwrap();
+        let io_thread = ctx.inner.choose_io_thread(0).unwrap();
+        drop(ctx);
+        drop(socket);
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while Arc::strong_count(&io_thread) > 1 && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(Arc::strong_count(&io_thread), 1);
+    }
+
+    #[test]
+    fn test_shutdown_interrupts_recv() {
+        let ctx = Context::new();
+        let socket = ctx.socket(SocketType::Pair).unwrap();
+
+        ctx.shutdown().unwrap();
+        assert!(matches!(socket.recv_msg(0), Err(ZmqError::Term)));
+    }
+
+    #[cfg(feature = "curve")]
+    #[test]
+    fn test_curve_keys() {
+        use crate::sockopt::CurvePublicKey;
+
+        let (public_key, secret_key) = curve_keypair().unwrap();
+        assert_eq!(public_key.len(), 40);
+        assert_eq!(secret_key.len(), 40);
+        assert_eq!(curve_public(&secret_key).unwrap(), public_key);
+
+        // The keys are usable as socket options.
+        let ctx = Context::new();
+        let socket = ctx.socket(SocketType::Req).unwrap();
+        socket.set(CurvePublicKey(public_key.into_bytes())).unwrap();
+
+        assert!(matches!(
+            curve_public("too short"),
+            Err(ZmqError::InvalidInput)
+        ));
+    }
+}
@@ -1,120 +1,64 @@
-//! Scatter socket implementation for ZMQ
-//! Converts the C++ scatter.hpp and scatter.cpp into Rust
-
-use std::error::Error;
-
-/// Message flags
-#[derive(Clone, Copy, PartialEq)]
-pub struct MsgFlags {
-    more: bool,
-}
-
-/// Simple message type
-pub struct Msg {
-    flags: MsgFlags,
-    data: Vec<u8>,
-}
-
-/// Pipe representation
-pub struct Pipe {
-    nodelay: bool,
-}
-
-/// Load balancer for managing outbound pipes
-struct LoadBalancer {
-    pipes: Vec<Pipe>,
-}
-
-impl LoadBalancer {
-    fn new() -> Self {
-        LoadBalancer { pipes: Vec::new() }
-    }
-
-    fn attach(&mut self, pipe: Pipe) {
-        self.pipes.push(pipe);
-    }
-
-    fn activated(&mut self, _pipe: &Pipe) {
-        // Implementation for pipe activation
-    }
-
-    fn pipe_terminated(&mut self, _pipe: &Pipe) {
-        // Implementation for pipe termination
-    }
-
-    fn send(&mut self, msg: &Msg) -> Result<(), Box<dyn Error>> {
-        // Simplified send implementation
-        if self.pipes.is_empty() {
-            return Err("No available pipes".into());
-        }
-        Ok(())
-    }
-
-    fn has_out(&self) -> bool {
-        !self.pipes.is_empty()
-    }
-}
-
-/// Scatter socket implementation
+use crate::context::Context;
+use crate::load_balancer::LoadBalancer;
+use crate::message::Message;
+use crate::pipe::Pipe;
+use crate::socket_base::{SocketBase, SocketBehavior};
+use crate::zmq_draft::ZMQ_SCATTER;
+use std::sync::Arc;
+
+/// SCATTER socket: thread-safe, single-part counterpart of PUSH.
 pub struct Scatter {
+    base: SocketBase,
+    // Load balancer managing the outbound pipes.
     lb: LoadBalancer,
-    socket_type: i32,
 }
 
 impl Scatter {
-    pub fn new() -> Self {
+    pub fn new(ctx: &Arc<Context>, tid: u32, sid: i32) -> Self {
+        let mut base = SocketBase::new(ctx, tid, sid, true);
+        base.options.socket_type = ZMQ_SCATTER;
+
         Scatter {
+            base,
             lb: LoadBalancer::new(),
-            socket_type: 1, // ZMQ_SCATTER constant
         }
     }
+}
 
-    pub fn attach_pipe(&mut self, pipe: Pipe) {
-        pipe.set_nodelay();
-        self.lb.attach(pipe);
+impl SocketBehavior for Scatter {
+    fn base(&self) -> &SocketBase {
+        &self.base
     }
 
-    pub fn send(&mut self, msg: &Msg) -> Result<(), Box<dyn Error>> {
-        if msg.flags.more {
-            return Err("SCATTER sockets do not allow multipart data".into());
-        }
-        self.lb.send(msg)
+    fn base_mut(&mut self) -> &mut SocketBase {
+        &mut self.base
     }
 
-    pub fn has_out(&self) -> bool {
-        self.lb.has_out()
+    fn xattach_pipe(&mut self, pipe: Pipe, _subscribe_to_all: bool, _locally_initiated: bool) {
+        // Don't delay pipe termination as there is no one
+        // to receive the delimiter.
+        pipe.set_nodelay();
+        self.lb.attach(pipe);
     }
 
-    pub fn write_activated(&mut self, pipe: &Pipe) {
+    fn xwrite_activated(&mut self, pipe: &Pipe) {
         self.lb.activated(pipe);
     }
 
-    pub fn pipe_terminated(&mut self, pipe: &Pipe) {
+    fn xpipe_terminated(&mut self, pipe: &Pipe) {
         self.lb.pipe_terminated(pipe);
     }
-}
-
-// Implement methods for Pipe
-impl Pipe {
-    pub fn new() -> Self {
-        Pipe { nodelay: false }
-    }
-
-    fn set_nodelay(&self) {
-        // Implementation for setting nodelay
-    }
-}
 
-// Implement methods for Msg
-impl Msg {
-    pub fn new() -> Self {
-        Msg {
-            flags: MsgFlags { more: false },
-            data: Vec::new(),
+    fn xsend(&mut self, msg: &mut Message) -> Result<(), i32> {
+        // SCATTER sockets do not allow multipart data (ZMQ_SNDMORE)
+        if msg.has_more() {
+            return Err(libc::EINVAL);
         }
+
+        self.lb.send(msg)
     }
 
-    pub fn flags(&self) -> MsgFlags {
-        self.flags
+    fn xhas_out(&mut self) -> bool {
+        self.lb.has_out()
     }
 }
@@ -1,118 +1,140 @@
+use crate::constants::ZMQ_EAGAIN;
+use crate::context::Context;
+use crate::fair_queue::FairQueue;
+use crate::message::Message;
+use crate::pipe::Pipe;
+use crate::random::generate_random;
+use crate::socket_base::{SocketBase, SocketBehavior};
+use crate::zmq_draft::ZMQ_SERVER;
 use std::collections::HashMap;
+use std::sync::Arc;
 
-// Forward declarations
-pub struct Context;
-pub struct Pipe;
-pub struct Message;
-pub struct FairQueue;
-
-#[derive(Default)]
+// Outbound pipe along with whether it can be written to.
 struct OutPipe {
-    pipe: Option<Box<Pipe>>,
+    pipe: Pipe,
     active: bool,
 }
 
+/// SERVER socket: talks to many CLIENT peers, addressing each through the
+/// integral routing id carried by the messages.
 pub struct Server {
+    base: SocketBase,
     // Fair queueing object for inbound pipes
     fq: FairQueue,
     // Outbound pipes indexed by peer IDs
     out_pipes: HashMap<u32, OutPipe>,
-    // Next routing ID to use
+    // Routing IDs are generated. It's a simple increment and wrap-over
+    // algorithm. This value is the next ID to use (if not used already).
     next_routing_id: u32,
 }
 
 impl Server {
-    pub fn new(parent: &Context, tid: u32, sid: i32) -> Self {
+    pub fn new(ctx: &Arc<Context>, tid: u32, sid: i32) -> Self {
+        let mut base = SocketBase::new(ctx, tid, sid, true);
+        base.options.socket_type = ZMQ_SERVER;
+        base.options.can_send_hello_msg = true;
+        base.options.can_recv_disconnect_msg = true;
+
         Self {
-            fq: FairQueue::default(),
+            base,
+            fq: FairQueue::new(),
             out_pipes: HashMap::new(),
             next_routing_id: generate_random(),
         }
     }
+}
+
+impl SocketBehavior for Server {
+    fn base(&self) -> &SocketBase {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut SocketBase {
+        &mut self.base
+    }
 
-    pub fn attach_pipe(&mut self, pipe: Box<Pipe>, subscribe_to_all: bool, locally_initiated: bool) {
-        let routing_id = self.next_routing_id;
-        self.next_routing_id += 1;
-        if self.next_routing_id == 0 {
-            self.next_routing_id += 1; // Never use Routing ID zero
+    fn xattach_pipe(&mut self, pipe: Pipe, _subscribe_to_all: bool, _locally_initiated: bool) {
+        let mut routing_id = self.next_routing_id;
+        self.next_routing_id = self.next_routing_id.wrapping_add(1);
+        if routing_id == 0 {
+            // Never use Routing ID zero
+            routing_id = self.next_routing_id;
+            self.next_routing_id = self.next_routing_id.wrapping_add(1);
         }
 
         pipe.set_server_socket_routing_id(routing_id);
-        
-        let outpipe = OutPipe {
-            pipe: Some(pipe),
-            active: true,
-        };
-        
-        self.out_pipes.insert(routing_id, outpipe);
+        // Add the record into output pipes lookup table
+        self.out_pipes.insert(
+            routing_id,
+            OutPipe {
+                pipe: pipe.clone(),
+                active: true,
+            },
+        );
+
         self.fq.attach(pipe);
     }
 
-    pub fn send(&mut self, msg: &mut Message) -> Result<(), i32> {
-        // SERVER sockets do not allow multipart data
+    fn xpipe_terminated(&mut self, pipe: &Pipe) {
+        self.out_pipes.remove(&pipe.get_server_socket_routing_id());
+        self.fq.pipe_terminated(pipe);
+    }
+
+    fn xread_activated(&mut self, pipe: &Pipe) {
+        self.fq.activated(pipe);
+    }
+
+    fn xwrite_activated(&mut self, pipe: &Pipe) {
+        if let Some(out) = self.out_pipes.get_mut(&pipe.get_server_socket_routing_id()) {
+            out.active = true;
+        }
+    }
+
+    fn xsend(&mut self, msg: &mut Message) -> Result<(), i32> {
+        // SERVER sockets do not allow multipart data (ZMQ_SNDMORE)
         if msg.has_more() {
             return Err(libc::EINVAL);
         }
 
+        // Find the pipe associated with the routing stored in the message.
         let routing_id = msg.get_routing_id();
-        if let Some(outpipe) = self.out_pipes.get_mut(&routing_id) {
-            if !outpipe.pipe.as_ref().unwrap().check_write() {
-                outpipe.active = false;
-                return Err(libc::EAGAIN);
-            }
-
-            msg.reset_routing_id()?;
-            
-            if !outpipe.pipe.as_ref().unwrap().write(msg) {
-                msg.close()?;
-            } else {
-                outpipe.pipe.as_ref().unwrap().flush();
-            }
-            
-            msg.init()?;
-            Ok(())
+        let out = match self.out_pipes.get_mut(&routing_id) {
+            Some(out) => out,
+            None => return Err(libc::EHOSTUNREACH),
+        };
+        if !out.pipe.check_write() {
+            out.active = false;
+            return Err(ZMQ_EAGAIN);
+        }
+
+        // Message might be delivered over inproc, so we reset routing id
+        msg.reset_routing_id();
+
+        if out.pipe.write(msg) {
+            out.pipe.flush();
         } else {
-            Err(libc::EHOSTUNREACH)
+            *msg = Message::new();
         }
+
+        Ok(())
     }
 
-    pub fn recv(&mut self, msg: &mut Message) -> Result<(), i32> {
-        let mut pipe = None;
-        
-        // Handle multi-frame messages
-        loop {
-            match self.fq.recvpipe(msg, &mut pipe) {
-                Ok(_) => {
-                    if !msg.has_more() {
-                        break;
-                    }
-                    // Drop all frames of multi-frame message
-                    while msg.has_more() {
-                        self.fq.recvpipe(msg, None)?;
-                    }
-                }
-                Err(e) => return Err(e),
-            }
-        }
+    fn xrecv(&mut self, msg: &mut Message) -> Result<(), i32> {
+        let pipe = self.fq.recvpipe_single_part(msg)?;
+
+        let _ = msg.set_routing_id(pipe.get_server_socket_routing_id());
 
-        let pipe = pipe.expect("Pipe should be available");
-        let routing_id = pipe.get_server_socket_routing_id();
-        msg.set_routing_id(routing_id);
-        
         Ok(())
     }
 
-    pub fn has_in(&self) -> bool {
+    fn xhas_in(&mut self) -> bool {
         self.fq.has_in()
     }
 
-    pub fn has_out(&self) -> bool {
-        true // Server socket is always ready for writing
+    fn xhas_out(&mut self) -> bool {
+        // In theory, SERVER socket is always ready for writing. Whether actual
+        // attempt to write succeeds depends on which pipe the message is going
+        // to be routed to.
+        true
     }
 }
-
-// Helper functions
-fn generate_random() -> u32 {
-    // Implementation needed
-    42
-}
@@ -1,344 +1,792 @@
-use std::collections::HashSet;
-
-// Forward declarations
-pub trait IOThread {}
-pub trait SocketBase {}
-pub trait Engine {
-    fn has_handshake_stage(&self) -> bool;
-    fn plug(&mut self, io_thread: &dyn IOThread, session: &dyn SessionBase);
-    fn terminate(&mut self);
-    fn get_endpoint(&self) -> EndpointUriPair;
-}
-pub trait Pipe {}
-
-#[derive(Clone)]
-pub struct Options {
-    pub mechanism: i32,
-    pub zap_domain: String,
-    pub type_: i32,
-    pub raw_socket: bool,
-    pub immediate: i32,
-    pub reconnect_ivl: i32,
-    pub affinity: u64,
-    pub can_send_hello_msg: bool,
-    pub hello_msg: Vec<u8>,
-    pub can_recv_disconnect_msg: bool,
-    pub disconnect_msg: Vec<u8>,
-    pub can_recv_hiccup_msg: bool,
-    pub hiccup_msg: Vec<u8>,
-    pub rcvhwm: i32,
-    pub sndhwm: i32,
-    #[cfg(feature = "wss")]
-    pub wss_hostname: String,
-}
+//! Sessions sit between a socket and the engine of one connection.
+//!
+//! A session lives in an I/O thread. Towards the socket it owns one end of
+//! a pipe; towards the network it owns the engine, and for connecting
+//! sessions the connecter that (re)establishes the connection. As in
+//! libzmq, a connecting ("active") session outlives its connections: when
+//! the engine fails it starts over, while a session created by a listener
+//! ("passive") terminates along with its connection.
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use crate::command::Command;
+use crate::constants::{ZMQ_EAGAIN, ZMQ_NULL, ZMQ_REP, ZMQ_REQ, ZMQ_ROUTER, ZMQ_SUB, ZMQ_XSUB};
+use crate::context::Context;
+use crate::dish::DishSession;
+use crate::i_engine::{ErrorReason, IEngine};
+use crate::i_mailbox::IMailbox;
+use crate::i_poll_events::IPollEvents;
+use crate::io_object::IoObject;
+use crate::message::{Message, MsgFlags};
+use crate::options::{get_effective_conflate_option, Options};
+use crate::pipe::{create_pipe_pair, Pipe};
+use crate::poller_base::IoContext;
+use crate::radio::RadioSession;
+use crate::req::ReqSession;
+use crate::tcp_connecter::{TcpConnecter, CONNECT_TIMER_ID, RECONNECT_TIMER_ID};
+use crate::udp_address::UdpAddress;
+use crate::udp_engine::UdpEngine;
+use crate::zap_client::ZAP_ENDPOINT;
+use crate::zmq_draft::{ZMQ_DISH, ZMQ_RADIO, ZMQ_SERVER};
+
+// Timer used to cap how long the session lingers for pending messages.
+const LINGER_TIMER_ID: i32 = 0x20;
+
+/// The part of a session its engine works with: the pipe to the socket
+/// and the bookkeeping around it.
+pub struct SessionBase {
+    // If true, this session (re)connects to the peer. Otherwise, it's
+    // a transient session created by the listener.
+    active: bool,
 
-pub struct Address {}
+    // Pipe connecting the session to its socket.
+    pipe: Option<Pipe>,
 
-#[derive(Clone)]
-pub struct EndpointUriPair {}
+    // Pipe used to exchange messages with ZAP socket.
+    zap_pipe: Option<Pipe>,
 
-pub trait Own {
-    fn process_term(&mut self, linger: i32);
-}
+    // This set is added to with pipes we are disconnecting, but haven't
+    // yet completed.
+    terminating_pipes: Vec<Pipe>,
 
-pub trait IOObject {
-    fn add_timer(&mut self, timeout: i32, id: i32);
-    fn cancel_timer(&mut self, id: i32);
-}
+    // This flag is true if the remainder of the message being processed
+    // is still in the in pipe.
+    incomplete_in: bool,
 
-pub trait PipeEvents {
-    fn read_activated(&mut self, pipe: &dyn Pipe);
-    fn write_activated(&mut self, pipe: &dyn Pipe);
-    fn hiccuped(&mut self, pipe: &dyn Pipe);
-    fn pipe_terminated(&mut self, pipe: &dyn Pipe);
-}
+    // True if termination have been suspended to push the pending
+    // messages to the network.
+    pending: bool,
 
-pub trait SessionBase: Own + IOObject + PipeEvents {
-    fn create(
-        io_thread: &dyn IOThread,
-        active: bool,
-        socket: &dyn SocketBase,
-        options: &Options,
-        addr: Box<Address>,
-    ) -> Box<dyn SessionBase>;
-
-    fn attach_pipe(&mut self, pipe: &dyn Pipe);
-    fn reset(&mut self);
-    fn flush(&mut self);
-    fn rollback(&mut self);
-    fn engine_error(&mut self, handshaked: bool, reason: ErrorReason);
-    fn engine_ready(&mut self);
-    fn pull_msg(&mut self, msg: &mut Msg) -> i32;
-    fn push_msg(&mut self, msg: &mut Msg) -> i32;
-    fn read_zap_msg(&mut self, msg: &mut Msg) -> i32;
-    fn write_zap_msg(&mut self, msg: &mut Msg) -> i32;
-    fn get_socket(&self) -> Option<&dyn SocketBase>;
-    fn get_endpoint(&self) -> EndpointUriPair;
-    fn process_plug(&mut self);
-    fn process_attach(&mut self, engine: Box<dyn Engine>);
-    fn process_term(&mut self, linger: i32);
-    fn zap_connect(&mut self) -> i32;
-    fn zap_enabled(&self) -> bool;
-}
+    // True once the session was asked to terminate.
+    terminating: bool,
 
-pub enum ErrorReason {
-    ConnectionError,
-    TimeoutError,
-    ProtocolError,
-}
+    // True if the linger timer is running.
+    has_linger_timer: bool,
 
-pub struct Msg {
-    flags: i32,
-    data: Vec<u8>,
-}
+    // Set when the hello message is to be sent ahead of the socket's
+    // messages on a new connection.
+    hello_pending: bool,
 
-impl Msg {
-    pub fn init(&mut self) -> i32 {
-        self.flags = 0;
-        self.data.clear();
-        0
-    }
+    // Mailbox of the socket the session belongs to.
+    socket: Arc<dyn IMailbox>,
 
-    pub fn init_buffer(&mut self, data: &[u8], size: usize) -> i32 {
-        self.data = data.to_vec();
-        0
-    }
+    // Context of the socket, where the ZAP handler is looked up.
+    ctx: Arc<Context>,
 
-    pub fn flags(&self) -> i32 {
-        self.flags
-    }
+    // Our own mailbox, used as the event sink of our pipes.
+    mailbox: Option<Arc<dyn IMailbox>>,
 
-    pub fn set_flags(&mut self, flags: i32) {
-        self.flags = flags;
-    }
+    // Failure the engine reported during the current callback.
+    engine_failure: Option<(bool, ErrorReason)>,
 
-    pub fn is_subscribe(&self) -> bool {
-        false // Implementation needed
-    }
+    // Endpoint the session was created for, e.g. tcp://127.0.0.1:5555.
+    endpoint: String,
 
-    pub fn is_cancel(&self) -> bool {
-        false // Implementation needed
-    }
+    // Validates the replies to a REQ socket.
+    req_session: Option<ReqSession>,
 
-    pub fn close(&mut self) -> i32 {
-        self.data.clear();
-        0
-    }
-}
+    // Frame the messages of RADIO and DISH sockets as group and body.
+    radio_session: Option<RadioSession>,
+    dish_session: Option<DishSession>,
 
-pub struct SessionBaseImpl {
-    active: bool,
-    pipe: Option<Box<dyn Pipe>>,
-    zap_pipe: Option<Box<dyn Pipe>>,
-    terminating_pipes: HashSet<*const dyn Pipe>,
-    incomplete_in: bool,
-    pending: bool,
-    engine: Option<Box<dyn Engine>>,
-    socket: Option<Box<dyn SocketBase>>,
-    io_thread: Option<Box<dyn IOThread>>,
-    has_linger_timer: bool,
-    addr: Option<Box<Address>>,
-    options: Options,
-    #[cfg(feature = "wss")]
-    wss_hostname: String,
+    pub options: Options,
 }
 
-impl SessionBaseImpl {
-    pub fn new(
-        io_thread: Box<dyn IOThread>,
+impl SessionBase {
+    fn new(
+        ctx: &Arc<Context>,
         active: bool,
-        socket: Box<dyn SocketBase>,
-        options: Options,
-        addr: Box<Address>,
+        options: &Options,
+        socket: Arc<dyn IMailbox>,
+        endpoint: &str,
     ) -> Self {
-        SessionBaseImpl {
+        SessionBase {
             active,
             pipe: None,
             zap_pipe: None,
-            terminating_pipes: HashSet::new(),
+            terminating_pipes: Vec::new(),
             incomplete_in: false,
             pending: false,
-            engine: None,
-            socket: Some(socket),
-            io_thread: Some(io_thread),
+            terminating: false,
             has_linger_timer: false,
-            addr: Some(addr),
-            options,
-            #[cfg(feature = "wss")]
-            wss_hostname: options.wss_hostname.clone(),
+            hello_pending: options.can_send_hello_msg && !options.hello_msg.is_empty(),
+            socket,
+            ctx: Arc::clone(ctx),
+            mailbox: None,
+            engine_failure: None,
+            endpoint: endpoint.to_string(),
+            req_session: if options.socket_type == ZMQ_REQ {
+                Some(ReqSession::new())
+            } else {
+                None
+            },
+            radio_session: if options.socket_type == ZMQ_RADIO {
+                Some(RadioSession::new())
+            } else {
+                None
+            },
+            dish_session: if options.socket_type == ZMQ_DISH {
+                Some(DishSession::new())
+            } else {
+                None
+            },
+            options: options.clone(),
         }
     }
 
-    fn clean_pipes(&mut self) {
-        if let Some(pipe) = &mut self.pipe {
-            pipe.rollback();
-            pipe.flush();
+    /// Fetches a message from the socket. Returns EAGAIN if there is none.
+    pub fn pull_msg(&mut self, msg: &mut Message) -> Result<(), i32> {
+        // The hello message goes out first on every new connection.
+        if self.hello_pending {
+            self.hello_pending = false;
+            *msg = Message::with_data(&self.options.hello_msg).map_err(|_| libc::ENOMEM)?;
+            return Ok(());
         }
 
-        while self.incomplete_in {
-            let mut msg = Msg {
-                flags: 0,
-                data: Vec::new(),
-            };
-            msg.init();
-            self.pull_msg(&mut msg);
-            msg.close();
+        // The body of a radio message follows its group frame.
+        if let Some(body) = self.radio_session.as_mut().and_then(|r| r.take_body()) {
+            *msg = body;
+            return Ok(());
         }
-    }
 
-    fn start_connecting(&mut self, wait: bool) {
-        // Implementation needed
+        match &self.pipe {
+            Some(pipe) if pipe.read(msg) => self.incomplete_in = msg.has_more(),
+            _ => return Err(ZMQ_EAGAIN),
+        }
+
+        if let Some(radio_session) = &mut self.radio_session {
+            radio_session.split_msg(msg)?;
+        }
+        if let Some(dish_session) = &self.dish_session {
+            dish_session.convert_join_leave(msg)?;
+        }
+        Ok(())
     }
 
-    fn reconnect(&mut self) {
-        // Implementation needed
+    /// Delivers a message to the socket. Returns EAGAIN if the pipe is
+    /// full or gone.
+    pub fn push_msg(&mut self, msg: &mut Message) -> Result<(), i32> {
+        // Radios learn about the groups their peers joined from commands.
+        if let Some(radio_session) = &self.radio_session {
+            radio_session.convert_command(msg)?;
+        }
+        // pass subscribe/cancel to the sockets
+        if msg.is_command() && !msg.is_subscribe() && !msg.is_cancel() {
+            return Ok(());
+        }
+        if let Some(req_session) = &mut self.req_session {
+            req_session.check_msg(msg)?;
+        }
+        if let Some(dish_session) = &mut self.dish_session {
+            if !dish_session.check_msg(msg)? {
+                return Ok(());
+            }
+        }
+        match &self.pipe {
+            Some(pipe) if pipe.write(msg) => {
+                if let Some(dish_session) = &mut self.dish_session {
+                    dish_session.msg_pushed();
+                }
+                Ok(())
+            }
+            _ => Err(ZMQ_EAGAIN),
+        }
     }
-}
 
-pub struct HelloMsgSession {
-    base: SessionBaseImpl,
-    new_pipe: bool,
-}
+    /// Forgets the partial message the socket type's framing is in the
+    /// middle of, e.g. after the engine had to drop the rest of it.
+    pub fn reset(&mut self) {
+        if let Some(req_session) = &mut self.req_session {
+            req_session.reset();
+        }
+        if let Some(radio_session) = &mut self.radio_session {
+            radio_session.reset();
+        }
+        if let Some(dish_session) = &mut self.dish_session {
+            dish_session.reset();
+        }
+    }
 
-impl HelloMsgSession {
-    pub fn new(
-        io_thread: Box<dyn IOThread>,
-        active: bool,
-        socket: Box<dyn SocketBase>,
-        options: Options,
-        addr: Box<Address>,
-    ) -> Self {
-        HelloMsgSession {
-            base: SessionBaseImpl::new(io_thread, active, socket, options, addr),
-            new_pipe: true,
+    pub fn flush(&mut self) {
+        if let Some(pipe) = &self.pipe {
+            pipe.flush();
         }
     }
-}
 
-impl SessionBase for HelloMsgSession {
-    fn create(
-        io_thread: &dyn IOThread,
-        active: bool,
-        socket: &dyn SocketBase,
-        options: &Options,
-        addr: Box<Address>,
-    ) -> Box<dyn SessionBase> {
-        // Implementation needed
-        unimplemented!()
-    }
-
-    fn pull_msg(&mut self, msg: &mut Msg) -> i32 {
-        if self.new_pipe {
-            self.new_pipe = false;
-            msg.init_buffer(
-                &self.base.options.hello_msg,
-                self.base.options.hello_msg.len(),
-            )
-        } else {
-            self.base.pull_msg(msg)
+    pub fn rollback(&mut self) {
+        if let Some(pipe) = &self.pipe {
+            pipe.rollback();
         }
     }
 
-    // Implement other required methods...
-    fn attach_pipe(&mut self, _pipe: &dyn Pipe) {
-        unimplemented!()
+    pub fn get_endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
     }
-    fn reset(&mut self) {
-        unimplemented!()
+
+    /// True if connections have to be authenticated through ZAP.
+    pub fn zap_enabled(&self) -> bool {
+        self.options.mechanism != ZMQ_NULL || !self.options.zap_domain.is_empty()
     }
-    fn flush(&mut self) {
-        unimplemented!()
+
+    /// Connects the session to the ZAP handler, unless it is connected
+    /// already. Returns ECONNREFUSED if no handler is bound.
+    pub fn zap_connect(&mut self) -> Result<(), i32> {
+        if self.zap_pipe.is_some() {
+            return Ok(());
+        }
+
+        let peer = self.ctx.find_endpoint(ZAP_ENDPOINT)?;
+        if !matches!(peer.options.socket_type, ZMQ_REP | ZMQ_ROUTER | ZMQ_SERVER) {
+            return Err(libc::ECONNREFUSED);
+        }
+
+        // Create a bi-directional pipe that will connect session with zap
+        // socket. ZAP requests and replies are never dropped, so neither
+        // direction has a HWM.
+        let (local, remote) = create_pipe_pair([false, false], [0, 0]);
+        local.set_nodelay();
+        if let Some(mailbox) = &self.mailbox {
+            local.set_event_sink(Arc::clone(mailbox));
+        }
+
+        // Send empty routing id if required by the peer.
+        if peer.options.recv_routing_id {
+            let mut id = Message::new();
+            id.set_flags(MsgFlags::RoutingId);
+            local.write(&mut id);
+            local.flush();
+        }
+
+        peer.mailbox.send(Command::Bind(remote));
+        self.zap_pipe = Some(local);
+        Ok(())
+    }
+
+    /// Sends a frame of a request to the ZAP handler. The request is
+    /// flushed with its last frame.
+    pub fn write_zap_msg(&mut self, msg: &mut Message) -> Result<(), i32> {
+        let pipe = match &self.zap_pipe {
+            Some(pipe) => pipe,
+            None => return Err(libc::ENOTCONN),
+        };
+        let more = msg.has_more();
+        if !pipe.write(msg) {
+            return Err(libc::ENOTCONN);
+        }
+        if !more {
+            pipe.flush();
+        }
+        Ok(())
+    }
+
+    /// Reads a frame of the ZAP handler's reply. Returns EAGAIN if there
+    /// is none; the session is told when it arrives.
+    pub fn read_zap_msg(&mut self, msg: &mut Message) -> Result<(), i32> {
+        match &self.zap_pipe {
+            Some(pipe) if pipe.read(msg) => Ok(()),
+            Some(_) => Err(ZMQ_EAGAIN),
+            None => Err(libc::ENOTCONN),
+        }
+    }
+
+    /// Following functions are the interface exposed towards the engine.
+    /// The engine calls this once the handshake completed; the pipe to
+    /// the socket is created now unless the session already has one.
+    pub fn engine_ready(&mut self) {
+        // Create the pipe if it does not exist yet.
+        if self.pipe.is_some() || self.terminating {
+            return;
+        }
+
+        let conflate = get_effective_conflate_option(&self.options);
+        let hwms = if conflate {
+            [-1, -1]
+        } else {
+            [
+                self.options.recv_high_water_mark,
+                self.options.send_high_water_mark,
+            ]
+        };
+        let (local, remote) = create_pipe_pair([conflate, conflate], hwms);
+
+        // Plug the local end of the pipe.
+        if let Some(mailbox) = &self.mailbox {
+            local.set_event_sink(Arc::clone(mailbox));
+        }
+        local.set_endpoint(&self.endpoint);
+        remote.set_endpoint(&self.endpoint);
+
+        // Remember the local end of the pipe.
+        self.pipe = Some(local);
+
+        // Ask socket to plug into the remote end of the pipe.
+        self.socket.send(Command::Bind(remote));
     }
-    fn rollback(&mut self) {
-        unimplemented!()
+
+    /// Reports that the engine failed. The session drops the engine once
+    /// the engine's callback returns.
+    pub fn engine_error(&mut self, handshaked: bool, reason: ErrorReason) {
+        self.engine_failure = Some((handshaked, reason));
     }
-    fn engine_error(&mut self, _handshaked: bool, _reason: ErrorReason) {
-        unimplemented!()
+
+    // Remove any half-done messages from the pipes.
+    fn clean_pipes(&mut self) {
+        let pipe = match &self.pipe {
+            Some(pipe) => pipe,
+            None => return,
+        };
+
+        // Get rid of half-processed messages in the out pipe. Flush any
+        // unflushed messages upstream.
+        pipe.rollback();
+        pipe.flush();
+
+        // Remove any half-read message from the in pipe.
+        while self.incomplete_in {
+            let mut msg = Message::new();
+            if !pipe.read(&mut msg) {
+                break;
+            }
+            self.incomplete_in = msg.has_more();
+        }
     }
-    fn engine_ready(&mut self) {
-        unimplemented!()
+
+    // Hands a message to the socket outside of the regular message flow,
+    // e.g. the disconnect and hiccup messages.
+    fn push_notification(&mut self, data: &[u8]) {
+        if let Some(pipe) = &self.pipe {
+            pipe.rollback();
+            if let Ok(mut msg) = Message::with_data(data) {
+                pipe.write(&mut msg);
+            }
+            pipe.flush();
+        }
     }
-    fn push_msg(&mut self, _msg: &mut Msg) -> i32 {
-        unimplemented!()
+}
+
+/// A session as plugged into an I/O thread.
+pub struct Session {
+    base: SessionBase,
+
+    // The protocol I/O engine connected to the session.
+    engine: Option<Box<dyn IEngine>>,
+
+    // Establishes the connection of an active session.
+    connecter: Option<TcpConnecter>,
+
+    // Address to connect to, for active sessions.
+    addr: Option<String>,
+
+    // Address of a udp session, which runs its engine without connecting.
+    udp_addr: Option<UdpAddress>,
+}
+
+impl Session {
+    /// Creates a session connecting to the TCP address `addr` (host:port).
+    /// `pipe` is the session's end of the pipe to the socket when the
+    /// socket doesn't wait for the connection to be established.
+    pub fn new_active(
+        ctx: &Arc<Context>,
+        options: &Options,
+        socket: Arc<dyn IMailbox>,
+        endpoint: &str,
+        addr: &str,
+        pipe: Option<Pipe>,
+    ) -> Self {
+        let mut base = SessionBase::new(ctx, true, options, socket, endpoint);
+        base.pipe = pipe;
+        Session {
+            base,
+            engine: None,
+            connecter: None,
+            addr: Some(addr.to_string()),
+            udp_addr: None,
+        }
     }
-    fn read_zap_msg(&mut self, _msg: &mut Msg) -> i32 {
-        unimplemented!()
+
+    /// Creates the session of a udp endpoint, bound or connected: it
+    /// sends the datagrams of a RADIO socket or receives those of a DISH
+    /// socket. `pipe` is the session's end of the pipe to the socket.
+    pub fn new_udp(
+        ctx: &Arc<Context>,
+        options: &Options,
+        socket: Arc<dyn IMailbox>,
+        endpoint: &str,
+        addr: UdpAddress,
+        pipe: Pipe,
+    ) -> Self {
+        let mut base = SessionBase::new(ctx, true, options, socket, endpoint);
+        base.pipe = Some(pipe);
+        Session {
+            base,
+            engine: None,
+            connecter: None,
+            addr: None,
+            udp_addr: Some(addr),
+        }
     }
-    fn write_zap_msg(&mut self, _msg: &mut Msg) -> i32 {
-        unimplemented!()
+
+    /// Creates a session for a connection accepted by a listener.
+    pub fn new_passive(
+        ctx: &Arc<Context>,
+        options: &Options,
+        socket: Arc<dyn IMailbox>,
+        endpoint: &str,
+        engine: Box<dyn IEngine>,
+    ) -> Self {
+        Session {
+            base: SessionBase::new(ctx, false, options, socket, endpoint),
+            engine: Some(engine),
+            connecter: None,
+            addr: None,
+            udp_addr: None,
+        }
     }
-    fn get_socket(&self) -> Option<&dyn SocketBase> {
-        unimplemented!()
+
+    fn start_connecting(&mut self, wait: bool, io: &mut IoContext) {
+        // Datagrams need no connection, the engine goes straight to work.
+        if let Some(udp_addr) = self.udp_addr.clone() {
+            let socket_type = self.base.options.socket_type;
+            debug_assert!(socket_type == ZMQ_RADIO || socket_type == ZMQ_DISH);
+            let send = socket_type == ZMQ_RADIO;
+            let engine = UdpEngine::new(&self.base.options, udp_addr, send, !send);
+            self.process_attach(Box::new(engine), io);
+            return;
+        }
+
+        let addr = self.addr.as_ref().expect("only active sessions connect");
+
+        // Create the connecter object and plug it in.
+        let mut connecter = TcpConnecter::new(addr, &self.base.endpoint, &self.base.options, wait);
+        connecter.process_plug(io);
+        self.connecter = Some(connecter);
     }
-    fn get_endpoint(&self) -> EndpointUriPair {
-        unimplemented!()
+
+    fn process_attach(&mut self, mut engine: Box<dyn IEngine>, io: &mut IoContext) {
+        // Create the pipe if it does not exist yet. Engines with a
+        // handshake stage ask for it once the handshake is done.
+        if !engine.has_handshake_stage() {
+            self.base.engine_ready();
+        }
+
+        // Plug in the engine.
+        engine.plug(&mut self.base, io);
+        self.engine = Some(engine);
+        self.check_engine(io);
     }
-    fn process_plug(&mut self) {
-        unimplemented!()
+
+    // Runs the failure handling if the engine reported an error during
+    // the call that just returned.
+    fn check_engine(&mut self, io: &mut IoContext) {
+        if let Some((handshaked, reason)) = self.base.engine_failure.take() {
+            // Engine is dead. Let's forget about it.
+            self.engine = None;
+            self.engine_error(handshaked, reason, io);
+        }
     }
-    fn process_attach(&mut self, _engine: Box<dyn Engine>) {
-        unimplemented!()
+
+    fn engine_error(&mut self, handshaked: bool, reason: ErrorReason, io: &mut IoContext) {
+        // Remove any half-done messages from the pipes.
+        if self.base.pipe.is_some() {
+            self.base.clean_pipes();
+
+            // Only send disconnect message if socket was accepted and
+            // handshake was completed
+            if !self.base.active
+                && handshaked
+                && self.base.options.can_recv_disconnect_msg
+                && !self.base.options.disconnect_msg.is_empty()
+            {
+                let msg = self.base.options.disconnect_msg.clone();
+                self.base.push_notification(&msg);
+            }
+
+            // Only send hiccup message if socket was connected and
+            // handshake was completed
+            if self.base.active
+                && handshaked
+                && self.base.options.can_recv_hiccup_msg
+                && !self.base.options.hiccup_msg.is_empty()
+            {
+                let msg = self.base.options.hiccup_msg.clone();
+                self.base.push_notification(&msg);
+            }
+        }
+
+        match reason {
+            ErrorReason::TimeoutError | ErrorReason::ConnectionError if self.base.active => {
+                self.reconnect(io);
+            }
+            _ => {
+                if self.base.pending {
+                    if let Some(pipe) = &self.base.pipe {
+                        pipe.terminate(false);
+                    }
+                    if let Some(zap_pipe) = &self.base.zap_pipe {
+                        zap_pipe.terminate(false);
+                    }
+                } else {
+                    let linger = self.base.options.linger.load(Ordering::SeqCst);
+                    self.process_term(linger, io);
+                }
+            }
+        }
+
+        // Just in case there's only a delimiter in the pipe.
+        if let Some(pipe) = &self.base.pipe {
+            pipe.check_read();
+        }
+        if let Some(zap_pipe) = &self.base.zap_pipe {
+            zap_pipe.check_read();
+        }
     }
-    fn process_term(&mut self, _linger: i32) {
-        unimplemented!()
+
+    fn reconnect(&mut self, io: &mut IoContext) {
+        let options = &self.base.options;
+        let socket_type = options.socket_type;
+
+        // For delayed connect situations, terminate the pipe and
+        // reestablish later on
+        if options.immediate == 1 && socket_type != ZMQ_DISH {
+            if let Some(pipe) = self.base.pipe.take() {
+                pipe.hiccup();
+                pipe.terminate(false);
+                self.base.terminating_pipes.push(pipe);
+                if self.base.has_linger_timer {
+                    io.cancel_timer(LINGER_TIMER_ID);
+                    self.base.has_linger_timer = false;
+                }
+            }
+        }
+
+        // The hello message is sent again on the new connection.
+        self.base.hello_pending =
+            self.base.options.can_send_hello_msg && !self.base.options.hello_msg.is_empty();
+        self.base.reset();
+
+        // Reconnect, unless reconnection was disabled, in which case the
+        // session goes away with the connection.
+        if self.base.options.reconnect_intvl <= 0 {
+            let linger = self.base.options.linger.load(Ordering::SeqCst);
+            self.process_term(linger, io);
+            return;
+        }
+        self.start_connecting(true, io);
+
+        // For subscriber sockets we hiccup the inbound pipe, which will
+        // cause the socket object to resend all the subscriptions.
+        if let Some(pipe) = &self.base.pipe {
+            if matches!(socket_type, ZMQ_SUB | ZMQ_XSUB | ZMQ_DISH) {
+                pipe.hiccup();
+            }
+        }
     }
-    fn zap_connect(&mut self) -> i32 {
-        unimplemented!()
+
+    fn pipe_terminated(&mut self, pipe: &Pipe, io: &mut IoContext) {
+        if self.base.pipe.as_ref() == Some(pipe) {
+            // If this is our current pipe, remove it
+            self.base.pipe = None;
+            if self.base.has_linger_timer {
+                io.cancel_timer(LINGER_TIMER_ID);
+                self.base.has_linger_timer = false;
+            }
+        } else if self.base.zap_pipe.as_ref() == Some(pipe) {
+            self.base.zap_pipe = None;
+        } else {
+            // Remove the pipe from the detached pipes set
+            self.base.terminating_pipes.retain(|p| p != pipe);
+        }
+
+        // Raw sockets don't outlive their connection.
+        if !self.base.terminating && self.base.options.raw_socket {
+            if let Some(mut engine) = self.engine.take() {
+                engine.terminate(io);
+            }
+            let linger = self.base.options.linger.load(Ordering::SeqCst);
+            self.process_term(linger, io);
+            return;
+        }
+
+        // If we are waiting for pending messages to be sent, at this point
+        // we are sure that there will be no more messages and we can
+        // proceed with termination safely.
+        if self.base.pending
+            && self.base.pipe.is_none()
+            && self.base.zap_pipe.is_none()
+            && self.base.terminating_pipes.is_empty()
+        {
+            self.base.pending = false;
+            self.finish(io);
+        }
     }
-    fn zap_enabled(&self) -> bool {
-        unimplemented!()
+
+    fn process_term(&mut self, linger: i32, io: &mut IoContext) {
+        if self.base.terminating {
+            return;
+        }
+        self.base.terminating = true;
+
+        // If the termination of the pipe happens before the term command is
+        // delivered there's nothing much to do. We can proceed with the
+        // standard termination immediately.
+        if self.base.pipe.is_none()
+            && self.base.zap_pipe.is_none()
+            && self.base.terminating_pipes.is_empty()
+        {
+            self.finish(io);
+            return;
+        }
+
+        self.base.pending = true;
+
+        if let Some(pipe) = &self.base.pipe {
+            // If there's finite linger value, delay the termination.
+            // If linger is infinite (negative) we don't even have to set
+            // the timer.
+            if linger > 0 {
+                io.add_timer(linger, LINGER_TIMER_ID);
+                self.base.has_linger_timer = true;
+            }
+
+            // Start pipe termination process. Delay the termination till
+            // all messages are processed in case the linger time is
+            // non-zero. A pipe the socket already asked to terminate is
+            // drained anyway.
+            if !pipe.is_terminating() || linger == 0 {
+                pipe.terminate(linger != 0);
+            }
+
+            // In case there's no engine and there's only delimiter in the
+            // pipe it wouldn't be ever read. Thus we check for it
+            // explicitly.
+            if self.engine.is_none() {
+                pipe.check_read();
+            }
+        }
+
+        if let Some(zap_pipe) = &self.base.zap_pipe {
+            zap_pipe.terminate(false);
+        }
     }
-}
 
-impl Own for HelloMsgSession {
-    fn process_term(&mut self, _linger: i32) {
-        unimplemented!()
+    // Tears down the engine and the connecter and leaves the I/O thread.
+    fn finish(&mut self, io: &mut IoContext) {
+        if let Some(mut engine) = self.engine.take() {
+            engine.terminate(io);
+        }
+        if let Some(mut connecter) = self.connecter.take() {
+            connecter.terminate(io);
+        }
+        io.terminate();
     }
 }
 
-impl IOObject for HelloMsgSession {
-    fn add_timer(&mut self, _timeout: i32, _id: i32) {
-        unimplemented!()
+impl IoObject for Session {
+    fn process_plug(&mut self, io: &mut IoContext) {
+        let mailbox = io.mailbox();
+        if let Some(pipe) = &self.base.pipe {
+            pipe.set_event_sink(Arc::clone(&mailbox));
+        }
+        self.base.mailbox = Some(mailbox);
+
+        if let Some(engine) = self.engine.take() {
+            self.process_attach(engine, io);
+        } else if self.base.active {
+            self.start_connecting(false, io);
+        }
     }
-    fn cancel_timer(&mut self, _id: i32) {
-        unimplemented!()
+
+    fn process_command(&mut self, cmd: Command, io: &mut IoContext) {
+        match cmd {
+            Command::ActivateRead(pipe) => {
+                if self.base.zap_pipe.as_ref() == Some(&pipe) {
+                    if let Some(engine) = &mut self.engine {
+                        engine.zap_msg_available(&mut self.base, io);
+                    }
+                    self.check_engine(io);
+                    return;
+                }
+                if self.base.pipe.as_ref() != Some(&pipe) {
+                    return;
+                }
+                match &mut self.engine {
+                    Some(engine) => engine.restart_output(&mut self.base, io),
+                    None => {
+                        pipe.check_read();
+                    }
+                }
+                self.check_engine(io);
+            }
+            Command::ActivateWrite(_) => {
+                if let Some(engine) = &mut self.engine {
+                    engine.restart_input(&mut self.base, io);
+                }
+                self.check_engine(io);
+            }
+            Command::PipeTerm(pipe) => {
+                if pipe.process_pipe_term() {
+                    self.pipe_terminated(&pipe, io);
+                }
+            }
+            Command::Term(linger) => self.process_term(linger, io),
+            // Hiccups are always sent from session to socket, not the other
+            // way round.
+            _ => {}
+        }
     }
 }
 
-impl PipeEvents for HelloMsgSession {
-    fn read_activated(&mut self, _pipe: &dyn Pipe) {
-        unimplemented!()
-    }
-    fn write_activated(&mut self, _pipe: &dyn Pipe) {
-        unimplemented!()
+impl IPollEvents for Session {
+    fn in_event(&mut self, io: &mut IoContext) {
+        if let Some(engine) = &mut self.engine {
+            engine.in_event(&mut self.base, io);
+            self.check_engine(io);
+        } else if let Some(connecter) = &mut self.connecter {
+            if let Some(engine) = connecter.in_event(io) {
+                self.connecter = None;
+                self.process_attach(engine, io);
+            }
+        }
     }
-    fn hiccuped(&mut self, _pipe: &dyn Pipe) {
-        unimplemented!()
+
+    fn out_event(&mut self, io: &mut IoContext) {
+        if let Some(engine) = &mut self.engine {
+            engine.out_event(&mut self.base, io);
+            self.check_engine(io);
+        } else if let Some(connecter) = &mut self.connecter {
+            if let Some(engine) = connecter.out_event(io) {
+                self.connecter = None;
+                self.process_attach(engine, io);
+            }
+        }
     }
-    fn pipe_terminated(&mut self, _pipe: &dyn Pipe) {
-        unimplemented!()
+
+    fn timer_event(&mut self, id: i32, io: &mut IoContext) {
+        match id {
+            LINGER_TIMER_ID => {
+                // Ask pipe to terminate even though there may be pending
+                // messages in it.
+                self.base.has_linger_timer = false;
+                if let Some(pipe) = &self.base.pipe {
+                    pipe.terminate(false);
+                }
+            }
+            RECONNECT_TIMER_ID | CONNECT_TIMER_ID => {
+                if let Some(connecter) = &mut self.connecter {
+                    connecter.timer_event(id, io);
+                }
+            }
+            _ => {
+                if let Some(engine) = &mut self.engine {
+                    engine.timer_event(id, &mut self.base, io);
+                    self.check_engine(io);
+                }
+            }
+        }
     }
 }
-
-// Constants
-pub const ZMQ_REQ: i32 = 3;
-pub const ZMQ_DEALER: i32 = 5;
-pub const ZMQ_REP: i32 = 4;
-pub const ZMQ_ROUTER: i32 = 6;
-pub const ZMQ_PUB: i32 = 1;
-pub const ZMQ_XPUB: i32 = 9;
-pub const ZMQ_SUB: i32 = 2;
-pub const ZMQ_XSUB: i32 = 10;
-pub const ZMQ_PUSH: i32 = 8;
-pub const ZMQ_PULL: i32 = 7;
-pub const ZMQ_PAIR: i32 = 0;
-pub const ZMQ_STREAM: i32 = 11;
-pub const ZMQ_SERVER: i32 = 12;
-pub const ZMQ_CLIENT: i32 = 13;
-pub const ZMQ_RADIO: i32 = 14;
-pub const ZMQ_DISH: i32 = 15;
-pub const ZMQ_GATHER: i32 = 16;
-pub const ZMQ_SCATTER: i32 = 17;
-pub const ZMQ_DGRAM: i32 = 18;
-pub const ZMQ_PEER: i32 = 19;
-pub const ZMQ_CHANNEL: i32 = 20;
@@ -1,12 +1,7 @@
-use std::io::{self, Read, Write};
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::io;
+use std::os::unix::io::RawFd;
 use std::time::Duration;
 
-#[cfg(unix)]
-use nix::sys::socket::{self, socketpair, AddressFamily, SockFlag, SockType};
-#[cfg(unix)]
-use nix::unistd::{close, getpid, Pid};
-
 #[cfg(windows)]
 use windows_sys::Win32::Networking::WinSock;
 
@@ -16,7 +11,7 @@ pub struct Signaler {
     reader: RawFd,
     writer: RawFd,
     #[cfg(feature = "fork")]
-    pid: Pid,
+    pid: libc::pid_t,
 }
 
 impl Signaler {
@@ -31,7 +26,7 @@ impl Signaler {
                 reader,
                 writer,
                 #[cfg(feature = "fork")]
-                pid: getpid(),
+                pid: unsafe { libc::getpid() },
             })
         }
 
@@ -48,7 +43,7 @@ impl Signaler {
 
     pub fn send(&self) -> io::Result<()> {
         #[cfg(feature = "fork")]
-        if self.pid != getpid() {
+        if self.pid != unsafe { libc::getpid() } {
             return Ok(());
         }
 
@@ -71,10 +66,15 @@ impl Signaler {
         {
             let dummy: u8 = 0;
             loop {
-                match socket::send(self.writer, &[dummy], socket::MsgFlags::empty()) {
-                    Ok(_) => break,
-                    Err(nix::errno::Errno::EINTR) => continue,
-                    Err(e) => return Err(e.into()),
+                let nbytes = unsafe {
+                    libc::send(self.writer, &dummy as *const u8 as *const libc::c_void, 1, 0)
+                };
+                if nbytes == 1 {
+                    break;
+                }
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
                 }
             }
         }
@@ -84,7 +84,7 @@ impl Signaler {
 
     pub fn wait(&self, timeout: Option<Duration>) -> io::Result<()> {
         #[cfg(feature = "fork")]
-        if self.pid != getpid() {
+        if self.pid != unsafe { libc::getpid() } {
             return Err(io::Error::from_raw_os_error(libc::EINTR));
         }
 
@@ -124,9 +124,11 @@ impl Signaler {
         #[cfg(not(target_os = "linux"))]
         {
             let mut dummy = [0u8; 1];
-            match socket::recv(self.reader, &mut dummy, socket::MsgFlags::empty()) {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e.into()),
+            let nbytes = unsafe {
+                libc::recv(self.reader, dummy.as_mut_ptr() as *mut libc::c_void, 1, 0)
+            };
+            if nbytes != 1 {
+                return Err(io::Error::last_os_error());
             }
         }
 
@@ -139,8 +141,10 @@ impl Signaler {
 
     #[cfg(feature = "fork")]
     pub fn forked(&mut self) -> io::Result<()> {
-        close(self.reader)?;
-        close(self.writer)?;
+        unsafe {
+            libc::close(self.reader);
+            libc::close(self.writer);
+        }
         let (reader, writer) = Self::make_fdpair()?;
         self.reader = reader;
         self.writer = writer;
@@ -149,21 +153,20 @@ impl Signaler {
 
     #[cfg(unix)]
     fn make_fdpair() -> io::Result<(RawFd, RawFd)> {
-        let (fd1, fd2) = socketpair(
-            AddressFamily::Unix,
-            SockType::Stream,
-            None,
-            SockFlag::empty(),
-        )?;
-        Ok((fd1, fd2))
+        let mut fds = [RETIRED_FD; 2];
+        let rc = unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) };
+        if rc == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((fds[0], fds[1]))
     }
 
     #[cfg(unix)]
     fn unblock_socket(fd: RawFd) -> io::Result<()> {
-        use nix::fcntl::{fcntl, FcntlArg, OFlag};
-        let flags = fcntl(fd, FcntlArg::F_GETFL)?;
-        let flags = OFlag::from_bits_truncate(flags) | OFlag::O_NONBLOCK;
-        fcntl(fd, FcntlArg::F_SETFL(flags))?;
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL, 0) };
+        if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
         Ok(())
     }
 }
@@ -1,174 +1,966 @@
 #![allow(non_upper_case_globals)]
 #![allow(dead_code)]
 
-use crate::constants::{ZMQ_BLOCKY, ZMQ_EPROTONOSUPPORT, ZMQ_ETERM, ZMQ_IPV6};
-use crate::context::Context;
-use crate::zmq_draft::ZMQ_ZERO_COPY_RECV;
+use crate::command::Command;
+use crate::constants::{
+    ZMQ_BLOCKY, ZMQ_DEALER, ZMQ_DONTWAIT, ZMQ_EAGAIN, ZMQ_ENOCOMPATPROTO, ZMQ_EPROTONOSUPPORT,
+    ZMQ_ETERM, ZMQ_EVENTS, ZMQ_IPV6, ZMQ_LAST_ENDPOINT, ZMQ_PAIR, ZMQ_POLLIN, ZMQ_POLLOUT, ZMQ_PUB,
+    ZMQ_PULL, ZMQ_PUSH, ZMQ_RCVMORE, ZMQ_REP, ZMQ_REQ, ZMQ_ROUTER, ZMQ_SNDMORE, ZMQ_STREAM,
+    ZMQ_SUB, ZMQ_THREAD_SAFE, ZMQ_XPUB, ZMQ_XSUB,
+};
+use crate::context::{self, Context};
+use crate::i_mailbox::IMailbox;
+use crate::io_object::ObjectMailbox;
+use crate::mailbox::Mailbox;
+use crate::mailbox_safe::MailboxSafe;
+use crate::message::{Message, MsgFlags};
+use crate::options::{self, get_effective_conflate_option, put_int, put_string, Options};
+use crate::pipe::{create_pipe_pair, Pipe};
+use crate::session_base::Session;
+use crate::tcp_address::{self, TcpAddress};
+use crate::tcp_listener::TcpListener;
+use crate::udp_address::UdpAddress;
+use crate::zmq_draft::{
+    ZMQ_CHANNEL, ZMQ_CLIENT, ZMQ_DGRAM, ZMQ_DISH, ZMQ_GATHER, ZMQ_PEER, ZMQ_RADIO, ZMQ_SCATTER,
+    ZMQ_SERVER, ZMQ_ZERO_COPY_RECV,
+};
+use crate::{
+    channel::Channel, client::Client, dealer::Dealer, dgram::Dgram, dish::Dish, gather::Gather,
+    pair::Pair, peer::Peer, pull::Pull, push::Push, radio::Radio, rep::Rep, req::Req,
+    router::Router, scatter::Scatter, server::Server, stream::Stream, sub::Sub, xpub::XPub,
+    xsub::XSub, zmq_pub::Pub,
+};
 use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
 // Type aliases
 type ZmqResult<T> = Result<T, i32>; // Using i32 for errno compatibility
 
-// Constants
-const ZMQ_PAIR: i32 = 0;
-const ZMQ_PUB: i32 = 1;
-const ZMQ_SUB: i32 = 2;
-const ZMQ_REQ: i32 = 3;
-const ZMQ_REP: i32 = 4;
-const ZMQ_DEALER: i32 = 5;
-const ZMQ_ROUTER: i32 = 6;
-// ...etc
+const SOCKET_TAG_VALUE_GOOD: u32 = 0xbaddecaf;
+const SOCKET_TAG_VALUE_BAD: u32 = 0xdeadbeef;
 
 // Core traits
-pub trait SocketBehavior {
-    fn check_tag(&self) -> bool;
-    fn is_thread_safe(&self) -> bool;
-    fn get_mailbox(&self) -> Option<&Mailbox>;
-    fn bind(&mut self, endpoint: &str) -> ZmqResult<()>;
-    fn connect(&mut self, endpoint: &str) -> ZmqResult<()>;
-    fn send(&mut self, msg: Message, flags: i32) -> ZmqResult<()>;
-    fn recv(&mut self, flags: i32) -> ZmqResult<Message>;
-    fn close(&mut self) -> ZmqResult<()>;
+//
+// Socket types embed a `SocketBase` and implement the `x*` hooks, which
+// correspond to the virtual methods of libzmq's socket_base_t. Everything
+// else is shared and provided by the default methods.
+pub trait SocketBehavior: Send {
+    fn base(&self) -> &SocketBase;
+    fn base_mut(&mut self) -> &mut SocketBase;
 
-    fn attach_pipe(&mut self, pipe: &mut Pipe, _subscribe_to_all: bool, _locally_initiated: bool);
+    fn xsend(&mut self, _msg: &mut Message) -> ZmqResult<()> {
+        Err(libc::ENOTSUP)
+    }
+
+    fn xrecv(&mut self, _msg: &mut Message) -> ZmqResult<()> {
+        Err(libc::ENOTSUP)
+    }
+
+    fn xhas_in(&mut self) -> bool {
+        false
+    }
+
+    fn xhas_out(&mut self) -> bool {
+        false
+    }
+
+    /// Connects the socket type's logic to a freshly attached pipe.
+    fn xattach_pipe(&mut self, pipe: Pipe, subscribe_to_all: bool, locally_initiated: bool);
+
+    /// Called once the pipe is gone; the socket type must forget it.
+    fn xpipe_terminated(&mut self, pipe: &Pipe);
+
+    /// Messages became available on a pipe that was empty before.
+    fn xread_activated(&mut self, _pipe: &Pipe) {}
+
+    /// A pipe that hit the high water mark can be written to again.
+    fn xwrite_activated(&mut self, _pipe: &Pipe) {}
+
+    /// The pipe was reconnected under the hood.
+    fn xhiccuped(&mut self, _pipe: &Pipe) {}
+
+    /// Handles options specific to the socket type. EINVAL passes the
+    /// option on to the generic parser.
+    fn xsetsockopt(&mut self, _option: i32, _optval: &[u8]) -> ZmqResult<()> {
+        Err(libc::EINVAL)
+    }
+
+    /// Reads options specific to the socket type. EINVAL passes the
+    /// option on to the generic options.
+    fn xgetsockopt(&mut self, _option: i32, _optval: &mut [u8]) -> ZmqResult<usize> {
+        Err(libc::EINVAL)
+    }
+
+    /// Joins and leaves groups, for the socket types that have them.
+    fn xjoin(&mut self, _group: &str) -> ZmqResult<()> {
+        Err(libc::ENOTSUP)
+    }
+
+    fn xleave(&mut self, _group: &str) -> ZmqResult<()> {
+        Err(libc::ENOTSUP)
+    }
+
+    fn check_tag(&self) -> bool {
+        self.base().check_tag()
+    }
+
+    fn is_thread_safe(&self) -> bool {
+        self.base().is_thread_safe()
+    }
+
+    fn get_mailbox(&self) -> Arc<dyn IMailbox> {
+        self.base().get_mailbox()
+    }
+
+    fn setsockopt(&mut self, option: i32, optval: &[u8]) -> ZmqResult<()> {
+        if !options::applies_to(option, self.base().options.socket_type) {
+            return Err(libc::EINVAL);
+        }
+
+        // First, check whether specific socket type overloads the option.
+        match self.xsetsockopt(option, optval) {
+            Err(e) if e == libc::EINVAL => {}
+            rc => return rc,
+        }
+
+        // If the socket type doesn't support the option, pass it to
+        // the generic option parser.
+        self.base_mut().options.setsockopt(option, optval)
+    }
+
+    /// Copies the option value into `optval`, returning its length.
+    fn getsockopt(&mut self, option: i32, optval: &mut [u8]) -> ZmqResult<usize> {
+        if !options::applies_to(option, self.base().options.socket_type) {
+            return Err(libc::EINVAL);
+        }
+
+        match option {
+            ZMQ_RCVMORE => return put_int(optval, self.base().rcvmore as i32),
+            ZMQ_THREAD_SAFE => return put_int(optval, self.is_thread_safe() as i32),
+            ZMQ_LAST_ENDPOINT => return put_string(optval, &self.base().last_endpoint),
+            ZMQ_EVENTS => {
+                self.process_commands(0)?;
+                let mut events = 0;
+                if self.xhas_out() {
+                    events |= ZMQ_POLLOUT as i32;
+                }
+                if self.xhas_in() {
+                    events |= ZMQ_POLLIN as i32;
+                }
+                return put_int(optval, events);
+            }
+            _ => {}
+        }
+
+        match self.xgetsockopt(option, optval) {
+            Err(e) if e == libc::EINVAL => {}
+            rc => return rc,
+        }
+
+        self.base().options.getsockopt(option, optval)
+    }
+
+    fn bind(&mut self, endpoint: &str) -> ZmqResult<()> {
+        // Process pending commands, if any.
+        self.process_commands(0)?;
+        if let Some(pipe) = self.base_mut().bind(endpoint)? {
+            // A udp endpoint has its session, and the pipe to it, right away.
+            self.attach_pipe(pipe, true, true);
+        }
+
+        // Inproc peers that connected before the bind were handed over
+        // through the mailbox; attach them straight away.
+        self.process_commands(0)
+    }
+
+    fn unbind(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.process_commands(0)?;
+        self.base_mut().term_endpoint(endpoint)
+    }
+
+    fn connect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.process_commands(0)?;
+        if let Some(pipe) = self.base_mut().connect(endpoint)? {
+            // Attach local end of the pipe to this socket object. There's
+            // no one to subscribe with at the other end of a udp pipe.
+            let subscribe_to_all = endpoint.starts_with("udp://");
+            self.attach_pipe(pipe, subscribe_to_all, true);
+        }
+        Ok(())
+    }
+
+    fn disconnect(&mut self, endpoint: &str) -> ZmqResult<()> {
+        self.process_commands(0)?;
+        self.base_mut().term_endpoint(endpoint)
+    }
+
+    /// Registers the pipe with the socket and hands it to the socket type.
+    fn attach_pipe(&mut self, pipe: Pipe, subscribe_to_all: bool, locally_initiated: bool) {
+        // First, register the pipe so that we can terminate it later on.
+        pipe.set_event_sink(self.get_mailbox());
+        self.base_mut().pipes.push(pipe.clone());
 
-    fn has_in(&self) -> bool;
+        // Let the derived socket type know about new pipe.
+        self.xattach_pipe(pipe.clone(), subscribe_to_all, locally_initiated);
 
-    fn read_activated(&mut self, pipe: &mut Pipe);
+        // If the socket is already being closed, ask any new pipes to
+        // terminate straight away.
+        if self.base().is_terminating() {
+            pipe.terminate(false);
+        }
+    }
+
+    /// Processes commands waiting in the mailbox. A non-zero timeout blocks
+    /// until a command arrives or the timeout (-1 = forever) expires.
+    fn process_commands(&mut self, timeout: i32) -> ZmqResult<()> {
+        let mailbox = self.get_mailbox();
+        let mut next = mailbox.recv(timeout);
+
+        loop {
+            match next {
+                Ok(cmd) => self.process_command(cmd),
+                Err(e) if e == ZMQ_EAGAIN => break,
+                Err(e) => return Err(e),
+            }
+            next = mailbox.recv(0);
+        }
+
+        if self.base().ctx_terminated {
+            return Err(ZMQ_ETERM);
+        }
+
+        Ok(())
+    }
+
+    fn process_command(&mut self, cmd: Command) {
+        match cmd {
+            // Here, someone have called zmq_ctx_term while the socket was
+            // still alive. We have to set the flag so that all blocking calls
+            // return ETERM from now on.
+            Command::Stop => self.base_mut().ctx_terminated = true,
+            Command::Done => {}
+            Command::ActivateRead(pipe) => self.xread_activated(&pipe),
+            Command::ActivateWrite(pipe) => self.xwrite_activated(&pipe),
+            Command::Bind(pipe) => self.attach_pipe(pipe, false, false),
+            Command::PipeTerm(pipe) => {
+                if pipe.process_pipe_term() {
+                    // Notify the specific socket type about the pipe
+                    // termination.
+                    self.xpipe_terminated(&pipe);
+                    self.base_mut().remove_pipe(&pipe);
+                }
+            }
+            Command::Hiccup(pipe) => self.xhiccuped(&pipe),
+            // Only objects living in I/O threads get plugged and
+            // terminated this way.
+            Command::Plug(_) | Command::Term(_) => {}
+        }
+    }
+
+    fn send(&mut self, msg: &mut Message, flags: i32) -> ZmqResult<()> {
+        // Process pending commands, if any.
+        self.process_commands(0)?;
+
+        // Conflating pipes hold a single message at a time, which can't
+        // be one part of a multipart message.
+        if flags & ZMQ_SNDMORE != 0 && get_effective_conflate_option(&self.base().options) {
+            return Err(libc::EINVAL);
+        }
+
+        // At this point we impose the flags on the message.
+        if flags & ZMQ_SNDMORE != 0 {
+            msg.set_flags(MsgFlags::More);
+        } else {
+            msg.reset_flags(MsgFlags::More);
+        }
+
+        // Try to send the message using method in each socket class
+        match self.xsend(msg) {
+            Err(e) if e == ZMQ_EAGAIN => {}
+            rc => return rc,
+        }
+
+        // In case of non-blocking send we'll simply propagate
+        // the error - including EAGAIN - up the stack.
+        let timeout = self.base().options.send_timeo;
+        if flags & ZMQ_DONTWAIT != 0 || timeout == 0 {
+            return Err(ZMQ_EAGAIN);
+        }
+
+        // Compute the time when the timeout should occur.
+        // If the timeout is infinite, don't care.
+        let deadline = deadline_after(timeout);
+
+        // Oops, we couldn't send the message. Wait for the next
+        // command, process it and try to send the message again.
+        // If timeout is reached in the meantime, return EAGAIN.
+        loop {
+            self.process_commands(remaining(deadline))?;
+            match self.xsend(msg) {
+                Err(e) if e == ZMQ_EAGAIN => {}
+                rc => return rc,
+            }
+            if remaining(deadline) == 0 {
+                return Err(ZMQ_EAGAIN);
+            }
+        }
+    }
+
+    fn recv(&mut self, msg: &mut Message, flags: i32) -> ZmqResult<()> {
+        // Once every inbound_poll_rate messages check for signals and
+        // process incoming commands. Here every call does it, which is
+        // cheap with a condition-variable mailbox.
+        self.process_commands(0)?;
+
+        // Get the message.
+        match self.xrecv(msg) {
+            Err(e) if e == ZMQ_EAGAIN => {}
+            rc => return self.base_mut().extract_flags(msg, rc),
+        }
+
+        // For non-blocking recv, commands are processed in case there's an
+        // activate_reader command already waiting in a command pipe.
+        // If it's not, return EAGAIN.
+        let timeout = self.base().options.recv_timeo;
+        if flags & ZMQ_DONTWAIT != 0 || timeout == 0 {
+            self.process_commands(0)?;
+            let rc = self.xrecv(msg);
+            return self.base_mut().extract_flags(msg, rc);
+        }
+
+        // Compute the time when the timeout should occur.
+        // If the timeout is infinite, don't care.
+        let deadline = deadline_after(timeout);
+
+        // In blocking scenario, commands are processed over and over again
+        // until we are able to fetch a message.
+        loop {
+            self.process_commands(remaining(deadline))?;
+            match self.xrecv(msg) {
+                Err(e) if e == ZMQ_EAGAIN => {}
+                rc => return self.base_mut().extract_flags(msg, rc),
+            }
+            if remaining(deadline) == 0 {
+                return Err(ZMQ_EAGAIN);
+            }
+        }
+    }
 
-    fn pipe_terminated(&mut self, pipe: &mut Pipe);
+    fn join(&mut self, group: &str) -> ZmqResult<()> {
+        self.xjoin(group)
+    }
+
+    fn leave(&mut self, group: &str) -> ZmqResult<()> {
+        self.xleave(group)
+    }
+
+    /// Connects to the endpoint and returns the routing id messages to the
+    /// new peer are addressed with. Only PEER sockets support it.
+    fn connect_peer(&mut self, _endpoint: &str) -> ZmqResult<u32> {
+        Err(libc::ENOTSUP)
+    }
+
+    fn close(&mut self) -> ZmqResult<()> {
+        self.base_mut().close()
+    }
 }
 
-// Core structures
-#[derive(Default)]
-pub struct SocketOptions {
-    pub socket_id: i32,
-    pub type_: i32,
-    pub immediate: i32,
-    pub rcvmore: bool,
-    pub sndhwm: i32,
-    pub rcvhwm: i32,
-    // ... etc
+// Returns the point in time a blocking call gives up, or None when the
+// timeout is infinite.
+fn deadline_after(timeout: i32) -> Option<Instant> {
+    if timeout < 0 {
+        None
+    } else {
+        Some(Instant::now() + Duration::from_millis(timeout as u64))
+    }
 }
 
+// Milliseconds left until the deadline, in the -1 = infinite convention of
+// the mailbox.
+fn remaining(deadline: Option<Instant>) -> i32 {
+    match deadline {
+        None => -1,
+        Some(deadline) => deadline
+            .saturating_duration_since(Instant::now())
+            .as_millis()
+            .min(i32::MAX as u128) as i32,
+    }
+}
+
+/// Sends on a socket that may be shared between threads.
+///
+/// Thread-safe sockets don't block with the lock held: the message is
+/// tried without blocking and, if it can't go out yet, the thread waits
+/// for the next command with the socket unlocked, so that other threads
+/// keep using it meanwhile.
+pub(crate) fn send_locked(
+    socket: &Mutex<Box<dyn SocketBehavior>>,
+    msg: &mut Message,
+    flags: i32,
+) -> ZmqResult<()> {
+    let mut guard = lock(socket);
+    let mailbox = match guard.base().get_mailbox_safe() {
+        Some(mailbox) if flags & ZMQ_DONTWAIT == 0 => mailbox,
+        _ => return guard.send(msg, flags),
+    };
+
+    let deadline = deadline_after(guard.base().options.send_timeo);
+    loop {
+        match guard.send(msg, flags | ZMQ_DONTWAIT) {
+            Err(e) if e == ZMQ_EAGAIN => {}
+            rc => return rc,
+        }
+        guard = wait_unlocked(socket, guard, &mailbox, deadline)?;
+    }
+}
+
+/// Receives from a socket that may be shared between threads, the
+/// counterpart of `send_locked`.
+pub(crate) fn recv_locked(
+    socket: &Mutex<Box<dyn SocketBehavior>>,
+    msg: &mut Message,
+    flags: i32,
+) -> ZmqResult<()> {
+    let mut guard = lock(socket);
+    let mailbox = match guard.base().get_mailbox_safe() {
+        Some(mailbox) if flags & ZMQ_DONTWAIT == 0 => mailbox,
+        _ => return guard.recv(msg, flags),
+    };
+
+    let deadline = deadline_after(guard.base().options.recv_timeo);
+    loop {
+        match guard.recv(msg, flags | ZMQ_DONTWAIT) {
+            Err(e) if e == ZMQ_EAGAIN => {}
+            rc => return rc,
+        }
+        guard = wait_unlocked(socket, guard, &mailbox, deadline)?;
+    }
+}
+
+/// Locks a socket shared between threads. A thread panicking with the
+/// lock held leaves the socket as usable as any other error would.
+pub(crate) fn lock(
+    socket: &Mutex<Box<dyn SocketBehavior>>,
+) -> MutexGuard<'_, Box<dyn SocketBehavior>> {
+    socket.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Waits for the next command with the socket unlocked, then locks it
+// again. Commands are left for the caller to process.
+fn wait_unlocked<'a>(
+    socket: &'a Mutex<Box<dyn SocketBehavior>>,
+    guard: MutexGuard<'a, Box<dyn SocketBehavior>>,
+    mailbox: &MailboxSafe,
+    deadline: Option<Instant>,
+) -> ZmqResult<MutexGuard<'a, Box<dyn SocketBehavior>>> {
+    drop(guard);
+    let timeout = remaining(deadline);
+    if timeout == 0 {
+        return Err(ZMQ_EAGAIN);
+    }
+    mailbox.wait(timeout)?;
+    Ok(lock(socket))
+}
+
+// Core structures
 pub struct SocketBase {
-    pub options: SocketOptions,
-    pub mailbox: Option<Mailbox>,
+    pub options: Options,
+    ctx: Arc<Context>,
+    tid: u32,
+    pub mailbox: Arc<dyn IMailbox>,
+    // The same mailbox for thread-safe sockets, which threads wait on with
+    // the socket unlocked.
+    mailbox_safe: Option<Arc<MailboxSafe>>,
     pub pipes: Vec<Pipe>,
-    pub endpoints: HashMap<String, Endpoint>,
+    // Listeners and sessions launched into I/O threads, by endpoint.
+    pub endpoints: HashMap<String, Vec<Endpoint>>,
+    // Local ends of the inproc connections, by endpoint, for disconnect.
+    inprocs: HashMap<String, Vec<Pipe>>,
     pub monitor_socket: Option<Box<dyn SocketBehavior>>,
     pub monitor_events: u64,
+    // Flag of the last message received, reported as ZMQ_RCVMORE.
+    pub rcvmore: bool,
+    // Endpoint the socket was last bound or connected to.
+    pub last_endpoint: String,
     thread_safe: bool,
     tag: u32,
-    ctx_terminated: bool,
+    pub(crate) ctx_terminated: bool,
     destroyed: bool,
     disconnected: bool,
 }
 
 impl SocketBase {
-    pub fn new(ctx: &Context, tid: u32, sid: i32, thread_safe: bool) -> Self {
+    pub fn new(ctx: &Arc<Context>, tid: u32, sid: i32, thread_safe: bool) -> Self {
+        let mailbox_safe = if thread_safe {
+            Some(Arc::new(MailboxSafe::new()))
+        } else {
+            None
+        };
+        let mailbox: Arc<dyn IMailbox> = match &mailbox_safe {
+            Some(mailbox) => Arc::clone(mailbox) as Arc<dyn IMailbox>,
+            None => Arc::new(Mailbox::new()),
+        };
+
         let mut socket = SocketBase {
-            options: SocketOptions::default(),
-            mailbox: None,
+            options: Options::new(),
+            ctx: Arc::clone(ctx),
+            tid,
+            mailbox,
+            mailbox_safe,
             pipes: Vec::new(),
             endpoints: HashMap::new(),
+            inprocs: HashMap::new(),
             monitor_socket: None,
             monitor_events: 0,
+            rcvmore: false,
+            last_endpoint: String::new(),
             thread_safe: thread_safe,
-            tag: 0xbaddecaf,
+            tag: SOCKET_TAG_VALUE_GOOD,
             ctx_terminated: false,
             destroyed: false,
             disconnected: false,
         };
 
         socket.options.socket_id = sid;
-        // socket.options.type_ = ctx.get_option(ZMQ_SOCKET_TYPE);
-        // options.socket_id = sid_;
         // options.ipv6 = (parent_->get (ZMQ_IPV6) != 0);
-        socket.options.ipv6 = socket.parent_.get(ZMQ_IPV6) != 0;
+        socket.options.ipv6 = ctx.get(ZMQ_IPV6).unwrap_or(0) != 0;
         // options.linger.store (parent_->get (ZMQ_BLOCKY) ? -1 : 0);
-        socket.options.linger = if socket.parent_.get(ZMQ_BLOCKY) {
+        let linger = if ctx.get(ZMQ_BLOCKY).unwrap_or(1) != 0 {
             -1
         } else {
             0
         };
+        socket.options.linger.store(linger, Ordering::SeqCst);
         // options.zero_copy = parent_->get (ZMQ_ZERO_COPY_RECV) != 0;
-        socket.options.zero_copy = socket.parent_.get(ZMQ_ZERO_COPY_RECV) != 0;
-
-        if thread_safe {
-            socket.mailbox = Some(Mailbox::new_safe());
-        } else {
-            socket.mailbox = Some(Mailbox::new());
-        }
+        socket.options.zero_copy = ctx.get(ZMQ_ZERO_COPY_RECV).unwrap_or(0) != 0;
 
         socket
     }
 
     pub fn create(
         socket_type: i32,
-        ctx: &Context,
+        ctx: &Arc<Context>,
         tid: u32,
         sid: i32,
-    ) -> Option<Box<dyn SocketBehavior>> {
-        match socket_type {
-            ZMQ_PAIR => Some(Box::new(PairSocket::new(ctx, tid, sid))),
-            ZMQ_PUB => Some(Box::new(PubSocket::new(ctx, tid, sid))),
-            ZMQ_SUB => Some(Box::new(SubSocket::new(ctx, tid, sid))),
-            // ... etc
-            _ => {
-                // errno = EINVAL
-                None
-            }
-        }
+    ) -> ZmqResult<Box<dyn SocketBehavior>> {
+        let socket: Box<dyn SocketBehavior> = match socket_type {
+            ZMQ_PAIR => Box::new(Pair::new(ctx, tid, sid)),
+            ZMQ_PUB => Box::new(Pub::new(ctx, tid, sid)),
+            ZMQ_SUB => Box::new(Sub::new(ctx, tid, sid)),
+            ZMQ_REQ => Box::new(Req::new(ctx, tid, sid)),
+            ZMQ_REP => Box::new(Rep::new(ctx, tid, sid)),
+            ZMQ_DEALER => Box::new(Dealer::new(ctx, tid, sid)),
+            ZMQ_ROUTER => Box::new(Router::new(ctx, tid, sid)),
+            ZMQ_PULL => Box::new(Pull::new(ctx, tid, sid)),
+            ZMQ_PUSH => Box::new(Push::new(ctx, tid, sid)),
+            ZMQ_XPUB => Box::new(XPub::new(ctx, tid, sid)),
+            ZMQ_XSUB => Box::new(XSub::new(ctx, tid, sid)),
+            ZMQ_STREAM => Box::new(Stream::new(ctx, tid, sid)),
+            ZMQ_SERVER => Box::new(Server::new(ctx, tid, sid)),
+            ZMQ_CLIENT => Box::new(Client::new(ctx, tid, sid)),
+            ZMQ_RADIO => Box::new(Radio::new(ctx, tid, sid)),
+            ZMQ_DISH => Box::new(Dish::new(ctx, tid, sid)),
+            ZMQ_GATHER => Box::new(Gather::new(ctx, tid, sid)),
+            ZMQ_SCATTER => Box::new(Scatter::new(ctx, tid, sid)),
+            ZMQ_DGRAM => Box::new(Dgram::new(ctx, tid, sid)),
+            ZMQ_PEER => Box::new(Peer::new(ctx, tid, sid)),
+            ZMQ_CHANNEL => Box::new(Channel::new(ctx, tid, sid)),
+            _ => return Err(libc::EINVAL),
+        };
+        Ok(socket)
+    }
+
+    pub fn get_ctx(&self) -> &Arc<Context> {
+        &self.ctx
+    }
+
+    pub fn get_tid(&self) -> u32 {
+        self.tid
     }
 
     // Socket behavior implementation
-    fn check_tag(&self) -> bool {
-        self.tag == 0xbaddecaf
+    pub fn check_tag(&self) -> bool {
+        self.tag == SOCKET_TAG_VALUE_GOOD
     }
 
     pub fn is_thread_safe(&self) -> bool {
         self.thread_safe
     }
 
-    fn get_mailbox(&self) -> Option<&Mailbox> {
-        self.mailbox.as_ref()
+    pub fn get_mailbox(&self) -> Arc<dyn IMailbox> {
+        Arc::clone(&self.mailbox)
     }
 
-    // Main socket operations
-    fn bind(&mut self, endpoint: &str) -> ZmqResult<()> {
-        if self.ctx_terminated {
-            return Err(ZMQ_ETERM);
+    pub(crate) fn get_mailbox_safe(&self) -> Option<Arc<MailboxSafe>> {
+        self.mailbox_safe.clone()
+    }
+
+    /// Returns true once the socket was closed or its context terminated.
+    pub fn is_terminating(&self) -> bool {
+        self.destroyed || self.ctx_terminated
+    }
+
+    /// Marks the socket dead and gives its slot back to the context.
+    pub fn close(&mut self) -> ZmqResult<()> {
+        // Ask all attached pipes to terminate. Peers still get the messages
+        // already sent to them.
+        self.destroyed = true;
+        for pipe in self.pipes.drain(..) {
+            pipe.terminate(true);
+        }
+        self.inprocs.clear();
+
+        // Take down the listeners and sessions. Sessions keep sending the
+        // pending messages for up to ZMQ_LINGER.
+        let linger = self.options.linger.load(Ordering::SeqCst);
+        for (_, endpoints) in self.endpoints.drain() {
+            for endpoint in endpoints {
+                endpoint.object.send(Command::Term(linger));
+            }
+        }
+
+        // Inproc peers may have handed us pipes we never got to attach.
+        while let Ok(cmd) = self.mailbox.recv(0) {
+            if let Command::Bind(pipe) = cmd {
+                pipe.terminate(false);
+            }
+        }
+
+        // Peers can't connect to our inproc endpoints anymore.
+        self.ctx.unregister_endpoints(self.tid);
+
+        // Free the slot used by this socket.
+        self.ctx.destroy_socket(self.tid);
+        self.tag = SOCKET_TAG_VALUE_BAD;
+        Ok(())
+    }
+
+    // Mirrors the MORE flag of a received message into ZMQ_RCVMORE.
+    fn extract_flags(&mut self, msg: &Message, rc: ZmqResult<()>) -> ZmqResult<()> {
+        if rc.is_ok() {
+            self.rcvmore = msg.has_more();
+        }
+        rc
+    }
+
+    /// Stops listening on, or disconnects from, the given endpoint.
+    pub fn term_endpoint(&mut self, endpoint: &str) -> ZmqResult<()> {
+        // Check whether endpoint address passed to the function is valid.
+        let (protocol, address) = self.parse_uri(endpoint)?;
+        self.check_protocol(&protocol)?;
+
+        // Unbind from the inproc endpoint or, failing that, disconnect the
+        // pipes connected to it.
+        if protocol == "inproc" {
+            if self.ctx.unregister_endpoint(endpoint, self.tid).is_ok() {
+                return Ok(());
+            }
+            return match self.inprocs.remove(endpoint) {
+                Some(pipes) => {
+                    for pipe in pipes {
+                        pipe.terminate(true);
+                    }
+                    Ok(())
+                }
+                None => Err(libc::ENOENT),
+            };
         }
 
+        // The resolved last_endpoint is used as a key in the endpoints map.
+        // The address passed by the user might not match in the TCP case
+        // due to IPv4-in-IPv6 mapping (EG: tcp://[::ffff:127.0.0.1]:9999),
+        // so try to resolve before giving up.
+        let endpoints = match self.endpoints.remove(endpoint) {
+            Some(endpoints) => endpoints,
+            None => {
+                let resolved = self.resolve_tcp_addr(endpoint, &address);
+                self.endpoints.remove(&resolved).ok_or(libc::ENOENT)?
+            }
+        };
+
+        // Ask the listeners and sessions to terminate. If there is an
+        // associated pipe, terminate it as well.
+        let linger = self.options.linger.load(Ordering::SeqCst);
+        for endpoint in endpoints {
+            if let Some(pipe) = endpoint.pipe {
+                pipe.terminate(false);
+            }
+            endpoint.object.send(Command::Term(linger));
+        }
+        Ok(())
+    }
+
+    // Canonical form of a tcp endpoint, as stored in the endpoints map.
+    fn resolve_tcp_addr(&self, endpoint: &str, address: &str) -> String {
+        match TcpAddress::resolve(address, false, self.options.ipv6) {
+            Ok(addr) => addr.to_string(),
+            Err(_) => endpoint.to_string(),
+        }
+    }
+
+    // Main socket operations
+    /// Binds to the endpoint. For udp the local end of the pipe to the
+    /// new session is returned, to be attached by the caller.
+    pub fn bind(&mut self, endpoint: &str) -> ZmqResult<Option<Pipe>> {
         // Parse endpoint URI
         let (protocol, address) = self.parse_uri(endpoint)?;
 
         // Check protocol
         self.check_protocol(&protocol)?;
 
-        match protocol.as_str() {
-            "inproc" => self.bind_inproc(&address),
-            "tcp" => self.bind_tcp(&address),
-            // ... etc
-            _ => Err(ZMQ_EPROTONOSUPPORT),
+        let mut pipe = None;
+        self.last_endpoint = match protocol.as_str() {
+            "inproc" => {
+                self.bind_inproc(endpoint)?;
+                endpoint.to_string()
+            }
+            "tcp" => self.bind_tcp(&address)?,
+            "udp" => {
+                // Only the receiving side of udp binds.
+                if self.options.socket_type != ZMQ_DISH {
+                    return Err(ZMQ_ENOCOMPATPROTO);
+                }
+                let (local, last_endpoint) = self.open_udp(endpoint, &address, true)?;
+                pipe = Some(local);
+                last_endpoint
+            }
+            _ => return Err(ZMQ_EPROTONOSUPPORT),
+        };
+        Ok(pipe)
+    }
+
+    /// Connects to the endpoint. For inproc the local end of the new pipe
+    /// is returned, to be attached by the caller.
+    pub fn connect(&mut self, endpoint: &str) -> ZmqResult<Option<Pipe>> {
+        let (protocol, address) = self.parse_uri(endpoint)?;
+        self.check_protocol(&protocol)?;
+
+        let pipe = match protocol.as_str() {
+            "inproc" => Some(self.connect_inproc(endpoint)?),
+            "tcp" => self.connect_tcp(endpoint, &address)?,
+            "udp" => {
+                // Only the sending side of udp connects.
+                if self.options.socket_type != ZMQ_RADIO {
+                    return Err(ZMQ_ENOCOMPATPROTO);
+                }
+                Some(self.open_udp(endpoint, &address, false)?.0)
+            }
+            _ => return Err(ZMQ_EPROTONOSUPPORT),
+        };
+
+        self.last_endpoint = endpoint.to_string();
+        Ok(pipe)
+    }
+
+    // Snapshot of this socket as seen by inproc peers.
+    fn inproc_endpoint(&self) -> context::Endpoint {
+        context::Endpoint {
+            tid: self.tid,
+            mailbox: Arc::clone(&self.mailbox),
+            options: self.options.clone(),
         }
     }
 
-    fn connect(&mut self, endpoint: &str) -> ZmqResult<()> {
-        if self.ctx_terminated {
-            return Err(ZMQ_ETERM);
+    fn bind_inproc(&mut self, endpoint_uri: &str) -> ZmqResult<()> {
+        self.ctx
+            .register_endpoint(endpoint_uri, self.inproc_endpoint())?;
+        self.ctx.connect_pending(endpoint_uri);
+        self.options.connected = true;
+        Ok(())
+    }
+
+    fn connect_inproc(&mut self, endpoint_uri: &str) -> ZmqResult<Pipe> {
+        // Find the peer endpoint.
+        let peer = self.ctx.find_endpoint(endpoint_uri).ok();
+
+        // The total HWM for an inproc connection should be the sum of
+        // the binder's HWM and the connector's HWM.
+        let sndhwm = self.options.send_high_water_mark;
+        let rcvhwm = self.options.recv_high_water_mark;
+        let (sndhwm, rcvhwm) = match &peer {
+            None => (sndhwm, rcvhwm),
+            Some(peer) => (
+                sum_hwms(sndhwm, peer.options.recv_high_water_mark),
+                sum_hwms(rcvhwm, peer.options.send_high_water_mark),
+            ),
+        };
+
+        // Create a bi-directional pipe to connect the peers.
+        let conflate = get_effective_conflate_option(&self.options);
+        let hwms = if conflate { [-1, -1] } else { [sndhwm, rcvhwm] };
+        let (local, remote) = create_pipe_pair([conflate, conflate], hwms);
+
+        match peer {
+            None => {
+                // The peer doesn't exist yet so we don't know whether
+                // to send the routing id message or not. To resolve this,
+                // we always send our routing id and drop it later if
+                // the peer doesn't expect it.
+                send_routing_id(&local, &self.options);
+                self.ctx.pend_connection(
+                    endpoint_uri,
+                    self.inproc_endpoint(),
+                    local.clone(),
+                    remote,
+                );
+            }
+            Some(peer) => {
+                // The peer keeps only the latest message even if we don't
+                // conflate ourselves.
+                if get_effective_conflate_option(&peer.options) {
+                    remote.set_conflate();
+                }
+
+                // If required, send the routing id of the local socket to
+                // the peer.
+                if peer.options.recv_routing_id {
+                    send_routing_id(&local, &self.options);
+                }
+
+                // If required, send the routing id of the peer to the local
+                // socket.
+                if self.options.recv_routing_id {
+                    send_routing_id(&remote, &peer.options);
+                }
+
+                // Attach remote end of the pipe to the peer socket.
+                peer.mailbox.send(Command::Bind(remote));
+            }
         }
 
-        let (protocol, address) = self.parse_uri(endpoint)?;
-        self.check_protocol(&protocol)?;
+        // Remember inproc connections for disconnect.
+        self.inprocs
+            .entry(endpoint_uri.to_string())
+            .or_default()
+            .push(local.clone());
+
+        self.options.connected = true;
+        Ok(local)
+    }
 
-        match protocol.as_str() {
-            "inproc" => self.connect_inproc(&address),
-            "tcp" => self.connect_tcp(&address),
-            // ... etc
-            _ => Err(libc::EPROTONOSUPPORT),
+    // Starts listening on the address. Returns the endpoint actually bound
+    // to, with the wildcards resolved.
+    fn bind_tcp(&mut self, address: &str) -> ZmqResult<String> {
+        // Choose I/O thread to run the listener in.
+        let io_thread = self.ctx.choose_io_thread(self.options.affinity)?;
+
+        let listener =
+            TcpListener::bind(&self.ctx, address, &self.options, Arc::clone(&self.mailbox))?;
+        let endpoint = listener.get_local_address().to_string();
+
+        let object = io_thread.launch(Box::new(listener));
+        self.endpoints
+            .entry(endpoint.clone())
+            .or_default()
+            .push(Endpoint { object, pipe: None });
+        self.options.connected = true;
+        Ok(endpoint)
+    }
+
+    // Launches a session connecting to the address. Unless ZMQ_IMMEDIATE
+    // is set, the pipe to the session is created right away and its local
+    // end returned, so that messages can be queued before the connection
+    // is up.
+    fn connect_tcp(&mut self, endpoint_uri: &str, address: &str) -> ZmqResult<Option<Pipe>> {
+        if !tcp_address::check_connect_syntax(address) {
+            return Err(libc::EINVAL);
         }
+
+        // Choose the I/O thread to run the session in.
+        let io_thread = self.ctx.choose_io_thread(self.options.affinity)?;
+
+        let (local, remote) = if self.options.immediate != 1 {
+            let conflate = get_effective_conflate_option(&self.options);
+            let hwms = if conflate {
+                [-1, -1]
+            } else {
+                [
+                    self.options.send_high_water_mark,
+                    self.options.recv_high_water_mark,
+                ]
+            };
+            let (local, remote) = create_pipe_pair([conflate, conflate], hwms);
+            (Some(local), Some(remote))
+        } else {
+            (None, None)
+        };
+
+        // Create session.
+        let session = Session::new_active(
+            &self.ctx,
+            &self.options,
+            Arc::clone(&self.mailbox),
+            endpoint_uri,
+            address,
+            remote,
+        );
+        let object = io_thread.launch(Box::new(session));
+        self.endpoints
+            .entry(endpoint_uri.to_string())
+            .or_default()
+            .push(Endpoint {
+                object,
+                pipe: local.clone(),
+            });
+
+        self.options.connected = true;
+        Ok(local)
+    }
+
+    // Launches the session of a udp endpoint, bound for a DISH socket and
+    // connected for a RADIO socket. Datagrams need no connection, so the
+    // pipe is always created up front; its local end is returned along with
+    // the resolved endpoint.
+    fn open_udp(
+        &mut self,
+        endpoint_uri: &str,
+        address: &str,
+        bind: bool,
+    ) -> ZmqResult<(Pipe, String)> {
+        let mut udp_addr = UdpAddress::new();
+        udp_addr.resolve(address, bind, self.options.ipv6)?;
+        let last_endpoint = udp_addr.to_string().map_err(|_| libc::EINVAL)?;
+
+        // Choose the I/O thread to run the session in.
+        let io_thread = self.ctx.choose_io_thread(self.options.affinity)?;
+
+        // Create a bi-directional pipe.
+        let hwms = [
+            self.options.send_high_water_mark,
+            self.options.recv_high_water_mark,
+        ];
+        let (local, remote) = create_pipe_pair([false, false], hwms);
+
+        let session = Session::new_udp(
+            &self.ctx,
+            &self.options,
+            Arc::clone(&self.mailbox),
+            endpoint_uri,
+            udp_addr,
+            remote,
+        );
+        let object = io_thread.launch(Box::new(session));
+        self.endpoints
+            .entry(endpoint_uri.to_string())
+            .or_default()
+            .push(Endpoint {
+                object,
+                pipe: Some(local.clone()),
+            });
+
+        self.options.connected = true;
+        Ok((local, last_endpoint))
+    }
+
+    // Forgets a pipe that finished terminating.
+    fn remove_pipe(&mut self, pipe: &Pipe) {
+        self.pipes.retain(|p| p != pipe);
+        for pipes in self.inprocs.values_mut() {
+            pipes.retain(|p| p != pipe);
+        }
+        self.inprocs.retain(|_, pipes| !pipes.is_empty());
     }
 
     // Helper functions
@@ -187,64 +979,1193 @@ impl SocketBase {
         }
     }
 
+    // Only the transports bind and connect implement get past this point.
     fn check_protocol(&self, protocol: &str) -> ZmqResult<()> {
         match protocol {
-            "inproc" | "tcp" | "ipc" | "pgm" | "epgm" | "ws" | "wss" => Ok(()),
-            _ => Err(libc::EPROTONOSUPPORT),
+            "inproc" | "tcp" => Ok(()),
+            // UDP transport is only compatible with RADIO and DISH.
+            "udp" if matches!(self.options.socket_type, ZMQ_RADIO | ZMQ_DISH) => Ok(()),
+            "udp" => Err(ZMQ_ENOCOMPATPROTO),
+            _ => Err(ZMQ_EPROTONOSUPPORT),
         }
     }
 }
 
-// Additional support structures
-// struct Context {
-//     options: HashMap<i32, i32>,
-// }
-
-struct Mailbox {
-    thread_safe: bool,
-    // ... mailbox implementation
+// The HWM of an inproc connection is the sum of both sides' HWMs, unless
+// either side has no limit at all.
+fn sum_hwms(local: i32, peer: i32) -> i32 {
+    if local != 0 && peer != 0 {
+        local + peer
+    } else {
+        0
+    }
 }
 
-struct Pipe {
-    // ... pipe implementation
+/// Sends the socket's routing id as the first message on the pipe.
+pub(crate) fn send_routing_id(pipe: &Pipe, options: &Options) {
+    let size = options.routing_id_size as usize;
+    let mut id = Message::with_data(&options.routing_id[..size]).unwrap();
+    id.set_flags(MsgFlags::RoutingId);
+    pipe.write(&mut id);
+    pipe.flush();
 }
 
-struct Endpoint {
-    // ... endpoint implementation
+/// A listener or session the socket launched into an I/O thread.
+pub struct Endpoint {
+    object: Arc<ObjectMailbox>,
+    // Local end of the pipe to a connecting session, if created up front.
+    pipe: Option<Pipe>,
 }
 
-struct Message {
-    // ... message implementation
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{
+        ZMQ_CONFLATE, ZMQ_EFSM, ZMQ_LINGER, ZMQ_RCVHWM, ZMQ_RCVTIMEO, ZMQ_REQ_CORRELATE,
+        ZMQ_REQ_RELAXED, ZMQ_ROUTER_HANDOVER, ZMQ_ROUTER_MANDATORY, ZMQ_ROUTING_ID, ZMQ_SNDHWM,
+        ZMQ_SUBSCRIBE, ZMQ_TYPE, ZMQ_UNSUBSCRIBE, ZMQ_XPUB_MANUAL, ZMQ_XPUB_NODROP,
+        ZMQ_XPUB_VERBOSE, ZMQ_XPUB_VERBOSER, ZMQ_XPUB_WELCOME_MSG,
+    };
+    use crate::zmq_draft::{
+        ZMQ_ONLY_FIRST_SUBSCRIBE, ZMQ_TOPICS_COUNT, ZMQ_XPUB_MANUAL_LAST_VALUE,
+        ZMQ_XSUB_VERBOSE_UNSUBSCRIBE,
+    };
 
-// Specific socket types
-struct PairSocket {
-    base: SocketBase,
-}
+    fn socket(ctx: &Arc<Context>, socket_type: i32) -> Box<dyn SocketBehavior> {
+        SocketBase::create(socket_type, ctx, 0, 1).unwrap()
+    }
 
-struct PubSocket {
-    base: SocketBase,
-}
+    // Wires the two sockets together the way a connect/bind pair would.
+    fn connect(from: &mut dyn SocketBehavior, to: &mut dyn SocketBehavior) {
+        connect_with_hwms(from, to, [1000, 1000]);
+    }
 
-struct SubSocket {
-    base: SocketBase,
-}
+    // Same as `connect`; `hwms` limit the messages sent by `from` and `to`.
+    fn connect_with_hwms(
+        from: &mut dyn SocketBehavior,
+        to: &mut dyn SocketBehavior,
+        hwms: [i32; 2],
+    ) {
+        let (local, remote) = create_pipe_pair([false, false], hwms);
+        if to.base().options.recv_routing_id {
+            send_routing_id(&local, &from.base().options);
+        }
+        from.attach_pipe(local, false, true);
+        to.attach_pipe(remote, false, false);
+    }
 
-// And so on for other socket types...
+    fn send(socket: &mut dyn SocketBehavior, data: &[u8], flags: i32) -> ZmqResult<()> {
+        let mut msg = Message::with_data(data).unwrap();
+        socket.send(&mut msg, flags)
+    }
 
-// Implementation of specific socket behaviors
-impl SocketBehavior for PairSocket {
-    // ... implement socket behavior for PAIR
-}
+    fn recv(socket: &mut dyn SocketBehavior) -> Vec<u8> {
+        let mut msg = Message::new();
+        socket.recv(&mut msg, ZMQ_DONTWAIT).unwrap();
+        msg.data().to_vec()
+    }
 
-impl SocketBehavior for PubSocket {
-    // ... implement socket behavior for PUB
-}
+    fn last_endpoint(socket: &mut dyn SocketBehavior) -> String {
+        let mut buf = [0u8; 256];
+        let len = socket.getsockopt(ZMQ_LAST_ENDPOINT, &mut buf).unwrap();
+        std::str::from_utf8(&buf[..len])
+            .unwrap()
+            .trim_end_matches('\0')
+            .to_string()
+    }
 
-impl SocketBehavior for SubSocket {
-    // ... implement socket behavior for SUB
-}
+    #[test]
+    fn test_create_every_socket_type() {
+        let ctx = Arc::new(Context::new());
+        for socket_type in ZMQ_PAIR..=ZMQ_CHANNEL {
+            let mut s = socket(&ctx, socket_type);
+            let mut value = [0u8; 4];
+            assert_eq!(s.getsockopt(ZMQ_TYPE, &mut value), Ok(4));
+            assert_eq!(i32::from_ne_bytes(value), socket_type);
+        }
+        assert_eq!(
+            SocketBase::create(ZMQ_CHANNEL + 1, &ctx, 0, 1).err(),
+            Some(libc::EINVAL)
+        );
+    }
+
+    #[test]
+    fn test_push_pull() {
+        let ctx = Arc::new(Context::new());
+        let mut push = socket(&ctx, ZMQ_PUSH);
+        let mut pull = socket(&ctx, ZMQ_PULL);
+        connect(push.as_mut(), pull.as_mut());
+
+        send(push.as_mut(), b"first", 0).unwrap();
+        send(push.as_mut(), b"second", 0).unwrap();
+        assert_eq!(recv(pull.as_mut()), b"first");
+        assert_eq!(recv(pull.as_mut()), b"second");
+
+        let mut msg = Message::new();
+        assert_eq!(pull.recv(&mut msg, ZMQ_DONTWAIT), Err(ZMQ_EAGAIN));
+    }
+
+    #[test]
+    fn test_req_rep() {
+        let ctx = Arc::new(Context::new());
+        let mut req = socket(&ctx, ZMQ_REQ);
+        let mut rep = socket(&ctx, ZMQ_REP);
+        connect(req.as_mut(), rep.as_mut());
+
+        send(req.as_mut(), b"ping", 0).unwrap();
+        assert_eq!(send(req.as_mut(), b"again", 0), Err(ZMQ_EFSM));
+
+        assert_eq!(recv(rep.as_mut()), b"ping");
+        assert!(!rep.base().rcvmore);
+        send(rep.as_mut(), b"pong", 0).unwrap();
+
+        assert_eq!(recv(req.as_mut()), b"pong");
+        send(req.as_mut(), b"next", 0).unwrap();
+    }
+
+    #[test]
+    fn test_req_relaxed_correlate() {
+        let ctx = Arc::new(Context::new());
+        let mut req = socket(&ctx, ZMQ_REQ);
+        let mut rep = socket(&ctx, ZMQ_REP);
+        let on = 1i32.to_ne_bytes();
+        req.setsockopt(ZMQ_REQ_RELAXED, &on).unwrap();
+        req.setsockopt(ZMQ_REQ_CORRELATE, &on).unwrap();
+        connect(req.as_mut(), rep.as_mut());
+
+        // A relaxed REQ may resend before the reply arrived.
+        send(req.as_mut(), b"first", 0).unwrap();
+        send(req.as_mut(), b"second", 0).unwrap();
+
+        assert_eq!(recv(rep.as_mut()), b"first");
+        send(rep.as_mut(), b"late", 0).unwrap();
+        assert_eq!(recv(rep.as_mut()), b"second");
+        send(rep.as_mut(), b"current", 0).unwrap();
+
+        // The reply to the abandoned request is dropped.
+        assert_eq!(recv(req.as_mut()), b"current");
+        let mut msg = Message::new();
+        assert_eq!(req.recv(&mut msg, ZMQ_DONTWAIT), Err(ZMQ_EFSM));
+    }
+
+    #[test]
+    fn test_dealer_router() {
+        let ctx = Arc::new(Context::new());
+        let mut dealer = socket(&ctx, ZMQ_DEALER);
+        let mut router = socket(&ctx, ZMQ_ROUTER);
+        connect(dealer.as_mut(), router.as_mut());
+
+        send(dealer.as_mut(), b"hello", 0).unwrap();
+        let routing_id = recv(router.as_mut());
+        assert_eq!(routing_id.len(), 5);
+        assert!(router.base().rcvmore);
+        assert_eq!(recv(router.as_mut()), b"hello");
+
+        send(router.as_mut(), &routing_id, ZMQ_SNDMORE).unwrap();
+        send(router.as_mut(), b"world", 0).unwrap();
+        assert_eq!(recv(dealer.as_mut()), b"world");
+    }
+
+    #[test]
+    fn test_router_mandatory_and_handover() {
+        let ctx = Arc::new(Context::new());
+        let mut router = socket(&ctx, ZMQ_ROUTER);
+        let on = 1i32.to_ne_bytes();
+        router.setsockopt(ZMQ_ROUTER_MANDATORY, &on).unwrap();
+        router.setsockopt(ZMQ_ROUTER_HANDOVER, &on).unwrap();
+
+        let mut first = socket(&ctx, ZMQ_DEALER);
+        first.setsockopt(ZMQ_ROUTING_ID, b"A").unwrap();
+        connect(first.as_mut(), router.as_mut());
+
+        // Unknown peers are reported rather than silently dropped.
+        assert_eq!(
+            send(router.as_mut(), b"B", ZMQ_SNDMORE),
+            Err(libc::EHOSTUNREACH)
+        );
+
+        // A second peer with the same routing id takes it over.
+        let mut second = socket(&ctx, ZMQ_DEALER);
+        second.setsockopt(ZMQ_ROUTING_ID, b"A").unwrap();
+        connect(second.as_mut(), router.as_mut());
+
+        send(router.as_mut(), b"A", ZMQ_SNDMORE).unwrap();
+        send(router.as_mut(), b"hello", 0).unwrap();
+        assert_eq!(recv(second.as_mut()), b"hello");
+        let mut msg = Message::new();
+        assert!(first.recv(&mut msg, ZMQ_DONTWAIT).is_err());
+    }
+
+    #[test]
+    fn test_pub_sub_filters() {
+        let ctx = Arc::new(Context::new());
+        let mut publisher = socket(&ctx, ZMQ_PUB);
+        let mut subscriber = socket(&ctx, ZMQ_SUB);
+        subscriber.setsockopt(ZMQ_SUBSCRIBE, b"a").unwrap();
+        connect(subscriber.as_mut(), publisher.as_mut());
+
+        send(publisher.as_mut(), b"apple", 0).unwrap();
+        send(publisher.as_mut(), b"banana", 0).unwrap();
+        send(publisher.as_mut(), b"avocado", 0).unwrap();
+
+        assert_eq!(recv(subscriber.as_mut()), b"apple");
+        assert_eq!(recv(subscriber.as_mut()), b"avocado");
+        let mut msg = Message::new();
+        assert_eq!(subscriber.recv(&mut msg, ZMQ_DONTWAIT), Err(ZMQ_EAGAIN));
+    }
+
+    #[test]
+    fn test_xpub_manual_with_welcome_msg() {
+        let ctx = Arc::new(Context::new());
+        let mut xpub = socket(&ctx, ZMQ_XPUB);
+        xpub.setsockopt(ZMQ_XPUB_MANUAL, &1i32.to_ne_bytes())
+            .unwrap();
+        xpub.setsockopt(ZMQ_XPUB_WELCOME_MSG, b"Welcome").unwrap();
+        let mut subscriber = socket(&ctx, ZMQ_SUB);
+        subscriber.setsockopt(ZMQ_SUBSCRIBE, b"W").unwrap();
+        connect(subscriber.as_mut(), xpub.as_mut());
+
+        // New subscribers are greeted first.
+        assert_eq!(recv(subscriber.as_mut()), b"Welcome");
+
+        // The subscription is passed up, but only the one set by the user
+        // for the pipe it came from takes effect.
+        assert_eq!(recv(xpub.as_mut()), b"\x01W");
+        xpub.setsockopt(ZMQ_SUBSCRIBE, b"Wel").unwrap();
+        send(xpub.as_mut(), b"Wat", 0).unwrap();
+        send(xpub.as_mut(), b"Well", 0).unwrap();
+        assert_eq!(recv(subscriber.as_mut()), b"Well");
+        let mut msg = Message::new();
+        assert_eq!(subscriber.recv(&mut msg, ZMQ_DONTWAIT), Err(ZMQ_EAGAIN));
+    }
+
+    #[test]
+    fn test_xpub_nodrop() {
+        let ctx = Arc::new(Context::new());
+        for nodrop in [1i32, 0] {
+            let mut xpub = socket(&ctx, ZMQ_XPUB);
+            xpub.setsockopt(ZMQ_XPUB_NODROP, &nodrop.to_ne_bytes())
+                .unwrap();
+            let mut subscriber = socket(&ctx, ZMQ_SUB);
+            subscriber.setsockopt(ZMQ_SUBSCRIBE, b"").unwrap();
+            connect_with_hwms(subscriber.as_mut(), xpub.as_mut(), [1000, 2]);
+            assert_eq!(recv(xpub.as_mut()), b"\x01");
+
+            send(xpub.as_mut(), b"1", ZMQ_DONTWAIT).unwrap();
+            send(xpub.as_mut(), b"2", ZMQ_DONTWAIT).unwrap();
+            if nodrop == 1 {
+                // The message is refused, and accepted again once the
+                // subscriber catches up.
+                assert_eq!(send(xpub.as_mut(), b"3", ZMQ_DONTWAIT), Err(ZMQ_EAGAIN));
+                assert_eq!(recv(subscriber.as_mut()), b"1");
+                send(xpub.as_mut(), b"3", ZMQ_DONTWAIT).unwrap();
+                assert_eq!(recv(subscriber.as_mut()), b"2");
+                assert_eq!(recv(subscriber.as_mut()), b"3");
+            } else {
+                // The message is dropped for the subscriber at its HWM.
+                send(xpub.as_mut(), b"3", ZMQ_DONTWAIT).unwrap();
+                assert_eq!(recv(subscriber.as_mut()), b"1");
+                assert_eq!(recv(subscriber.as_mut()), b"2");
+            }
+            let mut msg = Message::new();
+            assert_eq!(subscriber.recv(&mut msg, ZMQ_DONTWAIT), Err(ZMQ_EAGAIN));
+        }
+    }
+
+    #[test]
+    fn test_xpub_verbose_and_verboser() {
+        fn notifications(xpub: &mut dyn SocketBehavior) -> Vec<Vec<u8>> {
+            let mut msg = Message::new();
+            let mut received = Vec::new();
+            while xpub.recv(&mut msg, ZMQ_DONTWAIT).is_ok() {
+                received.push(msg.data().to_vec());
+            }
+            received
+        }
+
+        // Number of subscribe and unsubscribe notifications passed up when
+        // two subscribers come and go for the same topic.
+        let ctx = Arc::new(Context::new());
+        let modes = [
+            (None, 1, 1),
+            (Some(ZMQ_XPUB_VERBOSE), 2, 1),
+            (Some(ZMQ_XPUB_VERBOSER), 2, 2),
+        ];
+        for (option, subscribes, unsubscribes) in modes {
+            let mut xpub = socket(&ctx, ZMQ_XPUB);
+            if let Some(option) = option {
+                xpub.setsockopt(option, &1i32.to_ne_bytes()).unwrap();
+            }
+            let mut first = socket(&ctx, ZMQ_SUB);
+            let mut second = socket(&ctx, ZMQ_SUB);
+            connect(first.as_mut(), xpub.as_mut());
+            connect(second.as_mut(), xpub.as_mut());
+
+            first.setsockopt(ZMQ_SUBSCRIBE, b"A").unwrap();
+            second.setsockopt(ZMQ_SUBSCRIBE, b"A").unwrap();
+            assert_eq!(
+                notifications(xpub.as_mut()),
+                vec![b"\x01A".to_vec(); subscribes]
+            );
+
+            first.setsockopt(ZMQ_UNSUBSCRIBE, b"A").unwrap();
+            second.setsockopt(ZMQ_UNSUBSCRIBE, b"A").unwrap();
+            assert_eq!(
+                notifications(xpub.as_mut()),
+                vec![b"\x00A".to_vec(); unsubscribes]
+            );
+        }
+    }
+
+    #[test]
+    fn test_xpub_manual_last_value() {
+        let ctx = Arc::new(Context::new());
+        let mut xpub = socket(&ctx, ZMQ_XPUB);
+        xpub.setsockopt(ZMQ_XPUB_MANUAL_LAST_VALUE, &1i32.to_ne_bytes())
+            .unwrap();
+        let mut first = socket(&ctx, ZMQ_SUB);
+        let mut second = socket(&ctx, ZMQ_SUB);
+        connect(first.as_mut(), xpub.as_mut());
+        connect(second.as_mut(), xpub.as_mut());
+        let mut msg = Message::new();
+
+        first.setsockopt(ZMQ_SUBSCRIBE, b"A").unwrap();
+        assert_eq!(recv(xpub.as_mut()), b"\x01A");
+        xpub.setsockopt(ZMQ_SUBSCRIBE, b"A").unwrap();
+        send(xpub.as_mut(), b"A1", 0).unwrap();
+        assert_eq!(recv(first.as_mut()), b"A1");
+
+        // The last value goes to the new subscriber only.
+        second.setsockopt(ZMQ_SUBSCRIBE, b"A").unwrap();
+        assert_eq!(recv(xpub.as_mut()), b"\x01A");
+        xpub.setsockopt(ZMQ_SUBSCRIBE, b"A").unwrap();
+        send(xpub.as_mut(), b"A2", 0).unwrap();
+        assert_eq!(recv(second.as_mut()), b"A2");
+        assert_eq!(first.recv(&mut msg, ZMQ_DONTWAIT), Err(ZMQ_EAGAIN));
+
+        // Later messages reach every subscriber.
+        send(xpub.as_mut(), b"A3", 0).unwrap();
+        assert_eq!(recv(first.as_mut()), b"A3");
+        assert_eq!(recv(second.as_mut()), b"A3");
+    }
+
+    #[test]
+    fn test_xsub_verbose_unsubscribe() {
+        let ctx = Arc::new(Context::new());
+        for verbose in [0i32, 1] {
+            let mut xpub = socket(&ctx, ZMQ_XPUB);
+            xpub.setsockopt(ZMQ_XPUB_VERBOSER, &1i32.to_ne_bytes())
+                .unwrap();
+            let mut xsub = socket(&ctx, ZMQ_XSUB);
+            xsub.setsockopt(ZMQ_XSUB_VERBOSE_UNSUBSCRIBE, &verbose.to_ne_bytes())
+                .unwrap();
+            connect(xsub.as_mut(), xpub.as_mut());
+
+            send(xsub.as_mut(), b"\x01A", 0).unwrap();
+            send(xsub.as_mut(), b"\x01A", 0).unwrap();
+            send(xsub.as_mut(), b"\x00A", 0).unwrap();
+            send(xsub.as_mut(), b"\x00A", 0).unwrap();
+            send(xsub.as_mut(), b"\x00B", 0).unwrap();
+
+            // Unless verbose, only the cancellation dropping the last
+            // reference to a topic goes upstream.
+            let expected: &[&[u8]] = if verbose == 1 {
+                &[b"\x01A", b"\x01A", b"\x00A", b"\x00A", b"\x00B"]
+            } else {
+                &[b"\x01A", b"\x01A", b"\x00A"]
+            };
+            for data in expected {
+                assert_eq!(recv(xpub.as_mut()), *data);
+            }
+            let mut msg = Message::new();
+            assert_eq!(xpub.recv(&mut msg, ZMQ_DONTWAIT), Err(ZMQ_EAGAIN));
+        }
+    }
+
+    #[test]
+    fn test_only_first_subscribe() {
+        fn topics(socket: &mut dyn SocketBehavior) -> i32 {
+            let mut value = [0u8; 4];
+            socket.getsockopt(ZMQ_TOPICS_COUNT, &mut value).unwrap();
+            i32::from_ne_bytes(value)
+        }
+
+        let ctx = Arc::new(Context::new());
+        for only_first in [0i32, 1] {
+            let mut xpub = socket(&ctx, ZMQ_XPUB);
+            xpub.setsockopt(ZMQ_ONLY_FIRST_SUBSCRIBE, &only_first.to_ne_bytes())
+                .unwrap();
+            let mut xsub = socket(&ctx, ZMQ_XSUB);
+            xsub.setsockopt(ZMQ_ONLY_FIRST_SUBSCRIBE, &only_first.to_ne_bytes())
+                .unwrap();
+            connect(xsub.as_mut(), xpub.as_mut());
+
+            // With the option, a part following user data is user data as
+            // well, whatever its first byte.
+            send(xsub.as_mut(), b"data", ZMQ_SNDMORE).unwrap();
+            send(xsub.as_mut(), b"\x01B", 0).unwrap();
+            assert_eq!(recv(xpub.as_mut()), b"data");
+            assert_eq!(recv(xpub.as_mut()), b"\x01B");
+            assert_eq!(topics(xsub.as_mut()), 1 - only_first);
+            assert_eq!(topics(xpub.as_mut()), 1 - only_first);
+        }
+    }
+
+    #[test]
+    fn test_conflate_rejects_multipart() {
+        let ctx = Arc::new(Context::new());
+        let mut push = socket(&ctx, ZMQ_PUSH);
+        push.setsockopt(ZMQ_CONFLATE, &1i32.to_ne_bytes()).unwrap();
+        assert_eq!(send(push.as_mut(), b"head", ZMQ_SNDMORE), Err(libc::EINVAL));
+
+        // Socket types that don't conflate accept multipart messages.
+        let mut router = socket(&ctx, ZMQ_ROUTER);
+        router
+            .setsockopt(ZMQ_CONFLATE, &1i32.to_ne_bytes())
+            .unwrap();
+        send(router.as_mut(), b"peer", ZMQ_SNDMORE).unwrap();
+        send(router.as_mut(), b"body", 0).unwrap();
+    }
+
+    #[test]
+    fn test_conflating_receiver_drops_multipart() {
+        let ctx = Arc::new(Context::new());
+        let conflate = 1i32.to_ne_bytes();
+
+        // The peer is there when connecting.
+        let mut pull = ctx.create_socket(ZMQ_PULL).unwrap();
+        let mut push = ctx.create_socket(ZMQ_PUSH).unwrap();
+        pull.setsockopt(ZMQ_CONFLATE, &conflate).unwrap();
+        pull.bind("inproc://conflate-bound").unwrap();
+        push.connect("inproc://conflate-bound").unwrap();
+        send(push.as_mut(), b"head", ZMQ_SNDMORE).unwrap();
+        send(push.as_mut(), b"tail", 0).unwrap();
+        send(push.as_mut(), b"one", 0).unwrap();
+        send(push.as_mut(), b"two", 0).unwrap();
+        send(push.as_mut(), b"head", ZMQ_SNDMORE).unwrap();
+        send(push.as_mut(), b"tail", 0).unwrap();
+        assert_eq!(recv(pull.as_mut()), b"two");
+        let mut msg = Message::new();
+        assert_eq!(pull.recv(&mut msg, ZMQ_DONTWAIT), Err(ZMQ_EAGAIN));
+
+        // Messages queued before the bind are reduced the same way.
+        let mut pull = ctx.create_socket(ZMQ_PULL).unwrap();
+        let mut push = ctx.create_socket(ZMQ_PUSH).unwrap();
+        pull.setsockopt(ZMQ_CONFLATE, &conflate).unwrap();
+        push.connect("inproc://conflate-pending").unwrap();
+        send(push.as_mut(), b"early", 0).unwrap();
+        send(push.as_mut(), b"head", ZMQ_SNDMORE).unwrap();
+        send(push.as_mut(), b"tail", 0).unwrap();
+        pull.bind("inproc://conflate-pending").unwrap();
+        assert_eq!(recv(pull.as_mut()), b"early");
+        assert_eq!(pull.recv(&mut msg, ZMQ_DONTWAIT), Err(ZMQ_EAGAIN));
+    }
+
+    #[test]
+    fn test_pair_rejects_second_peer() {
+        let ctx = Arc::new(Context::new());
+        let mut first = socket(&ctx, ZMQ_PAIR);
+        let mut second = socket(&ctx, ZMQ_PAIR);
+        let mut third = socket(&ctx, ZMQ_PAIR);
+        connect(second.as_mut(), first.as_mut());
+        connect(third.as_mut(), first.as_mut());
+
+        send(second.as_mut(), b"hi", 0).unwrap();
+        assert_eq!(recv(first.as_mut()), b"hi");
+
+        // The third peer's pipe was terminated by the first socket.
+        let mut msg = Message::new();
+        assert_eq!(third.recv(&mut msg, ZMQ_DONTWAIT), Err(ZMQ_EAGAIN));
+        assert!(third.base().pipes.is_empty());
+    }
+
+    #[test]
+    fn test_scatter_gather_and_channel() {
+        fn events(socket: &mut dyn SocketBehavior) -> i32 {
+            let mut value = [0u8; 4];
+            socket.getsockopt(ZMQ_EVENTS, &mut value).unwrap();
+            i32::from_ne_bytes(value)
+        }
+
+        let ctx = Arc::new(Context::new());
+        let mut scatter = socket(&ctx, ZMQ_SCATTER);
+        let mut gather = socket(&ctx, ZMQ_GATHER);
+        assert_eq!(events(scatter.as_mut()), 0);
+        connect(scatter.as_mut(), gather.as_mut());
+        assert_eq!(events(scatter.as_mut()), ZMQ_POLLOUT as i32);
+        assert_eq!(events(gather.as_mut()), 0);
+
+        // Draft socket types carry single-part messages only.
+        assert_eq!(
+            send(scatter.as_mut(), b"head", ZMQ_SNDMORE),
+            Err(libc::EINVAL)
+        );
+        send(scatter.as_mut(), b"work", 0).unwrap();
+        assert_eq!(events(gather.as_mut()), ZMQ_POLLIN as i32);
+        assert_eq!(recv(gather.as_mut()), b"work");
+
+        // Multipart messages from a misbehaving peer are dropped whole.
+        let mut push = socket(&ctx, ZMQ_PUSH);
+        connect(push.as_mut(), gather.as_mut());
+        send(push.as_mut(), b"head", ZMQ_SNDMORE).unwrap();
+        send(push.as_mut(), b"tail", 0).unwrap();
+        send(push.as_mut(), b"single", 0).unwrap();
+        assert_eq!(recv(gather.as_mut()), b"single");
+
+        let mut first = socket(&ctx, ZMQ_CHANNEL);
+        let mut second = socket(&ctx, ZMQ_CHANNEL);
+        connect(first.as_mut(), second.as_mut());
+        assert_eq!(events(first.as_mut()), ZMQ_POLLOUT as i32);
+        assert_eq!(
+            send(first.as_mut(), b"head", ZMQ_SNDMORE),
+            Err(libc::EINVAL)
+        );
+        send(first.as_mut(), b"ping", 0).unwrap();
+        assert_eq!(events(second.as_mut()), (ZMQ_POLLIN | ZMQ_POLLOUT) as i32);
+        assert_eq!(recv(second.as_mut()), b"ping");
+        send(second.as_mut(), b"pong", 0).unwrap();
+        assert_eq!(recv(first.as_mut()), b"pong");
+    }
+
+    #[test]
+    fn test_unsupported_transports() {
+        let ctx = Arc::new(Context::new());
+        let mut pair = ctx.create_socket(ZMQ_PAIR).unwrap();
+        for endpoint in [
+            "ipc:///tmp/socket",
+            "pgm://eth0;239.192.1.1:5555",
+            "epgm://eth0;239.192.1.1:5555",
+            "ws://127.0.0.1:5555",
+            "wss://127.0.0.1:5555",
+            "bogus://address",
+        ] {
+            assert_eq!(pair.bind(endpoint), Err(ZMQ_EPROTONOSUPPORT));
+            assert_eq!(pair.connect(endpoint), Err(ZMQ_EPROTONOSUPPORT));
+            assert_eq!(pair.unbind(endpoint), Err(ZMQ_EPROTONOSUPPORT));
+        }
+
+        // UDP is known, but only to RADIO and DISH.
+        assert_eq!(pair.bind("udp://127.0.0.1:5555"), Err(ZMQ_ENOCOMPATPROTO));
+        assert_eq!(
+            pair.connect("udp://127.0.0.1:5555"),
+            Err(ZMQ_ENOCOMPATPROTO)
+        );
+    }
+
+    #[test]
+    fn test_inproc_bind_then_connect() {
+        let ctx = Arc::new(Context::new());
+        let mut rep = ctx.create_socket(ZMQ_REP).unwrap();
+        let mut req = ctx.create_socket(ZMQ_REQ).unwrap();
+        rep.bind("inproc://bind-first").unwrap();
+        req.connect("inproc://bind-first").unwrap();
+
+        send(req.as_mut(), b"ping", 0).unwrap();
+        assert_eq!(recv(rep.as_mut()), b"ping");
+        send(rep.as_mut(), b"pong", 0).unwrap();
+        assert_eq!(recv(req.as_mut()), b"pong");
+
+        // The name stays taken until the socket unbinds.
+        let mut other = ctx.create_socket(ZMQ_REP).unwrap();
+        assert_eq!(other.bind("inproc://bind-first"), Err(libc::EADDRINUSE));
+    }
+
+    #[test]
+    fn test_inproc_connect_before_bind() {
+        let ctx = Arc::new(Context::new());
+        let mut dealer = ctx.create_socket(ZMQ_DEALER).unwrap();
+        let mut router = ctx.create_socket(ZMQ_ROUTER).unwrap();
+        dealer.connect("inproc://pending").unwrap();
+        send(dealer.as_mut(), b"early", 0).unwrap();
+
+        router.bind("inproc://pending").unwrap();
+        let routing_id = recv(router.as_mut());
+        assert_eq!(routing_id.len(), 5);
+        assert_eq!(recv(router.as_mut()), b"early");
+
+        // A PULL socket doesn't want the routing id the connector sent.
+        let mut push = ctx.create_socket(ZMQ_PUSH).unwrap();
+        let mut pull = ctx.create_socket(ZMQ_PULL).unwrap();
+        push.connect("inproc://pending-pull").unwrap();
+        send(push.as_mut(), b"data", 0).unwrap();
+        pull.bind("inproc://pending-pull").unwrap();
+        assert_eq!(recv(pull.as_mut()), b"data");
+    }
+
+    #[test]
+    fn test_inproc_hwm_is_sum_of_both_sides() {
+        let ctx = Arc::new(Context::new());
+        for bind_first in [true, false] {
+            let mut push = ctx.create_socket(ZMQ_PUSH).unwrap();
+            let mut pull = ctx.create_socket(ZMQ_PULL).unwrap();
+            push.setsockopt(ZMQ_SNDHWM, &2i32.to_ne_bytes()).unwrap();
+            pull.setsockopt(ZMQ_RCVHWM, &3i32.to_ne_bytes()).unwrap();
+
+            let endpoint = format!("inproc://hwm-{}", bind_first);
+            if bind_first {
+                pull.bind(&endpoint).unwrap();
+                push.connect(&endpoint).unwrap();
+            } else {
+                push.connect(&endpoint).unwrap();
+                pull.bind(&endpoint).unwrap();
+            }
+
+            let mut sent = 0;
+            while send(push.as_mut(), b"x", ZMQ_DONTWAIT).is_ok() {
+                sent += 1;
+            }
+            assert_eq!(sent, 5);
+
+            push.close().unwrap();
+            pull.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_inproc_unbind_and_disconnect() {
+        let ctx = Arc::new(Context::new());
+        let mut pull = ctx.create_socket(ZMQ_PULL).unwrap();
+        let mut push = ctx.create_socket(ZMQ_PUSH).unwrap();
+        pull.bind("inproc://unbind").unwrap();
+        push.connect("inproc://unbind").unwrap();
+
+        // Unbinding keeps existing connections but refuses new ones.
+        pull.unbind("inproc://unbind").unwrap();
+        assert_eq!(pull.unbind("inproc://unbind"), Err(libc::ENOENT));
+        send(push.as_mut(), b"still here", 0).unwrap();
+        assert_eq!(recv(pull.as_mut()), b"still here");
+        assert!(ctx.find_endpoint("inproc://unbind").is_err());
+
+        // Disconnecting terminates the pipe; queued messages are delivered,
+        // even though PUSH pipes don't wait for their own inbound messages.
+        send(push.as_mut(), b"queued", ZMQ_SNDMORE).unwrap();
+        send(push.as_mut(), b"multipart", 0).unwrap();
+        send(push.as_mut(), b"last", 0).unwrap();
+        push.disconnect("inproc://unbind").unwrap();
+        assert_eq!(push.disconnect("inproc://unbind"), Err(libc::ENOENT));
+        assert_eq!(recv(pull.as_mut()), b"queued");
+        assert_eq!(recv(pull.as_mut()), b"multipart");
+        assert_eq!(recv(pull.as_mut()), b"last");
+        let mut msg = Message::new();
+        assert_eq!(pull.recv(&mut msg, ZMQ_DONTWAIT), Err(ZMQ_EAGAIN));
+        pull.process_commands(0).unwrap();
+        assert!(pull.base().pipes.is_empty());
+        assert_eq!(send(push.as_mut(), b"gone", ZMQ_DONTWAIT), Err(ZMQ_EAGAIN));
+    }
+
+    #[test]
+    fn test_tcp_req_rep() {
+        let ctx = Arc::new(Context::new());
+        let mut rep = ctx.create_socket(ZMQ_REP).unwrap();
+        let mut req = ctx.create_socket(ZMQ_REQ).unwrap();
+        for socket in [&mut rep, &mut req] {
+            socket.setsockopt(ZMQ_LINGER, &0i32.to_ne_bytes()).unwrap();
+            socket
+                .setsockopt(ZMQ_RCVTIMEO, &5000i32.to_ne_bytes())
+                .unwrap();
+        }
 
-// pub trait SocketOps {
+        // The port picked by the OS is reported as the last endpoint.
+        rep.bind("tcp://127.0.0.1:*").unwrap();
+        let endpoint = last_endpoint(rep.as_mut());
+        assert!(endpoint.starts_with("tcp://127.0.0.1:"));
+        assert!(!endpoint.ends_with(":*"));
 
-// }
+        // Messages sent before the connection is up are queued.
+        req.connect(&endpoint).unwrap();
+        send(req.as_mut(), b"ping", 0).unwrap();
+        let mut msg = Message::new();
+        rep.recv(&mut msg, 0).unwrap();
+        assert_eq!(msg.data(), b"ping");
+        send(rep.as_mut(), b"pong", 0).unwrap();
+        req.recv(&mut msg, 0).unwrap();
+        assert_eq!(msg.data(), b"pong");
+
+        req.disconnect(&endpoint).unwrap();
+        assert_eq!(req.disconnect(&endpoint), Err(libc::ENOENT));
+        rep.unbind(&endpoint).unwrap();
+
+        req.close().unwrap();
+        rep.close().unwrap();
+        ctx.terminate().unwrap();
+    }
+
+    #[test]
+    fn test_tcp_stream_raw_peers() {
+        use std::io::{Read, Write};
+
+        let ctx = Arc::new(Context::new());
+        let mut stream = ctx.create_socket(ZMQ_STREAM).unwrap();
+        stream.setsockopt(ZMQ_LINGER, &0i32.to_ne_bytes()).unwrap();
+        stream
+            .setsockopt(ZMQ_RCVTIMEO, &5000i32.to_ne_bytes())
+            .unwrap();
+        stream.bind("tcp://127.0.0.1:*").unwrap();
+        let endpoint = last_endpoint(stream.as_mut());
+        let address = endpoint.trim_start_matches("tcp://");
+
+        fn recv_pair(stream: &mut dyn SocketBehavior) -> (Vec<u8>, Vec<u8>) {
+            let mut routing_id = Message::new();
+            let mut data = Message::new();
+            stream.recv(&mut routing_id, 0).unwrap();
+            assert!(routing_id.has_more());
+            stream.recv(&mut data, 0).unwrap();
+            assert!(!data.has_more());
+            (routing_id.data().to_vec(), data.data().to_vec())
+        }
+
+        // A connection shows up as an empty message from its routing id.
+        let mut peer = std::net::TcpStream::connect(address).unwrap();
+        let (routing_id, data) = recv_pair(stream.as_mut());
+        assert_eq!(routing_id.len(), 5);
+        assert!(data.is_empty());
+
+        // Bytes travel as they are.
+        peer.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        let (from, data) = recv_pair(stream.as_mut());
+        assert_eq!(from, routing_id);
+        assert_eq!(data, b"GET / HTTP/1.0\r\n\r\n");
+
+        send(stream.as_mut(), &routing_id, ZMQ_SNDMORE).unwrap();
+        send(stream.as_mut(), b"HTTP/1.0 200 OK\r\n", 0).unwrap();
+        let mut reply = [0u8; 17];
+        peer.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"HTTP/1.0 200 OK\r\n");
+
+        // An empty message closes the connection.
+        send(stream.as_mut(), &routing_id, ZMQ_SNDMORE).unwrap();
+        send(stream.as_mut(), b"", 0).unwrap();
+        assert_eq!(peer.read(&mut reply).unwrap(), 0);
+
+        // Peers going away are reported the same way as new ones.
+        let peer = std::net::TcpStream::connect(address).unwrap();
+        let (routing_id, _) = recv_pair(stream.as_mut());
+        drop(peer);
+        let (from, data) = recv_pair(stream.as_mut());
+        assert_eq!(from, routing_id);
+        assert!(data.is_empty());
+
+        // Data the peer sent right before closing arrives ahead of the
+        // notification, although the session drops the pipe without
+        // lingering.
+        let mut peer = std::net::TcpStream::connect(address).unwrap();
+        let (routing_id, _) = recv_pair(stream.as_mut());
+        peer.write_all(b"bye").unwrap();
+        drop(peer);
+        let (from, data) = recv_pair(stream.as_mut());
+        assert_eq!(from, routing_id);
+        assert_eq!(data, b"bye");
+        let (from, data) = recv_pair(stream.as_mut());
+        assert_eq!(from, routing_id);
+        assert!(data.is_empty());
+
+        // Unknown routing ids can't be sent to.
+        assert_eq!(
+            send(stream.as_mut(), b"\0\0\0\0\0", ZMQ_SNDMORE),
+            Err(libc::EHOSTUNREACH)
+        );
+
+        stream.close().unwrap();
+        ctx.terminate().unwrap();
+    }
+
+    #[test]
+    fn test_tcp_subscriptions_to_zmtp_2_0_peer() {
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, TcpStream};
+
+        // Plays a ZMTP/2.0 PUB socket up to the exchange of the (empty)
+        // routing ids.
+        fn accept_publisher(listener: &TcpListener) -> TcpStream {
+            let (mut peer, _) = listener.accept().unwrap();
+            peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let signature = [0xff, 0, 0, 0, 0, 0, 0, 0, 1, 0x7f];
+            peer.write_all(&signature).unwrap();
+            peer.write_all(&[1, ZMQ_PUB as u8, 0, 0]).unwrap();
+
+            let mut greeting = [0u8; 14];
+            peer.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting[..10], signature);
+            assert_eq!(greeting[11], ZMQ_SUB as u8);
+            assert_eq!(greeting[12..], [0, 0]);
+            peer
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ctx = Arc::new(Context::new());
+        let mut subscriber = ctx.create_socket(ZMQ_SUB).unwrap();
+        subscriber
+            .setsockopt(ZMQ_LINGER, &0i32.to_ne_bytes())
+            .unwrap();
+        subscriber
+            .setsockopt(ZMQ_RCVTIMEO, &5000i32.to_ne_bytes())
+            .unwrap();
+        subscriber.setsockopt(ZMQ_SUBSCRIBE, b"A").unwrap();
+        let endpoint = format!("tcp://{}", listener.local_addr().unwrap());
+        subscriber.connect(&endpoint).unwrap();
+
+        // The subscription goes out as a message prefixed with 1.
+        let mut peer = accept_publisher(&listener);
+        let mut frame = [0u8; 4];
+        peer.read_exact(&mut frame).unwrap();
+        assert_eq!(frame, [0, 2, 1, b'A']);
+        peer.write_all(&[0, 2, b'B', b'1', 0, 2, b'A', b'1'])
+            .unwrap();
+        let mut msg = Message::new();
+        subscriber.recv(&mut msg, 0).unwrap();
+        assert_eq!(msg.data(), b"A1");
+
+        // After reconnecting, the subscriber sends it again once it
+        // handles the hiccup of its pipe.
+        drop(peer);
+        let mut peer = accept_publisher(&listener);
+        assert_eq!(subscriber.recv(&mut msg, ZMQ_DONTWAIT), Err(ZMQ_EAGAIN));
+        peer.read_exact(&mut frame).unwrap();
+        assert_eq!(frame, [0, 2, 1, b'A']);
+
+        // Cancellations are prefixed with 0.
+        subscriber.setsockopt(ZMQ_UNSUBSCRIBE, b"A").unwrap();
+        peer.read_exact(&mut frame).unwrap();
+        assert_eq!(frame, [0, 2, 0, b'A']);
+
+        subscriber.close().unwrap();
+        ctx.terminate().unwrap();
+    }
+
+    #[cfg(feature = "curve")]
+    #[test]
+    fn test_tcp_curve_req_rep() {
+        use crate::constants::{
+            ZMQ_CURVE_PUBLICKEY, ZMQ_CURVE_SECRETKEY, ZMQ_CURVE_SERVER, ZMQ_CURVE_SERVERKEY,
+        };
+        use sodiumoxide::crypto::box_;
+
+        let (server_public, server_secret) = box_::gen_keypair();
+        let ctx = Arc::new(Context::new());
+        let mut rep = ctx.create_socket(ZMQ_REP).unwrap();
+        rep.setsockopt(ZMQ_LINGER, &0i32.to_ne_bytes()).unwrap();
+        rep.setsockopt(ZMQ_RCVTIMEO, &5000i32.to_ne_bytes())
+            .unwrap();
+        rep.setsockopt(ZMQ_CURVE_SERVER, &1i32.to_ne_bytes())
+            .unwrap();
+        rep.setsockopt(ZMQ_CURVE_SECRETKEY, &server_secret.0)
+            .unwrap();
+        rep.bind("tcp://127.0.0.1:*").unwrap();
+        let endpoint = last_endpoint(rep.as_mut());
+
+        let curve_client = |server_key: &box_::PublicKey| {
+            let (public, secret) = box_::gen_keypair();
+            let mut req = ctx.create_socket(ZMQ_REQ).unwrap();
+            req.setsockopt(ZMQ_LINGER, &0i32.to_ne_bytes()).unwrap();
+            req.setsockopt(ZMQ_RCVTIMEO, &5000i32.to_ne_bytes())
+                .unwrap();
+            req.setsockopt(ZMQ_CURVE_SERVERKEY, &server_key.0).unwrap();
+            req.setsockopt(ZMQ_CURVE_PUBLICKEY, &public.0).unwrap();
+            req.setsockopt(ZMQ_CURVE_SECRETKEY, &secret.0).unwrap();
+            req.connect(&endpoint).unwrap();
+            req
+        };
+
+        // A client that knows the server's key gets through, multipart
+        // messages and all.
+        let mut req = curve_client(&server_public);
+        send(req.as_mut(), b"hello", ZMQ_SNDMORE).unwrap();
+        send(req.as_mut(), b"world", 0).unwrap();
+        let mut msg = Message::new();
+        rep.recv(&mut msg, 0).unwrap();
+        assert_eq!(msg.data(), b"hello");
+        assert!(msg.has_more());
+        rep.recv(&mut msg, 0).unwrap();
+        assert_eq!(msg.data(), b"world");
+        send(rep.as_mut(), b"welcome", 0).unwrap();
+        req.recv(&mut msg, 0).unwrap();
+        assert_eq!(msg.data(), b"welcome");
+
+        // One with the wrong key never completes the handshake.
+        let (other_public, _) = box_::gen_keypair();
+        let mut intruder = curve_client(&other_public);
+        send(intruder.as_mut(), b"hello", 0).unwrap();
+        rep.setsockopt(ZMQ_RCVTIMEO, &200i32.to_ne_bytes()).unwrap();
+        assert_eq!(rep.recv(&mut msg, 0), Err(libc::EAGAIN));
+
+        intruder.close().unwrap();
+        req.close().unwrap();
+        rep.close().unwrap();
+        ctx.terminate().unwrap();
+    }
+
+    // Binds a REP socket as the context's ZAP handler.
+    fn zap_handler(ctx: &Arc<Context>) -> Box<dyn SocketBehavior> {
+        let mut handler = ctx.create_socket(ZMQ_REP).unwrap();
+        handler.setsockopt(ZMQ_LINGER, &0i32.to_ne_bytes()).unwrap();
+        handler
+            .setsockopt(ZMQ_RCVTIMEO, &5000i32.to_ne_bytes())
+            .unwrap();
+        handler.bind(crate::zap_client::ZAP_ENDPOINT).unwrap();
+        handler
+    }
+
+    // Answers a ZAP request with the status code and user id, and a
+    // property in the metadata. Returns the frames of the request.
+    fn zap_reply(
+        handler: &mut dyn SocketBehavior,
+        status_code: &[u8],
+        user_id: &[u8],
+    ) -> Vec<Vec<u8>> {
+        let mut request = Vec::new();
+        let mut msg = Message::new();
+        loop {
+            handler.recv(&mut msg, 0).unwrap();
+            request.push(msg.data().to_vec());
+            if !msg.has_more() {
+                break;
+            }
+        }
+
+        let mut metadata = Vec::new();
+        crate::mechanism::add_property(&mut metadata, "Hello", b"World");
+        let reply: [&[u8]; 6] = [b"1.0", &request[1], status_code, b"", user_id, &metadata];
+        for (i, frame) in reply.iter().enumerate() {
+            let flags = if i < reply.len() - 1 { ZMQ_SNDMORE } else { 0 };
+            send(handler, frame, flags).unwrap();
+        }
+        request
+    }
+
+    #[test]
+    fn test_tcp_plain_zap() {
+        use crate::constants::{
+            ZMQ_PLAIN_PASSWORD, ZMQ_PLAIN_SERVER, ZMQ_PLAIN_USERNAME, ZMQ_ZAP_DOMAIN,
+        };
+
+        let ctx = Arc::new(Context::new());
+        let mut handler = zap_handler(&ctx);
+        let mut pull = ctx.create_socket(ZMQ_PULL).unwrap();
+        pull.setsockopt(ZMQ_LINGER, &0i32.to_ne_bytes()).unwrap();
+        pull.setsockopt(ZMQ_RCVTIMEO, &5000i32.to_ne_bytes())
+            .unwrap();
+        pull.setsockopt(ZMQ_PLAIN_SERVER, &1i32.to_ne_bytes())
+            .unwrap();
+        pull.setsockopt(ZMQ_ZAP_DOMAIN, b"global").unwrap();
+        pull.bind("tcp://127.0.0.1:*").unwrap();
+        let endpoint = last_endpoint(pull.as_mut());
+
+        let plain_client = |password: &[u8]| {
+            let mut push = ctx.create_socket(ZMQ_PUSH).unwrap();
+            push.setsockopt(ZMQ_LINGER, &0i32.to_ne_bytes()).unwrap();
+            push.setsockopt(ZMQ_PLAIN_USERNAME, b"admin").unwrap();
+            push.setsockopt(ZMQ_PLAIN_PASSWORD, password).unwrap();
+            push.connect(&endpoint).unwrap();
+            push
+        };
+
+        // The handler sees the credentials and lets the client in; the
+        // user id and metadata it returned come with the messages.
+        let mut push = plain_client(b"password");
+        send(push.as_mut(), b"hello", 0).unwrap();
+        let request = zap_reply(handler.as_mut(), b"200", b"admin");
+        let expected: [&[u8]; 8] = [
+            b"1.0",
+            b"1",
+            b"global",
+            b"127.0.0.1",
+            b"",
+            b"PLAIN",
+            b"admin",
+            b"password",
+        ];
+        assert_eq!(request, expected);
+
+        let mut msg = Message::new();
+        pull.recv(&mut msg, 0).unwrap();
+        assert_eq!(msg.data(), b"hello");
+        let metadata = msg.metadata().unwrap();
+        assert_eq!(metadata.get("User-Id"), Some("admin"));
+        assert_eq!(metadata.get("Hello"), Some("World"));
+
+        // A wrong password is turned away.
+        let mut intruder = plain_client(b"guess");
+        send(intruder.as_mut(), b"let me in", 0).unwrap();
+        let request = zap_reply(handler.as_mut(), b"400", b"");
+        assert_eq!(request[7], b"guess");
+        pull.setsockopt(ZMQ_RCVTIMEO, &200i32.to_ne_bytes())
+            .unwrap();
+        assert_eq!(pull.recv(&mut msg, 0), Err(libc::EAGAIN));
+
+        intruder.close().unwrap();
+        push.close().unwrap();
+        pull.close().unwrap();
+        handler.close().unwrap();
+        ctx.terminate().unwrap();
+    }
+
+    #[test]
+    fn test_tcp_null_zap_domain() {
+        use crate::constants::ZMQ_ZAP_DOMAIN;
+        use crate::zmq_draft::ZMQ_ZAP_ENFORCE_DOMAIN;
+
+        let ctx = Arc::new(Context::new());
+        let pull_with_domain = |enforce: bool| {
+            let mut pull = ctx.create_socket(ZMQ_PULL).unwrap();
+            pull.setsockopt(ZMQ_LINGER, &0i32.to_ne_bytes()).unwrap();
+            pull.setsockopt(ZMQ_RCVTIMEO, &200i32.to_ne_bytes())
+                .unwrap();
+            pull.setsockopt(ZMQ_ZAP_DOMAIN, b"global").unwrap();
+            pull.setsockopt(ZMQ_ZAP_ENFORCE_DOMAIN, &(enforce as i32).to_ne_bytes())
+                .unwrap();
+            pull.bind("tcp://127.0.0.1:*").unwrap();
+            pull
+        };
+        let connect_push = |pull: &mut dyn SocketBehavior| {
+            let mut push = ctx.create_socket(ZMQ_PUSH).unwrap();
+            push.setsockopt(ZMQ_LINGER, &0i32.to_ne_bytes()).unwrap();
+            push.connect(&last_endpoint(pull)).unwrap();
+            send(push.as_mut(), b"hello", 0).unwrap();
+            push
+        };
+        let mut msg = Message::new();
+
+        // Without a handler the domain is ignored, unless it is enforced.
+        let mut lax = pull_with_domain(false);
+        let mut push = connect_push(lax.as_mut());
+        lax.recv(&mut msg, 0).unwrap();
+        assert_eq!(msg.data(), b"hello");
+        push.close().unwrap();
+
+        let mut strict = pull_with_domain(true);
+        let mut push = connect_push(strict.as_mut());
+        assert_eq!(strict.recv(&mut msg, 0), Err(libc::EAGAIN));
+        push.close().unwrap();
+
+        // With a handler, it decides.
+        let mut handler = zap_handler(&ctx);
+        let mut push = connect_push(strict.as_mut());
+        let request = zap_reply(handler.as_mut(), b"200", b"anonymous");
+        assert_eq!(request.len(), 6);
+        assert_eq!(request[5], b"NULL");
+        strict
+            .setsockopt(ZMQ_RCVTIMEO, &5000i32.to_ne_bytes())
+            .unwrap();
+        strict.recv(&mut msg, 0).unwrap();
+        assert_eq!(msg.data(), b"hello");
+        assert_eq!(msg.metadata().unwrap().get("User-Id"), Some("anonymous"));
+        push.close().unwrap();
+
+        let mut push = connect_push(lax.as_mut());
+        zap_reply(handler.as_mut(), b"500", b"");
+        assert_eq!(lax.recv(&mut msg, 0), Err(libc::EAGAIN));
+
+        push.close().unwrap();
+        strict.close().unwrap();
+        lax.close().unwrap();
+        handler.close().unwrap();
+        ctx.terminate().unwrap();
+    }
+
+    #[cfg(feature = "curve")]
+    #[test]
+    fn test_tcp_curve_zap() {
+        use crate::constants::{
+            ZMQ_CURVE_PUBLICKEY, ZMQ_CURVE_SECRETKEY, ZMQ_CURVE_SERVER, ZMQ_CURVE_SERVERKEY,
+        };
+        use sodiumoxide::crypto::box_;
+
+        let (server_public, server_secret) = box_::gen_keypair();
+        let ctx = Arc::new(Context::new());
+        let mut handler = zap_handler(&ctx);
+        let mut pull = ctx.create_socket(ZMQ_PULL).unwrap();
+        pull.setsockopt(ZMQ_LINGER, &0i32.to_ne_bytes()).unwrap();
+        pull.setsockopt(ZMQ_RCVTIMEO, &5000i32.to_ne_bytes())
+            .unwrap();
+        pull.setsockopt(ZMQ_CURVE_SERVER, &1i32.to_ne_bytes())
+            .unwrap();
+        pull.setsockopt(ZMQ_CURVE_SECRETKEY, &server_secret.0)
+            .unwrap();
+        pull.bind("tcp://127.0.0.1:*").unwrap();
+        let endpoint = last_endpoint(pull.as_mut());
+
+        let curve_client = || {
+            let (public, secret) = box_::gen_keypair();
+            let mut push = ctx.create_socket(ZMQ_PUSH).unwrap();
+            push.setsockopt(ZMQ_LINGER, &0i32.to_ne_bytes()).unwrap();
+            push.setsockopt(ZMQ_CURVE_SERVERKEY, &server_public.0)
+                .unwrap();
+            push.setsockopt(ZMQ_CURVE_PUBLICKEY, &public.0).unwrap();
+            push.setsockopt(ZMQ_CURVE_SECRETKEY, &secret.0).unwrap();
+            push.connect(&endpoint).unwrap();
+            send(push.as_mut(), b"hello", 0).unwrap();
+            (push, public)
+        };
+
+        // The handler is asked about the client's long-term key.
+        let (mut push, public) = curve_client();
+        let request = zap_reply(handler.as_mut(), b"200", b"client");
+        assert_eq!(request[5], b"CURVE");
+        assert_eq!(request[6], public.0);
+        let mut msg = Message::new();
+        pull.recv(&mut msg, 0).unwrap();
+        assert_eq!(msg.data(), b"hello");
+        assert_eq!(msg.metadata().unwrap().get("User-Id"), Some("client"));
+
+        // Turned away temporarily, the client doesn't get an ERROR but is
+        // disconnected all the same.
+        let (mut intruder, _) = curve_client();
+        zap_reply(handler.as_mut(), b"300", b"");
+        pull.setsockopt(ZMQ_RCVTIMEO, &200i32.to_ne_bytes())
+            .unwrap();
+        assert_eq!(pull.recv(&mut msg, 0), Err(libc::EAGAIN));
+
+        intruder.close().unwrap();
+        push.close().unwrap();
+        pull.close().unwrap();
+        handler.close().unwrap();
+        ctx.terminate().unwrap();
+    }
+
+    #[test]
+    fn test_udp_radio_dish() {
+        let ctx = Arc::new(Context::new());
+        let mut radio = ctx.create_socket(ZMQ_RADIO).unwrap();
+        let mut dish = ctx.create_socket(ZMQ_DISH).unwrap();
+        radio.setsockopt(ZMQ_LINGER, &0i32.to_ne_bytes()).unwrap();
+
+        // Radios only send datagrams and dishes only receive them.
+        assert_eq!(radio.bind("udp://127.0.0.1:5556"), Err(ZMQ_ENOCOMPATPROTO));
+        assert_eq!(
+            dish.connect("udp://127.0.0.1:5556"),
+            Err(ZMQ_ENOCOMPATPROTO)
+        );
+
+        // Multicast over the loopback interface.
+        let endpoint = "udp://127.0.0.1;239.0.0.1:5556";
+        dish.bind(endpoint).unwrap();
+        dish.join("movies").unwrap();
+        assert_eq!(dish.join("movies"), Err(libc::EINVAL));
+        radio.connect(endpoint).unwrap();
+
+        // Datagrams sent before the dish's socket is up are lost, so keep
+        // sending until one gets through. Only the joined group does.
+        let mut msg = Message::new();
+        for _ in 0..100 {
+            for group in ["tv", "movies"] {
+                let mut out = Message::with_data(group.as_bytes()).unwrap();
+                out.set_group(group).unwrap();
+                radio.send(&mut out, 0).unwrap();
+            }
+            std::thread::sleep(Duration::from_millis(20));
+            if dish.recv(&mut msg, ZMQ_DONTWAIT).is_ok() {
+                break;
+            }
+        }
+        assert_eq!(msg.get_group(), "movies");
+        assert_eq!(msg.data(), b"movies");
+
+        dish.leave("movies").unwrap();
+        assert_eq!(dish.leave("movies"), Err(libc::EINVAL));
+        assert_eq!(radio.join("movies"), Err(libc::ENOTSUP));
+
+        radio.close().unwrap();
+        dish.close().unwrap();
+        ctx.terminate().unwrap();
+    }
+}
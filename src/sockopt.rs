@@ -0,0 +1,468 @@
+//! Typed socket options.
+//!
+//! Each option is a type carrying its `ZMQ_*` code and value type, so that
+//! `socket.set(SndHwm(1000))` and `socket.get::<LastEndpoint>()` are
+//! checked at compile time. Values are validated, and checked against the
+//! socket type, by the same code that serves `zmq_setsockopt()`.
+
+use std::mem;
+
+use crate::constants::*;
+use crate::err::ZmqError;
+use crate::rust_zmq::Result;
+use crate::zmq_draft::*;
+
+/// A socket option, identified by its `ZMQ_*` code.
+pub trait SocketOption {
+    const ID: i32;
+    type Value: OptionValue;
+}
+
+/// An option that can be written with `Socket::set`.
+pub trait SetOption: SocketOption {
+    fn into_value(self) -> Self::Value;
+}
+
+/// An option that can be read with `Socket::get`.
+pub trait GetOption: SocketOption {}
+
+/// Conversion between option values and their C representation.
+pub trait OptionValue: Sized {
+    /// Size of the buffer handed to getsockopt.
+    const MAX_SIZE: usize;
+
+    fn to_bytes(&self) -> Vec<u8>;
+    fn from_bytes(bytes: &[u8]) -> Result<Self>;
+}
+
+impl OptionValue for i32 {
+    const MAX_SIZE: usize = mem::size_of::<i32>();
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.to_ne_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let bytes = <[u8; mem::size_of::<i32>()]>::try_from(bytes)
+            .map_err(|_| ZmqError::InvalidInput)?;
+        Ok(i32::from_ne_bytes(bytes))
+    }
+}
+
+impl OptionValue for i64 {
+    const MAX_SIZE: usize = mem::size_of::<i64>();
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.to_ne_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let bytes = <[u8; mem::size_of::<i64>()]>::try_from(bytes)
+            .map_err(|_| ZmqError::InvalidInput)?;
+        Ok(i64::from_ne_bytes(bytes))
+    }
+}
+
+impl OptionValue for u64 {
+    const MAX_SIZE: usize = mem::size_of::<u64>();
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.to_ne_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let bytes = <[u8; mem::size_of::<u64>()]>::try_from(bytes)
+            .map_err(|_| ZmqError::InvalidInput)?;
+        Ok(u64::from_ne_bytes(bytes))
+    }
+}
+
+// Boolean options travel as ints, as in the C API.
+impl OptionValue for bool {
+    const MAX_SIZE: usize = mem::size_of::<i32>();
+
+    fn to_bytes(&self) -> Vec<u8> {
+        (*self as i32).to_bytes()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(i32::from_bytes(bytes)? != 0)
+    }
+}
+
+// Strings are read back NUL-terminated.
+impl OptionValue for String {
+    const MAX_SIZE: usize = 4096;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let bytes = match bytes.split_last() {
+            Some((0, rest)) => rest,
+            _ => bytes,
+        };
+        String::from_utf8(bytes.to_vec()).map_err(|_| ZmqError::InvalidInput)
+    }
+}
+
+impl OptionValue for Vec<u8> {
+    const MAX_SIZE: usize = 4096;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(bytes.to_vec())
+    }
+}
+
+macro_rules! socket_option {
+    ($(#[$doc:meta])* $name:ident, $id:expr, $ty:ty, rw) => {
+        socket_option!($(#[$doc])* $name, $id, $ty, w);
+        impl GetOption for $name {}
+    };
+    ($(#[$doc:meta])* $name:ident, $id:expr, $ty:ty, w) => {
+        $(#[$doc])*
+        #[derive(Debug, Clone, PartialEq)]
+        pub struct $name(pub $ty);
+
+        impl SocketOption for $name {
+            const ID: i32 = $id;
+            type Value = $ty;
+        }
+
+        impl SetOption for $name {
+            fn into_value(self) -> $ty {
+                self.0
+            }
+        }
+    };
+    ($(#[$doc:meta])* $name:ident, $id:expr, $ty:ty, r) => {
+        $(#[$doc])*
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        pub struct $name;
+
+        impl SocketOption for $name {
+            const ID: i32 = $id;
+            type Value = $ty;
+        }
+
+        impl GetOption for $name {}
+    };
+}
+
+socket_option!(
+    /// High water mark for outbound messages.
+    SndHwm, ZMQ_SNDHWM, i32, rw
+);
+socket_option!(
+    /// High water mark for inbound messages.
+    RcvHwm, ZMQ_RCVHWM, i32, rw
+);
+socket_option!(
+    /// I/O thread affinity bitmap for new connections.
+    Affinity, ZMQ_AFFINITY, u64, rw
+);
+socket_option!(
+    /// Socket identity, 1 to 255 bytes.
+    RoutingId, ZMQ_ROUTING_ID, Vec<u8>, rw
+);
+socket_option!(
+    /// Establishes a message filter on SUB, XSUB and manual XPUB sockets.
+    Subscribe, ZMQ_SUBSCRIBE, Vec<u8>, w
+);
+socket_option!(
+    /// Removes a message filter.
+    Unsubscribe, ZMQ_UNSUBSCRIBE, Vec<u8>, w
+);
+socket_option!(
+    /// Multicast data rate, in kilobits per second.
+    Rate, ZMQ_RATE, i32, rw
+);
+socket_option!(
+    /// Multicast recovery interval, in milliseconds.
+    RecoveryIvl, ZMQ_RECOVERY_IVL, i32, rw
+);
+socket_option!(
+    /// Kernel transmit buffer size, -1 for the OS default.
+    SndBuf, ZMQ_SNDBUF, i32, rw
+);
+socket_option!(
+    /// Kernel receive buffer size, -1 for the OS default.
+    RcvBuf, ZMQ_RCVBUF, i32, rw
+);
+socket_option!(
+    /// Whether more parts of the last received message follow.
+    RcvMore, ZMQ_RCVMORE, bool, r
+);
+socket_option!(
+    /// Bitmask of ZMQ_POLLIN and ZMQ_POLLOUT.
+    Events, ZMQ_EVENTS, i32, r
+);
+socket_option!(
+    /// The `ZMQ_*` socket type.
+    Type, ZMQ_TYPE, i32, r
+);
+socket_option!(
+    /// Linger period for pending messages on close, in milliseconds.
+    Linger, ZMQ_LINGER, i32, rw
+);
+socket_option!(
+    /// Initial reconnection interval, in milliseconds.
+    ReconnectIvl, ZMQ_RECONNECT_IVL, i32, rw
+);
+socket_option!(
+    /// Maximum length of the queue of pending connections.
+    Backlog, ZMQ_BACKLOG, i32, rw
+);
+socket_option!(
+    /// Maximum reconnection interval, in milliseconds.
+    ReconnectIvlMax, ZMQ_RECONNECT_IVL_MAX, i32, rw
+);
+socket_option!(
+    /// Maximum acceptable inbound message size, -1 for no limit.
+    MaxMsgSize, ZMQ_MAXMSGSIZE, i64, rw
+);
+socket_option!(
+    /// Maximum network hops for multicast packets.
+    MulticastHops, ZMQ_MULTICAST_HOPS, i32, rw
+);
+socket_option!(
+    /// Receive timeout, in milliseconds; -1 blocks forever.
+    RcvTimeo, ZMQ_RCVTIMEO, i32, rw
+);
+socket_option!(
+    /// Send timeout, in milliseconds; -1 blocks forever.
+    SndTimeo, ZMQ_SNDTIMEO, i32, rw
+);
+socket_option!(
+    /// Endpoint last bound or connected to.
+    LastEndpoint, ZMQ_LAST_ENDPOINT, String, r
+);
+socket_option!(
+    /// Fail sends to unroutable peers with EHOSTUNREACH.
+    RouterMandatory, ZMQ_ROUTER_MANDATORY, bool, w
+);
+socket_option!(
+    /// Overrides SO_KEEPALIVE: -1 for the OS default, 0 or 1.
+    TcpKeepalive, ZMQ_TCP_KEEPALIVE, i32, rw
+);
+socket_option!(
+    /// Overrides TCP_KEEPCNT.
+    TcpKeepaliveCnt, ZMQ_TCP_KEEPALIVE_CNT, i32, rw
+);
+socket_option!(
+    /// Overrides TCP_KEEPIDLE.
+    TcpKeepaliveIdle, ZMQ_TCP_KEEPALIVE_IDLE, i32, rw
+);
+socket_option!(
+    /// Overrides TCP_KEEPINTVL.
+    TcpKeepaliveIntvl, ZMQ_TCP_KEEPALIVE_INTVL, i32, rw
+);
+socket_option!(
+    /// Queue messages only to completed connections.
+    Immediate, ZMQ_IMMEDIATE, bool, rw
+);
+socket_option!(
+    /// Pass all subscription messages upstream.
+    XpubVerbose, ZMQ_XPUB_VERBOSE, bool, w
+);
+socket_option!(
+    /// Enables IPv6 in addition to IPv4.
+    Ipv6, ZMQ_IPV6, bool, rw
+);
+socket_option!(
+    /// The security mechanism in use, ZMQ_NULL, ZMQ_PLAIN or ZMQ_CURVE.
+    Mechanism, ZMQ_MECHANISM, i32, r
+);
+socket_option!(
+    /// Act as PLAIN server.
+    PlainServer, ZMQ_PLAIN_SERVER, bool, rw
+);
+socket_option!(
+    /// PLAIN client username.
+    PlainUsername, ZMQ_PLAIN_USERNAME, String, rw
+);
+socket_option!(
+    /// PLAIN client password.
+    PlainPassword, ZMQ_PLAIN_PASSWORD, String, rw
+);
+socket_option!(
+    /// Act as CURVE server.
+    CurveServer, ZMQ_CURVE_SERVER, bool, rw
+);
+socket_option!(
+    /// Long-term public key, 32 bytes or 40 Z85 characters.
+    CurvePublicKey, ZMQ_CURVE_PUBLICKEY, Vec<u8>, w
+);
+socket_option!(
+    /// Long-term secret key, 32 bytes or 40 Z85 characters.
+    CurveSecretKey, ZMQ_CURVE_SECRETKEY, Vec<u8>, w
+);
+socket_option!(
+    /// Public key of the server, 32 bytes or 40 Z85 characters.
+    CurveServerKey, ZMQ_CURVE_SERVERKEY, Vec<u8>, w
+);
+socket_option!(
+    /// Announce new peers to the ROUTER with an empty message.
+    ProbeRouter, ZMQ_PROBE_ROUTER, bool, w
+);
+socket_option!(
+    /// Match replies with requests on REQ sockets.
+    ReqCorrelate, ZMQ_REQ_CORRELATE, bool, w
+);
+socket_option!(
+    /// Relax the strict alternation between requests and replies.
+    ReqRelaxed, ZMQ_REQ_RELAXED, bool, w
+);
+socket_option!(
+    /// Keep only the last message in the queues.
+    Conflate, ZMQ_CONFLATE, bool, rw
+);
+socket_option!(
+    /// ZAP authentication domain.
+    ZapDomain, ZMQ_ZAP_DOMAIN, String, rw
+);
+socket_option!(
+    /// Hand a routing id over to a new connection that claims it.
+    RouterHandover, ZMQ_ROUTER_HANDOVER, bool, w
+);
+socket_option!(
+    /// IP type-of-service for outgoing packets.
+    Tos, ZMQ_TOS, i32, rw
+);
+socket_option!(
+    /// Routing id given to the peer of the next connect.
+    ConnectRoutingId, ZMQ_CONNECT_ROUTING_ID, Vec<u8>, w
+);
+socket_option!(
+    /// Maximum handshake duration, in milliseconds; 0 disables it.
+    HandshakeIvl, ZMQ_HANDSHAKE_IVL, i32, rw
+);
+socket_option!(
+    /// SOCKS5 proxy address.
+    SocksProxy, ZMQ_SOCKS_PROXY, String, rw
+);
+socket_option!(
+    /// Block instead of dropping messages at the high water mark.
+    XpubNodrop, ZMQ_XPUB_NODROP, bool, w
+);
+socket_option!(
+    /// Let the application manage subscriptions.
+    XpubManual, ZMQ_XPUB_MANUAL, bool, w
+);
+socket_option!(
+    /// Message sent to every new subscriber.
+    XpubWelcomeMsg, ZMQ_XPUB_WELCOME_MSG, Vec<u8>, w
+);
+socket_option!(
+    /// Deliver connect and disconnect notifications on STREAM sockets.
+    StreamNotify, ZMQ_STREAM_NOTIFY, bool, w
+);
+socket_option!(
+    /// Deliver messages that do not match the subscriptions.
+    InvertMatching, ZMQ_INVERT_MATCHING, bool, rw
+);
+socket_option!(
+    /// Interval between PING commands, in milliseconds.
+    HeartbeatIvl, ZMQ_HEARTBEAT_IVL, i32, rw
+);
+socket_option!(
+    /// Time-to-live advertised in PING commands, in milliseconds.
+    HeartbeatTtl, ZMQ_HEARTBEAT_TTL, i32, rw
+);
+socket_option!(
+    /// Time to wait for traffic after a PING, in milliseconds.
+    HeartbeatTimeout, ZMQ_HEARTBEAT_TIMEOUT, i32, rw
+);
+socket_option!(
+    /// Pass subscribe and unsubscribe messages upstream.
+    XpubVerboser, ZMQ_XPUB_VERBOSER, bool, w
+);
+socket_option!(
+    /// Timeout of connect() calls, in milliseconds.
+    ConnectTimeout, ZMQ_CONNECT_TIMEOUT, i32, rw
+);
+socket_option!(
+    /// Overrides TCP_MAXRT.
+    TcpMaxRt, ZMQ_TCP_MAXRT, i32, rw
+);
+socket_option!(
+    /// Whether the socket may be shared between threads.
+    ThreadSafe, ZMQ_THREAD_SAFE, bool, r
+);
+socket_option!(
+    /// Maximum multicast transport data unit size.
+    MulticastMaxTpdu, ZMQ_MULTICAST_MAXTPDU, i32, rw
+);
+socket_option!(
+    /// Pre-allocated file descriptor to bind to.
+    UseFd, ZMQ_USE_FD, i32, rw
+);
+socket_option!(
+    /// Network interface to bind to.
+    BindToDevice, ZMQ_BINDTODEVICE, String, rw
+);
+socket_option!(
+    /// Require a ZAP domain for NULL authentication.
+    ZapEnforceDomain, ZMQ_ZAP_ENFORCE_DOMAIN, bool, rw
+);
+socket_option!(
+    /// Adds application metadata, "X-key:value".
+    Metadata, ZMQ_METADATA, String, w
+);
+socket_option!(
+    /// Loop multicast packets back to the sender.
+    MulticastLoop, ZMQ_MULTICAST_LOOP, bool, rw
+);
+socket_option!(
+    /// ZMQ_NOTIFY_CONNECT and ZMQ_NOTIFY_DISCONNECT bitmask for ROUTER.
+    RouterNotify, ZMQ_ROUTER_NOTIFY, i32, rw
+);
+socket_option!(
+    /// Replace the last subscription instead of adding one.
+    XpubManualLastValue, ZMQ_XPUB_MANUAL_LAST_VALUE, bool, w
+);
+socket_option!(
+    /// SOCKS5 proxy username.
+    SocksUsername, ZMQ_SOCKS_USERNAME, String, rw
+);
+socket_option!(
+    /// SOCKS5 proxy password.
+    SocksPassword, ZMQ_SOCKS_PASSWORD, String, rw
+);
+socket_option!(
+    /// Only the first part of a multipart message is a subscription.
+    OnlyFirstSubscribe, ZMQ_ONLY_FIRST_SUBSCRIBE, bool, w
+);
+socket_option!(
+    /// Conditions on which reconnecting stops.
+    ReconnectStop, ZMQ_RECONNECT_STOP, i32, rw
+);
+socket_option!(
+    /// Message sent to every new peer.
+    HelloMsg, ZMQ_HELLO_MSG, Vec<u8>, w
+);
+socket_option!(
+    /// Message received when a peer disconnects.
+    DisconnectMsg, ZMQ_DISCONNECT_MSG, Vec<u8>, w
+);
+socket_option!(
+    /// Socket priority for outgoing packets.
+    Priority, ZMQ_PRIORITY, i32, rw
+);
+socket_option!(
+    /// Message received when the connection to a peer is interrupted.
+    HiccupMsg, ZMQ_HICCUP_MSG, Vec<u8>, w
+);
+socket_option!(
+    /// Pass every unsubscription upstream, not just the last one.
+    XsubVerboseUnsubscribe, ZMQ_XSUB_VERBOSE_UNSUBSCRIBE, bool, w
+);
+socket_option!(
+    /// Number of subscriptions.
+    TopicsCount, ZMQ_TOPICS_COUNT, i32, r
+);
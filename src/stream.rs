@@ -1,131 +1,249 @@
+use crate::constants::{ZMQ_CONNECT_ROUTING_ID, ZMQ_EAGAIN, ZMQ_STREAM, ZMQ_STREAM_NOTIFY};
+use crate::context::Context;
+use crate::fair_queue::FairQueue;
+use crate::message::{Message, MsgFlags};
+use crate::options::bool_strict;
+use crate::pipe::Pipe;
+use crate::random::generate_random;
+use crate::socket_base::{SocketBase, SocketBehavior};
 use std::collections::HashMap;
+use std::sync::Arc;
 
-// Forward declarations
-struct Pipe;
-struct Ctx;
-struct Msg;
-struct FQ;
-struct Blob;
-struct Metadata;
-
-#[derive(Default)]
-struct Options {
-    typ: i32,
-    raw_socket: bool,
-    raw_notify: bool,
-    routing_id: Vec<u8>,
-    routing_id_size: u8,
-}
+/// STREAM socket: talks raw bytes to non-ZMQ peers, prefixing each chunk
+/// with the routing id of the connection.
+pub struct Stream {
+    base: SocketBase,
+
+    // Fair queueing object for inbound pipes.
+    fq: FairQueue,
 
-struct Stream {
-    // Base class members would be included here
-    fq: FQ,
+    // True iff there is a message held in the pre-fetch buffer.
     prefetched: bool,
+
+    // If true, the receiver got the message part with
+    // the peer's routing id.
     routing_id_sent: bool,
-    prefetched_routing_id: Msg,
-    prefetched_msg: Msg,
-    current_out: Option<Box<Pipe>>,
+
+    // Holds the prefetched routing id.
+    prefetched_routing_id: Message,
+
+    // Holds the prefetched message.
+    prefetched_msg: Message,
+
+    // Outbound pipes indexed by the peer IDs.
+    out_pipes: HashMap<Vec<u8>, Pipe>,
+
+    // The pipe we are currently writing to.
+    current_out: Option<Pipe>,
+
+    // If true, more outgoing message parts are expected.
     more_out: bool,
+
+    // Routing IDs are generated. It's a simple increment and wrap-over
+    // algorithm. This value is the next ID to use (if not used already).
     next_integral_routing_id: u32,
-    options: Options,
+
+    // Routing id for the next outgoing connection, set with
+    // ZMQ_CONNECT_ROUTING_ID.
+    connect_routing_id: Vec<u8>,
 }
 
 impl Stream {
-    pub fn new(parent: &mut Ctx, tid: u32, sid: i32) -> Self {
-        let mut stream = Stream {
-            fq: FQ::default(),
+    pub fn new(ctx: &Arc<Context>, tid: u32, sid: i32) -> Self {
+        let mut base = SocketBase::new(ctx, tid, sid, false);
+        base.options.socket_type = ZMQ_STREAM;
+        base.options.raw_socket = true;
+
+        Stream {
+            base,
+            fq: FairQueue::new(),
             prefetched: false,
             routing_id_sent: false,
-            prefetched_routing_id: Msg::new(),
-            prefetched_msg: Msg::new(),
+            prefetched_routing_id: Message::new(),
+            prefetched_msg: Message::new(),
+            out_pipes: HashMap::new(),
             current_out: None,
             more_out: false,
             next_integral_routing_id: generate_random(),
-            options: Options::default(),
+            connect_routing_id: Vec::new(),
+        }
+    }
+
+    fn identify_peer(&mut self, pipe: &Pipe, locally_initiated: bool) {
+        let routing_id = if locally_initiated && !self.connect_routing_id.is_empty() {
+            let routing_id = std::mem::take(&mut self.connect_routing_id);
+
+            // Not allowed to duplicate an existing rid
+            debug_assert!(!self.out_pipes.contains_key(&routing_id));
+            routing_id
+        } else {
+            // Always assign routing id for raw-socket
+            let mut buffer = vec![0u8; 5];
+            buffer[1..].copy_from_slice(&self.next_integral_routing_id.to_be_bytes());
+            self.next_integral_routing_id = self.next_integral_routing_id.wrapping_add(1);
+            buffer
         };
-        
-        stream.options.typ = 1; // ZMQ_STREAM
-        stream.options.raw_socket = true;
-        
-        stream
+
+        pipe.set_router_socket_routing_id(&routing_id);
+        self.out_pipes.insert(routing_id, pipe.clone());
+    }
+
+    fn routing_id_frame(pipe: &Pipe) -> Result<Message, i32> {
+        let mut msg = Message::with_data(&pipe.get_routing_id()).map_err(|_| libc::ENOMEM)?;
+        msg.set_flags(MsgFlags::More);
+        Ok(msg)
+    }
+}
+
+impl SocketBehavior for Stream {
+    fn base(&self) -> &SocketBase {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut SocketBase {
+        &mut self.base
     }
 
-    pub fn attach_pipe(&mut self, pipe: Box<Pipe>, subscribe_to_all: bool, locally_initiated: bool) {
+    fn xattach_pipe(&mut self, pipe: Pipe, _subscribe_to_all: bool, locally_initiated: bool) {
         self.identify_peer(&pipe, locally_initiated);
         self.fq.attach(pipe);
     }
 
-    pub fn send(&mut self, msg: &mut Msg) -> Result<(), i32> {
+    fn xpipe_terminated(&mut self, pipe: &Pipe) {
+        self.out_pipes.remove(&pipe.get_routing_id());
+        self.fq.pipe_terminated(pipe);
+        if self.current_out.as_ref() == Some(pipe) {
+            self.current_out = None;
+        }
+    }
+
+    fn xread_activated(&mut self, pipe: &Pipe) {
+        self.fq.activated(pipe);
+    }
+
+    fn xsend(&mut self, msg: &mut Message) -> Result<(), i32> {
+        // If this is the first part of the message it's the ID of the
+        // peer to send the message to.
         if !self.more_out {
+            debug_assert!(self.current_out.is_none());
+
+            // If we have malformed message (prefix with no subsequent message)
+            // then just silently ignore it.
             if msg.has_more() {
-                // Find pipe associated with routing id
-                if let Some(out_pipe) = self.lookup_out_pipe(&msg.as_blob()) {
-                    self.current_out = Some(out_pipe);
-                    if !self.current_out.as_ref().unwrap().check_write() {
-                        self.current_out = None;
-                        return Err(libc::EAGAIN);
+                // Find the pipe associated with the routing id stored in the prefix.
+                // If there's no such pipe return an error
+                match self.out_pipes.get(msg.data()) {
+                    Some(pipe) => {
+                        if !pipe.check_write() {
+                            return Err(ZMQ_EAGAIN);
+                        }
+                        self.current_out = Some(pipe.clone());
                     }
-                } else {
-                    return Err(libc::EHOSTUNREACH);
+                    None => return Err(libc::EHOSTUNREACH),
                 }
             }
-            
+
+            // Expect one more message frame.
             self.more_out = true;
-            msg.close();
-            msg.init();
+
+            *msg = Message::new();
             return Ok(());
         }
 
-        msg.reset_flags();
+        // Ignore the MORE flag
+        msg.reset_flags(MsgFlags::More);
+
+        // This is the last part of the message.
         self.more_out = false;
 
-        if let Some(current_out) = &mut self.current_out {
-            if msg.size() == 0 {
-                current_out.terminate(false);
-                msg.close();
-                msg.init();
-                self.current_out = None;
-                return Ok(());
+        // Push the message into the pipe. If there's no out pipe, just drop it.
+        match self.current_out.take() {
+            // Close the remote connection if user has asked to do so
+            // by sending zero length message.
+            // Pending messages in the pipe will be dropped (on receiving term-ack)
+            Some(pipe) if msg.size() == 0 => {
+                pipe.terminate(false);
+                *msg = Message::new();
             }
+            Some(pipe) => {
+                if pipe.write(msg) {
+                    pipe.flush();
+                } else {
+                    *msg = Message::new();
+                }
+            }
+            None => *msg = Message::new(),
+        }
 
-            if current_out.write(msg) {
-                current_out.flush();
+        Ok(())
+    }
+
+    fn xsetsockopt(&mut self, option: i32, optval: &[u8]) -> Result<(), i32> {
+        match option {
+            ZMQ_CONNECT_ROUTING_ID => {
+                if optval.is_empty() {
+                    return Err(libc::EINVAL);
+                }
+                self.connect_routing_id = optval.to_vec();
             }
-            self.current_out = None;
-        } else {
-            msg.close();
+            ZMQ_STREAM_NOTIFY => self.base.options.raw_notify = bool_strict(optval)?,
+            _ => return Err(libc::EINVAL),
+        }
+        Ok(())
+    }
+
+    fn xrecv(&mut self, msg: &mut Message) -> Result<(), i32> {
+        if self.prefetched {
+            if !self.routing_id_sent {
+                *msg = std::mem::take(&mut self.prefetched_routing_id);
+                self.routing_id_sent = true;
+            } else {
+                *msg = std::mem::take(&mut self.prefetched_msg);
+                self.prefetched = false;
+            }
+            return Ok(());
         }
 
-        msg.init();
+        let pipe = self.fq.recvpipe(&mut self.prefetched_msg)?;
+
+        // We have received a frame with TCP data.
+        // Rather than sending this frame, we keep it in prefetched
+        // buffer and send a frame with peer's ID.
+        *msg = Self::routing_id_frame(&pipe)?;
+
+        self.prefetched = true;
+        self.routing_id_sent = true;
+
         Ok(())
     }
 
-    // Other method implementations would follow...
-    
-    fn identify_peer(&mut self, pipe: &Box<Pipe>, locally_initiated: bool) {
-        let mut buffer = vec![0u8; 5];
-        buffer[0] = 0;
+    fn xhas_in(&mut self) -> bool {
+        // We may already have a message pre-fetched.
+        if self.prefetched {
+            return true;
+        }
+
+        // Try to read the next message.
+        // The message, if read, is kept in the pre-fetch buffer.
+        let pipe = match self.fq.recvpipe(&mut self.prefetched_msg) {
+            Ok(pipe) => pipe,
+            Err(_) => return false,
+        };
 
-        let routing_id = if locally_initiated && self.connect_routing_id_is_set() {
-            let connect_id = self.extract_connect_routing_id();
-            Blob::from(connect_id.as_bytes())
-        } else {
-            write_u32(&mut buffer[1..], self.next_integral_routing_id);
-            self.next_integral_routing_id += 1;
-            Blob::from(&buffer[..])
+        self.prefetched_routing_id = match Self::routing_id_frame(&pipe) {
+            Ok(msg) => msg,
+            Err(_) => return false,
         };
+        self.prefetched = true;
+        self.routing_id_sent = false;
 
-        pipe.set_router_socket_routing_id(&routing_id);
-        self.add_out_pipe(routing_id, pipe);
+        true
     }
-}
-
-// Helper functions
-fn generate_random() -> u32 {
-    // Implementation needed
-    0
-}
 
-fn write_u32(buf: &mut [u8], val: u32) {
-    buf.copy_from_slice(&val.to_be_bytes());
+    fn xhas_out(&mut self) -> bool {
+        // In theory, STREAM socket is always ready for writing. Whether actual
+        // attempt to write succeeds depends on which pipe the message is going
+        // to be routed to.
+        true
+    }
 }
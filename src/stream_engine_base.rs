@@ -1,230 +1,976 @@
+//! Common part of the engines running a protocol over a byte stream.
+//!
+//! `StreamEngineBase` holds the state shared by the stream engines: the
+//! socket, the input and output buffers, the encoder, the decoder and the
+//! security mechanism. The message flow in libzmq is driven by two member
+//! function pointers, `_next_msg` and `_process_msg`, which the handshake
+//! swaps as it progresses; here they are the `NextMsg` and `ProcessMsg`
+//! states. The I/O logic is provided by the default methods of the
+//! `StreamEngine` trait, which the engines implement to plug in their
+//! handshake.
+
 use std::collections::HashMap;
-#[cfg(unix)]
+use std::mem;
 use std::os::unix::io::RawFd;
-use std::time::Duration;
-
-use crate::types::ZmqRawFd;
-
-// Constants
-const HANDSHAKE_TIMER_ID: i32 = 0x40;
-const HEARTBEAT_IVL_TIMER_ID: i32 = 0x80;
-const HEARTBEAT_TIMEOUT_TIMER_ID: i32 = 0x81;
-const HEARTBEAT_TTL_TIMER_ID: i32 = 0x82;
-
-// Error reasons
-#[derive(Debug)]
-pub enum ErrorReason {
-    ConnectionError,
-    ProtocolError,
-    TimeoutError,
+use std::sync::Arc;
+
+use crate::constants::ZMQ_EPROTO;
+use crate::endpoint::EndpointUriPair;
+use crate::i_decoder::IDecoder;
+use crate::i_encoder::IEncoder;
+use crate::i_engine::{ErrorReason, IEngine};
+use crate::mechanism::{MechanismOps, Status};
+use crate::message::{Message, MsgFlags};
+use crate::metadata::Metadata;
+use crate::options::Options;
+use crate::poller_base::{Handle, IoContext};
+use crate::session_base::SessionBase;
+use crate::tcp::{get_socket_address, tcp_read, tcp_write, unblock_socket};
+use crate::zmq_draft::{ZMQ_MSG_PROPERTY_PEER_ADDRESS, ZMQ_NOTIFY_CONNECT, ZMQ_NOTIFY_DISCONNECT};
+
+pub(crate) const HANDSHAKE_TIMER_ID: i32 = 0x40;
+pub(crate) const HEARTBEAT_IVL_TIMER_ID: i32 = 0x80;
+pub(crate) const HEARTBEAT_TIMEOUT_TIMER_ID: i32 = 0x81;
+pub(crate) const HEARTBEAT_TTL_TIMER_ID: i32 = 0x82;
+
+/// Where the next message to send comes from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum NextMsg {
+    // Our routing id, the first message of ZMTP/1.0 and ZMTP/2.0.
+    RoutingId,
+    // Messages from the session as they are, for peers without a
+    // security mechanism.
+    PullMsgFromSession,
+    // Handshake commands of the security mechanism.
+    HandshakeCommand,
+    // Messages from the session, passed through the mechanism.
+    PullAndEncode,
+    // A heartbeat PING, then back to the session's messages.
+    ProducePingMessage,
+    // The PONG answering the peer's PING, then back to the session's
+    // messages.
+    ProducePongMessage,
 }
 
-// Properties type alias
-type Properties = HashMap<String, String>;
+/// What happens to the next message received.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ProcessMsg {
+    // The routing id of a ZMTP/1.0 or ZMTP/2.0 peer.
+    RoutingId,
+    // Messages for the session as they are, for peers without a security
+    // mechanism.
+    PushMsgToSession,
+    // Handshake commands, handed to the security mechanism.
+    HandshakeCommand,
+    // The first message after the handshake; the credential of the peer
+    // goes ahead of it.
+    WriteCredential,
+    // Messages for the session, passed through the mechanism.
+    DecodeAndPush,
+    // A message the session couldn't take yet; retried before decoding
+    // anything else.
+    PushOneThenDecodeAndPush,
+    // Messages of raw peers, for the session as they are but with the
+    // metadata of the connection.
+    PushRawMsgToSession,
+}
 
-// Main structure representing stream_engine_base
 pub struct StreamEngineBase {
-    options: Options,
-    inpos: Option<*mut u8>,
+    pub(crate) options: Options,
+
+    // Data received and not yet decoded.
+    inbuf: Vec<u8>,
+    inpos: usize,
     insize: usize,
-    decoder: Option<Box<dyn Decoder>>,
-    outpos: Option<*mut u8>,
-    outsize: usize,
-    encoder: Option<Box<dyn Encoder>>,
-    mechanism: Option<Box<dyn Mechanism>>,
-    metadata: Option<Box<Metadata>>,
+    pub(crate) decoder: Option<Box<dyn IDecoder>>,
+
+    // Data to send; everything before `outpos` was written already.
+    pub(crate) outbuf: Vec<u8>,
+    pub(crate) outpos: usize,
+    pub(crate) encoder: Option<Box<dyn IEncoder>>,
+
+    pub(crate) mechanism: Option<Box<dyn MechanismOps>>,
+
+    pub(crate) next_msg: NextMsg,
+    pub(crate) process_msg: ProcessMsg,
+
+    // True iff a phantom subscription has to be injected for a ZMTP/1.0
+    // subscriber, which doesn't forward its subscriptions.
+    pub(crate) subscription_required: bool,
+
+    // Metadata to be attached to received messages. None if there is no
+    // metadata.
+    pub(crate) metadata: Option<Arc<Metadata>>,
+
+    // True iff the engine couldn't consume the last decoded message.
     input_stopped: bool,
-    output_stopped: bool,
+
+    // True iff the engine doesn't have any message to encode.
+    pub(crate) output_stopped: bool,
+
+    // Representation of the connected endpoints.
     endpoint_uri_pair: EndpointUriPair,
+
+    // True iff the handshake timer is running.
     has_handshake_timer: bool,
-    has_ttl_timer: bool,
-    has_timeout_timer: bool,
-    has_heartbeat_timer: bool,
-    peer_address: String,
-    socket: ZmqRawFd,
+
+    // Heartbeat stuff
+    pub(crate) has_heartbeat_timer: bool,
+    pub(crate) has_ttl_timer: bool,
+    pub(crate) has_timeout_timer: bool,
+
+    pub(crate) peer_address: String,
+
+    // Underlying socket.
+    pub(crate) fd: RawFd,
+    pub(crate) handle: Option<Handle>,
+
+    // True iff we are registered with an I/O poller.
     plugged: bool,
-    handshaking: bool,
+
+    // When true, we are still trying to determine whether
+    // the peer is using versioned protocol, and if so, which
+    // version. When false, normal message flow has started.
+    pub(crate) handshaking: bool,
+
+    // True once reading from the socket failed while the input was
+    // stopped; the error is reported when the input is restarted.
     io_error: bool,
-    session: Option<Box<dyn SessionBase>>,
+
     has_handshake_stage: bool,
 }
 
-// Implementation of main functionality
 impl StreamEngineBase {
     pub fn new(
-        fd: ZmqRawFd,
-        options: Options,
+        fd: RawFd,
+        options: &Options,
         endpoint_uri_pair: EndpointUriPair,
         has_handshake_stage: bool,
     ) -> Self {
+        // Put the socket into non-blocking mode.
+        let _ = unblock_socket(fd);
+
+        let in_batch_size = options.in_batch_size.max(1) as usize;
         StreamEngineBase {
-            options,
-            inpos: None,
+            options: options.clone(),
+            inbuf: vec![0; in_batch_size],
+            inpos: 0,
             insize: 0,
             decoder: None,
-            outpos: None,
-            outsize: 0,
+            outbuf: Vec::new(),
+            outpos: 0,
             encoder: None,
             mechanism: None,
+            next_msg: NextMsg::HandshakeCommand,
+            process_msg: ProcessMsg::HandshakeCommand,
+            subscription_required: false,
             metadata: None,
             input_stopped: false,
             output_stopped: false,
             endpoint_uri_pair,
             has_handshake_timer: false,
+            has_heartbeat_timer: false,
             has_ttl_timer: false,
             has_timeout_timer: false,
-            has_heartbeat_timer: false,
-            peer_address: get_peer_address(fd),
-            socket: fd,
+            peer_address: get_socket_address(fd, false)
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_default(),
+            fd,
+            handle: None,
             plugged: false,
             handshaking: true,
             io_error: false,
-            session: None,
             has_handshake_stage,
         }
     }
 
-    pub fn plug(&mut self, io_thread: &mut dyn IoThread, session: Box<dyn SessionBase>) {
-        assert!(!self.plugged);
+    pub(crate) fn get_endpoint(&self) -> &EndpointUriPair {
+        &self.endpoint_uri_pair
+    }
+
+    pub(crate) fn set_pollin(&mut self, io: &mut IoContext) {
+        if let Some(handle) = self.handle {
+            io.set_pollin(handle);
+        }
+    }
+
+    pub(crate) fn reset_pollin(&mut self, io: &mut IoContext) {
+        if let Some(handle) = self.handle {
+            io.reset_pollin(handle);
+        }
+    }
+
+    pub(crate) fn set_pollout(&mut self, io: &mut IoContext) {
+        if let Some(handle) = self.handle {
+            io.set_pollout(handle);
+        }
+    }
+
+    pub(crate) fn reset_pollout(&mut self, io: &mut IoContext) {
+        if let Some(handle) = self.handle {
+            io.reset_pollout(handle);
+        }
+    }
+
+    /// Makes the decoder start from data that was read already, e.g. the
+    /// part of the greeting that turned out to be a ZMTP/1.0 message.
+    pub(crate) fn set_input(&mut self, data: &[u8]) {
+        if self.inbuf.len() < data.len() {
+            self.inbuf.resize(data.len(), 0);
+        }
+        self.inbuf[..data.len()].copy_from_slice(data);
+        self.inpos = 0;
+        self.insize = data.len();
+    }
+
+    // Number of bytes queued for sending and not written yet.
+    pub(crate) fn outsize(&self) -> usize {
+        self.outbuf.len() - self.outpos
+    }
+
+    /// Reads from the socket. A connection closed by the peer is reported
+    /// as EPIPE.
+    pub(crate) fn read(&mut self, buf: &mut [u8]) -> Result<usize, i32> {
+        match tcp_read(self.fd, buf) {
+            // connection closed by peer
+            Ok(0) => Err(libc::EPIPE),
+            rc => rc,
+        }
+    }
+
+    pub(crate) fn set_handshake_timer(&mut self, io: &mut IoContext) {
+        debug_assert!(!self.has_handshake_timer);
+
+        if self.options.handshake_intvl > 0 {
+            io.add_timer(self.options.handshake_intvl, HANDSHAKE_TIMER_ID);
+            self.has_handshake_timer = true;
+        }
+    }
+
+    fn cancel_handshake_timer(&mut self, io: &mut IoContext) {
+        if self.has_handshake_timer {
+            io.cancel_timer(HANDSHAKE_TIMER_ID);
+            self.has_handshake_timer = false;
+        }
+    }
+
+    pub(crate) fn plug(&mut self, io: &mut IoContext) {
+        debug_assert!(!self.plugged);
         self.plugged = true;
-        self.session = Some(session);
-        self.plug_internal();
+
+        // Connect to I/O threads poller object.
+        self.handle = Some(io.add_fd(self.fd));
+        self.io_error = false;
     }
 
-    pub fn terminate(&mut self) {
-        self.unplug();
+    pub(crate) fn unplug(&mut self, io: &mut IoContext) {
+        if !self.plugged {
+            return;
+        }
+        self.plugged = false;
+
+        // Cancel all timers.
+        self.cancel_handshake_timer(io);
+        if self.has_heartbeat_timer {
+            io.cancel_timer(HEARTBEAT_IVL_TIMER_ID);
+            self.has_heartbeat_timer = false;
+        }
+        self.cancel_heartbeat_timeouts(io);
+
+        // Cancel all fd subscriptions.
+        if let Some(handle) = self.handle.take() {
+            io.rm_fd(handle);
+        }
     }
 
-    pub fn in_event(&mut self) -> bool {
-        self.in_event_internal()
+    /// Function to handle network disconnections. The session drops the
+    /// engine once the current callback returns.
+    pub(crate) fn error(
+        &mut self,
+        reason: ErrorReason,
+        session: &mut SessionBase,
+        io: &mut IoContext,
+    ) {
+        if self.options.router_notify & ZMQ_NOTIFY_DISCONNECT != 0 && !self.handshaking {
+            // For router sockets with disconnect notification, rollback
+            // any incomplete message in the pipe, and push the disconnect
+            // notification message.
+            session.rollback();
+
+            let mut disconnect_notification = Message::new();
+            let _ = session.push_msg(&mut disconnect_notification);
+        }
+
+        if self.options.raw_socket && self.options.raw_notify {
+            // For raw sockets, send a final 0-length message to the
+            // application so that it knows the peer has been disconnected.
+            let mut terminator = Message::new();
+            let _ = self.push_raw_msg_to_session(&mut terminator, session);
+        }
+
+        let handshaked = !self.handshaking
+            && self
+                .mechanism
+                .as_ref()
+                .map_or(true, |mechanism| mechanism.status() != Status::Handshaking);
+
+        session.flush();
+        session.engine_error(handshaked, reason);
+        self.unplug(io);
     }
 
-    fn in_event_internal(&mut self) -> bool {
-        assert!(!self.io_error);
+    pub(crate) fn mechanism_ready(&mut self, session: &mut SessionBase, io: &mut IoContext) {
+        if self.has_handshake_stage {
+            session.engine_ready();
+        }
 
-        if self.handshaking {
-            if self.handshake() {
-                self.handshaking = false;
+        let mechanism = self.mechanism.as_ref().expect("mechanism is set");
 
-                if self.mechanism.is_none() && self.has_handshake_stage {
-                    if let Some(session) = &mut self.session {
-                        session.engine_ready();
-                    }
+        if self.options.recv_routing_id {
+            let mut routing_id = mechanism.base().peer_routing_id();
+            if session.push_msg(&mut routing_id).is_err() {
+                // If the write is failing at this stage with
+                // an EAGAIN the pipe must be being shut down,
+                // so we can just bail out of the routing id set.
+                return;
+            }
+            session.flush();
+        }
 
-                    if self.has_handshake_timer {
-                        self.cancel_timer(HANDSHAKE_TIMER_ID);
-                        self.has_handshake_timer = false;
-                    }
-                }
-            } else {
-                return false;
+        if self.options.router_notify & ZMQ_NOTIFY_CONNECT != 0 {
+            let mut connect_notification = Message::new();
+            if session.push_msg(&mut connect_notification).is_err() {
+                // If the write is failing at this stage with
+                // an EAGAIN the pipe must be being shut down,
+                // so we can just bail out of the notification.
+                return;
             }
+            session.flush();
         }
 
-        // Rest of in_event_internal implementation...
-        true
+        self.next_msg = NextMsg::PullAndEncode;
+        self.process_msg = ProcessMsg::WriteCredential;
+
+        // Compile metadata.
+        let mut properties = HashMap::new();
+        self.init_properties(&mut properties);
+
+        // Add ZAP properties.
+        let base = mechanism.base();
+        properties.extend(
+            base.get_zap_properties()
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone())),
+        );
+
+        // Add ZMTP properties.
+        properties.extend(
+            base.get_zmtp_properties()
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone())),
+        );
+
+        debug_assert!(self.metadata.is_none());
+        if !properties.is_empty() {
+            self.metadata = Some(Arc::new(Metadata::new(properties)));
+        }
+
+        if self.options.heartbeat_intvl > 0 && !self.has_heartbeat_timer {
+            io.add_timer(self.options.heartbeat_intvl, HEARTBEAT_IVL_TIMER_ID);
+            self.has_heartbeat_timer = true;
+        }
+
+        self.cancel_handshake_timer(io);
     }
 
-    fn handshake(&mut self) -> bool {
-        // Handshake implementation
+    pub(crate) fn init_properties(&self, properties: &mut HashMap<String, String>) -> bool {
+        if self.peer_address.is_empty() {
+            return false;
+        }
+        properties.insert(
+            ZMQ_MSG_PROPERTY_PEER_ADDRESS.to_string(),
+            self.peer_address.clone(),
+        );
+
+        // Private property to support deprecated SRCFD
+        properties.insert("__fd".to_string(), self.fd.to_string());
         true
     }
 
-    fn error(&mut self, reason: ErrorReason) {
-        if let Some(session) = &mut self.session {
-            session.engine_error(!self.handshaking, reason);
+    pub(crate) fn routing_id_msg(&mut self, msg: &mut Message) -> Result<(), i32> {
+        let size = self.options.routing_id_size as usize;
+        *msg = Message::with_data(&self.options.routing_id[..size]).map_err(|_| libc::ENOMEM)?;
+        self.next_msg = NextMsg::PullMsgFromSession;
+        Ok(())
+    }
+
+    pub(crate) fn process_routing_id_msg(
+        &mut self,
+        msg: &mut Message,
+        session: &mut SessionBase,
+    ) -> Result<(), i32> {
+        if self.options.recv_routing_id {
+            msg.set_flags(MsgFlags::RoutingId);
+            session.push_msg(msg)?;
+        } else {
+            *msg = Message::new();
+        }
+
+        if self.subscription_required {
+            // Inject the subscription message, so that also
+            // ZMQ 2.x peers receive published messages.
+            let mut subscription = Message::with_data(&[1]).map_err(|_| libc::ENOMEM)?;
+            session.push_msg(&mut subscription)?;
         }
-        self.unplug();
+
+        self.process_msg = ProcessMsg::PushMsgToSession;
+        Ok(())
     }
 
-    fn unplug(&mut self) {
-        assert!(self.plugged);
-        self.plugged = false;
+    // Pushes the credential of the peer ahead of the first message; the
+    // message itself goes through decode_and_push.
+    fn write_credential(&mut self, session: &mut SessionBase) -> Result<(), i32> {
+        let mechanism = self.mechanism.as_ref().expect("mechanism is set");
+        let credential = mechanism.base().get_user_id();
+        if !credential.is_empty() {
+            let mut cred = Message::with_data(credential).map_err(|_| libc::ENOMEM)?;
+            cred.set_flags(MsgFlags::Credential);
+            session.push_msg(&mut cred)?;
+        }
+        self.process_msg = ProcessMsg::DecodeAndPush;
+        Ok(())
+    }
 
-        // Cancel all timers
-        if self.has_handshake_timer {
-            self.cancel_timer(HANDSHAKE_TIMER_ID);
-            self.has_handshake_timer = false;
+    pub(crate) fn pull_and_encode(
+        &mut self,
+        msg: &mut Message,
+        session: &mut SessionBase,
+    ) -> Result<(), i32> {
+        let mechanism = self.mechanism.as_mut().expect("mechanism is set");
+        session.pull_msg(msg)?;
+        mechanism.encode(msg)
+    }
+
+    // Any traffic from the peer proves it is alive.
+    fn cancel_heartbeat_timeouts(&mut self, io: &mut IoContext) {
+        if self.has_timeout_timer {
+            self.has_timeout_timer = false;
+            io.cancel_timer(HEARTBEAT_TIMEOUT_TIMER_ID);
+        }
+
+        if self.has_ttl_timer {
+            self.has_ttl_timer = false;
+            io.cancel_timer(HEARTBEAT_TTL_TIMER_ID);
+        }
+    }
+
+    // Hands a decoded message to the session.
+    fn push_decoded(&mut self, msg: &mut Message, session: &mut SessionBase) -> Result<(), i32> {
+        if let Some(metadata) = &self.metadata {
+            msg.set_metadata(Arc::clone(metadata));
+        }
+        if let Err(e) = session.push_msg(msg) {
+            if e == libc::EAGAIN {
+                self.process_msg = ProcessMsg::PushOneThenDecodeAndPush;
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn push_raw_msg_to_session(
+        &mut self,
+        msg: &mut Message,
+        session: &mut SessionBase,
+    ) -> Result<(), i32> {
+        if let Some(metadata) = &self.metadata {
+            msg.set_metadata(Arc::clone(metadata));
         }
+        session.push_msg(msg)
+    }
 
-        // Rest of unplug implementation...
+    fn push_one_then_decode_and_push(
+        &mut self,
+        msg: &mut Message,
+        session: &mut SessionBase,
+    ) -> Result<(), i32> {
+        session.push_msg(msg)?;
+        self.process_msg = ProcessMsg::DecodeAndPush;
+        Ok(())
     }
+}
 
-    fn cancel_timer(&mut self, timer_id: i32) {
-        // Timer cancellation implementation
+impl Drop for StreamEngineBase {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
     }
 }
 
-// Required trait implementations for StreamEngineBase
-impl IoObject for StreamEngineBase {
-    fn handle_io_event(&mut self, event_type: IoEventType) {
-        match event_type {
-            IoEventType::In => {
-                self.in_event();
+/// An engine driving a protocol over a byte stream. The default methods
+/// implement the I/O; the engine provides the protocol's handshake.
+pub trait StreamEngine: Send {
+    fn base(&self) -> &StreamEngineBase;
+    fn base_mut(&mut self) -> &mut StreamEngineBase;
+
+    /// Called once the engine is registered with the poller.
+    fn plug_internal(&mut self, session: &mut SessionBase, io: &mut IoContext);
+
+    /// Receives the greeting and sets the protocol up. Returns true once
+    /// the handshake stage is over; failures are reported through
+    /// `StreamEngineBase::error`.
+    fn handshake(&mut self, session: &mut SessionBase, io: &mut IoContext) -> bool;
+
+    fn next_handshake_command(
+        &mut self,
+        msg: &mut Message,
+        session: &mut SessionBase,
+        io: &mut IoContext,
+    ) -> Result<(), i32> {
+        let base = self.base_mut();
+        let status = base.mechanism.as_ref().expect("mechanism is set").status();
+
+        if status == Status::Ready {
+            base.mechanism_ready(session, io);
+            return base.pull_and_encode(msg, session);
+        }
+        if status == Status::Error {
+            return Err(ZMQ_EPROTO);
+        }
+
+        let mechanism = base.mechanism.as_mut().expect("mechanism is set");
+        mechanism.next_handshake_command(session, msg)?;
+        msg.set_flags(MsgFlags::Command);
+        Ok(())
+    }
+
+    fn process_handshake_command(
+        &mut self,
+        msg: &mut Message,
+        session: &mut SessionBase,
+        io: &mut IoContext,
+    ) -> Result<(), i32> {
+        let base = self.base_mut();
+        let mechanism = base.mechanism.as_mut().expect("mechanism is set");
+        mechanism.process_handshake_command(session, msg)?;
+
+        match mechanism.status() {
+            Status::Ready => base.mechanism_ready(session, io),
+            Status::Error => return Err(ZMQ_EPROTO),
+            Status::Handshaking => {}
+        }
+        if base.output_stopped {
+            self.restart_output_internal(session, io);
+        }
+        Ok(())
+    }
+
+    /// Handles a command received after the handshake, such as a
+    /// heartbeat. The command is passed on to the session afterwards.
+    fn process_command_message(
+        &mut self,
+        _msg: &mut Message,
+        _session: &mut SessionBase,
+        _io: &mut IoContext,
+    ) -> Result<(), i32> {
+        Ok(())
+    }
+
+    /// Produces a heartbeat PING; only engines supporting heartbeats
+    /// switch to it.
+    fn produce_ping_message(&mut self, _msg: &mut Message, _io: &mut IoContext) -> Result<(), i32> {
+        Err(libc::ENOTSUP)
+    }
+
+    /// Produces the PONG answering a PING of the peer.
+    fn produce_pong_message(&mut self, _msg: &mut Message) -> Result<(), i32> {
+        Err(libc::ENOTSUP)
+    }
+
+    fn decode_and_push(
+        &mut self,
+        msg: &mut Message,
+        session: &mut SessionBase,
+        io: &mut IoContext,
+    ) -> Result<(), i32> {
+        let base = self.base_mut();
+        let mechanism = base.mechanism.as_mut().expect("mechanism is set");
+        mechanism.decode(msg)?;
+        base.cancel_heartbeat_timeouts(io);
+
+        if msg.is_command() {
+            let _ = self.process_command_message(msg, session, io);
+        }
+
+        self.base_mut().push_decoded(msg, session)
+    }
+
+    /// Produces the next message to send.
+    fn next_msg(
+        &mut self,
+        msg: &mut Message,
+        session: &mut SessionBase,
+        io: &mut IoContext,
+    ) -> Result<(), i32> {
+        match self.base().next_msg {
+            NextMsg::RoutingId => self.base_mut().routing_id_msg(msg),
+            NextMsg::PullMsgFromSession => session.pull_msg(msg),
+            NextMsg::HandshakeCommand => self.next_handshake_command(msg, session, io),
+            NextMsg::PullAndEncode => self.base_mut().pull_and_encode(msg, session),
+            NextMsg::ProducePingMessage => self.produce_ping_message(msg, io),
+            NextMsg::ProducePongMessage => self.produce_pong_message(msg),
+        }
+    }
+
+    /// Handles a message received.
+    fn process_msg(
+        &mut self,
+        msg: &mut Message,
+        session: &mut SessionBase,
+        io: &mut IoContext,
+    ) -> Result<(), i32> {
+        match self.base().process_msg {
+            ProcessMsg::RoutingId => self.base_mut().process_routing_id_msg(msg, session),
+            ProcessMsg::PushMsgToSession => session.push_msg(msg),
+            ProcessMsg::HandshakeCommand => self.process_handshake_command(msg, session, io),
+            ProcessMsg::WriteCredential => {
+                self.base_mut().write_credential(session)?;
+                self.decode_and_push(msg, session, io)
+            }
+            ProcessMsg::DecodeAndPush => self.decode_and_push(msg, session, io),
+            ProcessMsg::PushOneThenDecodeAndPush => {
+                self.base_mut().push_one_then_decode_and_push(msg, session)
             }
-            IoEventType::Out => {
-                self.out_event();
+            ProcessMsg::PushRawMsgToSession => {
+                self.base_mut().push_raw_msg_to_session(msg, session)
             }
         }
     }
-}
 
-// Supporting traits and structures
-pub trait IoObject {
-    fn handle_io_event(&mut self, event_type: IoEventType);
-}
+    // Runs the message the decoder holds through process_msg. The message
+    // stays with the decoder in case it has to be retried.
+    fn process_decoded(&mut self, session: &mut SessionBase, io: &mut IoContext) -> Result<(), i32> {
+        let decoder = self.base_mut().decoder.as_mut().expect("decoder is set");
+        let mut msg = mem::take(decoder.msg());
+        let rc = self.process_msg(&mut msg, session, io);
+        if rc.is_err() {
+            // The engine may have switched to a different decoder.
+            if let Some(decoder) = self.base_mut().decoder.as_mut() {
+                *decoder.msg() = msg;
+            }
+        }
+        rc
+    }
 
-pub enum IoEventType {
-    In,
-    Out,
-}
+    // Decodes the buffered input, handing complete messages on.
+    fn decode_input(&mut self, session: &mut SessionBase, io: &mut IoContext) -> Result<(), i32> {
+        while self.base().insize > 0 {
+            let base = self.base_mut();
+            let decoder = base.decoder.as_mut().expect("decoder is set");
+            let (processed, complete) =
+                decoder.decode(&base.inbuf[base.inpos..base.inpos + base.insize])?;
+            debug_assert!(processed <= base.insize);
+            base.inpos += processed;
+            base.insize -= processed;
+            if !complete {
+                break;
+            }
+            self.process_decoded(session, io)?;
+        }
+        Ok(())
+    }
 
-pub trait IoThread {
-    fn add_fd(&mut self, fd: ZmqRawFd);
-    fn rm_fd(&mut self, fd: ZmqRawFd);
-}
+    // Returns false if the engine failed.
+    fn in_event_internal(&mut self, session: &mut SessionBase, io: &mut IoContext) -> bool {
+        // If still handshaking, receive and process the greeting message.
+        if self.base().handshaking {
+            if !self.handshake(session, io) {
+                return false;
+            }
+            // Handshaking was successful.
+            // Switch into the normal message flow.
+            let base = self.base_mut();
+            base.handshaking = false;
+            if base.mechanism.is_none() && base.has_handshake_stage {
+                session.engine_ready();
+                base.cancel_handshake_timer(io);
+            }
+        }
 
-pub trait SessionBase {
-    fn engine_ready(&mut self);
-    fn engine_error(&mut self, handshaking: bool, reason: ErrorReason);
-}
+        let base = self.base_mut();
+        debug_assert!(base.decoder.is_some());
 
-pub trait Encoder {
-    fn encode(&mut self, data: &[u8]) -> usize;
-}
+        // If there has been an I/O error, stop polling.
+        if base.input_stopped {
+            if let Some(handle) = base.handle.take() {
+                io.rm_fd(handle);
+            }
+            base.io_error = true;
+            return true;
+        }
 
-pub trait Decoder {
-    fn decode(&mut self, data: &[u8]) -> Result<Vec<u8>, std::io::Error>;
-}
+        // If there's no data to process in the buffer...
+        if base.insize == 0 {
+            // Retrieve the buffer and read as much data as possible.
+            // Note that buffer can be arbitrarily large. However, we assume
+            // the underlying TCP layer has fixed buffer size and thus the
+            // number of bytes read will be always limited.
+            let mut inbuf = mem::take(&mut base.inbuf);
+            let rc = base.read(&mut inbuf);
+            base.inbuf = inbuf;
+            match rc {
+                Ok(n) => {
+                    // Adjust input size
+                    base.inpos = 0;
+                    base.insize = n;
+                }
+                Err(e) if e == libc::EAGAIN => return true,
+                Err(_) => {
+                    base.error(ErrorReason::ConnectionError, session, io);
+                    return false;
+                }
+            }
+        }
 
-pub trait Mechanism {
-    fn status(&self) -> MechanismStatus;
-}
+        // Tear down the connection if we have failed to decode input data
+        // or the session has rejected the message.
+        if let Err(e) = self.decode_input(session, io) {
+            let base = self.base_mut();
+            if e != libc::EAGAIN {
+                base.error(ErrorReason::ProtocolError, session, io);
+                return false;
+            }
+            base.input_stopped = true;
+            base.reset_pollin(io);
+        }
 
-#[derive(PartialEq)]
-pub enum MechanismStatus {
-    Ready,
-    Error,
-    Handshaking,
-}
+        session.flush();
+        true
+    }
 
-pub struct Options {
-    pub heartbeat_interval: Duration,
-    pub handshake_ivl: Duration,
-    // Add other options as needed
-}
+    fn out_event_internal(&mut self, session: &mut SessionBase, io: &mut IoContext) {
+        // If write buffer is empty, try to read new data from the encoder.
+        if self.base().outsize() == 0 {
+            // Even when we stop polling as soon as there is no
+            // data to send, the poller may invoke out_event one
+            // more time due to 'speculative write' optimisation.
+            let base = self.base_mut();
+            if base.encoder.is_none() {
+                debug_assert!(base.handshaking);
+                return;
+            }
 
-pub struct EndpointUriPair {
-    local: String,
-    remote: String,
-}
+            let batch_size = base.options.out_batch_size.max(1) as usize;
+            let mut outbuf = mem::take(&mut base.outbuf);
+            outbuf.clear();
+            base.outpos = 0;
+            base.encoder
+                .as_mut()
+                .unwrap()
+                .encode(&mut outbuf, batch_size);
+
+            while outbuf.len() < batch_size {
+                let mut msg = Message::new();
+                if self.next_msg(&mut msg, session, io).is_err() {
+                    break;
+                }
+                let encoder = self.base_mut().encoder.as_mut().expect("encoder is set");
+                encoder.load_msg(&mut msg);
+                let space = batch_size - outbuf.len();
+                let n = encoder.encode(&mut outbuf, space);
+                debug_assert!(n > 0);
+            }
+
+            let base = self.base_mut();
+            base.outbuf = outbuf;
 
-pub struct Metadata {
-    properties: Properties,
+            // If there is no data to send, stop polling for output.
+            if base.outbuf.is_empty() {
+                base.output_stopped = true;
+                base.reset_pollout(io);
+                return;
+            }
+        }
+
+        // If there are any data to write in write buffer, write as much as
+        // possible to the socket. Note that amount of data to write can be
+        // arbitrarily large. However, we assume that underlying TCP layer has
+        // limited transmission buffer and thus the actual number of bytes
+        // written should be reasonably modest.
+        let base = self.base_mut();
+        match tcp_write(base.fd, &base.outbuf[base.outpos..]) {
+            Ok(nbytes) => base.outpos += nbytes,
+            // IO error has occurred. We stop waiting for output events.
+            // The engine is not terminated until we detect input error;
+            // this is necessary to prevent losing incoming messages.
+            Err(_) => {
+                base.reset_pollout(io);
+                return;
+            }
+        }
+
+        // If we are still handshaking and there are no data
+        // to send, stop polling for output.
+        if base.handshaking && base.outsize() == 0 {
+            base.reset_pollout(io);
+        }
+    }
+
+    fn restart_output_internal(&mut self, session: &mut SessionBase, io: &mut IoContext) {
+        let base = self.base_mut();
+        if base.io_error || !base.plugged {
+            return;
+        }
+
+        if base.output_stopped {
+            base.set_pollout(io);
+            base.output_stopped = false;
+        }
+
+        // Speculative write: The assumption is that at the moment new message
+        // was sent by the user the socket is probably available for writing.
+        // Thus we try to write the data to socket avoiding polling for POLLOUT.
+        // Consequently, the latency should be better in request/reply scenarios.
+        self.out_event_internal(session, io);
+    }
+
+    fn restart_input_internal(&mut self, session: &mut SessionBase, io: &mut IoContext) -> bool {
+        if !self.base().input_stopped {
+            return true;
+        }
+
+        if let Err(e) = self.process_decoded(session, io) {
+            if e == libc::EAGAIN {
+                session.flush();
+                return true;
+            }
+            self.base_mut()
+                .error(ErrorReason::ProtocolError, session, io);
+            return false;
+        }
+
+        let rc = self.decode_input(session, io);
+        let base = self.base_mut();
+        match rc {
+            Err(e) if e == libc::EAGAIN => session.flush(),
+            _ if base.io_error => {
+                base.error(ErrorReason::ConnectionError, session, io);
+                return false;
+            }
+            Err(_) => {
+                base.error(ErrorReason::ProtocolError, session, io);
+                return false;
+            }
+            Ok(()) => {
+                base.input_stopped = false;
+                base.set_pollin(io);
+                session.flush();
+
+                // Speculative read.
+                if !self.in_event_internal(session, io) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    fn zap_msg_available_internal(&mut self, session: &mut SessionBase, io: &mut IoContext) {
+        let base = self.base_mut();
+        // The reply may be for a connection that is gone already.
+        let mechanism = match base.mechanism.as_mut() {
+            Some(mechanism) => mechanism,
+            None => return,
+        };
+        if mechanism.zap_msg_available(session).is_err() {
+            base.error(ErrorReason::ProtocolError, session, io);
+            return;
+        }
+        if base.input_stopped && !self.restart_input_internal(session, io) {
+            return;
+        }
+        if self.base().output_stopped {
+            self.restart_output_internal(session, io);
+        }
+
+        // A peer the handler turned away is dropped once the ERROR
+        // command, if any, is out.
+        let base = self.base_mut();
+        let status = base.mechanism.as_ref().expect("mechanism is set").status();
+        if status == Status::Error && base.outsize() == 0 {
+            base.error(ErrorReason::ProtocolError, session, io);
+        }
+    }
+
+    fn timer_event_internal(&mut self, id: i32, session: &mut SessionBase, io: &mut IoContext) {
+        let base = self.base_mut();
+        match id {
+            HANDSHAKE_TIMER_ID => {
+                base.has_handshake_timer = false;
+                // handshake timer expired before handshake completed, so engine fail
+                base.error(ErrorReason::TimeoutError, session, io);
+            }
+            HEARTBEAT_IVL_TIMER_ID => {
+                base.next_msg = NextMsg::ProducePingMessage;
+                self.out_event_internal(session, io);
+                let interval = self.base().options.heartbeat_intvl;
+                io.add_timer(interval, HEARTBEAT_IVL_TIMER_ID);
+            }
+            HEARTBEAT_TTL_TIMER_ID => {
+                base.has_ttl_timer = false;
+                base.error(ErrorReason::TimeoutError, session, io);
+            }
+            HEARTBEAT_TIMEOUT_TIMER_ID => {
+                base.has_timeout_timer = false;
+                base.error(ErrorReason::TimeoutError, session, io);
+            }
+            // There are no other valid timer ids!
+            _ => debug_assert!(false, "unexpected timer id {}", id),
+        }
+    }
 }
 
-// Helper functions
-fn get_peer_address(fd: ZmqRawFd) -> String {
-    // Implementation to get peer address from socket
-    String::new()
+impl<T: StreamEngine> IEngine for T {
+    fn has_handshake_stage(&self) -> bool {
+        self.base().has_handshake_stage
+    }
+
+    fn plug(&mut self, session: &mut SessionBase, io: &mut IoContext) {
+        self.base_mut().plug(io);
+        self.plug_internal(session, io);
+    }
+
+    fn terminate(&mut self, io: &mut IoContext) {
+        self.base_mut().unplug(io);
+    }
+
+    fn restart_input(&mut self, session: &mut SessionBase, io: &mut IoContext) -> bool {
+        self.restart_input_internal(session, io)
+    }
+
+    fn restart_output(&mut self, session: &mut SessionBase, io: &mut IoContext) {
+        self.restart_output_internal(session, io)
+    }
+
+    fn zap_msg_available(&mut self, session: &mut SessionBase, io: &mut IoContext) {
+        self.zap_msg_available_internal(session, io)
+    }
+
+    fn get_endpoint(&self) -> &EndpointUriPair {
+        self.base().get_endpoint()
+    }
+
+    fn in_event(&mut self, session: &mut SessionBase, io: &mut IoContext) {
+        // ignore errors
+        let _ = self.in_event_internal(session, io);
+    }
+
+    fn out_event(&mut self, session: &mut SessionBase, io: &mut IoContext) {
+        self.out_event_internal(session, io)
+    }
+
+    fn timer_event(&mut self, id: i32, session: &mut SessionBase, io: &mut IoContext) {
+        self.timer_event_internal(id, session, io)
+    }
 }
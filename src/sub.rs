@@ -1,98 +1,95 @@
-use std::os::raw::{c_int, c_void};
-use std::convert::TryFrom;
-
-// Constants
-const ZMQ_SUB: i32 = 2;
-const ZMQ_SUBSCRIBE: i32 = 6;
-const ZMQ_UNSUBSCRIBE: i32 = 7;
-const EINVAL: i32 = 22;
-const ENOTSUP: i32 = 95;
-
-// Forward declarations
-pub struct Context;
-pub struct Message;
-pub struct IoThread;
-pub struct SocketBase;
-
-// Base trait for XSUB functionality
-pub trait XSub {
-    fn xsend(&mut self, msg: &mut Message) -> i32;
-    fn xhas_out(&self) -> bool;
+use crate::constants::{ZMQ_SUB, ZMQ_SUBSCRIBE, ZMQ_UNSUBSCRIBE};
+use crate::context::Context;
+use crate::message::Message;
+use crate::pipe::Pipe;
+use crate::socket_base::{SocketBase, SocketBehavior};
+use crate::xsub::XSub;
+use std::sync::Arc;
+
+/// SUB socket: an XSUB whose subscriptions are managed through socket
+/// options rather than sent as messages.
+pub struct Sub {
+    xsub: XSub,
 }
 
-#[derive(Debug)]
-pub struct Options {
-    pub socket_type: i32,
-    pub filter: bool,
-}
+impl Sub {
+    pub fn new(ctx: &Arc<Context>, tid: u32, sid: i32) -> Self {
+        let mut xsub = XSub::new(ctx, tid, sid);
+        xsub.base_mut().options.socket_type = ZMQ_SUB;
 
-pub struct Sub {
-    options: Options,
-    // Additional fields would go here
+        // Switch filtering messages on (as opposed to XSUB which where the
+        // filtering is off).
+        xsub.base_mut().options.filter = true;
+
+        Sub { xsub }
+    }
 }
 
-impl Sub {
-    pub fn new(parent: &Context, tid: u32, sid: i32) -> Self {
-        Sub {
-            options: Options {
-                socket_type: ZMQ_SUB,
-                filter: true,
-            },
-        }
+impl SocketBehavior for Sub {
+    fn base(&self) -> &SocketBase {
+        self.xsub.base()
+    }
+
+    fn base_mut(&mut self) -> &mut SocketBase {
+        self.xsub.base_mut()
     }
 
-    pub fn xsetsockopt(&mut self, option: i32, optval: *const c_void, optvallen: usize) -> i32 {
+    fn xattach_pipe(&mut self, pipe: Pipe, subscribe_to_all: bool, locally_initiated: bool) {
+        self.xsub.xattach_pipe(pipe, subscribe_to_all, locally_initiated);
+    }
+
+    fn xsetsockopt(&mut self, option: i32, optval: &[u8]) -> Result<(), i32> {
         if option != ZMQ_SUBSCRIBE && option != ZMQ_UNSUBSCRIBE {
-            // Set errno in real implementation
-            return -1;
+            return self.xsub.xsetsockopt(option, optval);
         }
 
-        let data = unsafe { std::slice::from_raw_parts(optval as *const u8, optvallen) };
-        let mut msg = Message::new(); // Simplified, real implementation would need proper initialization
-
-        let rc = if option == ZMQ_SUBSCRIBE {
-            msg.init_subscribe(data)
+        // Create the subscription message.
+        let mut msg = Message::new();
+        if option == ZMQ_SUBSCRIBE {
+            msg.init_subscribe(optval)?;
         } else {
-            msg.init_cancel(data)
-        };
-
-        if rc != 0 {
-            return rc;
+            msg.init_cancel(optval)?;
         }
 
-        self.xsend(&mut msg)
+        // Pass it further on in the stack.
+        self.xsub.xsend(&mut msg)
     }
-}
 
-impl XSub for Sub {
-    fn xsend(&mut self, _msg: &mut Message) -> i32 {
-        // Set errno to ENOTSUP in real implementation
-        -1
+    fn xgetsockopt(&mut self, option: i32, optval: &mut [u8]) -> Result<usize, i32> {
+        self.xsub.xgetsockopt(option, optval)
     }
 
-    fn xhas_out(&self) -> bool {
-        false
+    fn xread_activated(&mut self, pipe: &Pipe) {
+        self.xsub.xread_activated(pipe);
     }
-}
 
-// Implementing necessary traits
-impl Drop for Sub {
-    fn drop(&mut self) {
-        // Cleanup code would go here
+    fn xwrite_activated(&mut self, pipe: &Pipe) {
+        self.xsub.xwrite_activated(pipe);
+    }
+
+    fn xpipe_terminated(&mut self, pipe: &Pipe) {
+        self.xsub.xpipe_terminated(pipe);
+    }
+
+    fn xhiccuped(&mut self, pipe: &Pipe) {
+        self.xsub.xhiccuped(pipe);
     }
-}
 
-// Mock implementations for supporting types
-impl Message {
-    fn new() -> Self {
-        Message
+    fn xsend(&mut self, _msg: &mut Message) -> Result<(), i32> {
+        // Override the XSUB's send.
+        Err(libc::ENOTSUP)
+    }
+
+    fn xhas_out(&mut self) -> bool {
+        // Override the XSUB's send.
+        false
     }
 
-    fn init_subscribe(&mut self, _data: &[u8]) -> i32 {
-        0
+    fn xrecv(&mut self, msg: &mut Message) -> Result<(), i32> {
+        self.xsub.xrecv(msg)
     }
 
-    fn init_cancel(&mut self, _data: &[u8]) -> i32 {
-        0
+    fn xhas_in(&mut self) -> bool {
+        self.xsub.xhas_in()
     }
 }
@@ -1,9 +1,12 @@
-use std::io::{Read, Write};
-use std::net::{TcpStream, ToSocketAddrs};
+use std::net::{
+    Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream, ToSocketAddrs,
+};
 use std::os::unix::io::{AsRawFd, RawFd};
 #[cfg(target_os = "windows")]
 use std::os::windows::io::{AsRawSocket, RawSocket};
 
+use crate::options::Options;
+
 #[derive(Debug)]
 pub struct TcpOptions {
     pub sndbuf: i32,
@@ -38,13 +41,15 @@ pub fn tune_tcp_socket(stream: &TcpStream) -> std::io::Result<()> {
 }
 
 pub fn set_tcp_send_buffer(stream: &TcpStream, size: usize) -> std::io::Result<()> {
-    stream.set_send_buffer_size(size)
+    set_int_option(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_SNDBUF, size as i32)
 }
 
 pub fn set_tcp_receive_buffer(stream: &TcpStream, size: usize) -> std::io::Result<()> {
-    stream.set_recv_buffer_size(size)
+    set_int_option(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_RCVBUF, size as i32)
 }
 
+/// Enables TCP keepalives. Counts and intervals of -1 (passed in as 0 or
+/// less) leave the OS defaults in place.
 pub fn tune_tcp_keepalives(
     stream: &TcpStream,
     keepalive: bool,
@@ -52,44 +57,203 @@ pub fn tune_tcp_keepalives(
     keepalive_idle: u32,
     keepalive_intvl: u32,
 ) -> std::io::Result<()> {
-    stream.set_keepalive(Some(std::time::Duration::from_secs(keepalive_idle as u64)))?;
-    
-    #[cfg(target_family = "unix")]
+    let fd = stream.as_raw_fd();
+    set_int_option(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, keepalive as i32)?;
+    if !keepalive {
+        return Ok(());
+    }
+
+    #[cfg(target_os = "linux")]
     {
-        use std::os::unix::io::AsRawFd;
-        let fd = stream.as_raw_fd();
-        
-        #[cfg(target_os = "linux")]
-        unsafe {
-            let optval: libc::c_int = keepalive_cnt as libc::c_int;
-            libc::setsockopt(
-                fd,
-                libc::IPPROTO_TCP,
-                libc::TCP_KEEPCNT,
-                &optval as *const _ as *const libc::c_void,
-                std::mem::size_of_val(&optval) as libc::socklen_t,
-            );
-
-            let optval: libc::c_int = keepalive_intvl as libc::c_int;
-            libc::setsockopt(
-                fd,
-                libc::IPPROTO_TCP,
-                libc::TCP_KEEPINTVL,
-                &optval as *const _ as *const libc::c_void,
-                std::mem::size_of_val(&optval) as libc::socklen_t,
-            );
+        if keepalive_cnt > 0 {
+            set_int_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, keepalive_cnt as i32)?;
+        }
+        if keepalive_idle > 0 {
+            set_int_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, keepalive_idle as i32)?;
+        }
+        if keepalive_intvl > 0 {
+            set_int_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, keepalive_intvl as i32)?;
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (keepalive_cnt, keepalive_idle, keepalive_intvl);
+
+    Ok(())
+}
+
+fn set_int_option(fd: RawFd, level: i32, name: i32, value: i32) -> std::io::Result<()> {
+    let rc = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if rc == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Writes data to the socket. Returns the number of bytes written, which
+/// is 0 if the socket buffer is full. Errors mean the connection is gone.
+pub fn tcp_write(fd: RawFd, data: &[u8]) -> Result<usize, i32> {
+    let nbytes = unsafe {
+        libc::send(
+            fd,
+            data.as_ptr() as *const libc::c_void,
+            data.len(),
+            SEND_FLAGS,
+        )
+    };
+
+    //  Several errors are OK. When speculative write is being done we may not
+    //  be able to write a single byte from the socket. Also, SIGSTOP issued
+    //  by a debugging tool can result in EINTR error.
+    if nbytes == -1 {
+        let errno = last_errno();
+        if errno == libc::EAGAIN || errno == libc::EWOULDBLOCK || errno == libc::EINTR {
+            return Ok(0);
+        }
+        return Err(errno);
+    }
+    Ok(nbytes as usize)
+}
+
+/// Reads data from the socket. Returns EAGAIN if there's nothing to read
+/// and Ok(0) once the peer closed the connection.
+pub fn tcp_read(fd: RawFd, data: &mut [u8]) -> Result<usize, i32> {
+    let rc = unsafe { libc::recv(fd, data.as_mut_ptr() as *mut libc::c_void, data.len(), 0) };
+
+    //  Several errors are OK. When speculative read is being done we may not
+    //  be able to read a single byte from the socket. Also, SIGSTOP issued
+    //  by a debugging tool can result in EINTR error.
+    if rc == -1 {
+        let errno = last_errno();
+        if errno == libc::EWOULDBLOCK || errno == libc::EINTR {
+            return Err(libc::EAGAIN);
         }
+        return Err(errno);
     }
+    Ok(rc as usize)
+}
+
+// Broken connections must not kill the process with SIGPIPE.
+#[cfg(target_os = "linux")]
+const SEND_FLAGS: libc::c_int = libc::MSG_NOSIGNAL;
+#[cfg(not(target_os = "linux"))]
+const SEND_FLAGS: libc::c_int = 0;
+
+fn last_errno() -> i32 {
+    std::io::Error::last_os_error()
+        .raw_os_error()
+        .unwrap_or(libc::EIO)
+}
 
+/// Sets the socket into non-blocking mode.
+pub fn unblock_socket(fd: RawFd) -> Result<(), i32> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL, 0) };
+    if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1 {
+        return Err(last_errno());
+    }
     Ok(())
 }
 
-pub fn tcp_write(stream: &mut TcpStream, data: &[u8]) -> std::io::Result<usize> {
-    stream.write(data)
+/// Converts the address into the form the socket calls take.
+pub fn to_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from(*addr.ip()).to_be(),
+                },
+                ..unsafe { std::mem::zeroed() }
+            };
+            unsafe { std::ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in, sin) };
+            std::mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                sin6_scope_id: addr.scope_id(),
+                ..unsafe { std::mem::zeroed() }
+            };
+            unsafe { std::ptr::write(&mut storage as *mut _ as *mut libc::sockaddr_in6, sin6) };
+            std::mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
 }
 
-pub fn tcp_read(stream: &mut TcpStream, data: &mut [u8]) -> std::io::Result<usize> {
-    stream.read(data)
+fn from_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sin.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                u16::from_be(sin6.sin6_port),
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the local (`local` set) or the peer address of a connected
+/// socket.
+pub fn get_socket_address(fd: RawFd, local: bool) -> Option<SocketAddr> {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let addr = &mut storage as *mut _ as *mut libc::sockaddr;
+    let rc = unsafe {
+        if local {
+            libc::getsockname(fd, addr, &mut len)
+        } else {
+            libc::getpeername(fd, addr, &mut len)
+        }
+    };
+    if rc == -1 {
+        return None;
+    }
+    from_sockaddr(&storage)
+}
+
+/// Applies the socket options relevant to a freshly established TCP
+/// connection.
+pub fn tune_tcp_connection(stream: &TcpStream, options: &Options) -> std::io::Result<()> {
+    tune_tcp_socket(stream)?;
+    if options.send_buf_opt >= 0 {
+        set_tcp_send_buffer(stream, options.send_buf_opt as usize)?;
+    }
+    if options.recv_buf_opt >= 0 {
+        set_tcp_receive_buffer(stream, options.recv_buf_opt as usize)?;
+    }
+    if options.tcp_keepalive == 1 {
+        tune_tcp_keepalives(
+            stream,
+            true,
+            options.tcp_keepalive_cnt.max(0) as u32,
+            options.tcp_keepalive_idle.max(0) as u32,
+            options.tcp_keepalive_intvl.max(0) as u32,
+        )?;
+    }
+    Ok(())
 }
 
 pub fn tcp_tune_loopback_fast_path(stream: &TcpStream) {
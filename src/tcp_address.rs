@@ -1,193 +1,203 @@
-use std::mem;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-use std::str::FromStr;
-
-#[cfg(unix)]
-use libc::{sa_family_t, sockaddr, sockaddr_in, sockaddr_in6, AF_INET, AF_INET6};
-#[cfg(windows)]
-use winapi::shared::ws2def::{AF_INET, AF_INET6, SOCKADDR as sockaddr, SOCKADDR_IN as sockaddr_in};
-
-use crate::err::ZmqError;
-use crate::socket::{ZmqSockAddr, ZmqSockAddrIn, ZmqSockAddrIn6};
-
-#[derive(Clone)]
-pub enum IpAddrEnum {
-    V4(ZmqSockAddrIn),
-    V6(ZmqSockAddrIn6),
-}
+//! Resolution of TCP endpoint addresses.
+//!
+//! Addresses take the libzmq form `[source;]host:port`, where the host is
+//! an IPv4 address, a bracketed IPv6 address, a hostname or, when binding,
+//! `*` for all interfaces. A port of `*` or `0` binds to an ephemeral port.
 
-pub struct TcpAddress {
-    address: IpAddrEnum,
-    source_address: IpAddrEnum,
-    has_src_addr: bool,
-}
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 
-pub struct TcpAddressMask {
-    network_address: IpAddrEnum,
-    address_mask: i32,
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TcpAddress {
+    address: SocketAddr,
+    source_address: Option<SocketAddr>,
 }
 
 impl TcpAddress {
-    pub fn new() -> Self {
+    pub fn from_socket_addr(address: SocketAddr) -> Self {
         TcpAddress {
-            address: IpAddrEnum::V4(unsafe { mem::zeroed() }),
-            source_address: IpAddrEnum::V4(unsafe { mem::zeroed() }),
-            has_src_addr: false,
+            address,
+            source_address: None,
         }
     }
 
-    pub fn from_sockaddr_in(sai: &ZmqSockAddrIn) -> Self {
-        let mut tcp_addr = TcpAddress::new();
-        tcp_addr.address = IpAddrEnum::V4(sai.clone());
-        tcp_addr
+    /// Resolves `name`. `local` is set when the address is to be bound to,
+    /// which allows the `*` wildcards; `ipv6` allows IPv6 results.
+    pub fn resolve(name: &str, local: bool, ipv6: bool) -> Result<Self, i32> {
+        // Test the ';' to know if we have a source address in name.
+        if let Some(src_delimiter) = name.rfind(';') {
+            let source_address = resolve_address(&name[..src_delimiter], true, ipv6)?;
+            let address = resolve_address(&name[src_delimiter + 1..], local, ipv6)?;
+            return Ok(TcpAddress {
+                address,
+                source_address: Some(source_address),
+            });
+        }
+
+        Ok(TcpAddress {
+            address: resolve_address(name, local, ipv6)?,
+            source_address: None,
+        })
     }
-    
-    pub fn from_sockaddr_in6(sai: &ZmqSockAddrIn6) -> Self {
-        let mut tcp_addr = TcpAddress::new();
-        tcp_addr.address = IpAddrEnum::V6(sai.clone());
-        tcp_addr
+
+    pub fn addr(&self) -> SocketAddr {
+        self.address
     }
-    
-    pub fn from_sockaddr(sa: &ZmqSockAddr) -> Self {
-        let mut tcp_addr = TcpAddress::new();
-        
+
+    pub fn src_addr(&self) -> Option<SocketAddr> {
+        self.source_address
     }
 
-    pub fn resolve(&mut self, name: &str, local: bool, ipv6: bool) -> Result<(), i32> {
-        if let Some(src_delimiter) = name.rfind(';') {
-            let (src_name, dest_name) = name.split_at(src_delimiter);
-            let dest_name = &dest_name[1..]; // Skip the semicolon
+    pub fn family(&self) -> libc::c_int {
+        match self.address {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        }
+    }
+}
 
-            // Resolve source address
-            self.resolve_address(&mut self.source_address, src_name, true, false, ipv6)?;
-            self.has_src_addr = true;
+impl fmt::Display for TcpAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // SocketAddr already brackets IPv6 addresses.
+        write!(f, "tcp://{}", self.address)
+    }
+}
 
-            // Resolve destination address
-            self.resolve_address(&mut self.address, dest_name, local, true, ipv6)
-        } else {
-            // Just resolve destination address
-            self.resolve_address(&mut self.address, name, local, true, ipv6)
-        }
+/// Basic sanity check of a connect address, without resolving it: the
+/// host may contain alphanumerics, IPv6 brackets and colons, zone ids and
+/// a source part, and the address must end in a numeric ":port".
+pub fn check_connect_syntax(name: &str) -> bool {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || ".-:%;[]_*".contains(c);
+    let first_ok = name
+        .chars()
+        .next()
+        .map_or(false, |c| c.is_ascii_alphanumeric() || c == '[' || c == ':');
+    if !first_ok || !name.chars().all(valid_char) {
+        return false;
     }
 
-    fn resolve_address(
-        &self,
-        addr: &mut IpAddrEnum,
-        name: &str,
-        bindable: bool,
-        allow_dns: bool,
-        ipv6: bool,
-    ) -> Result<(), i32> {
-        // Parse address and port
-        let addr_parts: Vec<&str> = name.rsplitn(2, ':').collect();
-        if addr_parts.len() != 2 {
-            return Err(-1);
-        }
+    // Do we have a valid port string? (cannot be '*' in connect)
+    match name.rfind(':') {
+        Some(pos) => name[pos + 1..]
+            .chars()
+            .next()
+            .map_or(false, |c| c.is_ascii_digit()),
+        None => false,
+    }
+}
 
-        let host = addr_parts[1];
-        let port: u16 = addr_parts[0].parse().map_err(|_| -1)?;
+pub(crate) fn resolve_address(name: &str, local: bool, ipv6: bool) -> Result<SocketAddr, i32> {
+    // Find the ':' at end that separates address from the port number.
+    let delimiter = name.rfind(':').ok_or(libc::EINVAL)?;
+    let (host, port) = (&name[..delimiter], &name[delimiter + 1..]);
 
-        // Parse IP address
-        let ip_addr = if ipv6 {
-            if let Ok(addr) = Ipv6Addr::from_str(host) {
-                IpAddr::V6(addr)
-            } else {
-                return Err(-1);
-            }
-        } else if let Ok(addr) = Ipv4Addr::from_str(host) {
-            IpAddr::V4(addr)
-        } else {
-            return Err(-1);
-        };
+    // Allow 0 specifically, to detect invalid port error in atoi if not.
+    let port: u16 = if port == "*" || port == "0" {
+        if !local {
+            return Err(libc::EINVAL);
+        }
+        0
+    } else {
+        match port.parse() {
+            Ok(0) | Err(_) => return Err(libc::EINVAL),
+            Ok(port) => port,
+        }
+    };
 
-        // Create appropriate socket address
-        *addr = match ip_addr {
-            IpAddr::V4(ip) => {
-                let mut sa: sockaddr_in = unsafe { mem::zeroed() };
-                sa.sin_family = AF_INET as _;
-                sa.sin_port = port.to_be();
-                sa.sin_addr = unsafe { mem::transmute(ip.octets()) };
-                IpAddrEnum::V4(sa)
-            }
-            IpAddr::V6(ip) => {
-                let mut sa: ZmqSockAddrIn6 = unsafe { mem::zeroed() };
-                sa.sin6_family = AF_INET6 as _;
-                sa.sin6_port = port.to_be();
-                sa.sin6_addr = unsafe { mem::transmute(ip.octets()) };
-                IpAddrEnum::V6(sa)
-            }
-        };
+    // Remove square brackets around the address, if any, as used in IPv6.
+    let host = match host.strip_prefix('[') {
+        Some(rest) => rest.strip_suffix(']').ok_or(libc::EINVAL)?,
+        None => host,
+    };
 
-        Ok(())
+    if host.is_empty() {
+        return Err(libc::EINVAL);
     }
 
-    pub fn to_string(&self) -> Result<String, ZmqError> {
-        let (addr, port) = match &self.address {
-            IpAddrEnum::V4(sa) => {
-                let ip = Ipv4Addr::from(unsafe { sa.sin_addr.s_addr }.to_ne_bytes());
-                (IpAddr::V4(ip), sa.sin_port)
-            }
-            IpAddrEnum::V6(sa) => {
-                let ip = Ipv6Addr::from(unsafe { sa.sin6_addr.s6_addr });
-                (IpAddr::V6(ip), sa.sin6_port)
-            }
+    // Wildcard addresses only make sense when binding.
+    if host == "*" {
+        if !local {
+            return Err(libc::ENODEV);
+        }
+        let any = if ipv6 {
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
         };
-
-        let port = u16::from_be(port);
-
-        Ok(match addr {
-            IpAddr::V4(ip) => format!("tcp://{}:{}", ip, port),
-            IpAddr::V6(ip) => format!("tcp://[{}]:{}", ip, port),
-        })
+        return Ok(SocketAddr::new(any, port));
     }
-}
 
-impl TcpAddressMask {
-    pub fn new() -> Self {
-        TcpAddressMask {
-            network_address: IpAddrEnum::V4(unsafe { mem::zeroed() }),
-            address_mask: -1,
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        if ip.is_ipv6() && !ipv6 {
+            return Err(libc::EINVAL);
         }
+        return Ok(SocketAddr::new(ip, port));
     }
 
-    pub fn resolve(&mut self, name: &str, ipv6: bool) -> Result<(), i32> {
-        // Split address and mask
-        let parts: Vec<&str> = name.rsplitn(2, '/').collect();
-        let (addr_str, mask_str) = match parts.len() {
-            2 => (parts[1], Some(parts[0])),
-            1 => (parts[0], None),
-            _ => return Err(-1),
+    // Fall back to a name lookup, preferring IPv4 results.
+    let candidates: Vec<SocketAddr> = (host, port)
+        .to_socket_addrs()
+        .map_err(|_| libc::EINVAL)?
+        .filter(|addr| ipv6 || addr.is_ipv4())
+        .collect();
+    candidates
+        .iter()
+        .find(|addr| addr.is_ipv4())
+        .or_else(|| candidates.first())
+        .copied()
+        .ok_or(libc::EINVAL)
+}
+
+/// A network address with a prefix length, as used by accept filters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TcpAddressMask {
+    network_address: IpAddr,
+    address_mask: u32,
+}
+
+impl TcpAddressMask {
+    /// Parses `address[/mask]`. Without a mask the whole address has to
+    /// match.
+    pub fn resolve(name: &str, ipv6: bool) -> Result<Self, i32> {
+        let (addr_str, mask_str) = match name.rfind('/') {
+            Some(delimiter) => (&name[..delimiter], Some(&name[delimiter + 1..])),
+            None => (name, None),
         };
 
-        // Create temporary TcpAddress to resolve the network address
-        let mut temp_addr = TcpAddress::new();
-        temp_addr.resolve_address(&mut self.network_address, addr_str, false, false, ipv6)?;
-
-        // Parse the mask
-        self.address_mask = match mask_str {
-            None => {
-                if ipv6 {
-                    128
-                } else {
-                    32
-                }
-            }
-            Some("0") => 0,
-            Some(mask) => {
-                let mask: i32 = mask.parse().map_err(|_| -1)?;
-                if mask < 1 || (ipv6 && mask > 128) || (!ipv6 && mask > 32) {
-                    return Err(-1);
-                }
-                mask
-            }
+        let network_address: IpAddr = addr_str.parse().map_err(|_| libc::EINVAL)?;
+        if network_address.is_ipv6() && !ipv6 {
+            return Err(libc::EINVAL);
+        }
+        let full = if network_address.is_ipv6() { 128 } else { 32 };
+
+        let address_mask = match mask_str {
+            None => full,
+            Some(mask) => match mask.parse::<u32>() {
+                Ok(mask) if mask <= full => mask,
+                _ => return Err(libc::EINVAL),
+            },
         };
 
-        Ok(())
+        Ok(TcpAddressMask {
+            network_address,
+            address_mask,
+        })
     }
 
-    pub fn match_address(&self, sa: &sockaddr, sa_len: usize) -> bool {
-        // Match implementation goes here
-        // This is a simplified version
-        true
+    pub fn match_address(&self, addr: &IpAddr) -> bool {
+        let (network, addr) = match (self.network_address, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                (u32::from(network) as u128, u32::from(*addr) as u128)
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => (u128::from(network), u128::from(*addr)),
+            _ => return false,
+        };
+
+        if self.address_mask == 0 {
+            return true;
+        }
+        let bits = if self.network_address.is_ipv6() { 128 } else { 32 };
+        let shift = bits - self.address_mask;
+        (network >> shift) == (addr >> shift)
     }
 }
@@ -1,162 +1,318 @@
-use std::net::{SocketAddr, TcpStream};
-use std::os::unix::io::{AsRawFd, RawFd};
-use std::time::Duration;
+//! Establishes the TCP connection of a connecting session.
+//!
+//! The connecter is owned by its session and lives in the same I/O thread;
+//! the session routes the poll events and timers of the connecter to it
+//! and receives the engine for the new connection once it is up. Failed
+//! attempts are retried after the reconnect interval.
 
-const CONNECT_TIMER_ID: i32 = 2;
+use std::net::TcpStream;
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+
+use crate::endpoint::{EndpointType, EndpointUriPair};
+use crate::i_engine::IEngine;
+use crate::options::Options;
+use crate::poller_base::{Handle, IoContext};
+use crate::random::generate_random;
+use crate::raw_engine::RawEngine;
+use crate::tcp::{get_socket_address, to_sockaddr, tune_tcp_connection};
+use crate::tcp_address::TcpAddress;
+use crate::zmtp_engine::ZmtpEngine;
+
+// ID of the timer used to delay the reconnection.
+pub const RECONNECT_TIMER_ID: i32 = 1;
+
+// ID of the timer used to check the connect timeout, must be different
+// from the reconnect timer id.
+pub const CONNECT_TIMER_ID: i32 = 2;
 
-#[derive(Debug)]
 pub struct TcpConnecter {
-    io_thread: Box<IoThread>,
-    session: Box<SessionBase>,
+    // Address to connect to, host:port.
+    address: String,
+
+    // The endpoint as passed to zmq_connect.
+    endpoint: String,
+
     options: Options,
-    addr: Box<Address>,
+
+    // Underlying socket, while a connection attempt is in progress.
+    fd: Option<RawFd>,
+
+    // Handle corresponding to the listening socket, if file descriptor is
+    // registered with the poller, or None.
+    handle: Option<Handle>,
+
+    // If true, connecter is waiting a while before trying to connect.
+    delayed_start: bool,
+
+    // True iff a timer has been started.
+    reconnect_timer_started: bool,
     connect_timer_started: bool,
-    socket: Option<TcpStream>,
-    fd: RawFd,
+
+    // Current reconnect ivl, updated for backoff strategy
+    current_reconnect_ivl: i32,
 }
 
 impl TcpConnecter {
-    pub fn new(
-        io_thread: Box<IoThread>,
-        session: Box<SessionBase>,
-        options: Options,
-        addr: Box<Address>,
-        delayed_start: bool,
-    ) -> Self {
-        let connecter = Self {
-            io_thread,
-            session,
-            options,
-            addr,
+    pub fn new(address: &str, endpoint: &str, options: &Options, delayed_start: bool) -> Self {
+        TcpConnecter {
+            address: address.to_string(),
+            endpoint: endpoint.to_string(),
+            options: options.clone(),
+            fd: None,
+            handle: None,
+            delayed_start,
+            reconnect_timer_started: false,
             connect_timer_started: false,
-            socket: None,
-            fd: -1,
-        };
-
-        assert!(connecter.addr.protocol == Protocol::Tcp);
-
-        if !delayed_start {
-            connecter.start_connecting();
+            current_reconnect_ivl: -1,
         }
+    }
 
-        connecter
+    pub fn process_plug(&mut self, io: &mut IoContext) {
+        if self.delayed_start {
+            self.add_reconnect_timer(io);
+        } else {
+            self.start_connecting(io);
+        }
     }
 
-    pub fn process_term(&mut self, linger: i32) {
+    /// Stops connecting and releases the socket.
+    pub fn terminate(&mut self, io: &mut IoContext) {
+        if self.reconnect_timer_started {
+            io.cancel_timer(RECONNECT_TIMER_ID);
+            self.reconnect_timer_started = false;
+        }
         if self.connect_timer_started {
-            self.cancel_timer(CONNECT_TIMER_ID);
+            io.cancel_timer(CONNECT_TIMER_ID);
             self.connect_timer_started = false;
         }
-        // Call parent implementation
-        self.stream_connecter_base_process_term(linger);
+        self.rm_handle(io);
+        self.close();
+    }
+
+    /// Connection refused and the like are reported as input events.
+    pub fn in_event(&mut self, io: &mut IoContext) -> Option<Box<dyn IEngine>> {
+        // We are not polling for incoming data, so we are actually called
+        // because of error here. However, we can get error on out event as
+        // well on some platforms, so we'll simply handle both events in the
+        // same way.
+        self.out_event(io)
     }
 
-    pub fn out_event(&mut self) {
+    /// Completes the connection attempt. Returns the engine for the new
+    /// connection if it succeeded.
+    pub fn out_event(&mut self, io: &mut IoContext) -> Option<Box<dyn IEngine>> {
         if self.connect_timer_started {
-            self.cancel_timer(CONNECT_TIMER_ID);
+            io.cancel_timer(CONNECT_TIMER_ID);
             self.connect_timer_started = false;
         }
 
-        self.rm_handle();
+        // The socket is not registered with the poller anymore, whatever
+        // the outcome of the attempt.
+        self.rm_handle(io);
 
-        match self.connect() {
-            Ok(stream) => {
-                self.socket = Some(stream);
-                if !self.tune_socket() {
-                    self.close();
-                    self.add_reconnect_timer();
-                    return;
-                }
-                self.create_engine();
-            }
-            Err(e) => {
-                if e.kind() == std::io::ErrorKind::ConnectionRefused 
-                    && (self.options.reconnect_stop & ZMQ_RECONNECT_STOP_CONN_REFUSED) != 0 
-                {
-                    self.send_conn_failed();
-                    self.close();
-                    self.terminate();
-                } else {
-                    self.close();
-                    self.add_reconnect_timer();
-                }
+        let fd = match self.connect() {
+            Some(fd) => fd,
+            // Handle the error condition by attempt to reconnect.
+            None => {
+                self.close();
+                self.add_reconnect_timer(io);
+                return None;
             }
+        };
+
+        let stream = unsafe { TcpStream::from_raw_fd(fd) };
+        if tune_tcp_connection(&stream, &self.options).is_err() {
+            drop(stream);
+            self.add_reconnect_timer(io);
+            return None;
+        }
+        let fd = stream.into_raw_fd();
+
+        // Create the engine object for this connection.
+        let local = get_socket_address(fd, true)
+            .map(|addr| TcpAddress::from_socket_addr(addr).to_string())
+            .unwrap_or_default();
+        let endpoint_pair =
+            EndpointUriPair::with_values(&local, &self.endpoint, EndpointType::Connect);
+        if self.options.raw_socket {
+            Some(Box::new(RawEngine::new(fd, &self.options, endpoint_pair)))
+        } else {
+            Some(Box::new(ZmtpEngine::new(fd, &self.options, endpoint_pair)))
+        }
+    }
+
+    pub fn timer_event(&mut self, id: i32, io: &mut IoContext) {
+        if id == CONNECT_TIMER_ID {
+            self.connect_timer_started = false;
+            self.rm_handle(io);
+            self.close();
+            self.add_reconnect_timer(io);
+        } else if id == RECONNECT_TIMER_ID {
+            self.reconnect_timer_started = false;
+            self.start_connecting(io);
         }
     }
 
-    fn start_connecting(&mut self) {
+    // Internal function to start the actual connection establishment.
+    fn start_connecting(&mut self, io: &mut IoContext) {
         match self.open() {
-            Ok(()) => {
-                self.handle = self.add_fd();
-                self.out_event();
-            }
-            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                self.handle = self.add_fd();
-                self.set_pollout();
-                self.socket_event_connect_delayed();
-                self.add_connect_timer();
+            // Connection establishment may be delayed. Poll for its
+            // completion; a connection that succeeded immediately shows up
+            // as writable straight away.
+            Ok(fd) => {
+                let handle = io.add_fd(fd);
+                io.set_pollout(handle);
+                self.handle = Some(handle);
+                self.add_connect_timer(io);
             }
+            // Handle any other error condition by eventual reconnect.
             Err(_) => {
                 self.close();
-                self.add_reconnect_timer();
+                self.add_reconnect_timer(io);
             }
         }
     }
 
-    fn add_connect_timer(&mut self) {
-        if self.options.connect_timeout > 0 {
-            self.add_timer(self.options.connect_timeout, CONNECT_TIMER_ID);
-            self.connect_timer_started = true;
+    // Open TCP connecting socket. The connection is established
+    // asynchronously.
+    fn open(&mut self) -> Result<RawFd, i32> {
+        debug_assert!(self.fd.is_none());
+
+        // Resolve the address again on every attempt, names may change.
+        let addr = TcpAddress::resolve(&self.address, false, self.options.ipv6)?;
+
+        let fd = unsafe { libc::socket(addr.family(), libc::SOCK_STREAM, libc::IPPROTO_TCP) };
+        if fd == -1 {
+            return Err(last_errno());
+        }
+        self.fd = Some(fd);
+
+        unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+        crate::tcp::unblock_socket(fd)?;
+
+        // Set a source address for conversations
+        if let Some(src) = addr.src_addr() {
+            let (storage, len) = to_sockaddr(&src);
+            let reuse: libc::c_int = 1;
+            let rc = unsafe {
+                libc::setsockopt(
+                    fd,
+                    libc::SOL_SOCKET,
+                    libc::SO_REUSEADDR,
+                    &reuse as *const _ as *const libc::c_void,
+                    std::mem::size_of_val(&reuse) as libc::socklen_t,
+                );
+                libc::bind(fd, &storage as *const _ as *const libc::sockaddr, len)
+            };
+            if rc == -1 {
+                return Err(last_errno());
+            }
+        }
+
+        // Connect to the remote peer.
+        let (storage, len) = to_sockaddr(&addr.addr());
+        let rc = unsafe { libc::connect(fd, &storage as *const _ as *const libc::sockaddr, len) };
+
+        // Connect was successful immediately, or is in progress.
+        if rc == 0 {
+            return Ok(fd);
+        }
+        match last_errno() {
+            libc::EINPROGRESS | libc::EINTR => Ok(fd),
+            errno => Err(errno),
         }
     }
 
-    fn tune_socket(&mut self) -> bool {
-        if let Some(socket) = &self.socket {
-            socket.set_nodelay(true).is_ok()
-                && self.set_tcp_keepalive(socket)
-                && self.set_tcp_maxrt(socket)
-        } else {
-            false
+    // Get the file descriptor of newly created connection. Returns None
+    // if the connection was unsuccessful.
+    fn connect(&mut self) -> Option<RawFd> {
+        let fd = self.fd?;
+
+        // Async connect has finished. Check whether an error occurred
+        let mut err: libc::c_int = 0;
+        let mut len = std::mem::size_of_val(&err) as libc::socklen_t;
+        let rc = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_ERROR,
+                &mut err as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if rc == -1 || err != 0 {
+            return None;
         }
+
+        // Return the newly connected socket.
+        self.fd = None;
+        Some(fd)
     }
 
-    // Platform specific helpers would go here
-    #[cfg(unix)]
-    fn set_tcp_keepalive(&self, socket: &TcpStream) -> bool {
-        // Unix-specific TCP keepalive implementation
-        true // simplified
+    // Internal function to add a reconnect timer
+    fn add_reconnect_timer(&mut self, io: &mut IoContext) {
+        if self.options.reconnect_intvl > 0 {
+            let interval = self.get_new_reconnect_ivl();
+            io.add_timer(interval, RECONNECT_TIMER_ID);
+            self.reconnect_timer_started = true;
+        }
     }
 
-    #[cfg(windows)] 
-    fn set_tcp_keepalive(&self, socket: &TcpStream) -> bool {
-        // Windows-specific TCP keepalive implementation
-        true // simplified
+    // Internal function to add a connect timer
+    fn add_connect_timer(&mut self, io: &mut IoContext) {
+        if self.options.connect_timeout > 0 {
+            io.add_timer(self.options.connect_timeout, CONNECT_TIMER_ID);
+            self.connect_timer_started = true;
+        }
     }
-}
 
-// Additional types/traits would be defined here
-trait StreamConnecterBase {
-    fn stream_connecter_base_process_term(&mut self, linger: i32);
-}
+    // Internal function to return a reconnect backoff delay.
+    // Will modify the current_reconnect_ivl used for next call
+    // Returns the currently used interval
+    fn get_new_reconnect_ivl(&mut self) -> i32 {
+        let reconnect_ivl = self.options.reconnect_intvl;
 
-#[derive(Debug)]
-struct IoThread {}
+        if self.options.reconnect_intvl_max > 0 {
+            let candidate_interval = if self.current_reconnect_ivl == -1 {
+                reconnect_ivl
+            } else if self.current_reconnect_ivl > i32::MAX / 2 {
+                i32::MAX
+            } else {
+                self.current_reconnect_ivl * 2
+            };
 
-#[derive(Debug)] 
-struct SessionBase {}
+            self.current_reconnect_ivl = candidate_interval.min(self.options.reconnect_intvl_max);
+            self.current_reconnect_ivl
+        } else {
+            if self.current_reconnect_ivl == -1 {
+                self.current_reconnect_ivl = reconnect_ivl;
+            }
+            // The new interval is the base interval + random value.
+            let random_jitter = (generate_random() % reconnect_ivl as u32) as i32;
+            self.current_reconnect_ivl.saturating_add(random_jitter)
+        }
+    }
 
-#[derive(Debug)]
-struct Options {
-    connect_timeout: i64,
-    reconnect_stop: i32,
-}
+    fn rm_handle(&mut self, io: &mut IoContext) {
+        if let Some(handle) = self.handle.take() {
+            io.rm_fd(handle);
+        }
+    }
 
-#[derive(Debug)]
-struct Address {
-    protocol: Protocol,
+    // Close the connecting socket.
+    fn close(&mut self) {
+        if let Some(fd) = self.fd.take() {
+            unsafe {
+                libc::close(fd);
+            }
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
-enum Protocol {
-    Tcp,
+fn last_errno() -> i32 {
+    std::io::Error::last_os_error()
+        .raw_os_error()
+        .unwrap_or(libc::EIO)
 }
@@ -1,121 +1,158 @@
-use std::io::{self, Error, ErrorKind};
-use std::net::{SocketAddr, TcpListener};
-use std::os::unix::io::{AsRawFd, RawFd};
+//! Accepts TCP connections for a bound socket.
+//!
+//! The listener lives in an I/O thread. Every connection accepted gets a
+//! passive session of its own, launched in the same thread; the listener
+//! keeps their mailboxes so that unbinding or closing the socket takes the
+//! sessions down along with it.
+
+use std::net::{self, TcpStream};
+use std::os::unix::io::{AsRawFd, IntoRawFd};
+use std::sync::Arc;
+
+use crate::command::Command;
+use crate::context::Context;
+use crate::endpoint::{EndpointType, EndpointUriPair};
+use crate::i_engine::IEngine;
+use crate::i_mailbox::IMailbox;
+use crate::i_poll_events::IPollEvents;
+use crate::io_object::{IoObject, ObjectMailbox};
+use crate::options::Options;
+use crate::poller_base::{Handle, IoContext};
+use crate::raw_engine::RawEngine;
+use crate::session_base::Session;
+use crate::tcp::tune_tcp_connection;
+use crate::tcp_address::TcpAddress;
+use crate::zmtp_engine::ZmtpEngine;
+
+pub struct TcpListener {
+    // Underlying socket.
+    listener: net::TcpListener,
+
+    // Handle corresponding to the listening socket.
+    handle: Option<Handle>,
+
+    // String representation of endpoint to bind to
+    endpoint: String,
 
-pub struct TcpListenerZmq {
-    inner: TcpListener,
     options: Options,
-    socket: SocketBase,
-    endpoint: String,
-}
 
-impl TcpListenerZmq {
-    pub fn new(io_thread: &IoThread, socket: SocketBase, options: Options) -> Self {
-        Self {
-            inner: TcpListener::new().unwrap(),
-            options,
-            socket,
-            endpoint: String::new(),
-        }
-    }
+    // Mailbox of the socket the listener belongs to.
+    socket: Arc<dyn IMailbox>,
 
-    pub fn set_local_address(&mut self, addr: &str) -> io::Result<()> {
-        if self.options.use_fd != -1 {
-            // Use existing file descriptor
-            self.inner = unsafe { TcpListener::from_raw_fd(self.options.use_fd) };
-        } else {
-            self.inner = self.create_socket(addr)?;
-        }
+    // Context of the socket, handed to the sessions.
+    ctx: Arc<Context>,
 
-        self.endpoint = self.get_socket_name(self.inner.as_raw_fd(), SocketEnd::Local);
-        self.socket.event_listening(&self.endpoint, self.inner.as_raw_fd());
-        Ok(())
-    }
+    // Sessions of the accepted connections.
+    sessions: Vec<Arc<ObjectMailbox>>,
+}
 
-    fn create_socket(&self, addr: &str) -> io::Result<TcpListener> {
-        let addr = addr.parse::<SocketAddr>()?;
-        let socket = TcpListener::bind(addr)?;
-        
-        // Set socket options
-        let socket_fd = socket.as_raw_fd();
-        self.set_nonblocking(socket_fd)?;
-        self.set_reuse_address(socket_fd)?;
-        
-        Ok(socket)
+impl TcpListener {
+    /// Binds to `addr`, host:port as in the endpoint. The listener starts
+    /// accepting once it is launched into an I/O thread.
+    pub fn bind(
+        ctx: &Arc<Context>,
+        addr: &str,
+        options: &Options,
+        socket: Arc<dyn IMailbox>,
+    ) -> Result<Self, i32> {
+        let address = TcpAddress::resolve(addr, true, options.ipv6)?;
+
+        // Bind the socket to the network interface and port.
+        let listener = net::TcpListener::bind(address.addr())
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EINVAL))?;
+        listener
+            .set_nonblocking(true)
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EINVAL))?;
+
+        // Report the port actually bound to when it was left to the OS.
+        let local = listener
+            .local_addr()
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EINVAL))?;
+
+        Ok(TcpListener {
+            listener,
+            handle: None,
+            endpoint: TcpAddress::from_socket_addr(local).to_string(),
+            options: options.clone(),
+            socket,
+            ctx: Arc::clone(ctx),
+            sessions: Vec::new(),
+        })
     }
 
-    pub fn accept(&self) -> io::Result<RawFd> {
-        let (socket, _) = self.inner.accept()?;
-        let fd = socket.as_raw_fd();
-
-        // Configure accepted socket
-        self.set_nosigpipe(fd)?;
-        
-        if self.options.tos != 0 {
-            self.set_ip_type_of_service(fd, self.options.tos)?;
-        }
-
-        if self.options.priority != 0 {
-            self.set_socket_priority(fd, self.options.priority)?;
-        }
-
-        // Apply TCP accept filters
-        if !self.options.tcp_accept_filters.is_empty() {
-            if !self.match_accept_filters(&socket) {
-                return Err(Error::new(ErrorKind::Other, "Connection filtered"));
-            }
-        }
-
-        Ok(fd)
+    /// Endpoint the listener is bound to, as reported by ZMQ_LAST_ENDPOINT.
+    pub fn get_local_address(&self) -> &str {
+        &self.endpoint
     }
 
-    fn get_socket_name(&self, fd: RawFd, end: SocketEnd) -> String {
-        // Implementation for getting socket name
-        match end {
-            SocketEnd::Local => self.inner.local_addr()
-                                  .map(|addr| addr.to_string())
-                                  .unwrap_or_default(),
-            SocketEnd::Remote => String::new(),
-        }
+    // Accept the new connection. Returns the connected socket, or None
+    // if the connection had been dropped already or could not be set up.
+    fn accept(&mut self) -> Option<TcpStream> {
+        let (stream, _) = self.listener.accept().ok()?;
+        stream.set_nonblocking(true).ok()?;
+        tune_tcp_connection(&stream, &self.options).ok()?;
+        Some(stream)
     }
+}
 
-    // Helper methods for socket configuration
-    fn set_nonblocking(&self, fd: RawFd) -> io::Result<()> {
-        // Platform-specific non-blocking setup
-        #[cfg(unix)]
-        unsafe {
-            use libc::{fcntl, F_GETFL, F_SETFL, O_NONBLOCK};
-            let flags = fcntl(fd, F_GETFL);
-            fcntl(fd, F_SETFL, flags | O_NONBLOCK);
-        }
-        Ok(())
+impl IoObject for TcpListener {
+    fn process_plug(&mut self, io: &mut IoContext) {
+        // Start polling for incoming connections.
+        let handle = io.add_fd(self.listener.as_raw_fd());
+        io.set_pollin(handle);
+        self.handle = Some(handle);
     }
 
-    fn set_reuse_address(&self, fd: RawFd) -> io::Result<()> {
-        // Platform-specific address reuse setup
-        Ok(())
+    fn process_command(&mut self, cmd: Command, io: &mut IoContext) {
+        if let Command::Term(linger) = cmd {
+            // Take the sessions of the accepted connections down too.
+            for session in self.sessions.drain(..) {
+                session.send(Command::Term(linger));
+            }
+            if let Some(handle) = self.handle.take() {
+                io.rm_fd(handle);
+            }
+            io.terminate();
+        }
     }
+}
 
-    fn set_nosigpipe(&self, fd: RawFd) -> io::Result<()> {
-        // Platform-specific SIGPIPE handling
-        Ok(())
+impl IPollEvents for TcpListener {
+    fn in_event(&mut self, io: &mut IoContext) {
+        // If connection was reset by the peer in the meantime, just ignore it.
+        let stream = match self.accept() {
+            Some(stream) => stream,
+            None => return,
+        };
+
+        let remote = stream
+            .peer_addr()
+            .map(|addr| TcpAddress::from_socket_addr(addr).to_string())
+            .unwrap_or_default();
+        let endpoint_pair =
+            EndpointUriPair::with_values(&self.endpoint, &remote, EndpointType::Bind);
+
+        // Create the engine object for this connection.
+        let fd = stream.into_raw_fd();
+        let engine: Box<dyn IEngine> = if self.options.raw_socket {
+            Box::new(RawEngine::new(fd, &self.options, endpoint_pair))
+        } else {
+            Box::new(ZmtpEngine::new(fd, &self.options, endpoint_pair))
+        };
+
+        // Create and launch a session object.
+        let session = Session::new_passive(
+            &self.ctx,
+            &self.options,
+            Arc::clone(&self.socket),
+            &self.endpoint,
+            engine,
+        );
+        self.sessions.retain(|session| io.is_alive(session));
+        self.sessions.push(io.launch(Box::new(session)));
     }
-}
 
-// Helper enums and structs
-enum SocketEnd {
-    Local,
-    Remote,
-}
+    fn out_event(&mut self, _io: &mut IoContext) {}
 
-// Placeholder structs - these would need to be properly implemented
-struct IoThread;
-struct SocketBase;
-struct Options {
-    use_fd: i32,
-    tos: u32,
-    priority: i32,
-    tcp_accept_filters: Vec<TcpAcceptFilter>,
+    fn timer_event(&mut self, _id: i32, _io: &mut IoContext) {}
 }
-
-struct TcpAcceptFilter;
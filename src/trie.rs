@@ -1,19 +1,13 @@
+use std::collections::BTreeMap;
 use std::sync::atomic::{AtomicU32, Ordering};
 
-enum NextNode {
-    None,
-    Single(Box<Trie>),
-    Table(Vec<Option<Box<Trie>>>)
-}
-
+/// Reference-counted prefix tree holding subscriptions.
 pub struct Trie {
     refcnt: u32,
-    min: u8,
-    count: u16,
-    live_nodes: u16,
-    next: NextNode,
+    next: BTreeMap<u8, Box<Trie>>,
 }
 
+/// Trie that also keeps track of the number of distinct prefixes.
 pub struct TrieWithSize {
     num_prefixes: AtomicU32,
     trie: Trie,
@@ -23,127 +17,112 @@ impl Trie {
     pub fn new() -> Self {
         Trie {
             refcnt: 0,
-            min: 0,
-            count: 0,
-            live_nodes: 0,
-            next: NextNode::None,
+            next: BTreeMap::new(),
         }
     }
 
+    /// Add key to the trie. Returns true if this is a new item in the trie
+    /// rather than a duplicate.
     pub fn add(&mut self, prefix: &[u8]) -> bool {
-        if prefix.is_empty() {
-            self.refcnt += 1;
-            return self.refcnt == 1;
-        }
-
-        let c = prefix[0];
-        
-        if self.count == 0 || c < self.min || c >= self.min + self.count as u8 {
-            // Extend table
-            if self.count == 0 {
-                self.min = c;
-                self.count = 1;
-                self.next = NextNode::None;
-            } else if self.count == 1 {
-                let old_c = self.min;
-                let old_node = match std::mem::replace(&mut self.next, NextNode::None) {
-                    NextNode::Single(node) => node,
-                    _ => unreachable!()
-                };
-                
-                self.count = std::cmp::max(
-                    (if self.min < c { c - self.min } else { self.min - c }) + 1,
-                    1
-                ) as u16;
-                self.min = std::cmp::min(self.min, c);
-                
-                let mut table = vec![None; self.count as usize];
-                table[(old_c - self.min) as usize] = Some(old_node);
-                self.next = NextNode::Table(table);
-            } else {
-                match &mut self.next {
-                    NextNode::Table(table) => {
-                        if self.min < c {
-                            let new_count = (c - self.min + 1) as u16;
-                            table.resize(new_count as usize, None);
-                            self.count = new_count;
-                        } else {
-                            let old_min = self.min;
-                            self.min = c;
-                            let new_count = (old_min + self.count as u8 - c) as u16;
-                            let mut new_table = vec![None; new_count as usize];
-                            new_table[(old_min - c) as usize..]
-                                .clone_from_slice(&table[..]);
-                            self.count = new_count;
-                            self.next = NextNode::Table(new_table);
-                        }
-                    }
-                    _ => unreachable!()
-                }
+        match prefix.split_first() {
+            None => {
+                self.refcnt += 1;
+                self.refcnt == 1
             }
+            Some((c, rest)) => self
+                .next
+                .entry(*c)
+                .or_insert_with(|| Box::new(Trie::new()))
+                .add(rest),
         }
+    }
 
-        // Add node
-        match &mut self.next {
-            NextNode::None => {
-                let mut new_node = Box::new(Trie::new());
-                self.live_nodes += 1;
-                let result = new_node.add(&prefix[1..]);
-                self.next = NextNode::Single(new_node);
-                result
-            }
-            NextNode::Single(ref mut node) => {
-                node.add(&prefix[1..])
-            }
-            NextNode::Table(table) => {
-                let idx = (c - self.min) as usize;
-                if table[idx].is_none() {
-                    table[idx] = Some(Box::new(Trie::new()));
-                    self.live_nodes += 1;
+    /// Remove key from the trie. Returns true if the item is actually
+    /// removed from the trie.
+    pub fn rm(&mut self, prefix: &[u8]) -> bool {
+        let (c, rest) = match prefix.split_first() {
+            None => {
+                if self.refcnt == 0 {
+                    return false;
                 }
-                table[idx].as_mut().unwrap().add(&prefix[1..])
+                self.refcnt -= 1;
+                return self.refcnt == 0;
             }
+            Some(split) => split,
+        };
+
+        let next = match self.next.get_mut(c) {
+            Some(next) => next,
+            None => return false,
+        };
+        let removed = next.rm(rest);
+        if next.is_redundant() {
+            self.next.remove(c);
         }
+        removed
     }
 
+    /// Check whether particular key is in the trie.
     pub fn check(&self, data: &[u8]) -> bool {
+        // This function is on critical path. It deliberately doesn't use
+        // recursion to get a bit better performance.
         let mut current = self;
-        let mut data = data;
-
+        let mut data = data.iter();
         loop {
+            // We've found a corresponding subscription!
             if current.refcnt > 0 {
                 return true;
             }
 
-            if data.is_empty() {
-                return false;
-            }
+            // We've checked all the data and haven't found matching
+            // subscription.
+            let c = match data.next() {
+                Some(c) => c,
+                None => return false,
+            };
+
+            // If there's no corresponding slot for the first character
+            // of the prefix, the message does not match.
+            current = match current.next.get(c) {
+                Some(next) => next,
+                None => return false,
+            };
+        }
+    }
 
-            let c = data[0];
-            if c < current.min || c >= current.min + current.count as u8 {
-                return false;
-            }
+    /// Apply the function supplied to each subscription in the trie.
+    pub fn apply<F>(&self, mut func: F)
+    where
+        F: FnMut(&[u8]),
+    {
+        let mut buf = Vec::new();
+        self.apply_helper(&mut buf, &mut func);
+    }
 
-            match &current.next {
-                NextNode::Single(node) => {
-                    current = node;
-                }
-                NextNode::Table(table) => {
-                    if let Some(node) = &table[(c - current.min) as usize] {
-                        current = node;
-                    } else {
-                        return false;
-                    }
-                }
-                NextNode::None => return false,
-            }
+    fn apply_helper<F>(&self, buf: &mut Vec<u8>, func: &mut F)
+    where
+        F: FnMut(&[u8]),
+    {
+        // If this node is a subscription, apply the function.
+        if self.refcnt > 0 {
+            func(buf);
+        }
 
-            data = &data[1..];
+        for (c, next) in &self.next {
+            buf.push(*c);
+            next.apply_helper(buf, func);
+            buf.pop();
         }
     }
 
     fn is_redundant(&self) -> bool {
-        self.refcnt == 0 && self.live_nodes == 0
+        self.refcnt == 0 && self.next.is_empty()
+    }
+}
+
+impl Default for Trie {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -164,11 +143,33 @@ impl TrieWithSize {
         }
     }
 
+    pub fn rm(&mut self, prefix: &[u8]) -> bool {
+        if self.trie.rm(prefix) {
+            self.num_prefixes.fetch_sub(1, Ordering::SeqCst);
+            true
+        } else {
+            false
+        }
+    }
+
     pub fn check(&self, data: &[u8]) -> bool {
         self.trie.check(data)
     }
 
+    pub fn apply<F>(&self, func: F)
+    where
+        F: FnMut(&[u8]),
+    {
+        self.trie.apply(func)
+    }
+
     pub fn num_prefixes(&self) -> u32 {
         self.num_prefixes.load(Ordering::SeqCst)
     }
 }
+
+impl Default for TrieWithSize {
+    fn default() -> Self {
+        Self::new()
+    }
+}
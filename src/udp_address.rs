@@ -1,6 +1,16 @@
-use std::net::{IpAddr, SocketAddr};
-use std::str::FromStr;
+//! Resolution of UDP endpoint addresses.
+//!
+//! Addresses take the libzmq form `[interface;]host:port`. Without an
+//! interface a multicast host is the group to send to and receive from,
+//! on all interfaces; a unicast host is the address to bind to when
+//! binding and the destination otherwise. The interface, given by name
+//! or address, picks the NIC a multicast group is joined on.
+
+use std::ffi::{CStr, CString};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
 use crate::err::ZmqError;
+use crate::tcp_address;
 
 #[derive(Debug, Clone)]
 pub struct UdpAddress {
@@ -29,91 +39,87 @@ impl UdpAddress {
         Self::default()
     }
 
-    pub fn resolve(&mut self, name: &str, bind: bool, ipv6: bool) -> Result<(), std::io::Error> {
+    /// Resolves `name`. `bind` is set for the receiving side, which allows
+    /// the `*` wildcard; `ipv6` allows IPv6 results.
+    pub fn resolve(&mut self, name: &str, bind: bool, ipv6: bool) -> Result<(), i32> {
         self.address = name.to_string();
 
-        // Handle interface specification (after semicolon)
-        let (bind_part, addr_part) = if let Some(idx) = name.rfind(';') {
-            let (src, dst) = name.split_at(idx);
-            (Some(src), &dst[1..])
-        } else {
-            (None, name)
+        // If we have a semicolon then we should have an interface specifier
+        // in the URL.
+        let (source, name) = match name.rfind(';') {
+            Some(src_delimiter) => (Some(&name[..src_delimiter]), &name[src_delimiter + 1..]),
+            None => (None, name),
         };
 
-        // Resolve the target address
-        let target_addr = SocketAddr::from_str(addr_part)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
-
-        let is_multicast = match target_addr.ip() {
-            IpAddr::V4(addr) => addr.is_multicast(),
-            IpAddr::V6(addr) => addr.is_multicast(),
-        };
+        let mut has_interface = false;
+        if let Some(source) = source {
+            let ip = resolve_source(source, ipv6)?;
+            // It doesn't make sense to have a multicast address as a source.
+            if ip.is_multicast() {
+                return Err(libc::EINVAL);
+            }
+            self.bind_address = SocketAddr::new(ip, 0);
 
-        if ipv6 && target_addr.is_ipv4() {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                "IPv6 requested but address is IPv4",
-            ));
+            // The interface index is needed to join IPv6 groups, which can't
+            // be done by address.
+            self.bind_interface = CString::new(source)
+                .map(|name| unsafe { libc::if_nametoindex(name.as_ptr()) } as i32)
+                .unwrap_or(0);
+            if self.bind_interface == 0 {
+                self.bind_interface = -1;
+            }
+            has_interface = true;
         }
 
-        // Handle binding address
-        if let Some(bind_addr) = bind_part {
-            if bind_addr == "*" {
-                self.bind_interface = 0;
-                self.bind_address = if ipv6 {
-                    SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], target_addr.port()))
-                } else {
-                    SocketAddr::from(([0, 0, 0, 0], target_addr.port()))
-                };
-            } else {
-                #[cfg(unix)]
-                {
-                    use std::ffi::CString;
-                    use std::os::unix::ffi::OsStrExt;
-                    use std::path::Path;
-                    
-                    let if_name = Path::new(bind_addr);
-                    if let Ok(if_name_cstr) = CString::new(if_name.as_os_str().as_bytes()) {
-                        unsafe {
-                            self.bind_interface = libc::if_nametoindex(if_name_cstr.as_ptr()) as i32;
-                        }
-                    }
-                }
-                
-                if self.bind_interface == 0 {
-                    self.bind_interface = -1;
-                }
+        self.target_address = tcp_address::resolve_address(name, bind, ipv6)?;
+        self.is_multicast = self.target_address.ip().is_multicast();
+        let port = self.target_address.port();
+
+        if has_interface {
+            // If we have an interface specifier then the target address must
+            // be a multicast address.
+            if !self.is_multicast {
+                return Err(libc::EINVAL);
             }
-        } else if is_multicast || !bind {
-            self.bind_address = if ipv6 {
-                SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], target_addr.port()))
-            } else {
-                SocketAddr::from(([0, 0, 0, 0], target_addr.port()))
-            };
+            self.bind_address.set_port(port);
+        } else if self.is_multicast || !bind {
+            // If we don't have an explicit interface specifier then the URL
+            // is ambiguous: if the target address is multicast then it's the
+            // destination address and the bind address is ANY, if it's
+            // unicast then it's the bind address when 'bind' is true and the
+            // destination otherwise.
+            self.bind_address = SocketAddr::new(any(self.target_address.is_ipv6()), port);
             self.bind_interface = 0;
         } else {
-            self.bind_address = target_addr;
+            // If we were asked for a bind socket and the address provided
+            // was not multicast then it was really meant as a bind address
+            // and the target address is useless.
+            self.bind_address = self.target_address;
         }
 
-        self.target_address = target_addr;
-        self.is_multicast = is_multicast;
+        if self.bind_address.is_ipv6() != self.target_address.is_ipv6() {
+            return Err(libc::EINVAL);
+        }
 
-        if ipv6 && is_multicast && self.bind_interface < 0 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                "No interface specified for IPv6 multicast",
-            ));
+        // For IPv6 multicast we *must* have an interface index since we
+        // can't bind by address.
+        if ipv6 && self.is_multicast && self.bind_interface < 0 {
+            return Err(libc::ENODEV);
         }
 
         Ok(())
     }
 
-    pub fn to_string(&self) -> Result<String,ZmqError> {
-        Ok(self.address.clone())
+    pub fn to_string(&self) -> Result<String, ZmqError> {
+        Ok(format!("udp://{}", self.address))
     }
 
-    pub fn family(&self) -> i32 {
-        if self.bind_address.is_ipv4() { 2 } else { 10 }  // AF_INET or AF_INET6
+    pub fn family(&self) -> libc::c_int {
+        if self.bind_address.is_ipv4() {
+            libc::AF_INET
+        } else {
+            libc::AF_INET6
+        }
     }
 
     pub fn is_mcast(&self) -> bool {
@@ -132,3 +138,119 @@ impl UdpAddress {
         &self.target_address
     }
 }
+
+fn any(ipv6: bool) -> IpAddr {
+    if ipv6 {
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    }
+}
+
+// The source part is `*`, an address or the name of a network interface.
+fn resolve_source(source: &str, ipv6: bool) -> Result<IpAddr, i32> {
+    if source == "*" {
+        return Ok(any(ipv6));
+    }
+
+    let host = source
+        .strip_prefix('[')
+        .and_then(|rest| rest.strip_suffix(']'))
+        .unwrap_or(source);
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        if ip.is_ipv6() && !ipv6 {
+            return Err(libc::EINVAL);
+        }
+        return Ok(ip);
+    }
+
+    resolve_nic_name(source, ipv6).ok_or(libc::ENODEV)
+}
+
+// Returns the first address of the interface, preferring IPv4.
+fn resolve_nic_name(nic: &str, ipv6: bool) -> Option<IpAddr> {
+    let mut ifa: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifa) } == -1 {
+        return None;
+    }
+
+    let mut found: Option<IpAddr> = None;
+    let mut cursor = ifa;
+    while !cursor.is_null() {
+        let entry = unsafe { &*cursor };
+        cursor = entry.ifa_next;
+        if entry.ifa_addr.is_null()
+            || unsafe { CStr::from_ptr(entry.ifa_name) }.to_bytes() != nic.as_bytes()
+        {
+            continue;
+        }
+
+        let ip = match unsafe { (*entry.ifa_addr).sa_family } as libc::c_int {
+            libc::AF_INET => {
+                let sin = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in) };
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)))
+            }
+            libc::AF_INET6 if ipv6 => {
+                let sin6 = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in6) };
+                IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr))
+            }
+            _ => continue,
+        };
+        if found.map_or(true, |found| found.is_ipv6() && ip.is_ipv4()) {
+            found = Some(ip);
+        }
+    }
+
+    unsafe { libc::freeifaddrs(ifa) };
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unicast_bind_and_connect() {
+        let mut addr = UdpAddress::new();
+        addr.resolve("127.0.0.1:5555", true, false).unwrap();
+        assert!(!addr.is_mcast());
+        assert_eq!(
+            *addr.bind_addr(),
+            "127.0.0.1:5555".parse::<SocketAddr>().unwrap()
+        );
+
+        let mut addr = UdpAddress::new();
+        addr.resolve("127.0.0.1:5555", false, false).unwrap();
+        assert_eq!(
+            *addr.bind_addr(),
+            "0.0.0.0:5555".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            *addr.target_addr(),
+            "127.0.0.1:5555".parse::<SocketAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_multicast_with_interface() {
+        let mut addr = UdpAddress::new();
+        addr.resolve("127.0.0.1;239.0.0.1:5555", true, false)
+            .unwrap();
+        assert!(addr.is_mcast());
+        assert_eq!(
+            *addr.bind_addr(),
+            "127.0.0.1:5555".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            *addr.target_addr(),
+            "239.0.0.1:5555".parse::<SocketAddr>().unwrap()
+        );
+
+        // The interface only makes sense for multicast groups.
+        let mut addr = UdpAddress::new();
+        assert_eq!(
+            addr.resolve("127.0.0.1;127.0.0.2:5555", true, false),
+            Err(libc::EINVAL)
+        );
+    }
+}
@@ -1,234 +1,439 @@
-use std::io::{self, Error, ErrorKind};
+//! Engine for the datagram transport of RADIO and DISH.
+//!
+//! Each message travels as one datagram: a byte with the length of the
+//! group, the group itself, then the body. The radio side only sends and
+//! the dish side only receives, so there's no handshake and no way to
+//! forward the dish's joins; the dish filters on its groups locally.
+
 use std::mem;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::RawFd;
+
+use crate::endpoint::EndpointUriPair;
+use crate::i_engine::{ErrorReason, IEngine};
+use crate::message::{Message, MsgFlags};
+use crate::options::Options;
+use crate::poller_base::{Handle, IoContext};
+use crate::session_base::SessionBase;
+use crate::tcp::{to_sockaddr, unblock_socket};
+use crate::udp_address::UdpAddress;
 
 const MAX_UDP_MSG: usize = 8192;
 
 pub struct UdpEngine {
-    plugged: bool,
-    socket: Option<UdpSocket>,
-    session: Option<Box<dyn SessionBase>>,
+    fd: RawFd,
+    handle: Option<Handle>,
+    address: UdpAddress,
     options: Options,
-    out_buffer: [u8; MAX_UDP_MSG],
-    in_buffer: [u8; MAX_UDP_MSG],
-    send_enabled: bool,
-    recv_enabled: bool,
-    out_address: Option<SocketAddr>,
-}
+    empty_endpoint: EndpointUriPair,
 
-// Trait to represent session interface
-pub trait SessionBase {
-    fn push_msg(&mut self, data: &[u8], more: bool) -> io::Result<()>;
-    fn pull_msg(&mut self) -> io::Result<Vec<u8>>;
-    fn engine_error(&mut self, handshake: bool, reason: ErrorReason);
-    fn flush(&mut self);
-    fn reset(&mut self);
-}
-
-#[derive(Clone)]
-pub struct Options {
-    raw_socket: bool,
-    multicast_loop: bool,
-    multicast_hops: i32,
-    bound_device: String,
-}
+    // Where the datagrams go, set when sending is enabled.
+    out_address: Option<SocketAddr>,
 
-#[derive(Debug)]
-pub enum ErrorReason {
-    ConnectionError,
-    ProtocolError,
+    out_buffer: Vec<u8>,
+    in_buffer: Vec<u8>,
+    send_enabled: bool,
+    recv_enabled: bool,
 }
 
 impl UdpEngine {
-    pub fn new(options: Options) -> Self {
+    pub fn new(options: &Options, address: UdpAddress, send: bool, recv: bool) -> Self {
         UdpEngine {
-            plugged: false,
-            socket: None,
-            session: None,
-            options,
-            out_buffer: [0; MAX_UDP_MSG],
-            in_buffer: [0; MAX_UDP_MSG],
-            send_enabled: false,
-            recv_enabled: false,
+            fd: -1,
+            handle: None,
+            address,
+            options: options.clone(),
+            empty_endpoint: EndpointUriPair::new(),
             out_address: None,
+            out_buffer: vec![0; MAX_UDP_MSG],
+            in_buffer: vec![0; MAX_UDP_MSG],
+            send_enabled: send,
+            recv_enabled: recv,
         }
     }
 
-    pub fn init(&mut self, addr: SocketAddr, send: bool, recv: bool) -> io::Result<()> {
-        self.send_enabled = send;
-        self.recv_enabled = recv;
+    // Opens and sets up the socket: the multicast options for sending, the
+    // bind and the group membership for receiving.
+    fn open(&mut self) -> Result<(), i32> {
+        let family = self.address.family();
+        self.fd = unsafe { libc::socket(family, libc::SOCK_DGRAM, libc::IPPROTO_UDP) };
+        if self.fd == -1 {
+            return Err(last_errno());
+        }
+        unblock_socket(self.fd)?;
 
-        let socket = UdpSocket::bind("0.0.0.0:0")?;
-        socket.set_nonblocking(true)?;
+        // Bind the socket to a device if applicable
+        if !self.options.bound_device.is_empty() {
+            bind_to_device(self.fd, &self.options.bound_device)?;
+        }
 
-        if self.options.raw_socket {
-            // Additional raw socket setup if needed
+        let ipv6 = family == libc::AF_INET6;
+        if self.send_enabled {
+            let out = *self.address.target_addr();
+            if out.ip().is_multicast() {
+                set_multicast_loop(self.fd, ipv6, self.options.multicast_loop)?;
+                if self.options.multicast_hops > 0 {
+                    set_multicast_ttl(self.fd, ipv6, self.options.multicast_hops)?;
+                }
+                set_multicast_iface(self.fd, &self.address)?;
+            }
+            self.out_address = Some(out);
         }
 
-        self.socket = Some(socket);
-        Ok(())
-    }
+        if self.recv_enabled {
+            set_int_option(self.fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
 
-    pub fn plug(&mut self, session: Box<dyn SessionBase>) -> io::Result<()> {
-        self.plugged = true;
-        self.session = Some(session);
+            let mut bind_addr = *self.address.bind_addr();
+            let multicast = self.address.is_mcast();
+            if multicast {
+                // Multicast addresses should be allowed to bind to more than
+                // one port as all ports should receive the message
+                set_int_option(self.fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
 
-        if let Some(socket) = &self.socket {
-            if !self.options.bound_device.is_empty() {
-                // Bind to device if specified
+                // In multicast we should bind ANY and use the mreq struct to
+                // specify the interface
+                let any = match bind_addr {
+                    SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                };
+                bind_addr.set_ip(any);
             }
 
-            if self.send_enabled {
-                self.setup_send(socket)?;
+            let (storage, len) = to_sockaddr(&bind_addr);
+            let rc =
+                unsafe { libc::bind(self.fd, &storage as *const _ as *const libc::sockaddr, len) };
+            if rc == -1 {
+                return Err(last_errno());
             }
 
-            if self.recv_enabled {
-                self.setup_recv(socket)?;
+            if multicast {
+                add_membership(self.fd, &self.address)?;
             }
         }
-        
+
         Ok(())
     }
 
-    fn setup_send(&mut self, socket: &UdpSocket) -> io::Result<()> {
-        if !self.options.raw_socket {
-            if let Some(addr) = &self.out_address {
-                if addr.is_ipv4() || addr.is_ipv6() {
-                    self.set_multicast_options(socket)?;
-                }
-            }
-        }
-        Ok(())
+    fn error(&mut self, reason: ErrorReason, session: &mut SessionBase, io: &mut IoContext) {
+        session.engine_error(false, reason);
+        self.terminate(io);
     }
+}
 
-    fn setup_recv(&self, socket: &UdpSocket) -> io::Result<()> {
-        socket.set_reuse_address(true)?;
-        #[cfg(target_os = "linux")]
-        socket.set_reuse_port(true)?;
-        Ok(())
+impl IEngine for UdpEngine {
+    fn has_handshake_stage(&self) -> bool {
+        false
     }
 
-    fn set_multicast_options(&self, socket: &UdpSocket) -> io::Result<()> {
-        // Set multicast TTL
-        if self.options.multicast_hops > 0 {
-            socket.set_multicast_ttl_v4(self.options.multicast_hops as u32)?;
+    fn plug(&mut self, session: &mut SessionBase, io: &mut IoContext) {
+        if self.open().is_err() {
+            self.error(ErrorReason::ProtocolError, session, io);
+            return;
+        }
+
+        let handle = io.add_fd(self.fd);
+        self.handle = Some(handle);
+        if self.send_enabled {
+            io.set_pollout(handle);
+        }
+        if self.recv_enabled {
+            io.set_pollin(handle);
+
+            // Call restart output to drop all join/leave commands
+            self.restart_output(session, io);
         }
-        
-        // Set multicast loopback
-        socket.set_multicast_loop_v4(self.options.multicast_loop)?;
-        
-        Ok(())
     }
 
-    pub fn out_event(&mut self) -> io::Result<()> {
-        if let Some(session) = &mut self.session {
-            // Try to get a message from the session
-            match session.pull_msg() {
-                Ok(group_msg) => {
-                    // Handle the message...
-                    if let Ok(body_msg) = session.pull_msg() {
-                        if self.options.raw_socket {
-                            self.send_raw(&group_msg, &body_msg)?;
-                        } else {
-                            self.send_formatted(&group_msg, &body_msg)?;
-                        }
-                    }
-                }
-                Err(_) => return Ok(()),
-            }
+    fn terminate(&mut self, io: &mut IoContext) {
+        if let Some(handle) = self.handle.take() {
+            io.rm_fd(handle);
+        }
+        if self.fd != -1 {
+            unsafe { libc::close(self.fd) };
+            self.fd = -1;
         }
-        Ok(())
     }
 
-    pub fn in_event(&mut self) -> io::Result<()> {
-        if let Some(socket) = &self.socket {
-            let mut buf = [0u8; MAX_UDP_MSG];
-            match socket.recv_from(&mut buf) {
-                Ok((size, addr)) => {
-                    if let Some(session) = &mut self.session {
-                        self.handle_received_data(&buf[..size], addr, session)?;
-                    }
-                }
-                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
-                Err(e) => return Err(e),
+    fn restart_input(&mut self, session: &mut SessionBase, io: &mut IoContext) -> bool {
+        if self.recv_enabled {
+            if let Some(handle) = self.handle {
+                io.set_pollin(handle);
             }
+            self.in_event(session, io);
         }
-        Ok(())
+        true
     }
 
-    fn handle_received_data(
-        &mut self,
-        data: &[u8],
-        addr: SocketAddr,
-        session: &mut Box<dyn SessionBase>
-    ) -> io::Result<()> {
-        if self.options.raw_socket {
-            // Handle raw socket data
-            let addr_str = addr.to_string().into_bytes();
-            session.push_msg(&addr_str, true)?;
-            session.push_msg(data, false)?;
+    fn restart_output(&mut self, session: &mut SessionBase, io: &mut IoContext) {
+        // If we don't support send we just drop all messages
+        if !self.send_enabled {
+            let mut msg = Message::new();
+            while session.pull_msg(&mut msg).is_ok() {}
         } else {
-            // Handle formatted data
-            if data.len() < 1 {
-                return Ok(());
+            if let Some(handle) = self.handle {
+                io.set_pollout(handle);
             }
-            let group_size = data[0] as usize;
-            if data.len() < group_size + 1 {
-                return Ok(());
+            self.out_event(session, io);
+        }
+    }
+
+    fn zap_msg_available(&mut self, _session: &mut SessionBase, _io: &mut IoContext) {}
+
+    fn get_endpoint(&self) -> &EndpointUriPair {
+        &self.empty_endpoint
+    }
+
+    fn in_event(&mut self, session: &mut SessionBase, io: &mut IoContext) {
+        let mut in_address: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut in_addrlen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        let nbytes = unsafe {
+            libc::recvfrom(
+                self.fd,
+                self.in_buffer.as_mut_ptr() as *mut libc::c_void,
+                MAX_UDP_MSG,
+                0,
+                &mut in_address as *mut _ as *mut libc::sockaddr,
+                &mut in_addrlen,
+            )
+        };
+        if nbytes < 0 {
+            let errno = last_errno();
+            if errno != libc::EWOULDBLOCK && errno != libc::EINTR {
+                self.error(ErrorReason::ConnectionError, session, io);
             }
-            session.push_msg(&data[1..=group_size], true)?;
-            session.push_msg(&data[group_size+1..], false)?;
+            return;
         }
+        let datagram = &self.in_buffer[..nbytes as usize];
+
+        // This doesn't fit, just ignore
+        let group_size = match datagram.first() {
+            Some(&size) if datagram.len() > size as usize => size as usize,
+            _ => return,
+        };
+        let (group, body) = datagram[1..].split_at(group_size);
+
+        // Push group description to session
+        let mut msg = match Message::with_data(group) {
+            Ok(msg) => msg,
+            Err(_) => return,
+        };
+        msg.set_flags(MsgFlags::More);
+
+        // Group description message doesn't fit in the pipe, drop
+        if session.push_msg(&mut msg).is_err() {
+            if let Some(handle) = self.handle {
+                io.reset_pollin(handle);
+            }
+            return;
+        }
+
+        // Push message body to session
+        let pushed = match Message::with_data(body) {
+            Ok(mut msg) => session.push_msg(&mut msg),
+            Err(_) => Err(libc::ENOMEM),
+        };
+
+        // Message body doesn't fit in the pipe, drop and reset session state
+        if pushed.is_err() {
+            session.reset();
+            if let Some(handle) = self.handle {
+                io.reset_pollin(handle);
+            }
+            return;
+        }
+
         session.flush();
-        Ok(())
     }
 
-    fn send_raw(&self, addr: &[u8], data: &[u8]) -> io::Result<()> {
-        if let Some(socket) = &self.socket {
-            // Parse address from addr and send data
-            let addr_str = String::from_utf8_lossy(addr);
-            if let Ok(socket_addr) = addr_str.parse() {
-                socket.send_to(data, socket_addr)?;
+    fn out_event(&mut self, session: &mut SessionBase, io: &mut IoContext) {
+        let mut group_msg = Message::new();
+        if session.pull_msg(&mut group_msg).is_err() {
+            if let Some(handle) = self.handle {
+                io.reset_pollout(handle);
+            }
+            return;
+        }
+
+        // If there's a group, there should also be a body
+        let mut body_msg = Message::new();
+        let rc = session.pull_msg(&mut body_msg);
+        debug_assert!(rc.is_ok());
+
+        let group = group_msg.data();
+        let body = body_msg.data();
+        let size = 1 + group.len() + body.len();
+        if size > MAX_UDP_MSG {
+            // Too large for a datagram, drop it.
+            return;
+        }
+        self.out_buffer[0] = group.len() as u8;
+        self.out_buffer[1..1 + group.len()].copy_from_slice(group);
+        self.out_buffer[1 + group.len()..size].copy_from_slice(body);
+
+        let out = match &self.out_address {
+            Some(out) => to_sockaddr(out),
+            None => return,
+        };
+        let rc = unsafe {
+            libc::sendto(
+                self.fd,
+                self.out_buffer.as_ptr() as *const libc::c_void,
+                size,
+                0,
+                &out.0 as *const _ as *const libc::sockaddr,
+                out.1,
+            )
+        };
+        if rc < 0 {
+            let errno = last_errno();
+            if errno != libc::EWOULDBLOCK && errno != libc::EINTR {
+                self.error(ErrorReason::ConnectionError, session, io);
             }
         }
-        Ok(())
     }
 
-    fn send_formatted(&self, group: &[u8], body: &[u8]) -> io::Result<()> {
-        if let Some(socket) = &self.socket {
-            if let Some(addr) = &self.out_address {
-                let mut msg = Vec::with_capacity(1 + group.len() + body.len());
-                msg.push(group.len() as u8);
-                msg.extend_from_slice(group);
-                msg.extend_from_slice(body);
-                socket.send_to(&msg, addr)?;
+    fn timer_event(&mut self, _id: i32, _session: &mut SessionBase, _io: &mut IoContext) {}
+}
+
+impl Drop for UdpEngine {
+    fn drop(&mut self) {
+        if self.fd != -1 {
+            unsafe {
+                libc::close(self.fd);
             }
         }
-        Ok(())
     }
+}
 
-    pub fn terminate(&mut self) {
-        self.plugged = false;
-        self.socket = None;
-        self.session = None;
+fn last_errno() -> i32 {
+    std::io::Error::last_os_error()
+        .raw_os_error()
+        .unwrap_or(libc::EIO)
+}
+
+fn set_option<T>(fd: RawFd, level: libc::c_int, name: libc::c_int, value: &T) -> Result<(), i32> {
+    let rc = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if rc == -1 {
+        return Err(last_errno());
     }
+    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_udp_engine_creation() {
-        let options = Options {
-            raw_socket: false,
-            multicast_loop: false,
-            multicast_hops: 1,
-            bound_device: String::new(),
+fn set_int_option(
+    fd: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> Result<(), i32> {
+    set_option(fd, level, name, &value)
+}
+
+fn bind_to_device(fd: RawFd, device: &str) -> Result<(), i32> {
+    #[cfg(target_os = "linux")]
+    {
+        let rc = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_BINDTODEVICE,
+                device.as_ptr() as *const libc::c_void,
+                device.len() as libc::socklen_t,
+            )
         };
-        let engine = UdpEngine::new(options);
-        assert!(!engine.plugged);
-        assert!(engine.socket.is_none());
+        if rc == -1 {
+            return Err(last_errno());
+        }
+        Ok(())
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (fd, device);
+        Err(libc::ENOTSUP)
+    }
+}
+
+fn set_multicast_loop(fd: RawFd, ipv6: bool, on: bool) -> Result<(), i32> {
+    if ipv6 {
+        set_int_option(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MULTICAST_LOOP,
+            on as libc::c_int,
+        )
+    } else {
+        set_int_option(
+            fd,
+            libc::IPPROTO_IP,
+            libc::IP_MULTICAST_LOOP,
+            on as libc::c_int,
+        )
+    }
+}
+
+fn set_multicast_ttl(fd: RawFd, ipv6: bool, hops: i32) -> Result<(), i32> {
+    if ipv6 {
+        set_int_option(fd, libc::IPPROTO_IPV6, libc::IPV6_MULTICAST_HOPS, hops)
+    } else {
+        set_int_option(fd, libc::IPPROTO_IP, libc::IP_MULTICAST_TTL, hops)
+    }
+}
+
+// Sends the multicast datagrams through the interface of the address.
+fn set_multicast_iface(fd: RawFd, address: &UdpAddress) -> Result<(), i32> {
+    match address.bind_addr() {
+        SocketAddr::V6(_) => {
+            let bind_if = address.bind_if();
+            if bind_if <= 0 {
+                return Ok(());
+            }
+            set_int_option(fd, libc::IPPROTO_IPV6, libc::IPV6_MULTICAST_IF, bind_if)
+        }
+        SocketAddr::V4(bind_addr) => {
+            if bind_addr.ip().is_unspecified() {
+                return Ok(());
+            }
+            let iface = libc::in_addr {
+                s_addr: u32::from(*bind_addr.ip()).to_be(),
+            };
+            set_option(fd, libc::IPPROTO_IP, libc::IP_MULTICAST_IF, &iface)
+        }
+    }
+}
+
+// Joins the multicast group on the interface of the address.
+fn add_membership(fd: RawFd, address: &UdpAddress) -> Result<(), i32> {
+    match (address.target_addr(), address.bind_addr()) {
+        (SocketAddr::V6(target), _) => {
+            let mreq = libc::ipv6_mreq {
+                ipv6mr_multiaddr: libc::in6_addr {
+                    s6_addr: target.ip().octets(),
+                },
+                ipv6mr_interface: address.bind_if().max(0) as libc::c_uint,
+            };
+            set_option(fd, libc::IPPROTO_IPV6, libc::IPV6_ADD_MEMBERSHIP, &mreq)
+        }
+        (SocketAddr::V4(target), bind_addr) => {
+            let interface = match bind_addr {
+                SocketAddr::V4(bind_addr) => u32::from(*bind_addr.ip()),
+                SocketAddr::V6(_) => 0,
+            };
+            let mreq = libc::ip_mreq {
+                imr_multiaddr: libc::in_addr {
+                    s_addr: u32::from(*target.ip()).to_be(),
+                },
+                imr_interface: libc::in_addr {
+                    s_addr: interface.to_be(),
+                },
+            };
+            set_option(fd, libc::IPPROTO_IP, libc::IP_ADD_MEMBERSHIP, &mreq)
+        }
     }
 }
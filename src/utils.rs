@@ -1,23 +1,51 @@
 #![allow(dead_code)]
 
+use std::error::Error;
+use std::fmt;
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::{thread, time};
 
 #[cfg(feature = "curve")]
-use sodiumoxide::crypto::box_::{gen_keypair, PublicKey, SecretKey};
-#[cfg(feature = "curve")]
-use zmq::z85_encode;
+use sodiumoxide::crypto::box_::{gen_keypair, SecretKey};
 
-#[cfg(feature = "curve")]
-pub fn zmq_curve_keypair() -> Result<(String, String), &'static str> {
-    // Generate the keypair
-    let (public_key, secret_key) = gen_keypair();
+use crate::err::ZmqError;
 
-    // Encode the keys in Z85
-    let z85_public_key = z85_encode(&public_key.0).ok_or("Failed to encode public key")?;
-    let z85_secret_key = z85_encode(&secret_key.0).ok_or("Failed to encode secret key")?;
+// Size of a CURVE key, in bytes and encoded as Z85.
+const CURVE_KEYSIZE: usize = 32;
+const CURVE_KEYSIZE_Z85: usize = 40;
 
-    Ok((z85_public_key, z85_secret_key))
+/// Generates a CURVE keypair, returned as the Z85 encoded public and
+/// secret keys. Fails with ENOTSUP when CURVE support isn't built in.
+pub fn curve_keypair() -> Result<(String, String), i32> {
+    #[cfg(feature = "curve")]
+    {
+        let (public_key, secret_key) = gen_keypair();
+        let z85_public_key = z85_encode(&public_key.0).map_err(|_| libc::EINVAL)?;
+        let z85_secret_key = z85_encode(&secret_key.0).map_err(|_| libc::EINVAL)?;
+        Ok((z85_public_key, z85_secret_key))
+    }
+    #[cfg(not(feature = "curve"))]
+    Err(libc::ENOTSUP)
+}
+
+/// Derives the Z85 encoded public key matching a Z85 encoded secret key.
+/// Fails with EINVAL if the secret key isn't a valid 40-character key.
+pub fn curve_public(z85_secret_key: &str) -> Result<String, i32> {
+    #[cfg(feature = "curve")]
+    {
+        if z85_secret_key.len() != CURVE_KEYSIZE_Z85 {
+            return Err(libc::EINVAL);
+        }
+        let decoded = z85_decode(z85_secret_key).map_err(|_| libc::EINVAL)?;
+        let mut secret_key = SecretKey([0; CURVE_KEYSIZE]);
+        secret_key.0.copy_from_slice(&decoded);
+        z85_encode(&secret_key.public_key().0).map_err(|_| libc::EINVAL)
+    }
+    #[cfg(not(feature = "curve"))]
+    {
+        let _ = z85_secret_key;
+        Err(libc::ENOTSUP)
+    }
 }
 
 // Sleep function
@@ -62,59 +90,94 @@ const DECODER: [u8; 96] = [
     0x19, 0x1A, 0x1B, 0x1C, 0x1D, 0x1E, 0x1F, 0x20, 0x21, 0x22, 0x23, 0x4F, 0xFF, 0x50, 0xFF, 0xFF,
 ];
 
-pub fn z85_encode(data: &[u8]) -> Option<String> {
-    if data.len() % 4 != 0 {
-        return None;
-    }
-
-    let mut result = Vec::with_capacity(data.len() * 5 / 4);
-    let mut value: u32 = 0;
+/// Why a Z85 encoding or decoding failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Z85Error {
+    /// The data to encode isn't a multiple of 4 bytes long.
+    InvalidDataLength(usize),
+    /// The string to decode isn't a multiple of 5 characters long.
+    InvalidStringLength(usize),
+    /// The character at this position isn't in the Z85 alphabet.
+    InvalidCharacter(usize),
+    /// The 5 characters ending at this position encode a value above
+    /// 0xffffffff.
+    Overflow(usize),
+}
 
-    for (i, &byte) in data.iter().enumerate() {
-        value = value * 256 + byte as u32;
-        if (i + 1) % 4 == 0 {
-            let mut divisor = 85u32.pow(4);
-            while divisor > 0 {
-                result.push(ENCODER[(value / divisor % 85) as usize]);
-                divisor /= 85;
+impl fmt::Display for Z85Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Z85Error::InvalidDataLength(len) => {
+                write!(f, "data length {} is not a multiple of 4", len)
+            }
+            Z85Error::InvalidStringLength(len) => {
+                write!(f, "string length {} is not a multiple of 5", len)
             }
-            value = 0;
+            Z85Error::InvalidCharacter(pos) => write!(f, "invalid Z85 character at {}", pos),
+            Z85Error::Overflow(pos) => write!(f, "Z85 value overflows 32 bits at {}", pos),
         }
     }
+}
+
+impl Error for Z85Error {}
 
-    String::from_utf8(result).ok()
+impl From<Z85Error> for ZmqError {
+    fn from(err: Z85Error) -> Self {
+        ZmqError::ParsingError(err.to_string())
+    }
 }
 
-pub fn z85_decode(string: &str) -> Option<Vec<u8>> {
-    if string.len() % 5 != 0 || string.len() < 5 {
-        return None;
+/// Encodes binary data as Z85 (RFC 32). The data must be a multiple of
+/// 4 bytes long; the result is 5/4 as long.
+pub fn z85_encode(data: &[u8]) -> Result<String, Z85Error> {
+    if data.len() % 4 != 0 {
+        return Err(Z85Error::InvalidDataLength(data.len()));
     }
 
-    let mut result = Vec::with_capacity(string.len() * 4 / 5);
-    let mut value: u32 = 0;
+    let mut result = String::with_capacity(data.len() * 5 / 4);
+    for chunk in data.chunks_exact(4) {
+        //  Accumulate value in base 256 (binary)
+        let value = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
 
-    for (i, c) in string.bytes().enumerate() {
-        let index = (c as usize).checked_sub(32)?;
-        if index >= DECODER.len() {
-            return None;
-        }
-        let decoded = DECODER[index];
-        if decoded == 0xFF {
-            return None;
+        //  Output value in base 85
+        let mut divisor = 85u32.pow(4);
+        while divisor > 0 {
+            result.push(ENCODER[(value / divisor % 85) as usize] as char);
+            divisor /= 85;
         }
-        value = value.checked_mul(85)?.checked_add(decoded as u32)?;
+    }
+    Ok(result)
+}
 
-        if (i + 1) % 5 == 0 {
-            let mut divisor = 256u32.pow(3);
-            while divisor > 0 {
-                result.push((value / divisor % 256) as u8);
-                divisor /= 256;
-            }
-            value = 0;
-        }
+/// Decodes a Z85 (RFC 32) string. The string must be a multiple of 5
+/// characters long; the result is 4/5 as long.
+pub fn z85_decode<S: AsRef<[u8]>>(string: S) -> Result<Vec<u8>, Z85Error> {
+    let string = string.as_ref();
+    if string.len() % 5 != 0 {
+        return Err(Z85Error::InvalidStringLength(string.len()));
     }
 
-    Some(result)
+    let mut result = Vec::with_capacity(string.len() * 4 / 5);
+    for (chunk_nbr, chunk) in string.chunks_exact(5).enumerate() {
+        //  Accumulate value in base 85
+        let mut value: u32 = 0;
+        for (i, &c) in chunk.iter().enumerate() {
+            let pos = chunk_nbr * 5 + i;
+            let summand = c
+                .checked_sub(32)
+                .and_then(|index| DECODER.get(index as usize))
+                .filter(|&&summand| summand != 0xFF)
+                .ok_or(Z85Error::InvalidCharacter(pos))?;
+            value = value
+                .checked_mul(85)
+                .and_then(|value| value.checked_add(u32::from(*summand)))
+                .ok_or(Z85Error::Overflow(pos))?;
+        }
+
+        //  Output value in base 256
+        result.extend_from_slice(&value.to_be_bytes());
+    }
+    Ok(result)
 }
 
 // Atomic counter
@@ -165,6 +228,90 @@ mod tests {
         assert_eq!(data, decoded);
     }
 
+    // Test vector: rfc.zeromq.org/spec:32/Z85
+    #[test]
+    fn test_z85_rfc_vector() {
+        let decoded = [0x86, 0x4F, 0xD2, 0x6F, 0xB5, 0x59, 0xF7, 0x5B];
+        assert_eq!(z85_encode(&decoded).unwrap(), "HelloWorld");
+        assert_eq!(z85_decode("HelloWorld").unwrap(), decoded);
+    }
+
+    #[test]
+    fn test_z85_invalid_input() {
+        // Buffer length must be evenly divisible by 4.
+        assert_eq!(z85_encode(&[0]), Err(Z85Error::InvalidDataLength(1)));
+        assert_eq!(z85_encode(&[0; 42]), Err(Z85Error::InvalidDataLength(42)));
+
+        // String length must be evenly divisible by 5.
+        assert_eq!(
+            z85_decode("01234567"),
+            Err(Z85Error::InvalidStringLength(8))
+        );
+        assert_eq!(z85_decode("0"), Err(Z85Error::InvalidStringLength(1)));
+
+        // The maximum representable value, and the minimum one beyond the
+        // limit ("%nSc0" is 0xffffffff).
+        assert_eq!(z85_decode("#####"), Err(Z85Error::Overflow(4)));
+        assert_eq!(z85_decode("%nSc1"), Err(Z85Error::Overflow(4)));
+        assert_eq!(z85_decode("%nSc0").unwrap(), [0xff; 4]);
+
+        // Characters within, just below and just above the range of valid
+        // characters, which are not part of the alphabet.
+        for string in [&b"0000\""[..], b"0000\x1f", b"00000\x7f0000"] {
+            let pos = string.iter().position(|c| !ENCODER.contains(c)).unwrap();
+            assert_eq!(z85_decode(string), Err(Z85Error::InvalidCharacter(pos)));
+        }
+        assert_eq!(z85_decode("000\u{e9}"), Err(Z85Error::InvalidCharacter(3)));
+        assert!(matches!(
+            ZmqError::from(Z85Error::Overflow(4)),
+            ZmqError::ParsingError(_)
+        ));
+    }
+
+    #[test]
+    fn test_z85_round_trips() {
+        // Empty input encodes to the empty string and back, as in libzmq.
+        assert_eq!(z85_encode(&[]).unwrap(), "");
+        assert!(z85_decode(z85_encode(&[]).unwrap()).unwrap().is_empty());
+
+        for data in [[0x00; 4], [0xff; 4]] {
+            let encoded = z85_encode(&data).unwrap();
+            assert_eq!(z85_decode(&encoded).unwrap(), data);
+        }
+
+        let string = "r^/rM9M=rMToK)63O8dCvd9D<PY<7iGlC+{BiSnG";
+        let decoded = z85_decode(string).unwrap();
+        assert_eq!(decoded.len(), 32);
+        assert_eq!(z85_encode(&decoded).unwrap(), string);
+    }
+
+    // Arbitrary input must be rejected or decoded, never make the decoder
+    // panic, and whatever decodes must encode back to the same string.
+    #[test]
+    fn test_z85_decode_fuzz() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(0x2885);
+        for _ in 0..10_000 {
+            let len = rng.gen_range(0..32) / 5 * 5;
+            let string: Vec<u8> = (0..len)
+                .map(|_| {
+                    if rng.gen_bool(0.9) {
+                        ENCODER[rng.gen_range(0..ENCODER.len())]
+                    } else {
+                        rng.gen()
+                    }
+                })
+                .collect();
+
+            if let Ok(decoded) = z85_decode(&string) {
+                assert_eq!(decoded.len(), len * 4 / 5);
+                assert_eq!(z85_encode(&decoded).unwrap().as_bytes(), &string[..]);
+            }
+        }
+    }
+
     #[test]
     fn test_atomic_counter() {
         let counter = AtomicCounter::new();
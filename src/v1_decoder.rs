@@ -1,165 +1,153 @@
-use std::convert::TryFrom;
-
-#[derive(Debug)]
-pub struct Msg {
-    flags: u8,
-    data: Vec<u8>,
-}
-
-impl Msg {
-    fn new() -> Self {
-        Self {
-            flags: 0,
-            data: Vec::new(),
-        }
-    }
-
-    fn init_size(&mut self, size: usize) -> Result<(), &'static str> {
-        self.data = Vec::with_capacity(size);
-        Ok(())
-    }
-
-    fn set_flags(&mut self, flags: u8) {
-        self.flags = flags;
-    }
-
-    fn size(&self) -> usize {
-        self.data.len()
-    }
-
-    fn data_mut(&mut self) -> &mut [u8] {
-        self.data.as_mut_slice()
-    }
-}
-
-pub struct V1Decoder {
-    tmpbuf: [u8; 8],
-    in_progress: Msg,
-    max_msg_size: i64,
-    state: DecoderState,
-    bufsize: usize,
-}
-
-#[derive(Debug)]
-enum DecoderState {
+use crate::constants::ZMQ_EPROTO;
+use crate::decoder::{alloc_msg, DecoderBase};
+use crate::i_decoder::IDecoder;
+use crate::message::{Message, MsgFlags};
+use crate::v2_protocol::MORE_FLAG;
+
+#[derive(Clone, Copy)]
+enum Step {
     OneByteSizeReady,
     EightByteSizeReady,
     FlagsReady,
     MessageReady,
 }
 
+/// Decoder for ZMTP/1.0 frames, as spoken by unversioned and ZMTP/1.0
+/// peers.
+pub struct V1Decoder {
+    base: DecoderBase,
+    next: Step,
+    max_msg_size: i64,
+}
+
 impl V1Decoder {
-    pub fn new(bufsize: usize, max_msg_size: i64) -> Self {
-        let mut decoder = Self {
-            tmpbuf: [0; 8],
-            in_progress: Msg::new(),
+    pub fn new(max_msg_size: i64) -> Self {
+        let mut base = DecoderBase::new();
+        base.next_header_step(1);
+        V1Decoder {
+            base,
+            next: Step::OneByteSizeReady,
             max_msg_size,
-            state: DecoderState::OneByteSizeReady,
-            bufsize,
-        };
-        decoder.next_step(1);
-        decoder
-    }
-
-    fn next_step(&mut self, bytes: usize) {
-        // In a real implementation, this would set up the next read operation
-        self.state = match self.state {
-            DecoderState::OneByteSizeReady => DecoderState::EightByteSizeReady,
-            DecoderState::EightByteSizeReady => DecoderState::FlagsReady,
-            DecoderState::FlagsReady => DecoderState::MessageReady,
-            DecoderState::MessageReady => DecoderState::OneByteSizeReady,
-        };
-    }
-
-    pub fn decode(&mut self, data: &[u8]) -> Result<Option<Msg>, &'static str> {
-        match self.state {
-            DecoderState::OneByteSizeReady => self.one_byte_size_ready(data),
-            DecoderState::EightByteSizeReady => self.eight_byte_size_ready(data),
-            DecoderState::FlagsReady => self.flags_ready(data),
-            DecoderState::MessageReady => self.message_ready(data),
         }
     }
 
-    fn one_byte_size_ready(&mut self, data: &[u8]) -> Result<Option<Msg>, &'static str> {
-        self.tmpbuf[0] = data[0];
-        
-        if data[0] == u8::MAX {
-            self.next_step(8);
-            Ok(None)
-        } else {
-            if data[0] == 0 {
-                return Err("Protocol error: zero-size message");
+    // Runs once the current step got all of its bytes. Returns true if a
+    // message was completed.
+    fn step_done(&mut self) -> Result<bool, i32> {
+        match self.next {
+            Step::OneByteSizeReady => {
+                // First byte of size is read. If it is 0xff read 8-byte size.
+                // Otherwise allocate the buffer for message data and read the
+                // message data into it.
+                let size = self.base.header()[0];
+                if size == u8::MAX {
+                    self.base.next_header_step(8);
+                    self.next = Step::EightByteSizeReady;
+                    Ok(false)
+                } else {
+                    self.size_ready(u64::from(size))
+                }
             }
-
-            if self.max_msg_size >= 0 && i64::from(data[0] - 1) > self.max_msg_size {
-                return Err("Message size too large");
+            Step::EightByteSizeReady => {
+                // 8-byte payload length is read. Allocate the buffer
+                // for message body and read the message data into it.
+                let mut size = [0u8; 8];
+                size.copy_from_slice(self.base.header());
+                self.size_ready(u64::from_be_bytes(size))
+            }
+            Step::FlagsReady => {
+                // Store the flags from the wire into the message structure.
+                if self.base.header()[0] & MORE_FLAG != 0 {
+                    self.base.in_progress.set_flags(MsgFlags::More);
+                }
+                self.base.next_body_step();
+                self.next = Step::MessageReady;
+                Ok(false)
+            }
+            Step::MessageReady => {
+                // Message is completely read. Push it further and start
+                // reading new message.
+                self.base.next_header_step(1);
+                self.next = Step::OneByteSizeReady;
+                Ok(true)
             }
-
-            self.in_progress = Msg::new();
-            self.in_progress.init_size((data[0] - 1) as usize)?;
-            self.next_step(1);
-            Ok(None)
         }
     }
 
-    fn eight_byte_size_ready(&mut self, data: &[u8]) -> Result<Option<Msg>, &'static str> {
-        self.tmpbuf[..8].copy_from_slice(&data[..8]);
-        
-        let payload_length = u64::from_be_bytes(self.tmpbuf);
-
-        if payload_length == 0 {
-            return Err("Protocol error: zero-size message");
-        }
-
-        if self.max_msg_size >= 0 && (payload_length - 1) > self.max_msg_size as u64 {
-            return Err("Message size too large");
+    // The size on the wire counts the flags byte as well.
+    fn size_ready(&mut self, size: u64) -> Result<bool, i32> {
+        // There has to be at least one byte (the flags) in the message.
+        if size == 0 {
+            return Err(ZMQ_EPROTO);
         }
+        self.base.in_progress = alloc_msg(size - 1, self.max_msg_size)?;
 
-        let msg_size = usize::try_from(payload_length - 1)
-            .map_err(|_| "Message size too large for platform")?;
-
-        self.in_progress = Msg::new();
-        self.in_progress.init_size(msg_size)?;
-        self.next_step(1);
-        Ok(None)
+        self.base.next_header_step(1);
+        self.next = Step::FlagsReady;
+        Ok(false)
     }
+}
 
-    fn flags_ready(&mut self, data: &[u8]) -> Result<Option<Msg>, &'static str> {
-        self.tmpbuf[0] = data[0];
-        self.in_progress.set_flags(data[0] & 1); // 1 is MORE flag
-        self.next_step(self.in_progress.size());
-        Ok(None)
+impl IDecoder for V1Decoder {
+    fn decode(&mut self, data: &[u8]) -> Result<(usize, bool), i32> {
+        let mut processed = 0;
+        loop {
+            let (n, step_complete) = self.base.feed(&data[processed..]);
+            processed += n;
+            if !step_complete {
+                return Ok((processed, false));
+            }
+            if self.step_done()? {
+                return Ok((processed, true));
+            }
+        }
     }
 
-    fn message_ready(&mut self, data: &[u8]) -> Result<Option<Msg>, &'static str> {
-        self.in_progress.data.extend_from_slice(data);
-        let msg = std::mem::replace(&mut self.in_progress, Msg::new());
-        self.next_step(1);
-        Ok(Some(msg))
+    fn msg(&mut self) -> &mut Message {
+        &mut self.base.in_progress
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::i_encoder::IEncoder;
+    use crate::v1_encoder::V1Encoder;
 
     #[test]
     fn test_basic_decode() {
-        let mut decoder = V1Decoder::new(64, 1024);
-        
-        // Test one byte size
-        let result = decoder.decode(&[5]).unwrap(); // 4 byte message + 1 flag byte
-        assert!(result.is_none());
-        
-        // Test flags
-        let result = decoder.decode(&[0]).unwrap(); // no MORE flag
-        assert!(result.is_none());
-        
-        // Test message content
-        let result = decoder.decode(&[1, 2, 3, 4]).unwrap();
-        assert!(result.is_some());
-        let msg = result.unwrap();
-        assert_eq!(msg.data, vec![1, 2, 3, 4]);
-        assert_eq!(msg.flags, 0);
+        let mut decoder = V1Decoder::new(1024);
+
+        // 4 byte message + 1 flag byte, no MORE flag.
+        assert_eq!(decoder.decode(&[5]).unwrap(), (1, false));
+        assert_eq!(decoder.decode(&[0]).unwrap(), (1, false));
+        assert_eq!(decoder.decode(&[1, 2, 3, 4]).unwrap(), (4, true));
+        let msg = decoder.msg();
+        assert_eq!(msg.data(), &[1, 2, 3, 4]);
+        assert!(!msg.has_more());
+    }
+
+    #[test]
+    fn test_zero_size_is_a_protocol_error() {
+        let mut decoder = V1Decoder::new(-1);
+        assert_eq!(decoder.decode(&[0]), Err(ZMQ_EPROTO));
+    }
+
+    #[test]
+    fn test_round_trip_large_message() {
+        let mut encoder = V1Encoder::new();
+        let mut msg = Message::with_data(&[9u8; 300]).unwrap();
+        msg.set_flags(MsgFlags::More);
+        let mut wire = Vec::new();
+        encoder.load_msg(&mut msg);
+        while encoder.encode(&mut wire, 64) > 0 {}
+        assert_eq!(wire[0], u8::MAX);
+        assert_eq!(&wire[1..9], &301u64.to_be_bytes());
+
+        let mut decoder = V1Decoder::new(-1);
+        assert_eq!(decoder.decode(&wire).unwrap(), (wire.len(), true));
+        let msg = decoder.msg();
+        assert_eq!(msg.size(), 300);
+        assert!(msg.has_more());
     }
 }
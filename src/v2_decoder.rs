@@ -1,140 +1,106 @@
-use std::convert::TryFrom;
+use crate::decoder::{alloc_msg, DecoderBase};
+use crate::i_decoder::IDecoder;
+use crate::message::{Message, MsgFlags};
+use crate::v2_protocol::{COMMAND_FLAG, LARGE_FLAG, MORE_FLAG};
 
-// Protocol constants
-mod v2_protocol {
-    pub const MORE_FLAG: u8 = 0x1;
-    pub const COMMAND_FLAG: u8 = 0x2;
-    pub const LARGE_FLAG: u8 = 0x4;
-}
-
-#[derive(Debug)]
-pub struct Message {
-    data: Vec<u8>,
-    flags: u8,
-}
-
-impl Message {
-    const MORE: u8 = 0x1;
-    const COMMAND: u8 = 0x2;
-
-    fn new() -> Self {
-        Message {
-            data: Vec::new(),
-            flags: 0,
-        }
-    }
-
-    fn init_size(&mut self, size: usize) -> Result<(), &'static str> {
-        self.data = Vec::with_capacity(size);
-        Ok(())
-    }
-
-    fn set_flags(&mut self, flags: u8) {
-        self.flags = flags;
-    }
-
-    fn data(&self) -> &[u8] {
-        &self.data
-    }
-
-    fn size(&self) -> usize {
-        self.data.len()
-    }
+#[derive(Clone, Copy)]
+enum Step {
+    FlagsReady,
+    OneByteSizeReady,
+    EightByteSizeReady,
+    MessageReady,
 }
 
+/// Decoder for ZMTP/2.0 and ZMTP/3.x frames.
 pub struct V2Decoder {
-    tmpbuf: [u8; 8],
+    base: DecoderBase,
+    next: Step,
     msg_flags: u8,
-    in_progress: Message,
-    zero_copy: bool,
     max_msg_size: i64,
-    buffer: Vec<u8>,
-    next_step: DecoderState,
-}
-
-enum DecoderState {
-    FlagsReady,
-    OneByteSizeReady,
-    EightByteSizeReady,
-    MessageReady,
 }
 
 impl V2Decoder {
-    pub fn new(bufsize: usize, maxmsgsize: i64, zero_copy: bool) -> Self {
+    pub fn new(max_msg_size: i64) -> Self {
+        let mut base = DecoderBase::new();
+        base.next_header_step(1);
         V2Decoder {
-            tmpbuf: [0; 8],
+            base,
+            next: Step::FlagsReady,
             msg_flags: 0,
-            in_progress: Message::new(),
-            zero_copy,
-            max_msg_size: maxmsgsize,
-            buffer: Vec::with_capacity(bufsize),
-            next_step: DecoderState::FlagsReady,
+            max_msg_size,
         }
     }
 
-    pub fn decode(&mut self, data: &[u8]) -> Result<Option<Message>, &'static str> {
-        match self.next_step {
-            DecoderState::FlagsReady => self.flags_ready(data),
-            DecoderState::OneByteSizeReady => self.one_byte_size_ready(data),
-            DecoderState::EightByteSizeReady => self.eight_byte_size_ready(data),
-            DecoderState::MessageReady => self.message_ready(data),
+    // Runs once the current step got all of its bytes. Returns true if a
+    // message was completed.
+    fn step_done(&mut self) -> Result<bool, i32> {
+        match self.next {
+            Step::FlagsReady => {
+                // Store the flags from the wire into the message structure.
+                self.msg_flags = self.base.header()[0];
+
+                // The payload length is either one or eight bytes,
+                // depending on whether the 'large' bit is set.
+                if self.msg_flags & LARGE_FLAG != 0 {
+                    self.base.next_header_step(8);
+                    self.next = Step::EightByteSizeReady;
+                } else {
+                    self.base.next_header_step(1);
+                    self.next = Step::OneByteSizeReady;
+                }
+                Ok(false)
+            }
+            Step::OneByteSizeReady => {
+                let size = u64::from(self.base.header()[0]);
+                self.size_ready(size)
+            }
+            Step::EightByteSizeReady => {
+                let mut size = [0u8; 8];
+                size.copy_from_slice(self.base.header());
+                self.size_ready(u64::from_be_bytes(size))
+            }
+            Step::MessageReady => {
+                // Message is completely read. Signal this to the caller
+                // and prepare to decode next message.
+                self.base.next_header_step(1);
+                self.next = Step::FlagsReady;
+                Ok(true)
+            }
         }
     }
 
-    fn flags_ready(&mut self, data: &[u8]) -> Result<Option<Message>, &'static str> {
-        if data.is_empty() {
-            return Ok(None);
-        }
-
-        self.msg_flags = 0;
-        if data[0] & v2_protocol::MORE_FLAG != 0 {
-            self.msg_flags |= Message::MORE;
-        }
-        if data[0] & v2_protocol::COMMAND_FLAG != 0 {
-            self.msg_flags |= Message::COMMAND;
+    fn size_ready(&mut self, size: u64) -> Result<bool, i32> {
+        let mut msg = alloc_msg(size, self.max_msg_size)?;
+        if self.msg_flags & MORE_FLAG != 0 {
+            msg.set_flags(MsgFlags::More);
         }
-
-        self.next_step = if data[0] & v2_protocol::LARGE_FLAG != 0 {
-            DecoderState::EightByteSizeReady
-        } else {
-            DecoderState::OneByteSizeReady
-        };
-
-        Ok(None)
-    }
-
-    fn one_byte_size_ready(&mut self, data: &[u8]) -> Result<Option<Message>, &'static str> {
-        if data.is_empty() {
-            return Ok(None);
+        if self.msg_flags & COMMAND_FLAG != 0 {
+            msg.set_flags(MsgFlags::Command);
         }
-        self.size_ready(data[0] as u64, data)
-    }
+        self.base.in_progress = msg;
 
-    fn eight_byte_size_ready(&mut self, data: &[u8]) -> Result<Option<Message>, &'static str> {
-        if data.len() < 8 {
-            return Ok(None);
-        }
-        let msg_size = u64::from_be_bytes(data[0..8].try_into().unwrap());
-        self.size_ready(msg_size, &data[8..])
+        self.base.next_body_step();
+        self.next = Step::MessageReady;
+        Ok(false)
     }
+}
 
-    fn size_ready(&mut self, msg_size: u64, data: &[u8]) -> Result<Option<Message>, &'static str> {
-        if self.max_msg_size >= 0 && msg_size > self.max_msg_size as u64 {
-            return Err("Message size too large");
+impl IDecoder for V2Decoder {
+    fn decode(&mut self, data: &[u8]) -> Result<(usize, bool), i32> {
+        let mut processed = 0;
+        loop {
+            let (n, step_complete) = self.base.feed(&data[processed..]);
+            processed += n;
+            if !step_complete {
+                return Ok((processed, false));
+            }
+            if self.step_done()? {
+                return Ok((processed, true));
+            }
         }
-
-        let size = usize::try_from(msg_size).map_err(|_| "Message size overflow")?;
-        self.in_progress.init_size(size)?;
-        self.in_progress.set_flags(self.msg_flags);
-
-        self.next_step = DecoderState::MessageReady;
-        Ok(None)
     }
 
-    fn message_ready(&mut self, data: &[u8]) -> Result<Option<Message>, &'static str> {
-        self.next_step = DecoderState::FlagsReady;
-        let mut completed_msg = Message::new();
-        std::mem::swap(&mut self.in_progress, &mut completed_msg);
-        Ok(Some(completed_msg))
+    fn msg(&mut self) -> &mut Message {
+        &mut self.base.in_progress
     }
 }
@@ -1,115 +1,109 @@
-use std::cmp::min;
-
-// Constants from v2_protocol
-const MORE_FLAG: u8 = 0x1;
-const LARGE_FLAG: u8 = 0x2;
-const COMMAND_FLAG: u8 = 0x4;
-
-pub struct Message {
-    data: Vec<u8>,
-    flags: u8,
-    is_subscribe: bool,
-    is_cancel: bool,
-}
-
-impl Message {
-    pub fn size(&self) -> usize {
-        self.data.len()
-    }
-
-    pub fn has_more(&self) -> bool {
-        (self.flags & MORE_FLAG) == MORE_FLAG
-    }
-
-    pub fn is_command(&self) -> bool {
-        (self.flags & COMMAND_FLAG) == COMMAND_FLAG
-    }
-}
+use crate::encoder::EncoderBase;
+use crate::i_encoder::IEncoder;
+use crate::message::Message;
+use crate::v2_protocol::{COMMAND_FLAG, LARGE_FLAG, MORE_FLAG};
 
+/// Encoder for ZMTP/2.0 and ZMTP/3.0 frames: a flags byte, the size in
+/// one or eight bytes, then the body.
 pub struct V2Encoder {
-    tmp_buf: [u8; 10],
-    buffer_size: usize,
-    in_progress: Option<Message>,
+    base: EncoderBase,
 }
 
 impl V2Encoder {
-    pub fn new(buffer_size: usize) -> Self {
+    pub fn new() -> Self {
         V2Encoder {
-            tmp_buf: [0; 10],
-            buffer_size,
-            in_progress: None,
+            base: EncoderBase::new(),
         }
     }
+}
+
+impl Default for V2Encoder {
+    fn default() -> Self {
+        V2Encoder::new()
+    }
+}
+
+impl IEncoder for V2Encoder {
+    fn encode(&mut self, buffer: &mut Vec<u8>, size: usize) -> usize {
+        self.base.encode(buffer, size)
+    }
+
+    fn load_msg(&mut self, msg: &mut Message) {
+        let mut size = msg.size();
 
-    pub fn encode_message(&mut self, msg: Message) -> Vec<u8> {
-        self.in_progress = Some(msg);
-        let mut result = Vec::new();
-        
-        // Encode header
-        let size = self.in_progress.as_ref().unwrap().size();
-        let mut header_size = 2; // flags byte + size byte
-        
-        // Encode flags
         let mut protocol_flags: u8 = 0;
-        let msg = self.in_progress.as_ref().unwrap();
-        
         if msg.has_more() {
             protocol_flags |= MORE_FLAG;
         }
-        if size > u8::MAX as usize {
+        if msg.size() > u8::MAX as usize {
             protocol_flags |= LARGE_FLAG;
         }
         if msg.is_command() {
             protocol_flags |= COMMAND_FLAG;
         }
-        
-        self.tmp_buf[0] = protocol_flags;
+        if msg.is_subscribe() || msg.is_cancel() {
+            size += 1;
+        }
+
+        let mut header = Vec::with_capacity(10);
+        header.push(protocol_flags);
 
-        // Encode message length
+        // Encode the message length. For messages less then 256 bytes,
+        // the length is encoded as 8-bit unsigned integer. For larger
+        // messages, 64-bit unsigned integer in network byte order is used.
         if size > u8::MAX as usize {
-            self.tmp_buf[1..9].copy_from_slice(&(size as u64).to_be_bytes());
-            header_size = 9;
+            header.extend_from_slice(&(size as u64).to_be_bytes());
         } else {
-            self.tmp_buf[1] = size as u8;
+            header.push(size as u8);
         }
 
-        // Encode subscribe/cancel byte
-        if msg.is_subscribe {
-            self.tmp_buf[header_size] = 1;
-            header_size += 1;
-        } else if msg.is_cancel {
-            self.tmp_buf[header_size] = 0;
-            header_size += 1;
+        // Encode the subscribe/cancel byte. This is done in the encoder as
+        // opposed to when the subscribe message is created to allow
+        // different protocol behaviour on the wire in the v3.1 and legacy
+        // encoders.
+        if msg.is_subscribe() {
+            header.push(1);
+        } else if msg.is_cancel() {
+            header.push(0);
         }
 
-        // Add header to result
-        result.extend_from_slice(&self.tmp_buf[0..header_size]);
-        
-        // Add message body
-        result.extend_from_slice(&msg.data);
-
-        result
+        self.base.load(msg, header);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::message::MsgFlags;
+
+    fn encode_all(encoder: &mut V2Encoder, msg: &mut Message) -> Vec<u8> {
+        let mut result = Vec::new();
+        encoder.load_msg(msg);
+        while encoder.encode(&mut result, 4) > 0 {}
+        result
+    }
 
     #[test]
     fn test_basic_encoding() {
-        let mut encoder = V2Encoder::new(1024);
-        let msg = Message {
-            data: vec![1, 2, 3],
-            flags: 0,
-            is_subscribe: false,
-            is_cancel: false,
-        };
-        
-        let result = encoder.encode_message(msg);
+        let mut encoder = V2Encoder::new();
+        let mut msg = Message::with_data(&[1, 2, 3]).unwrap();
+
+        let result = encode_all(&mut encoder, &mut msg);
         assert_eq!(result.len(), 5); // 2 bytes header + 3 bytes data
         assert_eq!(result[0], 0); // no flags
         assert_eq!(result[1], 3); // size
         assert_eq!(&result[2..], &[1, 2, 3]); // data
     }
+
+    #[test]
+    fn test_large_message_with_more() {
+        let mut encoder = V2Encoder::new();
+        let mut msg = Message::with_data(&[7u8; 300]).unwrap();
+        msg.set_flags(MsgFlags::More);
+
+        let result = encode_all(&mut encoder, &mut msg);
+        assert_eq!(result[0], MORE_FLAG | LARGE_FLAG);
+        assert_eq!(&result[1..9], &300u64.to_be_bytes());
+        assert_eq!(result.len(), 9 + 300);
+    }
 }
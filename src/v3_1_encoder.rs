@@ -1,132 +1,117 @@
-use std::cmp::min;
-
-const SUB_CMD_NAME_SIZE: usize = 4;  // Assumed size, adjust as needed
-const CANCEL_CMD_NAME_SIZE: usize = 6;  // Assumed size, adjust as needed
-const SUB_CMD_NAME: &[u8] = b"SUB\0";  // Example command name
-const CANCEL_CMD_NAME: &[u8] = b"CANCEL";
-
-#[derive(Debug)]
-pub struct Msg {
-    flags: u8,
-    data: Vec<u8>,
+use crate::encoder::EncoderBase;
+use crate::i_encoder::IEncoder;
+use crate::message::{
+    Message, CANCEL_CMD_NAME, CANCEL_CMD_NAME_SIZE, SUB_CMD_NAME, SUB_CMD_NAME_SIZE,
+};
+use crate::v2_protocol::{COMMAND_FLAG, LARGE_FLAG, MORE_FLAG};
+
+/// Encoder for ZMTP/3.1 frames. Same framing as ZMTP/2.0, but
+/// subscriptions travel as SUBSCRIBE and CANCEL commands rather than
+/// messages prefixed with 1 or 0.
+pub struct V31Encoder {
+    base: EncoderBase,
 }
 
-impl Msg {
-    const MORE: u8 = 0x1;
-    const COMMAND: u8 = 0x2;
-
+impl V31Encoder {
     pub fn new() -> Self {
-        Msg {
-            flags: 0,
-            data: Vec::new(),
+        V31Encoder {
+            base: EncoderBase::new(),
         }
     }
-
-    pub fn size(&self) -> usize {
-        self.data.len()
-    }
-
-    pub fn flags(&self) -> u8 {
-        self.flags
-    }
-
-    pub fn is_subscribe(&self) -> bool {
-        (self.flags & Self::COMMAND) != 0 && self.data.starts_with(SUB_CMD_NAME)
-    }
-
-    pub fn is_cancel(&self) -> bool {
-        (self.flags & Self::COMMAND) != 0 && self.data.starts_with(CANCEL_CMD_NAME)
-    }
-}
-
-struct V31Encoder {
-    tmp_buf: [u8; 9 + SUB_CMD_NAME_SIZE],
-    in_progress: Option<Msg>,
-    buffer: Vec<u8>,
-    buffer_size: usize,
-    bytes_written: usize,
 }
 
-impl V31Encoder {
-    pub fn new(bufsize: usize) -> Self {
-        let mut encoder = V31Encoder {
-            tmp_buf: [0; 9 + SUB_CMD_NAME_SIZE],
-            in_progress: None,
-            buffer: Vec::with_capacity(bufsize),
-            buffer_size: bufsize,
-            bytes_written: 0,
-        };
-        encoder.next_step();
-        encoder
+impl Default for V31Encoder {
+    fn default() -> Self {
+        V31Encoder::new()
     }
+}
 
-    fn next_step(&mut self) {
-        if let Some(msg) = &self.in_progress {
-            self.message_ready();
-        }
+impl IEncoder for V31Encoder {
+    fn encode(&mut self, buffer: &mut Vec<u8>, size: usize) -> usize {
+        self.base.encode(buffer, size)
     }
 
-    fn message_ready(&mut self) {
-        if let Some(msg) = &self.in_progress {
-            // Encode flags
-            let mut size = msg.size();
-            let mut header_size = 2; // flags byte + size byte
-            let mut protocol_flags = 0u8;
+    fn load_msg(&mut self, msg: &mut Message) {
+        let mut size = msg.size();
 
-            if (msg.flags() & Msg::MORE) != 0 {
-                protocol_flags |= 0x1; // more_flag
+        let mut protocol_flags: u8 = 0;
+        if msg.has_more() {
+            protocol_flags |= MORE_FLAG;
+        }
+        if msg.is_command() || msg.is_subscribe() || msg.is_cancel() {
+            protocol_flags |= COMMAND_FLAG;
+            if msg.is_subscribe() {
+                size += SUB_CMD_NAME_SIZE;
+            } else if msg.is_cancel() {
+                size += CANCEL_CMD_NAME_SIZE;
             }
+        }
+        // Calculate large_flag after command_flag. Subscribe or cancel commands
+        // increase the message size.
+        if size > u8::MAX as usize {
+            protocol_flags |= LARGE_FLAG;
+        }
 
-            if (msg.flags() & Msg::COMMAND) != 0 || msg.is_subscribe() || msg.is_cancel() {
-                protocol_flags |= 0x4; // command_flag
-                if msg.is_subscribe() {
-                    size += SUB_CMD_NAME_SIZE;
-                } else if msg.is_cancel() {
-                    size += CANCEL_CMD_NAME_SIZE;
-                }
-            }
+        let mut header = Vec::with_capacity(9 + SUB_CMD_NAME_SIZE);
+        header.push(protocol_flags);
 
-            // Large message flag
-            if size > u8::MAX as usize {
-                protocol_flags |= 0x2; // large_flag
-            }
+        // Encode the message length. For messages less then 256 bytes,
+        // the length is encoded as 8-bit unsigned integer. For larger
+        // messages, 64-bit unsigned integer in network byte order is used.
+        if size > u8::MAX as usize {
+            header.extend_from_slice(&(size as u64).to_be_bytes());
+        } else {
+            header.push(size as u8);
+        }
 
-            self.tmp_buf[0] = protocol_flags;
+        // Encode the sub/cancel command string. This is done in the encoder
+        // as opposed to when the subscribe message is created to allow
+        // different protocol behaviour on the wire in the v3.1 and legacy
+        // encoders.
+        if msg.is_subscribe() {
+            header.extend_from_slice(SUB_CMD_NAME);
+        } else if msg.is_cancel() {
+            header.extend_from_slice(CANCEL_CMD_NAME);
+        }
 
-            // Encode message length
-            if size > u8::MAX as usize {
-                self.encode_u64(size as u64, &mut self.tmp_buf[1..9]);
-                header_size = 9;
-            } else {
-                self.tmp_buf[1] = size as u8;
-            }
+        self.base.load(msg, header);
+    }
+}
 
-            // Encode sub/cancel command string
-            if msg.is_subscribe() {
-                self.tmp_buf[header_size..header_size + SUB_CMD_NAME_SIZE]
-                    .copy_from_slice(SUB_CMD_NAME);
-                header_size += SUB_CMD_NAME_SIZE;
-            } else if msg.is_cancel() {
-                self.tmp_buf[header_size..header_size + CANCEL_CMD_NAME_SIZE]
-                    .copy_from_slice(CANCEL_CMD_NAME);
-                header_size += CANCEL_CMD_NAME_SIZE;
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            self.size_ready(header_size);
-        }
+    fn encode_all(encoder: &mut V31Encoder, msg: &mut Message) -> Vec<u8> {
+        let mut result = Vec::new();
+        encoder.load_msg(msg);
+        while encoder.encode(&mut result, 4) > 0 {}
+        result
     }
 
-    fn size_ready(&mut self, header_size: usize) {
-        if let Some(msg) = &self.in_progress {
-            // Write header
-            self.buffer.extend_from_slice(&self.tmp_buf[..header_size]);
-            // Write message body
-            self.buffer.extend_from_slice(&msg.data);
-            self.bytes_written = header_size + msg.size();
-        }
+    #[test]
+    fn test_subscribe_is_a_command() {
+        let mut encoder = V31Encoder::new();
+        let mut msg = Message::new();
+        msg.init_subscribe(b"topic").unwrap();
+
+        let result = encode_all(&mut encoder, &mut msg);
+        assert_eq!(result[0], COMMAND_FLAG);
+        assert_eq!(result[1] as usize, SUB_CMD_NAME_SIZE + 5);
+        assert_eq!(&result[2..2 + SUB_CMD_NAME_SIZE], SUB_CMD_NAME);
+        assert_eq!(&result[2 + SUB_CMD_NAME_SIZE..], b"topic");
     }
 
-    fn encode_u64(&mut self, value: u64, buf: &mut [u8]) {
-        buf.copy_from_slice(&value.to_be_bytes());
+    #[test]
+    fn test_long_cancel_is_large() {
+        let mut encoder = V31Encoder::new();
+        let mut msg = Message::new();
+        msg.init_cancel(&[b'x'; 250]).unwrap();
+
+        let result = encode_all(&mut encoder, &mut msg);
+        assert_eq!(result[0], COMMAND_FLAG | LARGE_FLAG);
+        let size = (CANCEL_CMD_NAME_SIZE + 250) as u64;
+        assert_eq!(&result[1..9], &size.to_be_bytes());
+        assert_eq!(&result[9..9 + CANCEL_CMD_NAME_SIZE], CANCEL_CMD_NAME);
     }
 }
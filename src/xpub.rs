@@ -1,50 +1,92 @@
-use std::collections::VecDeque;
-use std::ffi::c_void;
-use crate::blob::Blob;
+use crate::constants::{
+    ZMQ_EAGAIN, ZMQ_PUB, ZMQ_SUBSCRIBE, ZMQ_UNSUBSCRIBE, ZMQ_XPUB, ZMQ_XPUB_MANUAL,
+    ZMQ_XPUB_NODROP, ZMQ_XPUB_VERBOSE, ZMQ_XPUB_VERBOSER, ZMQ_XPUB_WELCOME_MSG,
+};
 use crate::context::Context;
 use crate::dist::Dist;
-use crate::metadata::Metadata;
+use crate::generic_mtrie::RemoveResult;
 use crate::message::Message;
 use crate::mtrie::Mtrie;
+use crate::options::{int_at_least, put_int};
 use crate::pipe::Pipe;
-// Forward declarations - these would be defined elsewhere
-// pub struct Context;
-// pub struct Pipe;
-// pub struct Message;
-// pub struct MTrie;
-// pub struct Dist;
-// pub struct Metadata;
-// pub struct Blob;
+use crate::socket_base::{SocketBase, SocketBehavior};
+use crate::zmq_draft::{ZMQ_ONLY_FIRST_SUBSCRIBE, ZMQ_TOPICS_COUNT, ZMQ_XPUB_MANUAL_LAST_VALUE};
+use std::collections::VecDeque;
+use std::sync::Arc;
 
+/// XPUB socket: distributes messages to subscribers and passes the
+/// subscriptions up to the user.
 pub struct XPub {
-    // Socket options
+    base: SocketBase,
+
+    // List of all subscriptions mapped to corresponding pipes.
+    subscriptions: Mtrie,
+
+    // List of manual subscriptions mapped to corresponding pipes.
+    manual_subscriptions: Mtrie,
+
+    // Distributor of messages holding the list of outbound pipes.
+    dist: Dist,
+
+    // If true, send all subscription messages upstream, not just
+    // unique ones.
     verbose_subs: bool,
+
+    // If true, send all unsubscription messages upstream, not just
+    // unique ones.
     verbose_unsubs: bool,
+
+    // True if we are in the middle of sending a multi-part message.
     more_send: bool,
+
+    // True if we are in the middle of receiving a multi-part message.
     more_recv: bool,
+
+    // If true, subscribe and cancel messages are processed for the rest
+    // of multipart message.
     process_subscribe: bool,
+
+    // This option is enabled with ZMQ_ONLY_FIRST_SUBSCRIBE.
+    // If true, messages following subscribe/unsubscribe in a multipart
+    // message are treated as user data regardless of the first byte.
     only_first_subscribe: bool,
+
+    // Drop messages if HWM reached, otherwise return with EAGAIN.
     lossy: bool,
+
+    // Subscriptions will not be added automatically, only after calling
+    // set option with ZMQ_SUBSCRIBE or ZMQ_UNSUBSCRIBE.
     manual: bool,
+
+    // Send message to the last pipe, only used if manual is enabled.
     send_last_pipe: bool,
 
-    // State
-    subscriptions: Mtrie,
-    manual_subscriptions: Mtrie,
-    dist: Dist,
-    last_pipe: Option<Box<Pipe>>,
-    pending_pipes: VecDeque<Box<Pipe>>,
+    // Pipe the last (un)subscription came from, target of manual
+    // subscriptions.
+    last_pipe: Option<Pipe>,
+
+    // Pipes the pending (un)subscriptions came from, only used if manual
+    // is enabled. None marks an unsubscription sent on pipe termination.
+    pending_pipes: VecDeque<Option<Pipe>>,
+
+    // Message sent to every new subscriber, if not empty.
     welcome_msg: Message,
-    
-    // Pending messages
-    pending_data: VecDeque<Blob>,
-    pending_metadata: VecDeque<Option<Box<Metadata>>>,
-    pending_flags: VecDeque<u8>,
+
+    // List of pending (un)subscriptions, ie. those that were already
+    // applied to the trie, but not yet received by the user.
+    pending: VecDeque<Message>,
 }
 
 impl XPub {
-    pub fn new(parent: &Context, tid: u32, sid: i32) -> Self {
+    pub fn new(ctx: &Arc<Context>, tid: u32, sid: i32) -> Self {
+        let mut base = SocketBase::new(ctx, tid, sid, false);
+        base.options.socket_type = ZMQ_XPUB;
+
         XPub {
+            base,
+            subscriptions: Mtrie::new(),
+            manual_subscriptions: Mtrie::new(),
+            dist: Dist::new(),
             verbose_subs: false,
             verbose_unsubs: false,
             more_send: false,
@@ -54,114 +96,305 @@ impl XPub {
             lossy: true,
             manual: false,
             send_last_pipe: false,
-            subscriptions: Mtrie::new(),
-            manual_subscriptions: Mtrie::new(),
-            dist: Dist::new(),
             last_pipe: None,
             pending_pipes: VecDeque::new(),
             welcome_msg: Message::new(),
-            pending_data: VecDeque::new(),
-            pending_metadata: VecDeque::new(),
-            pending_flags: VecDeque::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    // Queues a message for the user to receive.
+    fn push_pending(&mut self, data: &[u8]) {
+        if let Ok(msg) = Message::with_data(data) {
+            self.pending.push_back(msg);
+        }
+    }
+
+    // Queues the unsubscriptions of a terminated pipe for the user.
+    fn send_unsubscriptions(&mut self, unsubscriptions: Vec<Vec<u8>>) {
+        if self.base.options.socket_type == ZMQ_PUB {
+            return;
+        }
+        for unsub in unsubscriptions {
+            self.push_pending(&unsub);
+            if self.manual {
+                self.last_pipe = None;
+                self.pending_pipes.push_back(None);
+            }
         }
     }
+}
+
+// Collects the prefixes removed from a trie as unsubscription messages.
+fn unsubscription(unsubscriptions: &mut Vec<Vec<u8>>, topic: &[u8]) {
+    let mut unsub = Vec::with_capacity(topic.len() + 1);
+    unsub.push(0);
+    unsub.extend_from_slice(topic);
+    unsubscriptions.push(unsub);
+}
+
+impl SocketBehavior for XPub {
+    fn base(&self) -> &SocketBase {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut SocketBase {
+        &mut self.base
+    }
 
-    pub fn attach_pipe(&mut self, pipe: Box<Pipe>, subscribe_to_all: bool, _locally_initiated: bool) {
-        self.dist.attach(pipe.as_ref());
+    fn xattach_pipe(&mut self, pipe: Pipe, subscribe_to_all: bool, _locally_initiated: bool) {
+        self.dist.attach(pipe.clone());
 
+        // If subscribe_to_all is specified, the caller would like to subscribe
+        // to all data on this pipe, implicitly.
         if subscribe_to_all {
-            self.subscriptions.add(None, 0, pipe.as_ref());
+            self.subscriptions.add(b"", pipe.clone());
         }
 
-        if !self.welcome_msg.is_empty() {
-            let msg_copy = self.welcome_msg.clone();
-            pipe.write(&msg_copy);
+        // If welcome message exists, send a copy of it.
+        if self.welcome_msg.size() > 0 {
+            let mut copy = self.welcome_msg.copy();
+            let ok = pipe.write(&mut copy);
+            debug_assert!(ok);
             pipe.flush();
         }
 
-        self.read_activated(pipe);
+        // The pipe is active when attached. Let's read the subscriptions from
+        // it, if any.
+        self.xread_activated(&pipe);
     }
 
-    pub fn read_activated(&mut self, pipe: Box<Pipe>) {
-        while let Some(msg) = pipe.read() {
-            let metadata = msg.metadata();
-            let msg_data = msg.data();
-            let mut data = None;
-            let mut size = 0;
-            let mut subscribe = false;
-            let mut is_subscribe_or_cancel = false;
-            let mut notify = false;
-
+    fn xread_activated(&mut self, pipe: &Pipe) {
+        // There are some subscriptions waiting. Let's process them.
+        let mut msg = Message::new();
+        while pipe.read(&mut msg) {
             let first_part = !self.more_recv;
             self.more_recv = msg.has_more();
 
+            // Apply the subscription to the trie.
+            let mut subscription = None;
             if first_part || self.process_subscribe {
+                let data = msg.data();
                 if msg.is_subscribe() || msg.is_cancel() {
-                    data = Some(msg.command_body());
-                    size = msg.command_body_size();
-                    subscribe = msg.is_subscribe();
-                    is_subscribe_or_cancel = true;
-                } else if !msg_data.is_empty() && (msg_data[0] == 0 || msg_data[0] == 1) {
-                    data = Some(&msg_data[1..]);
-                    size = msg_data.len() - 1;
-                    subscribe = msg_data[0] == 1;
-                    is_subscribe_or_cancel = true;
+                    subscription = Some((msg.is_subscribe(), data.to_vec()));
+                } else if !data.is_empty() && (data[0] == 0 || data[0] == 1) {
+                    subscription = Some((data[0] == 1, data[1..].to_vec()));
                 }
             }
 
-            // Process subscription
             if first_part {
-                self.process_subscribe = !self.only_first_subscribe || is_subscribe_or_cancel;
+                self.process_subscribe = !self.only_first_subscribe || subscription.is_some();
             }
 
-            if is_subscribe_or_cancel {
-                self.handle_subscription(pipe.as_ref(), data, size, subscribe, &mut notify);
+            match subscription {
+                Some((subscribe, topic)) => {
+                    let mut notify = false;
+                    if self.manual {
+                        // Store manual subscription to use on termination.
+                        if subscribe {
+                            self.manual_subscriptions.add(&topic, pipe.clone());
+                        } else {
+                            self.manual_subscriptions.remove(&topic, pipe);
+                        }
+                        self.pending_pipes.push_back(Some(pipe.clone()));
+                    } else if subscribe {
+                        let first_added = self.subscriptions.add(&topic, pipe.clone());
+                        notify = first_added || self.verbose_subs;
+                    } else {
+                        let rm_result = self.subscriptions.remove(&topic, pipe);
+                        // TODO reconsider what to do if rm_result is NotFound
+                        notify = rm_result != RemoveResult::ValuesRemain || self.verbose_unsubs;
+                    }
+
+                    // If the request was a new subscription, or the subscription
+                    // was removed, or verbose mode or manual mode are enabled,
+                    // store it so that it can be passed to the user on next recv
+                    // call. ZMTP 3.1 subscribe/cancel commands are handed over
+                    // as old-style messages so that the user sees one format.
+                    if self.manual || (self.base.options.socket_type == ZMQ_XPUB && notify) {
+                        let mut data = Vec::with_capacity(topic.len() + 1);
+                        data.push(u8::from(subscribe));
+                        data.extend_from_slice(&topic);
+                        if let Ok(mut notification) = Message::with_data(&data) {
+                            if let Some(metadata) = msg.metadata() {
+                                notification.set_metadata(Arc::clone(metadata));
+                            }
+                            self.pending.push_back(notification);
+                        }
+                    }
+                }
+                None if self.base.options.socket_type != ZMQ_PUB => {
+                    // Process user message coming upstream from xsub socket,
+                    // but not if the type is PUB, which never processes user
+                    // messages
+                    self.pending.push_back(std::mem::take(&mut msg));
+                }
+                None => {}
             }
+        }
+    }
 
-            // Store message for later processing
-            if is_subscribe_or_cancel && (self.manual || notify) {
-                self.store_subscription_message(data, size, subscribe, metadata);
+    fn xwrite_activated(&mut self, pipe: &Pipe) {
+        self.dist.activated(pipe);
+    }
+
+    fn xsetsockopt(&mut self, option: i32, optval: &[u8]) -> Result<(), i32> {
+        match option {
+            ZMQ_XPUB_VERBOSE => {
+                self.verbose_subs = int_at_least(optval, 0)? != 0;
+                self.verbose_unsubs = false;
+            }
+            ZMQ_XPUB_VERBOSER => {
+                self.verbose_subs = int_at_least(optval, 0)? != 0;
+                self.verbose_unsubs = self.verbose_subs;
             }
+            ZMQ_XPUB_MANUAL_LAST_VALUE => {
+                self.manual = int_at_least(optval, 0)? != 0;
+                self.send_last_pipe = self.manual;
+            }
+            ZMQ_XPUB_NODROP => self.lossy = int_at_least(optval, 0)? == 0,
+            ZMQ_XPUB_MANUAL => self.manual = int_at_least(optval, 0)? != 0,
+            ZMQ_ONLY_FIRST_SUBSCRIBE => {
+                self.only_first_subscribe = int_at_least(optval, 0)? != 0;
+            }
+            ZMQ_SUBSCRIBE if self.manual => {
+                if let Some(pipe) = &self.last_pipe {
+                    self.subscriptions.add(optval, pipe.clone());
+                }
+            }
+            ZMQ_UNSUBSCRIBE if self.manual => {
+                if let Some(pipe) = &self.last_pipe {
+                    self.subscriptions.remove(optval, pipe);
+                }
+            }
+            ZMQ_XPUB_WELCOME_MSG => {
+                self.welcome_msg = Message::with_data(optval).map_err(|_| libc::ENOMEM)?;
+            }
+            _ => return Err(libc::EINVAL),
         }
+        Ok(())
     }
 
-    fn handle_subscription(
-        &mut self,
-        pipe: &Pipe,
-        data: Option<&[u8]>,
-        size: usize,
-        subscribe: bool,
-        notify: &mut bool,
-    ) {
+    fn xgetsockopt(&mut self, option: i32, optval: &mut [u8]) -> Result<usize, i32> {
+        match option {
+            ZMQ_TOPICS_COUNT => put_int(optval, self.subscriptions.num_prefixes() as i32),
+            _ => Err(libc::EINVAL),
+        }
+    }
+
+    fn xpipe_terminated(&mut self, pipe: &Pipe) {
+        let mut unsubscriptions = Vec::new();
         if self.manual {
-            if !subscribe {
-                self.manual_subscriptions.remove(data, size, pipe);
-            } else {
-                self.manual_subscriptions.add(data, size, pipe);
+            // Remove the pipe from the trie and send corresponding manual
+            // unsubscriptions upstream.
+            self.manual_subscriptions.rm_value(
+                pipe,
+                |topic| unsubscription(&mut unsubscriptions, topic),
+                false,
+            );
+            // Remove pipe without actually sending the message as it was taken
+            // care of by the manual call above. subscriptions is the real mtrie,
+            // so the pipe must be removed from there or it will be left over.
+            self.subscriptions.rm_value(pipe, |_| {}, false);
+
+            // In case the pipe is currently set as last we must clear it to
+            // prevent subscriptions from being re-added.
+            if self.last_pipe.as_ref() == Some(pipe) {
+                self.last_pipe = None;
             }
-            self.pending_pipes.push_back(Box::new(*pipe));
         } else {
-            if !subscribe {
-                let removed = self.subscriptions.remove(data, size, pipe);
-                *notify = removed || self.verbose_unsubs;
+            // Remove the pipe from the trie. If there are topics that nobody
+            // is interested in anymore, send corresponding unsubscriptions
+            // upstream.
+            let call_on_uniq = !self.verbose_unsubs;
+            self.subscriptions.rm_value(
+                pipe,
+                |topic| unsubscription(&mut unsubscriptions, topic),
+                call_on_uniq,
+            );
+        }
+        self.send_unsubscriptions(unsubscriptions);
+
+        self.dist.pipe_terminated(pipe);
+    }
+
+    fn xsend(&mut self, msg: &mut Message) -> Result<(), i32> {
+        let msg_more = msg.has_more();
+
+        // For the first part of multi-part message, find the matching pipes.
+        if !self.more_send {
+            // Ensure nothing from previous failed attempt to send is left matched
+            self.dist.unmatch();
+
+            let dist = &mut self.dist;
+            if self.manual && self.send_last_pipe && self.last_pipe.is_some() {
+                let last_pipe = self.last_pipe.take();
+                self.subscriptions.match_prefix(msg.data(), |pipe| {
+                    if last_pipe.as_ref() == Some(pipe) {
+                        dist.match_pipe(pipe);
+                    }
+                });
             } else {
-                let first_added = self.subscriptions.add(data, size, pipe);
-                *notify = first_added || self.verbose_subs;
+                self.subscriptions
+                    .match_prefix(msg.data(), |pipe| dist.match_pipe(pipe));
+            }
+
+            // If inverted matching is used, reverse the selection now
+            if self.base.options.invert_matching {
+                self.dist.reverse_match();
             }
         }
+
+        // In nodrop mode refuse the message rather than dropping it on
+        // the pipes that reached their HWM.
+        if !self.lossy && !self.dist.check_hwm() {
+            return Err(ZMQ_EAGAIN);
+        }
+
+        // Send the message to all the pipes that were marked as matching
+        // in the previous step.
+        self.dist.send_to_matching(msg)?;
+
+        // If we are at the end of multi-part message we can mark
+        // all the pipes as non-matching.
+        if !msg_more {
+            self.dist.unmatch();
+        }
+        self.more_send = msg_more;
+
+        Ok(())
     }
 
-    // Additional methods would be implemented here...
-}
+    fn xhas_out(&mut self) -> bool {
+        self.dist.has_out()
+    }
 
-// Implement Drop to clean up resources
-impl Drop for XPub {
-    fn drop(&mut self) {
-        // Clean up pending metadata
-        for metadata in self.pending_metadata.drain(..) {
-            if let Some(metadata) = metadata {
-                drop(metadata);
+    fn xrecv(&mut self, msg: &mut Message) -> Result<(), i32> {
+        // If there is at least one
+        if self.pending.is_empty() {
+            return Err(ZMQ_EAGAIN);
+        }
+
+        // User is reading a message, set last_pipe and remove it from the deque
+        if self.manual {
+            if let Some(last_pipe) = self.pending_pipes.pop_front() {
+                // If the distributor doesn't know about this pipe it must have
+                // already been terminated and thus we can't allow manual
+                // subscriptions.
+                self.last_pipe = last_pipe.filter(|pipe| self.dist.has_pipe(pipe));
             }
         }
+
+        match self.pending.pop_front() {
+            Some(pending) => {
+                *msg = pending;
+                Ok(())
+            }
+            None => Err(ZMQ_EAGAIN),
+        }
+    }
+
+    fn xhas_in(&mut self) -> bool {
+        !self.pending.is_empty()
     }
 }
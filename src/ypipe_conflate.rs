@@ -1,24 +1,20 @@
-use std::marker::PhantomData;
-
-// Trait to replace the C++ ypipe_base
-pub trait YPipeBase<T> {
-    fn write(&mut self, value: T);
-    fn unwrite(&mut self) -> Option<T>;
-    fn flush(&self) -> bool;
-    fn check_read(&mut self) -> bool;
-    fn read(&mut self) -> Option<T>;
-    fn probe<F>(&self, f: F) -> bool 
-    where F: Fn(&T) -> bool;
-}
-
-// DBBuffer equivalent for Rust
-struct DBuffer<T> {
+//! Pipe keeping only the latest value, used for ZMQ_CONFLATE.
+//!
+//! Same interface as the regular pipe but the writer overwrites whatever
+//! the reader hasn't picked up yet, so the reader always gets the most
+//! recent value. Access is serialised by the owner, as with the other
+//! pipes in this crate.
+
+// Stands in for libzmq's dbuffer_t. That one double buffers so the
+// writer never blocks the reader; with the owner doing the locking a
+// single slot holding the unread value is enough.
+struct Slot<T> {
     value: Option<T>,
 }
 
-impl<T> DBuffer<T> {
+impl<T> Slot<T> {
     fn new() -> Self {
-        DBuffer { value: None }
+        Slot { value: None }
     }
 
     fn write(&mut self, value: T) {
@@ -33,66 +29,51 @@ impl<T> DBuffer<T> {
         self.value.is_some()
     }
 
-    fn probe<F>(&self, f: F) -> bool 
-    where F: Fn(&T) -> bool {
+    fn probe<F>(&self, f: F) -> bool
+    where
+        F: Fn(&T) -> bool,
+    {
         self.value.as_ref().map_or(false, f)
     }
 }
 
-// Main YPipeConflate implementation
 pub struct YPipeConflate<T> {
-    dbuffer: DBuffer<T>,
-    reader_awake: bool,
-    _phantom: PhantomData<T>,
+    slot: Slot<T>,
 }
 
 impl<T> YPipeConflate<T> {
     pub fn new() -> Self {
-        YPipeConflate {
-            dbuffer: DBuffer::new(),
-            reader_awake: false,
-            _phantom: PhantomData,
-        }
+        YPipeConflate { slot: Slot::new() }
     }
-}
 
-impl<T> YPipeBase<T> for YPipeConflate<T> {
-    fn write(&mut self, value: T) {
-        self.dbuffer.write(value);
+    /// Write an item to the pipe, replacing the unread one, if any.
+    pub fn write(&mut self, value: T) {
+        self.slot.write(value);
     }
 
-    fn unwrite(&mut self) -> Option<T> {
-        None // Conflate pipe doesn't support unwrite
+    /// Check whether an item is available for reading. Waking up the
+    /// reader is left to the owner.
+    pub fn check_read(&self) -> bool {
+        self.slot.check_read()
     }
 
-    fn flush(&self) -> bool {
-        self.reader_awake
+    /// Reads an item from the pipe. Returns None if there is nothing
+    /// to read.
+    pub fn read(&mut self) -> Option<T> {
+        self.slot.read()
     }
 
-    fn check_read(&mut self) -> bool {
-        let res = self.dbuffer.check_read();
-        if !res {
-            self.reader_awake = false;
-        }
-        res
-    }
-
-    fn read(&mut self) -> Option<T> {
-        if !self.check_read() {
-            return None;
-        }
-        self.dbuffer.read()
-    }
-
-    fn probe<F>(&self, f: F) -> bool 
-    where F: Fn(&T) -> bool {
-        self.dbuffer.probe(f)
+    /// Applies the function to the item available for reading, if any.
+    pub fn probe<F>(&self, f: F) -> bool
+    where
+        F: Fn(&T) -> bool,
+    {
+        self.slot.probe(f)
     }
 }
 
-// Implement Clone restrictions
-impl<T> YPipeConflate<T> {
-    pub fn clone(&self) -> ! {
-        panic!("YPipeConflate cannot be cloned")
+impl<T> Default for YPipeConflate<T> {
+    fn default() -> Self {
+        YPipeConflate::new()
     }
 }
@@ -1,121 +1,168 @@
-use std::string::String;
+//! The server side mechanisms' end of the ZeroMQ Authentication Protocol
+//! (RFC 27): a request carrying the peer's credentials goes to the handler
+//! bound at inproc://zeromq.zap.01, whose reply decides whether the peer
+//! gets in.
 
-const ZAP_VERSION: &[u8] = b"1.0";
-const ID: &[u8] = b"1";
+use crate::constants::ZMQ_EPROTO;
+use crate::mechanism::{Mechanism, Status};
+use crate::message::{Message, MsgFlags};
+use crate::session_base::SessionBase;
 
-// Equivalent to the C++ mechanism_base_t virtual class
-trait MechanismBase {
-    fn set_user_id(&mut self, data: &[u8], size: usize);
-    fn parse_metadata(&mut self, data: &[u8], size: usize, zap_flag: bool) -> i32;
-}
-
-// Equivalent to mechanism_t in C++
-trait Mechanism {
-    fn status(&self) -> Status;
-    fn zap_msg_available(&mut self) -> i32;
-}
+/// Endpoint the ZAP handler binds to.
+pub const ZAP_ENDPOINT: &str = "inproc://zeromq.zap.01";
 
-#[derive(PartialEq)]
-enum Status {
-    Ready,
-    Error,
-    Handshaking,
-}
-
-#[derive(PartialEq)]
-enum State {
-    WaitingForHello,
-    SendingWelcome,
-    WaitingForInitiate,
-    WaitingForZapReply,
-    SendingReady,
-    SendingError,
-    ErrorSent,
-    Ready,
-}
-
-struct Session {
-    // Simplified for example - would need actual implementation
-    endpoint: String,
-}
-
-struct Socket {
-    // Simplified for example - would need actual implementation
-}
-
-struct Options {
-    zap_domain: String,
-    routing_id: Vec<u8>,
-    routing_id_size: usize,
-}
+const ZAP_VERSION: &[u8] = b"1.0";
+const ID: &[u8] = b"1";
+const ZAP_REPLY_FRAME_COUNT: usize = 7;
+const STATUS_CODE_LEN: usize = 3;
+const ERROR_COMMAND_NAME: &[u8] = b"\x05ERROR";
 
-struct ZapClient {
-    session: Session,
+/// Sends ZAP requests on behalf of a mechanism and checks the replies.
+pub struct ZapClient {
     peer_address: String,
-    options: Options,
+    // Status code of the last valid reply, e.g. "200".
     status_code: String,
 }
 
 impl ZapClient {
-    fn new(session: Session, peer_address: String, options: Options) -> Self {
+    pub fn new(peer_address: &str) -> Self {
         ZapClient {
-            session,
-            peer_address,
-            options,
+            peer_address: peer_address.to_string(),
             status_code: String::new(),
         }
     }
 
-    fn send_zap_request(
-        &mut self,
+    pub fn status_code(&self) -> &str {
+        &self.status_code
+    }
+
+    /// Sends a request for `mechanism` with its credentials, one frame
+    /// each.
+    pub fn send_zap_request(
+        &self,
+        base: &Mechanism,
+        session: &mut SessionBase,
         mechanism: &[u8],
         credentials: &[&[u8]],
-        credentials_sizes: &[usize],
-    ) {
-        // Implementation would go here
-        // Would need to handle the ZMQ message writing logic
+    ) -> Result<(), i32> {
+        let options = &base.options;
+        let routing_id = &options.routing_id[..options.routing_id_size as usize];
+        let frames: [&[u8]; 7] = [
+            // Address delimiter frame
+            b"",
+            ZAP_VERSION,
+            ID,
+            options.zap_domain.as_bytes(),
+            self.peer_address.as_bytes(),
+            routing_id,
+            mechanism,
+        ];
+
+        // The credentials, if any, follow the mechanism in the same
+        // message.
+        let last = frames.len() + credentials.len() - 1;
+        for (i, frame) in frames.iter().chain(credentials).enumerate() {
+            let mut msg = Message::with_data(frame).map_err(|_| libc::ENOMEM)?;
+            if i < last {
+                msg.set_flags(MsgFlags::More);
+            }
+            // This can only fail if the handler went away; the pipe to it
+            // has no HWM.
+            session.write_zap_msg(&mut msg)?;
+        }
+        Ok(())
     }
 
-    fn receive_and_process_zap_reply(&mut self) -> i32 {
-        // Implementation would go here
-        // Would need to handle the ZMQ message reading logic
-        0
-    }
+    /// Reads and checks the handler's reply, storing the peer's user id
+    /// and metadata. Returns false if the reply hasn't arrived yet and
+    /// EPROTO if it is malformed.
+    pub fn receive_and_process_zap_reply(
+        &mut self,
+        base: &mut Mechanism,
+        session: &mut SessionBase,
+    ) -> Result<bool, i32> {
+        let mut msg: [Message; ZAP_REPLY_FRAME_COUNT] = Default::default();
+
+        // Read the frames; they all arrive at once.
+        for (i, frame) in msg.iter_mut().enumerate() {
+            match session.read_zap_msg(frame) {
+                Ok(()) => {}
+                Err(e) if e == libc::EAGAIN => return Ok(false),
+                Err(e) => return Err(e),
+            }
+            if frame.has_more() != (i < ZAP_REPLY_FRAME_COUNT - 1) {
+                return Err(ZMQ_EPROTO);
+            }
+        }
 
-    fn handle_zap_status_code(&mut self) {
-        match self.status_code.chars().next() {
-            Some('2') => return,
-            Some('3') => self.session.get_socket().event_handshake_failed_auth(300),
-            Some('4') => self.session.get_socket().event_handshake_failed_auth(400),
-            Some('5') => self.session.get_socket().event_handshake_failed_auth(500),
-            _ => (),
+        // Address delimiter frame
+        if msg[0].size() > 0 {
+            return Err(ZMQ_EPROTO);
         }
+
+        // Version frame
+        if msg[1].data() != ZAP_VERSION {
+            return Err(ZMQ_EPROTO);
+        }
+
+        // Request id frame
+        if msg[2].data() != ID {
+            return Err(ZMQ_EPROTO);
+        }
+
+        // Status code frame, only 200, 300, 400 and 500 are valid status
+        // codes
+        match msg[3].data() {
+            [b'2'..=b'5', b'0', b'0'] => {}
+            _ => return Err(ZMQ_EPROTO),
+        }
+
+        // Save status code
+        self.status_code = String::from_utf8_lossy(msg[3].data()).into_owned();
+
+        // Save user id
+        base.set_user_id(msg[5].data());
+
+        // Process metadata frame
+        base.parse_metadata(msg[6].data(), true)
+            .map_err(|_| ZMQ_EPROTO)?;
+        Ok(true)
     }
 }
 
-struct ZapClientCommonHandshake {
-    zap_client: ZapClient,
-    state: State,
+/// Handshake states of the server mechanisms that consult a ZAP handler
+/// once the client's credentials are in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    WaitingForHello,
+    SendingWelcome,
+    WaitingForInitiate,
+    WaitingForZapReply,
+    SendingReady,
+    SendingError,
+    ErrorSent,
+    Ready,
+}
+
+/// The ZAP client of the PLAIN and CURVE servers, along with the
+/// handshake state it moves on when the reply comes in.
+pub struct ZapClientCommonHandshake {
+    pub zap_client: ZapClient,
+    pub state: State,
+    // State to move to when the handler lets the peer in.
     zap_reply_ok_state: State,
 }
 
 impl ZapClientCommonHandshake {
-    fn new(
-        session: Session,
-        peer_address: String,
-        options: Options,
-        zap_reply_ok_state: State,
-    ) -> Self {
+    pub fn new(peer_address: &str, zap_reply_ok_state: State) -> Self {
         ZapClientCommonHandshake {
-            zap_client: ZapClient::new(session, peer_address, options),
+            zap_client: ZapClient::new(peer_address),
             state: State::WaitingForHello,
             zap_reply_ok_state,
         }
     }
-}
 
-impl Mechanism for ZapClientCommonHandshake {
-    fn status(&self) -> Status {
+    pub fn status(&self) -> Status {
         match self.state {
             State::Ready => Status::Ready,
             State::ErrorSent => Status::Error,
@@ -123,38 +170,50 @@ impl Mechanism for ZapClientCommonHandshake {
         }
     }
 
-    fn zap_msg_available(&mut self) -> i32 {
-        assert!(self.state == State::WaitingForZapReply);
-        if self.zap_client.receive_and_process_zap_reply() == -1 {
-            -1
-        } else {
-            0
-        }
+    pub fn zap_msg_available(
+        &mut self,
+        base: &mut Mechanism,
+        session: &mut SessionBase,
+    ) -> Result<(), i32> {
+        debug_assert_eq!(self.state, State::WaitingForZapReply);
+        self.receive_and_process_zap_reply(base, session)
     }
-}
 
-// Extension trait for Session
-trait SessionExt {
-    fn write_zap_msg(&self, _msg: &[u8]) -> i32;
-    fn read_zap_msg(&self, _msg: &mut [u8]) -> i32;
-    fn get_socket(&self) -> &Socket;
-    fn get_endpoint(&self) -> &str;
-}
+    /// Applies the reply, if there is one already; otherwise we keep
+    /// waiting for it.
+    pub fn receive_and_process_zap_reply(
+        &mut self,
+        base: &mut Mechanism,
+        session: &mut SessionBase,
+    ) -> Result<(), i32> {
+        if self
+            .zap_client
+            .receive_and_process_zap_reply(base, session)?
+        {
+            self.handle_zap_status_code();
+        }
+        Ok(())
+    }
 
-// Extension trait for Socket
-trait SocketExt {
-    fn event_handshake_failed_protocol(&self, _endpoint: &str, _error: i32);
-    fn event_handshake_failed_auth(&self, _error: i32);
-}
+    fn handle_zap_status_code(&mut self) {
+        self.state = match self.zap_client.status_code().as_bytes()[0] {
+            b'2' => self.zap_reply_ok_state,
+            // A 300 (temporary failure) should not result in an ERROR
+            // command, the client is silently disconnected instead.
+            b'3' => State::ErrorSent,
+            _ => State::SendingError,
+        };
+    }
 
-impl SessionExt for Session {
-    fn write_zap_msg(&self, _msg: &[u8]) -> i32 { 0 }
-    fn read_zap_msg(&self, _msg: &mut [u8]) -> i32 { 0 }
-    fn get_socket(&self) -> &Socket { unimplemented!() }
-    fn get_endpoint(&self) -> &str { "" }
-}
+    /// Produces the ERROR command carrying the handler's status code.
+    pub fn produce_error(&self, msg: &mut Message) -> Result<(), i32> {
+        let status_code = self.zap_client.status_code().as_bytes();
+        debug_assert_eq!(status_code.len(), STATUS_CODE_LEN);
 
-impl SocketExt for Socket {
-    fn event_handshake_failed_protocol(&self, _endpoint: &str, _error: i32) {}
-    fn event_handshake_failed_auth(&self, _error: i32) {}
+        let mut error = ERROR_COMMAND_NAME.to_vec();
+        error.push(STATUS_CODE_LEN as u8);
+        error.extend_from_slice(status_code);
+        *msg = Message::with_data(&error).map_err(|_| libc::ENOMEM)?;
+        Ok(())
+    }
 }
@@ -1,7 +1,6 @@
 #![allow(non_camel_case_types)]
 
 use crate::constants::ZMQ_EVENT_ALL;
-use crate::message::zmq_msg_t;
 
 // Socket types
 pub const ZMQ_SERVER: i32 = 12;
@@ -95,63 +94,3 @@ pub struct zmq_poller_event_t {
     pub events: libc::c_short,
 }
 
-#[link(name = "zmq")]
-extern "C" {
-    pub fn zmq_ctx_set_ext(
-        context: *mut libc::c_void,
-        option: libc::c_int,
-        optval: *const libc::c_void,
-        optvallen: libc::size_t,
-    ) -> libc::c_int;
-
-    pub fn zmq_ctx_get_ext(
-        context: *mut libc::c_void,
-        option: libc::c_int,
-        optval: *mut libc::c_void,
-        optvallen: *mut libc::size_t,
-    ) -> libc::c_int;
-
-    pub fn zmq_join(socket: *mut libc::c_void, group: *const libc::c_char) -> libc::c_int;
-    pub fn zmq_leave(socket: *mut libc::c_void, group: *const libc::c_char) -> libc::c_int;
-
-    pub fn zmq_msg_set_routing_id(msg: *mut zmq_msg_t, routing_id: u32) -> libc::c_int;
-    pub fn zmq_msg_routing_id(msg: *mut zmq_msg_t) -> u32;
-    pub fn zmq_msg_set_group(msg: *mut zmq_msg_t, group: *const libc::c_char) -> libc::c_int;
-    pub fn zmq_msg_group(msg: *mut zmq_msg_t) -> *const libc::c_char;
-    pub fn zmq_msg_init_buffer(
-        msg: *mut zmq_msg_t,
-        buf: *const libc::c_void,
-        size: libc::size_t,
-    ) -> libc::c_int;
-
-    pub fn zmq_poller_new() -> *mut libc::c_void;
-    pub fn zmq_poller_destroy(poller_p: *mut *mut libc::c_void) -> libc::c_int;
-    pub fn zmq_poller_size(poller: *mut libc::c_void) -> libc::c_int;
-    pub fn zmq_poller_add(
-        poller: *mut libc::c_void,
-        socket: *mut libc::c_void,
-        user_data: *mut libc::c_void,
-        events: libc::c_short,
-    ) -> libc::c_int;
-    pub fn zmq_poller_modify(
-        poller: *mut libc::c_void,
-        socket: *mut libc::c_void,
-        events: libc::c_short,
-    ) -> libc::c_int;
-    pub fn zmq_poller_remove(poller: *mut libc::c_void, socket: *mut libc::c_void) -> libc::c_int;
-    pub fn zmq_poller_wait(
-        poller: *mut libc::c_void,
-        event: *mut zmq_poller_event_t,
-        timeout: libc::c_long,
-    ) -> libc::c_int;
-    pub fn zmq_poller_wait_all(
-        poller: *mut libc::c_void,
-        events: *mut zmq_poller_event_t,
-        n_events: libc::c_int,
-        timeout: libc::c_long,
-    ) -> libc::c_int;
-    pub fn zmq_poller_fd(poller: *mut libc::c_void) -> zmq_fd_t;
-}
-
-// Note: zmq_msg_t type needs to be defined elsewhere in your codebase
-// as it's not part of the draft API
@@ -1,50 +1,76 @@
-use std::os::raw::c_int;
+use crate::constants::ZMQ_PUB;
 use crate::context::Context;
 use crate::message::Message;
 use crate::pipe::Pipe;
+use crate::socket_base::{SocketBase, SocketBehavior};
 use crate::xpub::XPub;
-// Forward declarations
-// pub struct Ctx;
-// pub struct IoThread;
-// pub struct Pipe;
-// pub struct Msg;
-// pub struct XPub;
-
-// Equivalent to the C++ class
+use std::sync::Arc;
+
+/// PUB socket: an XPUB that never hands anything to the user.
 pub struct Pub {
-    inner: XPub,
+    xpub: XPub,
 }
 
 impl Pub {
-    pub fn new(parent: &mut Context, tid: u32, sid: i32) -> Self {
-        Pub {
-            inner: XPub::new(parent, tid, sid)
-        }
+    pub fn new(ctx: &Arc<Context>, tid: u32, sid: i32) -> Self {
+        let mut xpub = XPub::new(ctx, tid, sid);
+        xpub.base_mut().options.socket_type = ZMQ_PUB;
+
+        Pub { xpub }
+    }
+}
+
+impl SocketBehavior for Pub {
+    fn base(&self) -> &SocketBase {
+        self.xpub.base()
     }
 
-    pub fn attach_pipe(&mut self, pipe: &mut Pipe, subscribe_to_all: bool, locally_initiated: bool) {
-        // Assert pipe is valid
-        debug_assert!(!pipe.is_null());
+    fn base_mut(&mut self) -> &mut SocketBase {
+        self.xpub.base_mut()
+    }
 
+    fn xattach_pipe(&mut self, pipe: Pipe, subscribe_to_all: bool, locally_initiated: bool) {
         // Don't delay pipe termination as there is no one
-        // to receive the delimiter
+        // to receive the delimiter.
         pipe.set_nodelay();
 
-        self.inner.attach_pipe(pipe, subscribe_to_all, locally_initiated);
+        self.xpub.xattach_pipe(pipe, subscribe_to_all, locally_initiated);
     }
 
-    pub fn recv(&mut self, _msg: &mut Message) -> c_int {
-        // Messages cannot be received from PUB socket
-        // FIXME: raise ENOTSUP
-        // errno::set_errno(errno::Errno(libc::ENOTSUP));
-        -1
+    fn xsetsockopt(&mut self, option: i32, optval: &[u8]) -> Result<(), i32> {
+        self.xpub.xsetsockopt(option, optval)
     }
 
-    pub fn has_in(&self) -> bool {
+    fn xgetsockopt(&mut self, option: i32, optval: &mut [u8]) -> Result<usize, i32> {
+        self.xpub.xgetsockopt(option, optval)
+    }
+
+    fn xread_activated(&mut self, pipe: &Pipe) {
+        self.xpub.xread_activated(pipe);
+    }
+
+    fn xwrite_activated(&mut self, pipe: &Pipe) {
+        self.xpub.xwrite_activated(pipe);
+    }
+
+    fn xpipe_terminated(&mut self, pipe: &Pipe) {
+        self.xpub.xpipe_terminated(pipe);
+    }
+
+    fn xsend(&mut self, msg: &mut Message) -> Result<(), i32> {
+        self.xpub.xsend(msg)
+    }
+
+    fn xhas_out(&mut self) -> bool {
+        self.xpub.xhas_out()
+    }
+
+    fn xrecv(&mut self, _msg: &mut Message) -> Result<(), i32> {
+        // Messages cannot be received from PUB socket.
+        Err(libc::ENOTSUP)
+    }
+
+    fn xhas_in(&mut self) -> bool {
         false
     }
 }
-
-// Prevent automatic copy and move implementations
-// impl !Send for Pub {}
-// impl !Sync for Pub {}
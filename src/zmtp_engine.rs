@@ -1,248 +1,836 @@
-use std::convert::TryFrom;
-use std::io::{Read, Write};
+//! Engine speaking ZMTP, the ZeroMQ Message Transport Protocol.
+//!
+//! Both peers start by sending a greeting: a signature that ZMTP/1.0 peers
+//! read as a routing id message, followed by the protocol version and, from
+//! ZMTP/3.0 on, the name of the security mechanism. The version is
+//! negotiated byte by byte so that the engine can answer older peers in
+//! their own protocol.
+
 use std::mem;
+use std::os::unix::io::RawFd;
 
+use crate::constants::{ZMQ_CURVE, ZMQ_EPROTO, ZMQ_GSSAPI, ZMQ_NULL, ZMQ_PLAIN, ZMQ_PUB, ZMQ_XPUB};
+#[cfg(feature = "curve")]
+use crate::curve_client::CurveClient;
+#[cfg(feature = "curve")]
+use crate::curve_server::CurveServer;
 use crate::endpoint::EndpointUriPair;
+use crate::i_encoder::IEncoder;
+use crate::i_engine::ErrorReason;
+use crate::mechanism::MechanismOps;
+use crate::message::{Message, MsgFlags, PING_CMD_NAME_SIZE};
+use crate::null_mechanism::NullMechanism;
 use crate::options::Options;
-use crate::types::ZmqRawFd;
+use crate::plain_client::PlainClient;
+use crate::plain_server::PlainServer;
+use crate::poller_base::IoContext;
+use crate::session_base::SessionBase;
+use crate::stream_engine_base::{
+    NextMsg, ProcessMsg, StreamEngine, StreamEngineBase, HEARTBEAT_TIMEOUT_TIMER_ID,
+    HEARTBEAT_TTL_TIMER_ID,
+};
+use crate::v1_decoder::V1Decoder;
+use crate::v1_encoder::V1Encoder;
+use crate::v2_decoder::V2Decoder;
+use crate::v2_encoder::V2Encoder;
+use crate::v3_1_encoder::V31Encoder;
 
 // Protocol revisions
-#[derive(Debug, Clone, Copy, PartialEq)]
-#[repr(u8)]
-pub enum ZmtpVersion {
-    V1_0 = 0,
-    V2_0 = 1,
-    V3_x = 3,
-}
+const ZMTP_1_0: u8 = 0;
+const ZMTP_2_0: u8 = 1;
+const ZMTP_3_X: u8 = 3;
 
-// Constants
+// Size of the signature that starts every greeting.
 const SIGNATURE_SIZE: usize = 10;
+
+// Size of ZMTP/1.0 and ZMTP/2.0 greeting message
 const V2_GREETING_SIZE: usize = 12;
+
+// Size of ZMTP/3.0 greeting message
 const V3_GREETING_SIZE: usize = 64;
+
+// Position of the revision and minor fields in the greeting.
 const REVISION_POS: usize = 10;
 const MINOR_POS: usize = 11;
 
-#[derive(Debug)]
+// Longest PING context echoed back in the PONG.
+const PING_MAX_CTX_LEN: usize = 16;
+
+// Offset and size of the mechanism name in a ZMTP/3.x greeting.
+const MECHANISM_POS: usize = 12;
+const MECHANISM_SIZE: usize = 20;
+
 pub struct ZmtpEngine {
-    // Greeting state
+    base: StreamEngineBase,
+
+    // Size of the greeting expected from the peer; grows once the peer
+    // turns out to speak ZMTP/3.x.
     greeting_size: usize,
-    greeting_recv: [u8; V3_GREETING_SIZE],
-    greeting_send: [u8; V3_GREETING_SIZE],
-    greeting_bytes_read: usize,
 
-    // Connection state
-    subscription_required: bool,
-    heartbeat_timeout: i32,
+    // Greeting received from the peer.
+    greeting_recv: [u8; V3_GREETING_SIZE],
 
-    // Options
-    options: Options,
+    // Number of bytes of the greeting received so far.
+    greeting_bytes_read: usize,
 
-    // Message state
-    routing_id_msg: Vec<u8>,
-    pong_msg: Vec<u8>,
-}
+    // Number of bytes of our greeting queued for sending so far.
+    greeting_bytes_sent: usize,
 
-// #[derive(Debug, Clone)]
-// pub struct Options {
-//     routing_id: Vec<u8>,
-//     routing_id_size: usize,
-//     heartbeat_intvl: i32,
-//     heartbeat_timeo: i32,
-//     mechanism: SecurityMechanism,
-//     as_server: bool,
-//     socket_type: SocketType,
-//     max_msg_sz: i64,
-//     zero_copy: bool,
-//     out_batch_sz: usize,
-//     in_batch_sz: usize,
-// }
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum SecurityMechanism {
-    Null,
-    Plain,
-    Curve,
-    Gssapi,
-}
+    // How long to wait for any traffic after sending a PING, or -1.
+    heartbeat_timeout: i32,
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum SocketType {
-    Pub,
-    Sub,
-    // Add other socket types as needed
+    // The PONG answering the last PING of the peer.
+    pong_msg: Message,
 }
 
 impl ZmtpEngine {
-    pub fn new(fd: ZmqRawFd, options: Options, endpoint_uri_pair: EndpointUriPair) -> Self {
-        Self {
+    pub fn new(fd: RawFd, options: &Options, endpoint_uri_pair: EndpointUriPair) -> Self {
+        // ZMTP/1.0 and ZMTP/2.0 peers exchange routing ids first; the
+        // ZMTP/3.x handshake replaces these with the mechanism's commands.
+        let mut base = StreamEngineBase::new(fd, options, endpoint_uri_pair, true);
+        base.next_msg = NextMsg::RoutingId;
+        base.process_msg = ProcessMsg::RoutingId;
+
+        let mut heartbeat_timeout = -1;
+        if options.heartbeat_intvl > 0 {
+            heartbeat_timeout = options.heartbeat_timeo;
+            if heartbeat_timeout == -1 {
+                heartbeat_timeout = options.heartbeat_intvl;
+            }
+        }
+
+        ZmtpEngine {
+            base,
             greeting_size: V2_GREETING_SIZE,
             greeting_recv: [0; V3_GREETING_SIZE],
-            greeting_send: [0; V3_GREETING_SIZE],
             greeting_bytes_read: 0,
-            subscription_required: false,
-            heartbeat_timeout: 0,
-            options,
-            routing_id_msg: Vec::new(),
-            pong_msg: Vec::new(),
+            greeting_bytes_sent: 0,
+            heartbeat_timeout,
+            pong_msg: Message::new(),
         }
     }
 
-
-    pub fn handshake(&mut self) -> Result<bool, std::io::Error> {
-        debug_assert!(self.greeting_bytes_read < self.greeting_size);
-
-        // Receive the greeting
-        let unversioned = match self.receive_greeting()? {
-            -1 => return Ok(false),
-            1 => true,
-            _ => false,
-        };
-
-        let revision = self.greeting_recv[REVISION_POS];
-        let minor = self.greeting_recv[MINOR_POS];
-
-        self.select_handshake(unversioned, revision, minor)?;
-
-        Ok(true)
+    fn send_greeting(&mut self, data: &[u8]) {
+        self.base.outbuf.extend_from_slice(data);
+        self.greeting_bytes_sent += data.len();
     }
 
-    fn receive_greeting(&mut self) -> Result<i32, std::io::Error> {
+    // Reads the greeting of the peer. Returns Some(unversioned) once the
+    // greeting was received, None if more data is needed or the engine
+    // failed.
+    fn receive_greeting(&mut self, session: &mut SessionBase, io: &mut IoContext) -> Option<bool> {
         let mut unversioned = false;
-
         while self.greeting_bytes_read < self.greeting_size {
             let buf = &mut self.greeting_recv[self.greeting_bytes_read..self.greeting_size];
-            match self.read(buf) {
-                Ok(n) => {
-                    if n == 0 {
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::UnexpectedEof,
-                            "connection closed",
-                        ));
-                    }
-                    self.greeting_bytes_read += n;
-
-                    // Check for unversioned protocol
-                    if self.greeting_recv[0] != 0xff {
-                        unversioned = true;
-                        break;
+            match self.base.read(buf) {
+                Ok(n) => self.greeting_bytes_read += n,
+                Err(e) => {
+                    if e != libc::EAGAIN {
+                        self.base.error(ErrorReason::ConnectionError, session, io);
                     }
+                    return None;
+                }
+            }
 
-                    if self.greeting_bytes_read < SIGNATURE_SIZE {
-                        continue;
-                    }
+            // We have received at least one byte from the peer.
+            // If the first byte is not 0xff, we know that the
+            // peer is using unversioned protocol.
+            if self.greeting_recv[0] != 0xff {
+                unversioned = true;
+                break;
+            }
 
-                    // Check right-most bit of 10th byte
-                    if (self.greeting_recv[9] & 0x01) == 0 {
-                        unversioned = true;
-                        break;
-                    }
+            if self.greeting_bytes_read < SIGNATURE_SIZE {
+                continue;
+            }
 
-                    self.receive_greeting_versioned();
-                }
-                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    return Ok(-1);
-                }
-                Err(e) => return Err(e),
+            // Inspect the right-most bit of the 10th byte (which coincides
+            // with the 'flags' field if a regular message was sent).
+            // Zero indicates this is a header of a routing id message
+            // (i.e. the peer is using the unversioned protocol).
+            if self.greeting_recv[9] & 0x01 == 0 {
+                unversioned = true;
+                break;
             }
-        }
 
-        Ok(if unversioned { 1 } else { 0 })
+            // The peer is using versioned protocol.
+            self.receive_greeting_versioned(io);
+        }
+        Some(unversioned)
     }
 
-    fn receive_greeting_versioned(&mut self) {
-        // Send major version number
-        if self.greeting_bytes_read > SIGNATURE_SIZE {
-            self.greeting_send[SIGNATURE_SIZE] = 3; // Major version
-
-            // Handle minor version and mechanism
-            if self.greeting_recv[REVISION_POS] == ZmtpVersion::V1_0 as u8
-                || self.greeting_recv[REVISION_POS] == ZmtpVersion::V2_0 as u8
-            {
-                self.greeting_send[SIGNATURE_SIZE + 1] = self.options.socket_type as u8;
-            } else {
-                self.greeting_send[SIGNATURE_SIZE + 1] = 1; // Minor version
-
-                // Set mechanism
-                let mechanism_name = match self.options.mechanism {
-                    SecurityMechanism::Null => b"NULL",
-                    SecurityMechanism::Plain => b"PLAIN",
-                    SecurityMechanism::Curve => b"CURVE",
-                    SecurityMechanism::Gssapi => b"GSSAPI",
-                };
+    fn receive_greeting_versioned(&mut self, io: &mut IoContext) {
+        // Send the major version number.
+        if self.greeting_bytes_sent == SIGNATURE_SIZE {
+            if self.base.outsize() == 0 {
+                self.base.set_pollout(io);
+            }
+            self.send_greeting(&[ZMTP_3_X]);
+        }
 
-                let start = SIGNATURE_SIZE + 2;
-                self.greeting_send[start..start + mechanism_name.len()]
-                    .copy_from_slice(mechanism_name);
+        if self.greeting_bytes_read > SIGNATURE_SIZE
+            && self.greeting_bytes_sent == SIGNATURE_SIZE + 1
+        {
+            if self.base.outsize() == 0 {
+                self.base.set_pollout(io);
+            }
 
+            // Use ZMTP/2.0 to talk to older peers.
+            let revision = self.greeting_recv[REVISION_POS];
+            if revision == ZMTP_1_0 || revision == ZMTP_2_0 {
+                let socket_type = self.base.options.socket_type as u8;
+                self.send_greeting(&[socket_type]);
+            } else {
+                // Minor version number, then the mechanism name, zero
+                // padded, the as-server flag and the filler.
+                self.send_greeting(&[1]);
+                let name = mechanism_name(self.base.options.mechanism);
+                self.send_greeting(&name);
+                self.send_greeting(&[0; 32]);
                 self.greeting_size = V3_GREETING_SIZE;
             }
         }
     }
 
+    // Runs the handshake for the protocol revision the peer announced.
     fn select_handshake(
         &mut self,
         unversioned: bool,
         revision: u8,
         minor: u8,
-    ) -> Result<bool, std::io::Error> {
+        session: &mut SessionBase,
+        io: &mut IoContext,
+    ) -> bool {
+        // Is the peer using ZMTP/1.0 with no revision number?
         if unversioned {
-            self.handshake_v1_0_unversioned()
+            return self.handshake_v1_0_unversioned(session, io);
+        }
+        match (revision, minor) {
+            (ZMTP_1_0, _) => self.handshake_v1_0(session, io),
+            (ZMTP_2_0, _) => self.handshake_v2_0(session, io),
+            (ZMTP_3_X, 0) => self.handshake_v3_0(session, io),
+            _ => self.handshake_v3_1(session, io),
+        }
+    }
+
+    fn handshake_v1_0_unversioned(
+        &mut self,
+        session: &mut SessionBase,
+        io: &mut IoContext,
+    ) -> bool {
+        // We send and receive rest of routing id message
+        if session.zap_enabled() {
+            // reject ZMTP 1.0 connections if ZAP is enabled
+            self.base.error(ErrorReason::ProtocolError, session, io);
+            return false;
+        }
+
+        let mut encoder = V1Encoder::new();
+        self.base.decoder = Some(Box::new(V1Decoder::new(self.base.options.max_msg_sz)));
+
+        // We have already sent the message header.
+        // Since there is no way to tell the encoder to
+        // skip the message header, we simply throw that
+        // header data away.
+        let routing_id_size = self.base.options.routing_id_size as usize;
+        let header_size = if routing_id_size + 1 >= u8::MAX as usize {
+            10
         } else {
-            match revision {
-                x if x == ZmtpVersion::V1_0 as u8 => self.handshake_v1_0(),
-                x if x == ZmtpVersion::V2_0 as u8 => self.handshake_v2_0(),
-                x if x == ZmtpVersion::V3_x as u8 => match minor {
-                    0 => self.handshake_v3_0(),
-                    _ => self.handshake_v3_1(),
-                },
-                _ => self.handshake_v3_1(),
+            2
+        };
+
+        // Prepare the routing id message and load it into encoder.
+        // Then consume bytes we have already sent to the peer.
+        let mut routing_id_msg =
+            match Message::with_data(&self.base.options.routing_id[..routing_id_size]) {
+                Ok(msg) => msg,
+                Err(_) => {
+                    self.base.error(ErrorReason::ProtocolError, session, io);
+                    return false;
+                }
+            };
+        encoder.load_msg(&mut routing_id_msg);
+        let mut header = Vec::with_capacity(header_size);
+        let buffer_size = encoder.encode(&mut header, header_size);
+        debug_assert_eq!(buffer_size, header_size);
+        self.base.encoder = Some(Box::new(encoder));
+
+        // Make sure the decoder sees the data we have already received.
+        self.base
+            .set_input(&self.greeting_recv[..self.greeting_bytes_read]);
+
+        // To allow for interoperability with peers that do not forward
+        // their subscriptions, we inject a phantom subscription message
+        // message into the incoming message stream.
+        let socket_type = self.base.options.socket_type;
+        if socket_type == ZMQ_PUB || socket_type == ZMQ_XPUB {
+            self.base.subscription_required = true;
+        }
+
+        // We are sending our routing id now and the next message
+        // will come from the socket.
+        self.base.next_msg = NextMsg::PullMsgFromSession;
+
+        // We are expecting routing id message.
+        self.base.process_msg = ProcessMsg::RoutingId;
+        true
+    }
+
+    fn handshake_v1_0(&mut self, session: &mut SessionBase, io: &mut IoContext) -> bool {
+        if session.zap_enabled() {
+            // reject ZMTP 1.0 connections if ZAP is enabled
+            self.base.error(ErrorReason::ProtocolError, session, io);
+            return false;
+        }
+
+        self.base.encoder = Some(Box::new(V1Encoder::new()));
+        self.base.decoder = Some(Box::new(V1Decoder::new(self.base.options.max_msg_sz)));
+        true
+    }
+
+    fn handshake_v2_0(&mut self, session: &mut SessionBase, io: &mut IoContext) -> bool {
+        if session.zap_enabled() {
+            // reject ZMTP 2.0 connections if ZAP is enabled
+            self.base.error(ErrorReason::ProtocolError, session, io);
+            return false;
+        }
+
+        self.base.encoder = Some(Box::new(V2Encoder::new()));
+        self.base.decoder = Some(Box::new(V2Decoder::new(self.base.options.max_msg_sz)));
+        true
+    }
+
+    fn handshake_v3_x(
+        &mut self,
+        downgrade_sub: bool,
+        session: &mut SessionBase,
+        io: &mut IoContext,
+    ) -> bool {
+        let options = &self.base.options;
+        let peer_mechanism = &self.greeting_recv[MECHANISM_POS..MECHANISM_POS + MECHANISM_SIZE];
+
+        if peer_mechanism != mechanism_name(options.mechanism) {
+            self.base.error(ErrorReason::ProtocolError, session, io);
+            return false;
+        }
+
+        let mechanism: Box<dyn MechanismOps> = match options.mechanism {
+            ZMQ_NULL => Box::new(NullMechanism::new(options, &self.base.peer_address)),
+            ZMQ_PLAIN if options.as_server != 0 => {
+                Box::new(PlainServer::new(options, &self.base.peer_address))
             }
+            ZMQ_PLAIN => Box::new(PlainClient::new(options)),
+            #[cfg(feature = "curve")]
+            ZMQ_CURVE if options.as_server != 0 => Box::new(CurveServer::new(
+                options,
+                &self.base.peer_address,
+                downgrade_sub,
+            )),
+            #[cfg(feature = "curve")]
+            ZMQ_CURVE => Box::new(CurveClient::new(options, downgrade_sub)),
+            _ => {
+                self.base.error(ErrorReason::ProtocolError, session, io);
+                return false;
+            }
+        };
+        self.base.mechanism = Some(mechanism);
+
+        self.base.next_msg = NextMsg::HandshakeCommand;
+        self.base.process_msg = ProcessMsg::HandshakeCommand;
+        true
+    }
+
+    fn handshake_v3_0(&mut self, session: &mut SessionBase, io: &mut IoContext) -> bool {
+        self.base.encoder = Some(Box::new(V2Encoder::new()));
+        self.base.decoder = Some(Box::new(V2Decoder::new(self.base.options.max_msg_sz)));
+        self.handshake_v3_x(true, session, io)
+    }
+
+    fn handshake_v3_1(&mut self, session: &mut SessionBase, io: &mut IoContext) -> bool {
+        self.base.encoder = Some(Box::new(V31Encoder::new()));
+        self.base.decoder = Some(Box::new(V2Decoder::new(self.base.options.max_msg_sz)));
+        self.handshake_v3_x(false, session, io)
+    }
+
+    fn process_heartbeat_message(
+        &mut self,
+        msg: &Message,
+        session: &mut SessionBase,
+        io: &mut IoContext,
+    ) {
+        if !msg.is_ping() {
+            return;
+        }
+
+        // 16-bit TTL + \4PING == 7
+        let ping_ttl_len = PING_CMD_NAME_SIZE + 2;
+        let data = msg.data();
+        if data.len() < ping_ttl_len {
+            return;
+        }
+
+        // Get the remote heartbeat TTL to setup the timer. The remote
+        // heartbeat is in 10ths of a second so we multiply it by 100 to
+        // get the timer interval in ms.
+        let ttl = u16::from_be_bytes([data[PING_CMD_NAME_SIZE], data[PING_CMD_NAME_SIZE + 1]]);
+        let remote_heartbeat_ttl = i32::from(ttl) * 100;
+
+        if !self.base.has_ttl_timer && remote_heartbeat_ttl > 0 {
+            io.add_timer(remote_heartbeat_ttl, HEARTBEAT_TTL_TIMER_ID);
+            self.base.has_ttl_timer = true;
         }
+
+        // As per ZMTP 3.1 the PING command might contain an up to 16 bytes
+        // context which needs to be PONGed back, so build the pong message
+        // here and store it. Truncate it if it's too long.
+        // Given the engine goes straight to out_event, sequential PINGs will
+        // not be a problem.
+        let context = &data[ping_ttl_len..];
+        let context = &context[..context.len().min(PING_MAX_CTX_LEN)];
+        let mut pong = b"\x04PONG".to_vec();
+        pong.extend_from_slice(context);
+        let mut pong_msg = match Message::with_data(&pong) {
+            Ok(msg) => msg,
+            Err(_) => return,
+        };
+        pong_msg.set_flags(MsgFlags::Command);
+        self.pong_msg = pong_msg;
+
+        self.base.next_msg = NextMsg::ProducePongMessage;
+        self.out_event_internal(session, io);
     }
+}
 
-    // Implement remaining handshake methods...
-    fn handshake_v1_0_unversioned(&mut self) -> Result<bool, std::io::Error> {
-        // Implementation omitted for brevity
-        Ok(true)
+impl StreamEngine for ZmtpEngine {
+    fn base(&self) -> &StreamEngineBase {
+        &self.base
     }
 
-    fn handshake_v1_0(&mut self) -> Result<bool, std::io::Error> {
-        Ok(true)
+    fn base_mut(&mut self) -> &mut StreamEngineBase {
+        &mut self.base
     }
 
-    fn handshake_v2_0(&mut self) -> Result<bool, std::io::Error> {
-        Ok(true)
+    fn plug_internal(&mut self, session: &mut SessionBase, io: &mut IoContext) {
+        // start optional timer, to prevent handshake hanging on no input
+        self.base.set_handshake_timer(io);
+
+        // Send the 'length' and 'flags' fields of the routing id message.
+        // The 'length' field is encoded in the long format.
+        let length = self.base.options.routing_id_size as u64 + 1;
+        self.send_greeting(&[0xff]);
+        self.send_greeting(&length.to_be_bytes());
+        self.send_greeting(&[0x7f]);
+
+        self.base.set_pollin(io);
+        self.base.set_pollout(io);
+
+        // Flush all the data that may have been already received downstream.
+        let _ = self.in_event_internal(session, io);
     }
 
-    fn handshake_v3_0(&mut self) -> Result<bool, std::io::Error> {
-        Ok(true)
+    fn process_command_message(
+        &mut self,
+        msg: &mut Message,
+        session: &mut SessionBase,
+        io: &mut IoContext,
+    ) -> Result<(), i32> {
+        let data = msg.data();
+        let cmd_name_size = match data.first() {
+            Some(&size) => size as usize,
+            None => return Err(ZMQ_EPROTO),
+        };
+
+        // Malformed command
+        if data.len() < cmd_name_size + 1 {
+            return Err(ZMQ_EPROTO);
+        }
+
+        let flag = match &data[1..cmd_name_size + 1] {
+            b"PING" => Some(MsgFlags::Ping),
+            b"PONG" => Some(MsgFlags::Pong),
+            b"SUBSCRIBE" => Some(MsgFlags::Subscribe),
+            b"CANCEL" => Some(MsgFlags::Cancel),
+            _ => None,
+        };
+        match flag {
+            Some(flag @ (MsgFlags::Subscribe | MsgFlags::Cancel)) => {
+                // Subscriptions go up carrying just the topic, the same as
+                // the ones made locally. Which form they take on the wire
+                // is up to the encoder of whoever forwards them.
+                let topic = &data[cmd_name_size + 1..];
+                let mut subscription = Message::new();
+                if flag == MsgFlags::Subscribe {
+                    subscription.init_subscribe(topic)?;
+                } else {
+                    subscription.init_cancel(topic)?;
+                }
+                *msg = subscription;
+            }
+            Some(flag) => msg.set_flags(flag),
+            None => {}
+        }
+
+        if msg.is_ping() || msg.is_pong() {
+            self.process_heartbeat_message(msg, session, io);
+        }
+        Ok(())
     }
 
-    fn handshake_v3_1(&mut self) -> Result<bool, std::io::Error> {
-        Ok(true)
+    fn produce_ping_message(&mut self, msg: &mut Message, io: &mut IoContext) -> Result<(), i32> {
+        // 16-bit TTL + \4PING == 7
+        let mut ping = b"\x04PING".to_vec();
+        ping.extend_from_slice(&self.base.options.heartbeat_ttl.to_be_bytes());
+        *msg = Message::with_data(&ping).map_err(|_| libc::ENOMEM)?;
+        msg.set_flags(MsgFlags::Command);
+
+        let mechanism = self.base.mechanism.as_mut().expect("mechanism is set");
+        let rc = mechanism.encode(msg);
+        self.base.next_msg = NextMsg::PullAndEncode;
+        if !self.base.has_timeout_timer && self.heartbeat_timeout > 0 {
+            io.add_timer(self.heartbeat_timeout, HEARTBEAT_TIMEOUT_TIMER_ID);
+            self.base.has_timeout_timer = true;
+        }
+        rc
     }
 
-    // Helper methods for I/O operations
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
-        // Implementation would depend on underlying transport
-        unimplemented!()
+    fn produce_pong_message(&mut self, msg: &mut Message) -> Result<(), i32> {
+        *msg = mem::take(&mut self.pong_msg);
+        let mechanism = self.base.mechanism.as_mut().expect("mechanism is set");
+        let rc = mechanism.encode(msg);
+        self.base.next_msg = NextMsg::PullAndEncode;
+        rc
     }
 
-    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
-        // Implementation would depend on underlying transport
-        unimplemented!()
+    fn handshake(&mut self, session: &mut SessionBase, io: &mut IoContext) -> bool {
+        debug_assert!(self.greeting_bytes_read < self.greeting_size);
+
+        // Receive the greeting.
+        let unversioned = match self.receive_greeting(session, io) {
+            Some(unversioned) => unversioned,
+            None => return false,
+        };
+
+        let revision = self.greeting_recv[REVISION_POS];
+        let minor = self.greeting_recv[MINOR_POS];
+        if !self.select_handshake(unversioned, revision, minor, session, io) {
+            return false;
+        }
+
+        // Start polling for output if necessary.
+        if self.base.outsize() == 0 {
+            self.base.set_pollout(io);
+        }
+        true
     }
 }
 
-// Error types
-#[derive(Debug)]
-pub enum ZmtpError {
-    IoError(std::io::Error),
-    ProtocolError(&'static str),
+// The mechanism name as sent in the greeting, padded with zeros.
+fn mechanism_name(mechanism: i32) -> [u8; MECHANISM_SIZE] {
+    let name: &[u8] = match mechanism {
+        ZMQ_PLAIN => b"PLAIN",
+        ZMQ_CURVE => b"CURVE",
+        ZMQ_GSSAPI => b"GSSAPI",
+        _ => b"NULL",
+    };
+    let mut padded = [0; MECHANISM_SIZE];
+    padded[..name.len()].copy_from_slice(name);
+    padded
 }
 
-impl From<std::io::Error> for ZmtpError {
-    fn from(error: std::io::Error) -> Self {
-        ZmtpError::IoError(error)
+#[cfg(test)]
+mod tests {
+    use crate::constants::{
+        ZMQ_DEALER, ZMQ_HEARTBEAT_IVL, ZMQ_HEARTBEAT_TIMEOUT, ZMQ_HEARTBEAT_TTL, ZMQ_LAST_ENDPOINT,
+        ZMQ_LINGER, ZMQ_PUB, ZMQ_RCVTIMEO, ZMQ_ROUTER, ZMQ_SNDMORE,
+    };
+    use crate::context::Context;
+    use crate::message::Message;
+    use crate::socket_base::SocketBehavior;
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    // Signature sent by a peer whose routing id is "peer", and the one
+    // sent by our sockets, which have none.
+    const PEER_SIGNATURE: [u8; 10] = [0xff, 0, 0, 0, 0, 0, 0, 0, 5, 0x7f];
+    const SIGNATURE: [u8; 10] = [0xff, 0, 0, 0, 0, 0, 0, 0, 1, 0x7f];
+
+    fn socket(
+        ctx: &Arc<Context>,
+        socket_type: i32,
+        options: &[(i32, i32)],
+    ) -> Box<dyn SocketBehavior> {
+        let mut socket = ctx.create_socket(socket_type).unwrap();
+        socket.setsockopt(ZMQ_LINGER, &0i32.to_ne_bytes()).unwrap();
+        socket
+            .setsockopt(ZMQ_RCVTIMEO, &5000i32.to_ne_bytes())
+            .unwrap();
+        for (option, value) in options {
+            socket.setsockopt(*option, &value.to_ne_bytes()).unwrap();
+        }
+        socket
+    }
+
+    // Binds a socket of the given type and connects a raw TCP peer to it.
+    fn bind_with_peer(
+        ctx: &Arc<Context>,
+        socket_type: i32,
+        options: &[(i32, i32)],
+    ) -> (Box<dyn SocketBehavior>, TcpStream) {
+        let mut socket = socket(ctx, socket_type, options);
+        socket.bind("tcp://127.0.0.1:*").unwrap();
+
+        let mut endpoint = [0u8; 256];
+        let len = socket.getsockopt(ZMQ_LAST_ENDPOINT, &mut endpoint).unwrap();
+        let endpoint = std::str::from_utf8(&endpoint[..len]).unwrap();
+        let address = endpoint.trim_end_matches('\0').trim_start_matches("tcp://");
+        let peer = TcpStream::connect(address).unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        (socket, peer)
+    }
+
+    fn read(peer: &mut TcpStream, size: usize) -> Vec<u8> {
+        let mut data = vec![0; size];
+        peer.read_exact(&mut data).unwrap();
+        data
+    }
+
+    // Reads a ZMTP/2.0 or 3.x frame, returning its flags and body.
+    fn read_frame(peer: &mut TcpStream) -> (u8, Vec<u8>) {
+        let header = read(peer, 2);
+        let size = if header[0] & 0x02 != 0 {
+            let mut size = [0u8; 8];
+            size[0] = header[1];
+            size[1..].copy_from_slice(&read(peer, 7));
+            u64::from_be_bytes(size) as usize
+        } else {
+            usize::from(header[1])
+        };
+        (header[0], read(peer, size))
+    }
+
+    // Reads the next command, skipping the heartbeats that aren't `name`.
+    fn read_command(peer: &mut TcpStream, name: &[u8]) -> Vec<u8> {
+        loop {
+            let (flags, body) = read_frame(peer);
+            assert_eq!(flags, 0x04);
+            if &body[1..=name.len()] == name {
+                return body[name.len() + 1..].to_vec();
+            }
+            assert!(body.starts_with(b"\x04PING") || body.starts_with(b"\x04PONG"));
+        }
+    }
+
+    // Waits for the connection to be closed, dropping whatever arrives
+    // in the meantime.
+    fn read_until_closed(peer: &mut TcpStream) {
+        let mut buf = [0u8; 64];
+        while peer.read(&mut buf).unwrap() > 0 {}
+    }
+
+    // Sends a PING with the given TTL, in tenths of a second.
+    fn send_ping(peer: &mut TcpStream, ttl: u16, context: &[u8]) {
+        let mut body = b"\x04PING".to_vec();
+        body.extend_from_slice(&ttl.to_be_bytes());
+        body.extend_from_slice(context);
+        peer.write_all(&[0x04, body.len() as u8]).unwrap();
+        peer.write_all(&body).unwrap();
+    }
+
+    // Plays a ZMTP/3.1 DEALER socket through the NULL handshake.
+    fn handshake_v3_1(peer: &mut TcpStream) {
+        let mut greeting = [0u8; 64];
+        greeting[..10].copy_from_slice(&SIGNATURE);
+        greeting[10..12].copy_from_slice(&[3, 1]);
+        greeting[12..16].copy_from_slice(b"NULL");
+        peer.write_all(&greeting).unwrap();
+        let mut ready = b"\x05READY\x0bSocket-Type".to_vec();
+        ready.extend_from_slice(&6u32.to_be_bytes());
+        ready.extend_from_slice(b"DEALER");
+        peer.write_all(&[0x04, ready.len() as u8]).unwrap();
+        peer.write_all(&ready).unwrap();
+
+        let greeting = read(peer, 64);
+        assert_eq!(greeting[10], 3);
+        assert_eq!(greeting[12..16], *b"NULL");
+        read_command(peer, b"READY");
+    }
+
+    fn recv(socket: &mut dyn SocketBehavior) -> Vec<u8> {
+        let mut msg = Message::new();
+        socket.recv(&mut msg, 0).unwrap();
+        msg.data().to_vec()
+    }
+
+    // The peer's message shows up under the routing id it sent; the reply
+    // is a two part message.
+    fn exchange_with_router(router: &mut dyn SocketBehavior) {
+        assert_eq!(recv(router), b"peer");
+        assert_eq!(recv(router), b"hello");
+        for (data, flags) in [(&b"peer"[..], ZMQ_SNDMORE), (b"a", ZMQ_SNDMORE), (b"b", 0)] {
+            let mut msg = Message::with_data(data).unwrap();
+            router.send(&mut msg, flags).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_unversioned_peer() {
+        let ctx = Arc::new(Context::new());
+        let (mut router, mut peer) = bind_with_peer(&ctx, ZMQ_ROUTER, &[]);
+
+        // ZMTP/1.0 without a greeting: the routing id comes right away,
+        // framed as length and flags.
+        peer.write_all(&[5, 0]).unwrap();
+        peer.write_all(b"peer").unwrap();
+        peer.write_all(&[6, 0]).unwrap();
+        peer.write_all(b"hello").unwrap();
+
+        // Our signature doubles as the header of our empty routing id.
+        assert_eq!(read(&mut peer, 10), SIGNATURE);
+        exchange_with_router(router.as_mut());
+        assert_eq!(read(&mut peer, 6), [2, 1, b'a', 2, 0, b'b']);
+    }
+
+    #[test]
+    fn test_unversioned_subscriber_gets_everything() {
+        let ctx = Arc::new(Context::new());
+        let (mut publisher, mut peer) = bind_with_peer(&ctx, ZMQ_PUB, &[]);
+
+        // Subscribers that old never send their subscriptions.
+        peer.write_all(&[1, 0]).unwrap();
+        assert_eq!(read(&mut peer, 10), SIGNATURE);
+
+        // Messages are dropped until the engine subscribes on the peer's
+        // behalf.
+        peer.set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let mut frame = [0u8; 6];
+        for _ in 0..100 {
+            let mut msg = Message::with_data(b"news").unwrap();
+            publisher.send(&mut msg, 0).unwrap();
+            if peer.read_exact(&mut frame).is_ok() {
+                break;
+            }
+        }
+        assert_eq!(frame, [5, 0, b'n', b'e', b'w', b's']);
+    }
+
+    #[test]
+    fn test_zmtp_1_0_peer() {
+        let ctx = Arc::new(Context::new());
+        let (mut router, mut peer) = bind_with_peer(&ctx, ZMQ_ROUTER, &[]);
+
+        // Revision 0 and the socket type complete the greeting; the
+        // frames that follow have the ZMTP/1.0 layout.
+        peer.write_all(&PEER_SIGNATURE).unwrap();
+        peer.write_all(&[0, ZMQ_DEALER as u8]).unwrap();
+        peer.write_all(&[5, 0]).unwrap();
+        peer.write_all(b"peer").unwrap();
+        peer.write_all(&[6, 0]).unwrap();
+        peer.write_all(b"hello").unwrap();
+
+        let greeting = read(&mut peer, 12);
+        assert_eq!(greeting[..10], SIGNATURE);
+        assert_eq!(greeting[10..], [3, ZMQ_ROUTER as u8]);
+        assert_eq!(read(&mut peer, 2), [1, 0]);
+        exchange_with_router(router.as_mut());
+        assert_eq!(read(&mut peer, 6), [2, 1, b'a', 2, 0, b'b']);
+    }
+
+    #[test]
+    fn test_zmtp_2_0_peer() {
+        let ctx = Arc::new(Context::new());
+        let (mut router, mut peer) = bind_with_peer(&ctx, ZMQ_ROUTER, &[]);
+
+        // Frames are flags then size from revision 1 on.
+        peer.write_all(&PEER_SIGNATURE).unwrap();
+        peer.write_all(&[1, ZMQ_DEALER as u8]).unwrap();
+        peer.write_all(&[0, 4]).unwrap();
+        peer.write_all(b"peer").unwrap();
+        peer.write_all(&[0, 5]).unwrap();
+        peer.write_all(b"hello").unwrap();
+
+        let greeting = read(&mut peer, 12);
+        assert_eq!(greeting[..10], SIGNATURE);
+        assert_eq!(greeting[10..], [3, ZMQ_ROUTER as u8]);
+        assert_eq!(read(&mut peer, 2), [0, 0]);
+        exchange_with_router(router.as_mut());
+        assert_eq!(read(&mut peer, 6), [1, 1, b'a', 0, 1, b'b']);
+    }
+
+    #[test]
+    fn test_heartbeat_ping_and_pong() {
+        let ctx = Arc::new(Context::new());
+        let options = [
+            (ZMQ_HEARTBEAT_IVL, 100),
+            (ZMQ_HEARTBEAT_TTL, 500),
+            (ZMQ_HEARTBEAT_TIMEOUT, 1000),
+        ];
+        let (_dealer, mut peer) = bind_with_peer(&ctx, ZMQ_DEALER, &options);
+        handshake_v3_1(&mut peer);
+
+        // PINGs come every interval and carry the TTL in tenths of a
+        // second.
+        assert_eq!(read_command(&mut peer, b"PING"), [0, 5]);
+        let first = Instant::now();
+        assert_eq!(read_command(&mut peer, b"PING"), [0, 5]);
+        assert!(first.elapsed() >= Duration::from_millis(80));
+
+        // Our PINGs are answered with their context.
+        send_ping(&mut peer, 0, b"context");
+        assert_eq!(read_command(&mut peer, b"PONG"), b"context");
+    }
+
+    #[test]
+    fn test_heartbeat_timeout_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ctx = Arc::new(Context::new());
+        let options = [(ZMQ_HEARTBEAT_IVL, 100), (ZMQ_HEARTBEAT_TIMEOUT, 300)];
+        let mut dealer = socket(&ctx, ZMQ_DEALER, &options);
+        let endpoint = format!("tcp://{}", listener.local_addr().unwrap());
+        dealer.connect(&endpoint).unwrap();
+
+        // A peer that doesn't answer is dropped once the timeout expires.
+        let (mut peer, _) = listener.accept().unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        handshake_v3_1(&mut peer);
+        read_command(&mut peer, b"PING");
+        let ping = Instant::now();
+        read_until_closed(&mut peer);
+        assert!(ping.elapsed() >= Duration::from_millis(250));
+
+        // Then the socket connects again.
+        let (mut peer, _) = listener.accept().unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        handshake_v3_1(&mut peer);
+    }
+
+    #[test]
+    fn test_heartbeat_ttl() {
+        let ctx = Arc::new(Context::new());
+
+        // Without a TTL, a silent peer stays connected.
+        let (_dealer, mut peer) = bind_with_peer(&ctx, ZMQ_DEALER, &[]);
+        handshake_v3_1(&mut peer);
+        send_ping(&mut peer, 0, b"");
+        assert!(read_command(&mut peer, b"PONG").is_empty());
+        peer.set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let mut buf = [0u8; 1];
+        let err = peer.read(&mut buf).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::WouldBlock | ErrorKind::TimedOut
+        ));
+
+        // With one, it is dropped once the TTL expires.
+        let (_dealer, mut peer) = bind_with_peer(&ctx, ZMQ_DEALER, &[]);
+        handshake_v3_1(&mut peer);
+        send_ping(&mut peer, 3, b"");
+        let ping = Instant::now();
+        assert!(read_command(&mut peer, b"PONG").is_empty());
+        read_until_closed(&mut peer);
+        assert!(ping.elapsed() >= Duration::from_millis(250));
     }
 }